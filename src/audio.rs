@@ -0,0 +1,384 @@
+//! Supervised audio capture, running beside the video ffmpeg process
+//!
+//! When `audio_resilient` is requested, audio is not grabbed by the capture process itself:
+//! a PulseAudio/PipeWire restart makes the pulse input fail, and ffmpeg would take the video
+//! down with it. Instead [supervise] records the audio into separate segment files with its own
+//! ffmpeg process and reconnects with backoff whenever the source drops.
+//!
+//! Each segment remembers its offset from the start of the video, so at stop time
+//! [mix_filter] can position the segments with `adelay`, leaving silence in the gaps
+//! and keeping the audio in sync with the video.
+use crate::ffmpeg::{argv, shell_quote, FfmpegBuilder, File, Parameter};
+use crate::pulse::{self, AudioInput};
+use crate::service::{ChildRole, Recorder, RecordingState};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::*;
+
+/// A file of a single uninterrupted audio capture.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AudioSegment {
+    /// Path of the segment file.
+    pub file: String,
+    /// When the segment started, relative to the start of the video.
+    pub offset_ms: u64,
+}
+
+/// State of the supervised audio capture.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AudioStatus {
+    /// Whether an audio process is currently recording.
+    pub connected: bool,
+    /// How many times the source was reconnected after a drop.
+    pub reconnects: u32,
+    /// Total length of the gaps between the segments, filled with silence at mux time.
+    pub gap_ms_total: u64,
+    /// Segments recorded so far.
+    #[serde(skip)]
+    pub segments: Vec<AudioSegment>,
+    /// The audio ffmpeg currently running.
+    #[serde(skip)]
+    pub process_id: Option<u32>,
+}
+
+const BACKOFF_MIN: Duration = Duration::from_millis(500);
+const BACKOFF_MAX: Duration = Duration::from_secs(10);
+/// A segment running for longer than this resets the backoff.
+const STABLE_AFTER: Duration = Duration::from_secs(10);
+
+fn backoff(attempt: u32) -> Duration {
+    BACKOFF_MIN
+        .saturating_mul(1 << attempt.min(5))
+        .min(BACKOFF_MAX)
+}
+
+fn audio_of(state: &mut RecordingState) -> Option<&mut AudioStatus> {
+    match state {
+        RecordingState::Started {
+            audio: Some(audio), ..
+        } => Some(audio),
+        _ => None,
+    }
+}
+
+/// keep recording audio segments of the pulse `inputs`, mixed, next to `base` for as long as the recording is started
+pub async fn supervise(
+    mx: Arc<Recorder>,
+    base: String,
+    inputs: Vec<AudioInput>,
+    video_started: Instant,
+) {
+    supervise_with(mx, base, inputs, video_started, "ffmpeg".to_string()).await
+}
+
+/// [supervise] running `program` as the ffmpeg
+async fn supervise_with(
+    mx: Arc<Recorder>,
+    base: String,
+    inputs: Vec<AudioInput>,
+    video_started: Instant,
+    program: String,
+) {
+    let mix = pulse::mix_filter(&inputs, 0);
+    let mut attempt = 0;
+    let mut dropped_at: Option<Instant> = None;
+    for index in 0.. {
+        let file = format!("{}.audio.{:03}.mka", base, index);
+        let mut builder = FfmpegBuilder::new();
+        builder.ffmpeg_command = &program;
+        for input in &inputs {
+            builder = builder
+                .option(Parameter::KeyValue("f", "pulse"))
+                .option(Parameter::KeyValue("ac", "2"))
+                .option(Parameter::KeyValue("i", &input.source));
+        }
+        let mut output = File::new(&file).option(Parameter::codec("a", "pcm_s16le"));
+        if let Some(mix) = &mix {
+            builder = builder.option(Parameter::KeyValue("filter_complex", mix));
+            output = output.option(Parameter::Repeated("map", vec![pulse::MIXED]));
+        }
+        let command = builder.output(output).to_command();
+        if let Ok(command) = &command {
+            info!(command = %shell_quote(&argv(command)), "running the audio capture");
+        }
+        let spawned_at = Instant::now();
+        match command
+            .map_err(std::io::Error::other)
+            .and_then(|mut c| c.spawn())
+        {
+            Ok(mut child) => {
+                let process_id = child.id().unwrap_or_default();
+                mx.children
+                    .register(process_id, ChildRole::Audio, vec![file.clone()]);
+                {
+                    let mut state = mx.lock().await;
+                    let Some(audio) = audio_of(&mut state) else {
+                        // the recording was stopped while we were spawning
+                        drop(state);
+                        let _ = kill(Pid::from_raw(process_id as i32), Signal::SIGINT);
+                        let _ = child.wait().await;
+                        mx.children.unregister(process_id);
+                        let _ = std::fs::remove_file(&file);
+                        return;
+                    };
+                    if let Some(at) = dropped_at.take() {
+                        audio.reconnects += 1;
+                        audio.gap_ms_total += spawned_at.duration_since(at).as_millis() as u64;
+                        info!("audio source reconnected after {} attempts", attempt);
+                    }
+                    audio.connected = true;
+                    audio.process_id = Some(process_id);
+                    audio.segments.push(AudioSegment {
+                        file: file.clone(),
+                        offset_ms: spawned_at.duration_since(video_started).as_millis() as u64,
+                    });
+                }
+                let _ = child.wait().await;
+                mx.children.unregister(process_id);
+            }
+            Err(e) => warn!("cannot spawn audio capture: {}", e),
+        }
+
+        let ended_at = Instant::now();
+        {
+            let mut state = mx.lock().await;
+            let Some(audio) = audio_of(&mut state) else {
+                return;
+            };
+            audio.connected = false;
+            audio.process_id = None;
+        }
+        if dropped_at.is_none() {
+            warn!(
+                "audio source dropped after {:?}, reconnecting",
+                ended_at.duration_since(spawned_at)
+            );
+            dropped_at = Some(ended_at);
+        }
+        if ended_at.duration_since(spawned_at) > STABLE_AFTER {
+            attempt = 0;
+        }
+        tokio::time::sleep(backoff(attempt)).await;
+        attempt += 1;
+    }
+}
+
+/// stop the audio process the supervisor is running and wait until it has finished its file
+pub async fn stop(audio: &AudioStatus) {
+    let Some(process_id) = audio.process_id else {
+        return;
+    };
+    let pid = Pid::from_raw(process_id as i32);
+    if kill(pid, Signal::SIGINT).is_err() {
+        return;
+    }
+    // the supervisor reaps the process, we only watch for it to be gone
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while kill(pid, None).is_ok() {
+        if Instant::now() > deadline {
+            warn!("audio capture {} did not stop, killing", process_id);
+            let _ = kill(pid, Signal::SIGKILL);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+/// segments that actually produced a file, dropping the connection attempts that failed
+pub fn recorded_segments(audio: &AudioStatus) -> Vec<AudioSegment> {
+    audio
+        .segments
+        .iter()
+        .filter(|s| {
+            std::fs::metadata(&s.file)
+                .map(|m| m.len() > 0)
+                .unwrap_or(false)
+        })
+        .cloned()
+        .collect()
+}
+
+/// `-filter_complex` placing the segments (inputs `1..`) on the video timeline as `[aout]`
+pub fn mix_filter(segments: &[AudioSegment]) -> String {
+    format!("{},apad[aout]", mix(segments))
+}
+
+/// the mix of [mix_filter] as `[aout]`, ending with the last segment rather than padded with
+/// silence for as long as the video
+pub fn unpadded_mix_filter(segments: &[AudioSegment]) -> String {
+    format!("{}[aout]", mix(segments))
+}
+
+/// the mix of [mix_filter] brought to a loudness by `loudnorm` before it is padded, see
+/// [crate::loudness]
+pub fn normalized_mix_filter(segments: &[AudioSegment], loudnorm: &str) -> String {
+    format!("{},{},apad[aout]", mix(segments), loudnorm)
+}
+
+fn mix(segments: &[AudioSegment]) -> String {
+    let mut filter = String::new();
+    for (i, segment) in segments.iter().enumerate() {
+        filter += &format!(
+            "[{}:a]aresample=async=1,adelay={}:all=1[a{}];",
+            i + 1,
+            segment.offset_ms,
+            i + 1
+        );
+    }
+    for i in 0..segments.len() {
+        filter += &format!("[a{}]", i + 1);
+    }
+    filter += &format!(
+        "amix=inputs={}:duration=longest:normalize=0",
+        segments.len()
+    );
+    filter
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::{Path, PathBuf};
+
+    /// a directory of its own for the files of a test
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "record-screen-audio-{}-{}",
+            std::process::id(),
+            name
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// a source that records until it is killed, writing its file as ffmpeg would
+    fn source(dir: &Path) -> String {
+        let program = dir.join("audio-source");
+        let script = "#!/bin/sh\nfor last; do :; done\necho audio > \"$last\"\nexec sleep 30\n";
+        std::fs::write(&program, script).unwrap();
+        std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+        program.to_string_lossy().to_string()
+    }
+
+    fn input() -> AudioInput {
+        AudioInput {
+            source: "default".to_string(),
+            volume: None,
+        }
+    }
+
+    async fn audio(mx: &Recorder) -> AudioStatus {
+        match &*mx.lock().await {
+            RecordingState::Started {
+                audio: Some(audio), ..
+            } => audio.clone(),
+            state => panic!("no audio while {}", state.name()),
+        }
+    }
+
+    /// wait for the audio to be as `until` wants it
+    async fn until(mx: &Recorder, until: impl Fn(&AudioStatus) -> bool) -> AudioStatus {
+        for _ in 0..100 {
+            let audio = audio(mx).await;
+            if until(&audio) {
+                return audio;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("the audio is still {:?}", audio(mx).await);
+    }
+
+    #[tokio::test]
+    async fn a_dropped_source_is_reconnected_with_the_gap_counted() {
+        let dir = dir("dropped");
+        let program = source(&dir);
+        let mx = Arc::new(Recorder::new());
+        let started: RecordingState = serde_json::from_value(serde_json::json!({
+            "type": "Started",
+            "process_id": 1,
+            "file": dir.join("capture.mkv"),
+            "started_at": chrono::Local::now(),
+            "audio": { "connected": false, "reconnects": 0, "gap_ms_total": 0 },
+        }))
+        .unwrap();
+        mx.set(started).await;
+        let base = dir.join("capture").to_string_lossy().to_string();
+        let supervisor = tokio::spawn(supervise_with(
+            mx.clone(),
+            base,
+            vec![input()],
+            Instant::now(),
+            program,
+        ));
+
+        let first = until(&mx, |a| a.connected).await;
+        assert_eq!(first.reconnects, 0);
+        // the sound server restarts
+        let pid = Pid::from_raw(first.process_id.unwrap() as i32);
+        kill(pid, Signal::SIGKILL).unwrap();
+        let dropped = until(&mx, |a| !a.connected).await;
+        assert_eq!(dropped.segments.len(), 1);
+        let second = until(&mx, |a| a.connected && a.reconnects == 1).await;
+        assert!(second.gap_ms_total >= BACKOFF_MIN.as_millis() as u64);
+        assert_eq!(second.segments.len(), 2);
+        assert!(second.segments[1].offset_ms >= first.segments[0].offset_ms + second.gap_ms_total);
+
+        // the stop finishes the segment, the supervisor ends with the recording
+        mx.set(RecordingState::Stopping {
+            process_id: 1,
+            file: "capture.mkv".to_string(),
+        })
+        .await;
+        stop(&second).await;
+        tokio::time::timeout(Duration::from_secs(5), supervisor)
+            .await
+            .unwrap()
+            .unwrap();
+        let recorded = recorded_segments(&second);
+        assert_eq!(recorded.len(), 2);
+        // the gap is silence between the segments, the audio stays on the video timeline
+        let filter = mix_filter(&recorded);
+        assert!(filter.contains(&format!("adelay={}:all=1", recorded[1].offset_ms)));
+        assert!(filter.ends_with("amix=inputs=2:duration=longest:normalize=0,apad[aout]"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn the_backoff_grows_up_to_its_max() {
+        assert_eq!(backoff(0), BACKOFF_MIN);
+        assert_eq!(backoff(1), BACKOFF_MIN * 2);
+        assert_eq!(backoff(10), BACKOFF_MAX);
+    }
+
+    #[test]
+    fn the_failed_connections_are_not_mixed() {
+        let dir = dir("failed");
+        let recorded = dir.join("recorded.mka");
+        let empty = dir.join("empty.mka");
+        std::fs::write(&recorded, b"audio").unwrap();
+        std::fs::write(&empty, b"").unwrap();
+        let segment = |file: &Path, offset_ms| AudioSegment {
+            file: file.to_string_lossy().to_string(),
+            offset_ms,
+        };
+        let audio = AudioStatus {
+            segments: vec![
+                segment(&empty, 0),
+                segment(&dir.join("missing.mka"), 100),
+                segment(&recorded, 2500),
+            ],
+            ..Default::default()
+        };
+        let segments = recorded_segments(&audio);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(
+            mix_filter(&segments),
+            "[1:a]aresample=async=1,adelay=2500:all=1[a1];[a1]amix=inputs=1:duration=longest:normalize=0,apad[aout]"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,261 @@
+//! Named API tokens and the role each of them has
+//!
+//! A token is sent as `Authorization: Bearer <token>` and gives its [Role]: a viewer reads, an
+//! operator also records, an admin may do everything. What role a route needs is declared in the
+//! table of the router, a route it doesn't name needs the admin. [authorize] resolves the token
+//! into the [Identity] of the request, named after the token, so that the state and the history
+//! tell who did what by the name of the token rather than by the name a client gives itself.
+//!
+//! Without any token the routes of the viewer and the operator are open, as they were before
+//! there were roles, and the admin routes are refused. Once a token is configured, of whatever
+//! role, every route of the table wants a token of its role at least, and so does every route of a
+//! server told to [require] a token: the page of the UI alone stays open.
+//!
+//! [require]: Tokens::require
+use crate::presence::Identity;
+use crate::problem::{ApiError, ProblemType};
+use crate::service::Recorder;
+use axum::body::Body;
+use axum::extract::MatchedPath;
+use axum::http::{header, HeaderMap, Method, Request};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+/// What a token may do, each role everything of the roles before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Viewer,
+    Operator,
+    Admin,
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Viewer => "viewer",
+            Self::Operator => "operator",
+            Self::Admin => "admin",
+        })
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "viewer" => Ok(Self::Viewer),
+            "operator" => Ok(Self::Operator),
+            "admin" => Ok(Self::Admin),
+            _ => Err("expected viewer, operator or admin".to_string()),
+        }
+    }
+}
+
+/// A token of the server
+#[derive(Clone)]
+pub struct ApiToken {
+    pub name: String,
+    pub role: Role,
+    pub token: String,
+}
+
+impl std::fmt::Debug for ApiToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ApiToken")
+            .field("name", &self.name)
+            .field("role", &self.role)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FromStr for ApiToken {
+    type Err = String;
+
+    /// `<name>=<role>:<token>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expected = "expected '<name>=<role>:<token>'";
+        let (name, rest) = s.split_once('=').ok_or(expected)?;
+        let (role, token) = rest.split_once(':').ok_or(expected)?;
+        let name = name.trim();
+        if name.is_empty() || token.is_empty() {
+            return Err(expected.to_string());
+        }
+        Ok(Self {
+            name: name.to_string(),
+            role: role.parse()?,
+            token: token.to_string(),
+        })
+    }
+}
+
+/// whether the two are equal, in the same time whatever they are
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// The tokens of the server, and the requests made with each of them
+#[derive(Default)]
+pub struct Tokens {
+    tokens: Vec<ApiToken>,
+    /// no route is open, whatever roles the tokens have
+    required: bool,
+    requests: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Tokens {
+    pub fn new(tokens: Vec<ApiToken>) -> Self {
+        Self {
+            tokens,
+            required: false,
+            requests: Mutex::default(),
+        }
+    }
+
+    /// want a token on every route, when `required`
+    pub fn require(mut self, required: bool) -> Self {
+        self.required = required;
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// the token sent, comparing it with every token
+    pub fn resolve(&self, bearer: &str) -> Option<&ApiToken> {
+        self.tokens
+            .iter()
+            .fold(None, |found, t| match constant_time_eq(&t.token, bearer) {
+                true => Some(t),
+                false => found,
+            })
+    }
+
+    /// whether the routes of the role need no token, none being configured
+    pub fn open(&self, role: Role) -> bool {
+        !self.required && role < Role::Admin && self.tokens.is_empty()
+    }
+
+    fn count(&self, name: &str) {
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default() += 1;
+    }
+
+    /// the requests of every token, in the text format of Prometheus
+    pub fn metrics(&self) -> String {
+        let name = "record_screen_api_requests_total";
+        let mut metrics = format!(
+            "# HELP {} Requests made with an API token.\n# TYPE {} counter\n",
+            name, name
+        );
+        for (token, count) in self.requests.lock().unwrap().iter() {
+            metrics += &format!(
+                "{}{{token=\"{}\"}} {}\n",
+                name,
+                token.replace('"', ""),
+                count
+            );
+        }
+        metrics
+    }
+}
+
+/// the bearer token of a request
+pub fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
+
+fn unauthorized(detail: &str) -> Response {
+    let mut res = ApiError::new(ProblemType::Unauthorized, detail).into_response();
+    res.headers_mut().insert(
+        header::WWW_AUTHENTICATE,
+        header::HeaderValue::from_static("Bearer"),
+    );
+    res
+}
+
+/// the role a route needs, None for a route that checks the request itself
+pub type Access = (&'static str, &'static str, Option<Role>);
+
+/// refuse the request without a token of the role its route needs in `table`
+///
+/// To be layered after [crate::presence::track], which gives the request its identity.
+pub async fn authorize(
+    table: &'static [Access],
+    mut req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    // the fallback, nothing to refuse
+    let Some(path) = req.extensions().get::<MatchedPath>().cloned() else {
+        return next.run(req).await;
+    };
+    let Some(mx) = req.extensions().get::<Arc<Recorder>>().cloned() else {
+        return next.run(req).await;
+    };
+    // a HEAD is the GET without its body
+    let asked = match req.method() {
+        &Method::HEAD => "GET",
+        method => method.as_str(),
+    };
+    let needed = table
+        .iter()
+        .find(|(method, route, _)| *method == asked && *route == path.as_str())
+        .map_or(Some(Role::Admin), |(_, _, role)| *role);
+
+    let token = match bearer(req.headers()) {
+        Some(bearer) if !mx.tokens.is_empty() => match mx.tokens.resolve(bearer) {
+            Some(token) => Some(token),
+            None => return unauthorized("invalid token"),
+        },
+        _ => None,
+    };
+    if let Some(token) = token {
+        mx.tokens.count(&token.name);
+        if let Some(identity) = req.extensions_mut().get_mut::<Identity>() {
+            identity.name = Some(token.name.clone());
+            identity.role = Some(token.role);
+        }
+    }
+    let Some(needed) = needed else {
+        return next.run(req).await;
+    };
+    match token {
+        Some(token) if token.role >= needed => {}
+        Some(token) => {
+            return ApiError::new(
+                ProblemType::Forbidden,
+                format!(
+                    "the {} role is needed, {} is {}",
+                    needed, token.name, token.role
+                ),
+            )
+            .with("required_role", needed)
+            .into_response()
+        }
+        None if mx.tokens.open(needed) => {}
+        None if needed == Role::Admin && mx.tokens.is_empty() => {
+            return ApiError::new(ProblemType::Forbidden, "no admin token is configured")
+                .with("required_role", needed)
+                .into_response()
+        }
+        None => return unauthorized(&format!("a token of the {} role is needed", needed)),
+    }
+    next.run(req).await
+}
@@ -0,0 +1,827 @@
+//! A short recording on a schedule, telling that the recorder still works before it is needed
+//!
+//! An ffmpeg upgrade or a change of the X config breaks the recorder unnoticed, until somebody
+//! needs a recording. With `--canary` the server records [DURATION] on its own at the time of
+//! `--canary-at`, of the lavfi test source or, with `--canary-source screen` and inside the
+//! recording windows of the policy, of the screen. The recording is compressed like the others,
+//! its content is looked at and must not be found black or silent when it is the test source, its
+//! checksums are written and checked and, with `--canary-upload`, it is put into the storage and
+//! read back. Everything it wrote is removed afterwards.
+//!
+//! A canary never shows up in the state, the history or the quotas of the recordings. It only
+//! starts while the recorder is idle, trying again [RETRY] later otherwise, and a recording
+//! started meanwhile interrupts it: the run is kept as yielded.
+//!
+//! Every run is kept in `.record-screen-canaries.jsonl` next to the history, with how long each
+//! step took and why it failed, and listed at `GET /api/canary`; the metrics have the time of the
+//! last pass. A canary failing after one that passed is published as a notice.
+use crate::checksums;
+use crate::content_check::{self, ContentWarning};
+use crate::events::EventKind;
+use crate::ffmpeg::{FfmpegBuilder, File, Parameter};
+use crate::pipeline;
+use crate::policy;
+use crate::recordings;
+use crate::runner::{CancellationToken, CompletionSummary, Ffmpeg};
+use crate::schema::{self, Versioned};
+use crate::service::{
+    ChildRole, ContentKind, EncoderParams, Recorder, RecordingOptions, RecordingState,
+};
+use crate::storage;
+use anyhow::{bail, ensure};
+use chrono::{DateTime, Duration as ChronoDuration, Local, NaiveTime, Weekday};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::*;
+
+/// how long a canary records
+pub const DURATION: Duration = Duration::from_secs(15);
+/// when the canaries run unless told, a quiet hour
+pub const DEFAULT_SCHEDULE: &str = "daily 03:30";
+/// how long a canary waits for the recorder to be idle again
+pub const RETRY: Duration = Duration::from_secs(10 * 60);
+/// runs kept in memory for the listing
+const KEEP: usize = 100;
+/// how often the scheduler looks at the wall clock
+const WATCH_EVERY: Duration = Duration::from_secs(60);
+const TEST_SOURCE: &str = "testsrc2=size=1280x720:rate=25";
+const TEST_TONE: &str = "sine=frequency=440:sample_rate=48000";
+
+#[derive(Debug, Error, PartialEq)]
+pub enum Error {
+    #[error("a recording is active")]
+    Busy,
+    #[error("a canary is running already")]
+    Running,
+}
+
+/// What a canary records
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    /// the lavfi test pattern with a tone
+    #[default]
+    Test,
+    /// the screen, as the recordings capture it
+    Screen,
+}
+
+impl FromStr for Source {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "test" => Ok(Self::Test),
+            "screen" => Ok(Self::Screen),
+            _ => Err("expected test or screen".to_string()),
+        }
+    }
+}
+
+/// When the canaries run, days and a wall clock time of the server's timezone
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    pub days: Vec<Weekday>,
+    pub at: NaiveTime,
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    /// `<days> <HH:MM>`, the days as in the recording windows, e.g. `daily 03:30`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (days, at) = s
+            .trim()
+            .split_once(' ')
+            .ok_or("expected '<days> <HH:MM>'")?;
+        Ok(Self {
+            days: policy::parse_days(days)?,
+            at: policy::parse_time(at.trim())?,
+        })
+    }
+}
+
+impl Schedule {
+    /// the first run after `now`
+    pub fn next(&self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        let wall = now.naive_local();
+        (0..=7)
+            .map(|offset| wall.date() + ChronoDuration::days(offset))
+            .filter(|date| self.days.contains(&chrono::Datelike::weekday(date)))
+            .map(|date| date.and_time(self.at))
+            .find(|at| *at > wall)
+            .map(policy::to_local)
+    }
+}
+
+/// How the canaries run, disabled without a schedule
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub schedule: Option<Schedule>,
+    pub source: Source,
+    /// put the recording into the storage and read it back
+    pub upload: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Pass,
+    Fail,
+    /// a recording started meanwhile
+    Yielded,
+}
+
+/// A step of a canary and how long it took
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Step {
+    pub name: String,
+    pub secs: f64,
+}
+
+/// A run of a canary
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CanaryRun {
+    pub started_at: DateTime<Local>,
+    pub finished_at: DateTime<Local>,
+    /// what was recorded, the test source when the screen was outside the recording windows
+    pub source: Source,
+    pub outcome: Outcome,
+    /// the steps that ended, in order
+    pub steps: Vec<Step>,
+    /// the step that failed or was interrupted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failed_step: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// what the content looked like, only a failure for the test source
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub content_warnings: Vec<ContentWarning>,
+}
+
+impl Versioned for CanaryRun {
+    const KIND: &'static str = "canary runs";
+    const VERSION: u32 = 1;
+    const MIGRATIONS: &'static [schema::Migration] = &[schema::unversioned];
+}
+
+/// The canaries, as `GET /api/canary` shows them
+#[derive(Debug, Clone, Serialize)]
+pub struct CanaryStatus {
+    pub enabled: bool,
+    pub source: Source,
+    pub upload: bool,
+    pub running: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_run: Option<DateTime<Local>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_pass: Option<DateTime<Local>>,
+    /// newest first
+    pub runs: Vec<CanaryRun>,
+}
+
+/// The config of the canaries and their runs
+#[derive(Default)]
+pub struct Canaries {
+    pub config: Config,
+    path: Option<PathBuf>,
+    runs: Mutex<VecDeque<CanaryRun>>,
+    next_run: Mutex<Option<DateTime<Local>>>,
+    running: AtomicBool,
+}
+
+impl Canaries {
+    /// the canaries with the runs kept at `path`, quarantining the lines that can't be parsed
+    pub fn open(config: Config, path: Option<&Path>) -> anyhow::Result<Self> {
+        let mut runs = VecDeque::new();
+        if let Some(path) = path {
+            let (entries, report) = schema::read_lines::<CanaryRun>(path)?;
+            info!("{}", report);
+            runs.extend(entries);
+            while runs.len() > KEEP {
+                runs.pop_front();
+            }
+        }
+        Ok(Self {
+            config,
+            path: path.map(Path::to_path_buf),
+            runs: Mutex::new(runs),
+            ..Default::default()
+        })
+    }
+
+    /// keep the run, returning the outcome of the last one that passed or failed before it
+    fn record(&self, run: CanaryRun) -> Option<Outcome> {
+        if let Err(e) = self.write(&run) {
+            warn!("cannot write the canary runs: {}", e);
+        }
+        let mut runs = self.runs.lock().unwrap();
+        let previous = runs
+            .iter()
+            .rev()
+            .map(|r| r.outcome)
+            .find(|o| *o != Outcome::Yielded);
+        runs.push_back(run);
+        if runs.len() > KEEP {
+            runs.pop_front();
+        }
+        previous
+    }
+
+    fn write(&self, run: &CanaryRun) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        writeln!(file, "{}", schema::to_string(run)?)?;
+        Ok(())
+    }
+
+    pub fn last_pass(&self) -> Option<DateTime<Local>> {
+        let runs = self.runs.lock().unwrap();
+        runs.iter()
+            .rev()
+            .find(|r| r.outcome == Outcome::Pass)
+            .map(|r| r.finished_at)
+    }
+
+    pub fn status(&self) -> CanaryStatus {
+        CanaryStatus {
+            enabled: self.config.schedule.is_some(),
+            source: self.config.source,
+            upload: self.config.upload,
+            running: self.running.load(Ordering::SeqCst),
+            next_run: *self.next_run.lock().unwrap(),
+            last_pass: self.last_pass(),
+            runs: self.runs.lock().unwrap().iter().rev().cloned().collect(),
+        }
+    }
+
+    /// the time of the last pass, in the text format of Prometheus
+    pub fn metrics(&self) -> String {
+        let Some(last_pass) = self.last_pass() else {
+            return String::new();
+        };
+        let name = "record_screen_canary_last_success_timestamp_seconds";
+        format!(
+            "# HELP {} When a canary recording last passed.\n# TYPE {} gauge\n{} {}\n",
+            name,
+            name,
+            name,
+            last_pass.timestamp()
+        )
+    }
+}
+
+/// Unsets the running canary once it is over
+struct Running<'a>(&'a AtomicBool);
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+/// nothing is recorded nor compressed
+pub fn idle(state: &RecordingState) -> bool {
+    matches!(
+        state,
+        RecordingState::Waiting
+            | RecordingState::Done { .. }
+            | RecordingState::Failed { .. }
+            | RecordingState::Cancelled { .. }
+    )
+}
+
+/// run the canaries on their schedule
+pub async fn watch(mx: Arc<Recorder>) {
+    let Some(schedule) = mx.canaries.config.schedule.clone() else {
+        return;
+    };
+    let mut next = schedule.next(Local::now());
+    loop {
+        *mx.canaries.next_run.lock().unwrap() = next;
+        let Some(at) = next else {
+            warn!("the canary schedule has no next run");
+            return;
+        };
+        // the wall clock is looked at again and again, it may jump meanwhile
+        while Local::now() < at {
+            let left = (at - Local::now()).to_std().unwrap_or_default();
+            tokio::time::sleep(left.min(WATCH_EVERY)).await;
+        }
+        next = match run(mx.clone()).await {
+            Ok(_) => schedule.next(Local::now()),
+            Err(e) => {
+                info!("canary postponed by {:?}: {}", RETRY, e);
+                let retry = Local::now() + ChronoDuration::seconds(RETRY.as_secs() as i64);
+                schedule
+                    .next(Local::now())
+                    .map(|scheduled| scheduled.min(retry))
+            }
+        };
+    }
+}
+
+/// run a canary now, a failure is a run that failed rather than an error
+pub async fn run(mx: Arc<Recorder>) -> Result<CanaryRun, Error> {
+    let canaries = &mx.canaries;
+    let mut live = mx.subscribe(None).await.live;
+    if !idle(&*mx.lock().await) {
+        return Err(Error::Busy);
+    }
+    if canaries.running.swap(true, Ordering::SeqCst) {
+        return Err(Error::Running);
+    }
+    let _running = Running(&canaries.running);
+    let source = match canaries.config.source {
+        Source::Screen if !mx.policy.status(Local::now()).allowed => {
+            info!("the canary records the test source, the screen is outside the windows");
+            Source::Test
+        }
+        source => source,
+    };
+    let cancel = CancellationToken::new();
+    // a recording starting meanwhile interrupts the canary
+    let watcher = tokio::spawn({
+        let cancel = cancel.clone();
+        async move {
+            loop {
+                match live.recv().await {
+                    Ok(event) => match event.kind {
+                        EventKind::State { state } if !idle(&state) => break,
+                        _ => {}
+                    },
+                    Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => return,
+                }
+            }
+            info!("a recording started, the canary yields");
+            cancel.cancel();
+        }
+    });
+    let started_at = Local::now();
+    info!("canary of the {:?} source", source);
+    let attempt = attempt(&mx, source, &cancel).await;
+    watcher.abort();
+    let outcome = match (&attempt.failure, cancel.is_cancelled()) {
+        (_, true) => Outcome::Yielded,
+        (Some(_), false) => Outcome::Fail,
+        (None, false) => Outcome::Pass,
+    };
+    let run = CanaryRun {
+        started_at,
+        finished_at: Local::now(),
+        source,
+        outcome,
+        steps: attempt.steps,
+        failed_step: attempt
+            .failure
+            .as_ref()
+            .map(|_| attempt.current.to_string()),
+        reason: attempt.failure,
+        content_warnings: attempt.content_warnings,
+    };
+    match outcome {
+        Outcome::Pass => info!("canary passed"),
+        Outcome::Yielded => info!("canary yielded to a recording"),
+        Outcome::Fail => warn!(
+            "canary failed at {}: {}",
+            attempt.current,
+            run.reason.as_deref().unwrap_or_default()
+        ),
+    }
+    let previous = canaries.record(run.clone());
+    if outcome == Outcome::Fail && previous == Some(Outcome::Pass) {
+        mx.events.publish(EventKind::Notice {
+            message: format!(
+                "the canary recording failed at {}, the recorder may be broken: {}",
+                attempt.current,
+                run.reason.as_deref().unwrap_or_default()
+            ),
+        });
+    }
+    Ok(run)
+}
+
+/// What a canary did so far
+#[derive(Default)]
+struct Attempt {
+    steps: Vec<Step>,
+    /// the step running, or the one that failed
+    current: &'static str,
+    failure: Option<String>,
+    content_warnings: Vec<ContentWarning>,
+}
+
+impl Attempt {
+    fn begin(&mut self, name: &'static str, cancel: &CancellationToken) -> anyhow::Result<Instant> {
+        self.current = name;
+        ensure!(!cancel.is_cancelled(), "yielded to a recording");
+        Ok(Instant::now())
+    }
+
+    fn end(&mut self, started: Instant) {
+        self.steps.push(Step {
+            name: self.current.to_string(),
+            secs: started.elapsed().as_secs_f64(),
+        });
+    }
+}
+
+/// every step, the files removed whatever the outcome
+async fn attempt(mx: &Recorder, source: Source, cancel: &CancellationToken) -> Attempt {
+    let mut attempt = Attempt::default();
+    let dir = match recordings::output_dir() {
+        Ok(dir) => dir.join(".record-screen-canary"),
+        Err(e) => {
+            attempt.current = "prepare";
+            attempt.failure = Some(e.to_string());
+            return attempt;
+        }
+    };
+    if let Err(e) = steps(mx, source, &dir, cancel, &mut attempt).await {
+        attempt.failure = Some(format!("{:#}", e));
+    }
+    if let Err(e) = tokio::fs::remove_dir_all(&dir).await {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            warn!("cannot remove the canary in {}: {}", dir.display(), e);
+        }
+    }
+    attempt
+}
+
+async fn steps(
+    mx: &Recorder,
+    source: Source,
+    dir: &Path,
+    cancel: &CancellationToken,
+    attempt: &mut Attempt,
+) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(dir).await?;
+    let raw = dir.join("canary.mp4").to_string_lossy().to_string();
+    let output = dir.join("canary.compressed.mp4");
+
+    let started = attempt.begin("capture", cancel)?;
+    capture(mx, source, &raw, cancel).await?;
+    attempt.end(started);
+
+    let started = attempt.begin("compress", cancel)?;
+    compress(mx, &raw, &output.to_string_lossy(), cancel).await?;
+    attempt.end(started);
+
+    let started = attempt.begin("verify_content", cancel)?;
+    let warnings = content_check::check(&output, &mx.content_check, &mx.children).await?;
+    if source == Source::Test && !warnings.is_empty() {
+        bail!("the test source was found {:?}", warnings);
+    }
+    attempt.content_warnings = warnings;
+    attempt.end(started);
+
+    let started = attempt.begin("checksums", cancel)?;
+    let manifest = checksums::write_manifest(&output, mx.throttle.clone()).await?;
+    let checked = output.clone();
+    let verification =
+        tokio::task::spawn_blocking(move || checksums::verify(&checked, &manifest)).await??;
+    ensure!(verification.ok, "the checksums don't match the recording");
+    attempt.end(started);
+
+    if mx.canaries.config.upload {
+        let started = attempt.begin("upload", cancel)?;
+        upload(mx, &output).await?;
+        attempt.end(started);
+    }
+    Ok(())
+}
+
+async fn capture(
+    mx: &Recorder,
+    source: Source,
+    out: &str,
+    cancel: &CancellationToken,
+) -> anyhow::Result<()> {
+    match source {
+        Source::Test => {
+            let duration = DURATION.as_secs().to_string();
+            // read at its rate, as a capture would come
+            let builder = FfmpegBuilder::new()
+                .stderr(Stdio::piped())
+                .option(Parameter::Single("y"))
+                .input(
+                    File::new(TEST_SOURCE)
+                        .option(Parameter::Single("re"))
+                        .option(Parameter::KeyValue("f", "lavfi")),
+                )
+                .input(
+                    File::new(TEST_TONE)
+                        .option(Parameter::Single("re"))
+                        .option(Parameter::KeyValue("f", "lavfi")),
+                )
+                .option2(Parameter::KeyValue("t", &duration))
+                .option2(Parameter::codec("v", "libx264"))
+                .option2(Parameter::KeyValue("preset", "ultrafast"))
+                .option2(Parameter::codec("a", "aac"))
+                .output(File::new(out));
+            let summary = wait(mx, builder.run().await?, out, cancel).await?;
+            ensure!(summary.success(), "{}", failure("the capture", &summary));
+        }
+        Source::Screen => {
+            let ffmpeg = pipeline::spawn_capture(
+                &RecordingOptions::default(),
+                false,
+                out,
+                None,
+                None,
+                None,
+                None,
+            )
+            .await?;
+            // the capture ends like a stopped recording, interrupted
+            let stop = cancel.child_token();
+            let stopping = tokio::spawn({
+                let stop = stop.clone();
+                async move {
+                    tokio::time::sleep(DURATION).await;
+                    stop.cancel();
+                }
+            });
+            let summary = wait(mx, ffmpeg, out, &stop).await;
+            stopping.abort();
+            let summary = summary?;
+            ensure!(
+                summary.cancelled,
+                "{}",
+                failure("the capture ended early", &summary)
+            );
+            ensure!(
+                Path::new(out).is_file(),
+                "the capture wrote nothing: {}",
+                failure("the capture", &summary)
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn compress(
+    mx: &Recorder,
+    input: &str,
+    output: &str,
+    cancel: &CancellationToken,
+) -> anyhow::Result<()> {
+    let encoder = EncoderParams::resolve(ContentKind::Auto);
+    let mut builder = FfmpegBuilder::new()
+        .stderr(Stdio::piped())
+        .input(File::new(input))
+        .option2(Parameter::codec("v", &encoder.codec));
+    for (key, value) in &encoder.options {
+        builder = builder.option2(Parameter::KeyValue(key, value));
+    }
+    let builder = builder.output(File::new(output));
+    let summary = wait(mx, builder.run().await?, output, cancel).await?;
+    ensure!(
+        summary.success(),
+        "{}",
+        failure("the compression", &summary)
+    );
+    Ok(())
+}
+
+/// put the recording into the storage, check it is all there and delete it
+async fn upload(mx: &Recorder, file: &Path) -> anyhow::Result<()> {
+    let name = format!("record-screen-canary-{}.mp4", Local::now().timestamp());
+    let size = tokio::fs::metadata(file).await?.len();
+    let reader: storage::Reader = Box::pin(tokio::fs::File::open(file).await?);
+    let put = mx.storage.put(&name, reader).await;
+    let stat = match put {
+        Ok(_) => mx.storage.stat(&name).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = mx.storage.delete(&name).await {
+        warn!("cannot delete the canary {} from the storage: {}", name, e);
+    }
+    match stat? {
+        Some(object) => ensure!(
+            object.size == size,
+            "the storage has {} bytes of {}",
+            object.size,
+            size
+        ),
+        None => bail!("the storage has no {} after putting it", name),
+    }
+    Ok(())
+}
+
+/// wait for the canary ffmpeg, interrupted once `cancel` is
+async fn wait(
+    mx: &Recorder,
+    ffmpeg: Ffmpeg,
+    out: &str,
+    cancel: &CancellationToken,
+) -> anyhow::Result<CompletionSummary> {
+    let pid = ffmpeg.id();
+    mx.children
+        .register(pid, ChildRole::Canary, vec![out.to_string()]);
+    let summary = ffmpeg.wait_with_progress_or_cancel(cancel, |_| {}).await;
+    mx.children.unregister(pid);
+    Ok(summary?)
+}
+
+fn failure(what: &str, summary: &CompletionSummary) -> String {
+    let tail = &summary.stderr_tail;
+    format!(
+        "{} exited with {}: {}",
+        what,
+        summary.exit_status,
+        tail[tail.len().saturating_sub(5)..].join("\n")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    /// the canaries write in the same directory, one runs at a time
+    static ONE_AT_A_TIME: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    fn ffmpeg_installed() -> bool {
+        let installed = std::process::Command::new("ffmpeg")
+            .arg("-version")
+            .output()
+            .is_ok();
+        if !installed {
+            eprintln!("ffmpeg is not installed, skipped");
+        }
+        installed
+    }
+
+    /// a recorder with canaries of `config` kept in a file of their own
+    fn recorder(name: &str, config: Config) -> (Recorder, PathBuf) {
+        let path = recordings::test_output_dir().join(format!("{}.jsonl", name));
+        let _ = std::fs::remove_file(&path);
+        let canaries = Canaries::open(config, Some(&path)).unwrap();
+        (Recorder::new().with_canaries(canaries), path)
+    }
+
+    fn canary_dir() -> PathBuf {
+        recordings::test_output_dir().join(".record-screen-canary")
+    }
+
+    #[test]
+    fn the_schedule_runs_on_its_days_at_its_time() {
+        let schedule: Schedule = "mon-fri 03:30".parse().unwrap();
+        // a Friday
+        let friday = Local.with_ymd_and_hms(2026, 10, 16, 2, 0, 0).unwrap();
+        let next = schedule.next(friday).unwrap();
+        assert_eq!(
+            next,
+            Local.with_ymd_and_hms(2026, 10, 16, 3, 30, 0).unwrap()
+        );
+        // past it, the next is on Monday
+        let next = schedule.next(next).unwrap();
+        assert_eq!(
+            next,
+            Local.with_ymd_and_hms(2026, 10, 19, 3, 30, 0).unwrap()
+        );
+        assert!(DEFAULT_SCHEDULE.parse::<Schedule>().is_ok());
+        assert!("03:30".parse::<Schedule>().is_err());
+        assert!("daily 25:00".parse::<Schedule>().is_err());
+    }
+
+    #[tokio::test]
+    async fn a_canary_waits_for_the_recorder_to_be_idle() {
+        let (mx, _) = recorder("canaries-busy", Config::default());
+        let mx = Arc::new(mx);
+        mx.set(
+            serde_json::from_value(serde_json::json!({
+                "type": "Started",
+                "process_id": 1,
+                "file": "busy.mkv",
+                "started_at": Local::now(),
+            }))
+            .unwrap(),
+        )
+        .await;
+        assert_eq!(run(mx.clone()).await.unwrap_err(), Error::Busy);
+        assert!(mx.canaries.status().runs.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_canary_of_the_test_source_passes_and_leaves_nothing() {
+        if !ffmpeg_installed() {
+            return;
+        }
+        let _one = ONE_AT_A_TIME.lock().await;
+        let config = Config {
+            upload: true,
+            ..Default::default()
+        };
+        let (mx, path) = recorder("canaries-pass", config);
+        let mx = Arc::new(mx);
+        let canary = run(mx.clone()).await.unwrap();
+        assert_eq!(canary.outcome, Outcome::Pass, "{:?}", canary.reason);
+        assert_eq!(canary.source, Source::Test);
+        let steps: Vec<&str> = canary.steps.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(
+            steps,
+            [
+                "capture",
+                "compress",
+                "verify_content",
+                "checksums",
+                "upload"
+            ]
+        );
+        assert!(canary.content_warnings.is_empty());
+        assert!(!canary_dir().exists());
+        assert!(mx
+            .storage
+            .list()
+            .await
+            .unwrap()
+            .iter()
+            .all(|o| !o.name.contains("canary")));
+
+        // kept, listed and measured
+        let (kept, _) = schema::read_lines::<CanaryRun>(&path).unwrap();
+        assert_eq!(kept.len(), 1);
+        let status = mx.canaries.status();
+        assert_eq!(status.runs.len(), 1);
+        assert_eq!(status.last_pass, Some(canary.finished_at));
+        assert!(mx
+            .canaries
+            .metrics()
+            .contains(&format!(" {}\n", canary.finished_at.timestamp())));
+    }
+
+    #[tokio::test]
+    async fn a_canary_failing_after_a_pass_is_told() {
+        let _one = ONE_AT_A_TIME.lock().await;
+        let passed = CanaryRun {
+            started_at: Local::now(),
+            finished_at: Local::now(),
+            source: Source::Test,
+            outcome: Outcome::Pass,
+            steps: vec![],
+            failed_step: None,
+            reason: None,
+            content_warnings: vec![],
+        };
+        let path = recordings::test_output_dir().join("canaries-fail.jsonl");
+        std::fs::write(&path, format!("{}\n", schema::to_string(&passed).unwrap())).unwrap();
+        let config = Config {
+            upload: true,
+            ..Default::default()
+        };
+        let canaries = Canaries::open(config, Some(&path)).unwrap();
+        // a storage that can't be written, in a directory that is a file
+        let broken = recordings::test_output_dir().join("canaries-broken-storage");
+        std::fs::write(&broken, b"").unwrap();
+        let storage = storage::Storage::new(storage::LocalStorage::new(Some(broken)));
+        let mx = Arc::new(
+            Recorder::new()
+                .with_canaries(canaries)
+                .with_storage(storage),
+        );
+        let mut events = mx.events.subscribe(None, &RecordingState::Waiting).live;
+
+        let canary = run(mx.clone()).await.unwrap();
+        assert_eq!(canary.outcome, Outcome::Fail);
+        // without ffmpeg, it fails earlier
+        let failed_at = match ffmpeg_installed() {
+            true => "upload",
+            false => "capture",
+        };
+        assert_eq!(canary.failed_step.as_deref(), Some(failed_at));
+        assert!(canary.reason.is_some());
+        assert!(!canary_dir().exists());
+        let notice = loop {
+            match events.try_recv().unwrap().kind {
+                EventKind::Notice { message } => break message,
+                _ => continue,
+            }
+        };
+        assert!(notice.contains(failed_at), "{}", notice);
+
+        // a second failure is no news
+        run(mx.clone()).await.unwrap();
+        while let Ok(event) = events.try_recv() {
+            assert!(!matches!(event.kind, EventKind::Notice { .. }));
+        }
+        assert_eq!(mx.canaries.status().runs.len(), 3);
+        assert_eq!(mx.canaries.last_pass(), Some(passed.finished_at));
+    }
+}
@@ -0,0 +1,317 @@
+//! Which way of grabbing the screen costs the least CPU on this machine
+//!
+//! The analysis runs the same short capture once per [CapturePath]: x11grab with its MIT-SHM
+//! path turned off and on, and kmsgrab when there is a DRM device. The CPU time of each ffmpeg
+//! is the growth of utime + stime in `/proc/<pid>/stat` while it runs; its speed is the last one
+//! it reported. The result is kept next to the history, and the recordings that don't say
+//! whether to use shared memory default to what the faster x11grab run did.
+use crate::display;
+use crate::ffmpeg::{FfmpegBuilder, Parameter};
+use crate::recordings;
+use crate::schema::{self, Versioned};
+use crate::service::VIDEO_SIZE;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::*;
+
+/// seconds of every capture of the analysis
+pub const SECONDS: u64 = 5;
+/// ticks of utime and stime per second, the same on every Linux
+const USER_HZ: f64 = 100.0;
+/// a capture slower than this did not keep up with the screen
+const REAL_TIME: f64 = 0.95;
+const DRM_DEVICE: &str = "/dev/dri/card0";
+const SAMPLE_EVERY: Duration = Duration::from_millis(200);
+
+/// A way of grabbing the screen
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CapturePath {
+    /// x11grab copying every frame through the X protocol, `-use_shm 0`
+    X11grab,
+    /// x11grab reading the frames from shared memory, `-use_shm 1`
+    X11grabShm,
+    /// the framebuffer of the DRM device, without X
+    Kmsgrab,
+}
+
+impl CapturePath {
+    /// the key of the latency estimate of the captures going this way, see
+    /// [crate::latency::configuration]
+    pub fn configuration(&self) -> String {
+        match self {
+            Self::X11grab => "x11grab",
+            Self::X11grabShm => "x11grab_shm",
+            Self::Kmsgrab => "kmsgrab",
+        }
+        .to_string()
+    }
+
+    fn input(&self) -> Vec<(&'static str, String)> {
+        let x11grab = |shm: &str| {
+            vec![
+                ("f", "x11grab".to_string()),
+                ("use_shm", shm.to_string()),
+                ("video_size", VIDEO_SIZE.to_string()),
+                ("framerate", "25".to_string()),
+                ("i", display::name().to_string()),
+            ]
+        };
+        match self {
+            Self::X11grab => x11grab("0"),
+            Self::X11grabShm => x11grab("1"),
+            Self::Kmsgrab => vec![
+                ("device", DRM_DEVICE.to_string()),
+                ("f", "kmsgrab".to_string()),
+                ("framerate", "25".to_string()),
+                ("i", "-".to_string()),
+                ("vf", "hwdownload,format=bgr0".to_string()),
+            ],
+        }
+    }
+}
+
+/// The CPU time a process has used so far, in clock ticks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CpuTicks {
+    pub utime: u64,
+    pub stime: u64,
+}
+
+impl CpuTicks {
+    /// from the contents of `/proc/<pid>/stat`
+    pub fn parse(stat: &str) -> Option<Self> {
+        // the command in parentheses may contain spaces, the fields are after it
+        let fields: Vec<&str> = stat
+            .get(stat.rfind(')')? + 1..)?
+            .split_whitespace()
+            .collect();
+        // utime and stime are the 14th and 15th fields, the state being the 3rd
+        Some(Self {
+            utime: fields.get(11)?.parse().ok()?,
+            stime: fields.get(12)?.parse().ok()?,
+        })
+    }
+
+    pub fn read(pid: u32) -> Option<Self> {
+        Self::parse(&std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?)
+    }
+
+    /// seconds of CPU used since `earlier`
+    pub fn seconds_since(&self, earlier: &CpuTicks) -> f64 {
+        let ticks = |t: &CpuTicks| t.utime + t.stime;
+        ticks(self).saturating_sub(ticks(earlier)) as f64 / USER_HZ
+    }
+}
+
+/// How a capture went
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Measurement {
+    pub path: CapturePath,
+    /// CPU seconds of ffmpeg for the whole capture
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_seconds: Option<f64>,
+    /// relative to real time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speed: Option<f64>,
+    /// milliseconds from the spawn to the first frame, see [crate::latency]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first_frame_ms: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The measurements and what they recommend
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Analysis {
+    pub at: DateTime<Local>,
+    pub measurements: Vec<Measurement>,
+    /// the path that kept up with the screen using the least CPU
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fastest: Option<CapturePath>,
+    /// what x11grab captures default to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub use_shm: Option<bool>,
+}
+
+/// the path that kept up with the screen using the least CPU, among `candidates`
+pub fn fastest(measurements: &[Measurement], candidates: &[CapturePath]) -> Option<CapturePath> {
+    let usable: Vec<(&Measurement, f64)> = measurements
+        .iter()
+        .filter(|m| m.error.is_none() && candidates.contains(&m.path))
+        .filter_map(|m| Some((m, m.cpu_seconds?)))
+        .collect();
+    // a path falling behind only wins when all of them do
+    let keeping_up: Vec<&(&Measurement, f64)> = usable
+        .iter()
+        .filter(|(m, _)| m.speed.is_some_and(|s| s >= REAL_TIME))
+        .collect();
+    let pool = match keeping_up.is_empty() {
+        true => usable.iter().collect(),
+        false => keeping_up,
+    };
+    pool.into_iter()
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(m, _)| m.path)
+}
+
+impl Analysis {
+    pub fn new(measurements: Vec<Measurement>) -> Self {
+        let all = [
+            CapturePath::X11grab,
+            CapturePath::X11grabShm,
+            CapturePath::Kmsgrab,
+        ];
+        let fastest_x11grab = fastest(
+            &measurements,
+            &[CapturePath::X11grab, CapturePath::X11grabShm],
+        );
+        Self {
+            at: Local::now(),
+            fastest: fastest(&measurements, &all),
+            use_shm: fastest_x11grab.map(|path| path == CapturePath::X11grabShm),
+            measurements,
+        }
+    }
+}
+
+/// the paths this machine might capture with
+pub fn available() -> Vec<CapturePath> {
+    let mut paths = vec![CapturePath::X11grab, CapturePath::X11grabShm];
+    if Path::new(DRM_DEVICE).exists() {
+        paths.push(CapturePath::Kmsgrab);
+    }
+    paths
+}
+
+/// capture [SECONDS] with the path, discarding the frames
+pub async fn measure(path: CapturePath) -> Measurement {
+    let failed = |error: String| Measurement {
+        path,
+        cpu_seconds: None,
+        speed: None,
+        first_frame_ms: None,
+        error: Some(error),
+    };
+    let input = path.input();
+    let seconds = SECONDS.to_string();
+    let mut builder = FfmpegBuilder::new().stderr(Stdio::piped());
+    for (key, value) in &input {
+        builder = builder.option(Parameter::KeyValue(key, value));
+    }
+    builder = builder
+        .option(Parameter::KeyValue("t", &seconds))
+        .option(Parameter::KeyValue("f", "null"))
+        .output(crate::ffmpeg::File::new("-"));
+    let spawned = std::time::Instant::now();
+    let ffmpeg = match builder.run().await {
+        Ok(ffmpeg) => ffmpeg,
+        Err(e) => return failed(e.to_string()),
+    };
+    let pid = ffmpeg.id();
+    let first = CpuTicks::read(pid);
+    // the last sample before the process is gone
+    let last = Arc::new(Mutex::new(first));
+    let done = CancellationToken::new();
+    let sampler = tokio::spawn({
+        let (last, done) = (last.clone(), done.clone());
+        async move {
+            while !done.is_cancelled() {
+                if let Some(ticks) = CpuTicks::read(pid) {
+                    *last.lock().unwrap() = Some(ticks);
+                }
+                tokio::time::sleep(SAMPLE_EVERY).await;
+            }
+        }
+    });
+    // stopped should it ignore -t
+    let cancel = CancellationToken::new();
+    let deadline = tokio::spawn({
+        let cancel = cancel.clone();
+        async move {
+            tokio::time::sleep(Duration::from_secs(SECONDS * 2 + 5)).await;
+            cancel.cancel();
+        }
+    });
+    // the final report may leave the speed out
+    let mut speed = None;
+    let mut first_frame_ms = None;
+    let summary = ffmpeg
+        .wait_with_progress_or_cancel(&cancel, |p| {
+            speed = p.speed.or(speed);
+            // the first frame came as much before the report as ffmpeg processed since
+            if first_frame_ms.is_none() && p.frame.unwrap_or_default() >= 1 {
+                let processed = p.out_time.unwrap_or_default();
+                first_frame_ms =
+                    Some(spawned.elapsed().saturating_sub(processed).as_secs_f64() * 1000.0);
+            }
+        })
+        .await;
+    done.cancel();
+    deadline.abort();
+    _ = sampler.await;
+    let summary = match summary {
+        Ok(summary) => summary,
+        Err(e) => return failed(e.to_string()),
+    };
+    if !summary.success() && !summary.cancelled {
+        return failed(summary.stderr_tail.join("\n"));
+    }
+    let last = *last.lock().unwrap();
+    Measurement {
+        path,
+        cpu_seconds: first
+            .zip(last)
+            .map(|(first, last)| last.seconds_since(&first)),
+        speed,
+        first_frame_ms,
+        error: None,
+    }
+}
+
+/// measure every available path, one after the other
+pub async fn analyze() -> Analysis {
+    let mut measurements = vec![];
+    for path in available() {
+        info!("measuring {:?} for {} s", path, SECONDS);
+        measurements.push(measure(path).await);
+    }
+    Analysis::new(measurements)
+}
+
+impl Versioned for Analysis {
+    const KIND: &'static str = "capture path analyses";
+    const VERSION: u32 = 1;
+    const MIGRATIONS: &'static [schema::Migration] = &[schema::unversioned];
+}
+
+fn stored_path() -> anyhow::Result<PathBuf> {
+    Ok(recordings::output_dir()?.join(".record-screen-capture-paths.json"))
+}
+
+/// keep the analysis for the next recordings
+pub fn store(analysis: &Analysis) -> anyhow::Result<()> {
+    std::fs::write(stored_path()?, schema::to_vec_pretty(analysis)?)?;
+    Ok(())
+}
+
+/// the last analysis of this machine, one that can't be read is quarantined
+pub fn load() -> Option<Analysis> {
+    let path = stored_path().ok()?;
+    let json = std::fs::read(&path).ok()?;
+    match schema::from_slice(&json) {
+        Ok((analysis, _)) => Some(analysis),
+        Err(e) => {
+            warn!("ignoring the capture path analysis: {}", e);
+            if let Err(e) = schema::quarantine(&path) {
+                warn!("cannot quarantine {}: {}", path.display(), e);
+            }
+            None
+        }
+    }
+}
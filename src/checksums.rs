@@ -0,0 +1,136 @@
+//! Checksum manifests of the finished recordings
+//!
+//! The manifest holds the SHA-256 of the whole file and of every [CHUNK_SIZE] chunk, so that a
+//! corrupted copy can be told apart from a good one, and the damaged byte ranges located.
+//! It's written next to the recording as `<recording>.sha256.json`.
+use crate::schema::{self, Versioned};
+use crate::throttle::{Category, Throttle, ThrottledReader};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub const CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub size: u64,
+    pub sha256: String,
+    pub chunk_size: u64,
+    /// SHA-256 of every chunk, in order
+    pub chunks: Vec<String>,
+}
+
+impl Versioned for Manifest {
+    const KIND: &'static str = "checksum manifests";
+    const VERSION: u32 = 1;
+    const MIGRATIONS: &'static [schema::Migration] = &[schema::unversioned];
+}
+
+/// A chunk whose hash does not match the manifest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkMismatch {
+    pub index: usize,
+    /// first byte of the chunk
+    pub start: u64,
+    /// last byte of the chunk, inclusive
+    pub end: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Verification {
+    pub ok: bool,
+    pub expected_size: u64,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mismatched: Vec<ChunkMismatch>,
+}
+
+/// where the manifest of the file is kept
+pub fn manifest_path(file: &Path) -> PathBuf {
+    let mut path = file.as_os_str().to_owned();
+    path.push(".sha256.json");
+    PathBuf::from(path)
+}
+
+/// hash the file
+pub fn compute(file: &Path) -> anyhow::Result<Manifest> {
+    compute_from(std::fs::File::open(file)?)
+}
+
+/// hash what the reader reads
+pub fn compute_from(mut reader: impl Read) -> anyhow::Result<Manifest> {
+    let mut whole = Sha256::new();
+    let mut chunks = vec![];
+    let mut size = 0;
+    let mut buf = vec![0u8; 1024 * 1024];
+    loop {
+        let mut chunk = Sha256::new();
+        let mut in_chunk = 0;
+        while in_chunk < CHUNK_SIZE {
+            let want = buf.len().min((CHUNK_SIZE - in_chunk) as usize);
+            let n = reader.read(&mut buf[..want])?;
+            if n == 0 {
+                break;
+            }
+            whole.update(&buf[..n]);
+            chunk.update(&buf[..n]);
+            in_chunk += n as u64;
+            size += n as u64;
+        }
+        if in_chunk == 0 {
+            break;
+        }
+        chunks.push(format!("{:x}", chunk.finalize()));
+        if in_chunk < CHUNK_SIZE {
+            break;
+        }
+    }
+    Ok(Manifest {
+        size,
+        sha256: format!("{:x}", whole.finalize()),
+        chunk_size: CHUNK_SIZE,
+        chunks,
+    })
+}
+
+/// hash the file and write its manifest, in the background at the rate of the checksums
+pub async fn write_manifest(file: &Path, throttle: Arc<Throttle>) -> anyhow::Result<Manifest> {
+    let file = file.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let reader = std::fs::File::open(&file)?;
+        let manifest = compute_from(ThrottledReader::new(reader, throttle, Category::Checksum))?;
+        std::fs::write(manifest_path(&file), schema::to_vec_pretty(&manifest)?)?;
+        Ok(manifest)
+    })
+    .await?
+}
+
+pub fn read_manifest(file: &Path) -> anyhow::Result<Option<Manifest>> {
+    match std::fs::read(manifest_path(file)) {
+        Ok(json) => Ok(Some(schema::from_slice(&json)?.0)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// compare the file against the manifest
+pub fn verify(file: &Path, manifest: &Manifest) -> anyhow::Result<Verification> {
+    let actual = compute(file)?;
+    let chunks = manifest.chunks.len().max(actual.chunks.len());
+    let mismatched = (0..chunks)
+        .filter(|&i| manifest.chunks.get(i) != actual.chunks.get(i))
+        .map(|index| {
+            let start = index as u64 * manifest.chunk_size;
+            let end = (start + manifest.chunk_size).min(manifest.size.max(actual.size)) - 1;
+            ChunkMismatch { index, start, end }
+        })
+        .collect();
+    Ok(Verification {
+        ok: actual.sha256 == manifest.sha256,
+        expected_size: manifest.size,
+        size: actual.size,
+        mismatched,
+    })
+}
@@ -0,0 +1,256 @@
+//! Splitting a long capture into chunks of a fixed length
+//!
+//! With `segment_seconds`, the capture is written by the segment muxer of ffmpeg, as
+//! `<name>_000.<ext>`, `<name>_001.<ext>`... of about that length each, their timestamps from
+//! zero; see [pattern]. A chunk is complete once the next one was started: [follow] looks at the
+//! directory while the capture runs, the `file` of the Started state is the chunk being written and
+//! [Chunks::done] the complete ones. A capture that rolls over, for a quality change, a failover or
+//! a resume, goes on with the chunks of its next segment, `<name>.part001_000.<ext>`...
+//!
+//! Once stopped, every chunk is compressed on its own, the results being chunks as well, unless
+//! `concat_segments` asks for them to be joined first, as the segments of a capture rolled over
+//! are, into one result. The results of a recording in chunks are listed in the
+//! `<name>.chunks.json` sidecar, by which the listing of the recordings groups them.
+use crate::container;
+use crate::schema::{self, Versioned};
+use crate::service::{Recorder, RecordingOptions, RecordingState};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::*;
+
+/// the shortest chunk
+pub const MIN_SECONDS: u64 = 10;
+/// the longest chunk, a day
+pub const MAX_SECONDS: u64 = 86_400;
+/// how often the directory is looked at for a new chunk
+const POLL: Duration = Duration::from_secs(2);
+/// what stands for the number of the chunk in the pattern
+const NUMBER: &str = "%03d";
+
+/// The chunks of a capture
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Chunks {
+    /// the file of the recording the chunks are of, what it is named after
+    pub capture: String,
+    /// what ffmpeg names the chunks of the capture running, see [pattern]
+    pub pattern: String,
+    /// the chunks complete, in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub done: Vec<String>,
+    /// the times the capture rolled over, the number of the segment written
+    #[serde(default)]
+    pub segment: usize,
+}
+
+impl Chunks {
+    pub fn new(capture: &str) -> Self {
+        Self {
+            capture: capture.to_string(),
+            pattern: pattern(capture),
+            done: vec![],
+            segment: 0,
+        }
+    }
+
+    /// every chunk written, the one being written last
+    pub fn all(&self) -> Vec<String> {
+        let mut all = self.done.clone();
+        for chunk in written(&self.pattern) {
+            if !all.contains(&chunk) {
+                all.push(chunk);
+            }
+        }
+        all
+    }
+
+    /// go on with the chunks of another file, those written so far are complete
+    pub fn roll_over(&mut self, capture: &str) {
+        self.done = self.all();
+        self.pattern = pattern(capture);
+        self.segment += 1;
+    }
+}
+
+/// what the chunks of the capture are named by: `<name>_%03d.<ext>`
+pub fn pattern(capture: &str) -> String {
+    format!(
+        "{}_{}.{}",
+        container::base(capture),
+        NUMBER,
+        container::extension_of(capture)
+    )
+}
+
+/// the path of the chunk of the pattern with the number
+pub fn chunk(pattern: &str, number: usize) -> String {
+    pattern.replacen(NUMBER, &format!("{:03}", number), 1)
+}
+
+/// the compressed chunk, next to the output of the recording
+pub fn compressed(chunk: &str, output: &str) -> String {
+    let name = Path::new(chunk).file_name().unwrap_or_default();
+    let dir = Path::new(output).parent().unwrap_or(Path::new("."));
+    format!(
+        "{}.compressed.{}",
+        container::base(&dir.join(name).to_string_lossy()),
+        container::extension_of(output)
+    )
+}
+
+/// the chunks of the pattern in its directory, in order
+pub fn written(pattern: &str) -> Vec<String> {
+    let path = Path::new(pattern);
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let Some((prefix, suffix)) = name.split_once(NUMBER) else {
+        return vec![];
+    };
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return vec![];
+    };
+    let mut found: Vec<(u64, PathBuf)> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let number = name.strip_prefix(prefix)?.strip_suffix(suffix)?;
+            let digits = number.len() >= 3 && number.bytes().all(|b| b.is_ascii_digit());
+            Some((number.parse().ok().filter(|_| digits)?, entry.path()))
+        })
+        .collect();
+    found.sort();
+    found
+        .into_iter()
+        .map(|(_, path)| path.to_string_lossy().to_string())
+        .collect()
+}
+
+/// the last chunk of the pattern, the file itself when it is not a pattern
+pub fn being_written(file: &str) -> String {
+    written(file).pop().unwrap_or_else(|| file.to_string())
+}
+
+/// whether the chunks the options ask for can be recorded, the field that can't otherwise
+pub fn validate(opt: &RecordingOptions) -> Result<(), (&'static str, String)> {
+    let Some(seconds) = opt.segment_seconds else {
+        if opt.concat_segments {
+            return Err((
+                "concat_segments",
+                "given without segment_seconds".to_string(),
+            ));
+        }
+        return Ok(());
+    };
+    if !(MIN_SECONDS..=MAX_SECONDS).contains(&seconds) {
+        return Err((
+            "segment_seconds",
+            format!("must be within {}..{}", MIN_SECONDS, MAX_SECONDS),
+        ));
+    }
+    if !opt.concat_segments {
+        // both go along the whole recording, not a chunk
+        if opt.audio_resilient {
+            return Err((
+                "segment_seconds",
+                "the resilient audio is mixed in the chunks joined, with concat_segments"
+                    .to_string(),
+            ));
+        }
+        if opt.slate {
+            return Err((
+                "segment_seconds",
+                "the slate opens the chunks joined, with concat_segments".to_string(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// follow the chunks of the capture started at `started_at` until it is no longer Started
+pub async fn follow(mx: Arc<Recorder>, started_at: chrono::DateTime<chrono::Local>) {
+    loop {
+        tokio::time::sleep(POLL).await;
+        let mut state = mx.lock().await;
+        let RecordingState::Started {
+            file,
+            chunks: Some(chunks),
+            started_at: started,
+            ..
+        } = state.recording_mut()
+        else {
+            return;
+        };
+        if *started != started_at {
+            return;
+        }
+        let written = written(&chunks.pattern);
+        let Some((current, complete)) = written.split_last() else {
+            continue;
+        };
+        let complete: Vec<String> = complete
+            .iter()
+            .filter(|chunk| !chunks.done.contains(chunk))
+            .cloned()
+            .collect();
+        if complete.is_empty() && file == current {
+            continue;
+        }
+        for chunk in &complete {
+            info!("chunk {} is complete", chunk);
+        }
+        chunks.done.extend(complete);
+        *file = current.clone();
+        let updated = state.clone();
+        mx.replace(&mut state, updated);
+    }
+}
+
+/// The results of a recording in chunks, see [sidecar_path]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sidecar {
+    /// the file names of the results, in order
+    pub chunks: Vec<String>,
+}
+
+impl Versioned for Sidecar {
+    const KIND: &'static str = "chunk lists";
+    const VERSION: u32 = 1;
+    const MIGRATIONS: &'static [schema::Migration] = &[schema::unversioned];
+}
+
+/// path of the sidecar listing the results of the recording whose first chunk is `first`,
+/// `<name>.chunks.json`
+pub fn sidecar_path(first: &str) -> PathBuf {
+    let base = container::base(first);
+    let name = match base.rsplit_once('_') {
+        Some((name, number)) if number.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => base,
+    };
+    PathBuf::from(format!("{}.chunks.json", name))
+}
+
+/// write the sidecar of the results, in the directory of the first one
+pub async fn write_sidecar(first: &str, results: &[String]) {
+    let Some(dir) = results.first().and_then(|first| Path::new(first).parent()) else {
+        return;
+    };
+    let name = Path::new(first).file_name().unwrap_or_default();
+    let path = sidecar_path(&dir.join(name).to_string_lossy());
+    let sidecar = Sidecar {
+        chunks: results
+            .iter()
+            .filter_map(|file| Path::new(file).file_name())
+            .map(|name| name.to_string_lossy().to_string())
+            .collect(),
+    };
+    let json = schema::to_vec_pretty(&sidecar).expect("chunks json");
+    if let Err(e) = tokio::fs::write(&path, json).await {
+        warn!("cannot write {}: {}", path.display(), e);
+    }
+}
+
+/// whether the object is the sidecar of a recording in chunks
+pub fn is_sidecar(name: &str) -> bool {
+    name.ends_with(".chunks.json")
+}
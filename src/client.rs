@@ -0,0 +1,498 @@
+//! Typed client for the HTTP API of the server
+//!
+//! Requests and responses are the serde types of [crate::service], so the client and the
+//! server can never disagree about the wire format.
+//!
+//! ```no_run
+//! use futures::StreamExt;
+//! use record_screen::client::RecordScreenClient;
+//! use record_screen::service::RecordingOptions;
+//!
+//! #[tokio::main]
+//! async fn main() {
+//!     let client = RecordScreenClient::new("http://localhost:8000", None);
+//!     client.start(RecordingOptions::default()).await.unwrap();
+//!
+//!     let mut states = Box::pin(client.watch_progress());
+//!     while let Some(state) = states.next().await {
+//!         dbg!(state.unwrap());
+//!     }
+//! }
+//! ```
+#![warn(missing_docs)]
+
+use crate::endpoints::TOTAL_COUNT;
+use crate::events::Message;
+use crate::problem::FieldError;
+use crate::quality::{CaptureQuality, QualityChange};
+use crate::recordings::{Listed, Listing};
+use crate::service::{RecordingOptions, RecordingState};
+use crate::sync_start::ScheduledStart;
+use crate::trim::StopRequest;
+use futures::Stream;
+use reqwest::{Method, RequestBuilder, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+type Result<T> = std::result::Result<T, ClientError>;
+
+/// The acknowledgement the server replies with to start requests, the state the start led to.
+pub type StartResponse = RecordingState;
+
+/// Errors returned by [RecordScreenClient].
+#[derive(Error, Debug)]
+pub enum ClientError {
+    /// The server could not be reached, or the connection failed midway.
+    #[error("Transport Error: {0}")]
+    Transport(
+        #[source]
+        #[from]
+        reqwest::Error,
+    ),
+    /// The server rejected the request.
+    ///
+    /// The body is kept as the server sent it.
+    #[error("HTTP {status}: {body}")]
+    Http {
+        /// The status code of the response.
+        status: StatusCode,
+        /// The body of the response.
+        body: String,
+    },
+    /// The server replied with something that is not the expected type.
+    #[error("Decode Error: {0}")]
+    Decode(#[source] serde_json::Error),
+    /// A download could not be written.
+    #[error("IO Error: {0}")]
+    Io(
+        #[source]
+        #[from]
+        std::io::Error,
+    ),
+}
+
+/// The `application/problem+json` body of a rejected request.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Problem {
+    /// The URI of the kind of problem.
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// The summary of the kind of problem.
+    pub title: String,
+    /// The status code of the response.
+    pub status: u16,
+    /// What went wrong with this request.
+    #[serde(default)]
+    pub detail: String,
+    /// The fields of the request that were invalid.
+    #[serde(default)]
+    pub errors: Vec<FieldError>,
+    /// The id of the request in the log of the server.
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.title, self.status)?;
+        if !self.detail.is_empty() {
+            write!(f, ": {}", self.detail)?;
+        }
+        for error in &self.errors {
+            write!(f, "\n  {}: {}", error.field, error.message)?;
+        }
+        if let Some(id) = &self.request_id {
+            write!(f, "\n  request {}", id)?;
+        }
+        Ok(())
+    }
+}
+
+impl ClientError {
+    /// The problem the server answered with, when it rejected the request with one.
+    pub fn problem(&self) -> Option<Problem> {
+        match self {
+            ClientError::Http { body, .. } => serde_json::from_str(body).ok(),
+            _ => None,
+        }
+    }
+
+    /// Whether the request might succeed when sent again.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ClientError::Transport(e) => e.is_connect() || e.is_timeout(),
+            ClientError::Http { status, .. } => status.is_server_error(),
+            ClientError::Decode(_) | ClientError::Io(_) => false,
+        }
+    }
+}
+
+/// A client of a running server.
+#[derive(Debug, Clone)]
+pub struct RecordScreenClient {
+    base_url: String,
+    token: Option<String>,
+    http: reqwest::Client,
+    /// How many times mutating requests are retried.
+    pub retries: u32,
+    /// The delay before the first retry, doubled for every next one.
+    pub backoff: Duration,
+    /// How often [Self::watch_progress] asks for the state.
+    pub poll_interval: Duration,
+}
+
+impl RecordScreenClient {
+    /// Gets a client for the server at `base_url`, sending `token` as the bearer token
+    pub fn new(base_url: &str, token: Option<String>) -> RecordScreenClient {
+        RecordScreenClient {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token,
+            http: reqwest::Client::new(),
+            retries: 3,
+            backoff: Duration::from_millis(250),
+            poll_interval: Duration::from_secs(1),
+        }
+    }
+
+    /// Sets the timeout of connecting and of every whole request.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.http = reqwest::Client::builder()
+            .connect_timeout(timeout)
+            .timeout(timeout)
+            .build()
+            .expect("http client");
+
+        self
+    }
+
+    /// Sets how many times mutating requests are retried.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+
+        self
+    }
+
+    /// Sets how often [Self::watch_progress] asks for the state.
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+
+        self
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let req = self
+            .http
+            .request(method, format!("{}{}", self.base_url, path));
+        match &self.token {
+            Some(token) => req.bearer_auth(token),
+            None => req,
+        }
+    }
+
+    async fn send<T: DeserializeOwned>(&self, req: RequestBuilder) -> Result<T> {
+        self.read(req.send().await?).await
+    }
+
+    async fn read<T: DeserializeOwned>(&self, res: reqwest::Response) -> Result<T> {
+        let status = res.status();
+        let body = res.text().await?;
+        if !status.is_success() {
+            return Err(ClientError::Http { status, body });
+        }
+        serde_json::from_str(&body).map_err(ClientError::Decode)
+    }
+
+    /// Sends a mutating request, retrying with backoff under the same idempotency key.
+    async fn send_idempotent<T: DeserializeOwned, B: Serialize>(
+        &self,
+        path: &str,
+        body: Option<&B>,
+    ) -> Result<T> {
+        let key = idempotency_key();
+        let mut delay = self.backoff;
+        let mut attempt = 0;
+        loop {
+            let mut req = self
+                .request(Method::POST, path)
+                .header(crate::idempotency::HEADER, &key);
+            if let Some(body) = body {
+                req = req.json(body);
+            }
+            match self.send(req).await {
+                Err(e) if e.is_retryable() && attempt < self.retries => {
+                    attempt += 1;
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Starts a recording.
+    pub async fn start(&self, opt: RecordingOptions) -> Result<StartResponse> {
+        self.send_idempotent("/api/start", Some(&opt)).await
+    }
+
+    /// Starts a recording at `opt.start_at`, returning the clock of the server.
+    ///
+    /// Without `start_at` the server starts at once and the reply doesn't decode.
+    pub async fn schedule_start(&self, opt: RecordingOptions) -> Result<ScheduledStart> {
+        self.send_idempotent("/api/start", Some(&opt)).await
+    }
+
+    /// Stops the recording, the server then compresses it, returning the state the stop led to.
+    pub async fn stop(&self) -> Result<RecordingState> {
+        self.stop_trimmed(&StopRequest::default()).await
+    }
+
+    /// Stops the recording, its result trimmed as `request` asks.
+    pub async fn stop_trimmed(&self, request: &StopRequest) -> Result<RecordingState> {
+        self.send_idempotent("/api/stop", Some(request)).await
+    }
+
+    /// Changes the quality of the running capture, returning the settings it changes to.
+    pub async fn set_quality(&self, change: &QualityChange) -> Result<CaptureQuality> {
+        self.send(self.request(Method::PUT, "/api/quality").json(change))
+            .await
+    }
+
+    /// Pauses the running capture, returning the paused state.
+    pub async fn pause(&self) -> Result<RecordingState> {
+        self.send(self.request(Method::POST, "/api/pause")).await
+    }
+
+    /// Resumes the paused capture in a new segment.
+    pub async fn resume(&self) -> Result<RecordingState> {
+        self.send(self.request(Method::POST, "/api/resume")).await
+    }
+
+    /// Throws the running recording away, returning the state it left.
+    pub async fn cancel(&self) -> Result<RecordingState> {
+        self.send(self.request(Method::POST, "/api/cancel")).await
+    }
+
+    /// Gets the current state.
+    pub async fn status(&self) -> Result<RecordingState> {
+        self.send(self.request(Method::GET, "/api/status")).await
+    }
+
+    /// Lists the first page of the finished recordings, with the count of all of them.
+    pub async fn recordings(&self) -> Result<Listing> {
+        let res = self.request(Method::GET, "/api/recordings").send().await?;
+        let total = res
+            .headers()
+            .get(TOTAL_COUNT)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok());
+        let recordings: Vec<Listed> = self.read(res).await?;
+        Ok(Listing {
+            total: total.unwrap_or(recordings.len()),
+            recordings,
+        })
+    }
+
+    /// Downloads the finished recording `name` into `writer`, returning the bytes written.
+    pub async fn download<W: AsyncWrite + Unpin>(&self, name: &str, writer: &mut W) -> Result<u64> {
+        let path = format!("/api/recordings/{}/download", segment(name));
+        let mut res = self.request(Method::GET, &path).send().await?;
+        let status = res.status();
+        if !status.is_success() {
+            let body = res.text().await?;
+            return Err(ClientError::Http { status, body });
+        }
+        let mut written = 0;
+        while let Some(chunk) = res.chunk().await? {
+            writer.write_all(&chunk).await?;
+            written += chunk.len() as u64;
+        }
+        writer.flush().await?;
+        Ok(written)
+    }
+
+    /// The events of the server, the live ones or those after `since`.
+    ///
+    /// The stream is connected before this returns, so nothing that happens afterwards is
+    /// missed. It ends with the connection.
+    pub async fn events(&self, since: Option<u64>) -> Result<impl Stream<Item = Result<Message>>> {
+        let mut req = self.request(Method::GET, "/api/events");
+        if let Some(since) = since {
+            req = req.query(&[("since_seq", since)]);
+        }
+        let res = req.send().await?;
+        let status = res.status();
+        if !status.is_success() {
+            let body = res.text().await?;
+            return Err(ClientError::Http { status, body });
+        }
+        Ok(futures::stream::unfold(
+            Some((res, Vec::new())),
+            |reader| async move {
+                let (mut res, mut buf) = reader?;
+                loop {
+                    // an event ends with a blank line
+                    if let Some(end) = buf.windows(2).position(|w| w == b"\n\n") {
+                        let block: Vec<u8> = buf.drain(..end + 2).collect();
+                        let block = String::from_utf8_lossy(&block);
+                        let data: Vec<&str> = block
+                            .lines()
+                            .filter_map(|line| line.strip_prefix("data:"))
+                            .map(|data| data.strip_prefix(' ').unwrap_or(data))
+                            .collect();
+                        // the keep-alive comments have no data
+                        if data.is_empty() {
+                            continue;
+                        }
+                        let message =
+                            serde_json::from_str(&data.join("\n")).map_err(ClientError::Decode);
+                        return Some((message, Some((res, buf))));
+                    }
+                    match res.chunk().await {
+                        Ok(Some(chunk)) => buf.extend_from_slice(&chunk),
+                        Ok(None) => return None,
+                        Err(e) => return Some((Err(e.into()), None)),
+                    }
+                }
+            },
+        ))
+    }
+
+    /// The state of the server, every time it changes.
+    ///
+    /// The server is polled every [Self::poll_interval]. The stream ends after the first error.
+    pub fn watch_progress(&self) -> impl Stream<Item = Result<RecordingState>> + '_ {
+        futures::stream::unfold(Some(None::<String>), move |last| async move {
+            let mut last = last?;
+            loop {
+                match self.status().await {
+                    Ok(state) => {
+                        let json = serde_json::to_string(&state).ok();
+                        if json != last {
+                            return Some((Ok(state), Some(json)));
+                        }
+                        last = json;
+                    }
+                    Err(e) => return Some((Err(e), None)),
+                }
+                tokio::time::sleep(self.poll_interval).await;
+            }
+        })
+    }
+}
+
+/// `name` as a segment of a path, percent-encoded
+fn segment(name: &str) -> String {
+    name.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn idempotency_key() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    format!(
+        "{}-{}-{}",
+        std::process::id(),
+        chrono::Local::now().timestamp_millis(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::endpoints::{build_router, BodyLimits};
+    use crate::recordings;
+    use crate::service::Recorder;
+    use std::sync::Arc;
+
+    /// a client of the router of `mx`, served in-process
+    fn client(mx: Recorder) -> RecordScreenClient {
+        let router = build_router(Arc::new(mx), BodyLimits::default());
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener).unwrap();
+        tokio::spawn(server.serve(router.into_make_service()));
+        RecordScreenClient::new(&format!("http://{}/", addr), None)
+    }
+
+    #[tokio::test]
+    async fn the_state_is_that_of_the_server() {
+        let client = client(Recorder::new());
+        assert!(matches!(
+            client.status().await.unwrap(),
+            RecordingState::Waiting
+        ));
+    }
+
+    #[tokio::test]
+    async fn a_rejected_request_is_a_problem() {
+        let client = client(Recorder::new()).retries(0);
+        let e = client.stop().await.unwrap_err();
+        let ClientError::Http { status, .. } = &e else {
+            panic!("not rejected: {:?}", e);
+        };
+        assert_eq!(*status, StatusCode::CONFLICT);
+        assert!(!e.is_retryable());
+        let problem = e.problem().unwrap();
+        assert_eq!(problem.status, 409);
+        assert!(problem.request_id.is_some());
+    }
+
+    #[tokio::test]
+    async fn a_recording_is_listed_and_downloaded() {
+        let dir = recordings::test_output_dir();
+        let content: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        std::fs::write(dir.join("client download.mp4"), &content).unwrap();
+        let client = client(Recorder::new());
+
+        let listing = client.recordings().await.unwrap();
+        let listed = listing
+            .recordings
+            .iter()
+            .find(|r| r.name == "client download.mp4")
+            .expect("not listed");
+        assert_eq!(listed.size, content.len() as u64);
+        assert!(listing.total >= listing.recordings.len());
+
+        let mut downloaded = vec![];
+        let written = client
+            .download("client download.mp4", &mut downloaded)
+            .await
+            .unwrap();
+        assert_eq!(written, content.len() as u64);
+        assert_eq!(downloaded, content);
+        let e = client.download("missing.mp4", &mut vec![]).await;
+        assert!(matches!(
+            e,
+            Err(ClientError::Http { status, .. }) if status == StatusCode::NOT_FOUND
+        ));
+    }
+
+    #[tokio::test]
+    async fn a_token_is_sent_along() {
+        let tokens = crate::auth::Tokens::new(vec![crate::auth::ApiToken {
+            name: "viewer".to_string(),
+            role: crate::auth::Role::Viewer,
+            token: "viewer-secret".to_string(),
+        }]);
+        let anonymous = client(Recorder::new().with_tokens(tokens));
+        let e = anonymous.status().await.unwrap_err();
+        assert!(
+            matches!(e, ClientError::Http { status, .. } if status == StatusCode::UNAUTHORIZED)
+        );
+        let viewer = RecordScreenClient {
+            token: Some("viewer-secret".to_string()),
+            ..anonymous
+        };
+        assert!(viewer.status().await.is_ok());
+    }
+}
@@ -0,0 +1,285 @@
+//! A contact sheet of a recording: one image with a grid of its frames, to triage many at once
+//!
+//! The frames are evenly spaced over the recording, each with its time drawn on it, and tiled in
+//! one ffmpeg run: `fps` picks them, `drawtext` stamps their `pts`, `scale` sizes them to a tile
+//! and `tile` lays them out. The sheet is written next to the recording as `<name>.sheet.jpg`. A
+//! recording shorter than a frame per second of the grid gets a smaller grid, one frame per
+//! second, rather than frames shown twice. Requests for a sheet being made share the same job.
+use crate::container;
+use crate::ffmpeg::{escape_filter_option, escape_filtergraph, FfmpegBuilder, File, Parameter};
+use crate::probe::probe;
+use crate::service::{ChildRole, Recorder};
+use crate::throttle::Category;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::*;
+
+/// the most columns and rows of a grid
+pub const MAX_CELLS: u32 = 20;
+/// the widest sheet
+pub const MAX_WIDTH: u32 = 7680;
+
+fn default_columns() -> u32 {
+    6
+}
+
+fn default_rows() -> u32 {
+    5
+}
+
+fn default_width() -> u32 {
+    1920
+}
+
+/// The grid of a sheet, and how wide the sheet is
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SheetRequest {
+    #[serde(default = "default_columns")]
+    pub columns: u32,
+    #[serde(default = "default_rows")]
+    pub rows: u32,
+    /// of the whole sheet, in pixels
+    #[serde(default = "default_width")]
+    pub width: u32,
+}
+
+impl Default for SheetRequest {
+    fn default() -> Self {
+        Self {
+            columns: default_columns(),
+            rows: default_rows(),
+            width: default_width(),
+        }
+    }
+}
+
+impl SheetRequest {
+    /// the field that is out of range and why
+    pub fn invalid(&self) -> Option<(&'static str, String)> {
+        let cells = format!("must be within 1..{}", MAX_CELLS);
+        if !(1..=MAX_CELLS).contains(&self.columns) {
+            return Some(("columns", cells));
+        }
+        if !(1..=MAX_CELLS).contains(&self.rows) {
+            return Some(("rows", cells));
+        }
+        if self.width < self.columns * 16 || self.width > MAX_WIDTH {
+            return Some((
+                "width",
+                format!(
+                    "must be within {}..{}, 16 pixels a column at least",
+                    self.columns * 16,
+                    MAX_WIDTH
+                ),
+            ));
+        }
+        None
+    }
+}
+
+/// The frames of a sheet, as laid out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Grid {
+    pub columns: u32,
+    pub rows: u32,
+    pub frames: u32,
+    /// seconds between two frames
+    pub interval: f64,
+}
+
+/// the grid of the request over `duration` seconds, smaller when there is less than a second a
+/// frame
+pub fn grid(req: &SheetRequest, duration: f64) -> Grid {
+    let cells = req.columns * req.rows;
+    let frames = cells.min(duration.floor().max(1.0) as u32);
+    let columns = req.columns.min(frames);
+    Grid {
+        columns,
+        rows: frames.div_ceil(columns),
+        frames,
+        interval: duration.max(0.0) / frames as f64,
+    }
+}
+
+/// the filter laying the frames out, with tiles `tile_width` wide
+pub fn filter(grid: &Grid, tile_width: u32) -> String {
+    // the time of every tile, `%{pts:hms}` escaped as an option and as a filter
+    let time = escape_filtergraph(&escape_filter_option("%{pts:hms}"));
+    format!(
+        "fps=1/{:.6}:start_time=0,\
+         scale={}:-2,\
+         drawtext=text={}:x=8:y=h-th-8:fontsize=h/12:fontcolor=white:box=1:boxcolor=black@0.6,\
+         tile={}x{}",
+        grid.interval.max(0.001),
+        tile_width,
+        time,
+        grid.columns,
+        grid.rows
+    )
+}
+
+/// path of the sheet of a recording
+pub fn sheet_path(recording: &Path) -> PathBuf {
+    let name = recording.to_string_lossy();
+    PathBuf::from(format!("{}.sheet.jpg", container::base(&name)))
+}
+
+/// A sheet being made, or that failed
+#[derive(Debug, Clone, Serialize)]
+pub struct SheetJob {
+    pub id: u64,
+    pub recording: String,
+    pub request: SheetRequest,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// What a request for a sheet gets
+pub enum Lookup {
+    /// the sheet is there
+    Ready(PathBuf),
+    /// the sheet is being made
+    Pending(SheetJob),
+    /// making the sheet failed, the next request tries again
+    Failed(SheetJob),
+    /// there is no sheet and none asked for
+    Missing,
+}
+
+/// The sheets being made, by the path of the sheet
+#[derive(Default)]
+pub struct ContactSheets {
+    jobs: Mutex<HashMap<PathBuf, SheetJob>>,
+    next_id: AtomicU64,
+}
+
+impl ContactSheets {
+    /// the sheets being made and the ones that failed, oldest first
+    pub fn list(&self) -> Vec<SheetJob> {
+        let mut jobs: Vec<SheetJob> = self.jobs.lock().unwrap().values().cloned().collect();
+        jobs.sort_by_key(|j| j.id);
+        jobs
+    }
+
+    /// the sheet of the recording, without making it
+    pub fn lookup(&self, source: &Path) -> Lookup {
+        let sheet = sheet_path(source);
+        let mut jobs = self.jobs.lock().unwrap();
+        match jobs.get(&sheet) {
+            Some(job) if job.error.is_some() => {
+                let job = job.clone();
+                jobs.remove(&sheet);
+                Lookup::Failed(job)
+            }
+            Some(job) => Lookup::Pending(job.clone()),
+            None if sheet.is_file() => Lookup::Ready(sheet),
+            None => Lookup::Missing,
+        }
+    }
+
+    /// make the sheet of the recording again, or join the job making it already
+    pub fn start(&self, mx: &Arc<Recorder>, source: &Path, req: SheetRequest) -> SheetJob {
+        let sheet = sheet_path(source);
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.get(&sheet).filter(|job| job.error.is_none()) {
+            return job.clone();
+        }
+        let job = SheetJob {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            recording: source.to_string_lossy().to_string(),
+            request: req,
+            error: None,
+        };
+        jobs.insert(sheet.clone(), job.clone());
+        drop(jobs);
+
+        let mx = mx.clone();
+        let source = source.to_path_buf();
+        tokio::spawn(async move {
+            let result = make(&mx, &source, &sheet, &req).await;
+            let mut jobs = mx.sheets.jobs.lock().unwrap();
+            match result {
+                Ok(()) => {
+                    info!("contact sheet {}", sheet.display());
+                    jobs.remove(&sheet);
+                }
+                Err(e) => {
+                    warn!(
+                        "cannot make the contact sheet of {}: {}",
+                        source.display(),
+                        e
+                    );
+                    if let Some(job) = jobs.get_mut(&sheet) {
+                        job.error = Some(e.to_string());
+                    }
+                }
+            }
+        });
+        job
+    }
+}
+
+async fn make(
+    mx: &Arc<Recorder>,
+    source: &Path,
+    sheet: &Path,
+    req: &SheetRequest,
+) -> anyhow::Result<()> {
+    let duration = probe(source).await?.duration.unwrap_or_default();
+    let grid = grid(req, duration);
+    // even, for the encoder
+    let tile_width = (req.width / grid.columns) & !1;
+    let filter = filter(&grid, tile_width);
+    let mut partial = sheet.as_os_str().to_owned();
+    partial.push(".part.jpg");
+    let partial = PathBuf::from(partial);
+
+    let input = source.to_string_lossy().to_string();
+    let output = partial.to_string_lossy().to_string();
+    let readrate = mx.throttle.readrate(Category::ContactSheet, source).await;
+    let mut file = File::new(&input);
+    if let Some((speed, _)) = &readrate {
+        file = file.option(Parameter::KeyValue("readrate", speed));
+    }
+    let ffmpeg = FfmpegBuilder::new()
+        .stderr(Stdio::piped())
+        .option(Parameter::Single("y"))
+        .input(file)
+        .option2(Parameter::KeyValue("vf", &filter))
+        .option2(Parameter::StreamSpec {
+            base: "frames",
+            specifier: "v",
+            value: "1",
+        })
+        .option2(Parameter::StreamSpec {
+            base: "q",
+            specifier: "v",
+            value: "3",
+        })
+        .option2(Parameter::Single("an"))
+        .output(File::new(&output))
+        .run()
+        .await?;
+    let pid = ffmpeg.id();
+    mx.children
+        .register(pid, ChildRole::Frames, vec![output.clone()]);
+    mx.throttle.idle(pid);
+    // the one frame of the output comes at the end, there is no progress to tell
+    let summary = ffmpeg.wait_with_progress(|_| {}).await;
+    mx.children.unregister(pid);
+    let summary = summary?;
+    if !summary.success() || !partial.is_file() {
+        let _ = std::fs::remove_file(&partial);
+        anyhow::bail!(
+            "ffmpeg exited with {}: {}",
+            summary.exit_status,
+            summary.stderr_tail.join("\n")
+        );
+    }
+    std::fs::rename(&partial, sheet)?;
+    Ok(())
+}
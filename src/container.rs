@@ -0,0 +1,89 @@
+//! The container of a recording: mp4, mkv or webm
+//!
+//! An mp4 that ffmpeg could not finish has no index and plays nowhere, while an mkv plays up to
+//! where it ended: the capture of a recording in mkv or webm is an mkv, only its result is in
+//! the container asked for. A webm result is VP9 and Opus, an mp4 one is fragmented: an empty
+//! index at the front and the media in fragments of a keyframe each, so that it plays as it
+//! downloads and can be read while it is compressed, see [crate::recordings::tail]. The names of the files that go with a capture, its segments
+//! and sidecars, are made from its [base], whatever its container.
+use crate::ffmpeg::Parameter;
+use serde::{Deserialize, Serialize};
+
+/// What the result of a recording is muxed in
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Container {
+    #[default]
+    Mp4,
+    /// Matroska, the capture survives a kill
+    Mkv,
+    /// VP9 and Opus in Matroska, for the browsers
+    Webm,
+}
+
+/// the extensions of the containers, those [base] takes off
+pub const EXTENSIONS: &[&str] = &["mp4", "mkv", "webm"];
+
+impl Container {
+    pub fn is_mp4(&self) -> bool {
+        *self == Self::Mp4
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Mp4 => "mp4",
+            Self::Mkv => "mkv",
+            Self::Webm => "webm",
+        }
+    }
+
+    /// the container of the raw capture, which holds any codec of the capture
+    pub fn capture(self) -> Self {
+        match self {
+            Self::Mp4 => Self::Mp4,
+            Self::Mkv | Self::Webm => Self::Mkv,
+        }
+    }
+
+    /// the audio codec of a result
+    pub fn audio_codec(self) -> &'static str {
+        match self {
+            Self::Webm => "libopus",
+            Self::Mp4 | Self::Mkv => "aac",
+        }
+    }
+
+    /// the options of the muxer of a result
+    pub fn mux_options(self) -> Vec<Parameter<'static>> {
+        match self {
+            // readable as it is written, see [crate::recordings::is_fragmented_mp4]
+            Self::Mp4 => vec![Parameter::KeyValue(
+                "movflags",
+                "+frag_keyframe+empty_moov+default_base_moof",
+            )],
+            Self::Mkv | Self::Webm => vec![],
+        }
+    }
+}
+
+impl std::fmt::Display for Container {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.extension())
+    }
+}
+
+/// the file without the extension of its container, `a.mkv` is `a`
+pub fn base(file: &str) -> &str {
+    match file.rsplit_once('.') {
+        Some((base, extension)) if EXTENSIONS.contains(&extension) => base,
+        _ => file,
+    }
+}
+
+/// the extension of the container of the file, mp4 for another one
+pub fn extension_of(file: &str) -> &str {
+    match file.rsplit_once('.') {
+        Some((_, extension)) if EXTENSIONS.contains(&extension) => extension,
+        _ => "mp4",
+    }
+}
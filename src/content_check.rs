@@ -0,0 +1,200 @@
+//! A look at the content of a finished recording, for the ones that are black or silent
+//!
+//! A recording of the wrong display is black throughout and one of the wrong microphone is
+//! silent, and both compress and archive as well as any other. Once a recording is compressed, a
+//! few windows evenly spaced over it go through ffmpeg's `blackdetect` and `silencedetect`: each
+//! window is seeked to and read on its own, so that a long recording takes as long as a short one.
+//! When most of what was sampled is black, or silent, the recording is flagged with a
+//! [ContentWarning] in its Done state and its history entry, and a notice is published. The
+//! silence is only looked for in a recording with audio.
+use crate::ffmpeg::{FfmpegBuilder, File, Parameter};
+use crate::probe::probe;
+use crate::service::{ChildRole, Children};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::process::Stdio;
+
+/// What the content of a recording suggests went wrong
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContentWarning {
+    MostlyBlack,
+    Silent,
+}
+
+/// How the recordings are looked at
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// for the recordings that don't say
+    pub enabled: bool,
+    /// of what was sampled, above which a recording is flagged
+    pub fraction: f64,
+    pub windows: u32,
+    /// seconds of every window
+    pub window: f64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            fraction: 0.95,
+            windows: 10,
+            window: 5.0,
+        }
+    }
+}
+
+/// the windows to sample over `duration` seconds, as their start and length
+pub fn windows(duration: f64, count: u32, window: f64) -> Vec<(f64, f64)> {
+    if duration <= 0.0 || count == 0 {
+        return vec![];
+    }
+    if duration <= count as f64 * window || count == 1 {
+        return vec![(0.0, duration)];
+    }
+    let step = (duration - window) / (count - 1) as f64;
+    (0..count).map(|i| (i as f64 * step, window)).collect()
+}
+
+/// What the detectors found in a window, in seconds
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Detection {
+    pub black: f64,
+    pub silence: f64,
+    /// where a silence started that did not end yet
+    silence_start: Option<f64>,
+}
+
+/// the value after `key` in a line of a detector, up to the next space
+fn value(line: &str, key: &str) -> Option<f64> {
+    let rest = &line[line.find(key)? + key.len()..];
+    rest.trim_start()
+        .split(|c: char| c.is_whitespace() || c == '|')
+        .next()?
+        .parse()
+        .ok()
+}
+
+impl Detection {
+    /// take a line of the stderr of ffmpeg
+    ///
+    /// `blackdetect` prints a line for every black interval once it ends,
+    /// `black_start:0 black_end:5 black_duration:5`; `silencedetect` prints `silence_start: 0`
+    /// when a silence starts and `silence_end: 5 | silence_duration: 5` when it ends.
+    pub fn line(&mut self, line: &str) {
+        if line.contains("[blackdetect") {
+            if let Some(duration) = value(line, "black_duration:") {
+                self.black += duration;
+            }
+        } else if line.contains("[silencedetect") {
+            if let Some(duration) = value(line, "silence_duration:") {
+                self.silence += duration;
+                self.silence_start = None;
+            } else if let Some(start) = value(line, "silence_start:") {
+                self.silence_start = Some(start);
+            }
+        }
+    }
+
+    /// the window ended at `end` seconds of the input, ending the silence still going on
+    pub fn finish(&mut self, end: f64) {
+        if let Some(start) = self.silence_start.take() {
+            self.silence += (end - start).max(0.0);
+        }
+    }
+}
+
+/// the warnings of `detected` out of `sampled` seconds
+pub fn warnings(
+    detected: &Detection,
+    sampled: f64,
+    audio: bool,
+    fraction: f64,
+) -> Vec<ContentWarning> {
+    let mut warnings = vec![];
+    if sampled <= 0.0 {
+        return warnings;
+    }
+    if detected.black / sampled > fraction {
+        warnings.push(ContentWarning::MostlyBlack);
+    }
+    if audio && detected.silence / sampled > fraction {
+        warnings.push(ContentWarning::Silent);
+    }
+    warnings
+}
+
+/// look at the recording, returning what is wrong with it
+pub async fn check(
+    file: &Path,
+    config: &Config,
+    children: &Children,
+) -> anyhow::Result<Vec<ContentWarning>> {
+    let info = probe(file).await?;
+    let video = info.video_codec.is_some();
+    let audio = info.audio_codec.is_some();
+    let mut detected = Detection::default();
+    let mut sampled = 0.0;
+    for (start, length) in windows(
+        info.duration.unwrap_or_default(),
+        config.windows,
+        config.window,
+    ) {
+        let window = sample(file, start, length, video, audio, children).await?;
+        detected.black += window.black.min(length);
+        detected.silence += window.silence.min(length);
+        sampled += length;
+    }
+    Ok(warnings(&detected, sampled, audio, config.fraction))
+}
+
+async fn sample(
+    file: &Path,
+    start: f64,
+    length: f64,
+    video: bool,
+    audio: bool,
+    children: &Children,
+) -> anyhow::Result<Detection> {
+    let source = file.to_string_lossy();
+    let (seek, duration) = (format!("{:.3}", start), format!("{:.3}", length));
+    let mut builder = FfmpegBuilder::new().stderr(Stdio::piped()).input(
+        File::new(&source)
+            .option(Parameter::KeyValue("ss", &seek))
+            .option(Parameter::KeyValue("t", &duration)),
+    );
+    builder = match video {
+        true => builder.option2(Parameter::KeyValue("vf", "blackdetect=d=0.5:pix_th=0.10")),
+        false => builder.option2(Parameter::Single("vn")),
+    };
+    builder = match audio {
+        true => builder.option2(Parameter::KeyValue("af", "silencedetect=n=-50dB:d=0.5")),
+        false => builder.option2(Parameter::Single("an")),
+    };
+    let builder = builder
+        .option2(Parameter::KeyValue("f", "null"))
+        .output(File::new("-"));
+    let child = builder.to_command()?.spawn()?;
+    let pid = child.id().unwrap_or_default();
+    children.register(pid, ChildRole::Check, vec![]);
+    let output = child.wait_with_output().await;
+    children.unregister(pid);
+    let output = output?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        let tail: Vec<&str> = stderr.lines().rev().take(5).collect();
+        anyhow::bail!(
+            "cannot sample {}: {}",
+            source,
+            tail.into_iter().rev().collect::<Vec<_>>().join("\n")
+        );
+    }
+    let mut detection = Detection::default();
+    for line in stderr.lines() {
+        detection.line(line);
+    }
+    // the timestamps start over at the seek
+    detection.finish(length);
+    Ok(detection)
+}
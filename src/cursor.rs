@@ -0,0 +1,543 @@
+//! The pointer of a screen capture, recorded apart so that it can be drawn sharp over a zoom
+//!
+//! With `cursor_track` the screen is captured without the pointer, `-draw_mouse 0`, and [track]
+//! samples it with `xdotool getmouselocation`, and its buttons with `xinput --query-state`, at the
+//! framerate of the capture. A sample is kept when the pointer moved or a button changed, at its
+//! position in what was recorded, so that a pause leaves out of the [Track] what it leaves out of
+//! the video. The track is written next to the capture as `<name>.cursor.json` once the capture
+//! ends.
+//!
+//! `POST /api/recordings/:name/cursor` renders the pointer over the finished recording into
+//! `<name>.cursor.mp4`: an arrow moved by a `sendcmd` script, with a ring around it while a button
+//! is down. The recording itself stays without a pointer, for the post-production that draws its
+//! own. The render is written into the track once it is done.
+//!
+//! `draw_mouse: false` captures without the pointer as well, with nothing tracked. `show_clicks`
+//! keeps the pointer x11grab draws and tracks it into `<name>.clicks.json` instead, see
+//! [clicks_path]; the compression draws a square around the pointer for every press of a button,
+//! [click_filters], and removes the track.
+use crate::container;
+use crate::display;
+use crate::ffmpeg::{escape_filter_option, escape_filtergraph, FfmpegBuilder, File, Parameter};
+use crate::schema::{self, Versioned};
+use crate::service::{since, ChildRole, Recorder, RecordingOptions, RecordingState};
+use crate::source::CaptureSource;
+use crate::throttle::Category;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::*;
+
+/// height of the arrow, in pixels of the recording
+pub const ARROW_SIZE: u32 = 24;
+/// diameter of the ring of a button that is down
+pub const RING_SIZE: u32 = 40;
+/// failed samples in a row after which the pointer is no longer tracked
+const MAX_FAILURES: u32 = 25;
+/// how long a click is shown at least, a press is often shorter than a frame
+pub const CLICK_MS: u64 = 400;
+/// the clicks highlighted at most, each is a filter on the command line of the compression
+pub const MAX_CLICKS: usize = 500;
+
+/// Where the pointer was, `[content_ms, x, y, buttons]`
+///
+/// The position is on the screen, the buttons a mask of the first eight, button 1 the lowest bit.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Sample(pub u64, pub i32, pub i32, pub u8);
+
+/// The pointer of a recording
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Track {
+    /// the top left corner of the capture on the screen
+    pub x: i32,
+    pub y: i32,
+    /// where the capture starts in the result, after its slate, before it when its head was trimmed
+    #[serde(default)]
+    pub offset_ms: i64,
+    pub samples: Vec<Sample>,
+    /// the recording with the pointer drawn, once it was rendered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rendered: Option<String>,
+}
+
+impl Versioned for Track {
+    const KIND: &'static str = "cursor tracks";
+    const VERSION: u32 = 1;
+    const MIGRATIONS: &'static [schema::Migration] = &[schema::unversioned];
+}
+
+/// path of the track of a capture
+pub fn track_path(capture: &str) -> PathBuf {
+    PathBuf::from(format!("{}.cursor.json", container::base(capture)))
+}
+
+/// path of the track of the clicks of a capture, see [click_filters]
+pub fn clicks_path(capture: &str) -> PathBuf {
+    PathBuf::from(format!("{}.clicks.json", container::base(capture)))
+}
+
+/// path of the track of a finished recording, named after its capture
+pub fn track_of(recording: &Path) -> PathBuf {
+    let name = recording.to_string_lossy();
+    let base = container::base(&name);
+    track_path(base.strip_suffix(".compressed").unwrap_or(base))
+}
+
+/// path of the recording with the pointer drawn
+pub fn render_path(recording: &Path) -> PathBuf {
+    let name = recording.to_string_lossy();
+    PathBuf::from(format!(
+        "{}.cursor.{}",
+        container::base(&name),
+        container::extension_of(&name)
+    ))
+}
+
+pub fn read_track(path: &Path) -> anyhow::Result<Track> {
+    Ok(schema::from_slice(&std::fs::read(path)?)?.0)
+}
+
+fn write_track(path: &Path, track: &Track) -> anyhow::Result<()> {
+    std::fs::write(path, schema::to_vec_pretty(track)?)?;
+    Ok(())
+}
+
+/// shift the track of a recording by `offset_ms`, as the capture starts after a slate in it or
+/// before it when its head was trimmed
+pub fn set_offset(recording: &Path, offset_ms: i64) {
+    let path = track_of(recording);
+    if !path.is_file() {
+        return;
+    }
+    let result = read_track(&path).and_then(|mut track| {
+        track.offset_ms = offset_ms;
+        write_track(&path, &track)
+    });
+    if let Err(e) = result {
+        warn!("cannot shift the pointer of {}: {}", recording.display(), e);
+    }
+}
+
+/// `X=12\nY=34\nSCREEN=0\nWINDOW=...` of `xdotool getmouselocation --shell`
+pub fn parse_location(shell: &str) -> Option<(i32, i32)> {
+    let value = |key: &str| {
+        shell
+            .lines()
+            .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+            .and_then(|v| v.trim().parse().ok())
+    };
+    Some((value("X")?, value("Y")?))
+}
+
+/// the mask of the buttons down in `xinput --query-state`, `button[1]=down`
+pub fn parse_buttons(state: &str) -> u8 {
+    state
+        .lines()
+        .filter_map(|line| {
+            let (button, value) = line.trim().strip_prefix("button[")?.split_once("]=")?;
+            let button: u8 = button.parse().ok()?;
+            (value == "down" && (1..=8).contains(&button)).then(|| 1 << (button - 1))
+        })
+        .fold(0, |mask, bit| mask | bit)
+}
+
+async fn run(program: &str, args: &[&str]) -> Option<String> {
+    let output = tokio::process::Command::new(program)
+        .args(args)
+        .env("DISPLAY", display::name())
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// where the recording is, None once it is no longer the one started at `started_at`
+async fn position(mx: &Recorder, started_at: DateTime<Local>) -> Option<Option<u64>> {
+    let state = mx.lock().await;
+    match state.recording() {
+        RecordingState::Started {
+            started_at: current,
+            timeline,
+            ..
+        } if *current == started_at => {
+            let paused = matches!(*state, RecordingState::Paused { .. })
+                || timeline.spans.last().is_some_and(|s| s.ended_ms.is_some());
+            Some((!paused).then(|| timeline.content_at(since(started_at))))
+        }
+        _ => None,
+    }
+}
+
+/// sample the pointer of the recording started at `started_at` until it ends, into `path`
+pub async fn track(
+    mx: Arc<Recorder>,
+    started_at: DateTime<Local>,
+    track: Track,
+    framerate: u32,
+    path: PathBuf,
+) {
+    let mut track = track;
+    let mut tick = tokio::time::interval(Duration::from_secs_f64(1.0 / framerate.max(1) as f64));
+    tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    let (mut failures, mut buttons_known) = (0, true);
+    while let Some(content_ms) = position(&mx, started_at).await {
+        tick.tick().await;
+        let Some(content_ms) = content_ms else {
+            continue;
+        };
+        let Some((x, y)) = run("xdotool", &["getmouselocation", "--shell"])
+            .await
+            .as_deref()
+            .and_then(parse_location)
+        else {
+            failures += 1;
+            if failures == MAX_FAILURES {
+                warn!("cannot sample the pointer with xdotool, it is no longer tracked");
+                break;
+            }
+            continue;
+        };
+        failures = 0;
+        let mut buttons = 0;
+        if buttons_known {
+            match run("xinput", &["--query-state", "Virtual core pointer"]).await {
+                Some(state) => buttons = parse_buttons(&state),
+                None => {
+                    warn!("cannot query the buttons with xinput, only the pointer is tracked");
+                    buttons_known = false;
+                }
+            }
+        }
+        let moved = track
+            .samples
+            .last()
+            .is_none_or(|last| (last.1, last.2, last.3) != (x, y, buttons));
+        if moved {
+            track.samples.push(Sample(content_ms, x, y, buttons));
+        }
+    }
+    info!(
+        "{} pointer samples written to {}",
+        track.samples.len(),
+        path.display()
+    );
+    if let Err(e) = write_track(&path, &track) {
+        warn!("cannot write {}: {}", path.display(), e);
+    }
+}
+
+/// A press of a button, where the pointer was on the screen and while it was down in the content
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Click {
+    pub from_ms: u64,
+    pub to_ms: u64,
+    pub x: i32,
+    pub y: i32,
+}
+
+/// the presses of the buttons of a track, a new button down while others are starts one too
+pub fn clicks(track: &Track) -> Vec<Click> {
+    let mut clicks: Vec<Click> = vec![];
+    let mut down = 0;
+    for Sample(content_ms, x, y, buttons) in &track.samples {
+        if down != 0 {
+            if let Some(click) = clicks.last_mut() {
+                click.to_ms = *content_ms;
+            }
+        }
+        if buttons & !down != 0 {
+            clicks.push(Click {
+                from_ms: *content_ms,
+                to_ms: *content_ms,
+                x: *x,
+                y: *y,
+            });
+        }
+        down = *buttons;
+    }
+    clicks
+}
+
+/// the `drawbox` chain squaring the pointer of every click, the content of the track starting
+/// `offset_ms` into the video; None without a click
+pub fn click_filters(track: &Track, offset_ms: i64) -> Option<String> {
+    let clicks = clicks(track);
+    if clicks.len() > MAX_CLICKS {
+        warn!(
+            "{} clicks, only the first {} are highlighted",
+            clicks.len(),
+            MAX_CLICKS
+        );
+    }
+    let half = RING_SIZE as i32 / 2;
+    let filters: Vec<String> = clicks
+        .iter()
+        .take(MAX_CLICKS)
+        .filter_map(|click| {
+            let from_ms = offset_ms + click.from_ms as i64;
+            let to_ms = offset_ms + click.to_ms.max(click.from_ms + CLICK_MS) as i64;
+            // a click trimmed away
+            (to_ms > 0).then(|| {
+                format!(
+                    "drawbox=x={}:y={}:w={d}:h={d}:color=yellow@0.8:t=4\
+                     :enable='between(t,{:.3},{:.3})'",
+                    click.x - track.x - half,
+                    click.y - track.y - half,
+                    from_ms.max(0) as f64 / 1000.0,
+                    to_ms as f64 / 1000.0,
+                    d = RING_SIZE,
+                )
+            })
+        })
+        .collect();
+    (!filters.is_empty()).then(|| filters.join(","))
+}
+
+/// whether the pointer the options ask for can be captured, the field that can't otherwise
+pub fn validate(opt: &RecordingOptions) -> Result<(), (&'static str, String)> {
+    let asked = [
+        ("draw_mouse", opt.draw_mouse.is_some()),
+        ("show_clicks", opt.show_clicks),
+    ];
+    for (field, _) in asked.into_iter().filter(|(_, given)| *given) {
+        if opt.source != CaptureSource::Screen {
+            return Err((field, "a stream is recorded as it came".to_string()));
+        }
+    }
+    if opt.cursor_track && opt.draw_mouse == Some(true) {
+        return Err((
+            "draw_mouse",
+            "the pointer of cursor_track is drawn over the recording later".to_string(),
+        ));
+    }
+    if opt.show_clicks {
+        if opt.cursor_track {
+            return Err((
+                "show_clicks",
+                "the render of cursor_track rings the clicks already".to_string(),
+            ));
+        }
+        if opt.compress == Some(false) {
+            return Err(("show_clicks", "drawn by the compression".to_string()));
+        }
+        if opt.segment_seconds.is_some() {
+            return Err(("show_clicks", "not in a recording in chunks".to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// the `sendcmd` script moving the arrow and the ring over the recording
+///
+/// The ring is kept out of the picture while no button is down.
+pub fn sendcmd_script(track: &Track) -> String {
+    let mut script = String::new();
+    let hidden = -(RING_SIZE as i64) * 10;
+    for Sample(content_ms, x, y, buttons) in &track.samples {
+        // the samples trimmed away all go at the start, the last of them is where it starts
+        let at_ms = (track.offset_ms + *content_ms as i64).max(0);
+        let (x, y) = ((x - track.x) as i64, (y - track.y) as i64);
+        let (ring_x, ring_y) = match buttons {
+            0 => (hidden, hidden),
+            _ => (x - RING_SIZE as i64 / 2, y - RING_SIZE as i64 / 2),
+        };
+        let _ = writeln!(
+            script,
+            "{:.3} overlay@cursor x {}, overlay@cursor y {}, overlay@click x {}, overlay@click y {};",
+            at_ms as f64 / 1000.0,
+            x,
+            y,
+            ring_x,
+            ring_y
+        );
+    }
+    script
+}
+
+/// the filter graph drawing the pointer over the input, `[v]` out, with the script at `sendcmd`
+pub fn filter_graph(sendcmd: &Path) -> String {
+    let (s, r) = (ARROW_SIZE as f64, RING_SIZE as f64 / 2.0);
+    // the tip at the top left, a dark outline around a white arrow
+    let arrow = format!("lte(X,Y)*lte(0.4*X+Y,{})", s);
+    let inside = format!("gte(X,1.5)*lte(X+2,Y)*lte(0.4*X+Y,{})", s - 2.0);
+    let ring = format!("between(hypot(X-{r},Y-{r}),{},{})", r - 5.0, r - 1.0);
+    let script = escape_filtergraph(&escape_filter_option(&sendcmd.to_string_lossy()));
+    format!(
+        "color=c=black@0:s={w}x{h},format=rgba,geq=r='255*{inside}':g='255*{inside}':b='255*{inside}':a='255*{arrow}'[arrow];\
+         color=c=black@0:s={d}x{d},format=rgba,geq=r='255':g='200':b='0':a='220*{ring}'[ring];\
+         [0:v]sendcmd=f={script}[v0];\
+         [v0][ring]overlay@click=x=-{hidden}:y=-{hidden}:shortest=1[v1];\
+         [v1][arrow]overlay@cursor=x=-{hidden}:y=-{hidden}:shortest=1[v]",
+        w = ARROW_SIZE * 2 / 3,
+        h = ARROW_SIZE + 1,
+        d = RING_SIZE,
+        hidden = RING_SIZE * 10,
+    )
+}
+
+/// A render being made, or that failed
+#[derive(Debug, Clone, Serialize)]
+pub struct RenderJob {
+    pub id: u64,
+    pub recording: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// What a request for a render gets
+pub enum Lookup {
+    Ready(PathBuf),
+    Pending(RenderJob),
+    /// the next request tries again
+    Failed(RenderJob),
+    /// the recording has a track, but no render was asked for
+    Missing,
+    /// the recording was made without a track
+    Untracked,
+}
+
+/// The renders being made, by the path of the render
+#[derive(Default)]
+pub struct Renders {
+    jobs: Mutex<HashMap<PathBuf, RenderJob>>,
+    next_id: AtomicU64,
+}
+
+impl Renders {
+    /// the renders being made and the ones that failed, oldest first
+    pub fn list(&self) -> Vec<RenderJob> {
+        let mut jobs: Vec<RenderJob> = self.jobs.lock().unwrap().values().cloned().collect();
+        jobs.sort_by_key(|j| j.id);
+        jobs
+    }
+
+    /// the render of the recording, without making it
+    pub fn lookup(&self, recording: &Path) -> Lookup {
+        let render = render_path(recording);
+        let mut jobs = self.jobs.lock().unwrap();
+        match jobs.get(&render) {
+            Some(job) if job.error.is_some() => {
+                let job = job.clone();
+                jobs.remove(&render);
+                Lookup::Failed(job)
+            }
+            Some(job) => Lookup::Pending(job.clone()),
+            None if render.is_file() => Lookup::Ready(render),
+            None if track_of(recording).is_file() => Lookup::Missing,
+            None => Lookup::Untracked,
+        }
+    }
+
+    /// render the recording again, or join the job rendering it already
+    pub fn start(&self, mx: &Arc<Recorder>, recording: &Path) -> RenderJob {
+        let render = render_path(recording);
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.get(&render).filter(|job| job.error.is_none()) {
+            return job.clone();
+        }
+        let job = RenderJob {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            recording: recording.to_string_lossy().to_string(),
+            error: None,
+        };
+        jobs.insert(render.clone(), job.clone());
+        drop(jobs);
+
+        let mx = mx.clone();
+        let recording = recording.to_path_buf();
+        tokio::spawn(async move {
+            let result = make(&mx, &recording, &render).await;
+            let mut jobs = mx.cursor_renders.jobs.lock().unwrap();
+            match result {
+                Ok(()) => {
+                    info!("pointer drawn into {}", render.display());
+                    jobs.remove(&render);
+                }
+                Err(e) => {
+                    warn!(
+                        "cannot draw the pointer over {}: {}",
+                        recording.display(),
+                        e
+                    );
+                    if let Some(job) = jobs.get_mut(&render) {
+                        job.error = Some(e.to_string());
+                    }
+                }
+            }
+        });
+        job
+    }
+}
+
+async fn make(mx: &Arc<Recorder>, recording: &Path, render: &Path) -> anyhow::Result<()> {
+    let track_path = track_of(recording);
+    let mut track = read_track(&track_path)?;
+    let mut partial = render.as_os_str().to_owned();
+    partial.push(".part.mp4");
+    let partial = PathBuf::from(partial);
+    let sendcmd = PathBuf::from(format!("{}.sendcmd.txt", partial.to_string_lossy()));
+    let graph = PathBuf::from(format!("{}.filters.txt", partial.to_string_lossy()));
+    std::fs::write(&sendcmd, sendcmd_script(&track))?;
+    std::fs::write(&graph, filter_graph(&sendcmd))?;
+
+    let input = recording.to_string_lossy().to_string();
+    let output = partial.to_string_lossy().to_string();
+    let graph_file = graph.to_string_lossy().to_string();
+    let readrate = mx.throttle.readrate(Category::Transcode, recording).await;
+    let mut file = File::new(&input);
+    if let Some((speed, _)) = &readrate {
+        file = file.option(Parameter::KeyValue("readrate", speed));
+    }
+    let result = async {
+        let ffmpeg = FfmpegBuilder::new()
+            .stderr(Stdio::piped())
+            .option(Parameter::Single("y"))
+            .input(file)
+            .option2(Parameter::KeyValue("filter_complex_script", &graph_file))
+            .option2(Parameter::Repeated("map", vec!["[v]", "0:a?"]))
+            .option2(Parameter::codec("v", "libx264"))
+            .option2(Parameter::KeyValue("crf", "18"))
+            .option2(Parameter::KeyValue("preset", "veryfast"))
+            .option2(Parameter::codec("a", "copy"))
+            .output(File::new(&output))
+            .run()
+            .await?;
+        let pid = ffmpeg.id();
+        mx.children
+            .register(pid, ChildRole::Transcode, vec![output.clone()]);
+        mx.throttle.idle(pid);
+        let summary = ffmpeg.wait_with_progress(|_| {}).await;
+        mx.children.unregister(pid);
+        let summary = summary?;
+        if !summary.success() || !partial.is_file() {
+            anyhow::bail!(
+                "ffmpeg exited with {}: {}",
+                summary.exit_status,
+                summary.stderr_tail.join("\n")
+            );
+        }
+        std::fs::rename(&partial, render)?;
+        Ok(())
+    }
+    .await;
+    let _ = std::fs::remove_file(&sendcmd);
+    let _ = std::fs::remove_file(&graph);
+    if result.is_err() {
+        let _ = std::fs::remove_file(&partial);
+    }
+    result?;
+    track.rendered = Some(render.to_string_lossy().to_string());
+    write_track(&track_path, &track)
+}
@@ -0,0 +1,126 @@
+//! The X display a screen recording captures, and its size
+//!
+//! The display is that of `DISPLAY` in the environment of the server, [DEFAULT] without one.
+//! Its size is asked of `xrandr` when a recording starts: a screen recording without a region,
+//! a width or a height captures all of the screen rather than [VIDEO_SIZE], and a region must be
+//! on the screen. An X server that can't be reached fails the start, instead of an ffmpeg that
+//! would die at once; without `xrandr` the recording keeps [VIDEO_SIZE], unchecked.
+use crate::geometry::{self, Observation, Rect};
+use crate::service::{RecordingOptions, VIDEO_SIZE};
+use crate::source::CaptureSource;
+use serde::Serialize;
+use std::sync::OnceLock;
+use thiserror::Error;
+use tracing::*;
+
+/// the display captured without a `DISPLAY` in the environment
+pub const DEFAULT: &str = ":1.0";
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("cannot reach the X display {display}: {reason}")]
+    Unreachable { display: String, reason: String },
+    #[error("xrandr can't be run")]
+    NoXrandr,
+    #[error("xrandr tells no size of the screen of {0}")]
+    NoScreen(String),
+    #[error("{region} is not within the screen {screen}")]
+    OutsideScreen { region: Rect, screen: Rect },
+}
+
+/// the display the screen is captured from
+pub fn name() -> &'static str {
+    static NAME: OnceLock<String> = OnceLock::new();
+    NAME.get_or_init(|| match std::env::var("DISPLAY") {
+        Ok(display) if !display.trim().is_empty() => display.trim().to_string(),
+        _ => DEFAULT.to_string(),
+    })
+}
+
+/// A monitor of the display
+#[derive(Debug, Clone, Serialize)]
+pub struct Monitor {
+    pub name: String,
+    #[serde(flatten)]
+    pub rect: Rect,
+}
+
+/// What a screen recording captures by default
+#[derive(Debug, Clone, Serialize)]
+pub struct Display {
+    pub name: String,
+    pub screen: Rect,
+    pub monitors: Vec<Monitor>,
+}
+
+/// the display and its size, as xrandr tells
+pub async fn detect() -> Result<Display, Error> {
+    let name = name().to_string();
+    let layout = match geometry::observe().await {
+        Some(Observation::Layout(layout)) => layout,
+        Some(Observation::DisplayLost(reason)) => {
+            return Err(Error::Unreachable {
+                display: name,
+                reason,
+            })
+        }
+        None => return Err(Error::NoXrandr),
+    };
+    let Some(screen) = layout.screen else {
+        return Err(Error::NoScreen(name));
+    };
+    Ok(Display {
+        name,
+        screen,
+        monitors: layout
+            .monitors
+            .into_iter()
+            .map(|(name, rect)| Monitor { name, rect })
+            .collect(),
+    })
+}
+
+impl Display {
+    /// whether what the options capture is on the screen
+    pub fn check(&self, opt: &RecordingOptions) -> Result<(), Error> {
+        if opt.source != CaptureSource::Screen {
+            return Ok(());
+        }
+        let region = geometry::capture_region(&RecordingOptions {
+            width: opt.width.or(Some(self.screen.width)),
+            height: opt.height.or(Some(self.screen.height)),
+            ..opt.clone()
+        });
+        match self.screen.contains(&region) {
+            true => Ok(()),
+            false => Err(Error::OutsideScreen {
+                region,
+                screen: self.screen,
+            }),
+        }
+    }
+}
+
+/// size a screen recording to the screen, unless the options tell what to capture, and check
+/// that what they tell is on it
+pub async fn fit(opt: &mut RecordingOptions) -> Result<(), Error> {
+    if opt.source != CaptureSource::Screen {
+        return Ok(());
+    }
+    let display = match detect().await {
+        Ok(display) => display,
+        Err(Error::NoXrandr) => {
+            warn!("xrandr can't be run, capturing {}", VIDEO_SIZE);
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    display.check(opt)?;
+    if opt.region.is_none() {
+        // the encoder needs an even size, an odd screen loses its last column or row
+        let screen = display.screen.even();
+        opt.width = opt.width.or(Some(screen.width));
+        opt.height = opt.height.or(Some(screen.height));
+    }
+    Ok(())
+}
@@ -0,0 +1,554 @@
+//! Encoders the server was told about, for the codecs it doesn't wrap: ProRes, FFV1, AV1...
+//!
+//! A profile is given with `--encoder-profile`, `prores_hq=mov,yuv422p10le -c:v prores_ks
+//! -profile:v 3`: its name, the container it writes, an optional pixel format and the output
+//! options of ffmpeg, kept as typed [Parameter]s. A recording asks for one with
+//! `encoder_profile`, and may override its options with `encoder_options`, merged by
+//! [EncoderProfile::merged]. An override replaces the option of the profile of the same name, but
+//! a codec or a container different from the one of the profile is a conflict, as the
+//! [FfmpegBuilder] refuses it.
+//!
+//! Every profile is validated when the server starts, and again with `POST
+//! /api/encoder-profiles/validate`: one second of the lavfi `testsrc` is encoded with exactly its
+//! options into a temporary directory of its own, and the result is probed. The outcome is cached
+//! by the options, and a profile that fails is refused with the end of what ffmpeg wrote.
+use crate::ffmpeg::{FfmpegBuilder, File, Parameter};
+use crate::probe::{probe, MediaInfo};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::*;
+
+/// the input of a validation
+pub const TEST_SOURCE: &str = "testsrc=duration=1:size=320x240:rate=25";
+/// the longest a validation may encode
+pub const TIMEOUT: Duration = Duration::from_secs(30);
+/// lines of the stderr of ffmpeg kept with a failure
+const STDERR_LINES: usize = 8;
+
+/// An output option of a profile, [Parameter] owning its strings
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProfileParameter {
+    Single(String),
+    KeyValue(String, String),
+    Repeated(String, Vec<String>),
+    StreamSpec {
+        base: String,
+        specifier: String,
+        value: String,
+    },
+}
+
+impl ProfileParameter {
+    pub fn as_parameter(&self) -> Parameter<'_> {
+        match self {
+            Self::Single(key) => Parameter::Single(key),
+            Self::KeyValue(key, value) => Parameter::KeyValue(key, value),
+            Self::Repeated(key, values) => {
+                Parameter::Repeated(key, values.iter().map(String::as_str).collect())
+            }
+            Self::StreamSpec {
+                base,
+                specifier,
+                value,
+            } => Parameter::StreamSpec {
+                base,
+                specifier,
+                value,
+            },
+        }
+    }
+
+    /// an option of `key`, `c:v` for the one of some streams, with `value`
+    pub fn new(key: &str, value: Option<&str>) -> Self {
+        match (key.split_once(':'), value) {
+            (Some((base, specifier)), Some(value)) => Self::StreamSpec {
+                base: base.to_string(),
+                specifier: specifier.to_string(),
+                value: value.to_string(),
+            },
+            (_, Some(value)) => Self::KeyValue(key.to_string(), value.to_string()),
+            (_, None) => Self::Single(key.to_string()),
+        }
+    }
+
+    /// the name it is given by, `c:v` for the one of some streams
+    fn key(&self) -> String {
+        match self {
+            Self::Single(key) | Self::KeyValue(key, _) | Self::Repeated(key, _) => key.clone(),
+            Self::StreamSpec {
+                base, specifier, ..
+            } => format!("{}:{}", base, specifier),
+        }
+    }
+}
+
+/// the options of `args`, `-c:v prores_ks -profile:v 3 -an`
+pub fn parse_options(args: &str) -> Result<Vec<ProfileParameter>, String> {
+    let mut parameters = vec![];
+    let mut tokens = args.split_whitespace().peekable();
+    while let Some(token) = tokens.next() {
+        let key = token
+            .strip_prefix('-')
+            .filter(|key| !key.is_empty())
+            .ok_or_else(|| format!("expected an option, '-<name>', at '{}'", token))?;
+        let is_value = |next: &&str| !next.starts_with('-') || next.parse::<f64>().is_ok();
+        let value = tokens.next_if(is_value);
+        if key.contains(':') && value.is_none() {
+            return Err(format!("-{} takes a value", key));
+        }
+        parameters.push(ProfileParameter::new(key, value));
+    }
+    Ok(parameters)
+}
+
+/// A profile of the server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EncoderProfile {
+    pub name: String,
+    /// the format of ffmpeg the result is written in, `mov`, `matroska`
+    pub container: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pix_fmt: Option<String>,
+    pub parameters: Vec<ProfileParameter>,
+}
+
+impl FromStr for EncoderProfile {
+    type Err = String;
+
+    /// `<name>=<container>[,<pixel format>] <options>...`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expected = "expected '<name>=<container>[,<pixel format>] <options>...'";
+        let (name, rest) = s.split_once('=').ok_or(expected)?;
+        let rest = rest.trim_start();
+        let (format, options) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let (container, pix_fmt) = match format.split_once(',') {
+            Some((container, pix_fmt)) => (container, Some(pix_fmt.to_string())),
+            None => (format, None),
+        };
+        let name = name.trim();
+        let valid = |s: &str| {
+            !s.is_empty()
+                && s.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        };
+        if !valid(name) || !valid(container) {
+            return Err(expected.to_string());
+        }
+        Ok(Self {
+            name: name.to_string(),
+            container: container.to_string(),
+            pix_fmt,
+            parameters: parse_options(options)?,
+        })
+    }
+}
+
+impl EncoderProfile {
+    /// the extension of the result
+    pub fn extension(&self) -> &str {
+        match self.container.as_str() {
+            "matroska" => "mkv",
+            "mpegts" => "ts",
+            "ipod" => "m4a",
+            container => container,
+        }
+    }
+
+    /// the options of the profile with `overrides` over them, the container and the pixel format
+    /// last
+    ///
+    /// Fails when an override selects another codec or container than the profile.
+    pub fn merged(
+        &self,
+        overrides: &BTreeMap<String, String>,
+    ) -> Result<Vec<ProfileParameter>, String> {
+        let mut parameters = self.parameters.clone();
+        if let Some(pix_fmt) = &self.pix_fmt {
+            parameters.push(ProfileParameter::new("pix_fmt", Some(pix_fmt)));
+        }
+        parameters.push(ProfileParameter::new("f", Some(&self.container)));
+        let mut added = vec![];
+        for (key, value) in overrides {
+            let key = key.trim_start_matches('-');
+            let value = Some(value.as_str()).filter(|v| !v.is_empty());
+            let option = ProfileParameter::new(key, value);
+            let replaced = parameters
+                .iter_mut()
+                .filter(|p| p.key() == key && !p.as_parameter().is_selection())
+                .map(|p| *p = option.clone())
+                .count();
+            if replaced == 0 {
+                added.push(option);
+            }
+        }
+        parameters.extend(added);
+        // the layer of the options tells the conflicts
+        let mut output = File::new("-");
+        for parameter in &parameters {
+            output = output.option(parameter.as_parameter());
+        }
+        FfmpegBuilder::new()
+            .output(output)
+            .to_command()
+            .map_err(|e| e.to_string())?;
+        Ok(parameters)
+    }
+
+    /// the codec of the video, when the profile tells it
+    pub fn video_codec(&self) -> Option<&str> {
+        self.parameters.iter().find_map(|p| match p {
+            ProfileParameter::StreamSpec {
+                base,
+                specifier,
+                value,
+            } if (base == "c" || base == "codec") && specifier == "v" => Some(value.as_str()),
+            ProfileParameter::KeyValue(key, value) if key == "vcodec" => Some(value.as_str()),
+            _ => None,
+        })
+    }
+
+    /// what a validation is cached by: the options and the container
+    fn cache_key(&self) -> String {
+        serde_json::to_string(&(&self.container, &self.pix_fmt, &self.parameters))
+            .unwrap_or_default()
+    }
+}
+
+/// The outcome of the test encode of a profile
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Validation {
+    pub ok: bool,
+    pub at: DateTime<Local>,
+    /// the end of what ffmpeg wrote, when it failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// of what the test encode wrote
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub probe: Option<MediaInfo>,
+}
+
+impl Validation {
+    fn failed(error: impl ToString) -> Self {
+        Self {
+            ok: false,
+            at: Local::now(),
+            error: Some(error.to_string()),
+            probe: None,
+        }
+    }
+}
+
+static NEXT_DIR: AtomicU64 = AtomicU64::new(0);
+
+/// encode a second of the test source with the options of the profile
+pub async fn validate(profile: &EncoderProfile) -> Validation {
+    let dir = std::env::temp_dir().join(format!(
+        "record-screen-profile-{}-{}",
+        std::process::id(),
+        NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+    ));
+    if let Err(e) = tokio::fs::create_dir_all(&dir).await {
+        return Validation::failed(format!("cannot create {}: {}", dir.display(), e));
+    }
+    let output = dir.join(format!("test.{}", profile.extension()));
+    let validation = encode(profile, &output.to_string_lossy()).await;
+    if let Err(e) = tokio::fs::remove_dir_all(&dir).await {
+        warn!("cannot remove {}: {}", dir.display(), e);
+    }
+    validation
+}
+
+async fn encode(profile: &EncoderProfile, output: &str) -> Validation {
+    let parameters = match profile.merged(&BTreeMap::new()) {
+        Ok(parameters) => parameters,
+        Err(e) => return Validation::failed(e),
+    };
+    let mut file = File::new(output);
+    for parameter in &parameters {
+        file = file.option(parameter.as_parameter());
+    }
+    let command = FfmpegBuilder::new()
+        .stderr(Stdio::piped())
+        .option(Parameter::Single("y"))
+        .input(File::new(TEST_SOURCE).option(Parameter::KeyValue("f", "lavfi")))
+        .output(file)
+        .to_command();
+    let child = match command
+        .map_err(anyhow::Error::from)
+        .and_then(|mut c| Ok(c.spawn()?))
+    {
+        Ok(child) => child,
+        Err(e) => return Validation::failed(e),
+    };
+    let pid = child.id().unwrap_or_default();
+    let finished = match tokio::time::timeout(TIMEOUT, child.wait_with_output()).await {
+        Ok(Ok(finished)) => finished,
+        Ok(Err(e)) => return Validation::failed(e),
+        Err(_) => {
+            let _ = nix::sys::signal::kill(
+                nix::unistd::Pid::from_raw(pid as i32),
+                nix::sys::signal::Signal::SIGKILL,
+            );
+            return Validation::failed(format!("the test encode took over {:?}", TIMEOUT));
+        }
+    };
+    if !finished.status.success() {
+        let stderr = String::from_utf8_lossy(&finished.stderr);
+        let tail: Vec<&str> = stderr.lines().rev().take(STDERR_LINES).collect();
+        return Validation::failed(format!(
+            "ffmpeg exited with {}: {}",
+            finished.status,
+            tail.into_iter().rev().collect::<Vec<_>>().join("\n")
+        ));
+    }
+    match probe(std::path::Path::new(output)).await {
+        Ok(info) if info.video_codec.is_some() => Validation {
+            ok: true,
+            at: Local::now(),
+            error: None,
+            probe: Some(info),
+        },
+        Ok(_) => Validation::failed("the test encode has no video"),
+        Err(e) => Validation::failed(format!("cannot probe the test encode: {}", e)),
+    }
+}
+
+/// A profile with its last validation
+#[derive(Debug, Clone, Serialize)]
+pub struct ProfileStatus {
+    #[serde(flatten)]
+    pub profile: EncoderProfile,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub validation: Option<Validation>,
+}
+
+/// The profiles of the server and the validations, of them and of the profiles asked about
+#[derive(Default)]
+pub struct Profiles {
+    profiles: BTreeMap<String, EncoderProfile>,
+    validations: Mutex<HashMap<String, Validation>>,
+}
+
+impl Profiles {
+    pub fn new(profiles: Vec<EncoderProfile>) -> Self {
+        Self {
+            profiles: profiles.into_iter().map(|p| (p.name.clone(), p)).collect(),
+            validations: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&EncoderProfile> {
+        self.profiles.get(name)
+    }
+
+    fn cached(&self, profile: &EncoderProfile) -> Option<Validation> {
+        let validations = self.validations.lock().unwrap();
+        validations.get(&profile.cache_key()).cloned()
+    }
+
+    /// the profiles with their validation, by name
+    pub fn list(&self) -> Vec<ProfileStatus> {
+        self.profiles
+            .values()
+            .map(|profile| ProfileStatus {
+                validation: self.cached(profile),
+                profile: profile.clone(),
+            })
+            .collect()
+    }
+
+    /// the names of the profiles that passed their validation
+    pub fn validated(&self) -> Vec<String> {
+        self.list()
+            .into_iter()
+            .filter(|p| p.validation.as_ref().is_some_and(|v| v.ok))
+            .map(|p| p.profile.name)
+            .collect()
+    }
+
+    /// the profile of the name, when it passed its validation
+    pub fn usable(&self, name: &str) -> Result<&EncoderProfile, String> {
+        let profile = self
+            .get(name)
+            .ok_or_else(|| "not an encoder profile of the server".to_string())?;
+        match self.cached(profile) {
+            Some(validation) if validation.ok => Ok(profile),
+            Some(validation) => Err(format!(
+                "the profile failed its validation: {}",
+                validation.error.unwrap_or_default()
+            )),
+            None => Err("the profile is not validated yet".to_string()),
+        }
+    }
+
+    /// validate the profile, again when `fresh`, keeping the outcome
+    pub async fn validate(&self, profile: &EncoderProfile, fresh: bool) -> Validation {
+        if !fresh {
+            if let Some(validation) = self.cached(profile) {
+                return validation;
+            }
+        }
+        let validation = validate(profile).await;
+        let mut validations = self.validations.lock().unwrap();
+        validations.insert(profile.cache_key(), validation.clone());
+        validation
+    }
+
+    /// validate every profile of the server, failing with those that don't pass
+    pub async fn validate_all(&self) -> anyhow::Result<()> {
+        let mut failed = vec![];
+        for profile in self.profiles.values() {
+            let validation = self.validate(profile, true).await;
+            match validation.error {
+                None => info!("encoder profile {} validated", profile.name),
+                Some(error) => failed.push(format!("{}: {}", profile.name, error)),
+            }
+        }
+        match failed.is_empty() {
+            true => Ok(()),
+            false => anyhow::bail!("invalid encoder profiles, {}", failed.join("; ")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prores() -> EncoderProfile {
+        "prores_hq=mov,yuv422p10le -c:v prores_ks -profile:v 3 -an"
+            .parse()
+            .unwrap()
+    }
+
+    fn overrides(options: &[(&str, &str)]) -> BTreeMap<String, String> {
+        options
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn ffmpeg_installed() -> bool {
+        let installed = std::process::Command::new("ffmpeg")
+            .arg("-version")
+            .output()
+            .is_ok();
+        if !installed {
+            eprintln!("ffmpeg is not installed, skipped");
+        }
+        installed
+    }
+
+    #[test]
+    fn a_profile_is_parsed_into_typed_parameters() {
+        let profile = prores();
+        assert_eq!(profile.name, "prores_hq");
+        assert_eq!(profile.container, "mov");
+        assert_eq!(profile.pix_fmt.as_deref(), Some("yuv422p10le"));
+        assert_eq!(
+            profile.parameters,
+            [
+                ProfileParameter::new("c:v", Some("prores_ks")),
+                ProfileParameter::new("profile:v", Some("3")),
+                ProfileParameter::Single("an".to_string()),
+            ]
+        );
+        assert_eq!(profile.video_codec(), Some("prores_ks"));
+        let ffv1: EncoderProfile = "ffv1=matroska -vcodec ffv1 -level 3".parse().unwrap();
+        assert_eq!(ffv1.pix_fmt, None);
+        assert_eq!(ffv1.extension(), "mkv");
+        assert_eq!(ffv1.video_codec(), Some("ffv1"));
+        // a negative number is a value, not an option
+        assert_eq!(
+            parse_options("-itsoffset -0.5").unwrap(),
+            [ProfileParameter::new("itsoffset", Some("-0.5"))]
+        );
+    }
+
+    #[test]
+    fn an_invalid_profile_is_refused() {
+        for invalid in [
+            "no-container",
+            "=mov -c:v prores_ks",
+            "prores hq=mov -c:v prores_ks",
+            "prores=mov/x -c:v prores_ks",
+            "prores=mov c:v prores_ks",
+            "prores=mov -c:v",
+        ] {
+            assert!(invalid.parse::<EncoderProfile>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn the_overrides_replace_or_add_to_the_options_of_the_profile() {
+        let merged = prores()
+            .merged(&overrides(&[("profile:v", "2"), ("-vendor", "apl0")]))
+            .unwrap();
+        assert_eq!(
+            merged,
+            [
+                ProfileParameter::new("c:v", Some("prores_ks")),
+                ProfileParameter::new("profile:v", Some("2")),
+                ProfileParameter::Single("an".to_string()),
+                ProfileParameter::new("pix_fmt", Some("yuv422p10le")),
+                ProfileParameter::new("f", Some("mov")),
+                ProfileParameter::new("vendor", Some("apl0")),
+            ]
+        );
+        // the same codec again is no conflict
+        assert!(prores().merged(&overrides(&[("c:v", "prores_ks")])).is_ok());
+    }
+
+    #[test]
+    fn an_override_selecting_another_codec_or_container_conflicts() {
+        for conflicting in [("c:v", "ffv1"), ("vcodec", "libx264"), ("f", "matroska")] {
+            let merged = prores().merged(&overrides(&[conflicting]));
+            assert!(merged.is_err(), "{:?}", conflicting);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_profile_is_only_usable_once_validated() {
+        let broken: EncoderProfile = "broken=matroska -c:v nosuchencoder".parse().unwrap();
+        let profiles = Profiles::new(vec![broken.clone()]);
+        assert!(profiles.usable("other").is_err());
+        let not_yet = profiles.usable("broken").unwrap_err();
+        assert!(not_yet.contains("not validated"), "{}", not_yet);
+
+        let validation = profiles.validate(&broken, false).await;
+        assert!(!validation.ok);
+        assert!(validation.probe.is_none());
+        let failed = profiles.usable("broken").unwrap_err();
+        assert!(failed.contains("failed its validation"), "{}", failed);
+        assert!(profiles.validated().is_empty());
+        assert!(profiles.validate_all().await.is_err());
+        if ffmpeg_installed() {
+            // the excerpt of the stderr of ffmpeg tells why
+            let error = validation.error.unwrap();
+            assert!(error.contains("nosuchencoder"), "{}", error);
+        }
+    }
+
+    #[tokio::test]
+    async fn real_profiles_encode_the_test_source() {
+        if !ffmpeg_installed() {
+            return;
+        }
+        let ffv1: EncoderProfile = "ffv1=matroska -c:v ffv1 -level 3".parse().unwrap();
+        let profiles = Profiles::new(vec![prores(), ffv1]);
+        profiles.validate_all().await.unwrap();
+        assert_eq!(profiles.validated(), ["ffv1", "prores_hq"]);
+        for status in profiles.list() {
+            let probe = status.validation.unwrap().probe.unwrap();
+            let codec = probe.video_codec.unwrap();
+            assert!(codec.starts_with(&status.profile.name[..4]), "{}", codec);
+        }
+    }
+}
@@ -1,53 +1,2139 @@
+use crate::auth::{self, Access, ApiToken, Role, Tokens};
+use crate::canary::{self, Canaries};
+use crate::capture_paths;
+use crate::checksums;
+use crate::chunks;
+use crate::contact_sheet::{self, SheetRequest};
+use crate::content_check;
+use crate::cursor;
+use crate::display;
+use crate::encoder_profiles::{self, EncoderProfile, ProfileStatus};
+use crate::events::{Event, EventKind, Message};
+use crate::feed;
+use crate::ffmpeg::{FfmpegBuilder, File};
+use crate::frames::{self, FramesRequest};
+use crate::geometry;
+use crate::gif::{self, GifRequest};
+use crate::gpu;
+use crate::history::{self, History, PageQuery};
+use crate::hwaccel;
+use crate::idempotency;
+use crate::jobs::Journal;
+use crate::latency;
+use crate::liveness::{self, Liveness, Report};
+use crate::logging;
+use crate::options_schema;
+use crate::otel;
+use crate::overlays;
+use crate::pause;
+use crate::play::{Lookup, PlayCache, PlayFormat};
+use crate::policy::Policy;
+use crate::presence::{self, Client, Identity};
+use crate::preview;
+use crate::problem::{self, ApiError, ProblemType};
+use crate::pulse;
+use crate::quality::{self, QualityChange};
+use crate::quota::{self, OwnerQuota, Quotas};
+use crate::recordings;
+use crate::recovery::{self, StateFile};
+use crate::rtmp;
+use crate::runner::CancellationToken;
+use crate::s3::Bucket;
+use crate::scale;
+use crate::schema;
 use crate::service::*;
+use crate::shutdown;
+use crate::source::CaptureSource;
+use crate::storage::{self, StorageBackend};
+use crate::sync_start;
+use crate::thumbnail;
+use crate::timed;
+use crate::transcripts::{Transcriber, Transcribers};
+use crate::trim::{self, StopRequest};
+use crate::webhook::Webhook;
+use crate::webrtc_preview;
+use axum::body::{Body, Bytes, StreamBody};
+use axum::extract::ws::{self, WebSocket, WebSocketUpgrade};
+use axum::extract::{BodyStream, Path, Query};
+use axum::http::{header, HeaderMap, Method, Request, StatusCode};
+use axum::response::sse::{self, KeepAlive, Sse};
 use axum::response::*;
 use axum::Json;
 use axum::{extract::DefaultBodyLimit, extract::Extension, routing::*, Router, Server};
+use futures::{Stream, StreamExt};
+use http_body::LengthLimitError;
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::broadcast;
+use tower::ServiceExt;
 use tower_http::cors::{Any, CorsLayer};
 use tower_http::limit::RequestBodyLimitLayer;
+use tower_http::services::ServeFile;
 use tower_http::trace::*;
 use tracing::*;
 
+/// the claim of a recording of `opt`, or the response refusing it
+async fn claim_checked(
+    shared_state: &Arc<Recorder>,
+    opt: &RecordingOptions,
+) -> Result<StartClaim, Response> {
+    let policy = shared_state.policy.status(chrono::Local::now());
+    if !policy.allowed {
+        return Err(ApiError::new(
+            ProblemType::Forbidden,
+            "recording is not allowed at this time",
+        )
+        .with_reason(FailureReason::OutsideAllowedWindow)
+        .with("next_window", policy.next_window)
+        .into_response());
+    }
+    if let Some(transcribe) = &opt.transcribe {
+        if shared_state.transcribers.get(&transcribe.command).is_none() {
+            return Err(ApiError::validation("invalid transcript")
+                .with_field("transcribe.command", "not a transcriber of the server")
+                .into_response());
+        }
+    }
+    match &opt.encoder_profile {
+        Some(name) => {
+            if let Err(e) = shared_state
+                .encoder_profiles
+                .usable(name)
+                .and_then(|profile| profile.merged(&opt.encoder_options))
+            {
+                return Err(ApiError::validation("invalid encoder profile")
+                    .with_field("encoder_profile", e)
+                    .into_response());
+            }
+        }
+        None if !opt.encoder_options.is_empty() => {
+            return Err(ApiError::validation("invalid encoder options")
+                .with_field("encoder_options", "only given with an encoder_profile")
+                .into_response());
+        }
+        None => {}
+    }
+    let in_progress = quota::in_progress(shared_state).await;
+    if let Err(e) = shared_state
+        .quotas
+        .check(opt.owner.as_deref(), &in_progress)
+    {
+        return Err(ApiError::new(ProblemType::Forbidden, &e)
+            .with_reason(FailureReason::QuotaExceeded)
+            .with("owner", &e.owner)
+            .with("used", e.used)
+            .with("reserved", e.reserved)
+            .with("limit", e.limit)
+            .into_response());
+    }
+    if let Err(e) = opt.source.validate() {
+        return Err(ApiError::validation("invalid source")
+            .with_field("source", e)
+            .into_response());
+    }
+    if let Err(e) = geometry::validate_region(opt) {
+        return Err(ApiError::validation("invalid region")
+            .with_field("region", e)
+            .into_response());
+    }
+    if let Err((field, e)) = geometry::validate_size(opt) {
+        return Err(ApiError::validation("invalid capture size")
+            .with_field(field, e)
+            .into_response());
+    }
+    if let Err((field, e)) = chunks::validate(opt) {
+        return Err(ApiError::validation("invalid chunks")
+            .with_field(field, e)
+            .into_response());
+    }
+    if let Err((field, e)) = rtmp::validate(opt) {
+        return Err(ApiError::validation("invalid stream")
+            .with_field(field, e)
+            .into_response());
+    }
+    if let Err((field, e)) = overlays::validate(opt) {
+        return Err(ApiError::validation("invalid overlay")
+            .with_field(field, e)
+            .into_response());
+    }
+    if let Err((field, e)) = cursor::validate(opt) {
+        return Err(ApiError::validation("invalid pointer")
+            .with_field(field, e)
+            .into_response());
+    }
+    if let Err((field, e)) = scale::validate(opt) {
+        return Err(ApiError::validation("invalid scale")
+            .with_field(field, e)
+            .into_response());
+    }
+    if let Err((field, e)) = webrtc_preview::validate(opt) {
+        return Err(ApiError::validation("invalid preview")
+            .with_field(field, e)
+            .into_response());
+    }
+    if let Err((field, e)) = pulse::validate(opt) {
+        return Err(ApiError::validation("invalid audio source")
+            .with_field(field, e)
+            .into_response());
+    }
+    if let Err(e) = pulse::check(opt).await {
+        let refused = match &e {
+            pulse::Error::Unknown { available, .. } => {
+                let field = match opt.audio_sources.is_empty() {
+                    true => "audio_source",
+                    false => "audio_sources",
+                };
+                ApiError::validation("invalid audio source")
+                    .with_field(field, &e)
+                    .with("available", available)
+            }
+            _ => ApiError::new(ProblemType::Busy, &e),
+        };
+        return Err(refused.into_response());
+    }
+    if let Err((field, e)) = validate_compression(opt) {
+        return Err(ApiError::validation("invalid compression")
+            .with_field(field, e)
+            .into_response());
+    }
+    if let Err(e) = hwaccel::check(opt.encoder).await {
+        return Err(ApiError::validation("unavailable encoder")
+            .with_field("encoder", e)
+            .into_response());
+    }
+    if opt.source == CaptureSource::Screen {
+        match display::detect().await {
+            Ok(detected) => {
+                if let Err(e) = detected.check(opt) {
+                    let field = if opt.region.is_some() {
+                        "region"
+                    } else {
+                        "width"
+                    };
+                    return Err(ApiError::validation("invalid region")
+                        .with_field(field, e)
+                        .into_response());
+                }
+            }
+            Err(e @ display::Error::Unreachable { .. }) => {
+                return Err(ApiError::new(ProblemType::Busy, e)
+                    .with_reason(FailureReason::DisplayUnavailable)
+                    .with("display", display::name())
+                    .into_response())
+            }
+            Err(_) => {}
+        }
+    }
+    if let Some(e) = opt
+        .start_at
+        .and_then(|at| sync_start::invalid(at, chrono::Local::now()))
+    {
+        return Err(ApiError::validation("invalid start time")
+            .with_field("start_at", e)
+            .into_response());
+    }
+    if let Some(fallback) = &shared_state.fallback_dir {
+        if let Err(e) = crate::failover::writable(fallback).await {
+            return Err(ApiError::new(
+                ProblemType::Busy,
+                format!("the fallback directory can't be written: {}", e),
+            )
+            .with("fallback_dir", fallback)
+            .into_response());
+        }
+    }
+    let current = shared_state.lock().await.name();
+    if matches!(
+        current,
+        "Countdown" | "Started" | "Paused" | "Stopping" | "Compressing" | "Uploading"
+    ) {
+        return Err(ApiError::conflict(format!("cannot start while {}", current)).into_response());
+    }
+    shared_state
+        .claim_start()
+        .ok_or_else(|| ApiError::conflict("a recording is being started already").into_response())
+}
+
 pub async fn handle_start(
-    Extension(shared_state): Extension<Arc<Mutex<RecordingState>>>,
+    Extension(shared_state): Extension<Arc<Recorder>>,
+    Extension(identity): Extension<Identity>,
     Json(opt): Json<RecordingOptions>,
-) -> impl IntoResponse {
-    let mx = shared_state.clone();
-    tokio::spawn(start(mx, opt));
-    Json("STARTED")
+) -> Response {
+    let claim = match claim_checked(&shared_state, &opt).await {
+        Ok(claim) => claim,
+        Err(res) => return res,
+    };
+    info!("start requested by {}", identity);
+    let start_at = opt.start_at;
+    let live = shared_state.subscribe(None).await.live;
+    tokio::spawn(start_claimed(claim, opt, Some(identity)));
+    match start_at {
+        None => accepted(&shared_state, live).await,
+        Some(start_at) => Json(sync_start::ScheduledStart {
+            start_at,
+            server_time: chrono::Local::now(),
+            clock_synchronized: sync_start::clock_synchronized().await,
+        })
+        .into_response(),
+    }
+}
+
+/// What `/api/record` answers with once the recording is finished
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordReturn {
+    /// the Done state
+    #[default]
+    State,
+    /// the recording itself
+    File,
+}
+
+#[derive(Deserialize)]
+pub struct RecordQuery {
+    duration_secs: u64,
+    #[serde(default, rename = "return")]
+    returns: RecordReturn,
+    /// cancel the recording when the client goes away before it is finished
+    #[serde(default)]
+    cancel_on_disconnect: bool,
+}
+
+/// The Done state of a recording of `/api/record`, with its size
+#[derive(Serialize)]
+pub struct Recorded {
+    #[serde(flatten)]
+    pub state: RecordingState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+}
+
+/// the name of the object of a recording
+fn object_name(file: &str) -> String {
+    std::path::Path::new(file)
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// what a timed recording that finished as `finished` is answered with
+async fn recorded(state: &Recorder, finished: RecordingState) -> Result<Recorded, ApiError> {
+    match finished {
+        RecordingState::Done { ref file, .. } => {
+            let size = match state.storage.stat(&object_name(file)).await {
+                Ok(object) => object.map(|o| o.size),
+                Err(_) => None,
+            };
+            Ok(Recorded {
+                state: finished,
+                size,
+            })
+        }
+        RecordingState::Failed {
+            reason, message, ..
+        } => Err(ApiError::internal(message).with_reason(reason)),
+        RecordingState::Cancelled { reason, .. } => {
+            Err(ApiError::conflict("the recording was cancelled").with_reason(reason))
+        }
+        other => Err(ApiError::internal(format!(
+            "the recording ended as {}",
+            other.name()
+        ))),
+    }
+}
+
+/// record for `duration_secs` and answer once the recording is finished
+///
+/// With `Accept: text/event-stream` the progress is sent as comments while it runs, and what it
+/// finished as in a `done` event.
+pub async fn handle_record(
+    Extension(state): Extension<Arc<Recorder>>,
+    Extension(identity): Extension<Identity>,
+    Query(query): Query<RecordQuery>,
+    headers: HeaderMap,
+    Json(opt): Json<RecordingOptions>,
+) -> Response {
+    let max = state.max_record.unwrap_or(timed::DEFAULT_MAX).as_secs();
+    if !(1..=max).contains(&query.duration_secs) {
+        return ApiError::validation("invalid duration")
+            .with_field("duration_secs", format!("must be within 1..{}", max))
+            .into_response();
+    }
+    if opt.start_at.is_some() {
+        return ApiError::validation("invalid start time")
+            .with_field("start_at", "a timed recording starts at once")
+            .into_response();
+    }
+    let streaming = headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("text/event-stream"));
+    if streaming && query.returns == RecordReturn::File {
+        return ApiError::validation("invalid return")
+            .with_field("return", "the file can't be sent as events")
+            .into_response();
+    }
+    let claim = match claim_checked(&state, &opt).await {
+        Ok(claim) => claim,
+        Err(res) => return res,
+    };
+    info!(
+        "recording of {}s requested by {}",
+        query.duration_secs, identity
+    );
+    let mut updates = timed::spawn(
+        claim,
+        opt,
+        Some(identity.clone()),
+        std::time::Duration::from_secs(query.duration_secs),
+    )
+    .await;
+    let mut guard = timed::CancelOnDrop::new(query.cancel_on_disconnect, &state, &identity);
+    if !streaming {
+        // the future is dropped with the connection, and the guard with it
+        while let Some(update) = updates.recv().await {
+            let timed::Update::Finished(finished) = update else {
+                continue;
+            };
+            guard.disarm();
+            if let (RecordingState::Done { file, .. }, RecordReturn::File) =
+                (&finished, query.returns)
+            {
+                return serve_object(
+                    &*state.storage,
+                    &object_name(file),
+                    &Method::GET,
+                    &HeaderMap::new(),
+                )
+                .await;
+            }
+            return match recorded(&state, finished).await {
+                Ok(recorded) => Json(recorded).into_response(),
+                Err(e) => e.into_response(),
+            };
+        }
+        return ApiError::internal("the recording was lost").into_response();
+    }
+    let stream = futures::stream::unfold(Some((updates, guard, state)), |next| async move {
+        let (mut updates, mut guard, state) = next?;
+        match updates.recv().await {
+            Some(timed::Update::Progress(progress)) => {
+                let comment = format!(
+                    "{:?} frame={} time={:.1}s",
+                    progress.status,
+                    progress.frame.unwrap_or_default(),
+                    progress.out_time.unwrap_or_default().as_secs_f64()
+                );
+                let event = sse::Event::default().comment(comment);
+                Some((event, Some((updates, guard, state))))
+            }
+            Some(timed::Update::Finished(finished)) => {
+                guard.disarm();
+                let event = match recorded(&state, finished).await {
+                    Ok(recorded) => sse::Event::default().event("done").json_data(recorded),
+                    Err(e) => sse::Event::default().event("error").json_data(e),
+                };
+                Some((event.unwrap_or_default(), None))
+            }
+            None => None,
+        }
+    });
+    Sse::new(stream.map(Ok::<_, Infallible>))
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+#[derive(Deserialize)]
+pub struct StatusQuery {
+    /// include the commands of the processes
+    #[serde(default)]
+    verbose: bool,
+}
+
+/// The state, with the clients using the server
+#[derive(Serialize)]
+pub struct Status {
+    #[serde(flatten)]
+    pub state: RecordingState,
+    pub clients: Vec<Client>,
+    /// seconds since the start of the recording
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub elapsed_wall: Option<f64>,
+    /// seconds recorded of them, without the gaps between the segments
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recorded_content: Option<f64>,
+    /// the URL of the playlist of the live preview, see [preview]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preview_url: Option<&'static str>,
+}
+
+impl Status {
+    fn new(state: RecordingState, clients: Vec<Client>) -> Self {
+        let (elapsed_wall, recorded_content) = match state.recording() {
+            RecordingState::Started {
+                started_at,
+                timeline,
+                ..
+            } => {
+                let wall_ms = since(*started_at);
+                (
+                    Some(wall_ms as f64 / 1000.0),
+                    Some(timeline.content_at(wall_ms) as f64 / 1000.0),
+                )
+            }
+            _ => (None, None),
+        };
+        let preview_url = match &state {
+            RecordingState::Started {
+                preview: Some(_), ..
+            } => Some(preview::URL),
+            _ => None,
+        };
+        Self {
+            state,
+            clients,
+            elapsed_wall,
+            recorded_content,
+            preview_url,
+        }
+    }
 }
 
 pub async fn handle_status(
-    Extension(state): Extension<Arc<Mutex<RecordingState>>>,
+    Extension(state): Extension<Arc<Recorder>>,
+    Query(query): Query<StatusQuery>,
 ) -> impl IntoResponse {
     let mx = state.clone();
     let s = mx.lock().await.clone();
-    Json(s).into_response()
+    let clients = mx.presence.list();
+    if query.verbose {
+        return Json(Status::new(s, clients)).into_response();
+    }
+    Json(Status::new(s.without_command(), clients)).into_response()
 }
 
+/// a file of the live preview of the running recording, the playlist or a segment
+pub async fn handle_preview(
+    Extension(state): Extension<Arc<Recorder>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let dir = match &*state.lock().await {
+        RecordingState::Started {
+            preview: Some(dir), ..
+        } => dir.clone(),
+        _ => return ApiError::not_found("no live preview").into_response(),
+    };
+    let Some(path) = preview::file(&dir, &name) else {
+        return ApiError::not_found("not a file of the preview").into_response();
+    };
+    let mut res = serve_file(&path, &headers).await;
+    // the playlist changes with every segment
+    res.headers_mut().insert(
+        header::CACHE_CONTROL,
+        header::HeaderValue::from_static("no-cache"),
+    );
+    res
+}
+
+/// stop the recording, trimmed as the body asks when there is one, see [crate::trim]
 pub async fn handle_stop(
-    Extension(shared_state): Extension<Arc<Mutex<RecordingState>>>,
-) -> impl IntoResponse {
+    Extension(shared_state): Extension<Arc<Recorder>>,
+    Extension(identity): Extension<Identity>,
+    body: Bytes,
+) -> Response {
+    let request: StopRequest = match body.is_empty() {
+        true => Default::default(),
+        false => match serde_json::from_slice(&body) {
+            Ok(request) => request,
+            Err(e) => {
+                return ApiError::validation("invalid stop request")
+                    .with_field("body", e.to_string())
+                    .into_response()
+            }
+        },
+    };
     let mx = shared_state.clone();
-    tokio::spawn(stop(mx));
-    Json("STOPPED")
+    let current = mx.lock().await.name();
+    if !matches!(current, "Countdown" | "Started" | "Paused") {
+        return ApiError::conflict(format!("cannot stop while {}", current)).into_response();
+    }
+    if request.asks() {
+        if let RecordingState::Started {
+            started_at,
+            timeline,
+            ..
+        } = mx.lock().await.recording()
+        {
+            let checked = request
+                .trim(mx.trim)
+                .and_then(|trim| trim.window_at(timeline, since(*started_at)));
+            if let Err(e) = checked {
+                return ApiError::validation("invalid trim")
+                    .with_field(e.field(), e.to_string())
+                    .into_response();
+            }
+        }
+    }
+    info!("stop requested by {}", identity);
+    let live = mx.subscribe(None).await.live;
+    tokio::spawn(stop_by(mx.clone(), Some(identity), request));
+    accepted(&mx, live).await
+}
+
+/// how long a start or a stop is waited for before answering with the state as it is
+const HANDSHAKE: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// 202 with the state once the spawned start or stop changed it, or after [HANDSHAKE]
+async fn accepted(
+    mx: &Recorder,
+    mut live: tokio::sync::broadcast::Receiver<crate::events::Event>,
+) -> Response {
+    let changed = async {
+        loop {
+            match live.recv().await {
+                Ok(event) if matches!(event.kind, crate::events::EventKind::State { .. }) => break,
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                _ => {}
+            }
+        }
+    };
+    let _ = tokio::time::timeout(HANDSHAKE, changed).await;
+    let state = mx.lock().await.clone().without_command();
+    (StatusCode::ACCEPTED, Json(state)).into_response()
+}
+
+/// change the quality of the running capture, rolling it over to a new segment
+pub async fn handle_quality(
+    Extension(state): Extension<Arc<Recorder>>,
+    Extension(identity): Extension<Identity>,
+    Json(change): Json<QualityChange>,
+) -> Response {
+    info!("quality change {:?} requested by {}", change, identity);
+    match quality::request(&state, &change).await {
+        Ok(next) => Json(next).into_response(),
+        Err(quality::Error::Invalid(field, message)) => ApiError::validation("invalid quality")
+            .with_field(field, message)
+            .into_response(),
+        Err(e @ quality::Error::TooSoon { retry_after }) => {
+            let mut res = ApiError::new(ProblemType::Busy, e)
+                .with("retry_after", retry_after)
+                .into_response();
+            res.headers_mut()
+                .insert(header::RETRY_AFTER, header::HeaderValue::from(retry_after));
+            res
+        }
+        Err(e) => ApiError::conflict(e).into_response(),
+    }
+}
+
+/// pause the running capture, the state tells of the pause
+pub async fn handle_pause(
+    Extension(state): Extension<Arc<Recorder>>,
+    Extension(identity): Extension<Identity>,
+) -> Response {
+    info!("pause requested by {}", identity);
+    match pause::pause(&state).await {
+        Ok(_) => Json(state.lock().await.clone().without_command()).into_response(),
+        Err(e) => ApiError::conflict(e).into_response(),
+    }
+}
+
+/// resume the paused capture in a new segment
+pub async fn handle_resume(
+    Extension(state): Extension<Arc<Recorder>>,
+    Extension(identity): Extension<Identity>,
+) -> Response {
+    info!("resume requested by {}", identity);
+    match pause::resume(&state).await {
+        Ok(_) => Json(state.lock().await.clone().without_command()).into_response(),
+        Err(e) => ApiError::conflict(e).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct EventsQuery {
+    /// resume after the event with this sequence number
+    since_seq: Option<u64>,
+    /// end the stream once the recording is done, failed or cancelled
+    #[serde(default)]
+    until_done: bool,
+}
+
+fn sse_event(message: Message) -> sse::Event {
+    let (seq, name) = match &message {
+        Message::Resync { seq, .. } => (*seq, "resync"),
+        Message::Event(e) => match e.kind {
+            crate::events::EventKind::State { .. } => (e.seq, "state"),
+            crate::events::EventKind::Progress { .. } => (e.seq, "progress"),
+            crate::events::EventKind::Notice { .. } => (e.seq, "notice"),
+            crate::events::EventKind::Suspect { .. } => (e.seq, "suspect"),
+            crate::events::EventKind::Health { .. } => (e.seq, "health"),
+        },
+    };
+    sse::Event::default()
+        .id(seq.to_string())
+        .event(name)
+        .json_data(&message)
+        .unwrap_or_default()
+}
+
+/// whether the message tells that the recording is over
+fn ends_recording(message: &Message) -> bool {
+    match message {
+        Message::Resync { state, .. } => crate::timed::finished(state),
+        Message::Event(e) => match &e.kind {
+            crate::events::EventKind::State { state } => crate::timed::finished(state),
+            _ => false,
+        },
+    }
+}
+
+/// the current state to start from, with the events that follow it
+async fn resync(mx: &Recorder) -> (Message, broadcast::Receiver<Event>) {
+    let current = mx.lock().await;
+    let seq = mx.events.seq();
+    let live = mx.events.subscribe(None, &current).live;
+    let resync = Message::Resync {
+        seq,
+        state: current.clone(),
+    };
+    (resync, live)
+}
+
+/// state changes and progress as server-sent events,
+/// resumable with `?since_seq=` or the `Last-Event-ID` header,
+/// ending after the last state of the recording with `?until_done=true`
+pub async fn handle_events(
+    Extension(state): Extension<Arc<Recorder>>,
+    Extension(identity): Extension<Identity>,
+    Query(query): Query<EventsQuery>,
+    headers: HeaderMap,
+) -> Sse<impl Stream<Item = Result<sse::Event, Infallible>>> {
+    let since = query.since_seq.or_else(|| {
+        headers
+            .get("last-event-id")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+    });
+    let subscription = state.subscribe(since).await;
+    let replay = futures::stream::iter(subscription.replay);
+    // the client is present for as long as the stream is
+    let watching = state.presence.connect(&identity);
+    let live = futures::stream::unfold(
+        (subscription.live, state, watching),
+        |(mut live, state, watching)| async move {
+            use tokio::sync::broadcast::error::RecvError;
+            match live.recv().await {
+                Ok(event) => Some((Message::Event(event), (live, state, watching))),
+                Err(RecvError::Lagged(_)) => {
+                    // the client is too slow: start over from the current state
+                    let (resync, live) = resync(&state).await;
+                    Some((resync, (live, state, watching)))
+                }
+                Err(RecvError::Closed) => None,
+            }
+        },
+    );
+    let until_done = query.until_done;
+    let stream = replay
+        .chain(live)
+        .flat_map(move |m| {
+            // the last state of the recording is followed by the end of the stream
+            let end = (until_done && ends_recording(&m)).then_some(None);
+            futures::stream::iter(std::iter::once(Some(m)).chain(end))
+        })
+        .scan((), |_, m| futures::future::ready(m))
+        .map(|m| Ok(sse_event(m)));
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+/// whether the message goes to the progress clients: the states, the progress and the resyncs
+fn is_progress(message: &Message) -> bool {
+    match message {
+        Message::Resync { .. } => true,
+        Message::Event(e) => matches!(e.kind, EventKind::State { .. } | EventKind::Progress { .. }),
+    }
+}
+
+/// the progress and the state changes after the event `since`, as `/api/events` replays them, or
+/// the current state first without it
+async fn progress_messages(mx: Arc<Recorder>, since: Option<u64>) -> impl Stream<Item = Message> {
+    let (first, live) = match since {
+        Some(since) => {
+            let subscription = mx.subscribe(Some(since)).await;
+            (subscription.replay, subscription.live)
+        }
+        None => {
+            let (current, live) = resync(&mx).await;
+            (vec![current], live)
+        }
+    };
+    let live = futures::stream::unfold((live, mx), |(mut live, mx)| async move {
+        use tokio::sync::broadcast::error::RecvError;
+        match live.recv().await {
+            Ok(event) => Some((Message::Event(event), (live, mx))),
+            Err(RecvError::Lagged(_)) => {
+                let (resync, live) = resync(&mx).await;
+                Some((resync, (live, mx)))
+            }
+            Err(RecvError::Closed) => None,
+        }
+    });
+    futures::stream::iter(first)
+        .chain(live)
+        .filter(|message| futures::future::ready(is_progress(message)))
+}
+
+#[derive(Deserialize)]
+pub struct ProgressQuery {
+    /// resume after the event with this sequence number
+    since_seq: Option<u64>,
+}
+
+/// progress and state changes over a WebSocket, as the messages of `/api/events`, the first one
+/// being a resync with the current state, or resumed with `?since_seq=`
+pub async fn handle_progress(
+    Extension(state): Extension<Arc<Recorder>>,
+    Extension(identity): Extension<Identity>,
+    Query(query): Query<ProgressQuery>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    ws.on_upgrade(move |socket| send_progress(socket, state, identity, query.since_seq))
+}
+
+async fn send_progress(
+    mut socket: WebSocket,
+    mx: Arc<Recorder>,
+    identity: Identity,
+    since: Option<u64>,
+) {
+    // the client is present for as long as the socket is
+    let _watching = mx.presence.connect(&identity);
+    let mut messages = std::pin::pin!(progress_messages(mx, since).await);
+    loop {
+        tokio::select! {
+            message = messages.next() => {
+                let Some(message) = message else {
+                    break;
+                };
+                let Ok(text) = serde_json::to_string(&message) else {
+                    continue;
+                };
+                if socket.send(ws::Message::Text(text)).await.is_err() {
+                    break;
+                }
+            }
+            // nothing the client sends is read but its close
+            received = socket.recv() => match received {
+                Some(Ok(ws::Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+/// the page trying the WebRTC preview in a browser
+pub async fn handle_webrtc_page() -> Html<&'static str> {
+    Html(webrtc_preview::PAGE)
+}
+
+/// the SDP answer to the offer of a viewer of the WebRTC preview, see [webrtc_preview]
+pub async fn handle_webrtc_offer(
+    Extension(state): Extension<Arc<Recorder>>,
+    offer: String,
+) -> Response {
+    match state.webrtc_preview.answer(offer).await {
+        Ok(answer) => ([(header::CONTENT_TYPE, "application/sdp")], answer).into_response(),
+        Err(e @ webrtc_preview::Error::Unavailable) => ApiError::not_found(e).into_response(),
+        Err(e @ webrtc_preview::Error::Failed(_)) => {
+            ApiError::new(ProblemType::Busy, e).into_response()
+        }
+        Err(e) => ApiError::conflict(e).into_response(),
+    }
+}
+
+/// header with the count of the recordings of every page
+pub const TOTAL_COUNT: &str = "x-total-count";
+
+/// the finished recordings, a page of them, those whose transcript matches with `?q=`
+pub async fn handle_recordings(
+    Extension(state): Extension<Arc<Recorder>>,
+    Query(query): Query<recordings::ListQuery>,
+) -> Response {
+    if let Some((field, message)) = query.invalid() {
+        return ApiError::validation("invalid listing")
+            .with_field(field, message)
+            .into_response();
+    }
+    match recordings::list(&state, &query).await {
+        Ok(listing) => (
+            [(TOTAL_COUNT, listing.total.to_string())],
+            Json(listing.recordings),
+        )
+            .into_response(),
+        Err(e) => ApiError::internal(e).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DownloadQuery {
+    /// allow downloading while the recording is compressed
+    #[serde(default)]
+    progressive: bool,
+}
+
+/// header telling which file a progressive download actually sends
+const PROGRESSIVE_SOURCE: &str = "x-progressive-source";
+
+async fn serve_file(path: &std::path::Path, headers: &HeaderMap) -> Response {
+    let mut req = Request::new(Body::empty());
+    *req.headers_mut() = headers.clone();
+    match ServeFile::new(path).oneshot(req).await {
+        Ok(res) => res.map(axum::body::boxed).into_response(),
+        Err(e) => ApiError::internal(e).into_response(),
+    }
+}
+
+/// an object of the storage, or the range of it the headers ask for
+///
+/// The ETag is the checksum of its manifest, or its size and time without one. A range with an
+/// If-Range the object no longer matches gets all of it, a range of several parts a 416: only
+/// single ranges are served. HEAD gets the same headers without opening the object.
+async fn serve_object(
+    storage: &dyn StorageBackend,
+    name: &str,
+    method: &Method,
+    headers: &HeaderMap,
+) -> Response {
+    let info = match storage.stat(name).await {
+        Ok(Some(info)) => info,
+        Ok(None) => return ApiError::not_found("no such recording").into_response(),
+        Err(e) => return ApiError::internal(e).into_response(),
+    };
+    let sha256 = object_sha256(storage, name).await;
+    let etag = match (&sha256, info.modified) {
+        (Some(sha256), _) => Some(format!("\"{}\"", sha256)),
+        (None, Some(modified)) => Some(format!("\"{:x}-{:x}\"", info.size, modified.timestamp())),
+        (None, None) => None,
+    };
+    let last_modified = info.modified.map(http_date);
+    let range = headers.get(header::RANGE).and_then(|h| h.to_str().ok());
+    if range.is_some_and(|range| range.contains(',')) {
+        return (
+            [(header::CONTENT_RANGE, format!("bytes */{}", info.size))],
+            ApiError::new(
+                ProblemType::RangeNotSatisfiable,
+                "only a single range is served, ask for the parts one by one",
+            ),
+        )
+            .into_response();
+    }
+    let fresh = match headers.get(header::IF_RANGE).and_then(|h| h.to_str().ok()) {
+        None => true,
+        // weak tags never match
+        Some(tag) if tag.starts_with('"') => etag.as_deref() == Some(tag),
+        Some(date) => last_modified.as_deref() == Some(date),
+    };
+    let range = range
+        .filter(|_| fresh)
+        .and_then(|h| storage::parse_range(h, info.size));
+    let (status, range) = match range {
+        None => (StatusCode::OK, None),
+        Some(Ok(range)) => (StatusCode::PARTIAL_CONTENT, Some(range)),
+        Some(Err(())) => {
+            return (
+                [(header::CONTENT_RANGE, format!("bytes */{}", info.size))],
+                ApiError::new(
+                    ProblemType::RangeNotSatisfiable,
+                    format!("the range is past the end, at {} bytes", info.size),
+                ),
+            )
+                .into_response()
+        }
+    };
+    let body = if method == Method::HEAD {
+        axum::body::boxed(Body::empty())
+    } else {
+        match storage.get(name, range.clone()).await {
+            Ok(reader) => axum::body::boxed(StreamBody::new(storage::stream(reader))),
+            Err(e) => return ApiError::internal(e).into_response(),
+        }
+    };
+    let mime = mime_guess::from_path(name).first_or_octet_stream();
+    let length = range.as_ref().map_or(info.size, |r| r.end - r.start);
+    let mut res = (
+        status,
+        [
+            (header::CONTENT_TYPE, mime.to_string()),
+            (header::CONTENT_LENGTH, length.to_string()),
+            (header::ACCEPT_RANGES, "bytes".to_string()),
+        ],
+        body,
+    )
+        .into_response();
+    let range = range.map(|r| format!("bytes {}-{}/{}", r.start, r.end - 1, info.size));
+    let more = [
+        (header::CONTENT_RANGE, range),
+        (header::ETAG, etag),
+        (header::LAST_MODIFIED, last_modified),
+        // lets the client check the transfer
+        (header::HeaderName::from_static("x-checksum-sha256"), sha256),
+    ];
+    for (name, value) in more {
+        if let Some(Ok(value)) = value.map(|v| header::HeaderValue::from_str(&v)) {
+            res.headers_mut().insert(name, value);
+        }
+    }
+    res
+}
+
+/// a time as HTTP headers have it
+fn http_date(time: chrono::DateTime<chrono::Local>) -> String {
+    time.with_timezone(&chrono::Utc)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+/// the checksum of an object, from its manifest in the storage
+async fn object_sha256(storage: &dyn StorageBackend, name: &str) -> Option<String> {
+    let mut reader = storage
+        .get(&format!("{}.sha256.json", name), None)
+        .await
+        .ok()?;
+    let mut json = vec![];
+    tokio::io::AsyncReadExt::read_to_end(&mut reader, &mut json)
+        .await
+        .ok()?;
+    let (manifest, _): (checksums::Manifest, bool) = schema::from_slice(&json).ok()?;
+    Some(manifest.sha256)
+}
+
+pub async fn handle_download(
+    Extension(state): Extension<Arc<Recorder>>,
+    Path(name): Path<String>,
+    Query(query): Query<DownloadQuery>,
+    method: Method,
+    headers: HeaderMap,
+) -> Response {
+    let dir = match recordings::output_dir() {
+        Ok(dir) => dir,
+        Err(e) => return ApiError::internal(e).into_response(),
+    };
+    let path = match recordings::resolve(&dir, &name) {
+        Ok(path) => path,
+        Err(e) => return ApiError::validation(e).into_response(),
+    };
+    let current = state.lock().await.clone();
+    let file = path.to_string_lossy().to_string();
+    if !recordings::being_written(&current).contains(&file.as_str()) {
+        return serve_object(&*state.storage, &name, &method, &headers).await;
+    }
+
+    let RecordingState::Compressing { input, .. } = &current else {
+        return ApiError::conflict("recording is still being written").into_response();
+    };
+    if !query.progressive {
+        return ApiError::conflict("recording is being compressed, use ?progressive=true")
+            .into_response();
+    }
+    if !recordings::is_fragmented_mp4(&path).await {
+        // the compressed output can't be read before it's finished: send the raw master
+        let mut res = serve_file(std::path::Path::new(input), &headers).await;
+        res.headers_mut().insert(
+            PROGRESSIVE_SOURCE,
+            header::HeaderValue::from_static("raw-master"),
+        );
+        return res;
+    }
+
+    let mut res = if headers.contains_key(header::RANGE) {
+        // ranges are served from what is written so far, 416 past its end
+        serve_file(&path, &headers).await
+    } else {
+        let body = StreamBody::new(recordings::tail(state.clone(), path, 0));
+        ([(header::CONTENT_TYPE, "video/mp4")], body).into_response()
+    };
+    res.headers_mut().insert(
+        PROGRESSIVE_SOURCE,
+        header::HeaderValue::from_static("compressed-tail"),
+    );
+    res
+}
+
+#[derive(Serialize)]
+pub struct Deleted {
+    /// the recording, then its sidecars
+    pub deleted: Vec<String>,
+}
+
+/// remove a finished recording from the storage, with its sidecars
+pub async fn handle_delete_recording(
+    Extension(state): Extension<Arc<Recorder>>,
+    Path(name): Path<String>,
+) -> Response {
+    let dir = match recordings::output_dir() {
+        Ok(dir) => dir,
+        Err(e) => return ApiError::internal(e).into_response(),
+    };
+    let path = match recordings::resolve(&dir, &name) {
+        Ok(path) => path,
+        Err(e) => return ApiError::validation(e).into_response(),
+    };
+    let file = path.to_string_lossy().to_string();
+    if recordings::in_use(&*state.lock().await).contains(&file.as_str()) {
+        return ApiError::conflict("recording is being recorded or compressed").into_response();
+    }
+    let objects = match state.storage.list().await {
+        Ok(objects) => objects,
+        Err(e) => return ApiError::internal(e).into_response(),
+    };
+    let Some(size) = objects
+        .iter()
+        .find(|object| object.name == name)
+        .map(|object| object.size)
+    else {
+        return ApiError::not_found("no such recording").into_response();
+    };
+    // the manifest, the transcript, the frames: the objects named after it
+    let prefix = format!("{}.", name);
+    let sidecars = objects
+        .iter()
+        .map(|object| object.name.as_str())
+        .filter(|object| object.starts_with(&prefix));
+    let mut deleted = vec![];
+    for object in std::iter::once(name.as_str()).chain(sidecars) {
+        match state.storage.delete(object).await {
+            Ok(()) if object == name => {
+                let owner = state.history.owner_of(&file);
+                state.quotas.remove(owner.as_deref(), size);
+                deleted.push(object.to_string());
+            }
+            Ok(()) => deleted.push(object.to_string()),
+            Err(e) => {
+                return ApiError::internal(format!("cannot delete {}: {}", object, e))
+                    .with("deleted", deleted)
+                    .into_response()
+            }
+        }
+    }
+    info!("deleted {}", deleted.join(", "));
+    Json(Deleted { deleted }).into_response()
+}
+
+#[derive(Serialize)]
+pub struct Imported {
+    pub name: String,
+    pub size: u64,
+}
+
+/// receive a recording made elsewhere, written as it comes up to [BodyLimits::import]
+pub async fn handle_import(
+    Extension(state): Extension<Arc<Recorder>>,
+    Path(name): Path<String>,
+    body: BodyStream,
+) -> Response {
+    let dir = match recordings::output_dir() {
+        Ok(dir) => dir,
+        Err(e) => return ApiError::internal(e).into_response(),
+    };
+    let path = match recordings::resolve(&dir, &name) {
+        Ok(path) if recordings::is_recording(&name) => path,
+        Ok(_) => return ApiError::validation("not the name of a recording").into_response(),
+        Err(e) => return ApiError::validation(e).into_response(),
+    };
+    if path.exists() {
+        return ApiError::conflict("a recording has this name").into_response();
+    }
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".part.import");
+    let partial = std::path::PathBuf::from(partial);
+    match receive(&partial, body).await {
+        Ok(size) => match tokio::fs::rename(&partial, &path).await {
+            Ok(()) => {
+                info!("imported {} ({} bytes)", name, size);
+                state.quotas.add(None, size);
+                (StatusCode::CREATED, Json(Imported { name, size })).into_response()
+            }
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&partial).await;
+                ApiError::internal(format!("cannot import {}: {}", name, e)).into_response()
+            }
+        },
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&partial).await;
+            e.into_response()
+        }
+    }
+}
+
+/// write the body to `path` chunk by chunk, the size written
+async fn receive(path: &std::path::Path, mut body: BodyStream) -> Result<u64, ApiError> {
+    use tokio::io::AsyncWriteExt;
+    let mut file = tokio::fs::File::create(path)
+        .await
+        .map_err(|e| ApiError::internal(format!("cannot create {}: {}", path.display(), e)))?;
+    let mut size = 0;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|e| {
+            let e = e.into_inner();
+            match e.is::<LengthLimitError>() {
+                true => ApiError::new(ProblemType::PayloadTooLarge, e),
+                false => ApiError::validation(format!("cannot read the body: {}", e)),
+            }
+        })?;
+        file.write_all(&chunk)
+            .await
+            .map_err(|e| ApiError::internal(format!("cannot write {}: {}", path.display(), e)))?;
+        size += chunk.len() as u64;
+    }
+    file.sync_all()
+        .await
+        .map_err(|e| ApiError::internal(format!("cannot write {}: {}", path.display(), e)))?;
+    Ok(size)
+}
+
+/// compare a recording against its checksum manifest
+pub async fn handle_verify(
+    Extension(state): Extension<Arc<Recorder>>,
+    Path(name): Path<String>,
+) -> Response {
+    let dir = match recordings::output_dir() {
+        Ok(dir) => dir,
+        Err(e) => return ApiError::internal(e).into_response(),
+    };
+    let path = match recordings::resolve(&dir, &name) {
+        Ok(path) => path,
+        Err(e) => return ApiError::validation(e).into_response(),
+    };
+    let file = path.to_string_lossy().to_string();
+    if recordings::being_written(&*state.lock().await).contains(&file.as_str()) {
+        return ApiError::conflict("recording is still being written").into_response();
+    }
+    if !path.is_file() {
+        return ApiError::not_found("no such recording").into_response();
+    }
+    let manifest = match checksums::read_manifest(&path) {
+        Ok(Some(manifest)) => manifest,
+        Ok(None) => return ApiError::not_found("no checksum manifest").into_response(),
+        Err(e) => return ApiError::internal(e).into_response(),
+    };
+    match tokio::task::spawn_blocking(move || checksums::verify(&path, &manifest)).await {
+        Ok(Ok(verification)) => Json(verification).into_response(),
+        Ok(Err(e)) => ApiError::internal(e).into_response(),
+        Err(e) => ApiError::internal(e).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PlayQuery {
+    #[serde(default)]
+    format: PlayFormat,
+}
+
+/// seconds a client polling a transcode should wait
+const PLAY_RETRY_AFTER: &str = "2";
+
+/// a copy of the recording the browser can play, transcoded on the first request
+pub async fn handle_play(
+    Extension(state): Extension<Arc<Recorder>>,
+    Path(name): Path<String>,
+    Query(query): Query<PlayQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let dir = match recordings::output_dir() {
+        Ok(dir) => dir,
+        Err(e) => return ApiError::internal(e).into_response(),
+    };
+    let path = match recordings::resolve(&dir, &name) {
+        Ok(path) => path,
+        Err(e) => return ApiError::validation(e).into_response(),
+    };
+    let file = path.to_string_lossy().to_string();
+    if recordings::being_written(&*state.lock().await).contains(&file.as_str()) {
+        return ApiError::conflict("recording is still being written").into_response();
+    }
+    if !path.is_file() {
+        return ApiError::not_found("no such recording").into_response();
+    }
+    match state.play.lookup(&state, &path, query.format) {
+        Lookup::Ready(copy) => serve_file(&copy, &headers).await,
+        Lookup::Pending(job) => (
+            StatusCode::ACCEPTED,
+            [(header::RETRY_AFTER, PLAY_RETRY_AFTER)],
+            Json(job),
+        )
+            .into_response(),
+        Lookup::Failed(job) => ApiError::internal(job.error.unwrap_or_default())
+            .with("job", job.id)
+            .into_response(),
+    }
+}
+
+/// What this machine can do
+#[derive(Serialize)]
+pub struct Capabilities {
+    /// the last capture path analysis, when there was one
+    pub capture_paths: Option<capture_paths::Analysis>,
+    /// the estimates of the latency of the first frame
+    pub latency: latency::Calibration,
+    /// the encoder profiles that passed their validation
+    pub encoder_profiles: Vec<ProfileStatus>,
+    /// the video encoders a recording may ask for, see [hwaccel]
+    pub encoders: Vec<hwaccel::EncoderStatus>,
+    /// whether the server is built with the WebRTC preview, see [webrtc_preview]
+    pub webrtc_preview: bool,
+}
+
+/// the display a screen recording captures, and its size
+pub async fn handle_display() -> Response {
+    match display::detect().await {
+        Ok(display) => Json(display).into_response(),
+        Err(e @ display::Error::Unreachable { .. }) => ApiError::new(ProblemType::Busy, e)
+            .with("display", display::name())
+            .into_response(),
+        Err(e) => ApiError::internal(e).into_response(),
+    }
+}
+
+/// the PulseAudio sources the audio can be captured from
+pub async fn handle_audio_sources() -> Response {
+    match pulse::list().await {
+        Ok(sources) => Json(sources).into_response(),
+        Err(e @ pulse::Error::Unreachable(_)) => {
+            ApiError::new(ProblemType::Busy, e).into_response()
+        }
+        Err(e) => ApiError::internal(e).into_response(),
+    }
+}
+
+pub async fn handle_capabilities(Extension(state): Extension<Arc<Recorder>>) -> impl IntoResponse {
+    Json(Capabilities {
+        capture_paths: capture_paths::load(),
+        latency: latency::load(),
+        encoder_profiles: state
+            .encoder_profiles
+            .list()
+            .into_iter()
+            .filter(|p| p.validation.as_ref().is_some_and(|v| v.ok))
+            .collect(),
+        encoders: hwaccel::detect().await.to_vec(),
+        webrtc_preview: webrtc_preview::AVAILABLE,
+    })
+}
+
+/// A profile to validate: one of the server by its name, or one given whole
+#[derive(Deserialize)]
+pub struct ValidateProfile {
+    name: Option<String>,
+    profile: Option<EncoderProfile>,
+    /// options of a recording, to see them merged with those of the profile
+    #[serde(default)]
+    encoder_options: std::collections::BTreeMap<String, String>,
+}
+
+/// The outcome of a validation, with the options a recording would be compressed with
+#[derive(Serialize)]
+pub struct ProfileValidation {
+    validation: encoder_profiles::Validation,
+    /// the output options of ffmpeg, once merged
+    arguments: Vec<String>,
+}
+
+/// encode a second of the test source with a profile, see [encoder_profiles]
+pub async fn handle_validate_profile(
+    Extension(state): Extension<Arc<Recorder>>,
+    Json(req): Json<ValidateProfile>,
+) -> Response {
+    let profile = match (req.name, req.profile) {
+        (Some(name), None) => match state.encoder_profiles.get(&name) {
+            Some(profile) => profile.clone(),
+            None => {
+                return ApiError::not_found("not an encoder profile of the server")
+                    .with("name", name)
+                    .into_response()
+            }
+        },
+        (None, Some(profile)) => profile,
+        _ => {
+            return ApiError::validation("invalid validation")
+                .with_field("name", "give either the name of a profile or a profile")
+                .into_response()
+        }
+    };
+    let parameters = match profile.merged(&req.encoder_options) {
+        Ok(parameters) => parameters,
+        Err(e) => {
+            return ApiError::validation("invalid encoder options")
+                .with_field("encoder_options", e)
+                .into_response()
+        }
+    };
+    let validation = state.encoder_profiles.validate(&profile, true).await;
+    if let Some(error) = &validation.error {
+        return ApiError::validation("the encoder profile does not encode")
+            .with_field("profile", error)
+            .with("name", &profile.name)
+            .into_response();
+    }
+    let mut output = File::new("-");
+    for parameter in &parameters {
+        output = output.option(parameter.as_parameter());
+    }
+    let arguments = match FfmpegBuilder::new().output(output).to_command() {
+        // without the program and the output
+        Ok(command) => {
+            let argv = crate::ffmpeg::argv(&command);
+            argv[1..argv.len() - 1].to_vec()
+        }
+        Err(e) => return ApiError::internal(e).into_response(),
+    };
+    Json(ProfileValidation {
+        validation,
+        arguments,
+    })
+    .into_response()
+}
+
+/// the options a start accepts, for the clients building their form
+pub async fn handle_options_schema(
+    Extension(state): Extension<Arc<Recorder>>,
+) -> impl IntoResponse {
+    let available = options_schema::availability(&state).await;
+    Json(options_schema::schema(&available))
+}
+
+/// The quotas and the usage of the owners
+#[derive(Serialize)]
+pub struct QuotaReport {
+    /// bytes reserved for a recording in progress, at least
+    pub reservation: u64,
+    pub owners: Vec<quota::Usage>,
+}
+
+pub async fn handle_quota(Extension(state): Extension<Arc<Recorder>>) -> impl IntoResponse {
+    let in_progress = quota::in_progress(&state).await;
+    Json(QuotaReport {
+        reservation: quota::RESERVATION,
+        owners: state.quotas.report(&in_progress),
+    })
+}
+
+/// whether recording is allowed now, and until when
+pub async fn handle_policy(Extension(state): Extension<Arc<Recorder>>) -> impl IntoResponse {
+    Json(state.policy.status(chrono::Local::now()))
+}
+
+/// the liveness report, or a short one while a recording is running
+async fn liveness_report(state: &Recorder) -> Report {
+    let current = state.lock().await.clone();
+    if let RecordingState::Paused { .. } = current {
+        return Report {
+            live: true,
+            recording: true,
+            checks: vec![],
+        };
+    }
+    if let RecordingState::Started { process_id, .. } = current {
+        let pid = nix::unistd::Pid::from_raw(process_id as i32);
+        if nix::sys::signal::kill(pid, None).is_ok() {
+            return Report {
+                live: true,
+                recording: true,
+                checks: vec![],
+            };
+        }
+    }
+    state.liveness.report().await
+}
+
+/// whether recordings can be started: 200 when they can, 503 with the failed checks otherwise
+pub async fn handle_liveness(Extension(state): Extension<Arc<Recorder>>) -> Response {
+    let report = liveness_report(&state).await;
+    let status = if report.live {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report)).into_response()
+}
+
+/// the canaries and their last runs, see [crate::canary]
+pub async fn handle_canary(Extension(state): Extension<Arc<Recorder>>) -> Response {
+    Json(state.canaries.status()).into_response()
+}
+
+/// run a canary now, answering once it runs
+pub async fn handle_run_canary(
+    Extension(state): Extension<Arc<Recorder>>,
+    Extension(identity): Extension<Identity>,
+) -> Response {
+    if state.canaries.status().running {
+        return ApiError::conflict(canary::Error::Running).into_response();
+    }
+    if !canary::idle(&*state.lock().await) {
+        return ApiError::conflict(canary::Error::Busy).into_response();
+    }
+    info!("canary requested by {}", identity);
+    tokio::spawn(async move {
+        if let Err(e) = canary::run(state).await {
+            warn!("cannot run the canary: {}", e);
+        }
+    });
+    Json("STARTED").into_response()
+}
+
+/// the liveness checks and the GPU utilization as Prometheus gauges
+pub async fn handle_metrics(Extension(state): Extension<Arc<Recorder>>) -> Response {
+    let report = liveness_report(&state).await;
+    let mut metrics = liveness::metrics(&report);
+    if let Some(usage) = &*state.gpu.lock().unwrap() {
+        metrics += &gpu::metrics(usage);
+    }
+    metrics += &logging::metrics();
+    metrics += &otel::metrics();
+    metrics += &state.tokens.metrics();
+    metrics += &state.throttle.metrics();
+    metrics += &state.health.metrics();
+    metrics += &state.canaries.metrics();
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics,
+    )
+        .into_response()
+}
+
+/// the URL the clients reach the server at, without a trailing slash
+fn base_url(state: &Recorder, headers: &HeaderMap) -> String {
+    if let Some(url) = &state.external_url {
+        return url.trim_end_matches('/').to_string();
+    }
+    let host = headers
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("localhost");
+    format!("http://{}", host)
+}
+
+#[derive(Deserialize)]
+pub struct FeedQuery {
+    /// signed with the feed secret, for the apps that can't send a bearer token
+    token: Option<String>,
+    limit: Option<usize>,
+}
+
+/// a response refusing the feed, unless it is open, signed or asked for with an API token
+fn refuse_feed(state: &Recorder, identity: &Identity, query: &FeedQuery) -> Option<Response> {
+    if identity.role.is_some() {
+        return None;
+    }
+    let Some(secret) = &state.feed_secret else {
+        // as open as the history
+        if state.tokens.open(Role::Viewer) {
+            return None;
+        }
+        return Some(
+            ApiError::new(ProblemType::Unauthorized, "a viewer token is needed").into_response(),
+        );
+    };
+    if let Some(token) = &query.token {
+        if feed::verify(secret, token) {
+            return None;
+        }
+        return Some(
+            ApiError::new(ProblemType::Unauthorized, "invalid feed token").into_response(),
+        );
+    }
+    Some(ApiError::new(ProblemType::Unauthorized, "a feed token is needed").into_response())
+}
+
+/// the items of a feed served at `path`, with the absolute URLs of the server and the feed
+async fn feed_of(
+    state: &Recorder,
+    identity: &Identity,
+    headers: &HeaderMap,
+    query: &FeedQuery,
+    path: &str,
+) -> Result<(String, String, Vec<feed::Item>), Response> {
+    if let Some(res) = refuse_feed(state, identity, query) {
+        return Err(res);
+    }
+    let base = base_url(state, headers);
+    let mut feed_url = format!("{}{}", base, path);
+    if let Some(token) = &query.token {
+        feed_url += &format!("?token={}", token);
+    }
+    let limit = query
+        .limit
+        .unwrap_or(feed::DEFAULT_ITEMS)
+        .clamp(1, history::MAX_LIMIT);
+    match feed::items(&state.history, &*state.storage, &base, limit).await {
+        Ok(items) => Ok((base, feed_url, items)),
+        Err(e) => Err(ApiError::internal(e).into_response()),
+    }
+}
+
+/// the finished recordings as a JSON Feed
+pub async fn handle_feed_json(
+    Extension(state): Extension<Arc<Recorder>>,
+    Extension(identity): Extension<Identity>,
+    Query(query): Query<FeedQuery>,
+    headers: HeaderMap,
+) -> Response {
+    match feed_of(&state, &identity, &headers, &query, "/api/feed.json").await {
+        Ok((base, feed_url, items)) => (
+            [(header::CONTENT_TYPE, "application/feed+json")],
+            Json(feed::json_feed(&base, feed_url, items)),
+        )
+            .into_response(),
+        Err(res) => res,
+    }
+}
+
+/// the finished recordings as an RSS feed with enclosures
+pub async fn handle_feed_xml(
+    Extension(state): Extension<Arc<Recorder>>,
+    Extension(identity): Extension<Identity>,
+    Query(query): Query<FeedQuery>,
+    headers: HeaderMap,
+) -> Response {
+    match feed_of(&state, &identity, &headers, &query, "/api/feed.xml").await {
+        Ok((base, feed_url, items)) => (
+            [(header::CONTENT_TYPE, "application/rss+xml; charset=utf-8")],
+            feed::rss(&base, &feed_url, &items),
+        )
+            .into_response(),
+        Err(res) => res,
+    }
+}
+
+/// The token of the feeds and where to subscribe with it
+#[derive(Serialize)]
+pub struct FeedToken {
+    pub token: String,
+    pub json: String,
+    pub xml: String,
+}
+
+/// the signed feed token, for the admin to hand out
+pub async fn handle_feed_token(
+    Extension(state): Extension<Arc<Recorder>>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(secret) = &state.feed_secret else {
+        return ApiError::conflict("no feed secret is configured, the feeds are open")
+            .into_response();
+    };
+    let token = feed::token(secret);
+    let base = base_url(&state, &headers);
+    Json(FeedToken {
+        json: format!("{}/api/feed.json?token={}", base, token),
+        xml: format!("{}/api/feed.xml?token={}", base, token),
+        token,
+    })
+    .into_response()
+}
+
+/// kill every child process now, keeping the partial files
+pub async fn handle_emergency_stop(
+    Extension(state): Extension<Arc<Recorder>>,
+    Extension(identity): Extension<Identity>,
+) -> Response {
+    warn!("emergency stop requested by {}", identity);
+    Json(emergency_stop(state, Some(identity)).await).into_response()
+}
+
+/// throw the running recording away, see [cancel]
+pub async fn handle_cancel(
+    Extension(state): Extension<Arc<Recorder>>,
+    Extension(identity): Extension<Identity>,
+) -> Response {
+    info!("cancel requested by {}", identity);
+    match cancel(state, Some(identity)).await {
+        Ok(next) => Json(next.without_command()).into_response(),
+        Err(e) => ApiError::conflict(e).into_response(),
+    }
+}
+
+/// exit leaving the capture to the next server, see [crate::handoff]
+pub async fn handle_handoff(
+    Extension(state): Extension<Arc<Recorder>>,
+    Extension(identity): Extension<Identity>,
+) -> Response {
+    warn!("handoff requested by {}", identity);
+    match crate::handoff::hand_over(state).await {
+        Ok(handoff) => (StatusCode::ACCEPTED, Json(handoff)).into_response(),
+        Err(e) => ApiError::conflict(e).into_response(),
+    }
+}
+
+/// post a notification of the current state to the webhook, answering what it answered
+pub async fn handle_webhook_test(Extension(state): Extension<Arc<Recorder>>) -> Response {
+    let Some(webhook) = &state.webhook else {
+        return ApiError::not_found("no webhook is configured").into_response();
+    };
+    let current = state.lock().await.clone();
+    match webhook.test(&current).await {
+        Ok(status) => Json(serde_json::json!({ "status": status })).into_response(),
+        Err(e) => ApiError::new(ProblemType::BadGateway, e).into_response(),
+    }
+}
+
+/// finished recordings, newest first
+pub async fn handle_history(
+    Extension(state): Extension<Arc<Recorder>>,
+    Query(query): Query<PageQuery>,
+) -> Response {
+    match state.history.page(&query) {
+        Ok(page) => {
+            let mut res = Json(&page).into_response();
+            if let Some(total) = page.total {
+                res.headers_mut()
+                    .insert(TOTAL_COUNT, header::HeaderValue::from(total));
+            }
+            res
+        }
+        Err(e) => ApiError::validation(e).into_response(),
+    }
+}
+
+/// the compressions, newest first, paged as the history
+pub async fn handle_jobs(
+    Extension(state): Extension<Arc<Recorder>>,
+    Query(query): Query<PageQuery>,
+) -> Response {
+    match state.jobs.page(&query) {
+        Ok(page) => {
+            let mut res = Json(&page).into_response();
+            if let Some(total) = page.total {
+                res.headers_mut()
+                    .insert(TOTAL_COUNT, header::HeaderValue::from(total));
+            }
+            res
+        }
+        Err(e) => ApiError::validation(e).into_response(),
+    }
+}
+
+/// extract still frames of a finished recording
+pub async fn handle_frames(
+    Extension(state): Extension<Arc<Recorder>>,
+    Path(name): Path<String>,
+    Json(req): Json<FramesRequest>,
+) -> Response {
+    let dir = match recordings::output_dir() {
+        Ok(dir) => dir,
+        Err(e) => return ApiError::internal(e).into_response(),
+    };
+    let path = match recordings::resolve(&dir, &name) {
+        Ok(path) => path,
+        Err(e) => return ApiError::validation(e).into_response(),
+    };
+    if !path.is_file() {
+        return ApiError::not_found("no such recording").into_response();
+    }
+    let file = path.to_string_lossy().to_string();
+    if recordings::being_written(&*state.lock().await).contains(&file.as_str()) {
+        return ApiError::conflict("recording is still being written").into_response();
+    }
+    match frames::extract(&path, &req, &state.children).await {
+        Ok(frames) => Json(frames).into_response(),
+        Err(frames::Error::InvalidPositions(fields)) => fields
+            .into_iter()
+            .fold(ApiError::validation("invalid positions"), |e, f| {
+                e.with_field(f.field, f.message)
+            })
+            .into_response(),
+        Err(e) => ApiError::internal(e).into_response(),
+    }
+}
+
+/// the finished recording of the name, or the response saying why there is none
+async fn finished_recording(state: &Recorder, name: &str) -> Result<std::path::PathBuf, Response> {
+    let dir = recordings::output_dir().map_err(|e| ApiError::internal(e).into_response())?;
+    let path =
+        recordings::resolve(&dir, name).map_err(|e| ApiError::validation(e).into_response())?;
+    if !path.is_file() {
+        return Err(ApiError::not_found("no such recording").into_response());
+    }
+    let file = path.to_string_lossy().to_string();
+    if recordings::being_written(&*state.lock().await).contains(&file.as_str()) {
+        return Err(ApiError::conflict("recording is still being written").into_response());
+    }
+    Ok(path)
+}
+
+/// the preview image of a finished recording, made when it is first asked for, see [thumbnail]
+pub async fn handle_thumbnail(
+    Extension(state): Extension<Arc<Recorder>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let path = match finished_recording(&state, &name).await {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    match state.thumbnails.get(&state, &path).await {
+        Ok(thumbnail) => serve_file(&thumbnail, &headers).await,
+        Err(e @ thumbnail::Error::Unreadable(_)) => {
+            ApiError::new(ProblemType::BadGateway, e).into_response()
+        }
+        Err(e) => ApiError::internal(e).into_response(),
+    }
+}
+
+/// how often to ask again for a contact sheet being made, in seconds
+const SHEET_RETRY_AFTER: &str = "2";
+
+/// make the contact sheet of a finished recording, see [contact_sheet]
+pub async fn handle_contact_sheet(
+    Extension(state): Extension<Arc<Recorder>>,
+    Path(name): Path<String>,
+    Json(req): Json<SheetRequest>,
+) -> Response {
+    if let Some((field, message)) = req.invalid() {
+        return ApiError::validation("invalid contact sheet")
+            .with_field(field, message)
+            .into_response();
+    }
+    let path = match finished_recording(&state, &name).await {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    let job = state.sheets.start(&state, &path, req);
+    (
+        StatusCode::ACCEPTED,
+        [(header::RETRY_AFTER, SHEET_RETRY_AFTER)],
+        Json(job),
+    )
+        .into_response()
+}
+
+/// the contact sheet of a recording, once it is made
+pub async fn handle_get_contact_sheet(
+    Extension(state): Extension<Arc<Recorder>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let path = match finished_recording(&state, &name).await {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    match state.sheets.lookup(&path) {
+        contact_sheet::Lookup::Ready(sheet) => serve_file(&sheet, &headers).await,
+        contact_sheet::Lookup::Pending(job) => (
+            StatusCode::ACCEPTED,
+            [(header::RETRY_AFTER, SHEET_RETRY_AFTER)],
+            Json(job),
+        )
+            .into_response(),
+        contact_sheet::Lookup::Failed(job) => ApiError::internal(job.error.unwrap_or_default())
+            .with("job", job.id)
+            .into_response(),
+        contact_sheet::Lookup::Missing => {
+            ApiError::not_found("no contact sheet, POST to make it").into_response()
+        }
+    }
+}
+
+pub async fn handle_sheet_jobs(Extension(state): Extension<Arc<Recorder>>) -> impl IntoResponse {
+    Json(state.sheets.list())
+}
+
+/// draw the pointer tracked apart over a finished recording, see [cursor]
+pub async fn handle_cursor_render(
+    Extension(state): Extension<Arc<Recorder>>,
+    Path(name): Path<String>,
+) -> Response {
+    let path = match finished_recording(&state, &name).await {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    if !cursor::track_of(&path).is_file() {
+        return ApiError::not_found("the recording was made without cursor_track").into_response();
+    }
+    let job = state.cursor_renders.start(&state, &path);
+    (
+        StatusCode::ACCEPTED,
+        [(header::RETRY_AFTER, SHEET_RETRY_AFTER)],
+        Json(job),
+    )
+        .into_response()
+}
+
+/// the recording with its pointer drawn, once it is rendered
+pub async fn handle_get_cursor_render(
+    Extension(state): Extension<Arc<Recorder>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let path = match finished_recording(&state, &name).await {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    match state.cursor_renders.lookup(&path) {
+        cursor::Lookup::Ready(render) => serve_file(&render, &headers).await,
+        cursor::Lookup::Pending(job) => (
+            StatusCode::ACCEPTED,
+            [(header::RETRY_AFTER, SHEET_RETRY_AFTER)],
+            Json(job),
+        )
+            .into_response(),
+        cursor::Lookup::Failed(job) => ApiError::internal(job.error.unwrap_or_default())
+            .with("job", job.id)
+            .into_response(),
+        cursor::Lookup::Missing => {
+            ApiError::not_found("the pointer is not drawn, POST to draw it").into_response()
+        }
+        cursor::Lookup::Untracked => {
+            ApiError::not_found("the recording was made without cursor_track").into_response()
+        }
+    }
+}
+
+pub async fn handle_cursor_jobs(Extension(state): Extension<Arc<Recorder>>) -> impl IntoResponse {
+    Json(state.cursor_renders.list())
+}
+
+/// make an animated GIF of a finished recording, see [gif]
+pub async fn handle_gif(
+    Extension(state): Extension<Arc<Recorder>>,
+    Path(name): Path<String>,
+    Json(req): Json<GifRequest>,
+) -> Response {
+    if let Some((field, message)) = req.invalid() {
+        return ApiError::validation("invalid gif")
+            .with_field(field, message)
+            .into_response();
+    }
+    let path = match finished_recording(&state, &name).await {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    match state.gifs.start(&state, &path, req) {
+        Ok(job) => (
+            StatusCode::ACCEPTED,
+            [(header::RETRY_AFTER, SHEET_RETRY_AFTER)],
+            Json(job),
+        )
+            .into_response(),
+        Err(e) => {
+            let gif::Error::Converting(job) = &e;
+            ApiError::conflict(&e).with("job", job.id).into_response()
+        }
+    }
+}
+
+/// the GIF of a recording once it is made, or how far its conversion is
+pub async fn handle_get_gif(
+    Extension(state): Extension<Arc<Recorder>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let path = match finished_recording(&state, &name).await {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    match state.gifs.lookup(&path) {
+        gif::Lookup::Ready(gif) => serve_file(&gif, &headers).await,
+        gif::Lookup::Pending(job) => (
+            StatusCode::ACCEPTED,
+            [(header::RETRY_AFTER, SHEET_RETRY_AFTER)],
+            Json(job),
+        )
+            .into_response(),
+        gif::Lookup::Failed(job) => ApiError::internal(job.error.unwrap_or_default())
+            .with("job", job.id)
+            .into_response(),
+        gif::Lookup::Missing => ApiError::not_found("no gif, POST to make it").into_response(),
+    }
+}
+
+pub async fn handle_gif_jobs(Extension(state): Extension<Arc<Recorder>>) -> impl IntoResponse {
+    Json(state.gifs.list())
 }
 
 use std::net::SocketAddr;
 
-pub async fn run(socket_addr: SocketAddr) -> anyhow::Result<()> {
-    let shared_state = Arc::new(Mutex::new(RecordingState::Waiting));
+/// Largest request bodies accepted, by route group
+#[derive(Debug, Clone, Copy)]
+pub struct BodyLimits {
+    /// start, stop and the other small control requests
+    pub control: usize,
+    /// endpoints receiving recordings, their bodies are streamed
+    pub import: usize,
+    /// everything else
+    pub default: usize,
+}
+
+impl Default for BodyLimits {
+    fn default() -> Self {
+        Self {
+            control: 64 * 1024,
+            import: 10 * 1024 * 1024 * 1024,
+            default: 1024 * 1024,
+        }
+    }
+}
+
+/// The role every route needs, by method and path; a route that is not here needs the admin, one
+/// with None checks the request itself
+pub const ACCESS: &[Access] = &[
+    ("GET", "/api/status", Some(Role::Viewer)),
+    ("GET", "/api/events", Some(Role::Viewer)),
+    ("GET", "/api/progress", Some(Role::Viewer)),
+    ("GET", "/api/history", Some(Role::Viewer)),
+    ("GET", "/api/jobs", Some(Role::Viewer)),
+    ("GET", "/api/liveness", Some(Role::Viewer)),
+    ("GET", "/api/policy", Some(Role::Viewer)),
+    ("GET", "/api/quota", Some(Role::Viewer)),
+    ("GET", "/api/capabilities", Some(Role::Viewer)),
+    ("GET", "/api/display", Some(Role::Viewer)),
+    ("GET", "/api/audio-sources", Some(Role::Viewer)),
+    ("GET", "/api/canary", Some(Role::Viewer)),
+    ("GET", "/metrics", Some(Role::Viewer)),
+    ("GET", "/api/options-schema", Some(Role::Viewer)),
+    ("GET", "/api/preview/:file", Some(Role::Viewer)),
+    // the page asks for the token its offer is posted with
+    ("GET", "/api/preview/webrtc", None),
+    ("POST", "/api/preview/webrtc", Some(Role::Viewer)),
+    ("GET", "/api/recordings", Some(Role::Viewer)),
+    ("GET", "/api/recordings/:name", Some(Role::Viewer)),
+    ("GET", "/api/recordings/:name/download", Some(Role::Viewer)),
+    ("GET", "/api/recordings/:name/play", Some(Role::Viewer)),
+    ("GET", "/api/recordings/:name/thumbnail", Some(Role::Viewer)),
+    (
+        "GET",
+        "/api/recordings/:name/contact-sheet",
+        Some(Role::Viewer),
+    ),
+    ("GET", "/api/jobs/contact-sheets", Some(Role::Viewer)),
+    ("GET", "/api/recordings/:name/cursor", Some(Role::Viewer)),
+    ("GET", "/api/jobs/cursor-renders", Some(Role::Viewer)),
+    ("GET", "/api/recordings/:name/gif", Some(Role::Viewer)),
+    ("GET", "/api/jobs/gifs", Some(Role::Viewer)),
+    // signed with the feed secret, for the podcast apps
+    ("GET", "/api/feed.json", None),
+    ("GET", "/api/feed.xml", None),
+    ("POST", "/api/start", Some(Role::Operator)),
+    ("POST", "/api/stop", Some(Role::Operator)),
+    ("POST", "/api/record", Some(Role::Operator)),
+    ("PUT", "/api/quality", Some(Role::Operator)),
+    ("POST", "/api/pause", Some(Role::Operator)),
+    ("POST", "/api/resume", Some(Role::Operator)),
+    ("POST", "/api/cancel", Some(Role::Operator)),
+    ("DELETE", "/api/recordings/:name", Some(Role::Operator)),
+    ("PUT", "/api/recordings/:name", Some(Role::Operator)),
+    ("POST", "/api/recordings/:name/frames", Some(Role::Operator)),
+    ("POST", "/api/recordings/:name/verify", Some(Role::Operator)),
+    (
+        "POST",
+        "/api/recordings/:name/contact-sheet",
+        Some(Role::Operator),
+    ),
+    ("POST", "/api/recordings/:name/cursor", Some(Role::Operator)),
+    ("POST", "/api/recordings/:name/gif", Some(Role::Operator)),
+    (
+        "POST",
+        "/api/encoder-profiles/validate",
+        Some(Role::Operator),
+    ),
+    ("POST", "/api/canary", Some(Role::Operator)),
+    ("POST", "/api/emergency-stop", Some(Role::Admin)),
+    ("POST", "/api/handoff", Some(Role::Admin)),
+    ("POST", "/api/webhook/test", Some(Role::Admin)),
+    ("GET", "/api/feed/token", Some(Role::Admin)),
+];
+
+/// the application router over the given shared state
+pub fn build_router(shared_state: Arc<Recorder>, limits: BodyLimits) -> Router {
     let cors = CorsLayer::new()
         .allow_origin(Any)
         .allow_methods(Any)
         .allow_headers(Any);
-    let app = Router::new()
+    let control = Router::new()
         .route("/api/start", post(handle_start))
         .route("/api/stop", post(handle_stop))
+        .route("/api/record", post(handle_record))
+        .route("/api/quality", put(handle_quality))
+        .route("/api/pause", post(handle_pause))
+        .route("/api/resume", post(handle_resume))
+        .route("/api/cancel", post(handle_cancel))
+        .route("/api/emergency-stop", post(handle_emergency_stop))
+        .route("/api/handoff", post(handle_handoff))
+        .route("/api/webhook/test", post(handle_webhook_test))
+        .layer(RequestBodyLimitLayer::new(limits.control));
+    let import = Router::new()
+        .route("/api/recordings/:name", put(handle_import))
+        .layer(RequestBodyLimitLayer::new(limits.import));
+    let other = Router::new()
         .route("/api/status", get(handle_status))
+        .route("/api/events", get(handle_events))
+        .route("/api/progress", get(handle_progress))
+        .route("/api/history", get(handle_history))
+        .route("/api/jobs", get(handle_jobs))
+        .route("/api/feed.json", get(handle_feed_json))
+        .route("/api/feed.xml", get(handle_feed_xml))
+        .route("/api/feed/token", get(handle_feed_token))
+        .route("/api/liveness", get(handle_liveness))
+        .route("/api/policy", get(handle_policy))
+        .route("/api/quota", get(handle_quota))
+        .route("/api/capabilities", get(handle_capabilities))
+        .route("/api/display", get(handle_display))
+        .route("/api/audio-sources", get(handle_audio_sources))
+        .route("/api/canary", get(handle_canary).post(handle_run_canary))
+        .route("/api/options-schema", get(handle_options_schema))
+        .route(
+            "/api/preview/webrtc",
+            get(handle_webrtc_page).post(handle_webrtc_offer),
+        )
+        .route("/api/preview/:file", get(handle_preview))
+        .route("/metrics", get(handle_metrics))
+        .route("/api/recordings", get(handle_recordings))
+        .route(
+            "/api/recordings/:name",
+            get(handle_download).delete(handle_delete_recording),
+        )
+        .route("/api/recordings/:name/download", get(handle_download))
+        .route("/api/recordings/:name/frames", post(handle_frames))
+        .route("/api/recordings/:name/verify", post(handle_verify))
+        .route("/api/recordings/:name/play", get(handle_play))
+        .route("/api/recordings/:name/thumbnail", get(handle_thumbnail))
+        .route(
+            "/api/recordings/:name/contact-sheet",
+            post(handle_contact_sheet).get(handle_get_contact_sheet),
+        )
+        .route("/api/jobs/contact-sheets", get(handle_sheet_jobs))
+        .route(
+            "/api/recordings/:name/cursor",
+            post(handle_cursor_render).get(handle_get_cursor_render),
+        )
+        .route("/api/jobs/cursor-renders", get(handle_cursor_jobs))
+        .route(
+            "/api/recordings/:name/gif",
+            post(handle_gif).get(handle_get_gif),
+        )
+        .route("/api/jobs/gifs", get(handle_gif_jobs))
+        .route(
+            "/api/encoder-profiles/validate",
+            post(handle_validate_profile),
+        )
+        .layer(RequestBodyLimitLayer::new(limits.default));
+    Router::new()
+        .merge(control)
+        .merge(import)
+        .merge(other)
+        // the limits above are the only ones
         .layer(DefaultBodyLimit::disable())
-        .layer(RequestBodyLimitLayer::new(1 * 1024 * 1024))
+        .fallback(problem::not_found)
+        .layer(axum::middleware::map_response(problem::rewrite_rejections))
+        .layer(axum::middleware::from_fn(idempotency::replay))
+        .layer(axum::middleware::from_fn(|req, next| {
+            auth::authorize(ACCESS, req, next)
+        }))
+        .layer(axum::middleware::from_fn(problem::request_id))
+        .layer(axum::middleware::from_fn(presence::track))
         .layer(Extension(shared_state))
         .layer(
             TraceLayer::new_for_http()
@@ -66,11 +2152,1281 @@ pub async fn run(socket_addr: SocketAddr) -> anyhow::Result<()> {
                 Html(contents)
             }),
         )
-        .layer(cors);
+        .layer(cors)
+}
+
+/// How the server is set up
+#[derive(Default)]
+pub struct ServerConfig {
+    /// options of the recordings started with SIGUSR1
+    pub defaults: RecordingOptions,
+    pub limits: BodyLimits,
+    pub liveness: liveness::Config,
+    /// a token of the admin role, named `admin`
+    pub admin_token: Option<String>,
+    /// a token of the admin role, named `token`, that every route wants one of the tokens
+    pub token: Option<String>,
+    /// the API tokens and their roles
+    pub tokens: Vec<ApiToken>,
+    pub policy: Policy,
+    /// disk quotas of the owners, `default` for those without one
+    pub quotas: Vec<OwnerQuota>,
+    /// the speech-to-text programs the recordings may ask for
+    pub transcribers: Vec<Transcriber>,
+    /// how long a transcriber may run
+    pub transcribe_timeout: std::time::Duration,
+    /// where the captures go on when the output directory can't be written any more
+    pub fallback_dir: Option<std::path::PathBuf>,
+    /// size of all the playable copies together, unlimited with 0
+    pub play_cache_bytes: u64,
+    /// the URL the clients reach the server at
+    pub external_url: Option<String>,
+    pub feed_secret: Option<String>,
+    /// how the content of the finished recordings is looked at
+    pub content_check: content_check::Config,
+    /// the longest recording of `/api/record`
+    pub max_record: Option<std::time::Duration>,
+    /// the name of the recordings
+    pub file_name: crate::template::Template,
+    /// how fast the background jobs may read
+    pub throttle: crate::throttle::Config,
+    /// how the recordings are scored
+    pub health: crate::health::Config,
+    /// the encoders the recordings may ask for
+    pub encoder_profiles: Vec<EncoderProfile>,
+    /// what is cut off the results when the stop tells nothing
+    pub trim: trim::Trim,
+    /// when and how the canaries run
+    pub canary: canary::Config,
+    /// how the server finishes its recording on SIGTERM
+    pub shutdown: shutdown::Config,
+    /// where the changes of state are posted
+    pub webhook_url: Option<crate::template::Template>,
+    /// where the finished recordings are uploaded
+    pub s3: Option<crate::s3::Config>,
+}
+
+/// how long the connections may take to end once the server shuts down
+const CONNECTIONS_GRACE: std::time::Duration = std::time::Duration::from_secs(2);
+
+pub async fn run(socket_addr: SocketAddr, config: ServerConfig) -> anyhow::Result<()> {
+    let ServerConfig {
+        defaults,
+        limits,
+        liveness,
+        admin_token,
+        token,
+        mut tokens,
+        policy,
+        quotas,
+        transcribers,
+        transcribe_timeout,
+        fallback_dir,
+        play_cache_bytes,
+        external_url,
+        feed_secret,
+        content_check,
+        max_record,
+        file_name,
+        throttle,
+        health,
+        encoder_profiles,
+        trim,
+        canary,
+        shutdown,
+        webhook_url,
+        s3,
+    } = config;
+    let encoder_profiles = encoder_profiles::Profiles::new(encoder_profiles);
+    encoder_profiles.validate_all().await?;
+    let (history, jobs, canaries, left, state_file) = match recordings::output_dir() {
+        Ok(dir) => (
+            History::open(&dir.join(".record-screen-history.jsonl"))?,
+            Journal::open(&dir.join(".record-screen-jobs.jsonl"))?,
+            Canaries::open(canary, Some(&dir.join(".record-screen-canaries.jsonl")))?,
+            recovery::take(&dir),
+            Some(StateFile::open(recovery::path(&dir))),
+        ),
+        Err(e) => {
+            warn!(
+                "history, jobs, canaries and the state are not persisted: {}",
+                e
+            );
+            (
+                History::default(),
+                Journal::default(),
+                Canaries::open(canary, None)?,
+                None,
+                None,
+            )
+        }
+    };
+    tokens.extend(admin_token.map(|token| ApiToken {
+        name: "admin".to_string(),
+        role: Role::Admin,
+        token,
+    }));
+    let required = token.is_some();
+    tokens.extend(token.map(|token| ApiToken {
+        name: "token".to_string(),
+        role: Role::Admin,
+        token,
+    }));
+    let quotas = Quotas::new(quotas);
+    if let Err(e) = quotas.tally(&history) {
+        warn!("cannot tally the usage of the owners: {}", e);
+    }
+    let shared_state = Arc::new(
+        Recorder::with_history(history)
+            .with_liveness(Liveness::new(liveness))
+            .with_tokens(Tokens::new(tokens).require(required))
+            .with_external_url(external_url)
+            .with_feed_secret(feed_secret)
+            .with_content_check(content_check)
+            .with_max_record(max_record)
+            .with_file_name(file_name)
+            .with_throttle(throttle)
+            .with_health(health)
+            .with_encoder_profiles(encoder_profiles)
+            .with_trim(trim)
+            .with_canaries(canaries)
+            .with_policy(policy)
+            .with_quotas(quotas)
+            .with_transcribers(Transcribers::new(transcribers, transcribe_timeout))
+            .with_fallback_dir(fallback_dir)
+            .with_jobs(jobs)
+            .with_play_cache(PlayCache::new(play_cache_bytes))
+            .with_state_file(state_file)
+            .with_webhook(webhook_url.map(Webhook::open).transpose()?)
+            .with_bucket(s3.map(Bucket::new).transpose()?),
+    );
+    tokio::spawn(crate::policy::watch(shared_state.clone()));
+    tokio::spawn(crate::throttle::watch(shared_state.clone()));
+    tokio::spawn(crate::health::watch(shared_state.clone()));
+    tokio::spawn(canary::watch(shared_state.clone()));
+    let adopted = crate::handoff::adopt(shared_state.clone()).await;
+    match left {
+        Some(left) if !adopted => recovery::recover(shared_state.clone(), left).await,
+        _ => {}
+    }
+    if let Some(state_file) = &shared_state.state_file {
+        state_file.save(&*shared_state.lock().await);
+    }
+    tokio::spawn(crate::jobs::restore(shared_state.clone()));
+    tokio::spawn({
+        let mx = shared_state.clone();
+        async move {
+            if let Err(e) = crate::signals::listen(mx, defaults).await {
+                warn!("cannot listen to signals: {}", e);
+            }
+        }
+    });
+    let stopped = CancellationToken::new();
+    tokio::spawn({
+        let (mx, stopped) = (shared_state.clone(), stopped.clone());
+        async move {
+            if let Err(e) = shutdown::requested().await {
+                warn!("cannot listen to SIGTERM: {}", e);
+                return;
+            }
+            shutdown::finish(mx, &shutdown).await;
+            stopped.cancel();
+        }
+    });
+    let app = build_router(shared_state, limits);
 
     info!("Server is listening on {}", socket_addr);
-    Server::bind(&socket_addr)
+    let server = Server::bind(&socket_addr)
         .serve(app.into_make_service_with_connect_info::<SocketAddr>())
-        .await?;
+        .with_graceful_shutdown({
+            let stopped = stopped.clone();
+            async move { stopped.cancelled().await }
+        });
+    // the event streams don't end by themselves
+    let drained = async {
+        stopped.cancelled().await;
+        tokio::time::sleep(CONNECTIONS_GRACE).await;
+    };
+    tokio::select! {
+        served = server => served?,
+        _ = drained => info!("connections left open at the shutdown are dropped"),
+    }
+    info!("Server is shut down");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::Progress;
+    use std::time::Duration;
+
+    fn token(name: &str, role: Role) -> ApiToken {
+        ApiToken {
+            name: name.to_string(),
+            role,
+            token: format!("{}-secret", name),
+        }
+    }
+
+    fn router(mx: Recorder) -> Router {
+        build_router(Arc::new(mx), BodyLimits::default())
+    }
+
+    /// the response of the router to `method` on `uri`, with the bearer `token` when given
+    async fn send(router: &Router, method: &str, uri: &str, token: Option<&str>) -> Response {
+        let mut req = Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            req = req.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        router
+            .clone()
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    /// whether the request got past the authorization, whatever the route answered then
+    async fn allowed(router: &Router, method: &str, uri: &str, token: Option<&str>) -> bool {
+        let status = send(router, method, uri, token).await.status();
+        status != StatusCode::UNAUTHORIZED && status != StatusCode::FORBIDDEN
+    }
+
+    fn output_dir() -> std::path::PathBuf {
+        recordings::test_output_dir()
+    }
+
+    async fn body(res: Response) -> Vec<u8> {
+        use axum::body::HttpBody;
+        let mut body = res.into_body();
+        let mut bytes = vec![];
+        while let Some(chunk) = body.data().await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+        bytes
+    }
+
+    async fn get(router: &Router, uri: &str, headers: &[(header::HeaderName, &str)]) -> Response {
+        let mut req = Request::builder().uri(uri);
+        for (name, value) in headers {
+            req = req.header(name, *value);
+        }
+        router
+            .clone()
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    /// a finished recording of `size` bytes, and what is in it
+    fn recording(name: &str, size: usize) -> Vec<u8> {
+        let content: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        std::fs::write(output_dir().join(name), &content).unwrap();
+        content
+    }
+
+    #[tokio::test]
+    async fn an_interrupted_download_is_resumed() {
+        let content = recording("resumed.mp4", 1000);
+        let router = router(Recorder::new());
+        let uri = "/api/recordings/resumed.mp4/download";
+        let full = get(&router, uri, &[]).await;
+        assert_eq!(full.status(), StatusCode::OK);
+        assert_eq!(full.headers()[header::ACCEPT_RANGES], "bytes");
+        let etag = full.headers()[header::ETAG].to_str().unwrap().to_string();
+        // the transfer broke after 400 bytes
+        let rest = get(
+            &router,
+            uri,
+            &[(header::RANGE, "bytes=400-"), (header::IF_RANGE, &etag)],
+        )
+        .await;
+        assert_eq!(rest.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(rest.headers()[header::CONTENT_RANGE], "bytes 400-999/1000");
+        assert_eq!(body(rest).await, content[400..]);
+    }
+
+    #[tokio::test]
+    async fn a_stale_if_range_gets_the_whole_recording() {
+        let content = recording("stale.mp4", 1000);
+        let router = router(Recorder::new());
+        let res = get(
+            &router,
+            "/api/recordings/stale.mp4/download",
+            &[
+                (header::RANGE, "bytes=400-"),
+                (header::IF_RANGE, "\"changed\""),
+            ],
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().get(header::CONTENT_RANGE).is_none());
+        assert_eq!(body(res).await, content);
+    }
+
+    #[tokio::test]
+    async fn a_range_past_the_end_is_not_satisfiable() {
+        recording("short.mp4", 1000);
+        let router = router(Recorder::new());
+        let uri = "/api/recordings/short.mp4/download";
+        for range in ["bytes=2000-", "bytes=0-10,20-30"] {
+            let res = get(&router, uri, &[(header::RANGE, range)]).await;
+            assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE, "{}", range);
+            assert_eq!(res.headers()[header::CONTENT_RANGE], "bytes */1000");
+            assert_eq!(
+                res.headers()[header::CONTENT_TYPE],
+                problem::CONTENT_TYPE,
+                "{}",
+                range
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn a_recording_being_compressed_is_read_as_it_grows() {
+        let path = output_dir().join("growing.mp4");
+        // a fragmented mp4 starts with its type and an empty index
+        let mut content = b"\0\0\0\x10ftypisom\0\0\x02\0\0\0\0\x08moov".to_vec();
+        std::fs::write(&path, &content).unwrap();
+        let mx = Arc::new(Recorder::new());
+        mx.set(RecordingState::Compressing {
+            process_id: 1,
+            input: output_dir()
+                .join("growing.raw.mp4")
+                .to_string_lossy()
+                .to_string(),
+            output: path.to_string_lossy().to_string(),
+            encoder: EncoderParams::resolve(ContentKind::default()),
+            gpu: None,
+            command: vec![],
+            health: None,
+            progress: None,
+            duration_ms: None,
+            percent: None,
+        })
+        .await;
+        let fragments: Vec<Vec<u8>> = (0..4u8)
+            .map(|i| {
+                let mut fragment = b"\0\0\x03\xe8moof".to_vec();
+                fragment.resize(1000, i);
+                fragment
+            })
+            .collect();
+        content.extend(fragments.concat());
+        let writer = tokio::spawn({
+            let (mx, path) = (mx.clone(), path.clone());
+            async move {
+                for fragment in fragments {
+                    tokio::time::sleep(Duration::from_millis(300)).await;
+                    let mut file = std::fs::OpenOptions::new()
+                        .append(true)
+                        .open(&path)
+                        .unwrap();
+                    std::io::Write::write_all(&mut file, &fragment).unwrap();
+                }
+                // the compression is over
+                mx.set(RecordingState::Waiting).await;
+            }
+        });
+
+        let router = build_router(mx, BodyLimits::default());
+        let uri = "/api/recordings/growing.mp4/download?progressive=true";
+        let res = get(&router, uri, &[]).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[PROGRESSIVE_SOURCE], "compressed-tail");
+        assert_eq!(body(res).await, content);
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_compression_not_yet_readable_sends_the_raw_capture() {
+        let path = output_dir().join("unfragmented.mp4");
+        // the index of an mp4 that is not fragmented comes after its media
+        std::fs::write(&path, b"\0\0\0\x10ftypisom\0\0\x02\0\0\0\0\x08mdat").unwrap();
+        let raw = recording("unfragmented.raw.mp4", 1000);
+        let mx = Recorder::new();
+        mx.set(RecordingState::Compressing {
+            process_id: 1,
+            input: output_dir()
+                .join("unfragmented.raw.mp4")
+                .to_string_lossy()
+                .to_string(),
+            output: path.to_string_lossy().to_string(),
+            encoder: EncoderParams::resolve(ContentKind::default()),
+            gpu: None,
+            command: vec![],
+            health: None,
+            progress: None,
+            duration_ms: None,
+            percent: None,
+        })
+        .await;
+        let router = router(mx);
+        let uri = "/api/recordings/unfragmented.mp4/download?progressive=true";
+        let res = get(&router, uri, &[]).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[PROGRESSIVE_SOURCE], "raw-master");
+        assert_eq!(body(res).await, raw);
+    }
+
+    fn limited() -> Router {
+        let limits = BodyLimits {
+            control: 100,
+            import: 1000,
+            default: 500,
+        };
+        build_router(Arc::new(Recorder::new()), limits)
+    }
+
+    /// send `body` as JSON, with its length when it is known
+    async fn upload(router: &Router, method: &str, uri: &str, body: Body) -> Response {
+        use axum::body::HttpBody;
+        let mut req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json");
+        if let Some(length) = HttpBody::size_hint(&body).exact() {
+            req = req.header(header::CONTENT_LENGTH, length);
+        }
+        router
+            .clone()
+            .oneshot(req.body(body).unwrap())
+            .await
+            .unwrap()
+    }
+
+    async fn assert_too_large(res: Response) {
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], problem::CONTENT_TYPE);
+        let problem: serde_json::Value = serde_json::from_slice(&body(res).await).unwrap();
+        assert_eq!(problem["type"], "/problems/payload-too-large");
+        assert_eq!(problem["status"], 413);
+    }
+
+    /// a body of `size` bytes sent in chunks, without a length
+    fn streamed(size: usize) -> Body {
+        let chunks = (0..size / 100).map(|_| Ok::<_, Infallible>(vec![7u8; 100]));
+        Body::wrap_stream(futures::stream::iter(chunks))
+    }
+
+    #[tokio::test]
+    async fn an_imported_recording_within_the_limit_is_written() {
+        let router = limited();
+        let res = upload(
+            &router,
+            "PUT",
+            "/api/recordings/imported.mp4",
+            streamed(1000),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let imported = std::fs::read(output_dir().join("imported.mp4")).unwrap();
+        assert_eq!(imported, vec![7u8; 1000]);
+        // the name is taken now
+        let again = upload(
+            &router,
+            "PUT",
+            "/api/recordings/imported.mp4",
+            streamed(100),
+        )
+        .await;
+        assert_eq!(again.status(), StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn the_quotas_follow_the_imports_and_the_deletes() {
+        let history = History::default();
+        let owned = output_dir().join("quota-owned.mp4");
+        recording("quota-owned.mp4", 3000);
+        history
+            .append(crate::history::HistoryEntry {
+                file: Some(owned.to_string_lossy().to_string()),
+                ..crate::history::HistoryEntry::new(
+                    "done",
+                    chrono::Local::now(),
+                    Some("teamA".to_string()),
+                )
+            })
+            .unwrap();
+        let quotas = Quotas::new(vec![
+            "teamA=10GB".parse().unwrap(),
+            format!("default={}", quota::RESERVATION + 1500)
+                .parse()
+                .unwrap(),
+        ]);
+        quotas.tally(&history).unwrap();
+        let mx = Arc::new(Recorder::with_history(history).with_quotas(quotas));
+        let router = build_router(mx.clone(), BodyLimits::default());
+        assert_eq!(mx.quotas.used(Some("teamA")), 3000);
+
+        // an imported recording has no owner
+        for name in ["quota-a.mp4", "quota-b.mp4"] {
+            let uri = format!("/api/recordings/{}", name);
+            let res = upload(&router, "PUT", &uri, streamed(1000)).await;
+            assert_eq!(res.status(), StatusCode::CREATED);
+        }
+        assert_eq!(mx.quotas.used(None), 2000);
+        let res = upload(&router, "POST", "/api/start", Body::from("{}")).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let problem: serde_json::Value = serde_json::from_slice(&body(res).await).unwrap();
+        assert_eq!(problem["reason"], "quota_exceeded");
+        assert_eq!(problem["used"], 2000);
+        assert_eq!(problem["reserved"], quota::RESERVATION);
+
+        let res = send(&router, "DELETE", "/api/recordings/quota-a.mp4", None).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = send(&router, "DELETE", "/api/recordings/quota-owned.mp4", None).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = get(&router, "/api/quota", &[]).await;
+        let report: serde_json::Value = serde_json::from_slice(&body(res).await).unwrap();
+        let used = |owner: &str| {
+            report["owners"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|o| o["owner"] == owner)
+                .map(|o| o["used"].clone())
+                .unwrap()
+        };
+        assert_eq!(used("default"), 1000);
+        assert_eq!(used("teamA"), 0);
+    }
+
+    #[tokio::test]
+    async fn an_oversized_import_is_refused_as_a_problem() {
+        let router = limited();
+        // with its length, refused before it is read
+        let declared = upload(
+            &router,
+            "PUT",
+            "/api/recordings/declared.mp4",
+            Body::from(vec![0u8; 1001]),
+        )
+        .await;
+        assert_too_large(declared).await;
+        assert!(!output_dir().join("declared.mp4").exists());
+        // streamed, refused once the limit is past, the part written is removed
+        let res = upload(
+            &router,
+            "PUT",
+            "/api/recordings/streamed.mp4",
+            streamed(2000),
+        )
+        .await;
+        assert_too_large(res).await;
+        assert!(!output_dir().join("streamed.mp4").exists());
+        assert!(!output_dir().join("streamed.mp4.part.import").exists());
+    }
+
+    #[tokio::test]
+    async fn each_group_of_routes_has_its_limit() {
+        let router = limited();
+        // the import limit is for the imports alone
+        let control = upload(&router, "POST", "/api/start", Body::from(vec![b' '; 101])).await;
+        assert_too_large(control).await;
+        let other = upload(
+            &router,
+            "POST",
+            "/api/encoder-profiles/validate",
+            Body::from(vec![b' '; 501]),
+        )
+        .await;
+        assert_too_large(other).await;
+        let within = upload(
+            &router,
+            "POST",
+            "/api/encoder-profiles/validate",
+            Body::from(vec![b' '; 200]),
+        )
+        .await;
+        assert_ne!(within.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn the_jobs_and_the_history_are_paged_with_their_total() {
+        let mx = Recorder::new();
+        for _ in 0..3 {
+            mx.history
+                .append(history::HistoryEntry::new(
+                    "done",
+                    chrono::Local::now(),
+                    None,
+                ))
+                .unwrap();
+        }
+        let router = router(mx);
+        let first = get(&router, "/api/history?limit=2", &[]).await;
+        assert_eq!(first.headers()[TOTAL_COUNT], "3");
+        let page: serde_json::Value = serde_json::from_slice(&body(first).await).unwrap();
+        assert_eq!(page["entries"].as_array().unwrap().len(), 2);
+        let uri = format!(
+            "/api/history?limit=2&cursor={}",
+            page["next_cursor"].as_str().unwrap()
+        );
+        let rest: serde_json::Value =
+            serde_json::from_slice(&body(get(&router, &uri, &[]).await).await).unwrap();
+        assert_eq!(rest["entries"].as_array().unwrap().len(), 1);
+        assert!(rest["next_cursor"].is_null());
+
+        let jobs = get(&router, "/api/jobs?limit=2", &[]).await;
+        assert_eq!(jobs.status(), StatusCode::OK);
+        assert_eq!(jobs.headers()[TOTAL_COUNT], "0");
+        let invalid = get(&router, "/api/jobs?cursor=nope", &[]).await;
+        assert_eq!(invalid.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn every_route_wants_a_token_of_its_role() {
+        let tokens = vec![
+            token("viewer", Role::Viewer),
+            token("operator", Role::Operator),
+            token("admin", Role::Admin),
+        ];
+        let router = router(Recorder::new().with_tokens(Tokens::new(tokens)));
+        let routes = [
+            ("GET", "/api/history", Role::Viewer),
+            ("DELETE", "/api/recordings/missing.mp4", Role::Operator),
+            ("GET", "/api/feed/token", Role::Admin),
+        ];
+        for (method, uri, needed) in routes {
+            assert!(
+                !allowed(&router, method, uri, None).await,
+                "{} {}",
+                method,
+                uri
+            );
+            for role in [Role::Viewer, Role::Operator, Role::Admin] {
+                let bearer = format!("{}-secret", role);
+                assert_eq!(
+                    allowed(&router, method, uri, Some(&bearer)).await,
+                    role >= needed,
+                    "{} {} as {}",
+                    method,
+                    uri,
+                    role
+                );
+            }
+            assert_eq!(
+                send(&router, method, uri, Some("wrong")).await.status(),
+                StatusCode::UNAUTHORIZED
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn an_admin_token_alone_opens_nothing() {
+        let tokens = vec![token("admin", Role::Admin)];
+        let router = router(Recorder::new().with_tokens(Tokens::new(tokens)));
+        let res = send(&router, "DELETE", "/api/recordings/a.mp4", None).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert!(!allowed(&router, "GET", "/api/history", None).await);
+        assert!(allowed(&router, "GET", "/api/history", Some("admin-secret")).await);
+    }
+
+    #[tokio::test]
+    async fn without_a_token_the_admin_routes_alone_are_closed() {
+        let router = router(Recorder::new());
+        assert!(allowed(&router, "GET", "/api/history", None).await);
+        assert!(allowed(&router, "DELETE", "/api/recordings/missing.mp4", None).await);
+        let res = send(&router, "GET", "/api/feed/token", None).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+
+    async fn next(messages: &mut (impl Stream<Item = Message> + Unpin)) -> Message {
+        tokio::time::timeout(Duration::from_secs(5), messages.next())
+            .await
+            .expect("no message")
+            .expect("the stream ended")
+    }
+
+    #[tokio::test]
+    async fn a_progress_client_starts_from_the_current_state() {
+        let mx = Arc::new(Recorder::new());
+        mx.events.publish(EventKind::Notice {
+            message: "before".to_string(),
+        });
+        let mut messages = Box::pin(progress_messages(mx.clone(), None).await);
+        match next(&mut messages).await {
+            Message::Resync { seq, state } => {
+                assert_eq!(seq, 1);
+                assert!(matches!(state, RecordingState::Waiting));
+            }
+            message => panic!("not a resync: {:?}", message),
+        }
+    }
+
+    #[tokio::test]
+    async fn every_progress_client_gets_the_progress_and_the_states() {
+        let mx = Arc::new(Recorder::new());
+        let mut first = Box::pin(progress_messages(mx.clone(), None).await);
+        let mut second = Box::pin(progress_messages(mx.clone(), None).await);
+        mx.events.publish(EventKind::Notice {
+            message: "skipped".to_string(),
+        });
+        mx.events.publish(EventKind::Progress {
+            progress: Progress {
+                frame: Some(25),
+                ..Default::default()
+            },
+            gpu: None,
+        });
+        mx.events.publish(EventKind::State {
+            state: RecordingState::Waiting,
+        });
+        for messages in [&mut first, &mut second] {
+            assert!(matches!(next(messages).await, Message::Resync { .. }));
+            let Message::Event(progress) = next(messages).await else {
+                panic!("not an event");
+            };
+            assert_eq!(progress.seq, 2);
+            assert!(matches!(
+                progress.kind,
+                EventKind::Progress { progress, .. } if progress.frame == Some(25)
+            ));
+            let Message::Event(state) = next(messages).await else {
+                panic!("not an event");
+            };
+            assert_eq!(state.seq, 3);
+            assert!(matches!(state.kind, EventKind::State { .. }));
+        }
+    }
+
+    fn progress(frame: u64) -> EventKind {
+        EventKind::Progress {
+            progress: Progress {
+                frame: Some(frame),
+                ..Default::default()
+            },
+            gpu: None,
+        }
+    }
+
+    fn frame_of(message: Message) -> Option<u64> {
+        match message {
+            Message::Event(Event {
+                kind: EventKind::Progress { progress, .. },
+                ..
+            }) => progress.frame,
+            message => panic!("not a progress: {:?}", message),
+        }
+    }
+
+    #[tokio::test]
+    async fn a_progress_client_resumes_where_it_left() {
+        let mx = Arc::new(Recorder::new());
+        let mut first = Box::pin(progress_messages(mx.clone(), None).await);
+        assert!(matches!(next(&mut first).await, Message::Resync { .. }));
+        mx.events.publish(progress(25));
+        let Message::Event(seen) = next(&mut first).await else {
+            panic!("not an event");
+        };
+        drop(first);
+        // missed while it reconnects
+        mx.events.publish(EventKind::Notice {
+            message: "skipped".to_string(),
+        });
+        mx.events.publish(progress(50));
+
+        let mut resumed = Box::pin(progress_messages(mx.clone(), Some(seen.seq)).await);
+        assert_eq!(frame_of(next(&mut resumed).await), Some(50));
+        mx.events.publish(progress(75));
+        assert_eq!(frame_of(next(&mut resumed).await), Some(75));
+    }
+
+    #[tokio::test]
+    async fn a_running_recording_is_live_whatever_the_checks_say() {
+        let broken = liveness::Config {
+            checks: vec![liveness::Check::Ffmpeg],
+            ffmpeg: "/nonexistent/ffmpeg".to_string(),
+            cache_for: Duration::ZERO,
+            ..Default::default()
+        };
+        let mx = Arc::new(Recorder::new().with_liveness(Liveness::new(broken)));
+        let router = build_router(mx.clone(), BodyLimits::default());
+        let res = get(&router, "/api/liveness", &[]).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let mut capture = tokio::process::Command::new("sleep")
+            .arg("30")
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap();
+        mx.set(recording_state(serde_json::json!({
+            "type": "Started",
+            "process_id": capture.id().unwrap(),
+            "file": "live.mkv",
+            "started_at": chrono::Local::now(),
+        })))
+        .await;
+        let res = get(&router, "/api/liveness", &[]).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let report: serde_json::Value = serde_json::from_slice(&body(res).await).unwrap();
+        assert_eq!(report["recording"], true);
+
+        // a capture that is gone proves nothing
+        capture.kill().await.unwrap();
+        let res = get(&router, "/api/liveness", &[]).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn an_emergency_stop_kills_every_child_and_keeps_the_capture() {
+        use std::os::unix::process::ExitStatusExt;
+        let tokens = vec![
+            token("operator", Role::Operator),
+            token("admin", Role::Admin),
+        ];
+        let mx = Arc::new(Recorder::new().with_tokens(Tokens::new(tokens)));
+        let router = build_router(mx.clone(), BodyLimits::default());
+        let capture = output_dir().join("emergency.mkv");
+        std::fs::write(&capture, b"partial").unwrap();
+        let capture = capture.to_string_lossy().to_string();
+        let mut children = vec![];
+        for role in [ChildRole::Capture, ChildRole::Audio, ChildRole::Frames] {
+            let child = tokio::process::Command::new("sleep")
+                .arg("30")
+                .kill_on_drop(true)
+                .spawn()
+                .unwrap();
+            let files = match role {
+                ChildRole::Capture => vec![capture.clone()],
+                _ => vec![],
+            };
+            mx.children.register(child.id().unwrap(), role, files);
+            children.push(child);
+        }
+        mx.set(recording_state(serde_json::json!({
+            "type": "Started",
+            "process_id": children[0].id().unwrap(),
+            "file": capture,
+            "started_at": chrono::Local::now(),
+        })))
+        .await;
+
+        let res = send(
+            &router,
+            "POST",
+            "/api/emergency-stop",
+            Some("operator-secret"),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = send(&router, "POST", "/api/emergency-stop", Some("admin-secret")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let stopped: EmergencyStop = serde_json::from_slice(&body(res).await).unwrap();
+
+        let mut killed: Vec<u32> = stopped.killed.iter().map(|c| c.pid).collect();
+        let mut spawned: Vec<u32> = children.iter().map(|c| c.id().unwrap()).collect();
+        killed.sort();
+        spawned.sort();
+        assert_eq!(killed, spawned);
+        for child in &mut children {
+            let status = child.wait().await.unwrap();
+            assert_eq!(status.signal(), Some(nix::libc::SIGKILL));
+        }
+        assert_eq!(stopped.preserved, std::slice::from_ref(&capture));
+        assert!(std::path::Path::new(&format!("{}.recover", capture)).exists());
+        let state = mx.lock().await.clone();
+        match state {
+            RecordingState::Cancelled { reason, preserved } => {
+                assert_eq!(reason, CancelReason::EmergencyStop);
+                assert_eq!(preserved, [capture]);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    /// the ids and names of the next `n` server-sent events of `body`
+    async fn sse_events(body: &mut axum::body::BoxBody, n: usize) -> Vec<(u64, String)> {
+        use axum::body::HttpBody;
+        let mut text = String::new();
+        let mut events = vec![];
+        while events.len() < n {
+            let chunk = tokio::time::timeout(Duration::from_secs(5), body.data())
+                .await
+                .expect("no event")
+                .expect("the stream ended")
+                .unwrap();
+            text.push_str(std::str::from_utf8(&chunk).unwrap());
+            while let Some(end) = text.find("\n\n") {
+                let event: String = text.drain(..end + 2).collect();
+                let field = |name: &str| {
+                    event
+                        .lines()
+                        .find_map(|line| line.strip_prefix(name))
+                        .map(|value| value.trim().to_string())
+                };
+                // the keep-alives are comments
+                if let (Some(id), Some(name)) = (field("id:"), field("event:")) {
+                    events.push((id.parse().unwrap(), name));
+                }
+            }
+        }
+        events
+    }
+
+    fn recording_state(value: serde_json::Value) -> RecordingState {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[tokio::test]
+    async fn an_events_client_reconnecting_misses_no_transition() {
+        let mx = Arc::new(Recorder::new());
+        let router = build_router(mx.clone(), BodyLimits::default());
+        let mut first = get(&router, "/api/events", &[]).await.into_body();
+        mx.set(recording_state(serde_json::json!({
+            "type": "Countdown",
+            "start_at": chrono::Local::now(),
+        })))
+        .await;
+        mx.set(recording_state(serde_json::json!({
+            "type": "Started",
+            "process_id": 1,
+            "file": "events.mkv",
+            "started_at": chrono::Local::now(),
+        })))
+        .await;
+        let seen = sse_events(&mut first, 2).await;
+        assert_eq!(seen[0].1, "state");
+        drop(first);
+        // while the client reconnects
+        mx.set(RecordingState::Stopping {
+            process_id: 1,
+            file: "events.mkv".to_string(),
+        })
+        .await;
+        mx.events.publish(EventKind::Notice {
+            message: "compressing".to_string(),
+        });
+
+        let last = seen[1].0.to_string();
+        let res = get(
+            &router,
+            "/api/events?until_done=true",
+            &[(header::HeaderName::from_static("last-event-id"), &last)],
+        )
+        .await;
+        let mut resumed = res.into_body();
+        let missed = sse_events(&mut resumed, 2).await;
+        assert_eq!(
+            missed,
+            [
+                (seen[1].0 + 1, "state".to_string()),
+                (seen[1].0 + 2, "notice".to_string())
+            ]
+        );
+        mx.set(recording_state(serde_json::json!({
+            "type": "Done",
+            "file": "events.mp4",
+        })))
+        .await;
+        let done = sse_events(&mut resumed, 1).await;
+        assert_eq!(done, [(seen[1].0 + 3, "state".to_string())]);
+        // the stream ends with the recording
+        let end = tokio::time::timeout(Duration::from_secs(5), {
+            use axum::body::HttpBody;
+            resumed.data()
+        })
+        .await
+        .unwrap();
+        assert!(end.is_none());
+    }
+
+    #[tokio::test]
+    async fn a_progress_client_too_far_behind_starts_over() {
+        let mx = Arc::new(Recorder::new());
+        for frame in 0..crate::events::REPLAY as u64 + 10 {
+            mx.events.publish(progress(frame));
+        }
+        let mut resumed = Box::pin(progress_messages(mx.clone(), Some(1)).await);
+        match next(&mut resumed).await {
+            Message::Resync { seq, .. } => assert_eq!(seq, mx.events.seq()),
+            message => panic!("not a resync: {:?}", message),
+        }
+    }
+
+    /// the response to a timed recording of `options`
+    fn record(
+        router: &Router,
+        query: &str,
+        accept: &str,
+        options: serde_json::Value,
+    ) -> impl std::future::Future<Output = Response> + Send {
+        let req = Request::builder()
+            .method("POST")
+            .uri(format!("/api/record?{}", query))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::ACCEPT, accept)
+            .body(Body::from(options.to_string()))
+            .unwrap();
+        let router = router.clone();
+        async move { router.oneshot(req).await.unwrap() }
+    }
+
+    /// the options of a recording of the lavfi test source, sent over HTTP at its own pace
+    ///
+    /// None without ffmpeg.
+    async fn live_test_source() -> Option<serde_json::Value> {
+        if std::process::Command::new("ffmpeg")
+            .arg("-version")
+            .output()
+            .is_err()
+        {
+            eprintln!("ffmpeg is not installed, skipped");
+            return None;
+        }
+        let source = output_dir().join("live-test-source.ts");
+        let status = tokio::process::Command::new("ffmpeg")
+            .args(["-v", "error", "-y", "-f", "lavfi"])
+            .args(["-i", "testsrc=duration=60:size=160x120:rate=10"])
+            .args(["-f", "mpegts"])
+            .arg(&source)
+            .status()
+            .await
+            .unwrap();
+        assert!(status.success());
+        let content = Bytes::from(std::fs::read(&source).unwrap());
+        // a tenth of a second of it every tenth of a second, as a camera would
+        let tick = content.len() / 600 + 1;
+        let app = Router::new().route(
+            "/live.ts",
+            axum::routing::get(move || async move {
+                let chunks = futures::stream::unfold(0, move |at| {
+                    let content = content.clone();
+                    async move {
+                        if at >= content.len() {
+                            return None;
+                        }
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        let end = (at + tick).min(content.len());
+                        Some((Ok::<_, Infallible>(content.slice(at..end)), end))
+                    }
+                });
+                StreamBody::new(chunks)
+            }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener).unwrap();
+        tokio::spawn(server.serve(app.into_make_service()));
+        Some(serde_json::json!({
+            "source": {
+                "type": "url",
+                "url": format!("http://{}/live.ts", addr),
+                "input_format": "mpegts",
+            }
+        }))
+    }
+
+    #[tokio::test]
+    async fn a_timed_recording_is_bounded() {
+        let router = router(Recorder::new().with_max_record(Some(Duration::from_secs(60))));
+        for query in ["duration_secs=0", "duration_secs=61"] {
+            let res = record(&router, query, "*/*", serde_json::json!({})).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", query);
+            let problem: serde_json::Value = serde_json::from_slice(&body(res).await).unwrap();
+            assert_eq!(problem["errors"][0]["field"], "duration_secs");
+        }
+        let at = serde_json::json!({ "start_at": chrono::Local::now() });
+        let res = record(&router, "duration_secs=5", "*/*", at).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res = record(
+            &router,
+            "duration_secs=5&return=file",
+            "text/event-stream",
+            serde_json::json!({}),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn a_timed_recording_is_refused_while_busy() {
+        let mx = Arc::new(Recorder::new());
+        let router = build_router(mx.clone(), BodyLimits::default());
+        let busy = recording_state(serde_json::json!({
+            "type": "Started",
+            "process_id": 1,
+            "file": "busy.mkv",
+            "started_at": chrono::Local::now(),
+        }));
+        mx.set(busy.clone()).await;
+        let options = serde_json::json!({
+            "source": { "type": "url", "url": "http://127.0.0.1:9/live.ts" }
+        });
+        let res = record(&router, "duration_secs=5", "*/*", options).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        assert_eq!(
+            serde_json::to_value(mx.lock().await.clone()).unwrap(),
+            serde_json::to_value(busy).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn a_timed_recording_of_the_test_source_is_done() {
+        let Some(options) = live_test_source().await else {
+            return;
+        };
+        let mx = Arc::new(Recorder::new());
+        let router = build_router(mx.clone(), BodyLimits::default());
+        let res = record(&router, "duration_secs=2", "*/*", options.clone()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let done: serde_json::Value = serde_json::from_slice(&body(res).await).unwrap();
+        assert_eq!(done["type"], "Done");
+        let size = done["size"].as_u64().unwrap();
+        assert!(size > 0);
+
+        // the file itself, and the progress as comments before the outcome
+        let res = record(
+            &router,
+            "duration_secs=2&return=file",
+            "*/*",
+            options.clone(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(!body(res).await.is_empty());
+        let res = record(&router, "duration_secs=2", "text/event-stream", options).await;
+        let events = String::from_utf8(body(res).await).unwrap();
+        assert!(events.contains("event: done"), "{}", events);
+        assert!(events.find(": ").unwrap() < events.find("event: done").unwrap());
+    }
+
+    #[tokio::test]
+    async fn a_client_going_away_cancels_its_timed_recording_when_asked() {
+        let Some(options) = live_test_source().await else {
+            return;
+        };
+        let mx = Arc::new(Recorder::new());
+        let router = build_router(mx.clone(), BodyLimits::default());
+        for cancel in [true, false] {
+            let query = format!("duration_secs=3&cancel_on_disconnect={}", cancel);
+            let request = {
+                let (router, options) = (router.clone(), options.clone());
+                tokio::spawn(async move { record(&router, &query, "*/*", options).await })
+            };
+            let mut states = mx.subscribe(None).await.live;
+            while !matches!(mx.lock().await.clone(), RecordingState::Started { .. }) {
+                let _ = tokio::time::timeout(Duration::from_secs(1), states.recv()).await;
+            }
+            request.abort();
+            let state = tokio::time::timeout(Duration::from_secs(30), async {
+                loop {
+                    let state = mx.lock().await.clone();
+                    if timed::finished(&state) {
+                        break state;
+                    }
+                    let _ = tokio::time::timeout(Duration::from_secs(1), states.recv()).await;
+                }
+            })
+            .await
+            .unwrap();
+            match cancel {
+                true => assert!(matches!(state, RecordingState::Cancelled { .. })),
+                false => assert!(matches!(state, RecordingState::Done { .. })),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn a_stop_trimming_more_than_was_recorded_is_refused() {
+        let mx = Arc::new(Recorder::new());
+        let router = build_router(mx.clone(), BodyLimits::default());
+        mx.set(recording_state(serde_json::json!({
+            "type": "Started",
+            "process_id": 1,
+            "file": "trimmed.mkv",
+            "started_at": chrono::Local::now() - chrono::Duration::seconds(5),
+            "timeline": crate::service::RecordingTimeline::started(),
+        })))
+        .await;
+        for (request, field) in [
+            (
+                serde_json::json!({ "trim_tail_secs": 30 }),
+                "trim_tail_secs",
+            ),
+            (
+                serde_json::json!({ "trim_head_secs": 3, "trim_tail_secs": 3 }),
+                "trim_tail_secs",
+            ),
+            (
+                serde_json::json!({ "trim_head_secs": -1 }),
+                "trim_head_secs",
+            ),
+        ] {
+            let req = Request::builder()
+                .method("POST")
+                .uri("/api/stop")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(request.to_string()))
+                .unwrap();
+            let res = router.clone().oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", request);
+            let problem: serde_json::Value = serde_json::from_slice(&body(res).await).unwrap();
+            assert_eq!(problem["errors"][0]["field"], field);
+        }
+        // nothing was stopped
+        assert!(matches!(*mx.lock().await, RecordingState::Started { .. }));
+    }
+
+    #[tokio::test]
+    async fn the_webhook_is_tested_with_the_current_state() {
+        let admin = || Tokens::new(vec![token("admin", Role::Admin)]);
+        let res = send(
+            &router(Recorder::new().with_tokens(admin())),
+            "POST",
+            "/api/webhook/test",
+            Some("admin-secret"),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let (tx, mut posted) = tokio::sync::mpsc::unbounded_channel();
+        let receiver = Router::new().route(
+            "/hook",
+            post(move |Json(body): Json<serde_json::Value>| async move {
+                _ = tx.send(body);
+            }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(receiver.into_make_service()),
+        );
+        let webhook = |url: &str| {
+            let url = crate::template::webhook_url(url).unwrap();
+            Some(Webhook::open(url).unwrap())
+        };
+        let mx = Recorder::new()
+            .with_tokens(admin())
+            .with_webhook(webhook(&url));
+        let tested = router(mx);
+        let res = send(&tested, "POST", "/api/webhook/test", None).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = send(&tested, "POST", "/api/webhook/test", Some("admin-secret")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let answered: serde_json::Value = serde_json::from_slice(&body(res).await).unwrap();
+        assert_eq!(answered["status"], 200);
+        let notification = posted.recv().await.unwrap();
+        assert_eq!(notification["event"], "test");
+        assert_eq!(notification["kind"], "Waiting");
+
+        // nobody listens there
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", closed.local_addr().unwrap());
+        drop(closed);
+        let mx = Recorder::new()
+            .with_tokens(admin())
+            .with_webhook(webhook(&url));
+        let res = send(
+            &router(mx),
+            "POST",
+            "/api/webhook/test",
+            Some("admin-secret"),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+    }
+}
@@ -0,0 +1,250 @@
+//! Fanout of state changes and progress to the streaming clients
+//!
+//! Every event gets a sequence number, and the last [REPLAY] events are kept, so that a client
+//! that lost its connection can resume with the sequence number of the last event it has seen:
+//! it first gets the events it missed, then the live ones. A client that fell behind further
+//! than the buffer reaches gets a [Message::Resync] with the full current state instead.
+use crate::content_check::ContentWarning;
+use crate::gpu::GpuUsage;
+use crate::health::{Health, Level};
+use crate::runner::Progress;
+use crate::service::RecordingState;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+
+/// how many events are kept for the clients resuming their stream
+pub const REPLAY: usize = 100;
+
+/// What happened
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+#[allow(clippy::large_enum_variant)]
+pub enum EventKind {
+    /// The recording state changed.
+    State { state: RecordingState },
+    /// Ffmpeg reported progress.
+    Progress {
+        progress: Progress,
+        /// utilization of the hardware encoder
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        gpu: Option<GpuUsage>,
+    },
+    /// Something the users should know about, such as a recording about to be stopped.
+    Notice { message: String },
+    /// The health of the recording went to another level, see [crate::health].
+    Health { previous: Level, health: Health },
+    /// A finished recording looks like it recorded the wrong display or microphone.
+    Suspect {
+        file: String,
+        warnings: Vec<ContentWarning>,
+    },
+}
+
+/// An event with its sequence number
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+/// What a streaming client is sent
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum Message {
+    /// Events since the client's sequence number are gone, this is the state to rebuild from.
+    Resync { seq: u64, state: RecordingState },
+    #[serde(untagged)]
+    Event(Event),
+}
+
+impl<'de> Deserialize<'de> for Message {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // the tag of a resync is none of the events
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Wire {
+            Event(Event),
+            Resync { seq: u64, state: RecordingState },
+        }
+        Ok(match Wire::deserialize(deserializer)? {
+            Wire::Event(event) => Message::Event(event),
+            Wire::Resync { seq, state } => Message::Resync { seq, state },
+        })
+    }
+}
+
+/// A subscription: the events to replay, then the live receiver
+pub struct Subscription {
+    pub replay: Vec<Message>,
+    pub live: broadcast::Receiver<Event>,
+}
+
+struct Ring {
+    seq: u64,
+    events: VecDeque<Event>,
+}
+
+pub struct Fanout {
+    ring: Mutex<Ring>,
+    tx: broadcast::Sender<Event>,
+}
+
+impl Default for Fanout {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Fanout {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(REPLAY);
+        Self {
+            ring: Mutex::new(Ring {
+                seq: 0,
+                events: VecDeque::with_capacity(REPLAY),
+            }),
+            tx,
+        }
+    }
+
+    /// sequence number of the last event published
+    pub fn seq(&self) -> u64 {
+        self.ring.lock().unwrap().seq
+    }
+
+    pub fn publish(&self, kind: EventKind) {
+        let mut ring = self.ring.lock().unwrap();
+        ring.seq += 1;
+        let event = Event {
+            seq: ring.seq,
+            kind,
+        };
+        if ring.events.len() == REPLAY {
+            ring.events.pop_front();
+        }
+        ring.events.push_back(event.clone());
+        // sent while holding the ring, so a new subscriber sees each event exactly once
+        let _ = self.tx.send(event);
+    }
+
+    /// subscribe to the events after `since`, or only to the live ones with `None`
+    pub fn subscribe(&self, since: Option<u64>, current: &RecordingState) -> Subscription {
+        let ring = self.ring.lock().unwrap();
+        let live = self.tx.subscribe();
+        let replay = match since {
+            None => vec![],
+            Some(since) if since >= ring.seq => vec![],
+            Some(since) => match ring.events.front() {
+                Some(first) if first.seq <= since + 1 => ring
+                    .events
+                    .iter()
+                    .filter(|e| e.seq > since)
+                    .cloned()
+                    .map(Message::Event)
+                    .collect(),
+                _ => vec![Message::Resync {
+                    seq: ring.seq,
+                    state: current.clone(),
+                }],
+            },
+        };
+        Subscription { replay, live }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notice(message: &str) -> EventKind {
+        EventKind::Notice {
+            message: message.to_string(),
+        }
+    }
+
+    fn seqs(messages: &[Message]) -> Vec<u64> {
+        messages
+            .iter()
+            .map(|m| match m {
+                Message::Event(e) => e.seq,
+                Message::Resync { seq, .. } => panic!("resync at {}", seq),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_client_resuming_gets_what_it_missed_then_the_live_events() {
+        let fanout = Fanout::new();
+        for n in 0..5 {
+            fanout.publish(notice(&n.to_string()));
+        }
+        let mut subscription = fanout.subscribe(Some(2), &RecordingState::Waiting);
+        assert_eq!(seqs(&subscription.replay), [3, 4, 5]);
+        fanout.publish(notice("live"));
+        assert_eq!(subscription.live.try_recv().unwrap().seq, 6);
+        // none twice: the replayed events are not in the live ones
+        assert!(subscription.live.try_recv().is_err());
+    }
+
+    #[test]
+    fn a_client_up_to_date_or_new_gets_no_replay() {
+        let fanout = Fanout::new();
+        fanout.publish(notice("a"));
+        assert!(fanout
+            .subscribe(Some(1), &RecordingState::Waiting)
+            .replay
+            .is_empty());
+        assert!(fanout
+            .subscribe(None, &RecordingState::Waiting)
+            .replay
+            .is_empty());
+    }
+
+    #[test]
+    fn a_client_too_far_behind_is_resynced() {
+        let fanout = Fanout::new();
+        for n in 0..REPLAY + 10 {
+            fanout.publish(notice(&n.to_string()));
+        }
+        let subscription = fanout.subscribe(Some(5), &RecordingState::Waiting);
+        match &subscription.replay[..] {
+            [Message::Resync { seq, state }] => {
+                assert_eq!(*seq, (REPLAY + 10) as u64);
+                assert!(matches!(state, RecordingState::Waiting));
+            }
+            replay => panic!("replayed {} messages", replay.len()),
+        }
+        // the oldest event kept is still replayed
+        let kept = fanout.subscribe(Some(10), &RecordingState::Waiting);
+        assert_eq!(seqs(&kept.replay).len(), REPLAY);
+    }
+
+    #[test]
+    fn the_messages_read_back_as_sent() {
+        let resync = Message::Resync {
+            seq: 3,
+            state: RecordingState::Waiting,
+        };
+        let json = serde_json::to_string(&resync).unwrap();
+        assert!(json.contains(r#""event":"resync""#));
+        assert!(matches!(
+            serde_json::from_str(&json).unwrap(),
+            Message::Resync { seq: 3, .. }
+        ));
+        let event = Message::Event(Event {
+            seq: 4,
+            kind: notice("hello"),
+        });
+        let json = serde_json::to_string(&event).unwrap();
+        match serde_json::from_str(&json).unwrap() {
+            Message::Event(Event {
+                seq: 4,
+                kind: EventKind::Notice { message },
+            }) => assert_eq!(message, "hello"),
+            message => panic!("read back {:?}", message),
+        }
+    }
+}
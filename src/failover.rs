@@ -0,0 +1,420 @@
+//! Going on in a fallback directory when the output directory can't be written any more
+//!
+//! With a fallback directory, a capture writing the output directory is watched two ways: its
+//! stderr for the errors of a failing write, and the directory itself, written to every
+//! [PROBE_EVERY]. On a failure the capture rolls over to a new segment in the fallback, the way
+//! a quality change does, with a marker, a warning and a notice; it fails over once, and the
+//! segments of a change of the quality later on stay in the fallback as well. When it is
+//! stopped, [gather] brings the segments of both directories together before they are joined:
+//! into the output directory if it can be written again, or else into the fallback, where the
+//! recording is compressed.
+use crate::events::EventKind;
+use crate::quality::VideoSegment;
+use crate::service::{Recorder, RecordingState};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::*;
+
+/// how often the output directory is written to while capturing
+pub const PROBE_EVERY: Duration = Duration::from_secs(5);
+/// a directory that doesn't answer within this, a stale mount, can't be written
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+const PROBE_FILE: &str = ".record-screen-probe";
+/// what ffmpeg says when it can't write its output
+const WRITE_ERRORS: &[&str] = &[
+    "Stale file handle",
+    "Input/output error",
+    "Read-only file system",
+    "No space left on device",
+    "Error writing trailer",
+    "av_interleaved_write_frame()",
+];
+
+/// Where a recording writes to, when there is a fallback
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FailoverStatus {
+    /// the directory the capture writes to now
+    pub active: String,
+    pub primary: String,
+    pub fallback: String,
+    /// the capture is being restarted in the fallback
+    #[serde(default)]
+    pub pending: bool,
+    /// when and why it failed over
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub switched_at: Option<DateTime<Local>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl FailoverStatus {
+    pub fn new(primary: &Path, fallback: &Path) -> Self {
+        let primary = primary.to_string_lossy().to_string();
+        Self {
+            active: primary.clone(),
+            primary,
+            fallback: fallback.to_string_lossy().to_string(),
+            pending: false,
+            switched_at: None,
+            reason: None,
+        }
+    }
+
+    /// not failed over, nor failing over
+    pub fn on_primary(&self) -> bool {
+        !self.pending && self.active == self.primary
+    }
+
+    /// `file` in the directory written to now
+    pub fn relocate(&self, file: &str) -> String {
+        in_dir(Path::new(&self.active), file)
+    }
+}
+
+/// `file` moved into `dir`
+pub fn in_dir(dir: &Path, file: &str) -> String {
+    let name = Path::new(file).file_name().unwrap_or_default();
+    dir.join(name).to_string_lossy().to_string()
+}
+
+/// whether a file can be created in the directory
+pub async fn writable(dir: &Path) -> std::io::Result<()> {
+    let probe = dir.join(PROBE_FILE);
+    let task = tokio::task::spawn_blocking(move || {
+        std::fs::write(&probe, b"probe")?;
+        std::fs::remove_file(&probe)
+    });
+    match tokio::time::timeout(PROBE_TIMEOUT, task).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(e)) => Err(std::io::Error::other(e)),
+        Err(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("no answer in {}s", PROBE_TIMEOUT.as_secs()),
+        )),
+    }
+}
+
+/// Sees the stderr of the captures writing the output directory, see [Detector::line]
+#[derive(Debug, Clone, Default)]
+pub struct Detector {
+    failed: CancellationToken,
+    line: Arc<Mutex<Option<String>>>,
+}
+
+impl Detector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// take a stderr line of a capture, never keeping it from the others
+    pub fn line(&self, line: &str) -> bool {
+        if !self.failed.is_cancelled() && WRITE_ERRORS.iter().any(|e| line.contains(e)) {
+            *self.line.lock().unwrap() = Some(line.to_string());
+            self.failed.cancel();
+        }
+        false
+    }
+
+    /// the error of a failed write, once there was one
+    pub fn reason(&self) -> Option<String> {
+        self.line.lock().unwrap().clone()
+    }
+
+    async fn failed(&self) -> String {
+        self.failed.cancelled().await;
+        self.reason().unwrap_or_default()
+    }
+}
+
+/// set the recording to fail over, returning the capture to interrupt and the warning
+pub fn mark(state: &mut RecordingState, why: &str) -> Option<(u32, String)> {
+    let RecordingState::Started {
+        process_id,
+        warnings,
+        failover: Some(status),
+        ..
+    } = state.recording_mut()
+    else {
+        return None;
+    };
+    if !status.on_primary() {
+        return None;
+    }
+    let message = format!("failing over to {}: {}", status.fallback, why);
+    status.pending = true;
+    status.reason = Some(why.to_string());
+    warnings.push(message.clone());
+    Some((*process_id, message))
+}
+
+/// set the recording to fail over and tell the users, returning the capture to interrupt
+pub async fn announce(mx: &Recorder, why: &str) -> Option<u32> {
+    let mut state = mx.lock().await;
+    let (pid, message) = mark(&mut state, why)?;
+    let updated = state.clone();
+    mx.replace(&mut state, updated);
+    drop(state);
+    warn!("{}", message);
+    mx.events.publish(EventKind::Notice { message });
+    Some(pid)
+}
+
+/// fail over, the capture finishes its file and the capture stage starts the next segment
+pub async fn switch(mx: &Recorder, why: &str) {
+    let Some(pid) = announce(mx, why).await else {
+        return;
+    };
+    // it may have exited already on the error
+    let pid = nix::unistd::Pid::from_raw(pid as i32);
+    if let Err(e) = nix::sys::signal::kill(pid, nix::sys::signal::Signal::SIGINT) {
+        debug!("cannot interrupt the capture {}: {}", pid, e);
+    }
+}
+
+/// the output directory while the recording writes to it, and whether a change of the quality
+/// is pending
+async fn primary(mx: &Recorder) -> Option<(String, bool)> {
+    match mx.lock().await.recording() {
+        RecordingState::Started {
+            failover: Some(status),
+            quality,
+            ..
+        } if status.on_primary() => Some((
+            status.primary.clone(),
+            quality.as_ref().is_some_and(|q| q.pending.is_some()),
+        )),
+        _ => None,
+    }
+}
+
+/// why the capture that exited can't go on in the output directory, None when it can
+///
+/// A capture that exited for a change of the quality or a stop is not looked into.
+pub async fn exit_reason(mx: &Recorder, detector: &Detector) -> Option<String> {
+    let (primary, changing) = primary(mx).await?;
+    if changing {
+        return None;
+    }
+    if let Some(line) = detector.reason() {
+        return Some(line);
+    }
+    match writable(Path::new(&primary)).await {
+        Ok(()) => None,
+        Err(e) => Some(format!("{} can't be written: {}", primary, e)),
+    }
+}
+
+/// watch the output directory for as long as the recording writes to it
+pub async fn supervise(mx: Arc<Recorder>, detector: Detector) {
+    loop {
+        let Some((primary, _)) = primary(&mx).await else {
+            return;
+        };
+        let why = tokio::select! {
+            why = detector.failed() => why,
+            _ = tokio::time::sleep(PROBE_EVERY) => match writable(Path::new(&primary)).await {
+                Ok(()) => continue,
+                Err(e) => format!("{} can't be written: {}", primary, e),
+            },
+        };
+        switch(&mx, &why).await;
+    }
+}
+
+/// where a stopped recording is finished: `primary` when it can be written, or else `fallback`
+pub async fn finish_dir<'a>(primary: &'a Path, fallback: &'a Path) -> &'a Path {
+    match writable(primary).await {
+        Ok(()) => primary,
+        Err(e) => {
+            warn!(
+                "finishing in {}, {} can't be written: {}",
+                fallback.display(),
+                primary.display(),
+                e
+            );
+            fallback
+        }
+    }
+}
+
+/// move a file of the recording into `dir`, returning where it is now
+pub async fn bring(file: &str, dir: &Path) -> std::io::Result<String> {
+    let to = in_dir(dir, file);
+    if to == file {
+        return Ok(to);
+    }
+    // brought before a restart of the server
+    if Path::new(&to).exists() && !Path::new(file).exists() {
+        return Ok(to);
+    }
+    tokio::fs::copy(file, &to).await?;
+    if let Err(e) = tokio::fs::remove_file(file).await {
+        warn!("cannot remove {} once copied: {}", file, e);
+    }
+    info!("{} brought into {}", file, dir.display());
+    Ok(to)
+}
+
+/// bring the segments of the video together in `dir`, leaving out those that can't be read
+pub async fn gather(segments: &[VideoSegment], dir: &Path) -> anyhow::Result<Vec<VideoSegment>> {
+    let mut gathered = vec![];
+    for segment in segments {
+        match bring(&segment.file, dir).await {
+            Ok(file) => gathered.push(VideoSegment {
+                file,
+                ..segment.clone()
+            }),
+            Err(e) => warn!("leaving {} out, it can't be copied: {}", segment.file, e),
+        }
+    }
+    if gathered.is_empty() {
+        anyhow::bail!("none of the segments could be gathered");
+    }
+    Ok(gathered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// an output directory and its fallback
+    fn dirs(name: &str) -> (PathBuf, PathBuf) {
+        let dir = crate::recordings::test_output_dir().join(name);
+        let (primary, fallback) = (dir.join("primary"), dir.join("fallback"));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&primary).unwrap();
+        std::fs::create_dir_all(&fallback).unwrap();
+        (primary, fallback)
+    }
+
+    /// `dir` can't be written any more, whoever runs the tests: its probe can't be created
+    fn unwritable(dir: &Path) {
+        std::fs::create_dir_all(dir.join(PROBE_FILE)).unwrap();
+    }
+
+    fn started(primary: &Path, fallback: &Path) -> RecordingState {
+        serde_json::from_value(serde_json::json!({
+            "type": "Started",
+            "process_id": 1,
+            "file": primary.join("capture.mkv"),
+            "started_at": Local::now(),
+            "failover": FailoverStatus::new(primary, fallback),
+        }))
+        .unwrap()
+    }
+
+    fn segment(file: &Path, offset_ms: u64) -> VideoSegment {
+        std::fs::write(file, file.to_string_lossy().as_bytes()).unwrap();
+        VideoSegment {
+            file: file.to_string_lossy().to_string(),
+            offset_ms,
+            quality: None,
+        }
+    }
+
+    #[test]
+    fn the_write_errors_of_the_capture_are_detected() {
+        let detector = Detector::new();
+        assert!(!detector.line("frame=  100 fps= 30 q=-1.0 size=    1024kB"));
+        assert_eq!(detector.reason(), None);
+        let error = "[matroska @ 0x55] Error writing trailer: Stale file handle";
+        // seen, but left to the others
+        assert!(!detector.line(error));
+        assert!(!detector.line("No space left on device"));
+        assert_eq!(detector.reason().as_deref(), Some(error));
+    }
+
+    #[tokio::test]
+    async fn a_capture_that_cannot_go_on_in_the_output_directory_is_seen() {
+        let (primary, fallback) = dirs("failover-exit");
+        let mx = Recorder::new();
+        mx.set(started(&primary, &fallback)).await;
+        let detector = Detector::new();
+        assert_eq!(exit_reason(&mx, &detector).await, None);
+        unwritable(&primary);
+        let reason = exit_reason(&mx, &detector).await.unwrap();
+        assert!(reason.contains("can't be written"), "{}", reason);
+        // what the capture said comes first
+        detector.line("av_interleaved_write_frame(): Input/output error");
+        let reason = exit_reason(&mx, &detector).await.unwrap();
+        assert!(reason.starts_with("av_interleaved_write_frame()"));
+    }
+
+    #[tokio::test]
+    async fn a_recording_fails_over_once() {
+        let (primary, fallback) = dirs("failover-once");
+        let mx = Recorder::new();
+        mx.set(started(&primary, &fallback)).await;
+        let mut events = mx.events.subscribe(None, &RecordingState::Waiting);
+        assert_eq!(announce(&mx, "Stale file handle").await, Some(1));
+        match mx.lock().await.recording() {
+            RecordingState::Started {
+                failover: Some(status),
+                warnings,
+                ..
+            } => {
+                assert!(status.pending && !status.on_primary());
+                assert_eq!(status.reason.as_deref(), Some("Stale file handle"));
+                assert!(warnings[0].starts_with("failing over to"));
+            }
+            other => panic!("{:?}", other),
+        }
+        let notice = loop {
+            if let EventKind::Notice { message } = events.live.recv().await.unwrap().kind {
+                break message;
+            }
+        };
+        assert!(notice.contains("Stale file handle"));
+        assert_eq!(announce(&mx, "again").await, None);
+    }
+
+    #[tokio::test]
+    async fn the_segments_are_gathered_in_the_fallback_while_the_output_is_broken() {
+        let (primary, fallback) = dirs("failover-gather");
+        let segments = [
+            segment(&primary.join("capture.mkv"), 0),
+            segment(&fallback.join("capture.1.mkv"), 5000),
+        ];
+        unwritable(&primary);
+        let dir = finish_dir(&primary, &fallback).await;
+        assert_eq!(dir, fallback);
+        let gathered = gather(&segments, dir).await.unwrap();
+        let files: Vec<&str> = gathered.iter().map(|s| s.file.as_str()).collect();
+        let expected = [fallback.join("capture.mkv"), fallback.join("capture.1.mkv")];
+        assert_eq!(files, expected.each_ref().map(|p| p.to_str().unwrap()));
+        // the stranded one was moved, its content with it
+        assert!(!primary.join("capture.mkv").exists());
+        assert_eq!(
+            std::fs::read_to_string(&expected[0]).unwrap(),
+            segments[0].file
+        );
+        assert_eq!(gathered[1].offset_ms, 5000);
+    }
+
+    #[tokio::test]
+    async fn the_segments_are_brought_back_once_the_output_recovered() {
+        let (primary, fallback) = dirs("failover-recovered");
+        let segments = [
+            segment(&primary.join("capture.mkv"), 0),
+            segment(&fallback.join("capture.1.mkv"), 5000),
+            VideoSegment {
+                file: fallback.join("lost.mkv").to_string_lossy().to_string(),
+                offset_ms: 9000,
+                quality: None,
+            },
+        ];
+        let dir = finish_dir(&primary, &fallback).await;
+        assert_eq!(dir, primary);
+        let gathered = gather(&segments, dir).await.unwrap();
+        // the segment that can't be read is left out
+        let files: Vec<&str> = gathered.iter().map(|s| s.file.as_str()).collect();
+        let expected = [primary.join("capture.mkv"), primary.join("capture.1.mkv")];
+        assert_eq!(files, expected.each_ref().map(|p| p.to_str().unwrap()));
+        assert!(!fallback.join("capture.1.mkv").exists());
+    }
+}
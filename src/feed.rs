@@ -0,0 +1,289 @@
+//! Feeds of the finished recordings, for podcast apps and catalogs
+//!
+//! `/api/feed.json` is a JSON Feed 1.1 and `/api/feed.xml` an RSS 2.0 feed with enclosures, both
+//! made from the history: the newest recordings that are done and still on disk. The URLs in them
+//! are absolute, under the external URL of the server when one is configured. The image of an
+//! item is the first still frame extracted from its recording, when there is one. The recordings
+//! and their frames are looked up in the storage of the recorder.
+//!
+//! A podcast app can't send a bearer token, so with a feed secret configured the feeds want either
+//! the admin token or `?token=`, the [token] signed with that secret. Without a secret they are
+//! as open as the history.
+use crate::auth::constant_time_eq;
+use crate::history::{History, PageQuery};
+use crate::probe::probe;
+use crate::storage::{ObjectInfo, StorageBackend};
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+
+/// items of a feed unless `?limit=` asks for another count
+pub const DEFAULT_ITEMS: usize = 50;
+pub const JSON_FEED_VERSION: &str = "https://jsonfeed.org/version/1.1";
+const TITLE: &str = "Recordings";
+
+/// A recording of the feed
+#[derive(Debug, Clone)]
+pub struct Item {
+    pub id: u64,
+    pub title: String,
+    pub published: DateTime<Local>,
+    pub author: Option<String>,
+    /// seconds
+    pub duration: Option<f64>,
+    pub size: Option<u64>,
+    pub mime_type: &'static str,
+    /// the download of the recording
+    pub url: String,
+    pub image: Option<String>,
+}
+
+/// the token a feed secret signs, the same for every feed
+pub fn token(secret: &str) -> String {
+    hex(&hmac_sha256(secret.as_bytes(), b"feed"))
+}
+
+/// whether the token was signed with the secret
+pub fn verify(secret: &str, token: &str) -> bool {
+    constant_time_eq(&self::token(secret), token)
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    const BLOCK: usize = 64;
+    let mut block = [0u8; BLOCK];
+    if key.len() > BLOCK {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| block.iter().map(|k| k ^ byte).collect::<Vec<u8>>();
+    let inner = Sha256::new()
+        .chain_update(pad(0x36))
+        .chain_update(message)
+        .finalize();
+    Sha256::new()
+        .chain_update(pad(0x5c))
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// a file name as a path segment of a URL
+fn escape_segment(name: &str) -> String {
+    name.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// absolute URL of the download of a file of the output directory
+pub fn download_url(base: &str, name: &str) -> String {
+    format!(
+        "{}/api/recordings/{}/download",
+        base.trim_end_matches('/'),
+        escape_segment(name)
+    )
+}
+
+fn mime_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("webm") => "video/webm",
+        Some("mkv") => "video/x-matroska",
+        _ => "video/mp4",
+    }
+}
+
+/// the first still frame extracted from the recording, of the objects sorted by name
+fn thumbnail(objects: &[ObjectInfo], name: &str) -> Option<String> {
+    let prefix = format!("{}.frame-", name);
+    objects
+        .iter()
+        .find(|object| object.name.starts_with(&prefix))
+        .map(|object| object.name.clone())
+}
+
+/// the newest recordings that are done and still in the storage
+pub async fn items(
+    history: &History,
+    storage: &dyn StorageBackend,
+    base: &str,
+    limit: usize,
+) -> anyhow::Result<Vec<Item>> {
+    let objects = storage.list().await?;
+    let mut query = PageQuery {
+        state: Some("done".to_string()),
+        limit: Some(limit),
+        ..Default::default()
+    };
+    let mut items = vec![];
+    while items.len() < limit {
+        let page = history.page(&query)?;
+        for entry in page.entries {
+            let Some(file) = &entry.file else {
+                continue;
+            };
+            let path = Path::new(file);
+            let name = path
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default();
+            // deleted since
+            let Some(object) = objects.iter().find(|object| object.name == name) else {
+                continue;
+            };
+            if items.len() == limit {
+                continue;
+            }
+            let duration = match storage.local_path(&name).await {
+                Ok(local) => probe(&local).await.ok().and_then(|info| info.duration),
+                Err(_) => None,
+            };
+            let title = path
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_else(|| name.clone());
+            items.push(Item {
+                id: entry.id,
+                title,
+                published: entry.at,
+                author: entry.owner.clone(),
+                duration,
+                size: Some(object.size),
+                mime_type: mime_type(path),
+                url: download_url(base, &name),
+                image: thumbnail(&objects, &name).map(|frame| download_url(base, &frame)),
+            });
+        }
+        match page.next_cursor {
+            Some(cursor) => query.cursor = Some(cursor),
+            None => break,
+        }
+    }
+    Ok(items)
+}
+
+#[derive(Serialize)]
+pub struct JsonFeed {
+    pub version: &'static str,
+    pub title: &'static str,
+    pub home_page_url: String,
+    pub feed_url: String,
+    pub items: Vec<JsonItem>,
+}
+
+#[derive(Serialize)]
+pub struct JsonItem {
+    pub id: String,
+    pub title: String,
+    pub url: String,
+    pub date_published: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub authors: Vec<JsonAuthor>,
+    pub attachments: Vec<JsonAttachment>,
+}
+
+#[derive(Serialize)]
+pub struct JsonAuthor {
+    pub name: String,
+}
+
+#[derive(Serialize)]
+pub struct JsonAttachment {
+    pub url: String,
+    pub mime_type: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size_in_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_in_seconds: Option<f64>,
+}
+
+/// the JSON Feed of the items, `feed_url` being where it is served
+pub fn json_feed(base: &str, feed_url: String, items: Vec<Item>) -> JsonFeed {
+    JsonFeed {
+        version: JSON_FEED_VERSION,
+        title: TITLE,
+        home_page_url: format!("{}/", base.trim_end_matches('/')),
+        feed_url,
+        items: items
+            .into_iter()
+            .map(|item| JsonItem {
+                id: item.id.to_string(),
+                title: item.title,
+                url: item.url.clone(),
+                date_published: item.published.to_rfc3339(),
+                image: item.image,
+                authors: item
+                    .author
+                    .into_iter()
+                    .map(|name| JsonAuthor { name })
+                    .collect(),
+                attachments: vec![JsonAttachment {
+                    url: item.url,
+                    mime_type: item.mime_type,
+                    size_in_bytes: item.size,
+                    duration_in_seconds: item.duration,
+                }],
+            })
+            .collect(),
+    }
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// the RSS 2.0 feed of the items, `feed_url` being where it is served
+pub fn rss(base: &str, feed_url: &str, items: &[Item]) -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <rss version=\"2.0\" xmlns:atom=\"http://www.w3.org/2005/Atom\" \
+         xmlns:itunes=\"http://www.itunes.com/dtds/podcast-1.0.dtd\">\n<channel>\n",
+    );
+    xml += &format!(
+        "<title>{}</title>\n<link>{}/</link>\n<description>The finished recordings</description>\n\
+         <atom:link href=\"{}\" rel=\"self\" type=\"application/rss+xml\"/>\n",
+        TITLE,
+        xml_escape(base.trim_end_matches('/')),
+        xml_escape(feed_url)
+    );
+    for item in items {
+        xml += "<item>\n";
+        xml += &format!("<title>{}</title>\n", xml_escape(&item.title));
+        xml += &format!("<link>{}</link>\n", xml_escape(&item.url));
+        xml += &format!("<guid isPermaLink=\"false\">{}</guid>\n", item.id);
+        xml += &format!("<pubDate>{}</pubDate>\n", item.published.to_rfc2822());
+        if let Some(author) = &item.author {
+            xml += &format!("<itunes:author>{}</itunes:author>\n", xml_escape(author));
+        }
+        xml += &format!(
+            "<enclosure url=\"{}\" length=\"{}\" type=\"{}\"/>\n",
+            xml_escape(&item.url),
+            item.size.unwrap_or(0),
+            item.mime_type
+        );
+        if let Some(duration) = item.duration {
+            xml += &format!("<itunes:duration>{}</itunes:duration>\n", duration.round());
+        }
+        if let Some(image) = &item.image {
+            xml += &format!("<itunes:image href=\"{}\"/>\n", xml_escape(image));
+        }
+        xml += "</item>\n";
+    }
+    xml += "</channel>\n</rss>\n";
+    xml
+}
@@ -1,14 +1,13 @@
 //! Wraps the ffpmeg cli, using `-progress` to report progress
 //!
 //! Sometimes you just want a simple way to use ffmpeg. Most crates just use ffi, leading to
-//! complicated interfaces. `ffmpeg_cli` avoids this by wrapping the cli, for when you don't need
+//! complicated interfaces. This module avoids this by wrapping the cli, for when you don't need
 //! the flexibility the real ffmpeg api gives you.
 //!
 //! ```no_run
 //! use std::process::Stdio;
 //!
-//! use ffmpeg_cli::{FfmpegBuilder, File, Parameter};
-//! use futures::{future::ready, StreamExt};
+//! use record_screen::ffmpeg::{FfmpegBuilder, File, Parameter};
 //!
 //! #[tokio::main]
 //! async fn main() {
@@ -19,32 +18,37 @@
 //!         .input(File::new("input.mkv"))
 //!         .output(
 //!             File::new("output.mp4")
-//!                 .option(Parameter::KeyValue("vcodec", "libx265"))
-//!                 .option(Parameter::KeyValue("crf", "28")),
+//!                 .option(Parameter::Repeated("map", vec!["0:v", "0:a"]))
+//!                 .option(Parameter::StreamSpec {
+//!                     base: "c",
+//!                     specifier: "v",
+//!                     value: "libx265",
+//!                 })
+//!                 .option(Parameter::KeyValue("crf", "28"))
+//!                 .option(Parameter::Single("sn")),
 //!         );
 //!
 //!     let ffmpeg = builder.run().await.unwrap();
 //!
-//!     ffmpeg
-//!         .progress
-//!         .for_each(|x| {
-//!             dbg!(x.unwrap());
-//!             ready(())
+//!     let summary = ffmpeg
+//!         .wait_with_progress(|progress| {
+//!             dbg!(progress);
 //!         })
-//!         .await;
-//!
-//!     let output = ffmpeg.process.wait_with_output().unwrap();
+//!         .await
+//!         .unwrap();
 //!
 //!     println!(
-//!         "{}\nstderr:\n{}",
-//!         output.status,
-//!         std::str::from_utf8(&output.stderr).unwrap()
+//!         "{} in {:?}\nstderr:\n{}",
+//!         summary.exit_status,
+//!         summary.wall_time,
+//!         summary.stderr_tail.join("\n")
 //!     );
 //! }
 //! ```
 #![warn(missing_docs)]
 
-use std::process::{Command, Stdio};
+use std::process::Stdio;
+use tokio::process::Command;
 
 #[doc(inline)]
 pub use crate::runner::*;
@@ -69,12 +73,14 @@ pub struct FfmpegBuilder<'a> {
     pub stdout: Stdio,
     /// Passed as [Command::stderr]
     pub stderr: Stdio,
+    /// Secrets of the arguments and what is shown instead, in [Ffmpeg::argv] and the log.
+    pub redactions: Vec<(&'a str, &'a str)>,
 }
 
 /// A file that ffmpeg operates on.
 ///
 /// This can be an input or output, it depends on what you add it as.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct File<'a> {
     /// The url of the file.
     ///
@@ -85,9 +91,14 @@ pub struct File<'a> {
 }
 
 /// A global or file option to be passed to ffmpeg.
-#[derive(Debug)]
+///
+/// The options of a file are passed in the order they were added. An option given twice the
+/// same way is passed once, unless ffmpeg takes every occurrence (`-map`, `-metadata`, ...); a
+/// codec or a format given two different ways for the same file is refused by
+/// [FfmpegBuilder::to_command].
+#[derive(Debug, Clone, PartialEq)]
 pub enum Parameter<'a> {
-    /// An option which does not take a value, ex. `-autorotate`.
+    /// A flag, an option which does not take a value, ex. `-autorotate`.
     ///
     /// `-autorotate` would be represented as `Single("autorotate")`,
     /// as the `-` is inserted automatically.
@@ -97,6 +108,28 @@ pub enum Parameter<'a> {
     /// `-t 10` would be represented as `KeyValue("t", "10")`, as
     /// the `-` is inserted automatically.
     KeyValue(&'a str, &'a str),
+    /// An option given once per value, ex. `-map 0:v -map 0:a`.
+    ///
+    /// `-map 0:v -map 0:a` would be represented as `Repeated("map", vec!["0:v", "0:a"])`.
+    Repeated(&'a str, Vec<&'a str>),
+    /// An option for some of the streams, ex. `-c:v libx264` or `-metadata:s:a:0 language=eng`.
+    ///
+    /// `-c:v libx264` would be represented as
+    /// `StreamSpec { base: "c", specifier: "v", value: "libx264" }`.
+    StreamSpec {
+        /// The option, ex. `c`.
+        base: &'a str,
+        /// The streams it is for, ex. `v` or `s:a:0`.
+        specifier: &'a str,
+        /// Its value.
+        value: &'a str,
+    },
+}
+
+impl<'a> Default for FfmpegBuilder<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<'a> FfmpegBuilder<'a> {
@@ -111,6 +144,7 @@ impl<'a> FfmpegBuilder<'a> {
             stdin: Stdio::null(),
             stdout: Stdio::null(),
             stderr: Stdio::null(),
+            redactions: Vec::new(),
         }
     }
 
@@ -163,37 +197,181 @@ impl<'a> FfmpegBuilder<'a> {
         self
     }
 
+    /// Shows `shown` instead of `secret` wherever the arguments are shown.
+    pub fn redact(mut self, secret: &'a str, shown: &'a str) -> Self {
+        self.redactions.push((secret, shown));
+
+        self
+    }
+
     /// Turns it into a command, consuming the builder.
     ///
     /// This has to consume the builder for stdin, etc to work
     /// Note that usually you want to use [`Self::run()`], not call this directly
-    pub fn to_command(self) -> Command {
+    ///
+    /// Fails with [Error::Conflict] when a file is given two different codecs or formats
+    /// for the same streams.
+    pub fn to_command(self) -> Result<Command, Error> {
         let mut command = Command::new(self.ffmpeg_command);
 
-        for option in self.options {
-            option.push_to(&mut command);
+        // the options up to an `-i` or a file are those of that file
+        let mut scope = Scope::default();
+        for option in &self.options {
+            scope.push(option, &mut command)?;
         }
-        for input in self.inputs {
-            input.push_to(&mut command, true);
+        for input in &self.inputs {
+            input.push_to(&mut scope, &mut command, true)?;
         }
-        for option in self.options2 {
-            option.push_to(&mut command);
+        for option in &self.options2 {
+            scope.push(option, &mut command)?;
         }
-        for output in self.outputs {
-            output.push_to(&mut command, false)
+        for output in &self.outputs {
+            output.push_to(&mut scope, &mut command, false)?;
+            debug_assert_eq!(
+                command.as_std().get_args().last().and_then(|a| a.to_str()),
+                Some(output_path(output.url).as_ref()),
+                "an output must be a single argument"
+            );
         }
 
         command.stdin(self.stdin);
         command.stdout(self.stdout);
         command.stderr(self.stderr);
 
-        command
+        Ok(command)
+    }
+}
+
+/// The options a file was given so far.
+#[derive(Default)]
+struct Scope<'b, 'a> {
+    options: Vec<&'b Parameter<'a>>,
+}
+
+impl<'b, 'a> Scope<'b, 'a> {
+    /// Passes the option unless the file has it already, moving on to the next file after `-i`.
+    fn push(&mut self, option: &'b Parameter<'a>, command: &mut Command) -> Result<(), Error> {
+        if option.repeatable() || !self.options.contains(&option) {
+            if let Some((key, value)) = option.selection() {
+                let other = self
+                    .options
+                    .iter()
+                    .filter_map(|o| o.selection())
+                    .find(|(k, v)| *k == key && *v != value);
+                if let Some((_, other)) = other {
+                    return Err(Error::Conflict(format!(
+                        "-{} is {} and {} for the same file",
+                        key, other, value
+                    )));
+                }
+            }
+            option.push_to(command);
+            self.options.push(option);
+        }
+        if matches!(option, Parameter::KeyValue("i", _)) {
+            self.end();
+        }
+        Ok(())
+    }
+
+    /// The file was passed, the next options are for another one.
+    fn end(&mut self) {
+        self.options.clear();
     }
 }
 
+/// The program and the arguments a command was configured with.
+pub fn argv(command: &Command) -> Vec<String> {
+    let command = command.as_std();
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|a| a.to_string_lossy().to_string())
+        .collect()
+}
+
+/// Joins an argv into a line a POSIX shell runs as the same command.
+///
+/// Arguments with anything but a few safe characters are single quoted.
+pub fn shell_quote(argv: &[String]) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_-+=/.,:@%^".contains(c);
+    argv.iter()
+        .map(|a| {
+            if !a.is_empty() && a.chars().all(safe) {
+                a.clone()
+            } else {
+                format!("'{}'", a.replace('\'', "'\\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Makes a relative output path that starts with `-` start with `./` instead.
+///
+/// Ffmpeg would take it for an option otherwise. URLs and other paths are left alone.
+pub fn output_path(url: &str) -> std::borrow::Cow<'_, str> {
+    if url.starts_with('-') && url != "-" {
+        format!("./{}", url).into()
+    } else {
+        url.into()
+    }
+}
+
+/// Escapes a value of a filter option, so that `\`, `'` and `:` are taken literally.
+///
+/// This is the first level of the filter escaping; the filter description it ends up in still
+/// needs [escape_filtergraph].
+pub fn escape_filter_option(value: &str) -> String {
+    escape(value, &['\\', '\'', ':'])
+}
+
+/// Escapes a filter description for a filtergraph, so that `\`, `'`, `[`, `]`, `,` and `;`
+/// are taken literally.
+///
+/// This is the second level of the filter escaping, over a value that passed
+/// [escape_filter_option].
+pub fn escape_filtergraph(description: &str) -> String {
+    escape(description, &['\\', '\'', '[', ']', ',', ';'])
+}
+
+/// Escapes a text to be shown by `drawtext` as it is, for a `-vf` argument.
+///
+/// On top of both levels of the filter escaping, `%` would start an expansion and `\` escapes
+/// it in the text itself. A carriage return is dropped, a newline stays a line break.
+pub fn escape_drawtext(text: &str) -> String {
+    escape_drawtext_expansions(&escape(text, &['\\', '%']))
+}
+
+/// Escapes a text of `drawtext` with its expansions for a `-vf` argument, `%{localtime}` being
+/// expanded.
+///
+/// Like [escape_drawtext] but for the text itself: `%` and `\` go as they are, to start an
+/// expansion and to escape in it.
+pub fn escape_drawtext_expansions(text: &str) -> String {
+    let text: String = text.chars().filter(|c| *c != '\r').collect();
+    escape_filtergraph(&escape_filter_option(&text))
+}
+
+/// Escapes a file name for the outputs of the tee muxer, so that `\`, `|`, `[` and `]` are
+/// taken literally.
+pub fn escape_tee(file: &str) -> String {
+    escape(file, &['\\', '|', '[', ']'])
+}
+
+fn escape(value: &str, special: &[char]) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        if special.contains(&c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
 impl<'a> File<'a> {
     /// Gets a file without any options set.
-    pub fn new(url: &'a str) -> File {
+    pub fn new(url: &'a str) -> File<'a> {
         File {
             url,
             options: Vec::new(),
@@ -207,18 +385,31 @@ impl<'a> File<'a> {
         self
     }
 
-    fn push_to(&self, command: &mut Command, input: bool) {
+    fn push_to<'b>(
+        &'b self,
+        scope: &mut Scope<'b, 'a>,
+        command: &mut Command,
+        input: bool,
+    ) -> Result<(), Error> {
         for option in &self.options {
-            option.push_to(command);
+            scope.push(option, command)?;
         }
 
         if input {
             command.arg("-i");
+            command.arg(self.url);
+        } else {
+            // an output is positional, nothing but a path may start with a dash
+            command.arg(output_path(self.url).as_ref());
         }
-        command.arg(&self.url);
+        scope.end();
+        Ok(())
     }
 }
 
+/// Options ffmpeg takes every occurrence of, rather than the last one.
+const REPEATABLE: &[&str] = &["map", "metadata", "filter_complex", "attach", "i"];
+
 impl<'a> Parameter<'a> {
     fn push_to(&self, command: &mut Command) {
         match &self {
@@ -227,6 +418,367 @@ impl<'a> Parameter<'a> {
                 command.arg("-".to_owned() + key);
                 command.arg(value)
             }
+            Parameter::Repeated(key, values) => {
+                for value in values {
+                    command.arg("-".to_owned() + key);
+                    command.arg(value);
+                }
+                command
+            }
+            Parameter::StreamSpec {
+                base,
+                specifier,
+                value,
+            } => {
+                command.arg(format!("-{}:{}", base, specifier));
+                command.arg(value)
+            }
         };
     }
+
+    /// The codec of some of the streams, ex. `codec("v", "libx264")` for `-c:v libx264`.
+    pub fn codec(specifier: &'a str, value: &'a str) -> Self {
+        Parameter::StreamSpec {
+            base: "c",
+            specifier,
+            value,
+        }
+    }
+
+    /// The name of the option, without its stream specifier.
+    pub fn base(&self) -> &'a str {
+        match self {
+            Parameter::Single(key) | Parameter::KeyValue(key, _) | Parameter::Repeated(key, _) => {
+                key.split(':').next().unwrap_or(key)
+            }
+            Parameter::StreamSpec { base, .. } => base,
+        }
+    }
+
+    /// Whether it selects a codec or a format, which a file can only be given one way.
+    pub fn is_selection(&self) -> bool {
+        self.selection().is_some()
+    }
+
+    fn repeatable(&self) -> bool {
+        matches!(self, Parameter::Repeated(..)) || REPEATABLE.contains(&self.base())
+    }
+
+    /// The codec or the format it selects, under a name shared by its spellings (`-vcodec` is
+    /// `-c:v`), None for the other options.
+    fn selection(&self) -> Option<(String, &'a str)> {
+        let (key, value) = match self {
+            Parameter::KeyValue(key, value) => (key.to_string(), *value),
+            Parameter::StreamSpec {
+                base,
+                specifier,
+                value,
+            } => (format!("{}:{}", base, specifier), *value),
+            _ => return None,
+        };
+        let key = match key.as_str() {
+            "vcodec" => "c:v".to_string(),
+            "acodec" => "c:a".to_string(),
+            "scodec" => "c:s".to_string(),
+            "f" => key,
+            _ => match key.split_once(':') {
+                Some(("c" | "codec", specifier)) => format!("c:{}", specifier),
+                None if key == "c" || key == "codec" => "c".to_string(),
+                _ => return None,
+            },
+        };
+        Some((key, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the arguments of the command, without the program
+    fn args(builder: FfmpegBuilder) -> Vec<String> {
+        argv(&builder.to_command().unwrap())[1..].to_vec()
+    }
+
+    fn output_args(options: Vec<Parameter>) -> Vec<String> {
+        let output = options
+            .into_iter()
+            .fold(File::new("out.mp4"), |file, option| file.option(option));
+        args(FfmpegBuilder::new().output(output))
+    }
+
+    #[test]
+    fn every_parameter_is_passed_as_ffmpeg_takes_it() {
+        assert_eq!(output_args(vec![Parameter::Single("y")]), ["-y", "out.mp4"]);
+        assert_eq!(
+            output_args(vec![Parameter::KeyValue("t", "10")]),
+            ["-t", "10", "out.mp4"]
+        );
+        assert_eq!(
+            output_args(vec![Parameter::Repeated("map", vec!["0:v", "1:a"])]),
+            ["-map", "0:v", "-map", "1:a", "out.mp4"]
+        );
+        assert_eq!(
+            output_args(vec![Parameter::codec("v", "libx264")]),
+            ["-c:v", "libx264", "out.mp4"]
+        );
+        assert_eq!(
+            output_args(vec![Parameter::StreamSpec {
+                base: "metadata",
+                specifier: "s:a:0",
+                value: "language=eng",
+            }]),
+            ["-metadata:s:a:0", "language=eng", "out.mp4"]
+        );
+    }
+
+    #[test]
+    fn the_options_go_before_their_files() {
+        let builder = FfmpegBuilder::new()
+            .option(Parameter::Single("y"))
+            .input(File::new("in.mkv").option(Parameter::KeyValue("f", "matroska")))
+            .option2(Parameter::KeyValue("filter_complex", "[0:v]null[v]"))
+            .output(File::new("out.mp4").option(Parameter::KeyValue("f", "mp4")));
+        assert_eq!(
+            args(builder),
+            [
+                "-y",
+                "-f",
+                "matroska",
+                "-i",
+                "in.mkv",
+                "-filter_complex",
+                "[0:v]null[v]",
+                "-f",
+                "mp4",
+                "out.mp4"
+            ]
+        );
+    }
+
+    #[test]
+    fn an_option_given_twice_is_passed_once() {
+        assert_eq!(
+            output_args(vec![
+                Parameter::KeyValue("t", "10"),
+                Parameter::Single("shortest"),
+                Parameter::KeyValue("t", "10"),
+                Parameter::Single("shortest"),
+            ]),
+            ["-t", "10", "-shortest", "out.mp4"]
+        );
+    }
+
+    #[test]
+    fn a_repeatable_option_is_passed_every_time() {
+        assert_eq!(
+            output_args(vec![
+                Parameter::KeyValue("map", "0:v"),
+                Parameter::KeyValue("map", "0:v"),
+                Parameter::Repeated("map", vec!["1:a"]),
+                Parameter::Repeated("map", vec!["1:a"]),
+            ]),
+            ["-map", "0:v", "-map", "0:v", "-map", "1:a", "-map", "1:a", "out.mp4"]
+        );
+    }
+
+    #[test]
+    fn every_file_has_options_of_its_own() {
+        let builder = FfmpegBuilder::new()
+            .input(File::new("a.mkv").option(Parameter::KeyValue("f", "matroska")))
+            .input(File::new("b.mkv").option(Parameter::KeyValue("f", "matroska")))
+            .output(File::new("a.mp4").option(Parameter::codec("v", "libx264")))
+            .output(File::new("b.webm").option(Parameter::codec("v", "libvpx")));
+        assert_eq!(
+            args(builder),
+            [
+                "-f", "matroska", "-i", "a.mkv", "-f", "matroska", "-i", "b.mkv", "-c:v",
+                "libx264", "a.mp4", "-c:v", "libvpx", "b.webm"
+            ]
+        );
+    }
+
+    #[test]
+    fn an_output_starting_with_a_dash_is_a_path() {
+        assert_eq!(
+            args(FfmpegBuilder::new().output(File::new("-x.mp4"))),
+            ["./-x.mp4"]
+        );
+        assert_eq!(args(FfmpegBuilder::new().output(File::new("-"))), ["-"]);
+    }
+
+    fn conflict(options: Vec<Parameter>) -> Option<String> {
+        let output = options
+            .into_iter()
+            .fold(File::new("out.mp4"), |file, option| file.option(option));
+        match FfmpegBuilder::new().output(output).to_command() {
+            Err(Error::Conflict(message)) => Some(message),
+            Err(e) => panic!("expected a conflict, got {}", e),
+            Ok(_) => None,
+        }
+    }
+
+    #[test]
+    fn a_format_given_two_ways_is_refused() {
+        let message = conflict(vec![
+            Parameter::KeyValue("f", "mp4"),
+            Parameter::KeyValue("f", "matroska"),
+        ]);
+        assert_eq!(
+            message.as_deref(),
+            Some("-f is mp4 and matroska for the same file")
+        );
+    }
+
+    #[test]
+    fn a_codec_given_two_ways_is_refused_whatever_its_spelling() {
+        assert!(conflict(vec![
+            Parameter::KeyValue("vcodec", "libx264"),
+            Parameter::codec("v", "libx265"),
+        ])
+        .is_some());
+        assert!(conflict(vec![
+            Parameter::KeyValue("c:a", "aac"),
+            Parameter::KeyValue("acodec", "libopus"),
+        ])
+        .is_some());
+        assert!(conflict(vec![
+            Parameter::KeyValue("codec", "copy"),
+            Parameter::KeyValue("c", "libx264"),
+        ])
+        .is_some());
+    }
+
+    #[test]
+    fn the_same_codec_or_other_streams_are_no_conflict() {
+        assert!(conflict(vec![
+            Parameter::KeyValue("vcodec", "libx264"),
+            Parameter::codec("v", "libx264"),
+        ])
+        .is_none());
+        assert!(conflict(vec![
+            Parameter::codec("v", "libx264"),
+            Parameter::codec("a", "aac")
+        ])
+        .is_none());
+        // the input and the output are different files
+        let builder = FfmpegBuilder::new()
+            .input(File::new("in.mkv").option(Parameter::KeyValue("f", "matroska")))
+            .output(File::new("out.mp4").option(Parameter::KeyValue("f", "mp4")));
+        assert!(builder.to_command().is_ok());
+    }
+
+    /// values that mean something to one of the levels of the filter escaping
+    const SPECIAL: &[&str] = &[
+        "'",
+        ":",
+        "\\",
+        ";",
+        ",",
+        "[",
+        "]",
+        "=",
+        "\n",
+        "it's 12:30",
+        "C:\\Users\\me",
+        "a'b:c\\d;e,f[g]h",
+        "\\'",
+        "''",
+        "../../etc/passwd",
+        "x:enable=0,movie=../../etc/passwd[y];[y]overlay",
+        "",
+    ];
+
+    /// a token up to one of `terms` as ffmpeg reads it (`av_get_token`): a `\` takes the next
+    /// character as it is and a quote everything up to the next quote, returning what is left
+    fn token<'s>(text: &'s str, terms: &[char]) -> (String, &'s str) {
+        let mut out = String::new();
+        let mut chars = text.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => out.extend(chars.next().map(|(_, c)| c)),
+                '\'' => out.extend(chars.by_ref().map(|(_, c)| c).take_while(|c| *c != '\'')),
+                c if terms.contains(&c) => return (out, &text[i..]),
+                c => out.push(c),
+            }
+        }
+        (out, "")
+    }
+
+    /// the value of an option of a filter in a filtergraph, as ffmpeg reads it back
+    fn read_back(escaped: &str) -> String {
+        let (description, rest) = token(escaped, &['[', ']', ',', ';']);
+        assert_eq!(rest, "", "{} ends the filter", escaped);
+        let (value, rest) = token(&description, &[':']);
+        assert_eq!(rest, "", "{} ends the option", description);
+        value
+    }
+
+    #[test]
+    fn a_filter_option_is_escaped_at_its_level() {
+        assert_eq!(escape_filter_option("a:b"), "a\\:b");
+        assert_eq!(escape_filter_option("it's"), "it\\'s");
+        assert_eq!(escape_filter_option("C:\\x"), "C\\:\\\\x");
+        assert_eq!(escape_filter_option("a;b,c[d]\n"), "a;b,c[d]\n");
+    }
+
+    #[test]
+    fn a_filtergraph_is_escaped_at_its_level() {
+        assert_eq!(escape_filtergraph("a;b,c[d]"), "a\\;b\\,c\\[d\\]");
+        assert_eq!(escape_filtergraph("a\\:b"), "a\\\\:b");
+        assert_eq!(escape_filtergraph("'"), "\\'");
+        assert_eq!(escape_filtergraph("a:b=c\n"), "a:b=c\n");
+    }
+
+    #[test]
+    fn both_levels_read_back_as_the_value() {
+        for value in SPECIAL {
+            let escaped = escape_filtergraph(&escape_filter_option(value));
+            assert_eq!(read_back(&escaped), *value, "escaped as {}", escaped);
+        }
+    }
+
+    #[test]
+    fn a_value_cannot_add_filters_or_options() {
+        let escaped =
+            escape_filtergraph(&escape_filter_option("x:enable=0,movie=../../etc/passwd"));
+        assert_eq!(escaped, "x\\\\:enable=0\\,movie=../../etc/passwd");
+        let filter = format!("drawtext=text={}:fontsize=24,null", escaped);
+        let (first, rest) = token(&filter, &[',']);
+        assert_eq!(rest, ",null");
+        let (text, rest) = token(&first, &[':']);
+        assert_eq!(text, "drawtext=text=x:enable=0,movie=../../etc/passwd");
+        assert_eq!(rest, ":fontsize=24");
+    }
+
+    #[test]
+    fn a_drawtext_is_shown_as_it_is() {
+        for value in SPECIAL.iter().chain(&["100%", "%{localtime}", "50 % \\%"]) {
+            let escaped = escape_drawtext(value);
+            // drawtext itself takes a `\` for the next character, a bare `%` is an expansion
+            let text = read_back(&escaped);
+            let mut shown = String::new();
+            let mut chars = text.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => shown.extend(chars.next()),
+                    '%' => panic!("{} expands in {}", value, escaped),
+                    c => shown.push(c),
+                }
+            }
+            assert_eq!(shown, *value, "escaped as {}", escaped);
+        }
+    }
+
+    #[test]
+    fn a_drawtext_drops_carriage_returns() {
+        assert_eq!(escape_drawtext("a\r\nb\r"), "a\nb");
+        assert_eq!(escape_drawtext("100%"), "100\\\\\\\\%");
+    }
+
+    #[test]
+    fn a_tee_file_is_escaped() {
+        assert_eq!(escape_tee("a|b[c]\\d"), "a\\|b\\[c\\]\\\\d");
+        assert_eq!(escape_tee("../out.mp4"), "../out.mp4");
+    }
 }
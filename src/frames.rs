@@ -0,0 +1,322 @@
+//! Still frames extracted from a recording, e.g. to diff them against golden images in CI
+//!
+//! Positions are `first`, `last`, seconds (`12.5s` or `12.5`) or a percentage of the
+//! duration (`50%`). The images are written next to the recording as
+//! `<recording>.frame-<position>.<ext>`.
+use crate::ffmpeg::{FfmpegBuilder, File, Parameter};
+use crate::probe::probe;
+use crate::service::{ChildRole, Children};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::process::Stdio;
+use thiserror::Error;
+
+/// Format of the extracted images
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    #[default]
+    Png,
+    Jpeg,
+}
+
+impl ImageFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Jpeg => "jpg",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FramesRequest {
+    pub positions: Vec<String>,
+    #[serde(default)]
+    pub format: ImageFormat,
+    /// scale the images to this width, keeping the aspect ratio
+    #[serde(default)]
+    pub width: Option<u32>,
+}
+
+/// An extracted image
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Frame {
+    pub position: String,
+    pub file: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+/// A problem with one field of a request
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("invalid positions")]
+    InvalidPositions(Vec<FieldError>),
+    #[error("{0}")]
+    Other(#[from] anyhow::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Position {
+    First,
+    Last,
+    At(f64),
+}
+
+fn parse_position(position: &str, duration: f64) -> Result<Position, String> {
+    match position {
+        "first" => return Ok(Position::First),
+        "last" => return Ok(Position::Last),
+        _ => {}
+    }
+    if let Some(percent) = position.strip_suffix('%') {
+        let percent: f64 = percent.parse().map_err(|_| "not a percentage")?;
+        if !(0.0..=100.0).contains(&percent) {
+            return Err("percentage must be within 0..100".to_string());
+        }
+        return Ok(Position::At(duration * percent / 100.0));
+    }
+    let seconds: f64 = position
+        .strip_suffix('s')
+        .unwrap_or(position)
+        .parse()
+        .map_err(|_| "expected first, last, seconds or percentage")?;
+    if seconds < 0.0 {
+        return Err("position is negative".to_string());
+    }
+    if seconds > duration {
+        return Err(format!(
+            "position is beyond the duration of {:.3}s",
+            duration
+        ));
+    }
+    Ok(Position::At(seconds))
+}
+
+/// part of the file name for the position
+fn label(position: &str) -> String {
+    position
+        .replace('%', "pct")
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// extract the frames at the requested positions of the recording
+pub async fn extract(
+    source: &Path,
+    req: &FramesRequest,
+    children: &Children,
+) -> Result<Vec<Frame>, Error> {
+    if req.positions.is_empty() {
+        return Err(Error::InvalidPositions(vec![FieldError {
+            field: "positions".to_string(),
+            message: "at least one position is required".to_string(),
+        }]));
+    }
+    let duration = probe(source).await?.duration.unwrap_or_default();
+    let mut positions = vec![];
+    let mut errors = vec![];
+    for (i, position) in req.positions.iter().enumerate() {
+        match parse_position(position, duration) {
+            Ok(p) => positions.push((position, p)),
+            Err(message) => errors.push(FieldError {
+                field: format!("positions[{}]", i),
+                message,
+            }),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(Error::InvalidPositions(errors));
+    }
+
+    let mut frames = vec![];
+    for (position, at) in positions {
+        let file = format!(
+            "{}.frame-{}.{}",
+            source.to_string_lossy(),
+            label(position),
+            req.format.extension()
+        );
+        extract_one(source, at, &file, req, children).await?;
+        let info = probe(Path::new(&file)).await?;
+        frames.push(Frame {
+            position: position.clone(),
+            file,
+            width: info.width,
+            height: info.height,
+        });
+    }
+    Ok(frames)
+}
+
+async fn extract_one(
+    source: &Path,
+    at: Position,
+    file: &str,
+    req: &FramesRequest,
+    children: &Children,
+) -> anyhow::Result<()> {
+    let source = source.to_string_lossy();
+    let seek = match at {
+        Position::At(seconds) => format!("{:.3}", seconds),
+        _ => String::new(),
+    };
+    let scale = req.width.map(|w| format!("scale={}:-2", w));
+
+    let mut builder = FfmpegBuilder::new()
+        .stderr(Stdio::piped())
+        .option(Parameter::Single("y"));
+    builder = match at {
+        Position::First => builder
+            .input(File::new(&source))
+            .option2(Parameter::StreamSpec {
+                base: "frames",
+                specifier: "v",
+                value: "1",
+            }),
+        // the exact duration is unreliable to seek to: decode the last second,
+        // overwriting the image with every frame
+        Position::Last => builder
+            .input(File::new(&source).option(Parameter::KeyValue("sseof", "-1")))
+            .option2(Parameter::KeyValue("update", "1")),
+        Position::At(_) => builder
+            .input(File::new(&source).option(Parameter::KeyValue("ss", &seek)))
+            .option2(Parameter::StreamSpec {
+                base: "frames",
+                specifier: "v",
+                value: "1",
+            }),
+    };
+    if let Some(scale) = &scale {
+        builder = builder.option2(Parameter::KeyValue("vf", scale));
+    }
+    if req.format == ImageFormat::Jpeg {
+        builder = builder.option2(Parameter::StreamSpec {
+            base: "q",
+            specifier: "v",
+            value: "2",
+        });
+    }
+    let mut command = builder.output(File::new(file)).to_command()?;
+    let child = command.spawn()?;
+    let pid = child.id().unwrap_or_default();
+    children.register(pid, ChildRole::Frames, vec![file.to_string()]);
+    let output = child.wait_with_output().await;
+    children.unregister(pid);
+    let output = output?;
+    if !output.status.success() || !Path::new(file).exists() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let tail: Vec<&str> = stderr.lines().rev().take(5).collect();
+        anyhow::bail!(
+            "cannot extract frame: {}",
+            tail.into_iter().rev().collect::<Vec<_>>().join("\n")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_positions_are_parsed_against_the_duration() {
+        assert_eq!(parse_position("first", 10.0), Ok(Position::First));
+        assert_eq!(parse_position("last", 10.0), Ok(Position::Last));
+        assert_eq!(parse_position("12.5s", 20.0), Ok(Position::At(12.5)));
+        assert_eq!(parse_position("12.5", 20.0), Ok(Position::At(12.5)));
+        assert_eq!(parse_position("50%", 10.0), Ok(Position::At(5.0)));
+    }
+
+    #[test]
+    fn the_invalid_positions_are_refused() {
+        assert!(parse_position("-1s", 10.0)
+            .unwrap_err()
+            .contains("negative"));
+        assert!(parse_position("11s", 10.0).unwrap_err().contains("beyond"));
+        assert!(parse_position("101%", 10.0).is_err());
+        assert!(parse_position("middle", 10.0).is_err());
+    }
+
+    #[test]
+    fn the_file_names_are_predictable() {
+        assert_eq!(label("first"), "first");
+        assert_eq!(label("50%"), "50pct");
+        assert_eq!(label("12.5s"), "12.5s");
+        assert_eq!(label("1/2"), "1_2");
+    }
+
+    #[tokio::test]
+    async fn no_position_is_a_field_error() {
+        let req = FramesRequest {
+            positions: vec![],
+            format: ImageFormat::Png,
+            width: None,
+        };
+        let e = extract(Path::new("missing.mp4"), &req, &Children::default()).await;
+        match e {
+            Err(Error::InvalidPositions(errors)) => assert_eq!(errors[0].field, "positions"),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn the_first_middle_and_last_frames_of_a_testsrc_are_extracted() {
+        if std::process::Command::new("ffmpeg")
+            .arg("-version")
+            .output()
+            .is_err()
+        {
+            eprintln!("ffmpeg is not installed, skipped");
+            return;
+        }
+        let dir = crate::recordings::test_output_dir().join("frames");
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("testsrc.mp4");
+        let status = tokio::process::Command::new("ffmpeg")
+            .args(["-v", "error", "-y", "-f", "lavfi"])
+            .args(["-i", "testsrc=duration=3:size=320x240:rate=10"])
+            .arg(&source)
+            .status()
+            .await
+            .unwrap();
+        assert!(status.success());
+
+        let req = FramesRequest {
+            positions: vec!["first".into(), "50%".into(), "last".into()],
+            format: ImageFormat::Png,
+            width: Some(160),
+        };
+        let frames = extract(&source, &req, &Children::default()).await.unwrap();
+        assert_eq!(frames.len(), 3);
+        for frame in &frames {
+            assert_eq!((frame.width, frame.height), (Some(160), Some(120)));
+            // decodable, not only written
+            let info = probe(Path::new(&frame.file)).await.unwrap();
+            assert_eq!(info.width, Some(160));
+        }
+        assert!(frames[1].file.ends_with("testsrc.mp4.frame-50pct.png"));
+        let beyond = FramesRequest {
+            positions: vec!["first".into(), "4s".into()],
+            ..req
+        };
+        match extract(&source, &beyond, &Children::default()).await {
+            Err(Error::InvalidPositions(errors)) => assert_eq!(errors[0].field, "positions[1]"),
+            other => panic!("{:?}", other),
+        }
+    }
+}
@@ -0,0 +1,371 @@
+//! Watching the screen layout while it is captured
+//!
+//! Docking a laptop removes the captured monitor or changes the resolution, and x11grab keeps
+//! grabbing a region that no longer means anything. While a screen recording is started,
+//! [watch] polls `xrandr` and compares what the capture region shows with what it showed at the
+//! start. A change is logged, kept as a warning and a marker of the recording, announced to the
+//! streaming clients, and then handled as [GeometryPolicy] says. Losing the X connection stops
+//! the recording as a display failure.
+use crate::display;
+use crate::events::EventKind;
+use crate::quality;
+use crate::service::{
+    since, stop_for, Recorder, RecordingOptions, RecordingState, StopReason, VIDEO_SIZE,
+};
+use crate::source::CaptureSource;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::*;
+
+/// how often the layout is polled
+const POLL_EVERY: Duration = Duration::from_secs(2);
+
+/// What to do when the captured region changes
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum GeometryPolicy {
+    /// warn, and keep capturing the same region
+    #[default]
+    Continue,
+    /// warn, and stop the recording cleanly
+    Stop,
+}
+
+/// A rectangle of the X screen
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Rect {
+    pub width: u32,
+    pub height: u32,
+    pub x: i32,
+    pub y: i32,
+}
+
+impl Rect {
+    /// `1920x1080+0+0`, the offsets being optional
+    pub fn parse(s: &str) -> Option<Self> {
+        let (size, offsets) = match s.find(['+', '-']) {
+            Some(i) => s.split_at(i),
+            None => (s, "+0+0"),
+        };
+        let (width, height) = size.split_once('x')?;
+        // the sign belongs to each offset
+        let split = offsets[1..].find(['+', '-'])? + 1;
+        let (x, y) = offsets.split_at(split);
+        Some(Self {
+            width: width.parse().ok()?,
+            height: height.parse().ok()?,
+            x: x.parse().ok()?,
+            y: y.parse().ok()?,
+        })
+    }
+
+    /// the rectangle cut down to an even width and height
+    pub fn even(&self) -> Rect {
+        Rect {
+            width: self.width & !1,
+            height: self.height & !1,
+            ..*self
+        }
+    }
+
+    /// whether all of `other` is in the rectangle
+    pub fn contains(&self, other: &Rect) -> bool {
+        other.x >= self.x
+            && other.y >= self.y
+            && other.x + other.width as i32 <= self.x + self.width as i32
+            && other.y + other.height as i32 <= self.y + self.height as i32
+    }
+
+    fn intersect(&self, other: &Rect) -> Option<Rect> {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = (self.x + self.width as i32).min(other.x + other.width as i32);
+        let bottom = (self.y + self.height as i32).min(other.y + other.height as i32);
+        (right > x && bottom > y).then(|| Rect {
+            width: (right - x) as u32,
+            height: (bottom - y) as u32,
+            x,
+            y,
+        })
+    }
+}
+
+impl std::fmt::Display for Rect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}x{}{:+}{:+}", self.width, self.height, self.x, self.y)
+    }
+}
+
+/// The screen and its active monitors, as `xrandr --current` tells
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Layout {
+    pub screen: Option<Rect>,
+    pub monitors: Vec<(String, Rect)>,
+}
+
+impl Layout {
+    pub fn parse(xrandr: &str) -> Self {
+        let mut layout = Layout::default();
+        for line in xrandr.lines() {
+            if line.starts_with("Screen ") {
+                // Screen 0: minimum 8 x 8, current 3840 x 1080, maximum 32767 x 32767
+                let current = line.split(", ").find_map(|p| p.strip_prefix("current "));
+                layout.screen = current.and_then(|c| {
+                    let (w, h) = c.split_once(" x ")?;
+                    Rect::parse(&format!("{}x{}", w.trim(), h.trim()))
+                });
+                continue;
+            }
+            // eDP-1 connected primary 1920x1080+0+0 (normal left inverted ...) 309mm x 173mm
+            let mut words = line.split_whitespace();
+            let (Some(name), Some("connected")) = (words.next(), words.next()) else {
+                continue;
+            };
+            if let Some(rect) = words
+                .take_while(|w| !w.starts_with('('))
+                .find_map(Rect::parse)
+            {
+                layout.monitors.push((name.to_string(), rect));
+            }
+        }
+        layout
+    }
+
+    /// what the region shows: its visible part, and the parts of each monitor in it
+    pub fn view(&self, region: &Rect) -> (Option<Rect>, Vec<(String, Rect)>) {
+        let visible = match &self.screen {
+            Some(screen) => screen.intersect(region),
+            None => Some(*region),
+        };
+        let mut monitors: Vec<_> = self
+            .monitors
+            .iter()
+            .filter_map(|(name, rect)| Some((name.clone(), rect.intersect(region)?)))
+            .collect();
+        monitors.sort();
+        (visible, monitors)
+    }
+
+    fn describe(&self) -> String {
+        let screen = self
+            .screen
+            .map(|s| format!("screen {}x{}", s.width, s.height))
+            .unwrap_or_else(|| "screen ?".to_string());
+        let monitors: Vec<String> = self
+            .monitors
+            .iter()
+            .map(|(name, rect)| format!("{} {}", name, rect))
+            .collect();
+        format!("{} [{}]", screen, monitors.join(", "))
+    }
+}
+
+/// What the watcher saw
+#[derive(Debug, Clone, PartialEq)]
+pub enum Observation {
+    Layout(Layout),
+    /// `xrandr` could not talk to the X server
+    DisplayLost(String),
+}
+
+/// What the watcher does about it
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Nothing,
+    /// tell about the change, keep recording
+    Warn(String),
+    /// tell about it and stop the recording
+    Stop(String, StopReason),
+}
+
+/// decide about an observation, given the layout at the start of the capture
+pub fn decide(policy: GeometryPolicy, region: &Rect, before: &Layout, now: &Observation) -> Action {
+    let layout = match now {
+        Observation::DisplayLost(e) => {
+            return Action::Stop(format!("lost the display: {}", e), StopReason::DisplayLost)
+        }
+        Observation::Layout(layout) => layout,
+    };
+    if before.view(region) == layout.view(region) {
+        return Action::Nothing;
+    }
+    let message = format!(
+        "the captured region {} changed from {} to {}",
+        region,
+        before.describe(),
+        layout.describe()
+    );
+    match policy {
+        GeometryPolicy::Continue => Action::Warn(message),
+        GeometryPolicy::Stop => Action::Stop(message, StopReason::GeometryChanged),
+    }
+}
+
+/// the region of the display a screen recording captures
+pub fn capture_region(opt: &RecordingOptions) -> Rect {
+    opt.region.unwrap_or_else(|| {
+        let size = Rect::parse(VIDEO_SIZE).expect("valid capture size");
+        Rect {
+            width: opt.width.unwrap_or(size.width),
+            height: opt.height.unwrap_or(size.height),
+            ..size
+        }
+    })
+}
+
+/// the narrowest and the lowest region
+pub const MIN_REGION_SIZE: u32 = 1;
+/// the offset of a region at the top left corner of the screen
+pub const MIN_REGION_OFFSET: i32 = 0;
+/// the widest and the highest capture, past any display there is
+pub const MAX_CAPTURE_SIZE: u32 = 16384;
+
+/// whether the region the options ask for can be captured
+pub fn validate_region(opt: &RecordingOptions) -> Result<(), &'static str> {
+    let Some(region) = &opt.region else {
+        return Ok(());
+    };
+    if opt.source != CaptureSource::Screen {
+        return Err("only a screen recording captures a region");
+    }
+    if region.width < MIN_REGION_SIZE || region.height < MIN_REGION_SIZE {
+        return Err("the region is empty");
+    }
+    if region.x < MIN_REGION_OFFSET || region.y < MIN_REGION_OFFSET {
+        return Err("the region starts outside the screen");
+    }
+    if region.width % 2 != 0 || region.height % 2 != 0 {
+        return Err("the width and the height must be even, libx264 needs them so");
+    }
+    Ok(())
+}
+
+/// whether the size and the framerate the options ask for can be captured, the field wrong if not
+pub fn validate_size(opt: &RecordingOptions) -> Result<(), (&'static str, String)> {
+    let sizes = [("width", opt.width), ("height", opt.height)];
+    for (field, size) in sizes {
+        let Some(size) = size else {
+            continue;
+        };
+        if opt.source != CaptureSource::Screen {
+            return Err((
+                field,
+                "only a screen recording is captured at a size".into(),
+            ));
+        }
+        if opt.region.is_some() {
+            return Err((
+                field,
+                "the size is that of the region when there is one".into(),
+            ));
+        }
+        if !(MIN_REGION_SIZE..=MAX_CAPTURE_SIZE).contains(&size) {
+            let range = format!("{}..={}", MIN_REGION_SIZE, MAX_CAPTURE_SIZE);
+            return Err((field, format!("must be within {}", range)));
+        }
+        if size % 2 != 0 {
+            return Err((field, "must be even, libx264 needs it so".into()));
+        }
+    }
+    if let Some(framerate) = opt.framerate {
+        if opt.source != CaptureSource::Screen {
+            return Err(("framerate", "a stream keeps its own framerate".into()));
+        }
+        if !(1..=quality::MAX_FRAMERATE).contains(&framerate) {
+            let range = format!("1..={}", quality::MAX_FRAMERATE);
+            return Err(("framerate", format!("must be within {}", range)));
+        }
+    }
+    Ok(())
+}
+
+/// the current layout of the display
+pub async fn layout() -> anyhow::Result<Layout> {
+    match observe().await {
+        Some(Observation::Layout(layout)) => Ok(layout),
+        Some(Observation::DisplayLost(e)) => anyhow::bail!("cannot read the display: {}", e),
+        None => anyhow::bail!("cannot run xrandr"),
+    }
+}
+
+/// None when xrandr can't be run at all
+pub(crate) async fn observe() -> Option<Observation> {
+    let output = tokio::process::Command::new("xrandr")
+        .args(["-display", display::name(), "--current"])
+        .kill_on_drop(true)
+        .output()
+        .await
+        .ok()?;
+    if !output.status.success() {
+        let error = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        return Some(Observation::DisplayLost(error));
+    }
+    Some(Observation::Layout(Layout::parse(
+        &String::from_utf8_lossy(&output.stdout),
+    )))
+}
+
+/// the process id of the started capture, None once it's over
+async fn capturing(mx: &Recorder) -> Option<u32> {
+    match &*mx.lock().await {
+        RecordingState::Started { process_id, .. } => Some(*process_id),
+        _ => None,
+    }
+}
+
+/// the label of the marker of a geometry change
+pub const CHANGED: &str = "geometry changed";
+
+/// keep the warning in the state and a marker in the recording
+async fn note(mx: &Recorder, label: &str, message: &str) {
+    warn!("{}", message);
+    if let RecordingState::Started {
+        started_at,
+        markers,
+        warnings,
+        timeline,
+        ..
+    } = &mut *mx.lock().await
+    {
+        markers.push(timeline.marker(since(*started_at), label));
+        warnings.push(message.to_string());
+    }
+    mx.events.publish(EventKind::Notice {
+        message: message.to_string(),
+    });
+}
+
+/// watch the layout for as long as the capture `pid` runs
+pub async fn watch(mx: Arc<Recorder>, pid: u32, policy: GeometryPolicy, region: Rect) {
+    let Some(Observation::Layout(mut before)) = observe().await else {
+        warn!("cannot run xrandr, screen geometry changes are not watched");
+        return;
+    };
+    loop {
+        tokio::time::sleep(POLL_EVERY).await;
+        if capturing(&mx).await != Some(pid) {
+            return;
+        }
+        let Some(now) = observe().await else {
+            continue;
+        };
+        match decide(policy, &region, &before, &now) {
+            Action::Nothing => {}
+            Action::Warn(message) => note(&mx, CHANGED, &message).await,
+            Action::Stop(message, reason) => {
+                let label = match reason {
+                    StopReason::DisplayLost => "display lost",
+                    _ => CHANGED,
+                };
+                note(&mx, label, &message).await;
+                if let Err(e) = stop_for(mx.clone(), Some(reason)).await {
+                    warn!("cannot stop after the geometry change: {}", e);
+                }
+                return;
+            }
+        }
+        if let Observation::Layout(layout) = now {
+            before = layout;
+        }
+    }
+}
@@ -0,0 +1,310 @@
+//! An animated GIF of a finished recording, for a bug report or a chat
+//!
+//! A GIF has 256 colors, and the default palette of ffmpeg makes a screen capture a dither of
+//! them: the conversion makes two runs, `palettegen` finds the colors of the whole recording and
+//! `paletteuse` maps its frames to them. The GIF is written next to the recording as
+//! `<name>.gif`, the palette in between as `<name>.palette.png`. How far a conversion is shows
+//! on its job, from the progress ffmpeg reports; a recording is converted by one job at a time.
+use crate::container;
+use crate::ffmpeg::{FfmpegBuilder, File, Parameter};
+use crate::probe::probe;
+use crate::runner::Progress;
+use crate::service::{ChildRole, Recorder};
+use crate::throttle::Category;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tracing::*;
+
+/// the most frames a second of a GIF
+pub const MAX_FPS: u32 = 50;
+/// the narrowest and the widest GIF
+pub const WIDTHS: std::ops::RangeInclusive<u32> = 16..=3840;
+
+fn default_fps() -> u32 {
+    10
+}
+
+fn default_scale() -> u32 {
+    640
+}
+
+/// How many frames a second the GIF has, and how wide it is
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct GifRequest {
+    #[serde(default = "default_fps")]
+    pub fps: u32,
+    /// the width in pixels, the height keeps the aspect of the recording
+    #[serde(default = "default_scale")]
+    pub scale: u32,
+}
+
+impl Default for GifRequest {
+    fn default() -> Self {
+        Self {
+            fps: default_fps(),
+            scale: default_scale(),
+        }
+    }
+}
+
+impl GifRequest {
+    /// the field that is out of range and why
+    pub fn invalid(&self) -> Option<(&'static str, String)> {
+        if !(1..=MAX_FPS).contains(&self.fps) {
+            return Some(("fps", format!("must be within 1..{}", MAX_FPS)));
+        }
+        if !WIDTHS.contains(&self.scale) {
+            return Some((
+                "scale",
+                format!("must be within {}..{}", WIDTHS.start(), WIDTHS.end()),
+            ));
+        }
+        None
+    }
+
+    /// the frames picked and sized, before the palette
+    fn filters(&self) -> String {
+        format!("fps={},scale={}:-1:flags=lanczos", self.fps, self.scale)
+    }
+}
+
+/// path of the GIF of a recording
+pub fn gif_path(recording: &Path) -> PathBuf {
+    let name = recording.to_string_lossy();
+    PathBuf::from(format!("{}.gif", container::base(&name)))
+}
+
+fn palette_path(gif: &Path) -> PathBuf {
+    let name = gif.to_string_lossy();
+    PathBuf::from(format!("{}.palette.png", name.trim_end_matches(".gif")))
+}
+
+/// where the frames are written until the GIF is whole
+fn partial_path(gif: &Path) -> PathBuf {
+    let mut partial = gif.as_os_str().to_owned();
+    partial.push(".part.gif");
+    PathBuf::from(partial)
+}
+
+/// The run of a conversion
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Pass {
+    /// finding the colors
+    Palette,
+    /// writing the frames
+    Frames,
+}
+
+/// A GIF being made, or that failed
+#[derive(Debug, Clone, Serialize)]
+pub struct GifJob {
+    pub id: u64,
+    pub recording: String,
+    pub request: GifRequest,
+    pub pass: Pass,
+    /// how much of the recording the conversion went through, both runs together
+    pub percent: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// What a request for a GIF gets
+pub enum Lookup {
+    /// the GIF is there
+    Ready(PathBuf),
+    /// the GIF is being made
+    Pending(GifJob),
+    /// making the GIF failed, the next request tries again
+    Failed(GifJob),
+    /// there is no GIF and none asked for
+    Missing,
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("the recording is being converted already")]
+    Converting(GifJob),
+}
+
+/// The GIFs being made, by the path of the GIF
+#[derive(Default)]
+pub struct Gifs {
+    jobs: Mutex<HashMap<PathBuf, GifJob>>,
+    next_id: AtomicU64,
+}
+
+impl Gifs {
+    /// the GIFs being made and the ones that failed, oldest first
+    pub fn list(&self) -> Vec<GifJob> {
+        let mut jobs: Vec<GifJob> = self.jobs.lock().unwrap().values().cloned().collect();
+        jobs.sort_by_key(|j| j.id);
+        jobs
+    }
+
+    /// the GIF of the recording, without making it
+    pub fn lookup(&self, source: &Path) -> Lookup {
+        let gif = gif_path(source);
+        let mut jobs = self.jobs.lock().unwrap();
+        match jobs.get(&gif) {
+            Some(job) if job.error.is_some() => {
+                let job = job.clone();
+                jobs.remove(&gif);
+                Lookup::Failed(job)
+            }
+            Some(job) => Lookup::Pending(job.clone()),
+            None if gif.is_file() => Lookup::Ready(gif),
+            None => Lookup::Missing,
+        }
+    }
+
+    /// convert the recording again, unless a job converts it already
+    pub fn start(
+        &self,
+        mx: &Arc<Recorder>,
+        source: &Path,
+        req: GifRequest,
+    ) -> Result<GifJob, Error> {
+        let gif = gif_path(source);
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.get(&gif).filter(|job| job.error.is_none()) {
+            return Err(Error::Converting(job.clone()));
+        }
+        let job = GifJob {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            recording: source.to_string_lossy().to_string(),
+            request: req,
+            pass: Pass::Palette,
+            percent: 0.0,
+            error: None,
+        };
+        jobs.insert(gif.clone(), job.clone());
+        drop(jobs);
+
+        let mx = mx.clone();
+        let source = source.to_path_buf();
+        tokio::spawn(async move {
+            let result = make(&mx, &source, &gif, &req).await;
+            let mut jobs = mx.gifs.jobs.lock().unwrap();
+            match result {
+                Ok(()) => {
+                    info!("gif {}", gif.display());
+                    jobs.remove(&gif);
+                }
+                Err(e) => {
+                    warn!("cannot make the gif of {}: {}", source.display(), e);
+                    if let Some(job) = jobs.get_mut(&gif) {
+                        job.error = Some(e.to_string());
+                    }
+                }
+            }
+        });
+        Ok(job)
+    }
+
+    /// how far the job of the GIF is, within its run
+    fn progress(&self, gif: &Path, pass: Pass, fraction: f64) {
+        let before = match pass {
+            Pass::Palette => 0.0,
+            Pass::Frames => 50.0,
+        };
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(gif) {
+            job.pass = pass;
+            job.percent = before + 50.0 * fraction.clamp(0.0, 1.0);
+        }
+    }
+}
+
+async fn make(
+    mx: &Arc<Recorder>,
+    source: &Path,
+    gif: &Path,
+    req: &GifRequest,
+) -> anyhow::Result<()> {
+    let duration = probe(source).await?.duration.unwrap_or_default();
+    let (palette, partial) = (palette_path(gif), partial_path(gif));
+    let result = async {
+        let palettegen = format!("{},palettegen=stats_mode=diff", req.filters());
+        run(mx, source, gif, Pass::Palette, &palettegen, duration).await?;
+        let paletteuse = format!(
+            "[0:v]{}[x];[x][1:v]paletteuse=dither=bayer:bayer_scale=5:diff_mode=rectangle",
+            req.filters()
+        );
+        run(mx, source, gif, Pass::Frames, &paletteuse, duration).await?;
+        std::fs::rename(&partial, gif)?;
+        Ok(())
+    }
+    .await;
+    let _ = std::fs::remove_file(&palette);
+    if result.is_err() {
+        let _ = std::fs::remove_file(&partial);
+    }
+    result
+}
+
+/// one run of ffmpeg over the recording, writing the palette or, with it, the frames
+async fn run(
+    mx: &Arc<Recorder>,
+    source: &Path,
+    gif: &Path,
+    pass: Pass,
+    filters: &str,
+    duration: f64,
+) -> anyhow::Result<()> {
+    let palette = palette_path(gif).to_string_lossy().to_string();
+    let output = match pass {
+        Pass::Palette => palette_path(gif),
+        Pass::Frames => partial_path(gif),
+    };
+    let input = source.to_string_lossy().to_string();
+    let out = output.to_string_lossy().to_string();
+    let readrate = mx.throttle.readrate(Category::Transcode, source).await;
+    let mut file = File::new(&input);
+    if let Some((speed, _)) = &readrate {
+        file = file.option(Parameter::KeyValue("readrate", speed));
+    }
+    let mut ffmpeg = FfmpegBuilder::new()
+        .stderr(Stdio::piped())
+        .option(Parameter::Single("y"))
+        .input(file);
+    ffmpeg = match pass {
+        // one image, written over until the whole recording went through
+        Pass::Palette => ffmpeg
+            .option2(Parameter::KeyValue("vf", filters))
+            .option2(Parameter::KeyValue("update", "1")),
+        Pass::Frames => ffmpeg
+            .input(File::new(&palette))
+            .option2(Parameter::KeyValue("lavfi", filters)),
+    };
+    let ffmpeg = ffmpeg
+        .option2(Parameter::Single("an"))
+        .output(File::new(&out))
+        .run()
+        .await?;
+    let pid = ffmpeg.id();
+    mx.children.register(pid, ChildRole::Transcode, vec![out]);
+    mx.throttle.idle(pid);
+    let summary = ffmpeg
+        .wait_with_progress(|p: &Progress| {
+            if let (Some(at), true) = (p.out_time, duration > 0.0) {
+                mx.gifs.progress(gif, pass, at.as_secs_f64() / duration);
+            }
+        })
+        .await;
+    mx.children.unregister(pid);
+    let summary = summary?;
+    if !summary.success() || !output.is_file() {
+        anyhow::bail!(
+            "ffmpeg exited with {}: {}",
+            summary.exit_status,
+            summary.stderr_tail.join("\n")
+        );
+    }
+    mx.gifs.progress(gif, pass, 1.0);
+    Ok(())
+}
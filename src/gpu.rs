@@ -0,0 +1,369 @@
+//! Utilization of the GPU while a hardware encoder is working
+//!
+//! With VAAPI or NVENC the CPU looks idle even when the video engine is saturated by another
+//! process, so the engine is sampled along the encoding: `nvidia-smi` is polled for NVENC, and a
+//! supervised `intel_gpu_top -J` streams the samples for VAAPI and QSV. A tool that is missing or
+//! not permitted leaves the fields absent, with a single warning.
+use crate::service::{ChildRole, Recorder, RecordingState};
+use serde::{Deserialize, Serialize};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tracing::*;
+
+/// how often the GPU is sampled
+const SAMPLE_EVERY: Duration = Duration::from_secs(2);
+
+/// One sample of the GPU
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GpuUsage {
+    /// busy percent of the video encoding engine
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoder_util_pct: Option<f64>,
+    /// busy percent of the 3D/compute engine
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gpu_util_pct: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mem_used_mb: Option<f64>,
+}
+
+/// How the GPU of an encoder is sampled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    /// `nvidia-smi`
+    Nvidia,
+    /// `intel_gpu_top`
+    Intel,
+}
+
+impl Backend {
+    /// the backend of a hardware encoder, none for the software ones
+    pub fn for_codec(codec: &str) -> Option<Self> {
+        if codec.ends_with("_nvenc") {
+            Some(Backend::Nvidia)
+        } else if codec.ends_with("_vaapi") || codec.ends_with("_qsv") {
+            Some(Backend::Intel)
+        } else {
+            None
+        }
+    }
+}
+
+/// parse `nvidia-smi --query-gpu=utilization.encoder,utilization.gpu,memory.used
+/// --format=csv,noheader`, the first GPU is taken
+pub fn parse_nvidia_smi(output: &str) -> Option<GpuUsage> {
+    let line = output.lines().find(|l| !l.trim().is_empty())?;
+    let mut fields = line.split(',').map(|f| {
+        let value = f
+            .trim()
+            .trim_end_matches('%')
+            .trim_end_matches("MiB")
+            .trim();
+        value.parse::<f64>().ok()
+    });
+    Some(GpuUsage {
+        encoder_util_pct: fields.next()?,
+        gpu_util_pct: fields.next().flatten(),
+        mem_used_mb: fields.next().flatten(),
+    })
+}
+
+/// parse one sample of `intel_gpu_top -J`
+pub fn parse_intel_gpu_top(sample: &serde_json::Value) -> GpuUsage {
+    let busy = |prefix: &str| {
+        sample["engines"].as_object().and_then(|engines| {
+            engines
+                .iter()
+                .filter(|(name, _)| name.starts_with(prefix))
+                .filter_map(|(_, engine)| engine["busy"].as_f64())
+                .reduce(f64::max)
+        })
+    };
+    GpuUsage {
+        // "Video/0" encodes, "VideoEnhance/0" only scales and converts
+        encoder_util_pct: busy("Video/"),
+        gpu_util_pct: busy("Render/3D"),
+        mem_used_mb: None,
+    }
+}
+
+/// Splits the output of `intel_gpu_top -J` into its samples
+///
+/// The tool writes one JSON array that never ends while it runs: `[`, then the samples
+/// separated by commas. The objects are cut out by counting their braces.
+#[derive(Default)]
+pub struct JsonFrames {
+    buffer: Vec<u8>,
+}
+
+impl JsonFrames {
+    /// add output, getting the samples completed by it
+    pub fn push(&mut self, data: &[u8]) -> Vec<serde_json::Value> {
+        self.buffer.extend_from_slice(data);
+        let mut samples = vec![];
+        let (mut depth, mut start, mut in_string, mut escaped) = (0, None, false, false);
+        let mut consumed = 0;
+        for (i, &b) in self.buffer.iter().enumerate() {
+            if in_string {
+                match b {
+                    _ if escaped => escaped = false,
+                    b'\\' => escaped = true,
+                    b'"' => in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+            match b {
+                b'"' => in_string = true,
+                b'{' => {
+                    if depth == 0 {
+                        start = Some(i);
+                    }
+                    depth += 1;
+                }
+                b'}' if depth > 0 => {
+                    depth -= 1;
+                    if let (0, Some(s)) = (depth, start) {
+                        if let Ok(value) = serde_json::from_slice(&self.buffer[s..=i]) {
+                            samples.push(value);
+                        }
+                        start = None;
+                        consumed = i + 1;
+                    }
+                }
+                // the array brackets and separators between the samples
+                _ if depth == 0 => consumed = i + 1,
+                _ => {}
+            }
+        }
+        self.buffer.drain(..consumed);
+        samples
+    }
+}
+
+fn encoding(state: &RecordingState) -> bool {
+    matches!(state, RecordingState::Compressing { .. })
+}
+
+/// keep the sample in the state and for the progress events
+async fn record(mx: &Recorder, usage: Option<GpuUsage>) {
+    *mx.gpu.lock().unwrap() = usage.clone();
+    mx.lock().await.set_gpu(usage);
+}
+
+/// sample the GPU for as long as the encoding runs
+pub async fn monitor(mx: Arc<Recorder>, backend: Backend) {
+    let result = match backend {
+        Backend::Nvidia => poll_nvidia_smi(&mx).await,
+        Backend::Intel => stream_intel_gpu_top(&mx).await,
+    };
+    if let Err(e) = result {
+        warn!("GPU utilization is not sampled: {}", e);
+    }
+    record(&mx, None).await;
+}
+
+async fn poll_nvidia_smi(mx: &Recorder) -> anyhow::Result<()> {
+    let mut warned = false;
+    while encoding(&*mx.lock().await) {
+        let output = tokio::process::Command::new("nvidia-smi")
+            .args([
+                "--query-gpu=utilization.encoder,utilization.gpu,memory.used",
+                "--format=csv,noheader",
+            ])
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .output()
+            .await?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "nvidia-smi exited with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        let usage = parse_nvidia_smi(&String::from_utf8_lossy(&output.stdout));
+        if usage.is_none() && !warned {
+            warned = true;
+            warn!("cannot parse nvidia-smi output: {:?}", output.stdout);
+        }
+        record(mx, usage).await;
+        tokio::time::sleep(SAMPLE_EVERY).await;
+    }
+    Ok(())
+}
+
+async fn stream_intel_gpu_top(mx: &Recorder) -> anyhow::Result<()> {
+    let period = SAMPLE_EVERY.as_millis().to_string();
+    let mut child = tokio::process::Command::new("intel_gpu_top")
+        .args(["-J", "-s", &period])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+    let pid = child.id().unwrap_or_default();
+    mx.children.register(pid, ChildRole::Monitor, vec![]);
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let mut frames = JsonFrames::default();
+    let mut buffer = [0u8; 8192];
+    let result = loop {
+        let read = tokio::time::timeout(SAMPLE_EVERY * 2, stdout.read(&mut buffer)).await;
+        if !encoding(&*mx.lock().await) {
+            break Ok(());
+        }
+        match read {
+            // nothing yet, the state is looked at again
+            Err(_) => continue,
+            Ok(Ok(0)) => {
+                break Err(anyhow::anyhow!(
+                    "intel_gpu_top exited: {:?}",
+                    child.wait().await
+                ))
+            }
+            Ok(Ok(n)) => {
+                if let Some(sample) = frames.push(&buffer[..n]).last() {
+                    record(mx, Some(parse_intel_gpu_top(sample))).await;
+                }
+            }
+            Ok(Err(e)) => break Err(e.into()),
+        }
+    };
+    let _ = child.kill().await;
+    mx.children.unregister(pid);
+    result
+}
+
+/// the last sample as Prometheus gauges
+pub fn metrics(usage: &GpuUsage) -> String {
+    let mut out = String::new();
+    let gauges = [
+        (
+            "record_screen_gpu_encoder_utilization",
+            "Busy percent of the GPU video encoding engine.",
+            usage.encoder_util_pct,
+        ),
+        (
+            "record_screen_gpu_utilization",
+            "Busy percent of the GPU 3D/compute engine.",
+            usage.gpu_util_pct,
+        ),
+        (
+            "record_screen_gpu_memory_used_megabytes",
+            "GPU memory in use.",
+            usage.mem_used_mb,
+        ),
+    ];
+    for (name, help, value) in gauges {
+        if let Some(value) = value {
+            out += &format!(
+                "# HELP {} {}\n# TYPE {} gauge\n{} {}\n",
+                name, help, name, name, value
+            );
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `intel_gpu_top -J` of a GPU with two video engines, cut down to what is parsed
+    const INTEL_SAMPLE: &str = r#"{
+        "period": { "duration": 2000.123, "unit": "ms" },
+        "frequency": { "requested": 350.0, "actual": 349.9, "unit": "MHz" },
+        "engines": {
+            "Render/3D/0": { "busy": 12.5, "sema": 0.0, "wait": 0.0, "unit": "%" },
+            "Blitter/0": { "busy": 0.0, "sema": 0.0, "wait": 0.0, "unit": "%" },
+            "Video/0": { "busy": 63.2, "sema": 0.0, "wait": 0.0, "unit": "%" },
+            "Video/1": { "busy": 70.1, "sema": 0.0, "wait": 0.0, "unit": "%" },
+            "VideoEnhance/0": { "busy": 99.0, "sema": 0.0, "wait": 0.0, "unit": "%" }
+        }
+    }"#;
+
+    #[test]
+    fn nvidia_smi_is_parsed_with_its_units() {
+        let usage = parse_nvidia_smi("45 %, 12 %, 1024 MiB\n").unwrap();
+        assert_eq!(
+            usage,
+            GpuUsage {
+                encoder_util_pct: Some(45.0),
+                gpu_util_pct: Some(12.0),
+                mem_used_mb: Some(1024.0),
+            }
+        );
+        let usage = parse_nvidia_smi("7, 3, 512\n").unwrap();
+        assert_eq!(usage.mem_used_mb, Some(512.0));
+    }
+
+    #[test]
+    fn nvidia_smi_of_several_gpus_is_the_first_one() {
+        let usage = parse_nvidia_smi("\n 10 %, 20 %, 300 MiB\n90 %, 80 %, 7000 MiB\n").unwrap();
+        assert_eq!(usage.encoder_util_pct, Some(10.0));
+        assert_eq!(usage.mem_used_mb, Some(300.0));
+    }
+
+    #[test]
+    fn nvidia_smi_without_a_value_leaves_it_out() {
+        let usage = parse_nvidia_smi("[N/A], 12 %, [N/A]\n").unwrap();
+        assert_eq!(usage.encoder_util_pct, None);
+        assert_eq!(usage.gpu_util_pct, Some(12.0));
+        assert_eq!(usage.mem_used_mb, None);
+        let usage = parse_nvidia_smi("30 %").unwrap();
+        assert_eq!(usage.gpu_util_pct, None);
+        assert_eq!(parse_nvidia_smi(""), None);
+        assert_eq!(parse_nvidia_smi("\n\n"), None);
+    }
+
+    #[test]
+    fn intel_gpu_top_is_the_busiest_video_engine() {
+        let sample = serde_json::from_str(INTEL_SAMPLE).unwrap();
+        assert_eq!(
+            parse_intel_gpu_top(&sample),
+            GpuUsage {
+                encoder_util_pct: Some(70.1),
+                gpu_util_pct: Some(12.5),
+                mem_used_mb: None,
+            }
+        );
+    }
+
+    #[test]
+    fn intel_gpu_top_without_engines_has_no_usage() {
+        let sample = serde_json::json!({ "period": { "duration": 2000.0 } });
+        assert_eq!(parse_intel_gpu_top(&sample), GpuUsage::default());
+        let sample = serde_json::json!({ "engines": { "Render/3D/0": { "busy": 5.0 } } });
+        assert_eq!(parse_intel_gpu_top(&sample).encoder_util_pct, None);
+    }
+
+    #[test]
+    fn the_samples_are_cut_out_of_the_array() {
+        let output = format!(
+            "[\n{},\n{}\n",
+            INTEL_SAMPLE, r#"{"engines": {"Video/0": {"busy": 1.5, "unit": "}"}}}"#
+        );
+        let mut frames = JsonFrames::default();
+        let (first, second) = output.as_bytes().split_at(40);
+        assert!(frames.push(first).is_empty());
+        let samples = frames.push(second);
+        assert_eq!(samples.len(), 2);
+        assert_eq!(
+            parse_intel_gpu_top(&samples[0]).encoder_util_pct,
+            Some(70.1)
+        );
+        // a brace in a string is no end of the sample
+        assert_eq!(parse_intel_gpu_top(&samples[1]).encoder_util_pct, Some(1.5));
+        assert!(frames.push(b"]").is_empty());
+        assert!(frames.buffer.is_empty());
+    }
+
+    #[test]
+    fn the_backend_follows_the_codec() {
+        assert_eq!(Backend::for_codec("h264_nvenc"), Some(Backend::Nvidia));
+        assert_eq!(Backend::for_codec("hevc_vaapi"), Some(Backend::Intel));
+        assert_eq!(Backend::for_codec("h264_qsv"), Some(Backend::Intel));
+        assert_eq!(Backend::for_codec("libx264"), None);
+    }
+}
@@ -0,0 +1,334 @@
+//! Handing a running recording over to the next server process, for the deploys that must not end it
+//!
+//! `POST /api/handoff` ends the server without ending its capture: the Started state is written
+//! to a [Handoff] file in the state directory, the other children are killed, and the process
+//! exits leaving ffmpeg running. The next server finds the file when it starts and adopts the
+//! capture. It is not a child of that server and its progress socket went away with the old one,
+//! so it is watched by its pid and by the growth of its file instead, and a stop signals it by its
+//! pid as any other. The compressions that were running are restarted from the journal of the
+//! jobs, as after a crash.
+//!
+//! A capture that is gone by the time it is adopted is stopped right away, compressing what it
+//! wrote. Of several handoff files the newest is adopted and the others are quarantined. What the
+//! old server read from the stderr of the capture, the frame timestamps and the write errors,
+//! ends with the handoff, and so do the quality changes.
+//!
+//! Under systemd the unit needs `KillMode=process`, or the capture ends with the old process.
+use crate::chunks;
+use crate::ffmpeg;
+use crate::geometry;
+use crate::recordings;
+use crate::runner::Progress;
+use crate::schema::{self, Versioned};
+use crate::service::{stop_for, ChildRole, Recorder, RecordingState};
+use crate::source::CaptureSource;
+use anyhow::bail;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use tracing::*;
+
+/// how often an adopted capture is looked at
+pub const POLL: Duration = Duration::from_millis(500);
+/// an adopted capture whose file did not grow for as long is told stalled
+pub const STALLED: Duration = Duration::from_secs(10);
+/// for the answer to the request to go out before the process exits
+const EXIT_DELAY: Duration = Duration::from_millis(200);
+const PREFIX: &str = ".record-screen-handoff-";
+
+/// A recording left by a server for the next one
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Handoff {
+    pub at: DateTime<Local>,
+    /// the server that handed the recording over
+    pub server_pid: u32,
+    /// the Started state of the recording, with the pid and the files of its capture
+    pub state: RecordingState,
+}
+
+impl Versioned for Handoff {
+    const KIND: &'static str = "handoffs";
+    const VERSION: u32 = 1;
+    const MIGRATIONS: &'static [schema::Migration] = &[schema::unversioned];
+}
+
+fn path(dir: &Path, server_pid: u32) -> PathBuf {
+    dir.join(format!("{}{}.json", PREFIX, server_pid))
+}
+
+/// the handoff of `state`, None when no capture runs
+fn prepare(state: &RecordingState) -> anyhow::Result<Option<Handoff>> {
+    match state {
+        RecordingState::Started { audio: Some(_), .. } => {
+            bail!("a recording with a resilient audio can't be handed over")
+        }
+        RecordingState::Paused { .. } => {
+            bail!("a paused recording can't be handed over")
+        }
+        RecordingState::Started { .. } => {
+            let mut state = state.clone();
+            if let RecordingState::Started { progress, .. } = &mut state {
+                *progress = None;
+            }
+            Ok(Some(Handoff {
+                at: Local::now(),
+                server_pid: std::process::id(),
+                state,
+            }))
+        }
+        RecordingState::Countdown { .. } | RecordingState::Stopping { .. } => {
+            bail!("cannot hand over while {}", state.name())
+        }
+        // a compression, or the upload after it, is restarted from the journal
+        RecordingState::Waiting
+        | RecordingState::Compressing { .. }
+        | RecordingState::Uploading { .. }
+        | RecordingState::Done { .. }
+        | RecordingState::Failed { .. }
+        | RecordingState::Cancelled { .. } => Ok(None),
+    }
+}
+
+fn write(handoff: &Handoff) -> anyhow::Result<PathBuf> {
+    let path = path(&recordings::output_dir()?, handoff.server_pid);
+    let file = std::fs::File::create(&path)?;
+    serde_json::to_writer_pretty(&file, &schema::to_value(handoff)?)?;
+    file.sync_all()?;
+    Ok(path)
+}
+
+/// write the handoff of the recording and exit, leaving its capture running
+///
+/// Answers with the handoff, None when there was no capture to hand over, once it is written; the
+/// state stays locked until the process exits.
+pub async fn hand_over(mx: Arc<Recorder>) -> anyhow::Result<Option<Handoff>> {
+    let (written_tx, written) = oneshot::channel();
+    tokio::spawn(async move {
+        let state = mx.lock().await;
+        let handoff = prepare(&state).and_then(|handoff| {
+            if let Some(handoff) = &handoff {
+                let path = write(handoff)?;
+                info!("handed the recording over in {}", path.display());
+            }
+            Ok(handoff)
+        });
+        let capture = match &handoff {
+            Ok(Some(Handoff {
+                state: RecordingState::Started { process_id, .. },
+                ..
+            })) => Some(*process_id),
+            Ok(_) => None,
+            Err(_) => {
+                let _ = written_tx.send(handoff);
+                return;
+            }
+        };
+        let _ = written_tx.send(handoff);
+        tokio::time::sleep(EXIT_DELAY).await;
+        // without an await in between, so that no reaper tells the journal a job failed
+        for child in mx.children.list() {
+            if Some(child.pid) == capture {
+                continue;
+            }
+            let pid = nix::unistd::Pid::from_raw(child.pid as i32);
+            if let Err(e) = nix::sys::signal::kill(pid, nix::sys::signal::Signal::SIGKILL) {
+                warn!("cannot kill {:?} {}: {}", child.role, child.pid, e);
+            }
+        }
+        warn!("exiting for the next server");
+        drop(state);
+        std::process::exit(0);
+    });
+    written.await?
+}
+
+/// whether `pid` is still a process writing `file`, rather than gone or another one
+///
+/// A capture that streams writes it as the first output of its tee muxer, see [crate::rtmp].
+pub(crate) fn is_capture(pid: u32, file: &str) -> bool {
+    let teed = format!("]{}|", ffmpeg::escape_tee(file));
+    std::fs::read(format!("/proc/{}/cmdline", pid))
+        .map(|cmdline| {
+            cmdline
+                .split(|&b| b == 0)
+                .map(String::from_utf8_lossy)
+                .any(|arg| arg == file || (arg.starts_with('[') && arg.contains(&teed)))
+        })
+        .unwrap_or_default()
+}
+
+/// the newest handoff of the state directory, taken out of it
+fn take_newest() -> Option<Handoff> {
+    let dir = recordings::output_dir().ok()?;
+    let mut found = vec![];
+    for entry in std::fs::read_dir(&dir).ok()?.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !name.starts_with(PREFIX) || !name.ends_with(".json") {
+            continue;
+        }
+        let path = entry.path();
+        let read = std::fs::read(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|json| Ok(schema::from_slice::<Handoff>(&json)?.0));
+        match read {
+            Ok(handoff) => found.push((handoff, path)),
+            Err(e) => {
+                warn!("ignoring the handoff {}: {}", path.display(), e);
+                if let Err(e) = schema::quarantine(&path) {
+                    warn!("cannot quarantine {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+    found.sort_by_key(|(handoff, _)| handoff.at);
+    let (newest, path) = found.pop()?;
+    for (older, path) in found {
+        warn!(
+            "not adopting the handoff of the server {} at {}, a newer one is",
+            older.server_pid,
+            older.at.to_rfc3339()
+        );
+        if let Err(e) = schema::quarantine(&path) {
+            warn!("cannot quarantine {}: {}", path.display(), e);
+        }
+    }
+    if let Err(e) = std::fs::remove_file(&path) {
+        warn!("cannot remove {}: {}", path.display(), e);
+    }
+    Some(newest)
+}
+
+/// adopt the recording a previous server handed over, false when there was none
+///
+/// To be awaited before the jobs are restored, which wait for the recorder to be idle.
+pub async fn adopt(mx: Arc<Recorder>) -> bool {
+    let Some(handoff) = tokio::task::spawn_blocking(take_newest)
+        .await
+        .ok()
+        .flatten()
+    else {
+        return false;
+    };
+    let from = format!(
+        "handed over from the server {} at {}",
+        handoff.server_pid,
+        handoff.at.to_rfc3339()
+    );
+    adopt_state(mx, handoff.state, from).await;
+    true
+}
+
+/// take over the Started `state` of another server, `from` telling which, and its capture
+pub(crate) async fn adopt_state(mx: Arc<Recorder>, mut state: RecordingState, from: String) {
+    let RecordingState::Started {
+        process_id,
+        file,
+        options,
+        quality,
+        failover,
+        warnings,
+        chunks,
+        started_at,
+        ..
+    } = &mut state
+    else {
+        warn!("not adopting a recording {}: it is not started", from);
+        return;
+    };
+    let (pid, options, started_at) = (*process_id, options.clone(), *started_at);
+    // the capture writes the chunks of its pattern
+    let followed = chunks
+        .as_ref()
+        .map(|chunks| (chunks.pattern.clone(), started_at));
+    let file = followed
+        .as_ref()
+        .map_or(file.clone(), |(pattern, _)| pattern.clone());
+    // nothing rolls the capture over any more
+    *quality = None;
+    *failover = None;
+    warnings.push(from.clone());
+    let alive = is_capture(pid, &file);
+    if alive {
+        mx.children
+            .register(pid, ChildRole::Capture, vec![file.clone()]);
+    }
+    mx.set(state).await;
+    if !alive {
+        warn!(
+            "the capture {} is gone, stopping the recording {}",
+            pid, from
+        );
+        tokio::spawn(async move {
+            if let Err(e) = stop_for(mx, None).await {
+                warn!("cannot stop the adopted recording: {}", e);
+            }
+        });
+        return;
+    }
+    info!("adopted the capture {} of {}", pid, file);
+    if options.source == CaptureSource::Screen {
+        let region = geometry::capture_region(&options);
+        tokio::spawn(geometry::watch(
+            mx.clone(),
+            pid,
+            options.on_geometry_change,
+            region,
+        ));
+    }
+    if let Some((_, started_at)) = followed {
+        tokio::spawn(chunks::follow(mx.clone(), started_at));
+    }
+    tokio::spawn(watch(mx, pid, file));
+}
+
+/// follow an adopted capture by its pid and its file, or the pattern of its chunks, until it exits
+async fn watch(mx: Arc<Recorder>, pid: u32, file: String) {
+    let mut writing = file.clone();
+    let mut size = 0;
+    let mut grown = Instant::now();
+    let mut stalled = false;
+    loop {
+        tokio::time::sleep(POLL).await;
+        if !is_capture(pid, &file) {
+            // a stop waits for it to leave the table
+            mx.children.unregister(pid);
+            info!("the adopted capture {} exited, {} bytes written", pid, size);
+            return;
+        }
+        let current = chunks::being_written(&file);
+        if current != writing {
+            // the next chunk
+            (writing, size) = (current, 0);
+        }
+        let now = tokio::fs::metadata(&writing)
+            .await
+            .map(|m| m.len())
+            .unwrap_or(size);
+        if now > size {
+            (size, grown, stalled) = (now, Instant::now(), false);
+        } else if !stalled && grown.elapsed() >= STALLED {
+            stalled = true;
+            warn!("the adopted capture {} stopped writing {}", pid, file);
+            let mut state = mx.lock().await;
+            if let RecordingState::Started {
+                process_id,
+                warnings,
+                ..
+            } = &mut *state
+            {
+                if *process_id == pid {
+                    warnings.push(format!("the capture did not write for {:?}", STALLED));
+                    let updated = state.clone();
+                    mx.replace(&mut state, updated);
+                }
+            }
+        }
+        mx.progress(&Progress {
+            total_size: Some(size),
+            ..Default::default()
+        });
+    }
+}
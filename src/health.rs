@@ -0,0 +1,769 @@
+//! One number per recording telling whether it needs attention, from 100 down to 0
+//!
+//! [watch] samples what the server knows already while a recording is Started or Compressing:
+//! the progress of ffmpeg, the free space left, the load of the CPU and the GPU, the audio process
+//! and the geometry warnings. Every [Factor] takes up to its weight off 100, in proportion to how
+//! far its value went past the start of its [Rule], the table of [RULES] which `--health-rule`
+//! overrides. The score and the factors that took the most are kept in the state, so that they
+//! reach `/api/status` and the event stream with it, and in the metrics.
+//!
+//! The score is [Level::Healthy] above `--health-degraded`, [Level::Critical] below
+//! `--health-critical` and [Level::Degraded] between. A level is left for a better one only once the
+//! score is [Config::hysteresis] points past the threshold, so that a score wavering around it
+//! does not flap, and every change of level is told once with an [EventKind::Health]. The lowest
+//! score of a recording and its factors go into its history entry.
+//!
+//! The speed is only scored while capturing: a compression slower than realtime is no trouble.
+use crate::events::EventKind;
+use crate::geometry;
+use crate::liveness;
+use crate::quality;
+use crate::recordings;
+use crate::runner::Progress;
+use crate::service::{since, Recorder, RecordingState};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::*;
+
+/// how often the recording is scored
+pub const TICK: Duration = Duration::from_secs(2);
+/// how far back the rates are taken
+pub const WINDOW: Duration = Duration::from_secs(10);
+/// how long a geometry warning counts
+pub const GEOMETRY_WINDOW_MS: u64 = 5 * 60 * 1000;
+/// the factors kept with a score
+pub const TOP_FACTORS: usize = 3;
+
+/// What takes from the score, by its code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Factor {
+    /// the fraction of the frames dropped lately
+    DropFrames,
+    /// how far below realtime the capture is, `1 - speed`
+    SlowSpeed,
+    /// seconds without progress from ffmpeg
+    StaleProgress,
+    /// seconds until the free space reaches the least the liveness asks for, at the rate written
+    LowDisk,
+    /// the load average by CPU
+    CpuSaturated,
+    /// the busy percent of the GPU, while a hardware encoder runs
+    GpuSaturated,
+    /// 1 while the resilient audio is not recording
+    AudioDisconnected,
+    /// the geometry warnings of the last minutes
+    GeometryChanged,
+}
+
+impl Factor {
+    pub const ALL: &'static [Factor] = &[
+        Factor::DropFrames,
+        Factor::SlowSpeed,
+        Factor::StaleProgress,
+        Factor::LowDisk,
+        Factor::CpuSaturated,
+        Factor::GpuSaturated,
+        Factor::AudioDisconnected,
+        Factor::GeometryChanged,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::DropFrames => "drop_frames",
+            Self::SlowSpeed => "slow_speed",
+            Self::StaleProgress => "stale_progress",
+            Self::LowDisk => "low_disk",
+            Self::CpuSaturated => "cpu_saturated",
+            Self::GpuSaturated => "gpu_saturated",
+            Self::AudioDisconnected => "audio_disconnected",
+            Self::GeometryChanged => "geometry_changed",
+        }
+    }
+}
+
+impl FromStr for Factor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .find(|f| f.name() == s.trim())
+            .copied()
+            .ok_or_else(|| {
+                let known: Vec<_> = Self::ALL.iter().map(Factor::name).collect();
+                format!("expected one of {}", known.join(", "))
+            })
+    }
+}
+
+/// How a factor is scored: nothing up to `onset`, all of its weight from `full`, in proportion
+/// between; `full` below `onset` for the values that are worse the lower they are
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rule {
+    pub weight: f64,
+    pub onset: f64,
+    pub full: f64,
+}
+
+impl Rule {
+    pub const fn new(weight: f64, onset: f64, full: f64) -> Self {
+        Self {
+            weight,
+            onset,
+            full,
+        }
+    }
+
+    /// what the value takes from the score
+    pub fn penalty(&self, value: f64) -> f64 {
+        if self.full == self.onset {
+            return if value >= self.full { self.weight } else { 0.0 };
+        }
+        let past = (value - self.onset) / (self.full - self.onset);
+        self.weight * past.clamp(0.0, 1.0)
+    }
+}
+
+/// the rules when the server is not told, in the units of the [Factor]s
+pub const RULES: &[(Factor, Rule)] = &[
+    (Factor::DropFrames, Rule::new(30.0, 0.0, 0.2)),
+    (Factor::SlowSpeed, Rule::new(35.0, 0.05, 0.5)),
+    (Factor::StaleProgress, Rule::new(40.0, 5.0, 30.0)),
+    (Factor::LowDisk, Rule::new(30.0, 1800.0, 120.0)),
+    (Factor::CpuSaturated, Rule::new(15.0, 0.9, 2.0)),
+    (Factor::GpuSaturated, Rule::new(15.0, 85.0, 100.0)),
+    (Factor::AudioDisconnected, Rule::new(25.0, 0.0, 1.0)),
+    (Factor::GeometryChanged, Rule::new(10.0, 0.0, 2.0)),
+];
+
+/// A rule given to the server, `<factor>=<weight>[:<onset>:<full>]`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RuleOverride {
+    pub factor: Factor,
+    /// the weight alone keeps the onset and the full of the table
+    pub weight: f64,
+    pub bounds: Option<(f64, f64)>,
+}
+
+impl FromStr for RuleOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expected = "expected '<factor>=<weight>[:<onset>:<full>]', e.g. slow_speed=40:0.1:0.5";
+        let (factor, rule) = s.split_once('=').ok_or(expected)?;
+        let numbers: Vec<f64> = rule
+            .split(':')
+            .map(|n| n.trim().parse().map_err(|_| expected.to_string()))
+            .collect::<Result<_, _>>()?;
+        let (weight, bounds) = match numbers[..] {
+            [weight] => (weight, None),
+            [weight, onset, full] => (weight, Some((onset, full))),
+            _ => return Err(expected.to_string()),
+        };
+        if !(0.0..=100.0).contains(&weight) {
+            return Err("a weight must be within 0..100".to_string());
+        }
+        Ok(Self {
+            factor: factor.parse()?,
+            weight,
+            bounds,
+        })
+    }
+}
+
+/// How the recordings are scored
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub rules: BTreeMap<Factor, Rule>,
+    /// a score below is degraded
+    pub degraded: u8,
+    /// a score below is critical
+    pub critical: u8,
+    /// points past a threshold before a level is left for a better one
+    pub hysteresis: u8,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            rules: RULES.iter().copied().collect(),
+            degraded: 75,
+            critical: 45,
+            hysteresis: 5,
+        }
+    }
+}
+
+impl Config {
+    /// the defaults with `rules` over them
+    pub fn new(rules: Vec<RuleOverride>, degraded: u8, critical: u8) -> Self {
+        let mut config = Self {
+            degraded: degraded.min(100),
+            critical: critical.min(degraded),
+            ..Default::default()
+        };
+        for rule in rules {
+            let entry = config
+                .rules
+                .entry(rule.factor)
+                .or_insert(Rule::new(0.0, 0.0, 1.0));
+            entry.weight = rule.weight;
+            if let Some((onset, full)) = rule.bounds {
+                (entry.onset, entry.full) = (onset, full);
+            }
+        }
+        config
+    }
+}
+
+/// What is known of a recording at one time, None for what can't be told
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Signals {
+    pub drop_fraction: Option<f64>,
+    pub speed: Option<f64>,
+    pub stale_secs: f64,
+    pub disk_secs_left: Option<f64>,
+    pub cpu_load: Option<f64>,
+    pub gpu_pct: Option<f64>,
+    pub audio_disconnected: bool,
+    pub geometry_changes: u32,
+}
+
+impl Signals {
+    /// the value of the factor, in its unit
+    pub fn value(&self, factor: Factor) -> Option<f64> {
+        match factor {
+            Factor::DropFrames => self.drop_fraction,
+            Factor::SlowSpeed => self.speed.map(|speed| 1.0 - speed),
+            Factor::StaleProgress => Some(self.stale_secs),
+            Factor::LowDisk => self.disk_secs_left,
+            Factor::CpuSaturated => self.cpu_load,
+            Factor::GpuSaturated => self.gpu_pct,
+            Factor::AudioDisconnected => Some(self.audio_disconnected as u8 as f64),
+            Factor::GeometryChanged => Some(self.geometry_changes as f64),
+        }
+    }
+}
+
+/// A score with what took from it, the most first
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assessment {
+    pub score: u8,
+    pub penalties: Vec<(Factor, f64)>,
+}
+
+impl Assessment {
+    /// the factors that took the most
+    pub fn top(&self) -> Vec<Factor> {
+        self.penalties
+            .iter()
+            .take(TOP_FACTORS)
+            .map(|(factor, _)| *factor)
+            .collect()
+    }
+}
+
+/// the score of the signals
+pub fn assess(config: &Config, signals: &Signals) -> Assessment {
+    let mut penalties: Vec<(Factor, f64)> = config
+        .rules
+        .iter()
+        .filter_map(|(factor, rule)| Some((*factor, rule.penalty(signals.value(*factor)?))))
+        .filter(|(_, penalty)| *penalty > 0.0)
+        .collect();
+    penalties.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    let taken: f64 = penalties.iter().map(|(_, penalty)| penalty).sum();
+    Assessment {
+        score: (100.0 - taken).round().clamp(0.0, 100.0) as u8,
+        penalties,
+    }
+}
+
+/// How a recording goes, from its score
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Level {
+    #[default]
+    Healthy,
+    Degraded,
+    Critical,
+}
+
+impl Level {
+    fn of(score: u8, config: &Config, margin: u8) -> Self {
+        let score = score as u16;
+        if score < config.critical as u16 + margin as u16 {
+            Self::Critical
+        } else if score < config.degraded as u16 + margin as u16 {
+            Self::Degraded
+        } else {
+            Self::Healthy
+        }
+    }
+
+    /// the level after this one at `score`: worse right away, better past the hysteresis
+    pub fn next(self, score: u8, config: &Config) -> Self {
+        let now = Self::of(score, config, 0);
+        match now > self {
+            true => now,
+            false => Self::of(score, config, config.hysteresis).min(self),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Healthy => "healthy",
+            Self::Degraded => "degraded",
+            Self::Critical => "critical",
+        }
+    }
+}
+
+/// The health of a recording, in its state
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Health {
+    pub score: u8,
+    pub level: Level,
+    /// the codes that took the most from the score, the most first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub factors: Vec<Factor>,
+}
+
+/// The lowest score of a recording, for its history entry
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Low {
+    pub score: u8,
+    pub at: DateTime<Local>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub factors: Vec<Factor>,
+}
+
+struct Tracked {
+    started_at: DateTime<Local>,
+    current: Option<(Health, Vec<(Factor, f64)>)>,
+    low: Option<Low>,
+}
+
+/// The scores of the server: its config, and the recording scored last
+#[derive(Default)]
+pub struct Monitor {
+    pub config: Config,
+    tracked: Mutex<Option<Tracked>>,
+}
+
+impl Monitor {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            tracked: Mutex::new(None),
+        }
+    }
+
+    /// the lowest score of the recording scored last, taken for its history entry
+    pub fn take_low(&self) -> Option<Low> {
+        self.tracked.lock().unwrap().as_mut()?.low.take()
+    }
+
+    /// the score of the recording now, in the text format of Prometheus
+    pub fn metrics(&self) -> String {
+        let tracked = self.tracked.lock().unwrap();
+        let Some((health, penalties)) = tracked.as_ref().and_then(|t| t.current.as_ref()) else {
+            return String::new();
+        };
+        let mut metrics = String::new();
+        metrics += "# HELP record_screen_health_score Health of the recording, 100 down to 0.\n";
+        metrics += "# TYPE record_screen_health_score gauge\n";
+        metrics += &format!("record_screen_health_score {}\n", health.score);
+        metrics += "# HELP record_screen_health_level Level of the health: 0 healthy, 1 degraded, 2 critical.\n";
+        metrics += "# TYPE record_screen_health_level gauge\n";
+        metrics += &format!("record_screen_health_level {}\n", health.level as u8);
+        metrics += "# HELP record_screen_health_penalty Points a factor takes from the health.\n";
+        metrics += "# TYPE record_screen_health_penalty gauge\n";
+        for (factor, penalty) in penalties {
+            metrics += &format!(
+                "record_screen_health_penalty{{factor=\"{}\"}} {:.1}\n",
+                factor.name(),
+                penalty
+            );
+        }
+        metrics
+    }
+}
+
+/// The progress of the process being scored, over the [WINDOW]
+#[derive(Debug)]
+struct Sampler {
+    since: Instant,
+    last: Option<Instant>,
+    /// when, the frames dropped and the bytes written
+    samples: VecDeque<(Instant, u64, u64)>,
+    speed: Option<f64>,
+    fps: Option<f64>,
+}
+
+impl Sampler {
+    fn new() -> Self {
+        Self {
+            since: Instant::now(),
+            last: None,
+            samples: VecDeque::new(),
+            speed: None,
+            fps: None,
+        }
+    }
+
+    fn push(&mut self, progress: &Progress) {
+        let now = Instant::now();
+        self.last = Some(now);
+        self.speed = progress.speed.or(self.speed);
+        self.fps = progress.fps.filter(|fps| *fps > 0.0).or(self.fps);
+        self.samples.push_back((
+            now,
+            progress.drop_frames.unwrap_or_default(),
+            progress.total_size.unwrap_or_default(),
+        ));
+        while self
+            .samples
+            .front()
+            .is_some_and(|(at, ..)| now.duration_since(*at) > WINDOW)
+        {
+            self.samples.pop_front();
+        }
+    }
+
+    /// the frames dropped a second and the bytes written a second over the window
+    fn rates(&self) -> Option<(f64, f64)> {
+        let (first, last) = (self.samples.front()?, self.samples.back()?);
+        let secs = last.0.duration_since(first.0).as_secs_f64();
+        if secs < 1.0 {
+            return None;
+        }
+        Some((
+            last.1.saturating_sub(first.1) as f64 / secs,
+            last.2.saturating_sub(first.2) as f64 / secs,
+        ))
+    }
+
+    fn stale_secs(&self) -> f64 {
+        self.last.unwrap_or(self.since).elapsed().as_secs_f64()
+    }
+}
+
+/// the load average of the last minute by CPU
+fn cpu_load() -> Option<f64> {
+    let loadavg = std::fs::read_to_string("/proc/loadavg").ok()?;
+    let load: f64 = loadavg.split_whitespace().next()?.parse().ok()?;
+    let cpus = std::thread::available_parallelism().ok()?.get();
+    Some(load / cpus as f64)
+}
+
+/// the seconds until the output directory is down to `min_free` at `rate` bytes a second
+fn disk_secs_left(min_free: u64, rate: f64) -> Option<f64> {
+    let free = liveness::free_bytes(&recordings::output_dir().ok()?).ok()?;
+    match (free.saturating_sub(min_free), rate > 0.0) {
+        (0, _) => Some(0.0),
+        (_, false) => None,
+        (left, true) => Some(left as f64 / rate),
+    }
+}
+
+/// what is scored of a state: the recording it is of and whether it captures
+fn phase(state: &RecordingState) -> Option<(Option<DateTime<Local>>, bool)> {
+    match state {
+        RecordingState::Started { started_at, .. } => Some((Some(*started_at), true)),
+        RecordingState::Paused { recording, .. } => phase(recording).map(|(at, _)| (at, false)),
+        RecordingState::Compressing { .. } => Some((None, false)),
+        _ => None,
+    }
+}
+
+/// score the recordings for as long as the server runs
+pub async fn watch(mx: Arc<Recorder>) {
+    let mut live = mx.subscribe(None).await.live;
+    let mut tick = tokio::time::interval(TICK);
+    let mut sampler = Sampler::new();
+    let mut was: Option<(Option<DateTime<Local>>, bool)> = None;
+    let mut level = Level::Healthy;
+    loop {
+        tokio::select! {
+            event = live.recv() => match event {
+                Ok(event) => {
+                    if let EventKind::Progress { progress, .. } = event.kind {
+                        sampler.push(&progress);
+                    }
+                }
+                Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return,
+            },
+            _ = tick.tick() => {
+                let now = phase(&*mx.lock().await);
+                if now.map(|(_, capturing)| capturing) != was.map(|(_, capturing)| capturing) {
+                    sampler = Sampler::new();
+                }
+                if let Some((Some(started_at), _)) = now {
+                    if was.and_then(|(was, _)| was) != Some(started_at) {
+                        level = Level::Healthy;
+                    }
+                }
+                was = now;
+                match now {
+                    Some((started_at, capturing)) => {
+                        level = score(&mx, started_at, capturing, &sampler, level).await;
+                    }
+                    None => {
+                        if let Some(tracked) = mx.health.tracked.lock().unwrap().as_mut() {
+                            tracked.current = None;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// score the recording now, returning its new level
+async fn score(
+    mx: &Recorder,
+    started_at: Option<DateTime<Local>>,
+    capturing: bool,
+    sampler: &Sampler,
+    level: Level,
+) -> Level {
+    let config = &mx.health.config;
+    let rates = sampler.rates();
+    let mut signals = Signals {
+        stale_secs: sampler.stale_secs(),
+        cpu_load: cpu_load(),
+        gpu_pct: mx.gpu.lock().unwrap().as_ref().and_then(|usage| {
+            match (usage.encoder_util_pct, usage.gpu_util_pct) {
+                (Some(a), Some(b)) => Some(a.max(b)),
+                (a, b) => a.or(b),
+            }
+        }),
+        disk_secs_left: rates
+            .and_then(|(_, written)| disk_secs_left(mx.liveness.config.min_free_bytes, written)),
+        ..Default::default()
+    };
+    if capturing {
+        let fps = sampler.fps.unwrap_or(quality::SCREEN_FRAMERATE as f64);
+        signals.drop_fraction = rates.map(|(dropped, _)| dropped / fps);
+        signals.speed = sampler.speed;
+    }
+
+    let mut state = mx.lock().await;
+    let health = match &mut *state {
+        RecordingState::Started {
+            started_at,
+            audio,
+            markers,
+            health,
+            ..
+        } => {
+            signals.audio_disconnected = audio.as_ref().is_some_and(|audio| !audio.connected);
+            let recent = since(*started_at).saturating_sub(GEOMETRY_WINDOW_MS);
+            signals.geometry_changes = markers
+                .iter()
+                .filter(|m| m.label == geometry::CHANGED && m.at_ms >= recent)
+                .count() as u32;
+            health
+        }
+        RecordingState::Compressing { health, .. } => health,
+        _ => return level,
+    };
+    let assessment = assess(config, &signals);
+    let next = level.next(assessment.score, config);
+    let scored = Health {
+        score: assessment.score,
+        level: next,
+        factors: assessment.top(),
+    };
+    let changed = health.as_ref() != Some(&scored);
+    *health = Some(scored.clone());
+    if changed {
+        let updated = state.clone();
+        mx.replace(&mut state, updated);
+    }
+    drop(state);
+
+    {
+        let mut tracked = mx.health.tracked.lock().unwrap();
+        // a compression goes on with the recording it compresses
+        let started_at = started_at
+            .or(tracked.as_ref().map(|t| t.started_at))
+            .unwrap_or_else(Local::now);
+        let tracked = match tracked.as_mut().filter(|t| t.started_at == started_at) {
+            Some(tracked) => tracked,
+            None => tracked.insert(Tracked {
+                started_at,
+                current: None,
+                low: None,
+            }),
+        };
+        if tracked
+            .low
+            .as_ref()
+            .is_none_or(|low| scored.score < low.score)
+        {
+            tracked.low = Some(Low {
+                score: scored.score,
+                at: Local::now(),
+                factors: scored.factors.clone(),
+            });
+        }
+        tracked.current = Some((scored.clone(), assessment.penalties));
+    }
+
+    if next != level {
+        let factors: Vec<_> = scored.factors.iter().map(Factor::name).collect();
+        warn!(
+            "the recording is {} at {}, was {}: {}",
+            next.name(),
+            scored.score,
+            level.name(),
+            factors.join(", ")
+        );
+        mx.events.publish(EventKind::Health {
+            previous: level,
+            health: scored,
+        });
+    }
+    next
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_rule_takes_in_proportion_between_its_bounds() {
+        let rule = Rule::new(30.0, 0.0, 0.2);
+        assert_eq!(rule.penalty(-1.0), 0.0);
+        assert_eq!(rule.penalty(0.0), 0.0);
+        assert!((rule.penalty(0.1) - 15.0).abs() < 1e-9);
+        assert_eq!(rule.penalty(0.2), 30.0);
+        assert_eq!(rule.penalty(5.0), 30.0);
+        // worse the lower it is
+        let disk = Rule::new(30.0, 1800.0, 120.0);
+        assert_eq!(disk.penalty(3600.0), 0.0);
+        assert_eq!(disk.penalty(1800.0), 0.0);
+        assert!((disk.penalty(960.0) - 15.0).abs() < 1e-9);
+        assert_eq!(disk.penalty(120.0), 30.0);
+        assert_eq!(disk.penalty(0.0), 30.0);
+        // a step
+        let step = Rule::new(25.0, 1.0, 1.0);
+        assert_eq!(step.penalty(0.99), 0.0);
+        assert_eq!(step.penalty(1.0), 25.0);
+    }
+
+    #[test]
+    fn the_score_is_what_is_left_of_100() {
+        let config = Config::default();
+        assert_eq!(assess(&config, &Signals::default()).score, 100);
+        let signals = Signals {
+            drop_fraction: Some(0.1),
+            speed: Some(1.0),
+            stale_secs: 60.0,
+            audio_disconnected: true,
+            ..Default::default()
+        };
+        let assessment = assess(&config, &signals);
+        // 15 + 40 + 25 taken, realtime and unknown factors take nothing
+        assert_eq!(assessment.score, 20);
+        assert_eq!(
+            assessment.top(),
+            vec![
+                Factor::StaleProgress,
+                Factor::AudioDisconnected,
+                Factor::DropFrames
+            ]
+        );
+        assert_eq!(assessment.penalties.len(), 3);
+        let worst = Signals {
+            geometry_changes: 10,
+            cpu_load: Some(4.0),
+            gpu_pct: Some(100.0),
+            disk_secs_left: Some(0.0),
+            speed: Some(0.0),
+            ..signals
+        };
+        let assessment = assess(&config, &worst);
+        assert_eq!(assessment.score, 0);
+        assert_eq!(assessment.top().len(), TOP_FACTORS);
+    }
+
+    #[test]
+    fn the_score_is_rounded() {
+        let config = Config::new(vec!["stale_progress=10:0:1".parse().unwrap()], 75, 45);
+        let at = |stale_secs| {
+            assess(
+                &config,
+                &Signals {
+                    stale_secs,
+                    ..Default::default()
+                },
+            )
+            .score
+        };
+        assert_eq!(at(0.04), 100);
+        assert_eq!(at(0.06), 99);
+        assert_eq!(at(0.5), 95);
+    }
+
+    #[test]
+    fn a_level_is_worse_right_away() {
+        let config = Config::default();
+        assert_eq!(Level::Healthy.next(75, &config), Level::Healthy);
+        assert_eq!(Level::Healthy.next(74, &config), Level::Degraded);
+        assert_eq!(Level::Healthy.next(45, &config), Level::Degraded);
+        assert_eq!(Level::Healthy.next(44, &config), Level::Critical);
+        assert_eq!(Level::Degraded.next(0, &config), Level::Critical);
+    }
+
+    #[test]
+    fn a_level_is_better_past_the_hysteresis() {
+        let config = Config::default();
+        assert_eq!(Level::Critical.next(45, &config), Level::Critical);
+        assert_eq!(Level::Critical.next(49, &config), Level::Critical);
+        assert_eq!(Level::Critical.next(50, &config), Level::Degraded);
+        assert_eq!(Level::Degraded.next(79, &config), Level::Degraded);
+        assert_eq!(Level::Degraded.next(80, &config), Level::Healthy);
+        assert_eq!(Level::Critical.next(100, &config), Level::Healthy);
+    }
+
+    #[test]
+    fn the_thresholds_are_kept_in_order() {
+        let config = Config::new(vec![], 120, 90);
+        assert_eq!((config.degraded, config.critical), (100, 90));
+        let config = Config::new(vec![], 50, 60);
+        assert_eq!((config.degraded, config.critical), (50, 50));
+    }
+
+    #[test]
+    fn a_rule_is_overridden() {
+        let weight: RuleOverride = "cpu_saturated=50".parse().unwrap();
+        let bounds: RuleOverride = "slow_speed=40:0.1:0.5".parse().unwrap();
+        assert_eq!(bounds.bounds, Some((0.1, 0.5)));
+        let config = Config::new(vec![weight, bounds], 75, 45);
+        assert_eq!(
+            config.rules[&Factor::CpuSaturated],
+            Rule::new(50.0, 0.9, 2.0)
+        );
+        assert_eq!(config.rules[&Factor::SlowSpeed], Rule::new(40.0, 0.1, 0.5));
+        for refused in [
+            "cpu_saturated",
+            "cpu_saturated=",
+            "cpu_saturated=101",
+            "cpu_saturated=-1",
+            "cpu_saturated=10:1",
+            "nothing=10",
+        ] {
+            assert!(refused.parse::<RuleOverride>().is_err(), "{}", refused);
+        }
+    }
+}
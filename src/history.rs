@@ -0,0 +1,397 @@
+//! History of the finished recordings
+//!
+//! Entries are appended to a JSON lines file and indexed in memory by their finish time and id,
+//! newest first. Pages are addressed by an opaque cursor made of that key, which stays stable
+//! while new entries are appended.
+use crate::content_check::ContentWarning;
+use crate::health::Low;
+use crate::latency::FirstFrame;
+use crate::presence::Identity;
+use crate::recordings::Syncer;
+use crate::schema::{self, Versioned};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::*;
+
+pub const DEFAULT_LIMIT: usize = 50;
+pub const MAX_LIMIT: usize = 500;
+
+/// A finished recording
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub id: u64,
+    /// when the recording reached its final state
+    pub at: DateTime<Local>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<DateTime<Local>>,
+    /// final state, lowercase: `done`, `failed`
+    pub state: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// checksum manifest of the file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifest: Option<String>,
+    /// program and arguments of every ffmpeg run for the recording, in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub commands: Vec<Vec<String>>,
+    /// sidecar with the capture time of every frame
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frame_timestamps: Option<String>,
+    /// the client that asked for the recording
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_by: Option<Identity>,
+    /// the client that asked to stop it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stopped_by: Option<Identity>,
+    /// what the content suggests went wrong, the recording is suspect with any
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub content_warnings: Vec<ContentWarning>,
+    /// when the first frame came rather than the spawn, estimated or measured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first_frame: Option<FirstFrame>,
+    /// the lowest health of the recording and what took from it, see [crate::health]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_health: Option<Low>,
+}
+
+impl HistoryEntry {
+    /// an entry of a recording of `owner` that reached its final `state` at `at`, with nothing
+    /// else known of it yet; its id is assigned by [History::append]
+    pub fn new(state: &str, at: DateTime<Local>, owner: Option<String>) -> Self {
+        Self {
+            id: 0,
+            at,
+            started_at: None,
+            state: state.to_string(),
+            file: None,
+            owner,
+            size: None,
+            manifest: None,
+            commands: vec![],
+            frame_timestamps: None,
+            started_by: None,
+            stopped_by: None,
+            content_warnings: vec![],
+            first_frame: None,
+            min_health: None,
+        }
+    }
+}
+
+impl Versioned for HistoryEntry {
+    const KIND: &'static str = "history entries";
+    const VERSION: u32 = 1;
+    const MIGRATIONS: &'static [schema::Migration] = &[schema::unversioned];
+}
+
+/// Paging and filters of a listing
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PageQuery {
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+    pub cursor: Option<String>,
+    pub state: Option<String>,
+    pub from: Option<DateTime<Local>>,
+    pub to: Option<DateTime<Local>>,
+    pub owner: Option<String>,
+    /// only the recordings with content warnings, or only those without
+    pub suspect: Option<bool>,
+}
+
+impl PageQuery {
+    pub fn limit(&self) -> usize {
+        self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+    }
+}
+
+/// A page of entries
+#[derive(Debug, Clone, Serialize)]
+pub struct Page<T> {
+    pub entries: Vec<T>,
+    /// pass as `?cursor=` to get the next page, absent on the last one
+    pub next_cursor: Option<String>,
+    /// entries matching the filters, when known without a scan
+    #[serde(skip)]
+    pub total: Option<usize>,
+}
+
+type Key = (i64, u64);
+
+fn cursor_of(key: Key) -> String {
+    format!("{:x}.{:x}", key.0, key.1)
+}
+
+fn parse_cursor(cursor: &str) -> Option<Key> {
+    let (at, id) = cursor.split_once('.')?;
+    Some((
+        i64::from_str_radix(at, 16).ok()?,
+        u64::from_str_radix(id, 16).ok()?,
+    ))
+}
+
+#[derive(Default)]
+struct Inner {
+    path: Option<PathBuf>,
+    entries: BTreeMap<Key, HistoryEntry>,
+    next_id: u64,
+    by_state: HashMap<String, usize>,
+}
+
+impl Inner {
+    fn index(&mut self, entry: HistoryEntry) {
+        self.next_id = self.next_id.max(entry.id + 1);
+        *self.by_state.entry(entry.state.clone()).or_default() += 1;
+        self.entries
+            .insert((entry.at.timestamp_millis(), entry.id), entry);
+    }
+}
+
+/// The history store, in memory only unless opened on a file
+#[derive(Default)]
+pub struct History {
+    inner: Mutex<Inner>,
+}
+
+impl History {
+    /// load the history from its file, quarantining the lines that can't be parsed
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut inner = Inner {
+            path: Some(path.to_path_buf()),
+            ..Default::default()
+        };
+        let (entries, report) = schema::read_lines::<HistoryEntry>(path)?;
+        info!("{}", report);
+        for entry in entries {
+            inner.index(entry);
+        }
+        Ok(Self {
+            inner: Mutex::new(inner),
+        })
+    }
+
+    /// record an entry, its id is assigned here
+    pub fn append(&self, entry: HistoryEntry) -> anyhow::Result<HistoryEntry> {
+        self.write(entry, None)
+    }
+
+    /// [Self::append], the file and its directory fsynced before it returns
+    pub fn append_durable(
+        &self,
+        entry: HistoryEntry,
+        fsync: &Syncer,
+    ) -> anyhow::Result<HistoryEntry> {
+        self.write(entry, Some(fsync))
+    }
+
+    fn write(
+        &self,
+        mut entry: HistoryEntry,
+        fsync: Option<&Syncer>,
+    ) -> anyhow::Result<HistoryEntry> {
+        let mut inner = self.inner.lock().unwrap();
+        entry.id = inner.next_id;
+        if let Some(path) = &inner.path {
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?;
+            writeln!(file, "{}", schema::to_string(&entry)?)?;
+            if let Some(fsync) = fsync {
+                fsync.sync_file_blocking(path)?;
+            }
+        }
+        inner.index(entry.clone());
+        Ok(entry)
+    }
+
+    /// the owner of the recording done as `file`, None without one or when it isn't known
+    pub fn owner_of(&self, file: &str) -> Option<String> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .entries
+            .values()
+            .rev()
+            .find(|e| e.state == "done" && e.file.as_deref() == Some(file))
+            .and_then(|e| e.owner.clone())
+    }
+
+    /// a page of entries, newest first
+    pub fn page(&self, query: &PageQuery) -> anyhow::Result<Page<HistoryEntry>> {
+        let inner = self.inner.lock().unwrap();
+        let upper = match &query.cursor {
+            Some(cursor) => {
+                Some(parse_cursor(cursor).ok_or_else(|| anyhow::anyhow!("invalid cursor"))?)
+            }
+            None => None,
+        };
+        let range = match upper {
+            Some(key) => inner.entries.range(..key),
+            None => inner.entries.range(..),
+        };
+        let limit = query.limit();
+        // the cursor already says where to continue
+        let skip = if upper.is_none() {
+            query.offset.unwrap_or(0)
+        } else {
+            0
+        };
+        let mut matching = range
+            .rev()
+            .filter(|(_, e)| {
+                query.state.as_ref().is_none_or(|s| &e.state == s)
+                    && query
+                        .owner
+                        .as_ref()
+                        .is_none_or(|o| e.owner.as_ref() == Some(o))
+                    && query.from.is_none_or(|from| e.at >= from)
+                    && query.to.is_none_or(|to| e.at < to)
+                    && query
+                        .suspect
+                        .is_none_or(|suspect| e.content_warnings.is_empty() != suspect)
+            })
+            .skip(skip);
+        let mut entries = vec![];
+        let mut last = None;
+        for (key, entry) in matching.by_ref().take(limit) {
+            last = Some(*key);
+            entries.push(entry.clone());
+        }
+        let next_cursor = match (last, matching.next()) {
+            (Some(key), Some(_)) => Some(cursor_of(key)),
+            _ => None,
+        };
+        let total = match (
+            &query.state,
+            &query.owner,
+            query.from,
+            query.to,
+            query.suspect,
+        ) {
+            (None, None, None, None, None) => Some(inner.entries.len()),
+            (Some(state), None, None, None, None) => Some(*inner.by_state.get(state).unwrap_or(&0)),
+            _ => None,
+        };
+        Ok(Page {
+            entries,
+            next_cursor,
+            total,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a line of the first histories, before there were versions, ids or owners of clients
+    const UNVERSIONED: &str = r#"{"id":3,"at":"2024-05-01T14:30:00+02:00","started_at":"2024-05-01T14:00:00+02:00","state":"done","file":"/videos/a.compressed.mp4","size":1234,"manifest":"/videos/a.compressed.mp4.sha256"}"#;
+    /// a line of a failure of the first histories, with nothing but its state
+    const UNVERSIONED_FAILURE: &str =
+        r#"{"id":4,"at":"2024-05-01T15:00:00+02:00","state":"failed"}"#;
+    /// a line of a newer server, with a field this one doesn't know
+    const NEWER: &str = r#"{"schema_version":7,"id":5,"at":"2024-05-02T09:00:00+02:00","state":"done","file":"/videos/b.mp4","rating":5}"#;
+
+    #[test]
+    fn a_line_without_a_version_is_migrated() {
+        let (entry, migrated) = schema::from_str::<HistoryEntry>(UNVERSIONED).unwrap();
+        assert!(migrated);
+        assert_eq!(entry.id, 3);
+        assert_eq!(entry.state, "done");
+        assert_eq!(entry.file.as_deref(), Some("/videos/a.compressed.mp4"));
+        assert_eq!(entry.size, Some(1234));
+        let instant = |t: &str| DateTime::parse_from_rfc3339(t).unwrap();
+        assert_eq!(entry.at, instant("2024-05-01T14:30:00+02:00"));
+        assert_eq!(
+            entry.started_at.unwrap(),
+            instant("2024-05-01T14:00:00+02:00")
+        );
+        // what came later is absent
+        assert!(entry.commands.is_empty());
+        assert_eq!(entry.started_by, None);
+        assert!(entry.content_warnings.is_empty());
+        assert_eq!(entry.min_health, None);
+    }
+
+    #[test]
+    fn a_line_with_nothing_but_its_state_loads() {
+        let (entry, _) = schema::from_str::<HistoryEntry>(UNVERSIONED_FAILURE).unwrap();
+        assert_eq!(entry.state, "failed");
+        assert_eq!(entry.file, None);
+        assert_eq!(entry.owner, None);
+    }
+
+    #[test]
+    fn a_line_of_a_newer_server_loads_without_what_it_added() {
+        let (entry, migrated) = schema::from_str::<HistoryEntry>(NEWER).unwrap();
+        assert!(!migrated);
+        assert_eq!(entry.id, 5);
+        assert_eq!(entry.file.as_deref(), Some("/videos/b.mp4"));
+    }
+
+    #[test]
+    fn a_written_line_reads_back() {
+        let entry = HistoryEntry {
+            started_by: Some(Identity {
+                name: Some("ci".to_string()),
+                ..Default::default()
+            }),
+            commands: vec![vec!["ffmpeg".to_string(), "-y".to_string()]],
+            ..HistoryEntry::new("cancelled", Local::now(), Some("alice".to_string()))
+        };
+        let line = schema::to_string(&entry).unwrap();
+        assert!(line.contains(r#""schema_version":1"#), "{}", line);
+        let (read, migrated) = schema::from_str::<HistoryEntry>(&line).unwrap();
+        assert!(!migrated);
+        assert_eq!(read.state, "cancelled");
+        assert_eq!(read.owner.as_deref(), Some("alice"));
+        assert_eq!(read.started_by, entry.started_by);
+        assert_eq!(read.commands, entry.commands);
+        assert_eq!(read.at, entry.at);
+    }
+
+    #[test]
+    fn a_history_file_of_every_version_loads_and_quarantines_the_rest() {
+        let dir =
+            std::env::temp_dir().join(format!("record-screen-history-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history.jsonl");
+        let current = schema::to_string(&HistoryEntry::new("done", Local::now(), None)).unwrap();
+        let lines = [
+            UNVERSIONED,
+            "{not json",
+            UNVERSIONED_FAILURE,
+            NEWER,
+            "[1,2]",
+            &current,
+        ];
+        std::fs::write(&path, lines.join("\n")).unwrap();
+
+        let history = History::open(&path).unwrap();
+        let page = history.page(&PageQuery::default()).unwrap();
+        assert_eq!(page.entries.len(), 4);
+        // the newest first
+        assert_eq!(page.entries[0].state, "done");
+        assert_eq!(page.entries.last().map(|e| e.id), Some(3));
+        let kept = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(kept.lines().count(), 4);
+        let quarantined = std::fs::read_to_string(schema::quarantine_path(&path)).unwrap();
+        assert_eq!(
+            quarantined.lines().collect::<Vec<_>>(),
+            ["{not json", "[1,2]"]
+        );
+        // the ids go on after those of the file
+        let appended = history
+            .append(HistoryEntry::new("failed", Local::now(), None))
+            .unwrap();
+        assert_eq!(appended.id, 6);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,198 @@
+//! Hardware encoding of the video, with VAAPI or NVENC
+//!
+//! The software x264 of a screen capture takes a core of the CPU; the `encoder` of a recording
+//! moves its capture and its compression to the video engine of the GPU instead. What ffmpeg can
+//! encode with is asked of `ffmpeg -encoders` once, when an encoder is first needed, and VAAPI
+//! needs its render node [VAAPI_DEVICE] too: a recording asking for an encoder that is missing
+//! is refused at its start, rather than ffmpeg failing in the middle of it.
+use crate::ffmpeg::Parameter;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::process::Stdio;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::OnceCell;
+use tracing::*;
+
+/// the render node VAAPI encodes with
+pub const VAAPI_DEVICE: &str = "/dev/dri/renderD128";
+
+/// how long `ffmpeg -encoders` may take
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What encodes the video of a recording
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum VideoEncoder {
+    /// libx264 on the CPU
+    #[default]
+    Software,
+    /// the video engine of an Intel or AMD GPU
+    Vaapi,
+    /// the video engine of an NVIDIA GPU
+    Nvenc,
+}
+
+impl VideoEncoder {
+    pub const ALL: [Self; 3] = [Self::Software, Self::Vaapi, Self::Nvenc];
+
+    pub fn is_software(&self) -> bool {
+        *self == Self::Software
+    }
+
+    /// the ffmpeg encoder of the h264 video
+    pub fn codec(self) -> &'static str {
+        match self {
+            Self::Software => "libx264",
+            Self::Vaapi => "h264_vaapi",
+            Self::Nvenc => "h264_nvenc",
+        }
+    }
+
+    /// the options of ffmpeg before its inputs
+    pub fn global_options(self) -> Vec<Parameter<'static>> {
+        match self {
+            Self::Vaapi => vec![Parameter::KeyValue("vaapi_device", VAAPI_DEVICE)],
+            _ => vec![],
+        }
+    }
+
+    /// the filters ending with the frames uploaded to the GPU, when it needs them there
+    pub fn filters(self, filters: Option<String>) -> Option<String> {
+        match (self, filters) {
+            (Self::Vaapi, Some(filters)) => Some(format!("{},format=nv12,hwupload", filters)),
+            (Self::Vaapi, None) => Some("format=nv12,hwupload".to_string()),
+            (_, filters) => filters,
+        }
+    }
+
+    /// the option a rate control of x264, `crf` or `qp`, is given to this encoder as
+    pub fn rate_option(self, x264: &'static str) -> &'static str {
+        match (self, x264) {
+            (Self::Software, option) => option,
+            (Self::Vaapi, _) => "qp",
+            (Self::Nvenc, "qp") => "qp",
+            (Self::Nvenc, _) => "cq",
+        }
+    }
+}
+
+impl std::fmt::Display for VideoEncoder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Software => "software",
+            Self::Vaapi => "vaapi",
+            Self::Nvenc => "nvenc",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Whether this machine can encode with an encoder
+#[derive(Debug, Clone, Serialize)]
+pub struct EncoderStatus {
+    pub encoder: VideoEncoder,
+    pub codec: &'static str,
+    pub available: bool,
+    /// why it is not
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("the {encoder} encoder is not available: {reason}")]
+    Unavailable {
+        encoder: VideoEncoder,
+        reason: String,
+    },
+}
+
+/// the encoders of this machine, probed once
+pub async fn detect() -> &'static [EncoderStatus] {
+    static DETECTED: OnceCell<Vec<EncoderStatus>> = OnceCell::const_new();
+    DETECTED.get_or_init(probe).await
+}
+
+/// whether the recording can encode with `encoder`, the software one needs no probe
+pub async fn check(encoder: VideoEncoder) -> Result<(), Error> {
+    if encoder.is_software() {
+        return Ok(());
+    }
+    match detect().await.iter().find(|s| s.encoder == encoder) {
+        Some(status) if status.available => Ok(()),
+        status => Err(Error::Unavailable {
+            encoder,
+            reason: status
+                .and_then(|s| s.reason.clone())
+                .unwrap_or_else(|| "not probed".to_string()),
+        }),
+    }
+}
+
+async fn probe() -> Vec<EncoderStatus> {
+    let encoders = match list_encoders().await {
+        Ok(encoders) => Ok(encoders),
+        Err(e) => {
+            warn!("cannot list the encoders of ffmpeg: {}", e);
+            Err(e.to_string())
+        }
+    };
+    let statuses: Vec<_> = VideoEncoder::ALL
+        .into_iter()
+        .map(|encoder| {
+            let reason = match &encoders {
+                Err(e) => Some(format!("ffmpeg -encoders failed: {}", e)),
+                Ok(encoders) if !encoders.contains(encoder.codec()) => {
+                    Some(format!("ffmpeg has no {}", encoder.codec()))
+                }
+                Ok(_)
+                    if encoder == VideoEncoder::Vaapi
+                        && !std::path::Path::new(VAAPI_DEVICE).exists() =>
+                {
+                    Some(format!("there is no {}", VAAPI_DEVICE))
+                }
+                Ok(_) => None,
+            };
+            EncoderStatus {
+                encoder,
+                codec: encoder.codec(),
+                available: reason.is_none(),
+                reason,
+            }
+        })
+        .collect();
+    info!(
+        "video encoders: {:?}",
+        statuses
+            .iter()
+            .filter(|s| s.available)
+            .map(|s| s.encoder)
+            .collect::<Vec<_>>()
+    );
+    statuses
+}
+
+/// the names of the encoders ffmpeg lists
+async fn list_encoders() -> anyhow::Result<HashSet<String>> {
+    let output = tokio::process::Command::new("ffmpeg")
+        .args(["-hide_banner", "-encoders"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(PROBE_TIMEOUT, output)
+        .await
+        .map_err(|_| anyhow::anyhow!("no answer in {:?}", PROBE_TIMEOUT))??;
+    anyhow::ensure!(output.status.success(), "exited with {}", output.status);
+    // " V....D libx264   libx264 H.264 / AVC ...", after a legend ending with " ------"
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            let flags = words.next()?;
+            let name = words.next()?;
+            (flags.len() == 6 && flags != "------" && name != "=").then(|| name.to_string())
+        })
+        .collect())
+}
@@ -0,0 +1,250 @@
+//! Answering a retried request as the first one was, by its `Idempotency-Key`
+//!
+//! A client that timed out can't tell whether its start was done, so it sends it again with the
+//! same key, see [crate::client]. The first request with a key runs and its response is kept; a
+//! request with a key already seen, by the same client for the same method and path, gets that
+//! response again without running. One that comes while the first is still running waits for its
+//! response. A server error is not kept, the request runs again when it is retried.
+//!
+//! The last [KEPT] responses are kept for [KEPT_FOR]; the body of the retry is not compared with
+//! that of the first request.
+use crate::presence::Identity;
+use crate::service::Recorder;
+use axum::body::{Body, Bytes, HttpBody};
+use axum::http::{HeaderMap, Method, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+
+/// the header of the key
+pub const HEADER: &str = "idempotency-key";
+/// responses kept, the oldest ones are forgotten first
+pub const KEPT: usize = 1000;
+/// how long a response is kept
+pub const KEPT_FOR: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A response kept for the retries
+#[derive(Debug, Clone)]
+struct Kept {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl Kept {
+    fn response(&self) -> Response {
+        let mut res = (self.status, self.body.clone()).into_response();
+        *res.headers_mut() = self.headers.clone();
+        res
+    }
+}
+
+type Slot = Arc<OnceCell<Kept>>;
+
+/// The responses to the requests with a key
+#[derive(Default)]
+pub struct Responses {
+    /// by client, method, path and key
+    slots: Mutex<HashMap<String, (Instant, Slot)>>,
+    /// the keys, oldest first
+    order: Mutex<VecDeque<String>>,
+}
+
+impl Responses {
+    /// the slot of the key, a new one unless it is kept
+    fn slot(&self, key: &str) -> Slot {
+        let mut slots = self.slots.lock().unwrap();
+        let mut order = self.order.lock().unwrap();
+        let now = Instant::now();
+        if let Some((at, slot)) = slots.get(key) {
+            if now.duration_since(*at) <= KEPT_FOR {
+                return slot.clone();
+            }
+        }
+        while let Some(oldest) = order.front() {
+            let expired = slots
+                .get(oldest)
+                .is_none_or(|(at, _)| now.duration_since(*at) > KEPT_FOR);
+            if !expired && order.len() < KEPT {
+                break;
+            }
+            slots.remove(oldest);
+            order.pop_front();
+        }
+        let slot = Slot::default();
+        slots.insert(key.to_string(), (now, slot.clone()));
+        order.push_back(key.to_string());
+        slot
+    }
+
+    /// forget the response of the key, unless another slot took its place
+    fn forget(&self, key: &str, slot: &Slot) {
+        let mut slots = self.slots.lock().unwrap();
+        if slots
+            .get(key)
+            .is_some_and(|(_, kept)| Arc::ptr_eq(kept, slot))
+        {
+            slots.remove(key);
+            self.order.lock().unwrap().retain(|kept| kept != key);
+        }
+    }
+
+    /// how many responses are kept
+    pub fn len(&self) -> usize {
+        self.slots.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+async fn keep(res: Response) -> Kept {
+    let (parts, mut body) = res.into_parts();
+    let mut bytes = vec![];
+    while let Some(Ok(chunk)) = body.data().await {
+        bytes.extend_from_slice(&chunk);
+    }
+    Kept {
+        status: parts.status,
+        headers: parts.headers,
+        body: Bytes::from(bytes),
+    }
+}
+
+/// run a request with a key once, answering its retries with its response
+pub async fn replay(req: Request<Body>, next: Next<Body>) -> Response {
+    let key = req.headers().get(HEADER).and_then(|v| v.to_str().ok());
+    let mx = req.extensions().get::<Arc<Recorder>>().cloned();
+    let (Some(key), Some(mx)) = (key, mx) else {
+        return next.run(req).await;
+    };
+    if req.method() == Method::GET || req.method() == Method::HEAD {
+        return next.run(req).await;
+    }
+    let client = req
+        .extensions()
+        .get::<Identity>()
+        .map(|identity| identity.to_string())
+        .unwrap_or_default();
+    let key = format!("{} {} {} {}", client, req.method(), req.uri().path(), key);
+    let slot = mx.idempotency.slot(&key);
+    let kept = slot
+        .get_or_init(|| async move { keep(next.run(req).await).await })
+        .await;
+    if kept.status.is_server_error() {
+        mx.idempotency.forget(&key, &slot);
+    }
+    kept.response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::post;
+    use axum::{Extension, Router};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use tower::ServiceExt;
+
+    /// a route counting its runs, failing with `status` from the third one
+    fn counting(status: StatusCode) -> (Router, Arc<AtomicU64>) {
+        let runs = Arc::new(AtomicU64::new(0));
+        let counted = runs.clone();
+        let router = Router::new()
+            .route(
+                "/api/start",
+                post(move || async move {
+                    let run = counted.fetch_add(1, Ordering::SeqCst) + 1;
+                    match run {
+                        1 | 2 => (StatusCode::OK, format!("run {}", run)),
+                        _ => (status, format!("run {}", run)),
+                    }
+                }),
+            )
+            .layer(axum::middleware::from_fn(replay))
+            .layer(Extension(Arc::new(Recorder::new())));
+        (router, runs)
+    }
+
+    async fn send(router: &Router, key: Option<&str>) -> (StatusCode, String) {
+        let mut req = Request::builder().method(Method::POST).uri("/api/start");
+        if let Some(key) = key {
+            req = req.header(HEADER, key);
+        }
+        let res = router
+            .clone()
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = res.status();
+        let mut body = res.into_body();
+        let mut bytes = vec![];
+        while let Some(chunk) = body.data().await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+        (status, String::from_utf8(bytes).unwrap())
+    }
+
+    #[tokio::test]
+    async fn a_retry_gets_the_response_of_the_first_request() {
+        let (router, runs) = counting(StatusCode::OK);
+        let first = send(&router, Some("a")).await;
+        assert_eq!(first, (StatusCode::OK, "run 1".to_string()));
+        assert_eq!(send(&router, Some("a")).await, first);
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        // another key, or none, runs again
+        assert_eq!(send(&router, Some("b")).await.1, "run 2");
+        assert_eq!(send(&router, None).await.1, "run 3");
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn a_server_error_is_not_kept() {
+        let (router, runs) = counting(StatusCode::SERVICE_UNAVAILABLE);
+        send(&router, Some("a")).await;
+        send(&router, Some("b")).await;
+        let failed = send(&router, Some("c")).await;
+        assert_eq!(failed.0, StatusCode::SERVICE_UNAVAILABLE);
+        let retried = send(&router, Some("c")).await;
+        assert_eq!(
+            retried,
+            (StatusCode::SERVICE_UNAVAILABLE, "run 4".to_string())
+        );
+        assert_eq!(runs.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn concurrent_retries_wait_for_the_first_request() {
+        let runs = Arc::new(AtomicU64::new(0));
+        let counted = runs.clone();
+        let router = Router::new()
+            .route(
+                "/api/start",
+                post(move || async move {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    counted.fetch_add(1, Ordering::SeqCst).to_string()
+                }),
+            )
+            .layer(axum::middleware::from_fn(replay))
+            .layer(Extension(Arc::new(Recorder::new())));
+        let (first, second) = tokio::join!(send(&router, Some("a")), send(&router, Some("a")));
+        assert_eq!(first, second);
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn the_oldest_responses_are_forgotten() {
+        let responses = Responses::default();
+        for n in 0..KEPT + 10 {
+            responses.slot(&n.to_string());
+        }
+        assert_eq!(responses.len(), KEPT);
+        let slot = responses.slot(&(KEPT + 9).to_string());
+        assert_eq!(responses.len(), KEPT);
+        responses.forget(&(KEPT + 9).to_string(), &slot);
+        assert_eq!(responses.len(), KEPT - 1);
+    }
+}
@@ -0,0 +1,522 @@
+//! Journal of the compressions, so that a restart does not lose them
+//!
+//! Every compression is appended to a JSON lines journal when it is queued, when it starts and
+//! when it ends; only the queued line carries the job itself. On startup [restore] replays the
+//! journal and compresses again, from scratch, what never ended: its partial output is removed
+//! first, and a job whose raw input is gone or can't be probed is marked failed. The journal is
+//! rewritten with the jobs still needed once it grew past [COMPACT_AFTER] lines.
+use crate::audio::{AudioSegment, AudioStatus};
+use crate::history::{Page, PageQuery};
+use crate::latency::FirstFrame;
+use crate::presence::Identity;
+use crate::probe::probe;
+use crate::quality::VideoSegment;
+use crate::schema::{self, Versioned};
+use crate::service::{
+    compress, Marker, Recorder, RecordingOptions, RecordingState, RecordingTimeline, StopReason,
+};
+use crate::trim::Window;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::*;
+
+/// lines appended before the journal is compacted
+pub const COMPACT_AFTER: usize = 1000;
+/// finished jobs kept by a compaction, for the listing
+const KEEP_FINISHED: usize = 100;
+/// how often a restored job looks whether the recorder is idle
+const IDLE_POLL: Duration = Duration::from_secs(1);
+
+/// Where a job is
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    Running,
+    Done,
+    Failed,
+    /// the recording was cancelled meanwhile, its files are left for the recovery scan
+    Cancelled,
+}
+
+impl JobState {
+    pub fn finished(&self) -> bool {
+        matches!(self, Self::Done | Self::Failed | Self::Cancelled)
+    }
+
+    /// its name in the API, that of `?state=`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Queued => "queued",
+            Self::Running => "running",
+            Self::Done => "done",
+            Self::Failed => "failed",
+            Self::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// What a compression needs, everything the stopped recording knew
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Compression {
+    pub input: String,
+    pub output: String,
+    pub options: RecordingOptions,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub segments: Vec<AudioSegment>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio: Option<AudioStatus>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub markers: Vec<Marker>,
+    pub started_at: DateTime<Local>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub capture_command: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<StopReason>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frame_timestamps: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_by: Option<Identity>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stopped_by: Option<Identity>,
+    /// the files of a capture whose quality was changed, joined before the compression
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub video_segments: Vec<VideoSegment>,
+    /// the chunks of a capture of `segment_seconds` compressed one by one, see [crate::chunks]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<String>,
+    /// the spans of the capture, up to its stop
+    #[serde(default, skip_serializing_if = "RecordingTimeline::is_empty")]
+    pub timeline: RecordingTimeline,
+    /// when the first frame came, estimated or measured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first_frame: Option<FirstFrame>,
+    /// what is kept of the content in the result, all of it when None
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trim: Option<Window>,
+    /// the traceparent of the recording, its compression joins its trace, see [crate::otel]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace: Option<String>,
+}
+
+/// A compression and where it is
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: u64,
+    pub state: JobState,
+    /// when it got into its state
+    pub at: DateTime<Local>,
+    /// it was found unfinished after a restart
+    #[serde(default)]
+    pub restored: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// the read rate it runs at, in bytes per second, while it runs held to one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub io_rate: Option<u64>,
+    pub compression: Compression,
+}
+
+/// A line of the journal
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    id: u64,
+    state: JobState,
+    at: DateTime<Local>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    restored: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// only in the line queueing the job
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    compression: Option<Compression>,
+}
+
+impl Versioned for Entry {
+    const KIND: &'static str = "journal entries";
+    const VERSION: u32 = 1;
+    const MIGRATIONS: &'static [schema::Migration] = &[schema::unversioned];
+}
+
+#[derive(Default)]
+struct Inner {
+    path: Option<PathBuf>,
+    jobs: BTreeMap<u64, Job>,
+    next_id: u64,
+    /// lines in the journal file
+    lines: usize,
+}
+
+impl Inner {
+    fn apply(&mut self, entry: Entry) {
+        self.next_id = self.next_id.max(entry.id + 1);
+        match (self.jobs.get_mut(&entry.id), entry.compression) {
+            (_, Some(compression)) => {
+                self.jobs.insert(
+                    entry.id,
+                    Job {
+                        id: entry.id,
+                        state: entry.state,
+                        at: entry.at,
+                        restored: entry.restored,
+                        error: entry.error,
+                        io_rate: None,
+                        compression,
+                    },
+                );
+            }
+            (Some(job), None) => {
+                job.state = entry.state;
+                job.at = entry.at;
+                job.restored |= entry.restored;
+                job.error = entry.error;
+                if entry.state.finished() {
+                    job.io_rate = None;
+                }
+            }
+            (None, None) => warn!("journal entry of an unknown job {}", entry.id),
+        }
+    }
+
+    fn write(&mut self, entry: &Entry) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        writeln!(file, "{}", schema::to_string(entry)?)?;
+        self.lines += 1;
+        if self.lines > COMPACT_AFTER {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// rewrite the journal with one line per job, dropping the oldest finished ones
+    fn compact(&mut self) -> anyhow::Result<()> {
+        let Some(path) = self.path.clone() else {
+            return Ok(());
+        };
+        let finished: Vec<u64> = self
+            .jobs
+            .values()
+            .filter(|j| j.state.finished())
+            .map(|j| j.id)
+            .collect();
+        let drop = finished.len().saturating_sub(KEEP_FINISHED);
+        for id in &finished[..drop] {
+            self.jobs.remove(id);
+        }
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".compact");
+        let temporary = PathBuf::from(temporary);
+        let mut file = std::io::BufWriter::new(std::fs::File::create(&temporary)?);
+        for job in self.jobs.values() {
+            let entry = Entry {
+                id: job.id,
+                state: job.state,
+                at: job.at,
+                restored: job.restored,
+                error: job.error.clone(),
+                compression: Some(job.compression.clone()),
+            };
+            writeln!(file, "{}", schema::to_string(&entry)?)?;
+        }
+        file.into_inner()?.sync_all()?;
+        std::fs::rename(&temporary, &path)?;
+        self.lines = self.jobs.len();
+        info!("compacted {} to {} jobs", path.display(), self.lines);
+        Ok(())
+    }
+}
+
+/// The journal, in memory only unless opened on a file
+#[derive(Default)]
+pub struct Journal {
+    inner: Mutex<Inner>,
+}
+
+impl Journal {
+    /// load the journal from its file, quarantining the lines that can't be parsed
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut inner = Inner {
+            path: Some(path.to_path_buf()),
+            ..Default::default()
+        };
+        let (entries, report) = schema::read_lines::<Entry>(path)?;
+        info!("{}", report);
+        inner.lines = entries.len();
+        for entry in entries {
+            inner.apply(entry);
+        }
+        Ok(Self {
+            inner: Mutex::new(inner),
+        })
+    }
+
+    /// queue a compression, its id is assigned here
+    pub fn enqueue(&self, compression: Compression) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id;
+        let entry = Entry {
+            id,
+            state: JobState::Queued,
+            at: Local::now(),
+            restored: false,
+            error: None,
+            compression: Some(compression),
+        };
+        if let Err(e) = inner.write(&entry) {
+            warn!("cannot write the job journal: {}", e);
+        }
+        inner.apply(entry);
+        id
+    }
+
+    /// move the job to its next state
+    pub fn update(&self, id: u64, state: JobState, error: Option<String>) {
+        self.record(id, state, false, error);
+    }
+
+    fn record(&self, id: u64, state: JobState, restored: bool, error: Option<String>) {
+        let mut inner = self.inner.lock().unwrap();
+        let entry = Entry {
+            id,
+            state,
+            at: Local::now(),
+            restored,
+            error,
+            compression: None,
+        };
+        if let Err(e) = inner.write(&entry) {
+            warn!("cannot write the job journal: {}", e);
+        }
+        inner.apply(entry);
+    }
+
+    /// the read rate the running job was given, not journaled
+    pub fn set_io_rate(&self, id: u64, rate: Option<u64>) {
+        if let Some(job) = self.inner.lock().unwrap().jobs.get_mut(&id) {
+            job.io_rate = rate;
+        }
+    }
+
+    /// a page of the jobs, newest first, paged and filtered as the history is
+    ///
+    /// The cursor is the id of the last job of the page: the jobs queued meanwhile have larger
+    /// ones and don't shift the next page.
+    pub fn page(&self, query: &PageQuery) -> anyhow::Result<Page<Job>> {
+        let inner = self.inner.lock().unwrap();
+        let upper = match &query.cursor {
+            Some(cursor) => Some(
+                u64::from_str_radix(cursor, 16).map_err(|_| anyhow::anyhow!("invalid cursor"))?,
+            ),
+            None => None,
+        };
+        let range = match upper {
+            Some(id) => inner.jobs.range(..id),
+            None => inner.jobs.range(..),
+        };
+        let skip = match upper {
+            Some(_) => 0,
+            None => query.offset.unwrap_or(0),
+        };
+        let matches = |job: &Job| {
+            query.state.as_ref().is_none_or(|s| job.state.name() == s)
+                && query
+                    .owner
+                    .as_ref()
+                    .is_none_or(|o| job.compression.options.owner.as_ref() == Some(o))
+                && query
+                    .from
+                    .is_none_or(|from| job.compression.started_at >= from)
+                && query.to.is_none_or(|to| job.compression.started_at < to)
+        };
+        let mut matching = range.rev().filter(|(_, job)| matches(job)).skip(skip);
+        let mut entries = vec![];
+        let mut last = None;
+        for (id, job) in matching.by_ref().take(query.limit()) {
+            last = Some(*id);
+            entries.push(job.clone());
+        }
+        let next_cursor = match (last, matching.next()) {
+            (Some(id), Some(_)) => Some(format!("{:x}", id)),
+            _ => None,
+        };
+        // the journal is compacted, counting its jobs is cheap
+        let total = match (&query.owner, query.from, query.to, query.suspect) {
+            (None, None, None, None) => Some(inner.jobs.values().filter(|j| matches(j)).count()),
+            _ => None,
+        };
+        Ok(Page {
+            entries,
+            next_cursor,
+            total,
+        })
+    }
+
+    /// the jobs a restart interrupted, oldest first
+    pub fn unfinished(&self) -> Vec<Job> {
+        self.inner
+            .lock()
+            .unwrap()
+            .jobs
+            .values()
+            .filter(|j| !j.state.finished())
+            .cloned()
+            .collect()
+    }
+}
+
+fn idle(state: &RecordingState) -> bool {
+    matches!(
+        state,
+        RecordingState::Waiting
+            | RecordingState::Done { .. }
+            | RecordingState::Failed { .. }
+            | RecordingState::Cancelled { .. }
+    )
+}
+
+/// compress again what a restart interrupted, one job after the other
+pub async fn restore(mx: Arc<Recorder>) {
+    for job in mx.jobs.unfinished() {
+        let (input, output) = (
+            job.compression.input.clone(),
+            job.compression.output.clone(),
+        );
+        if !Path::new(&input).is_file() {
+            mx.jobs
+                .record(job.id, JobState::Failed, true, Some("input is gone".into()));
+            continue;
+        }
+        if let Err(e) = probe(Path::new(&input)).await {
+            warn!("cannot restore the compression of {}: {}", input, e);
+            mx.jobs
+                .record(job.id, JobState::Failed, true, Some(e.to_string()));
+            continue;
+        }
+        if Path::new(&output).exists() {
+            info!("removing the partial output {}", output);
+            if let Err(e) = std::fs::remove_file(&output) {
+                warn!("cannot remove {}: {}", output, e);
+            }
+        }
+        mx.jobs.record(job.id, JobState::Queued, true, None);
+        info!("restoring the compression of {}", input);
+
+        // no recording may start until the compression took over the state
+        let claim = loop {
+            if idle(&*mx.lock().await) {
+                if let Some(claim) = mx.claim_start() {
+                    break claim;
+                }
+            }
+            tokio::time::sleep(IDLE_POLL).await;
+        };
+        if let Err(e) = compress(mx.clone(), job.id, job.compression, Some(claim)).await {
+            warn!("restored compression of {} failed: {}", input, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compression(owner: Option<&str>) -> Compression {
+        let options = RecordingOptions {
+            owner: owner.map(str::to_string),
+            ..Default::default()
+        };
+        serde_json::from_value(serde_json::json!({
+            "input": "capture.mkv",
+            "output": "recording.mp4",
+            "options": options,
+            "started_at": Local::now(),
+        }))
+        .unwrap()
+    }
+
+    fn ids(page: &Page<Job>) -> Vec<u64> {
+        page.entries.iter().map(|j| j.id).collect()
+    }
+
+    #[test]
+    fn the_jobs_are_paged_across_appends() {
+        let journal = Journal::default();
+        for _ in 0..5 {
+            journal.enqueue(compression(None));
+        }
+        let query = PageQuery {
+            limit: Some(2),
+            ..Default::default()
+        };
+        let first = journal.page(&query).unwrap();
+        assert_eq!(ids(&first), [4, 3]);
+        assert_eq!(first.total, Some(5));
+        // queued meanwhile, not in the next pages
+        journal.enqueue(compression(None));
+        let second = journal
+            .page(&PageQuery {
+                cursor: first.next_cursor.clone(),
+                ..query.clone()
+            })
+            .unwrap();
+        assert_eq!(ids(&second), [2, 1]);
+        let last = journal
+            .page(&PageQuery {
+                cursor: second.next_cursor.clone(),
+                ..query.clone()
+            })
+            .unwrap();
+        assert_eq!(ids(&last), [0]);
+        assert_eq!(last.next_cursor, None);
+        assert_eq!(last.total, Some(6));
+        let invalid = PageQuery {
+            cursor: Some("not a cursor".to_string()),
+            ..Default::default()
+        };
+        assert!(journal.page(&invalid).is_err());
+    }
+
+    #[test]
+    fn the_jobs_are_filtered() {
+        let journal = Journal::default();
+        let done = journal.enqueue(compression(Some("alice")));
+        journal.enqueue(compression(Some("bob")));
+        journal.enqueue(compression(None));
+        journal.update(done, JobState::Done, None);
+        let by_state = journal
+            .page(&PageQuery {
+                state: Some("queued".to_string()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(ids(&by_state), [2, 1]);
+        assert_eq!(by_state.total, Some(2));
+        let by_owner = journal
+            .page(&PageQuery {
+                owner: Some("alice".to_string()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(ids(&by_owner), [done]);
+        // a total for the states alone
+        assert_eq!(by_owner.total, None);
+        let offset = journal
+            .page(&PageQuery {
+                offset: Some(1),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(ids(&offset), [1, 0]);
+    }
+}
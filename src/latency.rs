@@ -0,0 +1,255 @@
+//! How long a capture takes from its spawn to its first frame, to tell when a recording began
+//!
+//! The `started_at` of a recording is when its ffmpeg was spawned; the first frame it grabs comes
+//! some hundred milliseconds later, more or less depending on the machine and on how it captures.
+//! That latency is measured by the capture path analysis, from the spawn to the first progress
+//! with a frame less the time ffmpeg reports it processed, and by the recordings with frame
+//! timestamps, from the spawn to the anchor of their sidecar. A rolling estimate of it is kept per
+//! [configuration] of the capture next to the history.
+//!
+//! A recording gets its [FirstFrame] in its Started state, in its `<name>.first-frame.json`
+//! sidecar and in its history entry: the estimate when it starts, replaced by what it measured
+//! once its first frame was timestamped. It is only told: the names of the files and the anchors
+//! of the timeline stay on `started_at`.
+use crate::container;
+use crate::recordings;
+use crate::schema::{self, Versioned};
+use crate::service::{Recorder, RecordingOptions, RecordingState};
+use crate::source::CaptureSource;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tokio::sync::oneshot;
+use tracing::*;
+
+/// the weight of a new measurement in the estimate
+pub const WEIGHT: f64 = 0.2;
+/// a measurement longer than this is a capture that stalled, not its latency
+pub const MAX_MS: f64 = 10_000.0;
+
+/// The latency of a configuration, as measured so far
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Estimate {
+    pub latency_ms: f64,
+    pub samples: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub at: Option<DateTime<Local>>,
+}
+
+impl Estimate {
+    /// take a measurement into the moving average, the first one as it is
+    pub fn add(&mut self, latency_ms: f64, at: DateTime<Local>) {
+        self.latency_ms = match self.samples {
+            0 => latency_ms,
+            _ => self.latency_ms + WEIGHT * (latency_ms - self.latency_ms),
+        };
+        self.samples += 1;
+        self.at = Some(at);
+    }
+}
+
+/// The estimates of this machine, by configuration
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Calibration {
+    #[serde(default)]
+    pub estimates: BTreeMap<String, Estimate>,
+}
+
+impl Versioned for Calibration {
+    const KIND: &'static str = "latency calibrations";
+    const VERSION: u32 = 1;
+    const MIGRATIONS: &'static [schema::Migration] = &[schema::unversioned];
+}
+
+impl Calibration {
+    /// the estimated latency of the configuration, in milliseconds
+    pub fn estimate(&self, configuration: &str) -> Option<f64> {
+        self.estimates.get(configuration).map(|e| e.latency_ms)
+    }
+
+    /// take a measurement, false when it can't be a latency
+    pub fn add(&mut self, configuration: &str, latency_ms: f64, at: DateTime<Local>) -> bool {
+        if !(0.0..=MAX_MS).contains(&latency_ms) {
+            return false;
+        }
+        self.estimates
+            .entry(configuration.to_string())
+            .or_default()
+            .add(latency_ms, at);
+        true
+    }
+}
+
+/// what the latency of a capture depends on, the key of its estimate
+pub fn configuration(opt: &RecordingOptions) -> String {
+    match &opt.source {
+        // ffmpeg uses the shared memory unless told not to
+        CaptureSource::Screen if opt.use_shm == Some(false) => "x11grab".to_string(),
+        CaptureSource::Screen => "x11grab_shm".to_string(),
+        CaptureSource::Url { url, .. } => {
+            let scheme = url.split_once("://").map_or("url", |(scheme, _)| scheme);
+            format!("url_{}", scheme.to_ascii_lowercase())
+        }
+    }
+}
+
+fn stored_path() -> anyhow::Result<PathBuf> {
+    Ok(recordings::output_dir()?.join(".record-screen-latency.json"))
+}
+
+/// the estimates of this machine, none when there are none yet or they can't be read
+pub fn load() -> Calibration {
+    let Ok(path) = stored_path() else {
+        return Calibration::default();
+    };
+    let Ok(json) = std::fs::read(&path) else {
+        return Calibration::default();
+    };
+    match schema::from_slice(&json) {
+        Ok((calibration, _)) => calibration,
+        Err(e) => {
+            warn!("ignoring the latency calibration: {}", e);
+            if let Err(e) = schema::quarantine(&path) {
+                warn!("cannot quarantine {}: {}", path.display(), e);
+            }
+            Calibration::default()
+        }
+    }
+}
+
+pub fn store(calibration: &Calibration) -> anyhow::Result<()> {
+    std::fs::write(stored_path()?, schema::to_vec_pretty(calibration)?)?;
+    Ok(())
+}
+
+/// the estimates being updated, one at a time
+static UPDATING: Mutex<()> = Mutex::new(());
+
+/// take a measurement of the configuration into the stored estimate
+pub fn record(configuration: &str, latency_ms: f64) {
+    let _updating = UPDATING.lock().unwrap();
+    let mut calibration = load();
+    if !calibration.add(configuration, latency_ms, Local::now()) {
+        warn!(
+            "ignoring a first frame latency of {:.0}ms for {}",
+            latency_ms, configuration
+        );
+        return;
+    }
+    if let Err(e) = store(&calibration) {
+        warn!("cannot keep the latency calibration: {}", e);
+    }
+}
+
+/// When the first frame of a recording came, by the estimate or as measured
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FirstFrame {
+    /// when the capture was spawned, the `started_at` of the recording
+    pub spawned_at: DateTime<Local>,
+    pub estimated_first_frame_at: DateTime<Local>,
+    pub latency_ms: f64,
+    /// measured on the recording itself rather than estimated from the earlier ones
+    #[serde(default)]
+    pub measured: bool,
+    pub configuration: String,
+}
+
+impl Versioned for FirstFrame {
+    const KIND: &'static str = "first frame sidecars";
+    const VERSION: u32 = 1;
+    const MIGRATIONS: &'static [schema::Migration] = &[schema::unversioned];
+}
+
+impl FirstFrame {
+    /// the first frame of a capture spawned at `spawned_at`, None without an estimate
+    pub fn estimated(
+        calibration: &Calibration,
+        configuration: &str,
+        spawned_at: DateTime<Local>,
+    ) -> Option<Self> {
+        let latency_ms = calibration.estimate(configuration)?;
+        Some(Self {
+            spawned_at,
+            estimated_first_frame_at: spawned_at
+                + chrono::Duration::microseconds((latency_ms * 1000.0) as i64),
+            latency_ms,
+            measured: false,
+            configuration: configuration.to_string(),
+        })
+    }
+
+    /// the first frame of a capture spawned at `spawned_at`, seen at `seen_at`
+    pub fn measured(
+        configuration: &str,
+        spawned_at: DateTime<Local>,
+        seen_at: DateTime<Local>,
+    ) -> Self {
+        let latency = seen_at - spawned_at;
+        Self {
+            spawned_at,
+            estimated_first_frame_at: seen_at,
+            latency_ms: latency.num_microseconds().unwrap_or_default() as f64 / 1000.0,
+            measured: true,
+            configuration: configuration.to_string(),
+        }
+    }
+}
+
+/// path of the first frame sidecar of a capture
+pub fn sidecar_path(capture: &str) -> PathBuf {
+    PathBuf::from(format!("{}.first-frame.json", container::base(capture)))
+}
+
+/// write the sidecar next to the capture
+pub async fn write_sidecar(capture: &str, first_frame: &FirstFrame) {
+    let path = sidecar_path(capture);
+    let json = schema::to_vec_pretty(first_frame).expect("first frame json");
+    if let Err(e) = tokio::fs::write(&path, json).await {
+        warn!("cannot write {}: {}", path.display(), e);
+    }
+}
+
+/// replace the estimate of the capture with its first frame once it is timestamped
+///
+/// The capture is told by its process, the state may have moved on to another one meanwhile.
+pub async fn measure(
+    mx: &Recorder,
+    process_id: u32,
+    capture: String,
+    configuration: String,
+    seen: oneshot::Receiver<DateTime<Local>>,
+) {
+    let Ok(seen_at) = seen.await else {
+        return;
+    };
+    let mut state = mx.lock().await;
+    let RecordingState::Started {
+        process_id: current,
+        started_at,
+        first_frame,
+        ..
+    } = &mut *state
+    else {
+        return;
+    };
+    if *current != process_id {
+        return;
+    }
+    let measured = FirstFrame::measured(&configuration, *started_at, seen_at);
+    info!(
+        "first frame {:.0}ms after the spawn, estimated {}",
+        measured.latency_ms,
+        first_frame
+            .as_ref()
+            .map(|f| format!("{:.0}ms", f.latency_ms))
+            .unwrap_or_else(|| "none".to_string())
+    );
+    *first_frame = Some(measured.clone());
+    let updated = state.clone();
+    mx.replace(&mut state, updated);
+    drop(state);
+    write_sidecar(&capture, &measured).await;
+    tokio::task::spawn_blocking(move || record(&configuration, measured.latency_ms));
+}
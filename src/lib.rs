@@ -0,0 +1,71 @@
+pub mod audio;
+pub mod auth;
+pub mod canary;
+pub mod capture_paths;
+pub mod checksums;
+pub mod chunks;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod contact_sheet;
+pub mod container;
+pub mod content_check;
+pub mod cursor;
+pub mod display;
+pub mod encoder_profiles;
+pub mod endpoints;
+pub mod events;
+pub mod failover;
+pub mod feed;
+pub mod ffmpeg;
+pub mod frames;
+pub mod geometry;
+pub mod gif;
+pub mod gpu;
+pub mod handoff;
+pub mod health;
+pub mod history;
+pub mod hwaccel;
+pub mod idempotency;
+pub mod jobs;
+pub mod latency;
+pub mod liveness;
+pub mod logging;
+pub mod loudness;
+pub mod options_schema;
+pub mod otel;
+pub mod overlays;
+pub mod pause;
+pub mod picker;
+pub mod pipeline;
+pub mod play;
+pub mod policy;
+pub mod presence;
+pub mod preview;
+pub mod probe;
+pub mod problem;
+pub mod pulse;
+pub mod quality;
+pub mod quota;
+pub mod recordings;
+pub mod recovery;
+pub mod rtmp;
+pub mod runner;
+pub mod s3;
+pub mod scale;
+pub mod schema;
+pub mod service;
+pub mod shutdown;
+pub mod signals;
+pub mod slate;
+pub mod source;
+pub mod storage;
+pub mod sync_start;
+pub mod template;
+pub mod throttle;
+pub mod thumbnail;
+pub mod timed;
+pub mod timestamps;
+pub mod transcripts;
+pub mod trim;
+pub mod webhook;
+pub mod webrtc_preview;
@@ -0,0 +1,297 @@
+//! Whether recordings can be started at all, for the external monitors
+//!
+//! The checks are cheap probes of what a recording needs: the ffmpeg binary, the display, a
+//! writable output directory with free space. They run concurrently, each with its own timeout,
+//! within an overall budget; a check that times out is a warning, not a failure. The report is
+//! cached, so a monitor polling often does not spawn ffmpeg every time.
+use crate::display;
+use crate::recordings;
+use futures::future::join_all;
+use serde::Serialize;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// What is checked
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Check {
+    /// the ffmpeg binary runs
+    Ffmpeg,
+    /// the X11 display accepts connections
+    Display,
+    /// a file can be created in the output directory
+    OutputDir,
+    /// the output directory has enough free space
+    FreeSpace,
+}
+
+impl Check {
+    pub const ALL: &'static [Check] = &[
+        Check::Ffmpeg,
+        Check::Display,
+        Check::OutputDir,
+        Check::FreeSpace,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Check::Ffmpeg => "ffmpeg",
+            Check::Display => "display",
+            Check::OutputDir => "output_dir",
+            Check::FreeSpace => "free_space",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Pass,
+    /// the check did not finish in time
+    Warn,
+    Fail,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckResult {
+    pub check: Check,
+    pub outcome: Outcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    /// all checks passed or only warned, or a recording is running
+    pub live: bool,
+    /// a healthy recording is running, the checks were skipped
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub recording: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub checks: Vec<CheckResult>,
+}
+
+/// Which checks run, and their thresholds
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub checks: Vec<Check>,
+    /// the ffmpeg binary the recordings run
+    pub ffmpeg: String,
+    /// least free space in the output directory, in bytes
+    pub min_free_bytes: u64,
+    /// how long a report is reused
+    pub cache_for: Duration,
+    /// how long a single check may take
+    pub check_timeout: Duration,
+    /// how long all of them may take
+    pub budget: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            checks: Check::ALL.to_vec(),
+            ffmpeg: "ffmpeg".to_string(),
+            min_free_bytes: 1024 * 1024 * 1024,
+            cache_for: Duration::from_secs(30),
+            check_timeout: Duration::from_millis(1500),
+            budget: Duration::from_secs(2),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Liveness {
+    pub config: Config,
+    cached: Mutex<Option<(Instant, Report)>>,
+}
+
+impl Liveness {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// the cached report, running the checks when it's too old
+    pub async fn report(&self) -> Report {
+        let mut cached = self.cached.lock().await;
+        if let Some((at, report)) = &*cached {
+            if at.elapsed() < self.config.cache_for {
+                return report.clone();
+            }
+        }
+        let report = self.run().await;
+        *cached = Some((Instant::now(), report.clone()));
+        report
+    }
+
+    async fn run(&self) -> Report {
+        let timeout = self.config.check_timeout.min(self.config.budget);
+        let checks = self.config.checks.iter().map(|&check| async move {
+            match tokio::time::timeout(timeout, self.check(check)).await {
+                Ok(Ok(())) => CheckResult {
+                    check,
+                    outcome: Outcome::Pass,
+                    message: None,
+                },
+                Ok(Err(e)) => CheckResult {
+                    check,
+                    outcome: Outcome::Fail,
+                    message: Some(e.to_string()),
+                },
+                Err(_) => CheckResult {
+                    check,
+                    outcome: Outcome::Warn,
+                    message: Some(format!("no result in {:?}", timeout)),
+                },
+            }
+        });
+        let checks = join_all(checks).await;
+        Report {
+            live: checks.iter().all(|c| c.outcome != Outcome::Fail),
+            recording: false,
+            checks,
+        }
+    }
+
+    async fn check(&self, check: Check) -> anyhow::Result<()> {
+        match check {
+            Check::Ffmpeg => {
+                let status = tokio::process::Command::new(&self.config.ffmpeg)
+                    .arg("-version")
+                    .stdout(std::process::Stdio::null())
+                    .stderr(std::process::Stdio::null())
+                    .kill_on_drop(true)
+                    .status()
+                    .await?;
+                anyhow::ensure!(
+                    status.success(),
+                    "{} -version failed: {}",
+                    self.config.ffmpeg,
+                    status
+                );
+            }
+            Check::Display => {
+                // ":1.0" is served on the socket X1
+                let number = display::name()
+                    .rsplit(':')
+                    .next()
+                    .unwrap_or_default()
+                    .split('.')
+                    .next()
+                    .unwrap_or_default();
+                let socket = format!("/tmp/.X11-unix/X{}", number);
+                tokio::net::UnixStream::connect(&socket)
+                    .await
+                    .map_err(|e| anyhow::anyhow!("{}: {}", socket, e))?;
+            }
+            Check::OutputDir => {
+                let dir = recordings::output_dir()?;
+                let probe = dir.join(".record-screen-liveness");
+                tokio::fs::write(&probe, b"").await?;
+                let _ = tokio::fs::remove_file(&probe).await;
+            }
+            Check::FreeSpace => {
+                let dir = recordings::output_dir()?;
+                let free = free_bytes(&dir)?;
+                anyhow::ensure!(
+                    free >= self.config.min_free_bytes,
+                    "{} bytes free, {} needed",
+                    free,
+                    self.config.min_free_bytes
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn free_bytes(dir: &Path) -> anyhow::Result<u64> {
+    let stat = nix::sys::statvfs::statvfs(dir)?;
+    Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+
+/// the report as Prometheus gauges
+pub fn metrics(report: &Report) -> String {
+    let mut out = String::new();
+    out += "# HELP record_screen_live Whether recordings can be started.\n";
+    out += "# TYPE record_screen_live gauge\n";
+    out += &format!("record_screen_live {}\n", report.live as u8);
+    out += "# HELP record_screen_liveness_check Outcome of a liveness check: 1 pass, 0.5 warn, 0 fail.\n";
+    out += "# TYPE record_screen_liveness_check gauge\n";
+    for c in &report.checks {
+        let value = match c.outcome {
+            Outcome::Pass => "1",
+            Outcome::Warn => "0.5",
+            Outcome::Fail => "0",
+        };
+        out += &format!(
+            "record_screen_liveness_check{{check=\"{}\"}} {}\n",
+            c.check.name(),
+            value
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// checks only the ffmpeg binary at `path`
+    fn ffmpeg_at(path: &Path, cache_for: Duration) -> Liveness {
+        Liveness::new(Config {
+            checks: vec![Check::Ffmpeg],
+            ffmpeg: path.to_string_lossy().to_string(),
+            cache_for,
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn a_missing_binary_fails_the_check() {
+        let liveness = ffmpeg_at(Path::new("/nonexistent/ffmpeg"), Duration::ZERO);
+        let report = liveness.report().await;
+        assert!(!report.live);
+        assert_eq!(report.checks[0].outcome, Outcome::Fail);
+        assert!(metrics(&report).contains("record_screen_live 0"));
+    }
+
+    #[tokio::test]
+    async fn the_report_is_cached() {
+        let dir = recordings::test_output_dir().join("liveness");
+        std::fs::create_dir_all(&dir).unwrap();
+        let ffmpeg = dir.join("ffmpeg");
+        let _ = std::fs::remove_file(&ffmpeg);
+        let liveness = ffmpeg_at(&ffmpeg, Duration::from_millis(300));
+        assert!(!liveness.report().await.live);
+        std::fs::write(&ffmpeg, "#!/bin/sh\nexit 0\n").unwrap();
+        std::fs::set_permissions(&ffmpeg, std::fs::Permissions::from_mode(0o755)).unwrap();
+        // the binary is there now, the report of before is still answered
+        assert!(!liveness.report().await.live);
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        let report = liveness.report().await;
+        assert!(report.live);
+        assert_eq!(report.checks[0].outcome, Outcome::Pass);
+    }
+
+    #[tokio::test]
+    async fn a_slow_check_is_a_warning() {
+        let dir = recordings::test_output_dir().join("liveness-slow");
+        std::fs::create_dir_all(&dir).unwrap();
+        let ffmpeg = dir.join("ffmpeg");
+        std::fs::write(&ffmpeg, "#!/bin/sh\nexec sleep 30\n").unwrap();
+        std::fs::set_permissions(&ffmpeg, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let liveness = Liveness::new(Config {
+            check_timeout: Duration::from_millis(100),
+            ..ffmpeg_at(&ffmpeg, Duration::ZERO).config
+        });
+        let report = liveness.report().await;
+        assert!(report.live);
+        assert_eq!(report.checks[0].outcome, Outcome::Warn);
+    }
+}
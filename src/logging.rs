@@ -1,14 +1,318 @@
+//! The log of the server, to stdout and optionally to a size-rotated file
+//!
+//! The file is written by a thread of its own through a bounded buffer, so a slow disk never
+//! stalls the recording: a line that finds the buffer full is dropped and counted, see
+//! [dropped_lines]. At `max_bytes` the file is renamed to `<file>.1`, the older ones shift up to
+//! `<file>.<keep>`, and a new file is started. [reopen], on SIGHUP, lets an external logrotate
+//! move the file away instead.
+use crate::otel;
 use atty::Stream;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
 use tracing_error::ErrorLayer;
-use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+use tracing_subscriber::fmt::format::{self, FormatEvent, FormatFields};
+use tracing_subscriber::fmt::{FmtContext, MakeWriter};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{fmt, prelude::*, EnvFilter, Layer, Registry};
 
-pub fn start(defaults: &str) {
-    let env_filter = EnvFilter::try_from_default_env().unwrap_or(EnvFilter::new(defaults));
+/// lines waiting for the file before new ones are dropped
+pub const BUFFERED_LINES: usize = 10_000;
+
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+static REOPEN: AtomicBool = AtomicBool::new(false);
+
+/// How the lines are written
+#[derive(Default, Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// one JSON object per line
+    Json,
+}
+
+/// The log file and its rotation
+#[derive(Debug, Clone)]
+pub struct FileConfig {
+    pub path: PathBuf,
+    /// the file is rotated before it grows past this size
+    pub max_bytes: u64,
+    /// rotated files kept
+    pub keep: usize,
+    /// filter directives of the file, those of stdout when None
+    pub filter: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub format: LogFormat,
+    pub file: Option<FileConfig>,
+    /// the base URL of the OTLP/HTTP collector the spans are exported to, see [crate::otel]
+    pub otel_endpoint: Option<String>,
+}
+
+/// lines the file lost to a full buffer
+pub fn dropped_lines() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// the Prometheus exposition of the log file
+pub fn metrics() -> String {
+    let name = "record_screen_log_dropped_lines_total";
+    format!(
+        "# HELP {} Log lines the file lost to a full buffer.\n# TYPE {} counter\n{} {}\n",
+        name,
+        name,
+        name,
+        dropped_lines()
+    )
+}
+
+/// reopen the log file before its next line
+pub fn reopen() {
+    REOPEN.store(true, Ordering::Relaxed);
+}
+
+pub fn start(defaults: &str, config: Config) {
+    let env_filter = || EnvFilter::try_from_default_env().unwrap_or(EnvFilter::new(defaults));
     let is_terminal = atty::is(Stream::Stdout);
-    let subscriber = tracing_subscriber::fmt::fmt()
-        .with_env_filter(env_filter)
-        .with_ansi(is_terminal)
-        .with_span_events(fmt::format::FmtSpan::CLOSE) // enable durations
-        .finish();
-    _ = subscriber.with(ErrorLayer::default()).try_init();
+    let mut layers: Vec<Box<dyn Layer<Registry> + Send + Sync>> = vec![];
+    let stdout = fmt::layer().with_span_events(fmt::format::FmtSpan::CLOSE); // enable durations
+    layers.push(match config.format {
+        LogFormat::Text => stdout
+            .with_ansi(is_terminal)
+            .with_filter(env_filter())
+            .boxed(),
+        LogFormat::Json => stdout
+            .with_ansi(false)
+            .event_format(Json)
+            .with_filter(env_filter())
+            .boxed(),
+    });
+    if let Some(file) = &config.file {
+        match RotatingFile::open(file) {
+            Ok(rotating) => {
+                let filter = match &file.filter {
+                    Some(directives) => EnvFilter::new(directives),
+                    None => env_filter(),
+                };
+                let file = fmt::layer()
+                    .with_ansi(false)
+                    .with_span_events(fmt::format::FmtSpan::CLOSE)
+                    .with_writer(NonBlocking::spawn(rotating, BUFFERED_LINES));
+                layers.push(match config.format {
+                    LogFormat::Text => file.with_filter(filter).boxed(),
+                    LogFormat::Json => file.event_format(Json).with_filter(filter).boxed(),
+                });
+            }
+            Err(e) => eprintln!("cannot log to {}: {}", file.path.display(), e),
+        }
+    }
+    if let Some(endpoint) = &config.otel_endpoint {
+        match otel::layer(endpoint) {
+            Ok(layer) => {
+                // the spans of the HTTP requests are debug ones
+                let filter = env_filter().add_directive(
+                    "tower_http::trace=debug"
+                        .parse()
+                        .expect("a valid directive"),
+                );
+                layers.push(layer.with_filter(filter).boxed());
+            }
+            Err(e) => eprintln!("cannot export the spans to {}: {}", endpoint, e),
+        }
+    }
+    _ = tracing_subscriber::registry()
+        .with(layers)
+        .with(ErrorLayer::default())
+        .try_init();
+}
+
+/// A log file renamed away once it is full
+struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    file: Option<std::fs::File>,
+    written: u64,
+}
+
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+impl RotatingFile {
+    fn open(config: &FileConfig) -> std::io::Result<Self> {
+        let mut file = Self {
+            path: config.path.clone(),
+            max_bytes: config.max_bytes,
+            keep: config.keep,
+            file: None,
+            written: 0,
+        };
+        file.reopen()?;
+        Ok(file)
+    }
+
+    fn reopen(&mut self) -> std::io::Result<()> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.written = file.metadata()?.len();
+        self.file = Some(file);
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file = None;
+        for n in (1..self.keep).rev() {
+            let from = numbered(&self.path, n);
+            if from.exists() {
+                std::fs::rename(&from, numbered(&self.path, n + 1))?;
+            }
+        }
+        let moved = match self.keep {
+            0 => std::fs::remove_file(&self.path),
+            _ => std::fs::rename(&self.path, numbered(&self.path, 1)),
+        };
+        match moved {
+            // already moved away by logrotate, ahead of its SIGHUP
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            moved => moved?,
+        }
+        self.reopen()
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> std::io::Result<()> {
+        if REOPEN.swap(false, Ordering::Relaxed) {
+            self.reopen()?;
+        }
+        if self.written > 0 && self.written + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        if self.file.is_none() {
+            self.reopen()?;
+        }
+        if let Some(file) = &mut self.file {
+            file.write_all(line)?;
+            self.written += line.len() as u64;
+        }
+        Ok(())
+    }
+}
+
+/// Hands the lines to the thread writing the file, dropping them when it is behind
+#[derive(Clone)]
+struct NonBlocking {
+    lines: SyncSender<Vec<u8>>,
+}
+
+impl NonBlocking {
+    fn spawn(mut file: RotatingFile, capacity: usize) -> Self {
+        let (lines, rx) = sync_channel::<Vec<u8>>(capacity);
+        let spawned = std::thread::Builder::new()
+            .name("log-file".to_string())
+            .spawn(move || {
+                for line in rx {
+                    if let Err(e) = file.write_line(&line) {
+                        // a broken file is retried with the next line
+                        eprintln!("cannot write {}: {}", file.path.display(), e);
+                        file.file = None;
+                    }
+                }
+            });
+        if let Err(e) = spawned {
+            eprintln!("cannot start writing the log file: {}", e);
+        }
+        Self { lines }
+    }
+}
+
+impl Write for NonBlocking {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.lines.try_send(buf.to_vec()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                DROPPED.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for NonBlocking {
+    type Writer = NonBlocking;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+/// Formats an event as a flat JSON object: its fields along with the timestamp, the level, the
+/// target and the spans, which win over the fields of the same name
+struct Json;
+
+struct JsonFields<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl Visit for JsonFields<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{:?}", value).into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for Json
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: format::Writer<'_>,
+        event: &Event<'_>,
+    ) -> std::fmt::Result {
+        let meta = event.metadata();
+        let mut fields = serde_json::Map::new();
+        event.record(&mut JsonFields(&mut fields));
+        let spans: Vec<serde_json::Value> = ctx
+            .event_scope()
+            .map(|scope| scope.from_root().map(|s| s.name().into()).collect())
+            .unwrap_or_default();
+        fields.insert("timestamp".into(), chrono::Local::now().to_rfc3339().into());
+        fields.insert("level".into(), meta.level().as_str().into());
+        fields.insert("target".into(), meta.target().into());
+        fields.insert("spans".into(), spans.into());
+        writeln!(writer, "{}", serde_json::Value::Object(fields))
+    }
 }
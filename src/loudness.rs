@@ -0,0 +1,183 @@
+//! Bringing the loudness of a recording to a common level while it is compressed
+//!
+//! The microphones and the sound servers record at levels far apart. With `normalize_audio`, the
+//! compression brings the audio to [TARGET_LUFS] with the two passes of the `loudnorm` filter: a
+//! first ffmpeg reads the audio the compression would encode, that of the capture or the mix of
+//! its segments, and prints what loudnorm measured; see [measure_filter]. The compression then
+//! applies loudnorm with the values [Measured::filter] reads from it, linearly where it can, and
+//! prints what it made of it.
+//!
+//! Both are kept, in the Done state and in the `<name>.loudness.json` sidecar of the result, for
+//! the target to be checked. A recording without audio, or of silence only, is compressed as if
+//! nothing was asked.
+use crate::ffmpeg::{Ffmpeg, FfmpegBuilder};
+use crate::schema::{self, Versioned};
+use crate::service::{log_summary, ChildRole, Children};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::*;
+
+/// the integrated loudness of the result
+pub const TARGET_LUFS: f64 = -16.0;
+/// the highest true peak of the result, in dBTP
+pub const TARGET_TRUE_PEAK: f64 = -1.5;
+/// the loudness range of the result, in LU
+pub const TARGET_RANGE: f64 = 11.0;
+/// loudnorm works at 192 kHz, the result is brought back to this rate
+const SAMPLE_RATE: u32 = 48_000;
+
+/// What loudnorm prints once it is done, its numbers being strings
+#[derive(Debug, Clone, Deserialize)]
+pub struct Measured {
+    input_i: String,
+    input_tp: String,
+    input_lra: String,
+    input_thresh: String,
+    #[serde(default)]
+    output_i: Option<String>,
+    #[serde(default)]
+    output_tp: Option<String>,
+    target_offset: String,
+}
+
+impl Measured {
+    /// the integrated loudness it read, None for silence
+    pub fn input_lufs(&self) -> Option<f64> {
+        number(&self.input_i)
+    }
+
+    /// the loudnorm of the second pass, which applies what the first one measured; None for
+    /// silence, which can't be brought to any loudness
+    pub fn filter(&self) -> Option<String> {
+        let measured = [
+            ("measured_I", number(&self.input_i)?),
+            ("measured_TP", number(&self.input_tp)?),
+            ("measured_LRA", number(&self.input_lra)?),
+            ("measured_thresh", number(&self.input_thresh)?),
+            ("offset", number(&self.target_offset)?),
+        ];
+        let measured: String = measured
+            .iter()
+            .map(|(key, value)| format!(":{}={}", key, value))
+            .collect();
+        Some(format!(
+            "{}{}:linear=true:print_format=json,aresample={}",
+            target(),
+            measured,
+            SAMPLE_RATE
+        ))
+    }
+}
+
+fn number(value: &str) -> Option<f64> {
+    value.trim().parse::<f64>().ok().filter(|n| n.is_finite())
+}
+
+/// loudnorm with the targets
+fn target() -> String {
+    format!(
+        "loudnorm=I={}:TP={}:LRA={}",
+        TARGET_LUFS, TARGET_TRUE_PEAK, TARGET_RANGE
+    )
+}
+
+/// the loudnorm of the first pass, which only measures
+pub fn measure_filter() -> String {
+    format!("{}:print_format=json", target())
+}
+
+/// takes the JSON loudnorm prints out of the stderr of an ffmpeg, the stats of the encoder
+/// printed after it would push it out of the tail
+pub fn collect(ffmpeg: &mut Ffmpeg) -> Arc<Mutex<Vec<String>>> {
+    let printed = Arc::new(Mutex::new(vec![]));
+    let lines = printed.clone();
+    let mut inside = false;
+    ffmpeg.on_stderr(move |line| {
+        let line = line.trim();
+        if line == "{" {
+            inside = true;
+            lines.lock().unwrap().clear();
+        }
+        if !inside {
+            return false;
+        }
+        inside = line != "}";
+        lines.lock().unwrap().push(line.to_string());
+        true
+    });
+    printed
+}
+
+/// what loudnorm printed, as [collect] took it
+pub fn parse(printed: &[String]) -> Option<Measured> {
+    let start = printed.iter().rposition(|line| line.trim() == "{")?;
+    let end = start
+        + printed[start..]
+            .iter()
+            .position(|line| line.trim() == "}")?;
+    serde_json::from_str(&printed[start..=end].join("\n")).ok()
+}
+
+/// run the first pass, an ffmpeg of [measure_filter] writing nowhere, for what it measured
+pub async fn measure(builder: FfmpegBuilder<'_>, children: &Children) -> anyhow::Result<Measured> {
+    let mut ffmpeg = builder.run().await?;
+    let printed = collect(&mut ffmpeg);
+    let process_id = ffmpeg.id();
+    children.register(process_id, ChildRole::Compression, vec![]);
+    let summary = ffmpeg.wait_with_progress(|_| {}).await;
+    children.unregister(process_id);
+    let summary = summary?;
+    log_summary("loudness measurement", &summary);
+    if !summary.success() {
+        anyhow::bail!("cannot measure the loudness: {}", summary.exit_status);
+    }
+    let printed = printed.lock().unwrap().clone();
+    parse(&printed).ok_or_else(|| anyhow::anyhow!("loudnorm printed no measurement"))
+}
+
+/// The loudness of a result normalized, see [sidecar_path]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Loudness {
+    pub target_lufs: f64,
+    /// the integrated loudness of the audio captured
+    pub measured_lufs: f64,
+    /// that of the result, as the second pass tells
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_lufs: Option<f64>,
+    /// the true peak of the result, in dBTP
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_true_peak: Option<f64>,
+}
+
+impl Versioned for Loudness {
+    const KIND: &'static str = "loudness sidecars";
+    const VERSION: u32 = 1;
+    const MIGRATIONS: &'static [schema::Migration] = &[schema::unversioned];
+}
+
+impl Loudness {
+    /// of the first pass, and of the second one when it printed what it made
+    pub fn of(first: &Measured, second: Option<&Measured>) -> Option<Self> {
+        Some(Self {
+            target_lufs: TARGET_LUFS,
+            measured_lufs: first.input_lufs()?,
+            output_lufs: second.and_then(|m| m.output_i.as_deref().and_then(number)),
+            output_true_peak: second.and_then(|m| m.output_tp.as_deref().and_then(number)),
+        })
+    }
+}
+
+/// path of the loudness sidecar of a result
+pub fn sidecar_path(result: &Path) -> PathBuf {
+    result.with_extension("loudness.json")
+}
+
+/// write the sidecar next to the result
+pub async fn write_sidecar(result: &Path, loudness: &Loudness) {
+    let path = sidecar_path(result);
+    let json = schema::to_vec_pretty(loudness).expect("loudness json");
+    if let Err(e) = tokio::fs::write(&path, json).await {
+        warn!("cannot write {}: {}", path.display(), e);
+    }
+}
@@ -1,27 +1,335 @@
-pub mod endpoints;
-pub mod ffmpeg;
-pub mod logging;
-pub mod runner;
-pub mod service;
-
 use clap::{Parser, Subcommand};
-use service::*;
+use record_screen::container::Container;
+use record_screen::geometry::GeometryPolicy;
+use record_screen::hwaccel::VideoEncoder;
+use record_screen::pulse::AudioInput;
+use record_screen::service::*;
+use record_screen::source::{CaptureSource, RtspTransport};
+use record_screen::{
+    canary, capture_paths, checksums, content_check, encoder_profiles, endpoints, health, latency,
+    liveness, logging, picker, policy, quota, recordings, s3, shutdown, template, throttle,
+    transcripts, trim,
+};
 use std::sync::Arc;
-use std::{thread, time::Duration};
-use tokio::sync::Mutex;
+use std::time::Duration;
 
 #[derive(Subcommand)]
+#[allow(clippy::large_enum_variant)]
 enum CliCommand {
-    /// Record for 10 seconds and quit
+    /// Record for --duration seconds and quit, or hand the recording over to a running server
     Start {
+        /// Record the stream at this URL (rtsp, rtsps, http, https, srt) instead of the screen
+        #[clap(long)]
+        url: Option<String>,
+        /// Transport of an RTSP stream
+        #[clap(long, value_enum)]
+        rtsp_transport: Option<RtspTransport>,
         #[clap(short, long, default_value = "false")]
         audio: bool,
+        /// Record audio with a separate process that survives sound server restarts
+        #[clap(long, default_value = "false")]
+        audio_resilient: bool,
+        /// PulseAudio source to record, e.g. the monitor of a sink for the sound of the desktop;
+        /// the sources given more than once are mixed
+        #[clap(long, requires = "audio")]
+        audio_source: Vec<String>,
+        /// What is being recorded, to tune the compression for it
+        #[clap(long, value_enum, default_value = "auto")]
+        content: ContentKind,
+        /// Begin the video with a countdown and a red border
+        #[clap(long, default_value = "false")]
+        intro_countdown: bool,
+        /// Draw this text over the video, %{localtime} showing the wall clock
+        #[clap(long)]
+        overlay_text: Option<String>,
+        /// Where the overlay text is drawn
+        #[clap(long, value_enum, default_value = "top-left")]
+        overlay_position: record_screen::overlays::Position,
+        /// Size of the overlay text in pixels, 24 by default
+        #[clap(long, requires = "overlay_text")]
+        overlay_font_size: Option<u32>,
+        /// Whether the result must be on disk before the recording is done
+        #[clap(long, value_enum, default_value = "default")]
+        durability: Durability,
+        /// Also name the result by its hash, in the by-hash directory
+        #[clap(long, default_value = "false")]
+        content_addressed: bool,
+        /// What the human readable name of a content addressed result becomes
+        #[clap(long, value_enum, default_value = "symlink")]
+        hash_link: HashLink,
+        /// What to do when the captured monitor or the resolution changes
+        #[clap(long, value_enum, default_value = "continue")]
+        on_geometry_change: GeometryPolicy,
+        /// Write the capture time of every frame to a <name>.frames.csv.gz sidecar
+        #[clap(long, default_value = "false")]
+        frame_timestamps: bool,
+        /// Whether x11grab reads the screen from shared memory, by default as capture-paths found
+        #[clap(long)]
+        use_shm: Option<bool>,
+        /// Capture without the pointer and track it apart, <name>.cursor.json, to draw it later
+        #[clap(long, default_value = "false")]
+        cursor_track: bool,
+        /// Capture without the pointer, with nothing tracked
+        #[clap(long, default_value = "false", conflicts_with = "cursor_track")]
+        hide_mouse: bool,
+        /// Draw a square around the pointer for every click while it is compressed
+        #[clap(long, default_value = "false", conflicts_with = "cursor_track")]
+        show_clicks: bool,
+        /// Open the compressed recording with a slate of its id, start, host and resolution
+        #[clap(long, default_value = "false")]
+        slate: bool,
+        /// Make a contact sheet of the recording once it is done, <name>.sheet.jpg
+        #[clap(long, default_value = "false")]
+        auto_contact_sheet: bool,
+        /// Look for a black or silent recording once it is compressed; the server decides without
+        #[clap(long)]
+        verify_content: Option<bool>,
+        /// Keep the raw capture as the recording rather than compress it
+        #[clap(long, default_value = "false")]
+        no_compress: bool,
+        /// Constant rate factor of the compression, 0 to 51, that for the content by default
+        #[clap(long)]
+        crf: Option<u8>,
+        /// Preset of libx264 for the compression, e.g. slow for an archive
+        #[clap(long)]
+        preset: Option<String>,
+        /// Keep the raw capture next to the compressed recording
+        #[clap(long, default_value = "false")]
+        keep_original: bool,
+        /// Bring the audio to -16 LUFS while it is compressed, measured in a first pass
+        #[clap(long, default_value = "false")]
+        normalize_audio: bool,
+        /// Scale the compressed recording, e.g. 1080p or 1920:-2; the capture keeps its size
+        #[clap(long)]
+        scale: Option<String>,
+        /// Write the capture in chunks of this many seconds, each compressed on its own
+        #[clap(long)]
+        segment_seconds: Option<u64>,
+        /// Join the chunks of --segment-seconds into one recording
+        #[clap(long, default_value = "false", requires = "segment_seconds")]
+        concat_segments: bool,
+        /// Stream the capture to this RTMP server as well, encoded for it
+        #[clap(long)]
+        rtmp_url: Option<String>,
+        /// Bitrate of the stream in kbit/s, 4500 by default
+        #[clap(long, requires = "rtmp_url")]
+        rtmp_bitrate: Option<u32>,
+        /// Stream the capture to --rtmp-url without writing it
+        #[clap(long, default_value = "false", requires = "rtmp_url")]
+        stream_only: bool,
+        /// What encodes the capture and its compression
+        #[clap(long, value_enum, default_value = "software")]
+        encoder: VideoEncoder,
+        /// Container of the recording; that of an mkv or a webm survives a kill of the capture
+        #[clap(long, value_enum, default_value = "mp4")]
+        format: Container,
+        /// Drag out the region of the screen to record, with slop
+        #[clap(long, default_value = "false", conflicts_with = "monitor")]
+        select_region: bool,
+        /// Record this monitor, by name or number; the list is printed to pick from without one
+        #[clap(long, num_args = 0..=1, default_missing_value = "")]
+        monitor: Option<String>,
+        /// Seconds counted down in the terminal before the recording starts
+        #[clap(long, default_value = "0")]
+        countdown: u32,
+        /// Start the recording on the server and follow it, instead of recording here
+        #[clap(long, default_value = "false")]
+        via_server: bool,
+        /// The server of --via-server
+        #[clap(
+            long,
+            default_value = "http://localhost:8000",
+            env = "RECORD_SCREEN_SERVER"
+        )]
+        server: String,
+        /// Bearer token sent to the server
+        #[clap(long, env = "RECORD_SCREEN_TOKEN")]
+        token: Option<String>,
+        /// Stop the recording on Ctrl-C rather than leave it running on the server
+        #[clap(long, default_value = "false")]
+        stop_on_detach: bool,
+        /// Seconds to record for, until Ctrl-C with 0
+        #[clap(long, default_value = "10")]
+        duration: u64,
+        /// Width captured from the top left corner, that of the screen by default as xrandr tells
+        /// it; 1920 when xrandr can't be run, and the start fails when the display can't be reached
+        #[clap(long, conflicts_with_all = ["select_region", "monitor"])]
+        width: Option<u32>,
+        /// Height captured from the top left corner, that of the screen by default as xrandr tells
+        /// it; 1080 when xrandr can't be run, and the start fails when the display can't be reached
+        #[clap(long, conflicts_with_all = ["select_region", "monitor"])]
+        height: Option<u32>,
+        /// Frames per second of the capture, 25 by default
+        #[clap(long)]
+        framerate: Option<u32>,
+    },
+    /// Start recordings on several servers at the same instant, and tell how close each one was
+    SyncStart {
+        /// The URLs of the servers, comma separated
+        #[clap(long, value_delimiter = ',', required = true)]
+        servers: Vec<String>,
+        /// How long from now the recordings start, e.g. "5s" or "1500ms"
+        #[clap(long = "in", default_value = "5s", value_parser = parse_delay)]
+        delay: Duration,
+        #[clap(short, long, default_value = "false")]
+        audio: bool,
+        /// Bearer token sent to the servers
+        #[clap(long, env = "RECORD_SCREEN_TOKEN")]
+        token: Option<String>,
+    },
+    /// Measure the CPU the ways of grabbing the screen take, and keep the fastest for the recordings
+    CapturePaths,
+    /// Check a recording against its checksum manifest
+    Verify {
+        /// The recording, its manifest is next to it
+        file: std::path::PathBuf,
     },
     /// Start server
     Server {
         /// Net listening address of HTTP server in case of "server" command
         #[clap(short, long, default_value = "0.0.0.0:8000", env = "LISTEN")]
         listen: String,
+        /// Record audio in recordings started with SIGUSR1
+        #[clap(short, long, default_value = "false")]
+        audio: bool,
+        /// Largest body accepted by the endpoints receiving recordings, in bytes
+        #[clap(long, env = "IMPORT_BODY_LIMIT")]
+        import_body_limit: Option<usize>,
+        /// Checks of /api/liveness
+        #[clap(long, value_enum, value_delimiter = ',')]
+        liveness_checks: Option<Vec<liveness::Check>>,
+        /// Least free space in the output directory for /api/liveness, in bytes
+        #[clap(long)]
+        liveness_min_free: Option<u64>,
+        /// Bearer token of the admin endpoints such as /api/emergency-stop
+        #[clap(long, env = "ADMIN_TOKEN")]
+        admin_token: Option<String>,
+        /// Bearer token every route of the API needs, the admin role; no route is open with it
+        #[clap(long, env = "AUTH_TOKEN")]
+        token: Option<String>,
+        /// How long the recording may take to finish on SIGTERM or SIGINT, in seconds
+        #[clap(long, default_value_t = 30)]
+        shutdown_grace: u64,
+        /// Compress the recording stopped by SIGTERM or SIGINT rather than keep the raw capture
+        #[clap(long)]
+        compress_on_shutdown: bool,
+        /// URL the changes of the recording state are posted to, e.g.
+        /// "https://hooks.example.com/{owner}/{recording_id}", with the tokens of --file-name and
+        /// {recording_id}; the values are percent-encoded
+        #[clap(long, env = "WEBHOOK_URL", value_parser = template::webhook_url)]
+        webhook_url: Option<template::Template>,
+        /// Bucket the finished recordings are uploaded to, with the credentials of
+        /// AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY
+        #[clap(long, env = "S3_BUCKET", requires_all = ["s3_access_key", "s3_secret_key"])]
+        s3_bucket: Option<String>,
+        /// URL of the S3 compatible store, that of AWS in the region by default
+        #[clap(long, env = "S3_ENDPOINT")]
+        s3_endpoint: Option<String>,
+        #[clap(long, env = "AWS_REGION", default_value = "us-east-1")]
+        s3_region: String,
+        /// Prepended to the file names of the recordings to make their keys, e.g.
+        /// "recordings/{owner}/{date}/", with the tokens of --file-name, {recording_id} and
+        /// {filename}; the values are percent-encoded
+        #[clap(long, default_value = "", value_parser = template::key_prefix)]
+        s3_prefix: template::Template,
+        #[clap(long, env = "AWS_ACCESS_KEY_ID")]
+        s3_access_key: Option<String>,
+        #[clap(long, env = "AWS_SECRET_ACCESS_KEY")]
+        s3_secret_key: Option<String>,
+        #[clap(long, env = "AWS_SESSION_TOKEN")]
+        s3_session_token: Option<String>,
+        /// Remove the local result once it was uploaded
+        #[clap(long, requires = "s3_bucket")]
+        delete_after_upload: bool,
+        /// A named API token and its role, e.g. "hallway-tv=viewer:<token>", the role being
+        /// viewer, operator or admin; repeatable
+        #[clap(long = "api-token", env = "API_TOKENS", value_delimiter = ',')]
+        api_tokens: Vec<record_screen::auth::ApiToken>,
+        /// When recording is allowed, e.g. "mon-fri 08:00-18:00", in the local timezone; repeatable
+        #[clap(long = "allowed-window")]
+        allowed_windows: Vec<policy::RecordingWindow>,
+        /// Disk quota of an owner, e.g. "teamA=50GB", or "default=20GB" for the others; repeatable
+        #[clap(long = "quota")]
+        quotas: Vec<quota::OwnerQuota>,
+        /// A speech-to-text program recordings may ask for, e.g. "whisper=/opt/whisper/run -m base";
+        /// repeatable
+        #[clap(long = "transcriber")]
+        transcribers: Vec<transcripts::Transcriber>,
+        /// Seconds a transcriber may run
+        #[clap(long, default_value = "3600")]
+        transcribe_timeout: u64,
+        /// Directory the captures go on in when the output directory can't be written any more
+        #[clap(long)]
+        fallback_dir: Option<std::path::PathBuf>,
+        /// Seconds a recording may run past the end of its window
+        #[clap(long, default_value = "60")]
+        window_grace: i64,
+        /// Size of all the browser playable copies together, in bytes; unlimited with 0
+        #[clap(long, default_value = "2147483648")]
+        play_cache_size: u64,
+        /// URL the clients reach the server at, for the absolute links of the feeds
+        #[clap(long, env = "EXTERNAL_URL")]
+        external_url: Option<String>,
+        /// Secret signing the feed tokens; without it the feeds are open
+        #[clap(long, env = "FEED_SECRET")]
+        feed_secret: Option<String>,
+        /// Don't look for black or silent recordings unless they ask for it
+        #[clap(long, default_value = "false")]
+        no_verify_content: bool,
+        /// Fraction of the sampled content black or silent above which a recording is flagged
+        #[clap(long, default_value = "0.95")]
+        suspect_fraction: f64,
+        /// Longest recording of /api/record, in seconds
+        #[clap(long, default_value = "300")]
+        max_record_secs: u64,
+        /// Name of the recordings without the extension, of {date}, {time}, {hostname}, {owner}
+        /// and {profile}
+        #[clap(long, default_value = template::DEFAULT_FILE_NAME, value_parser = template::file_name)]
+        file_name: template::Template,
+        /// Read rate of a kind of background job: compression, checksum, contact_sheet or
+        /// transcode, e.g. "checksum=64M"; unlimited with 0; repeatable
+        #[clap(long = "io-limit")]
+        io_limits: Vec<throttle::IoLimit>,
+        /// Fraction of their read rate the background jobs keep while a recording runs
+        #[clap(long, default_value = "0.25")]
+        capture_io_fraction: f64,
+        /// Run the ffmpeg background jobs in the idle I/O class, with ionice
+        #[clap(long, default_value = "false")]
+        idle_io: bool,
+        /// Weight of a factor of the health score, with the values where it starts and where it
+        /// takes all of it, e.g. "slow_speed=40:0.1:0.5"; repeatable
+        #[clap(long = "health-rule")]
+        health_rules: Vec<health::RuleOverride>,
+        /// Health score below which a recording is degraded
+        #[clap(long, default_value = "75")]
+        health_degraded: u8,
+        /// An encoder the recordings may ask for by name, with its container, pixel format and
+        /// output options, e.g. "prores_hq=mov,yuv422p10le -c:v prores_ks -profile:v 3"; repeatable
+        #[clap(long = "encoder-profile")]
+        encoder_profiles: Vec<encoder_profiles::EncoderProfile>,
+        /// Health score below which a recording is critical
+        #[clap(long, default_value = "45")]
+        health_critical: u8,
+        /// Seconds cut off the start of the results when the stop tells nothing
+        #[clap(long, default_value = "0")]
+        trim_head: f64,
+        /// Seconds cut off the end of the results when the stop tells nothing, not of the ones
+        /// the server stops itself
+        #[clap(long, default_value = "0")]
+        trim_tail: f64,
+        /// Record a short canary on a schedule, to tell that the recording still works
+        #[clap(long, default_value = "false")]
+        canary: bool,
+        /// When the canary records, days and a time, e.g. "mon-fri 06:00"
+        #[clap(long, default_value = canary::DEFAULT_SCHEDULE)]
+        canary_at: canary::Schedule,
+        /// What the canary records: test for the test source, screen for the screen inside the
+        /// recording windows
+        #[clap(long, default_value = "test")]
+        canary_source: canary::Source,
+        /// Put the canary into the storage and read it back
+        #[clap(long, default_value = "false")]
+        canary_upload: bool,
     },
 }
 
@@ -30,35 +338,708 @@ enum CliCommand {
 struct Opts {
     #[clap(subcommand)]
     cmd: CliCommand,
+    /// Also log to this file, rotated by size
+    #[clap(long, global = true, env = "LOG_FILE")]
+    log_file: Option<std::path::PathBuf>,
+    /// Size at which the log file is rotated, in bytes
+    #[clap(long, global = true, default_value = "10485760")]
+    log_max_size: u64,
+    /// Rotated log files kept
+    #[clap(long, global = true, default_value = "5")]
+    log_keep: usize,
+    /// Filter directives of the log file, those of RUST_LOG by default
+    #[clap(long, global = true)]
+    log_file_level: Option<String>,
+    /// How the log lines are written
+    #[clap(
+        long,
+        global = true,
+        value_enum,
+        env = "LOG_FORMAT",
+        default_value = "text"
+    )]
+    log_format: logging::LogFormat,
+    /// Base URL of the OTLP/HTTP collector the spans of the recordings are exported to, needs the
+    /// otel feature
+    #[clap(long, global = true, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    otel_endpoint: Option<String>,
+    /// Directory the recordings are written to, created when missing; the videos directory by
+    /// default
+    #[clap(long, global = true, env = "OUTPUT_DIR")]
+    output_dir: Option<std::path::PathBuf>,
 }
 
 #[tokio::main]
 async fn main() {
     color_eyre::install().unwrap();
-    logging::start("INFO");
-
     let opt = Opts::parse();
+    logging::start(
+        "INFO",
+        logging::Config {
+            format: opt.log_format,
+            file: opt.log_file.map(|path| logging::FileConfig {
+                path,
+                max_bytes: opt.log_max_size,
+                keep: opt.log_keep,
+                filter: opt.log_file_level,
+            }),
+            otel_endpoint: opt.otel_endpoint,
+        },
+    );
+    if let Some(dir) = opt.output_dir {
+        recordings::set_output_dir(dir);
+    }
+
     match opt.cmd {
-        CliCommand::Server { listen } => {
+        CliCommand::SyncStart {
+            servers,
+            delay,
+            audio,
+            token,
+        } => {
+            let opt = RecordingOptions {
+                audio,
+                ..Default::default()
+            };
+            if let Err(e) = sync_start(&servers, token, opt, delay).await {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        CliCommand::CapturePaths => {
+            let analysis = capture_paths::analyze().await;
+            for m in &analysis.measurements {
+                match &m.error {
+                    Some(error) => println!("{:?}: failed, {}", m.path, error),
+                    None => println!(
+                        "{:?}: {} CPU seconds at {}x",
+                        m.path,
+                        m.cpu_seconds
+                            .map(|s| format!("{:.2}", s))
+                            .unwrap_or_else(|| "?".to_string()),
+                        m.speed
+                            .map(|s| format!("{:.2}", s))
+                            .unwrap_or_else(|| "?".to_string()),
+                    ),
+                }
+            }
+            match analysis.fastest {
+                Some(path) => println!("fastest: {:?}", path),
+                None => println!("no capture path worked"),
+            }
+            if let Err(e) = capture_paths::store(&analysis) {
+                eprintln!("cannot keep the analysis: {}", e);
+                std::process::exit(1);
+            }
+            for m in &analysis.measurements {
+                if let Some(latency_ms) = m.first_frame_ms {
+                    println!("{:?}: first frame after {:.0}ms", m.path, latency_ms);
+                    latency::record(&m.path.configuration(), latency_ms);
+                }
+            }
+        }
+        CliCommand::Verify { file } => match verify(&file) {
+            Ok(true) => {}
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("{:#}", e);
+                std::process::exit(2);
+            }
+        },
+        CliCommand::Server {
+            listen,
+            audio,
+            import_body_limit,
+            liveness_checks,
+            liveness_min_free,
+            admin_token,
+            token,
+            shutdown_grace,
+            compress_on_shutdown,
+            webhook_url,
+            s3_bucket,
+            s3_endpoint,
+            s3_region,
+            s3_prefix,
+            s3_access_key,
+            s3_secret_key,
+            s3_session_token,
+            delete_after_upload,
+            api_tokens,
+            allowed_windows,
+            quotas,
+            transcribers,
+            transcribe_timeout,
+            fallback_dir,
+            window_grace,
+            play_cache_size,
+            external_url,
+            feed_secret,
+            no_verify_content,
+            suspect_fraction,
+            max_record_secs,
+            file_name,
+            io_limits,
+            capture_io_fraction,
+            idle_io,
+            health_rules,
+            health_degraded,
+            health_critical,
+            encoder_profiles,
+            trim_head,
+            trim_tail,
+            canary,
+            canary_at,
+            canary_source,
+            canary_upload,
+        } => {
             let socket_addr: std::net::SocketAddr = listen.parse().expect("invalid bind to listen");
-            endpoints::run(socket_addr).await.unwrap();
+            let defaults = RecordingOptions {
+                audio,
+                ..Default::default()
+            };
+            let mut limits = endpoints::BodyLimits::default();
+            if let Some(import) = import_body_limit {
+                limits.import = import;
+            }
+            let mut checks = liveness::Config::default();
+            if let Some(list) = liveness_checks {
+                checks.checks = list;
+            }
+            if let Some(min_free) = liveness_min_free {
+                checks.min_free_bytes = min_free;
+            }
+            let config = endpoints::ServerConfig {
+                defaults,
+                limits,
+                liveness: checks,
+                admin_token,
+                token,
+                tokens: api_tokens,
+                policy: policy::Policy {
+                    windows: allowed_windows,
+                    grace: chrono::Duration::seconds(window_grace),
+                },
+                quotas,
+                transcribers,
+                transcribe_timeout: Duration::from_secs(transcribe_timeout),
+                fallback_dir,
+                play_cache_bytes: play_cache_size,
+                external_url,
+                feed_secret,
+                content_check: content_check::Config {
+                    enabled: !no_verify_content,
+                    fraction: suspect_fraction,
+                    ..Default::default()
+                },
+                max_record: Some(Duration::from_secs(max_record_secs)),
+                file_name,
+                throttle: throttle::Config::new(io_limits, capture_io_fraction, idle_io),
+                health: health::Config::new(health_rules, health_degraded, health_critical),
+                encoder_profiles,
+                trim: trim::Trim {
+                    head_secs: trim_head.max(0.0),
+                    tail_secs: trim_tail.max(0.0),
+                },
+                canary: canary::Config {
+                    schedule: canary.then_some(canary_at),
+                    source: canary_source,
+                    upload: canary_upload,
+                },
+                shutdown: shutdown::Config {
+                    grace: Duration::from_secs(shutdown_grace),
+                    compress: compress_on_shutdown,
+                },
+                webhook_url,
+                s3: s3_bucket.map(|bucket| s3::Config {
+                    bucket,
+                    endpoint: s3_endpoint,
+                    region: s3_region,
+                    access_key: s3_access_key.unwrap_or_default(),
+                    secret_key: s3_secret_key.unwrap_or_default(),
+                    session_token: s3_session_token,
+                    prefix: s3_prefix,
+                    delete_after_upload,
+                }),
+            };
+            endpoints::run(socket_addr, config).await.unwrap();
         }
-        CliCommand::Start { audio } => {
+        CliCommand::Start {
+            url,
+            rtsp_transport,
+            audio,
+            audio_resilient,
+            audio_source,
+            content,
+            durability,
+            intro_countdown,
+            overlay_text,
+            overlay_position,
+            overlay_font_size,
+            content_addressed,
+            hash_link,
+            on_geometry_change,
+            frame_timestamps,
+            use_shm,
+            cursor_track,
+            hide_mouse,
+            show_clicks,
+            slate,
+            auto_contact_sheet,
+            verify_content,
+            no_compress,
+            crf,
+            preset,
+            keep_original,
+            normalize_audio,
+            scale,
+            segment_seconds,
+            concat_segments,
+            rtmp_url,
+            rtmp_bitrate,
+            stream_only,
+            encoder,
+            format,
+            select_region,
+            monitor,
+            countdown,
+            via_server,
+            server,
+            token,
+            stop_on_detach,
+            duration,
+            width,
+            height,
+            framerate,
+        } => {
+            let region = if select_region {
+                match picker::select_region().await {
+                    Ok(Some(region)) => Some(region),
+                    Ok(None) => {
+                        eprintln!("selection cancelled");
+                        std::process::exit(1);
+                    }
+                    Err(e) => {
+                        eprintln!("cannot select a region: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            } else if let Some(monitor) = monitor {
+                match picker::pick_monitor(&monitor).await {
+                    Ok((name, rect)) => {
+                        println!("STATUS: recording {} {}", name, rect);
+                        Some(rect)
+                    }
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        std::process::exit(2);
+                    }
+                }
+            } else {
+                None
+            };
+            let source = match url {
+                Some(url) => CaptureSource::Url {
+                    url,
+                    input_format: None,
+                    rtsp_transport,
+                },
+                None => CaptureSource::Screen,
+            };
+            // a single source is captured as it is, more are mixed
+            let (audio_source, audio_sources) = match audio_source.as_slice() {
+                [source] => (Some(source.clone()), vec![]),
+                sources => (
+                    None,
+                    sources
+                        .iter()
+                        .map(|source| AudioInput {
+                            source: source.clone(),
+                            volume: None,
+                        })
+                        .collect(),
+                ),
+            };
+            let opt = RecordingOptions {
+                source,
+                audio,
+                audio_resilient,
+                audio_source,
+                audio_sources,
+                content,
+                durability,
+                intro_countdown,
+                overlay_text,
+                overlay_position,
+                overlay_font_size,
+                content_addressed,
+                hash_link,
+                on_geometry_change,
+                frame_timestamps,
+                region,
+                use_shm,
+                cursor_track,
+                draw_mouse: hide_mouse.then_some(false),
+                show_clicks,
+                slate,
+                auto_contact_sheet,
+                verify_content,
+                width,
+                height,
+                framerate,
+                compress: no_compress.then_some(false),
+                crf,
+                preset,
+                keep_original,
+                normalize_audio,
+                scale,
+                segment_seconds,
+                concat_segments,
+                rtmp_url,
+                rtmp_bitrate,
+                stream_only,
+                encoder,
+                format,
+                ..Default::default()
+            };
+            if let Err((field, e)) = record_screen::geometry::validate_size(&opt) {
+                eprintln!("invalid --{}: {}", field, e);
+                std::process::exit(2);
+            }
+            if let Err((field, e)) = record_screen::chunks::validate(&opt) {
+                eprintln!("invalid --{}: {}", field.replace('_', "-"), e);
+                std::process::exit(2);
+            }
+            if let Err((field, e)) = record_screen::rtmp::validate(&opt) {
+                eprintln!("invalid --{}: {}", field.replace('_', "-"), e);
+                std::process::exit(2);
+            }
+            if let Err((field, e)) = record_screen::overlays::validate(&opt) {
+                eprintln!("invalid --{}: {}", field.replace('_', "-"), e);
+                std::process::exit(2);
+            }
+            if let Err((field, e)) = record_screen::cursor::validate(&opt) {
+                let field = match field {
+                    "draw_mouse" => "hide-mouse",
+                    field => field,
+                };
+                eprintln!("invalid --{}: {}", field.replace('_', "-"), e);
+                std::process::exit(2);
+            }
+            if let Err((field, e)) = record_screen::scale::validate(&opt) {
+                eprintln!("invalid --{}: {}", field, e);
+                std::process::exit(2);
+            }
+            if let Err((_, e)) = record_screen::pulse::validate(&opt) {
+                eprintln!("invalid --audio-source: {}", e);
+                std::process::exit(2);
+            }
+            if let Err((field, e)) = record_screen::service::validate_compression(&opt) {
+                let field = match field {
+                    "compress" => "no-compress",
+                    field => field,
+                };
+                eprintln!("invalid --{}: {}", field, e);
+                std::process::exit(2);
+            }
+            if !via_server {
+                // the server looks for the source of its own sound server
+                match record_screen::pulse::check(&opt).await {
+                    Ok(()) => {}
+                    Err(record_screen::pulse::Error::Unknown { name, available }) => {
+                        eprintln!(
+                            "invalid --audio-source: the sound server has no source {}, it has {}",
+                            name,
+                            available.join(", ")
+                        );
+                        std::process::exit(2);
+                    }
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            picker::countdown(countdown).await;
+            if via_server {
+                if let Err(e) = via(&server, token, opt, stop_on_detach).await {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+                return;
+            }
+
             // start recording
-            let mx = Arc::new(Mutex::new(RecordingState::Waiting));
+            let mx = Arc::new(Recorder::new());
+            let mut recording = tokio::spawn(start(mx.clone(), opt));
+            let until_stop = async {
+                match duration {
+                    0 => {
+                        println!("STATUS: launched, recording until Ctrl-C");
+                        if let Err(e) = tokio::signal::ctrl_c().await {
+                            eprintln!("cannot wait for Ctrl-C: {}", e);
+                        }
+                    }
+                    secs => {
+                        println!("STATUS: launched, waiting for {} seconds to stop", secs);
+                        tokio::time::sleep(Duration::from_secs(secs)).await;
+                    }
+                }
+            };
+            tokio::select! {
+                // Ctrl-C reaches ffmpeg too, which exits with it
+                biased;
+                _ = until_stop => {}
+                ended = &mut recording => {
+                    match ended {
+                        Ok(Ok(())) => eprintln!("the capture exited before the recording was stopped"),
+                        Ok(Err(e)) => eprintln!("the recording failed: {:#}", e),
+                        Err(e) => eprintln!("the recording failed: {}", e),
+                    }
+                    std::process::exit(1);
+                }
+            }
+            if let Err(e) = stop(mx.clone()).await {
+                eprintln!("cannot stop the recording: {:#}", e);
+                std::process::exit(1);
+            }
+            let finished = mx.lock().await.clone();
+            match finished {
+                RecordingState::Done { file, .. } => println!("STATUS: done, {}", file),
+                RecordingState::Waiting if stream_only => println!("STATUS: done, streamed"),
+                state => {
+                    eprintln!("the recording ended {}", state.name());
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+}
+
+/// starts the recording on the server and prints its progress until it's over or Ctrl-C
+#[cfg(feature = "client")]
+async fn via(
+    server: &str,
+    token: Option<String>,
+    opt: RecordingOptions,
+    stop_on_detach: bool,
+) -> anyhow::Result<()> {
+    use futures::StreamExt;
+    use record_screen::client::{ClientError, RecordScreenClient};
+    use record_screen::events::{EventKind, Message};
+
+    let explain = |e: ClientError| match e.problem() {
+        Some(problem) => anyhow::anyhow!("{}", problem),
+        None => anyhow::anyhow!(e),
+    };
+    let client = RecordScreenClient::new(server, token);
+    // attached before the start, to see the recording from its first event
+    let mut events = Box::pin(client.events(None).await.map_err(explain)?);
+    client.start(opt).await.map_err(explain)?;
+    println!("STATUS: started on {}, Ctrl-C to detach", server);
+    loop {
+        let message = tokio::select! {
+            _ = tokio::signal::ctrl_c() => {
+                if stop_on_detach {
+                    client.stop().await.map_err(explain)?;
+                    println!("\nSTATUS: stopped, the server compresses the recording");
+                } else {
+                    println!("\nSTATUS: detached, the recording goes on at {}", server);
+                }
+                return Ok(());
+            }
+            message = events.next() => match message {
+                None => anyhow::bail!("the server closed the stream"),
+                Some(message) => message.map_err(explain)?,
+            },
+        };
+        let state = match message {
+            Message::Resync { state, .. } => state,
+            Message::Event(event) => match event.kind {
+                EventKind::State { state } => state,
+                EventKind::Progress { progress, .. } => {
+                    println!("{}", progress.print_info());
+                    continue;
+                }
+                EventKind::Notice { message } => {
+                    println!("NOTICE: {}", message);
+                    continue;
+                }
+                EventKind::Suspect { file, warnings } => {
+                    let warnings = serde_json::to_string(&warnings).unwrap_or_default();
+                    println!(
+                        "WARNING: {} is probably not what was meant, {}",
+                        file, warnings
+                    );
+                    continue;
+                }
+                EventKind::Health { previous, health } => {
+                    let factors: Vec<_> = health.factors.iter().map(|f| f.name()).collect();
+                    println!(
+                        "HEALTH: {} at {}, was {}: {}",
+                        health.level.name(),
+                        health.score,
+                        previous.name(),
+                        factors.join(", ")
+                    );
+                    continue;
+                }
+            },
+        };
+        match state {
+            RecordingState::Done { file, .. } => {
+                println!("STATUS: done, {}", file);
+                return Ok(());
+            }
+            RecordingState::Failed { message, .. } => {
+                anyhow::bail!("the recording failed: {}", message)
+            }
+            RecordingState::Cancelled { reason, .. } => {
+                anyhow::bail!("the recording was cancelled: {:?}", reason)
+            }
+            state => println!("STATUS: {}", state.name()),
+        }
+    }
+}
+
+#[cfg(not(feature = "client"))]
+async fn via(
+    _server: &str,
+    _token: Option<String>,
+    _opt: RecordingOptions,
+    _stop_on_detach: bool,
+) -> anyhow::Result<()> {
+    anyhow::bail!("--via-server needs a build with the client feature")
+}
+
+/// prints how the recording compares with its manifest, whether it matches
+fn verify(file: &std::path::Path) -> anyhow::Result<bool> {
+    let Some(manifest) = checksums::read_manifest(file)? else {
+        anyhow::bail!(
+            "no manifest at {}",
+            checksums::manifest_path(file).display()
+        );
+    };
+    let verification = checksums::verify(file, &manifest)?;
+    println!("{}", serde_json::to_string_pretty(&verification)?);
+    Ok(verification.ok)
+}
+
+/// a delay in seconds, or with an `s` or `ms` suffix
+fn parse_delay(s: &str) -> Result<Duration, String> {
+    let (number, unit) = match s.strip_suffix("ms") {
+        Some(ms) => (ms, 0.001),
+        None => (s.strip_suffix('s').unwrap_or(s), 1.0),
+    };
+    let value: f64 = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("not a delay: {}", s))?;
+    Duration::try_from_secs_f64(value * unit).map_err(|e| format!("not a delay: {}", e))
+}
 
-            let mx1 = mx.clone();
-            let opt = RecordingOptions { audio };
-            tokio::spawn(async {
-                let _ = start(mx1, opt).await.unwrap();
-            });
+/// schedules the same start on every server, then prints how far off each one started
+#[cfg(feature = "client")]
+async fn sync_start(
+    servers: &[String],
+    token: Option<String>,
+    opt: RecordingOptions,
+    delay: Duration,
+) -> anyhow::Result<()> {
+    use record_screen::client::{ClientError, RecordScreenClient};
 
-            let h2 = tokio::spawn(async {
-                thread::sleep(Duration::from_secs(10));
-                let _ = stop(mx).await.unwrap();
-            });
-            println!("STATUS: launched, waiting for 10 seconds to stop");
-            h2.await.unwrap();
+    let explain = |e: ClientError| match e.problem() {
+        Some(problem) => problem.to_string(),
+        None => e.to_string(),
+    };
+    let start_at = chrono::Local::now() + chrono::Duration::from_std(delay)?;
+    let opt = RecordingOptions {
+        start_at: Some(start_at),
+        ..opt
+    };
+    let clients: Vec<_> = servers
+        .iter()
+        .map(|server| RecordScreenClient::new(server, token.clone()))
+        .collect();
+    let scheduled = futures::future::join_all(
+        clients
+            .iter()
+            .map(|client| client.schedule_start(opt.clone())),
+    )
+    .await;
+    println!("STATUS: starting at {}", start_at.to_rfc3339());
+    let mut failed = 0;
+    let mut started = vec![];
+    for ((server, client), scheduled) in servers.iter().zip(&clients).zip(scheduled) {
+        match scheduled {
+            Ok(scheduled) => {
+                let synchronized = match scheduled.clock_synchronized {
+                    Some(true) => "synchronized",
+                    Some(false) => "not synchronized",
+                    None => "synchronization unknown",
+                };
+                println!(
+                    "{}: scheduled, clock at {} ({})",
+                    server,
+                    scheduled.server_time.to_rfc3339(),
+                    synchronized
+                );
+                started.push((server, client));
+            }
+            Err(e) => {
+                println!("{}: not scheduled, {}", server, explain(e));
+                failed += 1;
+            }
         }
     }
+    tokio::time::sleep(delay).await;
+    let reports =
+        futures::future::join_all(started.iter().map(|(_, client)| triggered(client))).await;
+    for ((server, _), report) in started.iter().zip(reports) {
+        match report {
+            Ok(sync) => println!(
+                "{}: started at {}, {:+.1}ms off",
+                server,
+                sync.triggered.to_rfc3339(),
+                sync.error_ms
+            ),
+            Err(e) => {
+                println!("{}: {}", server, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!("{} of {} servers did not start", failed, servers.len());
+    }
+    Ok(())
+}
+
+/// how far off the server started, once it did
+#[cfg(feature = "client")]
+async fn triggered(
+    client: &record_screen::client::RecordScreenClient,
+) -> anyhow::Result<record_screen::sync_start::StartSync> {
+    for _ in 0..20 {
+        match client.status().await? {
+            RecordingState::Countdown { .. } => {}
+            RecordingState::Started {
+                start_sync: Some(sync),
+                ..
+            } => return Ok(sync),
+            state => anyhow::bail!("not started, {}", state.name()),
+        }
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+    anyhow::bail!("still counting down")
+}
+
+#[cfg(not(feature = "client"))]
+async fn sync_start(
+    _servers: &[String],
+    _token: Option<String>,
+    _opt: RecordingOptions,
+    _delay: Duration,
+) -> anyhow::Result<()> {
+    anyhow::bail!("sync-start needs a build with the client feature")
 }
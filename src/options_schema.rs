@@ -0,0 +1,811 @@
+//! What the RecordingOptions of this server are, for the clients that build their start form
+//!
+//! `GET /api/options-schema` lists every field a start accepts, by its dotted path in the JSON
+//! body, with its type, its default, the values or the bounds the validation accepts, the group
+//! it goes in and its place there. Nothing is written twice: the defaults are those of
+//! [RecordingOptions::default] as serialized, the values of an enum are its variants as
+//! serialized, and the bounds are the constants the validation checks against. A field the
+//! server can't honor now, audio without a sound server or a transcript without a transcriber,
+//! is marked unavailable with the reason.
+use crate::chunks;
+use crate::container::Container;
+use crate::geometry::{self, GeometryPolicy};
+use crate::hwaccel::VideoEncoder;
+use crate::overlays;
+use crate::quality;
+use crate::rtmp;
+use crate::service::{
+    ContentKind, Durability, HashLink, Recorder, RecordingOptions, MAX_CRF, PRESETS,
+};
+use crate::source::{self, CaptureSource, RtspTransport};
+use crate::sync_start;
+use crate::webrtc_preview;
+use serde::Serialize;
+use serde_json::Value;
+use std::process::Stdio;
+
+/// Where a field goes in the form, in this order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Group {
+    Source,
+    Audio,
+    Capture,
+    Compression,
+    Finishing,
+    Scheduling,
+    Bookkeeping,
+}
+
+impl Group {
+    pub const ALL: &'static [Group] = &[
+        Group::Source,
+        Group::Audio,
+        Group::Capture,
+        Group::Compression,
+        Group::Finishing,
+        Group::Scheduling,
+        Group::Bookkeeping,
+    ];
+
+    fn title(&self) -> &'static str {
+        match self {
+            Self::Source => "What to record",
+            Self::Audio => "Audio",
+            Self::Capture => "Capture",
+            Self::Compression => "Compression",
+            Self::Finishing => "Once it is done",
+            Self::Scheduling => "When to start",
+            Self::Bookkeeping => "Bookkeeping",
+        }
+    }
+}
+
+/// The JSON type of a field
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    Boolean,
+    Integer,
+    String,
+    /// one of the `values`
+    Enum,
+    /// RFC 3339
+    DateTime,
+    /// of strings
+    Array,
+    /// of strings by name
+    Object,
+}
+
+/// A field of the options
+#[derive(Debug, Clone, Serialize)]
+pub struct Field {
+    /// the dotted path in the body, `region.width`
+    pub name: &'static str,
+    #[serde(rename = "type")]
+    pub kind: Kind,
+    pub group: Group,
+    /// the place in its group
+    pub order: usize,
+    pub description: &'static str,
+    /// absent when the server decides
+    #[serde(skip_serializing_if = "Value::is_null")]
+    pub default: Value,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub values: Vec<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub minimum: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub maximum: Option<i64>,
+    /// of the bounds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit: Option<&'static str>,
+    /// the schemes a URL may have
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub schemes: Vec<&'static str>,
+    /// the field only applies when another one has this value, `source.type=url`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub applies_when: Option<&'static str>,
+    /// why the server can't honor the field now
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unavailable: Option<String>,
+}
+
+impl Field {
+    fn new(name: &'static str, kind: Kind, group: Group, description: &'static str) -> Self {
+        Self {
+            name,
+            kind,
+            group,
+            order: 0,
+            description,
+            default: Value::Null,
+            values: vec![],
+            minimum: None,
+            maximum: None,
+            unit: None,
+            schemes: vec![],
+            applies_when: None,
+            unavailable: None,
+        }
+    }
+
+    fn values(mut self, values: Vec<Value>) -> Self {
+        self.values = values;
+        self
+    }
+
+    fn bounds(mut self, minimum: Option<i64>, maximum: Option<i64>, unit: &'static str) -> Self {
+        self.minimum = minimum;
+        self.maximum = maximum;
+        self.unit = Some(unit);
+        self
+    }
+
+    fn applies_when(mut self, condition: &'static str) -> Self {
+        self.applies_when = Some(condition);
+        self
+    }
+
+    fn unavailable(mut self, reason: Option<String>) -> Self {
+        self.unavailable = reason;
+        self
+    }
+}
+
+/// A group of the form
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupInfo {
+    pub name: Group,
+    pub title: &'static str,
+}
+
+/// The options of this server
+#[derive(Debug, Clone, Serialize)]
+pub struct OptionsSchema {
+    pub groups: Vec<GroupInfo>,
+    /// by group, then in their order
+    pub fields: Vec<Field>,
+}
+
+/// What the server can do now, for the fields that depend on it
+#[derive(Debug, Clone, Default)]
+pub struct Availability {
+    /// a sound server answers `pactl info`
+    pub sound_server: bool,
+    /// `gzip` runs, for the frame timestamps
+    pub gzip: bool,
+    pub transcribers: Vec<String>,
+    /// the encoder profiles that passed their validation
+    pub encoder_profiles: Vec<String>,
+    /// the server is built with the WebRTC preview
+    pub webrtc_preview: bool,
+}
+
+async fn runs(program: &str, args: &[&str]) -> bool {
+    tokio::process::Command::new(program)
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .status()
+        .await
+        .is_ok_and(|status| status.success())
+}
+
+/// what the server can do now
+pub async fn availability(mx: &Recorder) -> Availability {
+    let (sound_server, gzip) = tokio::join!(runs("pactl", &["info"]), runs("gzip", &["-V"]));
+    Availability {
+        sound_server,
+        gzip,
+        transcribers: mx.transcribers.names(),
+        encoder_profiles: mx.encoder_profiles.validated(),
+        webrtc_preview: webrtc_preview::AVAILABLE,
+    }
+}
+
+/// the variants of an enum as they are serialized
+fn variants<T: clap::ValueEnum + Serialize>() -> Vec<Value> {
+    T::value_variants()
+        .iter()
+        .filter_map(|v| serde_json::to_value(v).ok())
+        .collect()
+}
+
+/// the value at the dotted `path` of `value`
+fn at(value: &Value, path: &str) -> Value {
+    path.split('.')
+        .try_fold(value, |value, key| value.get(key))
+        .cloned()
+        .unwrap_or(Value::Null)
+}
+
+/// the tag of a source as it is serialized
+fn source_type(source: &CaptureSource) -> Value {
+    at(&serde_json::to_value(source).unwrap_or_default(), "type")
+}
+
+/// the options of this server, with what `available` tells about them
+pub fn schema(available: &Availability) -> OptionsSchema {
+    use Group::*;
+    let no_sound = (!available.sound_server).then(|| "no sound server answers".to_string());
+    let url = CaptureSource::Url {
+        url: String::new(),
+        input_format: None,
+        rtsp_transport: None,
+    };
+    let screen = "source.type=screen";
+    let streamed = "source.type=url";
+    let capture_size = (
+        Some(geometry::MIN_REGION_SIZE as i64),
+        Some(geometry::MAX_CAPTURE_SIZE as i64),
+    );
+    let mut fields = vec![
+        Field::new("source.type", Kind::Enum, Source, "what to record")
+            .values(vec![source_type(&CaptureSource::Screen), source_type(&url)]),
+        Field {
+            schemes: source::SCHEMES.to_vec(),
+            ..Field::new("source.url", Kind::String, Source, "the stream to record")
+        }
+        .applies_when(streamed),
+        Field::new(
+            "source.input_format",
+            Kind::String,
+            Source,
+            "the format of the stream, when ffmpeg can't guess it",
+        )
+        .applies_when(streamed),
+        Field::new(
+            "source.rtsp_transport",
+            Kind::Enum,
+            Source,
+            "the transport of an RTSP stream",
+        )
+        .values(variants::<RtspTransport>())
+        .applies_when(streamed),
+        Field::new("region.width", Kind::Integer, Source, "width of the region to capture")
+            .bounds(Some(geometry::MIN_REGION_SIZE as i64), None, "pixels")
+            .applies_when(screen),
+        Field::new("region.height", Kind::Integer, Source, "height of the region")
+            .bounds(Some(geometry::MIN_REGION_SIZE as i64), None, "pixels")
+            .applies_when(screen),
+        Field::new("region.x", Kind::Integer, Source, "offset of the region from the left")
+            .bounds(Some(geometry::MIN_REGION_OFFSET as i64), None, "pixels")
+            .applies_when(screen),
+        Field::new("region.y", Kind::Integer, Source, "offset of the region from the top")
+            .bounds(Some(geometry::MIN_REGION_OFFSET as i64), None, "pixels")
+            .applies_when(screen),
+        Field::new("width", Kind::Integer, Source, "width captured without a region")
+            .bounds(capture_size.0, capture_size.1, "pixels")
+            .applies_when(screen),
+        Field::new("height", Kind::Integer, Source, "height captured without a region")
+            .bounds(capture_size.0, capture_size.1, "pixels")
+            .applies_when(screen),
+        Field::new("framerate", Kind::Integer, Capture, "frames per second of the capture")
+            .bounds(Some(1), Some(quality::MAX_FRAMERATE as i64), "frames per second")
+            .applies_when(screen),
+        Field::new("audio", Kind::Boolean, Audio, "record the sound server along with the screen")
+            .unavailable(no_sound.clone()),
+        Field::new(
+            "audio_resilient",
+            Kind::Boolean,
+            Audio,
+            "record the audio apart, surviving a restart of the sound server",
+        )
+        .unavailable(no_sound.clone()),
+        Field::new(
+            "audio_source",
+            Kind::String,
+            Audio,
+            "the PulseAudio source captured, one of /api/audio-sources",
+        )
+        .applies_when(screen)
+        .unavailable(no_sound.clone()),
+        Field::new(
+            "audio_sources",
+            Kind::Array,
+            Audio,
+            "the PulseAudio sources mixed, by name or as {source, volume}",
+        )
+        .applies_when(screen)
+        .unavailable(no_sound),
+        Field::new(
+            "use_shm",
+            Kind::Boolean,
+            Capture,
+            "read the screen from shared memory, as the capture path analysis recommends by default",
+        )
+        .applies_when(screen),
+        Field::new(
+            "cursor_track",
+            Kind::Boolean,
+            Capture,
+            "capture without the pointer and track it apart, to draw it over the recording later",
+        )
+        .applies_when(screen),
+        Field::new(
+            "draw_mouse",
+            Kind::Boolean,
+            Capture,
+            "capture the pointer, unless false",
+        )
+        .applies_when(screen),
+        Field::new(
+            "show_clicks",
+            Kind::Boolean,
+            Capture,
+            "draw a square around the pointer for every click while it is compressed",
+        )
+        .applies_when(screen),
+        Field::new(
+            "live_preview",
+            Kind::Boolean,
+            Capture,
+            "write an HLS stream of the capture to watch while it is recorded",
+        ),
+        Field::new(
+            "preview",
+            Kind::Enum,
+            Capture,
+            "how the capture is previewed, webrtc being experimental",
+        )
+        .values(variants::<webrtc_preview::Transport>())
+        .unavailable(
+            (!available.webrtc_preview)
+                .then(|| "the server is built without the WebRTC preview".to_string()),
+        ),
+        Field::new(
+            "rtmp_url",
+            Kind::String,
+            Capture,
+            "stream the capture to this RTMP server, encoded for it",
+        ),
+        Field::new("rtmp_bitrate", Kind::Integer, Capture, "the bitrate of the stream")
+            .bounds(
+                Some(rtmp::MIN_BITRATE as i64),
+                Some(rtmp::MAX_BITRATE as i64),
+                "kbit/s",
+            ),
+        Field::new(
+            "stream_only",
+            Kind::Boolean,
+            Capture,
+            "stream the capture without writing it",
+        ),
+        Field::new(
+            "frame_timestamps",
+            Kind::Boolean,
+            Capture,
+            "write the capture time of every frame to a sidecar",
+        )
+        .unavailable((!available.gzip).then(|| "gzip can't be run".to_string())),
+        Field::new(
+            "intro_countdown",
+            Kind::Boolean,
+            Capture,
+            "start the video with a countdown",
+        ),
+        Field::new(
+            "overlay_text",
+            Kind::String,
+            Capture,
+            "a text drawn over the video, %{localtime} showing the wall clock",
+        ),
+        Field::new(
+            "overlay_position",
+            Kind::Enum,
+            Capture,
+            "where the overlay text is drawn",
+        )
+        .values(variants::<overlays::Position>()),
+        Field::new(
+            "overlay_font_size",
+            Kind::Integer,
+            Capture,
+            "the size of the overlay text",
+        )
+        .bounds(
+            Some(overlays::MIN_FONT_SIZE as i64),
+            Some(overlays::MAX_FONT_SIZE as i64),
+            "pixels",
+        ),
+        Field::new(
+            "on_geometry_change",
+            Kind::Enum,
+            Capture,
+            "what to do when the captured monitor or the resolution changes",
+        )
+        .values(variants::<GeometryPolicy>())
+        .applies_when(screen),
+        Field::new(
+            "segment_seconds",
+            Kind::Integer,
+            Capture,
+            "write the capture in chunks of this length, each compressed on its own",
+        )
+        .bounds(
+            Some(chunks::MIN_SECONDS as i64),
+            Some(chunks::MAX_SECONDS as i64),
+            "seconds",
+        ),
+        Field::new(
+            "durability",
+            Kind::Enum,
+            Capture,
+            "whether the result is on disk before it is reported done",
+        )
+        .values(variants::<Durability>()),
+        Field::new("content", Kind::Enum, Compression, "what is recorded, to tune the encoder for it")
+            .values(variants::<ContentKind>()),
+        Field::new(
+            "encoder_profile",
+            Kind::Enum,
+            Compression,
+            "compress with an encoder of the server rather than for the content",
+        )
+        .values(
+            available
+                .encoder_profiles
+                .iter()
+                .map(|name| Value::from(name.as_str()))
+                .collect(),
+        )
+        .unavailable(
+            available
+                .encoder_profiles
+                .is_empty()
+                .then(|| "no encoder profile is configured".to_string()),
+        ),
+        Field::new(
+            "encoder_options",
+            Kind::Object,
+            Compression,
+            "options of ffmpeg over those of the encoder profile, by name",
+        ),
+        Field::new(
+            "compress",
+            Kind::Boolean,
+            Compression,
+            "compress the capture, unless false: the raw capture is the result then",
+        ),
+        Field::new("crf", Kind::Integer, Compression, "the constant rate factor of the compression")
+            .bounds(Some(0), Some(MAX_CRF as i64), "crf"),
+        Field::new("preset", Kind::Enum, Compression, "the libx264 preset of the compression")
+            .values(PRESETS.iter().map(|preset| Value::from(*preset)).collect()),
+        Field::new(
+            "format",
+            Kind::Enum,
+            Compression,
+            "the container of the result, the capture of an mkv or a webm is an mkv",
+        )
+        .values(variants::<Container>()),
+        Field::new(
+            "encoder",
+            Kind::Enum,
+            Compression,
+            "what encodes the capture and its compression, the CPU by default",
+        )
+        .values(variants::<VideoEncoder>()),
+        Field::new(
+            "keep_original",
+            Kind::Boolean,
+            Compression,
+            "keep the raw capture next to the result",
+        ),
+        Field::new(
+            "normalize_audio",
+            Kind::Boolean,
+            Compression,
+            "bring the audio to -16 LUFS in two passes of loudnorm",
+        ),
+        Field::new(
+            "scale",
+            Kind::String,
+            Compression,
+            "the size of the result, <width>:<height> with -2 keeping the ratio, or 1080p",
+        ),
+        Field::new("slate", Kind::Boolean, Compression, "open the result with a slate naming it"),
+        Field::new(
+            "concat_segments",
+            Kind::Boolean,
+            Compression,
+            "join the chunks of the capture into one result",
+        ),
+        Field::new(
+            "verify_content",
+            Kind::Boolean,
+            Compression,
+            "look for a black or silent result, the server decides when absent",
+        ),
+        Field::new(
+            "extract_frames",
+            Kind::Array,
+            Finishing,
+            "positions of the stills to extract: first, last, seconds or a percentage",
+        ),
+        Field::new(
+            "auto_contact_sheet",
+            Kind::Boolean,
+            Finishing,
+            "make the contact sheet of the result",
+        ),
+        Field::new("transcribe.command", Kind::Enum, Finishing, "the transcriber to run")
+            .values(
+                available
+                    .transcribers
+                    .iter()
+                    .map(|name| Value::from(name.as_str()))
+                    .collect(),
+            )
+            .unavailable(
+                available
+                    .transcribers
+                    .is_empty()
+                    .then(|| "no transcriber is configured".to_string()),
+            ),
+        Field::new(
+            "transcribe.language",
+            Kind::String,
+            Finishing,
+            "the language spoken, the transcriber guesses without it",
+        ),
+        Field::new(
+            "transcribe.mux",
+            Kind::Boolean,
+            Finishing,
+            "also add the subtitles to the result",
+        ),
+        Field::new(
+            "content_addressed",
+            Kind::Boolean,
+            Finishing,
+            "also name the result by its hash",
+        ),
+        Field::new(
+            "hash_link",
+            Kind::Enum,
+            Finishing,
+            "how the readable name of a content addressed result keeps working",
+        )
+        .values(variants::<HashLink>())
+        .applies_when("content_addressed=true"),
+        Field::new(
+            "start_at",
+            Kind::DateTime,
+            Scheduling,
+            "start at this time, to start on several machines at once",
+        )
+        .bounds(
+            Some(0),
+            Some(sync_start::MAX_AHEAD.as_secs() as i64),
+            "seconds from now",
+        ),
+        Field::new("owner", Kind::String, Bookkeeping, "who the recording is for"),
+    ];
+    let defaults = serde_json::to_value(RecordingOptions::default()).unwrap_or_default();
+    for field in &mut fields {
+        field.default = at(&defaults, field.name);
+    }
+    fields.sort_by_key(|f| f.group);
+    for group in Group::ALL {
+        for (order, field) in fields.iter_mut().filter(|f| f.group == *group).enumerate() {
+            field.order = order;
+        }
+    }
+    OptionsSchema {
+        groups: Group::ALL
+            .iter()
+            .map(|&name| GroupInfo {
+                name,
+                title: name.title(),
+            })
+            .collect(),
+        fields,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Rect;
+    use crate::pulse::AudioInput;
+    use crate::transcripts::TranscribeRequest;
+    use chrono::Local;
+    use std::collections::{BTreeMap, BTreeSet};
+
+    fn available() -> Availability {
+        Availability {
+            sound_server: true,
+            gzip: true,
+            transcribers: vec!["whisper".to_string()],
+            encoder_profiles: vec!["archive".to_string()],
+            webrtc_preview: true,
+        }
+    }
+
+    /// options with every field given, without `..Default::default()` for a new field to be
+    /// missed here, and so in the schema, at compile time
+    fn every_option() -> RecordingOptions {
+        RecordingOptions {
+            source: CaptureSource::Url {
+                url: "rtsp://camera/stream".to_string(),
+                input_format: Some("rtsp".to_string()),
+                rtsp_transport: Some(RtspTransport::Udp),
+            },
+            audio: true,
+            audio_resilient: true,
+            audio_source: Some("mic".to_string()),
+            audio_sources: vec![AudioInput {
+                source: "mic".to_string(),
+                volume: Some(0.5),
+            }],
+            content: ContentKind::Screen,
+            durability: Durability::Strict,
+            owner: Some("alice".to_string()),
+            intro_countdown: true,
+            overlay_text: Some("%{localtime}".to_string()),
+            overlay_position: overlays::Position::BottomRight,
+            overlay_font_size: Some(overlays::MIN_FONT_SIZE),
+            extract_frames: vec!["first".to_string()],
+            transcribe: Some(TranscribeRequest {
+                command: "whisper".to_string(),
+                language: Some("en".to_string()),
+                mux: true,
+            }),
+            content_addressed: true,
+            hash_link: HashLink::Hardlink,
+            on_geometry_change: GeometryPolicy::Stop,
+            frame_timestamps: true,
+            region: Some(Rect {
+                width: 640,
+                height: 480,
+                x: 10,
+                y: 20,
+            }),
+            width: Some(1920),
+            height: Some(1080),
+            framerate: Some(30),
+            use_shm: Some(true),
+            cursor_track: true,
+            draw_mouse: Some(false),
+            show_clicks: true,
+            live_preview: true,
+            preview: Some(webrtc_preview::Transport::Webrtc),
+            rtmp_url: Some("rtmp://live/key".to_string()),
+            rtmp_bitrate: Some(rtmp::MIN_BITRATE),
+            stream_only: true,
+            slate: true,
+            start_at: Some(Local::now()),
+            auto_contact_sheet: true,
+            verify_content: Some(true),
+            encoder_profile: Some("archive".to_string()),
+            encoder_options: BTreeMap::from([("tune".to_string(), "film".to_string())]),
+            segment_seconds: Some(chunks::MIN_SECONDS),
+            concat_segments: true,
+            compress: Some(true),
+            crf: Some(MAX_CRF),
+            preset: Some(PRESETS[0].to_string()),
+            keep_original: true,
+            normalize_audio: true,
+            scale: Some("1080p".to_string()),
+            encoder: VideoEncoder::Vaapi,
+            format: Container::Mkv,
+        }
+    }
+
+    /// the dotted paths of `value` that are no field of the schema
+    fn unknown(value: &Value, prefix: &str, names: &BTreeSet<&str>, out: &mut Vec<String>) {
+        let Some(object) = value.as_object() else {
+            out.push(prefix.to_string());
+            return;
+        };
+        for (key, value) in object {
+            let path = match prefix {
+                "" => key.clone(),
+                prefix => format!("{}.{}", prefix, key),
+            };
+            if !names.contains(path.as_str()) {
+                unknown(value, &path, names, out);
+            }
+        }
+    }
+
+    #[test]
+    fn every_option_is_in_the_schema() {
+        let schema = schema(&available());
+        let names: BTreeSet<&str> = schema.fields.iter().map(|f| f.name).collect();
+        assert_eq!(names.len(), schema.fields.len(), "a field is listed twice");
+        let options = serde_json::to_value(every_option()).unwrap();
+        let mut missing = vec![];
+        unknown(&options, "", &names, &mut missing);
+        assert!(missing.is_empty(), "not in the schema: {:?}", missing);
+        let stale: Vec<&str> = names
+            .iter()
+            .copied()
+            .filter(|name| at(&options, name).is_null())
+            .collect();
+        assert!(stale.is_empty(), "not in the options: {:?}", stale);
+    }
+
+    #[test]
+    fn the_values_of_an_enum_are_accepted() {
+        let schema = schema(&available());
+        for field in schema.fields.iter().filter(|f| f.kind == Kind::Enum) {
+            assert!(!field.values.is_empty(), "{} has no values", field.name);
+            for value in &field.values {
+                let mut options = serde_json::to_value(every_option()).unwrap();
+                let (parent, key) = match field.name.rsplit_once('.') {
+                    Some((parent, key)) => (options.pointer_mut(&format!("/{}", parent)), key),
+                    None => (Some(&mut options), field.name),
+                };
+                parent.unwrap()[key] = value.clone();
+                assert!(
+                    serde_json::from_value::<RecordingOptions>(options).is_ok(),
+                    "{}={} is refused",
+                    field.name,
+                    value
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn the_defaults_are_those_of_the_options() {
+        let schema = schema(&available());
+        let field = |name: &str| schema.fields.iter().find(|f| f.name == name).unwrap();
+        assert_eq!(field("audio").default, Value::Bool(false));
+        assert_eq!(
+            field("content").default,
+            serde_json::to_value(ContentKind::default()).unwrap()
+        );
+        assert_eq!(field("source.type").default, Value::from("screen"));
+        // absent when the server decides
+        assert!(field("crf").default.is_null());
+        assert!(field("region.width").default.is_null());
+    }
+
+    #[test]
+    fn the_fields_are_in_order_by_group() {
+        let schema = schema(&available());
+        let groups: Vec<Group> = schema.groups.iter().map(|g| g.name).collect();
+        assert_eq!(groups, Group::ALL);
+        assert!(schema.fields.windows(2).all(|w| w[0].group <= w[1].group));
+        for group in Group::ALL {
+            let orders: Vec<usize> = schema
+                .fields
+                .iter()
+                .filter(|f| f.group == *group)
+                .map(|f| f.order)
+                .collect();
+            assert_eq!(orders, (0..orders.len()).collect::<Vec<_>>(), "{:?}", group);
+        }
+    }
+
+    #[test]
+    fn what_the_server_cannot_do_is_unavailable() {
+        let unavailable = |available: &Availability| -> Vec<&'static str> {
+            schema(available)
+                .fields
+                .into_iter()
+                .filter(|f| f.unavailable.is_some())
+                .map(|f| f.name)
+                .collect()
+        };
+        assert!(unavailable(&available()).is_empty());
+        assert_eq!(
+            unavailable(&Availability::default()),
+            vec![
+                "audio",
+                "audio_resilient",
+                "audio_source",
+                "audio_sources",
+                "preview",
+                "frame_timestamps",
+                "encoder_profile",
+                "transcribe.command",
+            ]
+        );
+    }
+}
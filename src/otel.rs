@@ -0,0 +1,374 @@
+//! Export of the spans of the recordings over OTLP, with the `otel` feature
+//!
+//! The spans are those of the log, see [crate::pipeline]: a recording is a trace, its `recording`
+//! span holding a `stage` span for each stage, and the HTTP requests are traces of their own. A
+//! compression carries the context of its recording in its job, see [context], so that it joins
+//! the trace of its recording even when it runs later or after a restart, see [attach].
+//!
+//! The spans go to the exporter through a bounded queue, written by a thread of its own: a span
+//! that finds the queue full is dropped and counted, see [dropped_spans], and an exporter that
+//! cannot reach its endpoint loses its batch. Neither stalls nor fails a recording.
+#[cfg(feature = "otel")]
+pub use exporter::layer;
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(not(feature = "otel"))]
+use tracing_subscriber::{Layer, Registry};
+
+/// whether the server is built with the export
+pub const AVAILABLE: bool = cfg!(feature = "otel");
+/// spans waiting for the exporter before new ones are dropped
+pub const QUEUED_SPANS: usize = 2048;
+/// spans exported at once
+pub const BATCH: usize = 512;
+
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// spans lost to a full queue
+pub fn dropped_spans() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// the Prometheus exposition of the export
+pub fn metrics() -> String {
+    let name = "record_screen_otel_dropped_spans_total";
+    format!(
+        "# HELP {} Spans the OTLP export lost to a full queue.\n# TYPE {} counter\n{} {}\n",
+        name,
+        name,
+        name,
+        dropped_spans()
+    )
+}
+
+/// the W3C traceparent of the span, None when it is not exported
+#[cfg(feature = "otel")]
+pub fn context(span: &tracing::Span) -> Option<String> {
+    use opentelemetry::propagation::TextMapPropagator;
+    use opentelemetry::trace::TraceContextExt;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    let cx = span.context();
+    if !cx.span().span_context().is_valid() {
+        return None;
+    }
+    let mut carrier = std::collections::HashMap::new();
+    opentelemetry_sdk::propagation::TraceContextPropagator::new().inject_context(&cx, &mut carrier);
+    carrier.remove("traceparent")
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn context(_span: &tracing::Span) -> Option<String> {
+    None
+}
+
+/// make the span a child of the span of the traceparent, before it is entered
+#[cfg(feature = "otel")]
+pub fn attach(span: &tracing::Span, traceparent: Option<&str>) {
+    use opentelemetry::propagation::TextMapPropagator;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    let Some(traceparent) = traceparent else {
+        return;
+    };
+    let carrier =
+        std::collections::HashMap::from([("traceparent".to_string(), traceparent.to_string())]);
+    let cx = opentelemetry_sdk::propagation::TraceContextPropagator::new().extract(&carrier);
+    _ = span.set_parent(cx);
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn attach(_span: &tracing::Span, _traceparent: Option<&str>) {}
+
+/// the layer exporting the spans, without the `otel` feature there is none
+#[cfg(not(feature = "otel"))]
+pub fn layer(_endpoint: &str) -> Result<Box<dyn Layer<Registry> + Send + Sync>, String> {
+    Err("the server is built without the otel feature".to_string())
+}
+
+#[cfg(feature = "otel")]
+mod exporter {
+    use super::{BATCH, DROPPED, QUEUED_SPANS};
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry::Context;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::error::{OTelSdkError, OTelSdkResult};
+    use opentelemetry_sdk::trace::{
+        SdkTracerProvider, Span, SpanData, SpanExporter, SpanProcessor,
+    };
+    use opentelemetry_sdk::Resource;
+    use std::sync::atomic::Ordering;
+    use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+    use std::time::{Duration, Instant};
+    use tracing_subscriber::{Layer, Registry};
+
+    /// a batch is sent at the latest this long after its first span
+    const LINGER: Duration = Duration::from_secs(5);
+
+    /// the layer exporting the spans to the OTLP/HTTP collector at `endpoint`, its base URL
+    pub fn layer(endpoint: &str) -> Result<Box<dyn Layer<Registry> + Send + Sync>, String> {
+        let url = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+        url.parse::<reqwest::Url>().map_err(|e| e.to_string())?;
+        // the blocking client of the exporter is built on the thread, away from the runtime
+        let processor = Bounded::spawn(
+            move || {
+                opentelemetry_otlp::SpanExporter::builder()
+                    .with_http()
+                    .with_endpoint(url)
+                    .build()
+                    .map_err(|e| e.to_string())
+            },
+            QUEUED_SPANS,
+        );
+        Ok(tracing_opentelemetry::layer()
+            .with_tracer(provider(processor).tracer("record-screen"))
+            .boxed())
+    }
+
+    pub(super) fn provider(processor: Bounded) -> SdkTracerProvider {
+        SdkTracerProvider::builder()
+            .with_span_processor(processor)
+            .with_resource(
+                Resource::builder()
+                    .with_service_name("record-screen")
+                    .build(),
+            )
+            .build()
+    }
+
+    enum Queued {
+        Span(Box<SpanData>),
+        /// export what is queued, then answer
+        Flush(SyncSender<()>),
+    }
+
+    /// Hands the spans to the thread of the exporter, dropping them when it is behind
+    #[derive(Debug)]
+    pub(super) struct Bounded {
+        spans: SyncSender<Queued>,
+    }
+
+    impl Bounded {
+        pub(super) fn spawn<E, F>(make: F, capacity: usize) -> Self
+        where
+            E: SpanExporter + 'static,
+            F: FnOnce() -> Result<E, String> + Send + 'static,
+        {
+            let (spans, rx) = sync_channel(capacity);
+            let spawned = std::thread::Builder::new()
+                .name("otel-export".to_string())
+                .spawn(move || match make() {
+                    Ok(exporter) => export(exporter, rx),
+                    Err(e) => eprintln!("cannot export the spans: {}", e),
+                });
+            if let Err(e) = spawned {
+                eprintln!("cannot start exporting the spans: {}", e);
+            }
+            Self { spans }
+        }
+    }
+
+    fn export<E: SpanExporter>(exporter: E, rx: Receiver<Queued>) {
+        let mut batch = vec![];
+        let mut deadline: Option<Instant> = None;
+        loop {
+            let received = match deadline {
+                Some(deadline) => {
+                    rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                }
+                None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            let flushed = match received {
+                Ok(Queued::Span(span)) => {
+                    deadline.get_or_insert_with(|| Instant::now() + LINGER);
+                    batch.push(*span);
+                    if batch.len() < BATCH {
+                        continue;
+                    }
+                    None
+                }
+                Ok(Queued::Flush(done)) => Some(done),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            if !batch.is_empty() {
+                let spans = std::mem::take(&mut batch);
+                if let Err(e) = futures::executor::block_on(exporter.export(spans)) {
+                    eprintln!("cannot export the spans: {}", e);
+                }
+            }
+            deadline = None;
+            if let Some(done) = flushed {
+                _ = done.send(());
+            }
+        }
+        if !batch.is_empty() {
+            _ = futures::executor::block_on(exporter.export(batch));
+        }
+    }
+
+    impl SpanProcessor for Bounded {
+        fn on_start(&self, _span: &mut Span, _cx: &Context) {}
+
+        fn on_end(&self, span: SpanData) {
+            if !span.span_context.is_sampled() {
+                return;
+            }
+            match self.spans.try_send(Queued::Span(Box::new(span))) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                    DROPPED.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+
+        fn force_flush(&self) -> OTelSdkResult {
+            let (done, flushed) = sync_channel(1);
+            self.spans
+                .send(Queued::Flush(done))
+                .map_err(|_| OTelSdkError::AlreadyShutdown)?;
+            flushed
+                .recv_timeout(LINGER)
+                .map_err(|_| OTelSdkError::Timeout(LINGER))
+        }
+
+        fn shutdown_with_timeout(&self, _timeout: Duration) -> OTelSdkResult {
+            self.force_flush()
+        }
+    }
+}
+
+#[cfg(all(test, feature = "otel"))]
+mod tests {
+    use super::exporter::{provider, Bounded};
+    use super::*;
+    use crate::jobs;
+    use crate::pipeline::{Context, Flow, PipelineBuilder, Stage};
+    use crate::service::{Recorder, RecordingOptions};
+    use futures::future::BoxFuture;
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_sdk::trace::{InMemorySpanExporter, SpanData};
+    use std::sync::Arc;
+    use tracing_subscriber::prelude::*;
+
+    struct Named(&'static str);
+
+    impl Stage for Named {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn run<'a>(&'a self, _ctx: &'a mut Context) -> BoxFuture<'a, anyhow::Result<Flow>> {
+            Box::pin(async { Ok(Flow::Continue) })
+        }
+    }
+
+    fn stage_of<'a>(spans: &'a [SpanData], name: &str) -> &'a SpanData {
+        spans
+            .iter()
+            .find(|s| {
+                s.name == "stage"
+                    && s.attributes
+                        .iter()
+                        .any(|kv| kv.key.as_str() == "name" && kv.value.as_str() == name)
+            })
+            .unwrap_or_else(|| panic!("no {} stage in {:#?}", name, spans))
+    }
+
+    #[tokio::test]
+    async fn a_deferred_compression_joins_the_trace_of_its_recording() {
+        let exporter = InMemorySpanExporter::default();
+        let provider = provider(Bounded::spawn(
+            {
+                let exporter = exporter.clone();
+                move || Ok(exporter)
+            },
+            QUEUED_SPANS,
+        ));
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let pipeline = PipelineBuilder::empty()
+            .start_stage(Named("resolve"))
+            .start_stage(Named("capture"))
+            .then(Named("compress"))
+            .then(Named("upload"))
+            .build();
+        let mx = Arc::new(Recorder::new().with_pipeline(pipeline));
+        let claim = mx.claim_start().unwrap();
+        let flow = mx
+            .pipeline
+            .start(claim, RecordingOptions::default(), None)
+            .await
+            .unwrap();
+        assert_eq!(flow, Flow::Continue);
+        // the job as the stop writes it, read back as after a restart
+        let job = serde_json::json!({
+            "input": "capture.mp4",
+            "output": "result.mp4",
+            "options": RecordingOptions::default(),
+            "started_at": chrono::Local::now(),
+            "trace": mx.trace.lock().unwrap().take(),
+        });
+        let job: jobs::Compression = serde_json::from_value(job).unwrap();
+        assert!(job.trace.is_some());
+        let flow = mx.pipeline.finish(mx.clone(), 1, job, None).await.unwrap();
+        assert_eq!(flow, Flow::Continue);
+
+        provider.force_flush().unwrap();
+        let spans = exporter.get_finished_spans().unwrap();
+        let recordings: Vec<_> = spans.iter().filter(|s| s.name == "recording").collect();
+        assert_eq!(recordings.len(), 2, "{:#?}", spans);
+        let (start, finish) = match recordings[0]
+            .attributes
+            .iter()
+            .any(|kv| kv.key.as_str() == "job")
+        {
+            true => (recordings[1], recordings[0]),
+            false => (recordings[0], recordings[1]),
+        };
+        let trace = start.span_context.trace_id();
+        assert_eq!(finish.span_context.trace_id(), trace);
+        assert_eq!(finish.parent_span_id, start.span_context.span_id());
+        for (stage, parent) in [
+            ("resolve", start),
+            ("capture", start),
+            ("compress", finish),
+            ("upload", finish),
+        ] {
+            let stage = stage_of(&spans, stage);
+            assert_eq!(stage.span_context.trace_id(), trace);
+            assert_eq!(stage.parent_span_id, parent.span_context.span_id());
+        }
+    }
+
+    #[test]
+    fn a_span_the_exporter_cannot_take_is_dropped_and_counted() {
+        let processor = Bounded::spawn(
+            || Err::<InMemorySpanExporter, _>("unreachable".to_string()),
+            1,
+        );
+        let provider = provider(processor);
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let before = dropped_spans();
+        tracing::subscriber::with_default(subscriber, || {
+            // the thread gave up, the queue is closed
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            for _ in 0..3 {
+                tracing::info_span!("stage").in_scope(|| {});
+            }
+        });
+        assert!(dropped_spans() >= before + 3);
+    }
+
+    #[test]
+    fn a_span_without_the_export_has_no_context() {
+        let span = tracing::info_span!("recording");
+        assert_eq!(context(&span), None);
+        attach(
+            &span,
+            Some("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"),
+        );
+    }
+}
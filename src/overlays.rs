@@ -0,0 +1,318 @@
+//! Overlays drawn into the capture by ffmpeg itself
+//!
+//! Every overlay is a filter of a single `-vf` chain over the live capture, so the timestamps
+//! stay continuous and `t` is the time since the capture began. Filters drawn later end up on
+//! top: the chain is always built in the [Layer] order, whatever order the overlays were asked
+//! for in, so that combinations render the same way every time.
+//!
+//! `overlay_text` is drawn in a corner or at the middle of an edge, `overlay_position`, for all
+//! of the recording. It is the text of `drawtext` with its expansions, `%{localtime}` showing the
+//! wall clock; `\%` is a percent sign. The format of `%{localtime:...}` and `%{gmtime:...}` may
+//! hold colons as they are, `%{localtime:%H:%M:%S}`, see [expansion_text].
+use crate::ffmpeg::escape_drawtext_expansions;
+use crate::service::RecordingOptions;
+use serde::{Deserialize, Serialize};
+
+/// how long the intro countdown runs, in seconds
+pub const COUNTDOWN: u32 = 3;
+/// how long the recording border is shown, in seconds
+pub const BORDER: u32 = 5;
+/// the size of the overlay text when none is asked for, in pixels
+pub const FONT_SIZE: u32 = 24;
+/// the smallest and the largest overlay text
+pub const MIN_FONT_SIZE: u32 = 8;
+pub const MAX_FONT_SIZE: u32 = 400;
+/// how far the overlay text is from the edges, in pixels
+const MARGIN: u32 = 10;
+
+/// Where the overlay text is drawn
+#[derive(
+    Default,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+    clap::ValueEnum,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Position {
+    #[default]
+    TopLeft,
+    TopCenter,
+    TopRight,
+    BottomLeft,
+    BottomCenter,
+    BottomRight,
+}
+
+impl Position {
+    /// the `x` and `y` of drawtext
+    fn coordinates(self) -> String {
+        let m = MARGIN;
+        let x = match self {
+            Self::TopLeft | Self::BottomLeft => m.to_string(),
+            Self::TopCenter | Self::BottomCenter => "(w-text_w)/2".to_string(),
+            Self::TopRight | Self::BottomRight => format!("w-text_w-{}", m),
+        };
+        let y = match self {
+            Self::TopLeft | Self::TopCenter | Self::TopRight => m.to_string(),
+            _ => format!("h-text_h-{}", m),
+        };
+        format!("x={}:y={}", x, y)
+    }
+}
+
+/// An overlay, in stacking order from the bottom
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Layer {
+    /// a text of the options, its expansions expanded on every frame
+    Text {
+        text: String,
+        position: Position,
+        font_size: u32,
+    },
+    /// a red frame around the picture for the first seconds
+    Border { seconds: u32 },
+    /// big seconds left, counting down to zero
+    Countdown { seconds: u32 },
+}
+
+impl Layer {
+    /// its filter, or what is wrong with its text
+    fn filter(&self) -> Result<String, String> {
+        Ok(match self {
+            Layer::Text {
+                text,
+                position,
+                font_size,
+            } => format!(
+                "drawtext=text={}:expansion=normal:fontsize={}:fontcolor=white\
+                 :box=1:boxcolor=black@0.5:boxborderw=8:{}",
+                escape_drawtext_expansions(&expansion_text(text)?),
+                font_size,
+                position.coordinates()
+            ),
+            Layer::Border { seconds } => format!(
+                "drawbox=x=0:y=0:w=iw:h=ih:color=red@0.8:t=8:enable='lt(t,{})'",
+                seconds
+            ),
+            Layer::Countdown { seconds } => format!(
+                "drawtext=text='%{{eif\\:{}-floor(t)\\:d}}':fontsize=h/6:fontcolor=white\
+                 :box=1:boxcolor=black@0.5:boxborderw=20\
+                 :x=(w-text_w)/2:y=(h-text_h)/2:enable='lt(t,{})'",
+                seconds, seconds
+            ),
+        })
+    }
+}
+
+/// overlays the options ask for
+pub fn layers(opt: &RecordingOptions) -> Vec<Layer> {
+    let mut layers = vec![];
+    if let Some(text) = &opt.overlay_text {
+        layers.push(Layer::Text {
+            text: text.clone(),
+            position: opt.overlay_position,
+            font_size: opt.overlay_font_size.unwrap_or(FONT_SIZE),
+        });
+    }
+    if opt.intro_countdown {
+        layers.push(Layer::Countdown { seconds: COUNTDOWN });
+        layers.push(Layer::Border { seconds: BORDER });
+    }
+    layers
+}
+
+/// the `-vf` chain drawing the layers, none without layers, or what is wrong with one of them
+pub fn filter_chain(layers: &[Layer]) -> Result<Option<String>, String> {
+    if layers.is_empty() {
+        return Ok(None);
+    }
+    let mut layers = layers.to_vec();
+    layers.sort();
+    let filters = layers
+        .iter()
+        .map(Layer::filter)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Some(filters.join(",")))
+}
+
+/// the text as drawtext expands it, with the colons of the format of a `%{localtime}` or a
+/// `%{gmtime}` escaped, which would end its argument otherwise; or what is wrong with it
+pub fn expansion_text(text: &str) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                expanded.push(c);
+                expanded.extend(chars.next());
+            }
+            '%' => {
+                if chars.next() != Some('{') {
+                    return Err("a % starts an expansion, \\% is a percent sign".into());
+                }
+                let mut inner = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => inner.push(c),
+                        None => return Err(format!("%{{{} is not closed", inner)),
+                    }
+                }
+                expanded.push_str("%{");
+                match inner.split_once(':') {
+                    Some((name @ ("localtime" | "gmtime"), format)) => {
+                        expanded.push_str(name);
+                        expanded.push(':');
+                        expanded.push_str(&escape_colons(format));
+                    }
+                    _ => expanded.push_str(&inner),
+                }
+                expanded.push('}');
+            }
+            c => expanded.push(c),
+        }
+    }
+    Ok(expanded)
+}
+
+/// `:` escaped with a backslash, unless it is already
+fn escape_colons(format: &str) -> String {
+    let mut escaped = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                escaped.push(c);
+                escaped.extend(chars.next());
+            }
+            ':' => escaped.push_str("\\:"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// whether the overlays the options ask for can be drawn, the field that can't otherwise
+pub fn validate(opt: &RecordingOptions) -> Result<(), (&'static str, String)> {
+    let Some(text) = &opt.overlay_text else {
+        if opt.overlay_font_size.is_some() {
+            return Err((
+                "overlay_font_size",
+                "given without overlay_text".to_string(),
+            ));
+        }
+        return Ok(());
+    };
+    if text.trim().is_empty() {
+        return Err(("overlay_text", "must not be empty".to_string()));
+    }
+    expansion_text(text).map_err(|e| ("overlay_text", e))?;
+    if let Some(size) = opt.overlay_font_size {
+        if !(MIN_FONT_SIZE..=MAX_FONT_SIZE).contains(&size) {
+            let range = format!("{}..={}", MIN_FONT_SIZE, MAX_FONT_SIZE);
+            return Err(("overlay_font_size", format!("must be within {}", range)));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_colons_of_a_time_format_are_escaped() {
+        assert_eq!(
+            expansion_text("%{localtime:%H:%M:%S}").unwrap(),
+            "%{localtime:%H\\:%M\\:%S}"
+        );
+        assert_eq!(
+            expansion_text("UTC %{gmtime:%Y-%m-%d %H:%M}").unwrap(),
+            "UTC %{gmtime:%Y-%m-%d %H\\:%M}"
+        );
+        assert_eq!(expansion_text("%{localtime}").unwrap(), "%{localtime}");
+        // the arguments of other expansions are theirs
+        assert_eq!(expansion_text("%{pts:hms}").unwrap(), "%{pts:hms}");
+    }
+
+    #[test]
+    fn an_escaped_colon_stays_as_it_is() {
+        assert_eq!(escape_colons("%H\\:%M:%S"), "%H\\:%M\\:%S");
+        assert_eq!(
+            expansion_text("%{localtime:%H\\:%M}").unwrap(),
+            "%{localtime:%H\\:%M}"
+        );
+    }
+
+    #[test]
+    fn plain_text_and_escapes_go_as_they_are() {
+        assert_eq!(expansion_text("rec 12:30").unwrap(), "rec 12:30");
+        assert_eq!(expansion_text("100\\%").unwrap(), "100\\%");
+    }
+
+    #[test]
+    fn a_bare_percent_is_refused() {
+        assert!(expansion_text("100%").is_err());
+        assert!(expansion_text("100% done").is_err());
+    }
+
+    #[test]
+    fn an_unclosed_expansion_is_refused() {
+        assert_eq!(
+            expansion_text("%{localtime:%H").unwrap_err(),
+            "%{localtime:%H is not closed"
+        );
+        assert!(expansion_text("%{").is_err());
+    }
+
+    fn text(text: &str) -> Layer {
+        Layer::Text {
+            text: text.to_string(),
+            position: Position::BottomRight,
+            font_size: FONT_SIZE,
+        }
+    }
+
+    #[test]
+    fn the_text_is_escaped_for_the_filter() {
+        let filter = text("it's %{localtime:%H:%M}\r\n[rec]").filter().unwrap();
+        assert_eq!(
+            filter,
+            "drawtext=text=it\\\\\\'s %{localtime\\\\:%H\\\\\\\\\\\\:%M}\n\\[rec\\]\
+             :expansion=normal:fontsize=24:fontcolor=white:box=1:boxcolor=black@0.5\
+             :boxborderw=8:x=w-text_w-10:y=h-text_h-10"
+        );
+    }
+
+    #[test]
+    fn a_text_that_is_wrong_fails_the_chain() {
+        assert!(filter_chain(&[text("100%")]).is_err());
+        assert_eq!(filter_chain(&[]).unwrap(), None);
+    }
+
+    #[test]
+    fn the_layers_are_chained_from_the_bottom() {
+        let chain = filter_chain(&[
+            Layer::Countdown { seconds: 3 },
+            Layer::Border { seconds: 5 },
+            text("x"),
+        ])
+        .unwrap()
+        .unwrap();
+        let filters: Vec<_> = chain
+            .split(",drawbox")
+            .flat_map(|part| part.split(",drawtext"))
+            .collect();
+        assert_eq!(filters.len(), 3);
+        assert!(filters[0].starts_with("drawtext=text=x:"));
+        assert!(filters[1].starts_with("=x=0:y=0"));
+        assert!(filters[2].starts_with("=text='%{eif"));
+    }
+}
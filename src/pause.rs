@@ -0,0 +1,222 @@
+//! Pausing a screen recording
+//!
+//! A pause ends the segment being captured: [pause] moves the recording to Paused, keeping its
+//! Started state there, and interrupts the capture, which finishes its file. The capture stage
+//! waits for [resume] before it is Started again in the next segment, as for a quality change. The timeline has a gap for the pause, so
+//! the joined result is without it rather than frozen on its last frame, and the markers and the
+//! trim count what was recorded. A stop while paused stops the recording as it is.
+//!
+//! The resilient audio records on through a pause, and the frame timestamps can't span a
+//! rollover: recordings with either can't be paused.
+use crate::service::{since, Recorder, RecordingState};
+use chrono::Local;
+use std::time::Duration;
+use thiserror::Error;
+use tracing::*;
+
+/// how often a paused capture looks for the resume
+const POLL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("not started")]
+    NotStarted,
+    #[error("the recording is paused already")]
+    Paused,
+    #[error("the recording is not paused")]
+    NotPaused,
+    #[error("the capture is being resumed already")]
+    Resuming,
+    #[error("the resilient audio can't be paused")]
+    ResilientAudio,
+    #[error("frame timestamps can't span a pause")]
+    FrameTimestamps,
+    #[error("the capture is rolling over to a new segment")]
+    RollingOver,
+}
+
+/// pause the running capture, its segment ends in the background
+pub async fn pause(mx: &Recorder) -> Result<RecordingState, Error> {
+    let mut state = mx.lock().await;
+    let RecordingState::Started {
+        process_id,
+        file,
+        audio,
+        options,
+        started_at,
+        markers,
+        timeline,
+        quality,
+        failover,
+        ..
+    } = &mut *state
+    else {
+        return Err(match &*state {
+            RecordingState::Paused { .. } => Error::Paused,
+            _ => Error::NotStarted,
+        });
+    };
+    if audio.is_some() {
+        return Err(Error::ResilientAudio);
+    }
+    if options.frame_timestamps {
+        return Err(Error::FrameTimestamps);
+    }
+    let pending_quality = quality.as_ref().is_some_and(|q| q.pending.is_some());
+    let failing_over = failover.as_ref().is_some_and(|f| f.pending);
+    if pending_quality || failing_over {
+        return Err(Error::RollingOver);
+    }
+    let at_ms = since(*started_at);
+    markers.push(timeline.marker(at_ms, "paused"));
+    let (pid, file, elapsed_ms) = (*process_id, file.clone(), timeline.content_at(at_ms));
+    let paused = RecordingState::Paused {
+        process_id: pid,
+        file,
+        at: Local::now(),
+        elapsed_ms,
+        resumed: false,
+        recording: Box::new(state.clone()),
+    };
+    mx.replace(&mut state, paused.clone());
+    // the capture finishes its file, the capture stage waits for the resume
+    let pid = nix::unistd::Pid::from_raw(pid as i32);
+    if let Err(e) = nix::sys::signal::kill(pid, nix::sys::signal::Signal::SIGINT) {
+        warn!("cannot interrupt the capture {}: {}", pid, e);
+    }
+    info!("capture paused");
+    Ok(paused)
+}
+
+/// resume the paused capture, its next segment starts in the background
+pub async fn resume(mx: &Recorder) -> Result<RecordingState, Error> {
+    let mut state = mx.lock().await;
+    let RecordingState::Paused { resumed, .. } = &mut *state else {
+        return Err(match &*state {
+            RecordingState::Started { .. } => Error::NotPaused,
+            _ => Error::NotStarted,
+        });
+    };
+    if *resumed {
+        return Err(Error::Resuming);
+    }
+    *resumed = true;
+    let updated = state.clone();
+    mx.replace(&mut state, updated.clone());
+    info!("capture resumed");
+    Ok(updated)
+}
+
+/// wait for the resume of a capture that exited for a pause, false when it didn't or when the
+/// recording was stopped meanwhile
+pub(crate) async fn resumed(mx: &Recorder) -> bool {
+    loop {
+        match &*mx.lock().await {
+            RecordingState::Paused { resumed: true, .. } => return true,
+            RecordingState::Paused { .. } => {}
+            _ => return false,
+        }
+        tokio::time::sleep(POLL).await;
+    }
+}
+
+/// the recording Started again once its next segment is about to start, false when it was not
+/// resumed
+pub(crate) fn restart(state: &mut RecordingState) -> bool {
+    let RecordingState::Paused {
+        resumed: true,
+        recording,
+        ..
+    } = state
+    else {
+        return false;
+    };
+    *state = std::mem::take(&mut **recording);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::stop;
+    use std::sync::Arc;
+
+    /// a Started recording of a capture that only waits to be interrupted
+    async fn started(mx: &Recorder) -> tokio::process::Child {
+        let capture = tokio::process::Command::new("sleep")
+            .arg("30")
+            .spawn()
+            .unwrap();
+        let state = serde_json::from_value(serde_json::json!({
+            "type": "Started",
+            "process_id": capture.id().unwrap(),
+            "file": "paused.mkv",
+            "started_at": Local::now(),
+            // stopped without a compression
+            "options": { "stream_only": true },
+        }))
+        .unwrap();
+        mx.set(state).await;
+        capture
+    }
+
+    #[tokio::test]
+    async fn a_paused_recording_is_resumed_then_stopped() {
+        let mx = Arc::new(Recorder::new());
+        let mut capture = started(&mx).await;
+        let paused = pause(&mx).await.unwrap();
+        let RecordingState::Paused {
+            file,
+            resumed: false,
+            recording,
+            ..
+        } = &paused
+        else {
+            panic!("not paused: {}", paused.name());
+        };
+        assert_eq!(file, "paused.mkv");
+        let RecordingState::Started { markers, .. } = &**recording else {
+            panic!("paused a {}", recording.name());
+        };
+        assert_eq!(markers.last().map(|m| m.label.as_str()), Some("paused"));
+        // the capture was told to finish its file
+        assert!(!capture.wait().await.unwrap().success());
+        assert_eq!(mx.lock().await.name(), "Paused");
+        assert!(matches!(pause(&mx).await, Err(Error::Paused)));
+
+        let resuming = resume(&mx).await.unwrap();
+        assert!(matches!(
+            resuming,
+            RecordingState::Paused { resumed: true, .. }
+        ));
+        assert!(matches!(resume(&mx).await, Err(Error::Resuming)));
+        assert!(resumed(&mx).await);
+        // the capture stage starts the next segment
+        assert!(restart(&mut *mx.lock().await));
+        assert_eq!(mx.lock().await.name(), "Started");
+        assert!(matches!(resume(&mx).await, Err(Error::NotPaused)));
+
+        stop(mx.clone()).await.unwrap();
+        assert!(matches!(*mx.lock().await, RecordingState::Waiting));
+    }
+
+    #[tokio::test]
+    async fn a_paused_recording_is_stopped_as_it_is() {
+        let mx = Arc::new(Recorder::new());
+        let mut capture = started(&mx).await;
+        pause(&mx).await.unwrap();
+        capture.wait().await.unwrap();
+        stop(mx.clone()).await.unwrap();
+        assert!(matches!(*mx.lock().await, RecordingState::Waiting));
+        // the capture stage waiting for the resume gives up
+        assert!(!resumed(&mx).await);
+        assert!(!restart(&mut *mx.lock().await));
+    }
+
+    #[tokio::test]
+    async fn only_a_started_recording_is_paused() {
+        let mx = Recorder::new();
+        assert!(matches!(pause(&mx).await, Err(Error::NotStarted)));
+        assert!(matches!(resume(&mx).await, Err(Error::NotStarted)));
+    }
+}
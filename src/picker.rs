@@ -0,0 +1,79 @@
+//! Choosing what the CLI records, before the recording starts
+//!
+//! A region is dragged out on the desktop with `slop`, or a whole monitor is taken from the
+//! layout xrandr reports, by name or by its number in the printed list. The countdown is shown in
+//! the terminal, so it is not part of the video the way `intro_countdown` is.
+use crate::display;
+use crate::geometry::{self, Rect};
+use anyhow::{bail, Context};
+use std::io::Write;
+use std::time::Duration;
+
+/// the region dragged out with slop, None when the selection was cancelled
+pub async fn select_region() -> anyhow::Result<Option<Rect>> {
+    let output = tokio::process::Command::new("slop")
+        .args(["-f", "%wx%h+%x+%y"])
+        .env("DISPLAY", display::name())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .context("cannot run slop, is it installed?")?;
+    // slop exits with an error when the selection is cancelled with a key
+    if !output.status.success() {
+        return Ok(None);
+    }
+    let selection = String::from_utf8_lossy(&output.stdout);
+    match Rect::parse(selection.trim()) {
+        // the encoder needs an even size
+        Some(region) => Ok(Some(region.even())),
+        None => bail!("unexpected selection from slop: {}", selection.trim()),
+    }
+}
+
+/// the monitor of `choice`, a name or a number of the printed list, asked for when it's empty
+pub async fn pick_monitor(choice: &str) -> anyhow::Result<(String, Rect)> {
+    let monitors = geometry::layout().await?.monitors;
+    if monitors.is_empty() {
+        bail!("no connected monitor");
+    }
+    for (n, (name, rect)) in monitors.iter().enumerate() {
+        println!("{:>3}) {} {}", n + 1, name, rect);
+    }
+    let choice = match choice {
+        "" => ask("monitor to record: ").await?,
+        choice => choice.to_string(),
+    };
+    let choice = choice.trim();
+    let picked = match choice.parse::<usize>() {
+        Ok(n) => n.checked_sub(1).and_then(|i| monitors.get(i)),
+        Err(_) => monitors.iter().find(|(name, _)| name == choice),
+    };
+    match picked {
+        Some(monitor) => Ok(monitor.clone()),
+        None => bail!("no monitor {}", choice),
+    }
+}
+
+/// a line from the terminal
+async fn ask(prompt: &str) -> anyhow::Result<String> {
+    print!("{}", prompt);
+    std::io::stdout().flush()?;
+    let line = tokio::task::spawn_blocking(|| {
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line).map(|_| line)
+    })
+    .await??;
+    Ok(line)
+}
+
+/// counts the seconds down in the terminal
+pub async fn countdown(seconds: u32) {
+    for left in (1..=seconds).rev() {
+        print!("\rrecording in {}... ", left);
+        _ = std::io::stdout().flush();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    if seconds > 0 {
+        println!("\rrecording      ");
+    }
+}
@@ -0,0 +1,2450 @@
+//! The recording as a chain of stages
+//!
+//! A recording runs two chains of [Stage]s over one [Context]. The start chain checks that a
+//! recording may start, resolves what it records to, and captures until the capture exits. Once
+//! it was stopped, the finish chain turns the raw capture into the result: it joins its segments,
+//! compresses it, makes it durable, looks for a black or silent content, extracts its frames,
+//! uploads it to the bucket when there is one, reports it done, transcribes it, starts its
+//! contact sheet, writes its checksums and removes what is no longer needed. [PipelineBuilder::standard] is what the server runs; library users
+//! swap, remove or add stages by their name and give the result to [Recorder::with_pipeline].
+//!
+//! Both chains run in a `recording` span of the log, its id from the start of the capture, see
+//! [recording_id], and every stage in a `stage` span in it: their durations are logged as they
+//! close. A compression restored after a restart gets the id of its recording again, and joins its
+//! trace when the spans are exported, see [crate::otel].
+//!
+//! The rules every stage follows:
+//! - the stages run one after the other, each seeing what the previous ones left in the context;
+//! - an error ends the chain: a start fails with it, a compression job is marked failed with it;
+//! - [Flow::Cancelled] ends the chain quietly, for a recording cancelled meanwhile, and the job is
+//!   marked cancelled;
+//! - a stage moving the state on releases [Context::claim] once it did, or the claim is released
+//!   when the chain ends;
+//! - [Context::file] is the file the next stage works on: the raw capture at first, the
+//!   compressed output once [Compress] ran. Without [Compress] the raw capture is the result.
+//!
+//! ```no_run
+//! use futures::future::BoxFuture;
+//! use record_screen::pipeline::{self, Context, Flow, PipelineBuilder, Stage};
+//! use record_screen::service::Recorder;
+//!
+//! /// hands the result to another program
+//! struct Upload;
+//!
+//! impl Stage for Upload {
+//!     fn name(&self) -> &'static str {
+//!         "upload"
+//!     }
+//!
+//!     fn run<'a>(&'a self, ctx: &'a mut Context) -> BoxFuture<'a, anyhow::Result<Flow>> {
+//!         Box::pin(async move {
+//!             let status = tokio::process::Command::new("upload-recording")
+//!                 .arg(&ctx.file)
+//!                 .status()
+//!                 .await?;
+//!             anyhow::ensure!(status.success(), "upload exited with {}", status);
+//!             Ok(Flow::Continue)
+//!         })
+//!     }
+//! }
+//!
+//! let pipeline = PipelineBuilder::standard()
+//!     // the raw capture is uploaded as it is
+//!     .without(pipeline::COMPRESS)
+//!     .after(pipeline::FINALIZE, Upload)
+//!     .build();
+//! let recorder = Recorder::new().with_pipeline(pipeline);
+//! ```
+use crate::audio::{self, AudioStatus};
+use crate::capture_paths;
+use crate::checksums;
+use crate::chunks::{self, Chunks};
+use crate::contact_sheet;
+use crate::container;
+use crate::content_check::{self, ContentWarning};
+use crate::cursor;
+use crate::display;
+use crate::events::EventKind;
+use crate::failover::{self, FailoverStatus};
+use crate::ffmpeg::*;
+use crate::frames;
+use crate::geometry;
+use crate::gpu;
+use crate::history::HistoryEntry;
+use crate::hwaccel::{self, VideoEncoder};
+use crate::jobs::{self, JobState};
+use crate::latency;
+use crate::loudness::{self, Loudness};
+use crate::otel;
+use crate::overlays;
+use crate::pause;
+use crate::presence::Identity;
+use crate::preview;
+use crate::probe;
+use crate::pulse;
+use crate::quality;
+use crate::quota;
+use crate::recordings;
+use crate::rtmp;
+use crate::scale;
+use crate::service::*;
+use crate::slate;
+use crate::source::{self, CaptureSource};
+use crate::sync_start::{self, StartSync};
+use crate::template;
+use crate::throttle::Category;
+use crate::timestamps;
+use crate::transcripts;
+use crate::trim;
+use crate::webrtc_preview;
+use anyhow::{anyhow, bail};
+use chrono::{DateTime, Local};
+use futures::future::BoxFuture;
+use std::process::Stdio;
+use std::sync::Arc;
+use tracing::*;
+
+pub const PREFLIGHT: &str = "preflight";
+pub const RESOLVE: &str = "resolve";
+pub const CAPTURE: &str = "capture";
+pub const GATHER: &str = "gather";
+pub const JOIN: &str = "join";
+pub const COMPRESS: &str = "compress";
+pub const SYNC: &str = "sync";
+pub const VERIFY_CONTENT: &str = "verify_content";
+pub const FRAMES: &str = "frames";
+pub const UPLOAD: &str = "upload";
+pub const FINALIZE: &str = "finalize";
+pub const TRANSCRIBE: &str = "transcribe";
+pub const CONTACT_SHEET: &str = "contact_sheet";
+pub const CHECKSUMS: &str = "checksums";
+pub const CLEANUP: &str = "cleanup";
+pub const REMOVE_UPLOADED: &str = "remove_uploaded";
+
+/// how often the bytes sent of an upload are published
+const UPLOAD_PROGRESS: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// How the chain goes on after a stage
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Flow {
+    Continue,
+    /// the recording was cancelled meanwhile, the remaining stages are skipped
+    Cancelled,
+}
+
+/// A step of a recording
+pub trait Stage: Send + Sync {
+    /// the name the builder finds it by
+    fn name(&self) -> &'static str;
+
+    fn run<'a>(&'a self, ctx: &'a mut Context) -> BoxFuture<'a, anyhow::Result<Flow>>;
+}
+
+/// What the stages of a recording share
+pub struct Context {
+    /// the state, the child processes and the events
+    pub mx: Arc<Recorder>,
+    pub options: RecordingOptions,
+    /// the client that asked for the recording
+    pub by: Option<Identity>,
+    /// held until the recording took over the state, see [StartClaim]
+    pub claim: Option<StartClaim>,
+    /// the stream is recorded as it came, without decoding it
+    pub copy: bool,
+    /// what the capture encodes with, None when it copies or can't be changed
+    pub quality: Option<quality::CaptureQuality>,
+    /// the file the next stage works on
+    pub file: String,
+    /// the compression of the stopped recording and its job id, in the finish chain
+    pub job: Option<(u64, jobs::Compression)>,
+    /// program and arguments of every ffmpeg run, in order
+    pub commands: Vec<Vec<String>>,
+    /// the result was fsynced
+    pub durable: bool,
+    pub frames: Vec<frames::Frame>,
+    /// what the content of the result suggests went wrong
+    pub content_warnings: Vec<ContentWarning>,
+    /// sees the write errors of the captures, when there is a fallback directory
+    pub write_errors: Option<failover::Detector>,
+    /// when a synchronized start was asked for and when the capture was spawned
+    pub start_sync: Option<StartSync>,
+    /// the span of the recording in the log, the stages are spans in it
+    pub span: tracing::Span,
+    /// a stage switched the state to Failed, see [Context::fail]
+    pub failed: bool,
+    /// the key the result was uploaded as, that of its first chunk
+    pub uploaded: Option<String>,
+    /// every result of a recording in chunks, [Context::file] is the first, see [chunks]
+    pub chunks: Vec<String>,
+    /// the directory of the live preview of the capture, see [preview]
+    pub preview: Option<String>,
+    /// the port the capture sends its WebRTC preview to, see [webrtc_preview]
+    pub rtp_port: Option<u16>,
+    /// the URL the capture is streamed to, the options have it redacted, see [rtmp]
+    pub stream_url: Option<String>,
+    /// the loudness the audio of the result was brought to, see [loudness]
+    pub loudness: Option<Loudness>,
+}
+
+impl Context {
+    fn new(mx: Arc<Recorder>, options: RecordingOptions) -> Self {
+        Self {
+            mx,
+            options,
+            by: None,
+            claim: None,
+            copy: false,
+            quality: None,
+            file: String::new(),
+            job: None,
+            commands: vec![],
+            durable: false,
+            frames: vec![],
+            content_warnings: vec![],
+            write_errors: None,
+            start_sync: None,
+            span: tracing::Span::none(),
+            failed: false,
+            uploaded: None,
+            chunks: vec![],
+            preview: None,
+            rtp_port: None,
+            stream_url: None,
+            loudness: None,
+        }
+    }
+
+    /// the result was uploaded and is removed at the end of the chain, see [RemoveUploaded]
+    pub fn removes_upload(&self) -> bool {
+        let delete = self
+            .mx
+            .bucket
+            .as_ref()
+            .map(|b| b.config.delete_after_upload);
+        self.uploaded.is_some() && delete.unwrap_or_default()
+    }
+
+    /// the files of the result: its chunks, or [Context::file]
+    pub fn results(&self) -> Vec<String> {
+        match self.chunks.is_empty() {
+            true => vec![self.file.clone()],
+            false => self.chunks.clone(),
+        }
+    }
+
+    /// switch to Failed, so that the start chain doesn't report the error again
+    pub async fn fail(&mut self, reason: FailureReason, message: String) {
+        fail(&self.mx, &self.options, reason, message, vec![]).await;
+        self.failed = true;
+    }
+
+    /// [Self::fail] with the stderr of the ffmpeg that caused `e`, when it exited at once
+    pub async fn fail_on(&mut self, reason: FailureReason, message: String, e: &anyhow::Error) {
+        let stderr_tail = e
+            .chain()
+            .find_map(|e| e.downcast_ref::<crate::runner::Error>())
+            .map(|e| e.stderr_tail().to_vec())
+            .unwrap_or_default();
+        fail(&self.mx, &self.options, reason, message, stderr_tail).await;
+        self.failed = true;
+    }
+
+    /// the compression of the finish chain
+    pub fn job(&self) -> anyhow::Result<&(u64, jobs::Compression)> {
+        match &self.job {
+            Some(job) => Ok(job),
+            None => bail!("no compression job in the start chain"),
+        }
+    }
+}
+
+/// The stages of a recording, see [PipelineBuilder]
+pub struct Pipeline {
+    start: Vec<Box<dyn Stage>>,
+    finish: Vec<Box<dyn Stage>>,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        PipelineBuilder::standard().build()
+    }
+}
+
+impl Pipeline {
+    /// the names of the start and the finish stages, in order
+    pub fn stages(&self) -> (Vec<&'static str>, Vec<&'static str>) {
+        let names = |stages: &[Box<dyn Stage>]| stages.iter().map(|s| s.name()).collect();
+        (names(&self.start), names(&self.finish))
+    }
+
+    /// run the start chain, until the capture exits
+    pub async fn start(
+        &self,
+        claim: StartClaim,
+        options: RecordingOptions,
+        by: Option<Identity>,
+    ) -> anyhow::Result<Flow> {
+        let mut ctx = Context::new(claim.recorder(), options);
+        ctx.stream_url = rtmp::conceal(&mut ctx.options);
+        ctx.by = by;
+        ctx.claim = Some(claim);
+        // the id is known once the capture started
+        ctx.span = info_span!("recording", id = field::Empty);
+        let span = ctx.span.clone();
+        // a start over another recording leaves its state alone
+        let idle = matches!(
+            *ctx.mx.lock().await,
+            RecordingState::Waiting
+                | RecordingState::Done { .. }
+                | RecordingState::Failed { .. }
+                | RecordingState::Cancelled { .. }
+        );
+        if idle {
+            *ctx.mx.trace.lock().unwrap() = otel::context(&span);
+        }
+        let result = run(&self.start, &mut ctx).instrument(span).await;
+        if let Err(e) = &result {
+            if idle && !ctx.failed {
+                ctx.fail_on(FailureReason::StartFailed, e.to_string(), e)
+                    .await;
+            }
+        }
+        result
+    }
+
+    /// run the finish chain over a stopped recording
+    pub async fn finish(
+        &self,
+        mx: Arc<Recorder>,
+        id: u64,
+        job: jobs::Compression,
+        claim: Option<StartClaim>,
+    ) -> anyhow::Result<Flow> {
+        let mut ctx = Context::new(mx, job.options.clone());
+        ctx.by = job.started_by.clone();
+        ctx.claim = claim;
+        ctx.file = job.input.clone();
+        ctx.commands = vec![job.capture_command.clone()];
+        ctx.span = info_span!("recording", id = %recording_id(job.started_at), job = id);
+        otel::attach(&ctx.span, job.trace.as_deref());
+        ctx.job = Some((id, job));
+        let span = ctx.span.clone();
+        run(&self.finish, &mut ctx).instrument(span).await
+    }
+}
+
+/// the id of a recording in the log, from its start so that a restored job has it too
+pub fn recording_id(started_at: DateTime<Local>) -> String {
+    started_at.format("%Y%m%dT%H%M%S%.3f").to_string()
+}
+
+async fn run(stages: &[Box<dyn Stage>], ctx: &mut Context) -> anyhow::Result<Flow> {
+    for stage in stages {
+        debug!("stage {}", stage.name());
+        let span = info_span!("stage", name = stage.name());
+        if stage.run(ctx).instrument(span).await? == Flow::Cancelled {
+            info!(
+                "recording was cancelled before the {} stage ended",
+                stage.name()
+            );
+            return Ok(Flow::Cancelled);
+        }
+    }
+    Ok(Flow::Continue)
+}
+
+/// Puts a pipeline together
+pub struct PipelineBuilder {
+    start: Vec<Box<dyn Stage>>,
+    finish: Vec<Box<dyn Stage>>,
+}
+
+impl PipelineBuilder {
+    /// no stages at all
+    pub fn empty() -> Self {
+        Self {
+            start: vec![],
+            finish: vec![],
+        }
+    }
+
+    /// the stages the server runs
+    pub fn standard() -> Self {
+        Self {
+            start: vec![Box::new(Preflight), Box::new(Resolve), Box::new(Capture)],
+            finish: vec![
+                Box::new(Gather),
+                Box::new(Join),
+                Box::new(Compress),
+                Box::new(MakeDurable),
+                Box::new(VerifyContent),
+                Box::new(ExtractFrames),
+                Box::new(Upload),
+                Box::new(Finalize),
+                Box::new(Transcribe),
+                Box::new(ContactSheet),
+                Box::new(Checksums),
+                Box::new(Cleanup),
+                Box::new(RemoveUploaded),
+            ],
+        }
+    }
+
+    /// add a stage at the end of the start chain
+    pub fn start_stage(mut self, stage: impl Stage + 'static) -> Self {
+        self.start.push(Box::new(stage));
+        self
+    }
+
+    /// add a stage at the end of the finish chain
+    pub fn then(mut self, stage: impl Stage + 'static) -> Self {
+        self.finish.push(Box::new(stage));
+        self
+    }
+
+    /// put the stage in place of the one named like it
+    pub fn replace(mut self, stage: impl Stage + 'static) -> Self {
+        let stage: Box<dyn Stage> = Box::new(stage);
+        let chain = match self.start.iter().any(|s| s.name() == stage.name()) {
+            true => &mut self.start,
+            false => &mut self.finish,
+        };
+        match chain.iter_mut().find(|s| s.name() == stage.name()) {
+            Some(existing) => *existing = stage,
+            None => warn!("no stage {} to replace", stage.name()),
+        }
+        self
+    }
+
+    /// add the stage right after the named one
+    pub fn after(mut self, name: &str, stage: impl Stage + 'static) -> Self {
+        let position = |chain: &[Box<dyn Stage>]| chain.iter().position(|s| s.name() == name);
+        if let Some(i) = position(&self.start) {
+            self.start.insert(i + 1, Box::new(stage));
+        } else if let Some(i) = position(&self.finish) {
+            self.finish.insert(i + 1, Box::new(stage));
+        } else {
+            warn!("no stage {} to add {} after", name, stage.name());
+        }
+        self
+    }
+
+    /// leave the named stage out
+    pub fn without(mut self, name: &str) -> Self {
+        self.start.retain(|s| s.name() != name);
+        self.finish.retain(|s| s.name() != name);
+        self
+    }
+
+    pub fn build(self) -> Pipeline {
+        Pipeline {
+            start: self.start,
+            finish: self.finish,
+        }
+    }
+}
+
+/// The recorder is idle, recording is allowed now, the owner has room and the source is valid
+pub struct Preflight;
+
+impl Stage for Preflight {
+    fn name(&self) -> &'static str {
+        PREFLIGHT
+    }
+
+    fn run<'a>(&'a self, ctx: &'a mut Context) -> BoxFuture<'a, anyhow::Result<Flow>> {
+        Box::pin(async move {
+            let current = ctx.mx.lock().await.clone();
+            match current {
+                RecordingState::Done { .. } => {}
+                RecordingState::Waiting => {}
+                RecordingState::Failed { .. } => {}
+                RecordingState::Cancelled { .. } => {}
+                _ => anyhow::bail!("not ready to start"),
+            };
+            let policy = ctx.mx.policy.status(Local::now());
+            if !policy.allowed {
+                bail!(
+                    "recording is not allowed now, the next window starts at {}",
+                    policy
+                        .next_window
+                        .map(|t| t.to_rfc3339())
+                        .unwrap_or_else(|| "never".to_string())
+                );
+            }
+            let in_progress = quota::in_progress(&ctx.mx).await;
+            ctx.mx
+                .quotas
+                .check(ctx.options.owner.as_deref(), &in_progress)?;
+            ctx.options.source.validate()?;
+            hwaccel::check(ctx.options.encoder).await?;
+            geometry::validate_region(&ctx.options).map_err(anyhow::Error::msg)?;
+            if let Err((field, e)) = geometry::validate_size(&ctx.options) {
+                bail!("invalid {}: {}", field, e);
+            }
+            if let Err((field, e)) = chunks::validate(&ctx.options) {
+                bail!("invalid {}: {}", field, e);
+            }
+            if let Err((field, e)) = rtmp::validate(&ctx.options) {
+                bail!("invalid {}: {}", field, e);
+            }
+            if let Err((field, e)) = scale::validate(&ctx.options) {
+                bail!("invalid {}: {}", field, e);
+            }
+            if let Err((field, e)) = cursor::validate(&ctx.options) {
+                bail!("invalid {}: {}", field, e);
+            }
+            if let Err((field, e)) = overlays::validate(&ctx.options) {
+                bail!("invalid {}: {}", field, e);
+            }
+            if let Err((field, e)) = pulse::validate(&ctx.options) {
+                bail!("invalid {}: {}", field, e);
+            }
+            pulse::check(&ctx.options).await?;
+            if let Some(fallback) = &ctx.mx.fallback_dir {
+                if let Err(e) = failover::writable(fallback).await {
+                    bail!(
+                        "the fallback directory {} can't be written: {}",
+                        fallback.display(),
+                        e
+                    );
+                }
+            }
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+/// Probes a stream to tell whether it can be copied, picks the capture path and names the capture
+pub struct Resolve;
+
+impl Stage for Resolve {
+    fn name(&self) -> &'static str {
+        RESOLVE
+    }
+
+    fn run<'a>(&'a self, ctx: &'a mut Context) -> BoxFuture<'a, anyhow::Result<Flow>> {
+        Box::pin(async move {
+            if ctx.options.source == CaptureSource::Screen && ctx.options.use_shm.is_none() {
+                ctx.options.use_shm = capture_paths::load().and_then(|a| a.use_shm);
+            }
+            match display::fit(&mut ctx.options).await {
+                Ok(()) => {}
+                Err(e @ display::Error::OutsideScreen { .. }) => bail!("invalid region: {}", e),
+                Err(e) => {
+                    let message = e.to_string();
+                    ctx.fail(FailureReason::DisplayUnavailable, message).await;
+                    return Err(e.into());
+                }
+            }
+            ctx.copy = match &ctx.options.source {
+                CaptureSource::Screen => false,
+                CaptureSource::Url { .. } => match ctx.options.source.probe().await {
+                    Ok(info) => {
+                        info!("source {:?}: {:?}", ctx.options.source, info);
+                        source::can_copy(&info)
+                    }
+                    Err(e) => {
+                        let message = e.to_string();
+                        ctx.fail(FailureReason::SourceUnreachable, message).await;
+                        return Err(e.into());
+                    }
+                },
+            };
+            let dir = match recordings::writable_output_dir().await {
+                Ok(dir) => dir,
+                Err(e) => {
+                    let message = format!("{:#}", e);
+                    ctx.fail(FailureReason::OutputUnwritable, message).await;
+                    return Err(e);
+                }
+            };
+            let opt = &ctx.options;
+            let values = template::Values::new(Local::now(), opt.owner.as_deref())
+                .with_profile(opt.encoder_profile.as_deref());
+            let name = ctx
+                .mx
+                .file_name
+                .render(&values, template::Context::FileName)
+                .map_err(anyhow::Error::msg)?;
+            let name = format!("{}.{}", name, opt.format.capture().extension());
+            ctx.file = recordings::new_file(&dir, &name)
+                .await?
+                .to_string_lossy()
+                .to_string();
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+/// Captures the screen or the stream with ffmpeg until it exits, a segment per quality
+///
+/// With `start_at`, the capture is spawned at that time, see [sync_start].
+pub struct Capture;
+
+impl Stage for Capture {
+    fn name(&self) -> &'static str {
+        CAPTURE
+    }
+
+    fn run<'a>(&'a self, ctx: &'a mut Context) -> BoxFuture<'a, anyhow::Result<Flow>> {
+        Box::pin(async move {
+            let opt = &ctx.options;
+            let out = ctx.file.clone();
+            info!("on air {:?} -> {}", opt, out);
+            // drawing and showing the frames need them decoded, a stream is encoded for it
+            ctx.copy = ctx.copy && capture_filters(opt)?.is_none() && ctx.stream_url.is_none();
+            ctx.quality = match (ctx.copy, &opt.source) {
+                // the bitrate of the stream
+                _ if ctx.stream_url.is_some() => None,
+                (true, _) => None,
+                (false, CaptureSource::Screen) => Some(quality::CaptureQuality::lossless(Some(
+                    opt.framerate.unwrap_or(quality::SCREEN_FRAMERATE),
+                ))),
+                (false, _) => Some(quality::CaptureQuality::lossless(None)),
+            };
+            let hls = opt.live_preview || opt.preview == Some(webrtc_preview::Transport::Hls);
+            ctx.preview = hls.then(|| preview::dir(&out));
+            if opt.preview == Some(webrtc_preview::Transport::Webrtc) {
+                ctx.rtp_port = ctx.mx.webrtc_preview.open().await;
+            }
+            let (preview, stream) = (ctx.preview.as_deref(), ctx.stream_url.as_deref());
+            let (quality, rtp) = (ctx.quality.as_ref(), ctx.rtp_port);
+            let ffmpeg = match opt.start_at {
+                None => {
+                    match spawn_capture(opt, ctx.copy, &out, quality, preview, rtp, stream).await {
+                        Ok(ffmpeg) => ffmpeg,
+                        Err(e) => {
+                            let message = format!("the capture could not be started: {}", e);
+                            ctx.fail_on(FailureReason::CaptureFailed, message, &e).await;
+                            return Err(e);
+                        }
+                    }
+                }
+                Some(start_at) => match synchronized(ctx, start_at).await? {
+                    Some(ffmpeg) => ffmpeg,
+                    None => return Ok(Flow::Cancelled),
+                },
+            };
+            let flow = async {
+                let flow = captured(ctx, ffmpeg).await?;
+                while let Some(ffmpeg) = next_segment(ctx).await {
+                    let process_id = ffmpeg.id();
+                    let summary = wait_capture(&ctx.mx, ffmpeg).await?;
+                    capture_ended(ctx, process_id, &summary).await;
+                }
+                Ok(flow)
+            }
+            .await;
+            // nothing writes the preview any more, the capture exited
+            if let Some(preview) = &ctx.preview {
+                preview::remove(preview).await;
+            }
+            if ctx.rtp_port.is_some() {
+                ctx.mx.webrtc_preview.close().await;
+            }
+            flow
+        })
+    }
+}
+
+/// spawn the capture at `start_at`, None when it was stopped in the countdown
+async fn synchronized(
+    ctx: &mut Context,
+    start_at: chrono::DateTime<Local>,
+) -> anyhow::Result<Option<Ffmpeg>> {
+    let mx = ctx.mx.clone();
+    let clock_synchronized = sync_start::clock_synchronized().await;
+    let countdown = RecordingState::Countdown {
+        start_at,
+        options: ctx.options.clone(),
+        started_by: ctx.by.clone(),
+    };
+    sync_start::countdown(&mx, countdown, start_at).await;
+    // under the lock, so that a stop either cancels the countdown or finds the capture
+    let state = mx.lock().await;
+    if !matches!(*state, RecordingState::Countdown { .. }) {
+        return Ok(None);
+    }
+    let triggered = Local::now();
+    let spawned = spawn_capture(
+        &ctx.options,
+        ctx.copy,
+        &ctx.file,
+        ctx.quality.as_ref(),
+        ctx.preview.as_deref(),
+        ctx.rtp_port,
+        ctx.stream_url.as_deref(),
+    )
+    .await;
+    drop(state);
+    let ffmpeg = match spawned {
+        Ok(ffmpeg) => ffmpeg,
+        Err(e) => {
+            let message = format!("the capture could not be started: {}", e);
+            ctx.fail_on(FailureReason::CaptureFailed, message, &e).await;
+            return Err(e);
+        }
+    };
+    let sync = StartSync::new(start_at, triggered, clock_synchronized);
+    info!(
+        "capture triggered {:+.1}ms off {}",
+        sync.error_ms,
+        start_at.to_rfc3339()
+    );
+    sync_start::write_sidecar(&ctx.file, &sync).await;
+    ctx.start_sync = Some(sync);
+    Ok(Some(ffmpeg))
+}
+
+/// the `-vf` chain of the capture: the overlays, then `showinfo`
+fn capture_filters(opt: &RecordingOptions) -> anyhow::Result<Option<String>> {
+    let overlay = overlays::filter_chain(&overlays::layers(opt))
+        .map_err(|e| anyhow::anyhow!("invalid overlay_text: {}", e))?;
+    Ok(match (overlay, opt.frame_timestamps) {
+        (Some(overlay), true) => Some(format!("{},{}", overlay, timestamps::FILTER)),
+        (None, true) => Some(timestamps::FILTER.to_string()),
+        (overlay, false) => overlay,
+    })
+}
+
+/// the options of the x11grab input of `display`, up to its `-i`
+///
+/// The pointer is left out when it is tracked to be drawn later, or not to be shown at all.
+fn screen_input(
+    opt: &RecordingOptions,
+    video_size: String,
+    framerate: &str,
+    display: String,
+) -> Vec<(&'static str, String)> {
+    let mut options = vec![("f", "x11grab".to_string())];
+    if let Some(shm) = opt.use_shm {
+        options.push(("use_shm", if shm { "1" } else { "0" }.to_string()));
+    }
+    if opt.cursor_track || opt.draw_mouse == Some(false) {
+        options.push(("draw_mouse", "0".to_string()));
+    }
+    options.extend([
+        ("video_size", video_size),
+        ("framerate", framerate.to_string()),
+        ("i", display),
+    ]);
+    options
+}
+
+/// spawn the ffmpeg capturing into `out`, encoding with `quality` unless it copies
+///
+/// With `segment_seconds`, it writes the chunks of `out` instead, see [chunks]. With `preview`,
+/// it writes the live preview into that directory as well, see [preview], and with `rtp` it sends
+/// the WebRTC preview to that port, see [webrtc_preview]. With `stream`, it encodes for the
+/// stream to that URL and sends it there, see [rtmp].
+pub async fn spawn_capture(
+    opt: &RecordingOptions,
+    copy: bool,
+    out: &str,
+    quality: Option<&quality::CaptureQuality>,
+    preview: Option<&str>,
+    rtp: Option<u16>,
+    stream: Option<&str>,
+) -> anyhow::Result<Ffmpeg> {
+    let is_screen = opt.source == CaptureSource::Screen;
+    // a stream brings its own audio
+    let resilient_audio = is_screen && opt.audio && opt.audio_resilient;
+    let framerate = quality
+        .and_then(|q| q.framerate)
+        .unwrap_or(quality::SCREEN_FRAMERATE)
+        .to_string();
+    let (rate, rate_value) = quality
+        .map(|q| q.rate_option())
+        .unwrap_or(("qp", "0".to_string()));
+    let encoder = opt.encoder;
+    let rate = encoder.rate_option(rate);
+    let (video_size, display) = match &opt.region {
+        Some(r) => (
+            format!("{}x{}", r.width, r.height),
+            format!("{}+{},{}", display::name(), r.x, r.y),
+        ),
+        None => {
+            let size = geometry::capture_region(opt);
+            (
+                format!("{}x{}", size.width, size.height),
+                display::name().to_string(),
+            )
+        }
+    };
+    let mut builder = FfmpegBuilder::new().stderr(Stdio::piped());
+    if !copy {
+        for option in encoder.global_options() {
+            builder = builder.option(option);
+        }
+    }
+    let input = opt.source.input_options();
+    let audio_inputs = pulse::inputs(opt);
+    let mut mix = None;
+    let screen = screen_input(opt, video_size, &framerate, display);
+    if is_screen {
+        for (key, value) in &screen {
+            builder = builder.option(Parameter::KeyValue(key, value));
+        }
+        if opt.audio && !resilient_audio {
+            for input in &audio_inputs {
+                builder = builder
+                    .option(Parameter::KeyValue("f", "pulse"))
+                    .option(Parameter::KeyValue("ac", "2"))
+                    .option(Parameter::KeyValue("i", &input.source));
+            }
+            mix = pulse::mix_filter(&audio_inputs, 1);
+        }
+    } else {
+        for (key, value) in &input {
+            builder = builder.option(Parameter::KeyValue(key, value));
+        }
+    }
+
+    if let Some(mix) = &mix {
+        builder = builder.option(Parameter::KeyValue("filter_complex", mix));
+    }
+    let mut filters = capture_filters(opt)?;
+    let stream_options = stream.map(|_| rtmp::encode_options(opt, &framerate));
+    if copy {
+        // h264/aac already, the master is the stream as it came
+        builder = builder.option(Parameter::KeyValue("c", "copy"));
+    } else if let Some(stream_options) = &stream_options {
+        for (key, value) in stream_options {
+            builder = builder.option(Parameter::KeyValue(key, value));
+        }
+    } else {
+        if !is_screen {
+            builder = builder
+                .option(Parameter::codec("v", encoder.codec()))
+                .option(Parameter::codec("a", "aac"));
+            if quality.and_then(|q| q.framerate).is_some() {
+                builder = builder.option(Parameter::KeyValue("r", &framerate));
+            }
+        } else if !encoder.is_software() {
+            builder = builder.option(Parameter::codec("v", encoder.codec()));
+        }
+        if is_screen && opt.audio && !resilient_audio && !opt.format.capture().is_mp4() {
+            // that of an mp4 already, an mkv would have vorbis
+            builder = builder.option(Parameter::codec("a", "aac"));
+        }
+        builder = match encoder {
+            VideoEncoder::Software => builder.option(Parameter::KeyValue("preset", "ultrafast")),
+            VideoEncoder::Vaapi => builder,
+            // the fastest, the quality is that of the rate
+            VideoEncoder::Nvenc => builder
+                .option(Parameter::KeyValue("preset", "p1"))
+                .option(Parameter::KeyValue("tune", "ll")),
+        };
+        if encoder == VideoEncoder::Nvenc && rate == "qp" {
+            builder = builder.option(Parameter::KeyValue("rc", "constqp"));
+        }
+        builder = builder.option(Parameter::KeyValue(rate, &rate_value));
+        if is_screen && encoder.is_software() {
+            builder = builder.option(Parameter::KeyValue("pix_fmt", "yuv444p"));
+        }
+        filters = encoder.filters(filters);
+    }
+    if let Some(filters) = &filters {
+        builder = builder.option(Parameter::KeyValue("vf", filters));
+    }
+    if opt.durability == Durability::Strict {
+        // less of the capture waiting in the page cache
+        builder = builder.option(Parameter::KeyValue("fflags", "+flush_packets"));
+    }
+    let segment_time = opt.segment_seconds.map(|seconds| seconds.to_string());
+    let pattern = segment_time.as_ref().map(|_| chunks::pattern(out));
+    let out = pattern.as_deref().unwrap_or(out);
+    let (tee, shown);
+    if mix.is_some() && (stream.is_none() || opt.stream_only) {
+        // the tee muxer has them mapped already
+        builder = builder.option(Parameter::Repeated("map", vec!["0:v", pulse::MIXED]));
+    }
+    let out = match stream {
+        Some(url) if opt.stream_only => {
+            for (key, value) in rtmp::flv_options() {
+                builder = builder.option(Parameter::KeyValue(key, value));
+            }
+            url
+        }
+        Some(url) => {
+            // one encode for both, the file and the stream
+            builder = builder
+                .option(Parameter::Repeated("map", rtmp::maps(opt)))
+                .option(Parameter::KeyValue("flags", "+global_header"))
+                .option(Parameter::KeyValue("f", "tee"));
+            let format = opt.format;
+            tee = rtmp::tee(out, format, segment_time.as_deref(), url);
+            &tee
+        }
+        None => {
+            if let Some(segment_time) = &segment_time {
+                builder = builder
+                    .option(Parameter::KeyValue("f", "segment"))
+                    .option(Parameter::KeyValue("segment_time", segment_time))
+                    .option(Parameter::KeyValue("reset_timestamps", "1"));
+            }
+            out
+        }
+    };
+    if let Some(url) = stream {
+        shown = rtmp::redact(url);
+        builder = builder.redact(url, &shown);
+    }
+    builder = builder.output(File::new(out));
+    let preview_options;
+    let playlist;
+    if let Some(dir) = preview {
+        tokio::fs::create_dir_all(dir).await?;
+        preview_options = preview::output_options(copy, &framerate);
+        playlist = preview::playlist(dir);
+        let mut output = File::new(&playlist);
+        for (key, value) in &preview_options {
+            output = output.option(Parameter::KeyValue(key, value));
+        }
+        builder = builder.output(output);
+    }
+    let rtp_options;
+    let rtp_url;
+    if let Some(port) = rtp {
+        rtp_options = webrtc_preview::output_options(&framerate);
+        rtp_url = webrtc_preview::url(port);
+        let mut output = File::new(&rtp_url);
+        for (key, value) in &rtp_options {
+            output = output.option(Parameter::KeyValue(key, value));
+        }
+        builder = builder.output(output);
+    }
+    Ok(builder.run().await?)
+}
+
+/// start the next segment when the capture exited for a quality change or to fail over
+///
+/// Decided under the lock of the state, so that a stop meanwhile either finds the new segment
+/// or keeps it from being started. A capture that exited on a write error fails over here, when
+/// its supervisor didn't see it yet.
+async fn next_segment(ctx: &mut Context) -> Option<Ffmpeg> {
+    let mx = ctx.mx.clone();
+    let exited = Local::now();
+    let mut state = mx.lock().await;
+    if let RecordingState::Paused { recording, .. } = &mut *state {
+        if let RecordingState::Started {
+            started_at,
+            timeline,
+            ..
+        } = &mut **recording
+        {
+            // the pause is a gap of the timeline until the next segment starts
+            timeline.end((exited - *started_at).num_milliseconds().max(0) as u64);
+        }
+        let updated = state.clone();
+        mx.replace(&mut state, updated);
+        drop(state);
+        if !pause::resumed(&mx).await {
+            return None;
+        }
+        state = mx.lock().await;
+    }
+    let resuming = pause::restart(&mut state);
+    let RecordingState::Started {
+        process_id,
+        file,
+        started_at,
+        markers,
+        warnings,
+        command,
+        quality,
+        segments,
+        chunks,
+        failover: output,
+        timeline,
+        ..
+    } = &mut *state
+    else {
+        return None;
+    };
+    let next = quality.as_mut().and_then(|status| status.pending.take());
+    let failing_over = output.as_ref().is_some_and(|output| output.pending);
+    if next.is_none() && !failing_over && !resuming {
+        return None;
+    }
+    timeline.end((exited - *started_at).num_milliseconds().max(0) as u64);
+    if let Some(output) = output.as_mut().filter(|output| output.pending) {
+        output.pending = false;
+        output.active = output.fallback.clone();
+        output.switched_at = Some(Local::now());
+    }
+    let index = match chunks {
+        Some(chunks) => chunks.segment + 1,
+        None => segments.len().max(1),
+    };
+    let mut segment = quality::segment_path(&ctx.file, index);
+    if let Some(output) = output.as_ref() {
+        segment = output.relocate(&segment);
+    }
+    let settings = next.or(quality.as_ref().map(|status| status.active));
+    let why = match (next, output.as_ref()) {
+        (Some(next), _) => format!("the change to {}", next),
+        (None, _) if resuming => "the resume".to_string(),
+        (None, output) => format!(
+            "the failover to {}",
+            output.map(|o| o.active.as_str()).unwrap_or_default()
+        ),
+    };
+    // the countdown was at the start of the recording, not of the segment
+    let opt = RecordingOptions {
+        intro_countdown: false,
+        ..ctx.options.clone()
+    };
+    let (preview, stream) = (ctx.preview.as_deref(), ctx.stream_url.as_deref());
+    let spawned = spawn_capture(
+        &opt,
+        ctx.copy,
+        &segment,
+        settings.as_ref(),
+        preview,
+        ctx.rtp_port,
+        stream,
+    );
+    let mut ffmpeg = match spawned.await {
+        Ok(ffmpeg) => ffmpeg,
+        Err(e) => {
+            warn!("cannot start the capture for {}: {}", why, e);
+            warnings.push(format!("the capture ended at {}: {}", why, e));
+            let updated = state.clone();
+            mx.replace(&mut state, updated);
+            return None;
+        }
+    };
+    let at_ms = since(*started_at);
+    timeline.resume(at_ms);
+    match chunks.as_mut() {
+        // the chunks go on, they are the files of the capture
+        Some(chunks) => {
+            chunks.roll_over(&segment);
+            segment = chunks::chunk(&chunks.pattern, 0);
+        }
+        None => {
+            if segments.is_empty() {
+                segments.push(quality::VideoSegment {
+                    file: file.clone(),
+                    offset_ms: 0,
+                    quality: quality.as_ref().map(|status| status.active),
+                });
+            }
+            segments.push(quality::VideoSegment {
+                file: segment.clone(),
+                offset_ms: at_ms,
+                quality: settings,
+            });
+        }
+    }
+    if let (Some(status), Some(next)) = (quality.as_mut(), next) {
+        status.active = next;
+        status.changes += 1;
+        markers.push(timeline.marker(at_ms, &format!("quality {}", next)));
+    }
+    if let Some(output) = output.as_ref().filter(|_| failing_over) {
+        markers.push(timeline.marker(at_ms, &format!("failover to {}", output.active)));
+    }
+    if resuming {
+        markers.push(timeline.marker(at_ms, "resumed"));
+    }
+    if let (Some(detector), Some(output)) = (&ctx.write_errors, output.as_ref()) {
+        if output.on_primary() {
+            let detector = detector.clone();
+            ffmpeg.on_stderr(move |line| detector.line(line));
+        }
+    }
+    *process_id = ffmpeg.id();
+    *file = segment.clone();
+    *command = ffmpeg.argv().to_vec();
+    mx.children
+        .register(ffmpeg.id(), ChildRole::Capture, vec![segment.clone()]);
+    ctx.commands.push(ffmpeg.argv().to_vec());
+    let updated = state.clone();
+    mx.replace(&mut state, updated);
+    drop(state);
+    info!("capture rolled over to {} at {}ms", segment, at_ms);
+    if ctx.options.source == CaptureSource::Screen {
+        // the watcher of the previous segment ended with it
+        let policy = ctx.options.on_geometry_change;
+        let region = geometry::capture_region(&ctx.options);
+        tokio::spawn(geometry::watch(mx.clone(), ffmpeg.id(), policy, region));
+    }
+    Some(ffmpeg)
+}
+
+/// wait for a capture to exit, reaping it
+async fn wait_capture(mx: &Recorder, ffmpeg: Ffmpeg) -> anyhow::Result<CompletionSummary> {
+    let process_id = ffmpeg.id();
+    let summary = ffmpeg
+        .wait_with_progress(|p| {
+            p.log();
+            trace!(
+                fps = p.fps.unwrap_or_default(),
+                speed = p.speed.unwrap_or_default(),
+                drop_frames = p.drop_frames.unwrap_or_default(),
+                "capture progress"
+            );
+            mx.progress(p);
+        })
+        .await;
+    mx.children.unregister(process_id);
+    let summary = summary?;
+    log_summary("capture", &summary);
+    Ok(summary)
+}
+
+/// take over a capture that was spawned: the state, the watchers, the progress and the stop
+///
+/// A capture stage of its own spawns its ffmpeg writing [Context::file] and hands it over here.
+pub async fn captured(ctx: &mut Context, mut ffmpeg: Ffmpeg) -> anyhow::Result<Flow> {
+    let mx = ctx.mx.clone();
+    let opt = ctx.options.clone();
+    let out = ctx.file.clone();
+    let is_screen = opt.source == CaptureSource::Screen;
+    let resilient_audio = is_screen && opt.audio && opt.audio_resilient;
+    let video_started = std::time::Instant::now();
+    let mut frame_timestamps = None;
+    let mut sidecar = None;
+    if opt.frame_timestamps {
+        let path = timestamps::sidecar_path(&out);
+        match timestamps::Sidecar::create(&path) {
+            Ok(created) => {
+                sidecar = Some(created);
+                frame_timestamps = Some(path.to_string_lossy().to_string());
+            }
+            Err(e) => warn!("cannot write {}: {}", path.display(), e),
+        }
+    }
+    let failover = mx.fallback_dir.as_ref().map(|fallback| {
+        let primary = std::path::Path::new(&out).parent().unwrap_or(fallback);
+        FailoverStatus::new(primary, fallback)
+    });
+    ctx.write_errors = failover.is_some().then(failover::Detector::new);
+    // the first frame, when the anchor of the timestamps is read
+    let (first_seen, seen) = tokio::sync::oneshot::channel();
+    let mut first_seen = Some(first_seen);
+    if sidecar.is_some() || ctx.write_errors.is_some() {
+        let detector = ctx.write_errors.clone();
+        ffmpeg.on_stderr(move |line| {
+            if let Some(detector) = &detector {
+                detector.line(line);
+            }
+            let Some(sidecar) = sidecar.as_mut() else {
+                return false;
+            };
+            let taken = sidecar.line(line);
+            if let Some(anchor) = sidecar.anchor() {
+                if let Some(first_seen) = first_seen.take() {
+                    let _ = first_seen.send(anchor.realtime);
+                }
+            }
+            taken
+        });
+    }
+    let process_id = ffmpeg.id();
+    let chunks = opt.segment_seconds.map(|_| Chunks::new(&out));
+    // the chunk being written
+    let file = match &chunks {
+        Some(chunks) => chunks::chunk(&chunks.pattern, 0),
+        None => out.clone(),
+    };
+    mx.children
+        .register(process_id, ChildRole::Capture, vec![file.clone()]);
+    ctx.commands.push(ffmpeg.argv().to_vec());
+    if process_id > 0 {
+        let geometry_policy = opt.on_geometry_change;
+        let region = geometry::capture_region(&opt);
+        let configuration = latency::configuration(&opt);
+        let timestamped = frame_timestamps.is_some();
+        let cursor_track = (is_screen && (opt.cursor_track || opt.show_clicks)).then(|| {
+            let track = cursor::Track {
+                x: region.x,
+                y: region.y,
+                ..Default::default()
+            };
+            let path = match opt.cursor_track {
+                true => cursor::track_path(&out),
+                false => cursor::clicks_path(&out),
+            };
+            (track, path)
+        });
+        let framerate = ctx
+            .quality
+            .as_ref()
+            .and_then(|q| q.framerate)
+            .unwrap_or(quality::SCREEN_FRAMERATE);
+        let started_at = Local::now();
+        let first_frame =
+            latency::FirstFrame::estimated(&latency::load(), &configuration, started_at);
+        mx.set(RecordingState::Started {
+            progress: None,
+            process_id,
+            file,
+            audio: resilient_audio.then(AudioStatus::default),
+            options: opt,
+            started_at,
+            markers: vec![],
+            timeline: RecordingTimeline::started(),
+            warnings: vec![],
+            command: ffmpeg.argv().to_vec(),
+            frame_timestamps,
+            started_by: ctx.by.clone(),
+            quality: ctx.quality.map(quality::QualityStatus::new),
+            segments: vec![],
+            chunks: chunks.clone(),
+            failover,
+            start_sync: ctx.start_sync.clone(),
+            first_frame: first_frame.clone(),
+            health: None,
+            preview: ctx.preview.clone(),
+            streaming: rtmp::Streaming::new(&ctx.options),
+        })
+        .await;
+        ctx.span
+            .record("id", field::display(recording_id(started_at)));
+        drop(ctx.claim.take());
+        if let Some(first_frame) = &first_frame {
+            latency::write_sidecar(&out, first_frame).await;
+        }
+        if timestamped {
+            let (mx, capture) = (mx.clone(), out.clone());
+            tokio::spawn(async move {
+                latency::measure(&mx, process_id, capture, configuration, seen).await
+            });
+        }
+        if let Some(detector) = &ctx.write_errors {
+            tokio::spawn(failover::supervise(mx.clone(), detector.clone()));
+        }
+        if chunks.is_some() {
+            tokio::spawn(chunks::follow(mx.clone(), started_at));
+        }
+        if let Some((track, path)) = cursor_track {
+            tokio::spawn(cursor::track(
+                mx.clone(),
+                started_at,
+                track,
+                framerate,
+                path,
+            ));
+        }
+        if is_screen {
+            tokio::spawn(geometry::watch(
+                mx.clone(),
+                process_id,
+                geometry_policy,
+                region,
+            ));
+        }
+        if resilient_audio {
+            let base = container::base(&out).to_string();
+            let inputs = pulse::inputs(&ctx.options);
+            tokio::spawn(audio::supervise(mx.clone(), base, inputs, video_started));
+        }
+    }
+    // the capture is reaped here, stop() waits for it to leave the table
+    let summary = wait_capture(&mx, ffmpeg).await?;
+    capture_ended(ctx, process_id, &summary).await;
+    Ok(Flow::Continue)
+}
+
+/// fail the recording when its capture exited on its own with an error, the server refusing or
+/// dropping a stream, a device going away..., and stop it when the capture ended cleanly
+///
+/// A capture that was stopped, paused, changed or failed over is not Started by this process any
+/// more, or has what comes next pending. The write errors are looked into first, a capture that
+/// can't write fails over rather than failing the recording.
+async fn capture_ended(ctx: &mut Context, process_id: u32, summary: &CompletionSummary) {
+    if let Some(detector) = &ctx.write_errors {
+        if let Some(why) = failover::exit_reason(&ctx.mx, detector).await {
+            failover::announce(&ctx.mx, &why).await;
+        }
+    }
+    let file = match &*ctx.mx.lock().await {
+        RecordingState::Started {
+            process_id: pid,
+            file,
+            failover,
+            quality,
+            ..
+        } if *pid == process_id
+            && !failover.as_ref().is_some_and(|f| f.pending)
+            && quality.as_ref().and_then(|q| q.pending.as_ref()).is_none() =>
+        {
+            file.clone()
+        }
+        _ => return,
+    };
+    if summary.success() {
+        // the stop waits for the chain of the start to be done with the capture
+        info!("the capture {} of {} ended on its own", process_id, file);
+        let mx = ctx.mx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = stop_for(mx, Some(StopReason::CaptureEnded)).await {
+                warn!("cannot stop after the capture ended: {}", e);
+            }
+        });
+        return;
+    }
+    let (reason, mut message, stderr_tail) = match ctx.stream_url.clone() {
+        Some(url) => {
+            let shown = rtmp::redact(&url);
+            let message = format!(
+                "the stream to {} ended, the capture exited with {}",
+                shown, summary.exit_status
+            );
+            let stderr_tail = summary
+                .stderr_tail
+                .iter()
+                .map(|line| line.replace(&url, &shown))
+                .collect();
+            (FailureReason::StreamFailed, message, stderr_tail)
+        }
+        None => (
+            FailureReason::CaptureFailed,
+            format!("the capture exited with {}", summary.exit_status),
+            summary.stderr_tail.clone(),
+        ),
+    };
+    if !ctx.options.stream_only {
+        if let Err(e) = recordings::flag_for_recovery(std::path::Path::new(&file)) {
+            warn!("cannot flag {} for recovery: {}", file, e);
+        }
+        message += &format!(", {} is kept as it was captured", file);
+    }
+    fail(&ctx.mx, &ctx.options, reason, message, stderr_tail).await;
+    ctx.failed = true;
+}
+
+/// Brings the files of a capture that failed over into one directory, see [failover]
+pub struct Gather;
+
+impl Stage for Gather {
+    fn name(&self) -> &'static str {
+        GATHER
+    }
+
+    fn run<'a>(&'a self, ctx: &'a mut Context) -> BoxFuture<'a, anyhow::Result<Flow>> {
+        Box::pin(async move {
+            let Some(fallback) = ctx.mx.fallback_dir.clone() else {
+                return Ok(Flow::Continue);
+            };
+            let Some((_, job)) = ctx.job.as_mut() else {
+                bail!("no compression job in the start chain");
+            };
+            let fallback_segment = |file: &str| std::path::Path::new(file).starts_with(&fallback);
+            if !job.video_segments.iter().any(|s| fallback_segment(&s.file)) {
+                return Ok(Flow::Continue);
+            }
+            let output = std::path::Path::new(&job.output);
+            let primary = output.parent().unwrap_or(&fallback).to_path_buf();
+            let dir = failover::finish_dir(&primary, &fallback).await;
+            job.video_segments = failover::gather(&job.video_segments, dir).await?;
+            let mut audio = vec![];
+            for segment in std::mem::take(&mut job.segments) {
+                match failover::bring(&segment.file, dir).await {
+                    Ok(file) => audio.push(audio::AudioSegment { file, ..segment }),
+                    Err(e) => warn!("leaving {} out, it can't be copied: {}", segment.file, e),
+                }
+            }
+            job.segments = audio;
+            job.input = job.video_segments[0].file.clone();
+            job.output = failover::in_dir(dir, &job.output);
+            ctx.file = job.input.clone();
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+/// Joins the segments of a capture whose quality was changed or that failed over
+pub struct Join;
+
+impl Stage for Join {
+    fn name(&self) -> &'static str {
+        JOIN
+    }
+
+    fn run<'a>(&'a self, ctx: &'a mut Context) -> BoxFuture<'a, anyhow::Result<Flow>> {
+        Box::pin(async move {
+            let (_, job) = ctx.job()?;
+            if job.video_segments.len() < 2 {
+                return Ok(Flow::Continue);
+            }
+            let (joined, command) = quality::join(&job.video_segments, &ctx.mx.children).await?;
+            ctx.commands.push(command);
+            ctx.file = joined;
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+/// Compresses the raw capture, the audio segments mixed in, or every chunk of it on its own
+pub struct Compress;
+
+impl Stage for Compress {
+    fn name(&self) -> &'static str {
+        COMPRESS
+    }
+
+    fn run<'a>(&'a self, ctx: &'a mut Context) -> BoxFuture<'a, anyhow::Result<Flow>> {
+        Box::pin(async move {
+            let (id, job) = ctx.job()?.clone();
+            if job.options.compress == Some(false) {
+                if job.trim.is_some() {
+                    warn!("{} is not trimmed without a compression", ctx.file);
+                }
+                info!("{} is kept uncompressed", ctx.file);
+                ctx.chunks = job.chunks.clone();
+                return Ok(Flow::Continue);
+            }
+            if job.chunks.is_empty() {
+                return compress(ctx, id, job).await;
+            }
+            if job.trim.is_some() {
+                warn!("the chunks of {} are not trimmed", job.output);
+            }
+            let mut results = vec![];
+            for chunk in &job.chunks {
+                ctx.file = chunk.clone();
+                let single = jobs::Compression {
+                    output: chunks::compressed(chunk, &job.output),
+                    trim: None,
+                    timeline: RecordingTimeline::default(),
+                    ..job.clone()
+                };
+                if compress(ctx, id, single).await? == Flow::Cancelled {
+                    return Ok(Flow::Cancelled);
+                }
+                results.push(ctx.file.clone());
+            }
+            ctx.file = results[0].clone();
+            ctx.chunks = results;
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+/// compress [Context::file] into the output of the job
+async fn compress(ctx: &mut Context, id: u64, job: jobs::Compression) -> anyhow::Result<Flow> {
+    let mx = ctx.mx.clone();
+    let (input, output) = (ctx.file.clone(), job.output.clone());
+    // start compression and watch its progress
+    // ffmpeg -i input.mp4 -vcodec libx264 -crf 20 output.mp4
+    let encoder = match &job.options.encoder_profile {
+        Some(name) => {
+            let profile = mx
+                .encoder_profiles
+                .usable(name)
+                .map_err(|e| anyhow!("{}", e))?;
+            EncoderParams::of_profile(profile, job.options.content, &job.options.encoder_options)
+                .map_err(|e| anyhow!("{}", e))?
+        }
+        None => EncoderParams::resolve(job.options.content)
+            .in_container(job.options.format)
+            .on(job.options.encoder)
+            .tuned(&job.options),
+    };
+    info!("encoder {:?}", encoder);
+    let readrate = mx
+        .throttle
+        .readrate(Category::Compression, std::path::Path::new(&input))
+        .await;
+    let mut capture = File::new(&input);
+    if let Some((speed, _)) = &readrate {
+        capture = capture.option(Parameter::KeyValue("readrate", speed));
+    }
+    // only the result is trimmed, the capture and the segments are read from the window
+    let (seek, duration) = match &job.trim {
+        Some(window) => (window.seek(), Some(window.duration())),
+        None => (None, None),
+    };
+    if let Some(seek) = &seek {
+        capture = capture.option(Parameter::KeyValue("ss", seek));
+    }
+    if let Some(duration) = &duration {
+        capture = capture.option(Parameter::KeyValue("t", duration));
+    }
+    let segments = match &job.trim {
+        Some(window) => window.segments(&job.segments),
+        None => job.segments.iter().map(|s| (s.clone(), None)).collect(),
+    };
+    let scale = match &job.options.scale {
+        Some(scale) => Some(scale::filter(scale).map_err(|e| anyhow!("invalid scale: {}", e))?),
+        None => None,
+    };
+    let format = job.options.format;
+    let audio_codec = format.audio_codec();
+    let mut inputs = vec![capture];
+    for (segment, seek) in &segments {
+        let mut file = File::new(&segment.file);
+        if let Some(seek) = seek {
+            file = file.option(Parameter::KeyValue("ss", seek));
+        }
+        inputs.push(file);
+    }
+    let placed: Vec<_> = segments
+        .iter()
+        .map(|(segment, _)| segment.clone())
+        .collect();
+    let measured = match job.options.normalize_audio {
+        true => measure_loudness(&mx, &inputs, &placed).await,
+        false => None,
+    };
+    let loudnorm = measured.as_ref().and_then(|measured| measured.filter());
+    if measured.is_some() && loudnorm.is_none() {
+        info!("{} is silent, its loudness is left as it is", input);
+    }
+    let mut builder = FfmpegBuilder::new().stderr(Stdio::piped());
+    for option in encoder.video_encoder.global_options() {
+        builder = builder.option(option);
+    }
+    for file in inputs {
+        builder = builder.input(file);
+    }
+    let slate = match job.options.slate {
+        true => match slate::Slate::of(id, job.started_at, std::path::Path::new(&input)).await {
+            Ok(slate) => Some(slate),
+            Err(e) => {
+                warn!("compressing {} without its slate: {}", input, e);
+                None
+            }
+        },
+        false => None,
+    };
+    let clicks = match job.options.show_clicks {
+        true => match cursor::read_track(&cursor::clicks_path(&job.input)) {
+            Ok(track) => {
+                // the clicks are drawn after the slate, on the content from the head trimmed
+                let head_ms = job.trim.as_ref().map_or(0, |window| window.start_ms);
+                let slate_ms = slate.as_ref().map_or(0, |_| slate::MS);
+                cursor::click_filters(&track, slate_ms as i64 - head_ms as i64)
+            }
+            Err(e) => {
+                warn!("compressing {} without its clicks: {}", input, e);
+                None
+            }
+        },
+        false => None,
+    };
+    // the clicks are drawn at the positions of the capture, before it is scaled
+    let video: Vec<String> = clicks.into_iter().chain(scale).collect();
+    let video = (!video.is_empty()).then(|| video.join(","));
+    let mut graph = match &loudnorm {
+        Some(loudnorm) => audio::normalized_mix_filter(&placed, loudnorm),
+        None => audio::mix_filter(&placed),
+    };
+    if let Some(slate) = &slate {
+        let audio = match job.segments.is_empty() {
+            false => Some("[aout]"),
+            true if loudnorm.is_some() => slate.audio.then_some("[anorm]"),
+            true => slate.audio.then_some("[0:a]"),
+        };
+        // the segments are mixed first, the mix is delayed along with the capture
+        graph = match (job.segments.is_empty(), &loudnorm) {
+            (false, _) => format!("{};{}", graph, slate.graph(audio, video.as_deref())),
+            (true, Some(loudnorm)) if slate.audio => format!(
+                "[0:a]{}[anorm];{}",
+                loudnorm,
+                slate.graph(audio, video.as_deref())
+            ),
+            (true, _) => slate.graph(audio, video.as_deref()),
+        };
+        let maps = match audio {
+            Some(_) => vec!["[v]", "[a]"],
+            None => vec!["[v]"],
+        };
+        builder = builder
+            .option2(Parameter::KeyValue("filter_complex", &graph))
+            .option2(Parameter::Repeated("map", maps));
+        if audio.is_some() {
+            builder = builder.option2(Parameter::codec("a", audio_codec));
+        }
+        if !job.segments.is_empty() {
+            builder = builder.option2(Parameter::Single("shortest"));
+        }
+    } else if !job.segments.is_empty() {
+        // audio segments are placed on the video timeline, the gaps are silence
+        builder = builder
+            .option2(Parameter::KeyValue("filter_complex", &graph))
+            .option2(Parameter::Repeated("map", vec!["0:v", "[aout]"]))
+            .option2(Parameter::codec("a", audio_codec))
+            .option2(Parameter::Single("shortest"));
+    } else if let Some(loudnorm) = &loudnorm {
+        builder = builder.option2(Parameter::KeyValue("af", loudnorm));
+    }
+    if encoder.profile.is_none() {
+        builder = builder.option2(Parameter::codec("v", &encoder.codec));
+    }
+    // the slate runs them in its filtergraph
+    let filters = encoder.video_encoder.filters(video);
+    if let Some(filters) = filters.as_ref().filter(|_| slate.is_none()) {
+        builder = builder.option2(Parameter::KeyValue("vf", filters));
+    }
+    for (key, value) in &encoder.options {
+        builder = builder.option2(Parameter::KeyValue(key, value));
+    }
+    for parameter in &encoder.parameters {
+        builder = builder.option2(parameter.as_parameter());
+    }
+    if encoder.profile.is_none() {
+        if !format.is_mp4() {
+            // the audio of the capture is aac, which a webm can't hold
+            builder = builder.option2(Parameter::codec("a", audio_codec));
+        }
+        for option in format.mux_options() {
+            builder = builder.option2(option);
+        }
+    }
+    builder = builder.output(File::new(&output));
+    let mut ffmpeg = builder.run().await?;
+    let printed = loudnorm.is_some().then(|| loudness::collect(&mut ffmpeg));
+    let process_id = ffmpeg.id();
+    let command = ffmpeg.argv().to_vec();
+    let duration_ms = job
+        .trim
+        .map(|window| window.kept_ms())
+        .or(job.timeline.recorded_ms())
+        .map(|ms| ms + slate.as_ref().map_or(0, |_| slate::MS));
+    mx.children
+        .register(process_id, ChildRole::Compression, vec![output.clone()]);
+    mx.throttle.idle(process_id);
+    mx.set(RecordingState::Compressing {
+        process_id,
+        input: input.clone(),
+        output: output.clone(),
+        encoder: encoder.clone(),
+        gpu: None,
+        command: command.clone(),
+        health: None,
+        progress: None,
+        duration_ms,
+        percent: None,
+    })
+    .await;
+    drop(ctx.claim.take());
+    mx.jobs.update(id, JobState::Running, None);
+    mx.jobs.set_io_rate(id, readrate.map(|(_, rate)| rate));
+    ctx.commands.push(command);
+    if let Some(backend) = gpu::Backend::for_codec(&encoder.codec) {
+        tokio::spawn(gpu::monitor(mx.clone(), backend));
+    }
+
+    info!("compressing {}", process_id);
+    let summary = ffmpeg
+        .wait_with_progress(|p| {
+            p.log();
+            mx.progress(p);
+        })
+        .await;
+    mx.children.unregister(process_id);
+    log_summary("compression", &summary?);
+    if !matches!(*mx.lock().await, RecordingState::Compressing { .. }) {
+        return Ok(Flow::Cancelled);
+    }
+    let result = std::path::Path::new(&output);
+    if let Some((first, printed)) = measured.as_ref().zip(printed) {
+        let second = loudness::parse(&printed.lock().unwrap());
+        if let Some(loudness) = Loudness::of(first, second.as_ref()) {
+            info!(
+                "the loudness of {} was {} LUFS, it is {:?}",
+                output, loudness.measured_lufs, loudness.output_lufs
+            );
+            loudness::write_sidecar(result, &loudness).await;
+            // that of the first chunk for a recording in chunks
+            ctx.loudness.get_or_insert(loudness);
+        }
+    }
+    if let Some((window, (_, job))) = job.trim.zip(ctx.job.as_mut()) {
+        window.markers(&mut job.markers);
+        trim::write_sidecar(result, &window).await;
+    }
+    // the markers were set on the capture, which starts after the slate now
+    if let Some((_, job)) = ctx.job.as_mut().filter(|_| slate.is_some()) {
+        for marker in &mut job.markers {
+            marker.at_ms += slate::MS;
+            if let Some(content_ms) = marker.content_ms.as_mut() {
+                *content_ms += slate::MS;
+            }
+        }
+    }
+    let head_ms = job.trim.map_or(0, |window| window.start_ms);
+    let slate_ms = slate.as_ref().map_or(0, |_| slate::MS);
+    if head_ms > 0 || slate_ms > 0 {
+        cursor::set_offset(result, slate_ms as i64 - head_ms as i64);
+    }
+    ctx.file = output;
+    Ok(Flow::Continue)
+}
+
+/// the first pass of the loudness normalization over the audio the compression encodes, see
+/// [loudness]; None without audio, the compression goes on without the normalization then
+async fn measure_loudness(
+    mx: &Recorder,
+    inputs: &[File<'_>],
+    segments: &[audio::AudioSegment],
+) -> Option<loudness::Measured> {
+    let capture = inputs[0].url;
+    // the mix ends with the last segment, padded it would never end without the video
+    let audio = match segments.is_empty() {
+        false => format!("{};[aout]", audio::unpadded_mix_filter(segments)),
+        true => match probe::probe(std::path::Path::new(capture)).await {
+            Ok(info) if info.audio_codec.is_some() => "[0:a]".to_string(),
+            Ok(_) => {
+                info!("{} has no audio to normalize", capture);
+                return None;
+            }
+            Err(e) => {
+                warn!("not normalizing {}, it can't be probed: {:#}", capture, e);
+                return None;
+            }
+        },
+    };
+    let graph = format!("{}{}[measured]", audio, loudness::measure_filter());
+    let mut builder = FfmpegBuilder::new().stderr(Stdio::piped());
+    for file in inputs {
+        builder = builder.input(file.clone());
+    }
+    builder = builder
+        .option2(Parameter::KeyValue("filter_complex", &graph))
+        .option2(Parameter::Repeated("map", vec!["[measured]"]))
+        .option2(Parameter::KeyValue("f", "null"))
+        .output(File::new("-"));
+    match loudness::measure(builder, &mx.children).await {
+        Ok(measured) => {
+            info!(
+                "measured the loudness of {}: {:?}",
+                capture,
+                measured.input_lufs()
+            );
+            Some(measured)
+        }
+        Err(e) => {
+            warn!("not normalizing {}: {:#}", capture, e);
+            None
+        }
+    }
+}
+
+/// Fsyncs the result of a strictly durable recording
+pub struct MakeDurable;
+
+impl Stage for MakeDurable {
+    fn name(&self) -> &'static str {
+        SYNC
+    }
+
+    fn run<'a>(&'a self, ctx: &'a mut Context) -> BoxFuture<'a, anyhow::Result<Flow>> {
+        Box::pin(async move {
+            ctx.durable = ctx.options.durability == Durability::Strict;
+            if ctx.durable {
+                for file in ctx.results() {
+                    let started = std::time::Instant::now();
+                    let fsync = &ctx.mx.fsync;
+                    fsync.sync_file(std::path::Path::new(&file)).await?;
+                    info!("{} is durable, fsync took {:?}", file, started.elapsed());
+                }
+            }
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+/// Flags a result that is mostly black or silent, see [crate::content_check]
+pub struct VerifyContent;
+
+impl Stage for VerifyContent {
+    fn name(&self) -> &'static str {
+        VERIFY_CONTENT
+    }
+
+    fn run<'a>(&'a self, ctx: &'a mut Context) -> BoxFuture<'a, anyhow::Result<Flow>> {
+        Box::pin(async move {
+            let mx = ctx.mx.clone();
+            let config = &mx.content_check;
+            if !ctx.options.verify_content.unwrap_or(config.enabled) {
+                return Ok(Flow::Continue);
+            }
+            for file in ctx.results() {
+                let path = std::path::Path::new(&file);
+                match content_check::check(path, config, &mx.children).await {
+                    Ok(warnings) if warnings.is_empty() => {}
+                    Ok(warnings) => {
+                        warn!("{} is probably not what was meant: {:?}", file, warnings);
+                        mx.events.publish(EventKind::Suspect {
+                            file: file.clone(),
+                            warnings: warnings.clone(),
+                        });
+                        ctx.content_warnings.extend(warnings);
+                    }
+                    Err(e) => warn!("cannot look at the content of {}: {}", file, e),
+                }
+            }
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+/// Extracts the still frames the options ask for, of the first chunk of a recording in chunks
+pub struct ExtractFrames;
+
+impl Stage for ExtractFrames {
+    fn name(&self) -> &'static str {
+        FRAMES
+    }
+
+    fn run<'a>(&'a self, ctx: &'a mut Context) -> BoxFuture<'a, anyhow::Result<Flow>> {
+        Box::pin(async move {
+            if ctx.options.extract_frames.is_empty() {
+                return Ok(Flow::Continue);
+            }
+            let req = frames::FramesRequest {
+                positions: ctx.options.extract_frames.clone(),
+                format: frames::ImageFormat::Png,
+                width: None,
+            };
+            let path = std::path::Path::new(&ctx.file);
+            match frames::extract(path, &req, &ctx.mx.children).await {
+                Ok(frames) => ctx.frames = frames,
+                Err(e) => warn!("cannot extract frames of {}: {}", ctx.file, e),
+            }
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+/// Uploads the result to the bucket of the server, when it has one, see [crate::s3]
+///
+/// The chunks of a recording in chunks are uploaded one after the other. A failed upload fails
+/// the recording, the chain ends with the local files kept.
+pub struct Upload;
+
+impl Stage for Upload {
+    fn name(&self) -> &'static str {
+        UPLOAD
+    }
+
+    fn run<'a>(&'a self, ctx: &'a mut Context) -> BoxFuture<'a, anyhow::Result<Flow>> {
+        Box::pin(async move {
+            let mx = ctx.mx.clone();
+            let Some(bucket) = &mx.bucket else {
+                return Ok(Flow::Continue);
+            };
+            let started_at = ctx
+                .job
+                .as_ref()
+                .map_or_else(Local::now, |(_, job)| job.started_at);
+            let values = template::Values::new(started_at, ctx.options.owner.as_deref())
+                .with_profile(ctx.options.encoder_profile.as_deref());
+            let mut keys = vec![];
+            for result in ctx.results() {
+                let file = std::path::PathBuf::from(&result);
+                let key = bucket.key(&file, &values).map_err(anyhow::Error::msg)?;
+                let total = tokio::fs::metadata(&file).await?.len();
+                mx.set(RecordingState::Uploading {
+                    file: result.clone(),
+                    key: key.clone(),
+                    bytes_sent: 0,
+                    total,
+                })
+                .await;
+                let sent = std::sync::atomic::AtomicU64::new(0);
+                let uploaded = {
+                    let upload = bucket.upload(&file, &key, &sent);
+                    tokio::pin!(upload);
+                    let mut tick = tokio::time::interval(UPLOAD_PROGRESS);
+                    loop {
+                        tokio::select! {
+                            uploaded = &mut upload => break uploaded,
+                            _ = tick.tick() => {
+                                let sent = sent.load(std::sync::atomic::Ordering::Relaxed);
+                                upload_progress(&mx, sent).await;
+                            }
+                        }
+                    }
+                };
+                if let Err(e) = uploaded {
+                    let message = format!("cannot upload {} as {}: {:#}", result, key, e);
+                    ctx.fail(FailureReason::UploadFailed, message).await;
+                    return Err(e);
+                }
+                if !matches!(*mx.lock().await, RecordingState::Uploading { .. }) {
+                    return Ok(Flow::Cancelled);
+                }
+                info!("uploaded {} as {}", result, key);
+                keys.push(key);
+            }
+            ctx.uploaded = keys.into_iter().next();
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+async fn upload_progress(mx: &Recorder, sent: u64) {
+    let mut state = mx.lock().await;
+    if let RecordingState::Uploading { bytes_sent, .. } = &mut *state {
+        if *bytes_sent != sent {
+            *bytes_sent = sent;
+            let updated = state.clone();
+            mx.replace(&mut state, updated);
+        }
+    }
+}
+
+/// Keeps the recording in the history and reports it done
+pub struct Finalize;
+
+impl Stage for Finalize {
+    fn name(&self) -> &'static str {
+        FINALIZE
+    }
+
+    fn run<'a>(&'a self, ctx: &'a mut Context) -> BoxFuture<'a, anyhow::Result<Flow>> {
+        Box::pin(async move {
+            let (_, job) = ctx.job()?.clone();
+            let output = ctx.file.clone();
+            let resolution = scale::resolution(std::path::Path::new(&output)).await;
+            let done = RecordingState::Done {
+                file: output.clone(),
+                audio: job.audio.map(|audio| AudioStatus {
+                    connected: false,
+                    ..audio
+                }),
+                markers: job.markers,
+                durable: ctx.durable,
+                frames: std::mem::take(&mut ctx.frames),
+                stopped_reason: job.reason,
+                frame_timestamps: job.frame_timestamps.clone(),
+                content_warnings: ctx.content_warnings.clone(),
+                chunks: ctx.chunks.clone(),
+                loudness: ctx.loudness.clone(),
+                resolution,
+            };
+            if !ctx.chunks.is_empty() {
+                chunks::write_sidecar(&job.input, &ctx.chunks).await;
+            }
+            let sizes = ctx
+                .results()
+                .iter()
+                .map(|file| std::fs::metadata(file).ok().map(|m| m.len()))
+                .collect::<Option<Vec<u64>>>();
+            let entry = HistoryEntry {
+                started_at: Some(job.started_at),
+                file: Some(output.clone()),
+                size: sizes.map(|sizes| sizes.iter().sum()),
+                manifest: Some(
+                    checksums::manifest_path(std::path::Path::new(&output))
+                        .to_string_lossy()
+                        .to_string(),
+                ),
+                commands: ctx.commands.clone(),
+                frame_timestamps: job.frame_timestamps,
+                started_by: job.started_by,
+                stopped_by: job.stopped_by,
+                content_warnings: std::mem::take(&mut ctx.content_warnings),
+                first_frame: job.first_frame,
+                min_health: ctx.mx.health.take_low(),
+                ..HistoryEntry::new("done", Local::now(), job.options.owner.clone())
+            };
+            if let Some(size) = entry.size {
+                ctx.mx.quotas.add(entry.owner.as_deref(), size);
+            }
+            let appended = match ctx.durable {
+                true => ctx.mx.history.append_durable(entry, &ctx.mx.fsync),
+                false => ctx.mx.history.append(entry),
+            };
+            if let Err(e) = appended {
+                warn!("cannot write history: {}", e);
+            }
+            // Done once the result and its history are on disk, when they must be
+            ctx.mx.set(done).await;
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+/// Transcribes the recording when the options ask for it, once it is reported done
+pub struct Transcribe;
+
+impl Stage for Transcribe {
+    fn name(&self) -> &'static str {
+        TRANSCRIBE
+    }
+
+    fn run<'a>(&'a self, ctx: &'a mut Context) -> BoxFuture<'a, anyhow::Result<Flow>> {
+        Box::pin(async move {
+            let Some(req) = &ctx.options.transcribe else {
+                return Ok(Flow::Continue);
+            };
+            let mx = &ctx.mx;
+            let started_at = ctx
+                .job
+                .as_ref()
+                .map_or_else(Local::now, |(_, job)| job.started_at);
+            for file in ctx.results() {
+                let values = template::Values::new(started_at, ctx.options.owner.as_deref())
+                    .with_profile(ctx.options.encoder_profile.as_deref())
+                    .with_file(&file);
+                if let Err(e) =
+                    transcripts::transcribe(&file, req, &values, &mx.transcribers, &mx.children)
+                        .await
+                {
+                    warn!("cannot transcribe {}: {}", file, e);
+                }
+            }
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+/// Starts making the contact sheet of the recording when the options ask for it, of its first
+/// chunk
+pub struct ContactSheet;
+
+impl Stage for ContactSheet {
+    fn name(&self) -> &'static str {
+        CONTACT_SHEET
+    }
+
+    fn run<'a>(&'a self, ctx: &'a mut Context) -> BoxFuture<'a, anyhow::Result<Flow>> {
+        Box::pin(async move {
+            if ctx.options.auto_contact_sheet && !ctx.removes_upload() {
+                let source = std::path::Path::new(&ctx.file);
+                let req = contact_sheet::SheetRequest::default();
+                ctx.mx.sheets.start(&ctx.mx, source, req);
+            }
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+/// Writes the checksum manifest in the background, and names the result by its hash on request
+pub struct Checksums;
+
+impl Stage for Checksums {
+    fn name(&self) -> &'static str {
+        CHECKSUMS
+    }
+
+    fn run<'a>(&'a self, ctx: &'a mut Context) -> BoxFuture<'a, anyhow::Result<Flow>> {
+        Box::pin(async move {
+            // the object store has its own
+            if ctx.removes_upload() {
+                return Ok(Flow::Continue);
+            }
+            let results = ctx.results();
+            let options = ctx.options.clone();
+            let throttle = ctx.mx.throttle.clone();
+            // runs on after the stage, in the span of the recording
+            let span = info_span!(parent: &ctx.span, "background", name = CHECKSUMS);
+            let checksums = async move {
+                for output in &results {
+                    let path = std::path::Path::new(output);
+                    let manifest = match checksums::write_manifest(path, throttle.clone()).await {
+                        Ok(manifest) => manifest,
+                        Err(e) => {
+                            warn!("cannot write the checksums of {}: {}", output, e);
+                            continue;
+                        }
+                    };
+                    info!("{} sha256 {}", output, manifest.sha256);
+                    if options.content_addressed {
+                        match recordings::address_by_hash(path, &manifest, options.hash_link) {
+                            Ok(hashed) => info!("{} is {}", output, hashed.display()),
+                            Err(e) => warn!("cannot name {} by its hash: {}", output, e),
+                        }
+                    }
+                }
+            };
+            tokio::spawn(checksums.instrument(span));
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+/// Removes the raw capture, its segments and the audio segments, unless one of them is the result
+pub struct Cleanup;
+
+impl Stage for Cleanup {
+    fn name(&self) -> &'static str {
+        CLEANUP
+    }
+
+    fn run<'a>(&'a self, ctx: &'a mut Context) -> BoxFuture<'a, anyhow::Result<Flow>> {
+        Box::pin(async move {
+            let (_, job) = ctx.job()?;
+            let joined = quality::joined_path(&job.input);
+            if job.options.show_clicks {
+                let _ = std::fs::remove_file(cursor::clicks_path(&job.input));
+            }
+            if job.options.keep_original {
+                // the segments are the original, their join is not
+                if joined != ctx.file {
+                    let _ = std::fs::remove_file(&joined);
+                }
+                info!("done {}", ctx.file);
+                return Ok(Flow::Continue);
+            }
+            // remove local "input" file, ignore error
+            if job.input != ctx.file {
+                let _ = std::fs::remove_file(&job.input);
+            }
+            for segment in &job.segments {
+                let _ = std::fs::remove_file(&segment.file);
+            }
+            let video = job.video_segments.iter().map(|s| &s.file);
+            for file in video.chain(&job.chunks).chain([&joined]) {
+                if *file != ctx.file && !ctx.chunks.contains(file) {
+                    let _ = std::fs::remove_file(file);
+                }
+            }
+            info!("done {}", ctx.file);
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+/// Removes the local result once it is in the bucket, with `--delete-after-upload`
+///
+/// Its sidecars stay, the contact sheet and the checksums are not made of a result removed.
+pub struct RemoveUploaded;
+
+impl Stage for RemoveUploaded {
+    fn name(&self) -> &'static str {
+        REMOVE_UPLOADED
+    }
+
+    fn run<'a>(&'a self, ctx: &'a mut Context) -> BoxFuture<'a, anyhow::Result<Flow>> {
+        Box::pin(async move {
+            if !ctx.removes_upload() {
+                return Ok(Flow::Continue);
+            }
+            for file in ctx.results() {
+                match tokio::fs::remove_file(&file).await {
+                    Ok(()) => info!("removed {}, it is in the bucket", file),
+                    Err(e) => warn!("cannot remove the uploaded {}: {}", file, e),
+                }
+            }
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the arguments of the x11grab input of the options
+    fn screen_args(opt: &RecordingOptions) -> Vec<String> {
+        let options = screen_input(opt, "1920x1080".into(), "30", ":0.0+0,0".into());
+        let builder = options
+            .iter()
+            .fold(FfmpegBuilder::new(), |builder, (k, v)| {
+                builder.option(Parameter::KeyValue(k, v))
+            });
+        argv(&builder.to_command().unwrap())[1..].to_vec()
+    }
+
+    fn hides_the_pointer(args: &[String]) -> bool {
+        args.windows(2)
+            .any(|w| w[0] == "-draw_mouse" && w[1] == "0")
+    }
+
+    #[test]
+    fn the_pointer_is_drawn_unless_asked_otherwise() {
+        let opt = RecordingOptions::default();
+        let args = screen_args(&opt);
+        assert!(!args.contains(&"-draw_mouse".to_string()), "{:?}", args);
+        let opt = RecordingOptions {
+            draw_mouse: Some(true),
+            ..Default::default()
+        };
+        assert!(!screen_args(&opt).contains(&"-draw_mouse".to_string()));
+    }
+
+    #[test]
+    fn a_hidden_pointer_is_not_drawn() {
+        let opt = RecordingOptions {
+            draw_mouse: Some(false),
+            ..Default::default()
+        };
+        assert!(hides_the_pointer(&screen_args(&opt)));
+    }
+
+    #[test]
+    fn a_tracked_pointer_is_drawn_later() {
+        let opt = RecordingOptions {
+            cursor_track: true,
+            ..Default::default()
+        };
+        assert!(hides_the_pointer(&screen_args(&opt)));
+    }
+
+    #[test]
+    fn the_input_options_come_before_the_display() {
+        let opt = RecordingOptions {
+            draw_mouse: Some(false),
+            use_shm: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(
+            screen_args(&opt),
+            [
+                "-f",
+                "x11grab",
+                "-use_shm",
+                "0",
+                "-draw_mouse",
+                "0",
+                "-video_size",
+                "1920x1080",
+                "-framerate",
+                "30",
+                "-i",
+                ":0.0+0,0"
+            ]
+        );
+    }
+
+    /// a stage that does nothing, by the name it is given
+    struct Named(&'static str);
+
+    impl Stage for Named {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn run<'a>(&'a self, _: &'a mut Context) -> BoxFuture<'a, anyhow::Result<Flow>> {
+            Box::pin(async { Ok(Flow::Continue) })
+        }
+    }
+
+    #[test]
+    fn the_standard_pipeline_runs_the_stages_of_the_server() {
+        let (start, finish) = Pipeline::default().stages();
+        assert_eq!(start, [PREFLIGHT, RESOLVE, CAPTURE]);
+        assert_eq!(
+            finish,
+            [
+                GATHER,
+                JOIN,
+                COMPRESS,
+                SYNC,
+                VERIFY_CONTENT,
+                FRAMES,
+                UPLOAD,
+                FINALIZE,
+                TRANSCRIBE,
+                CONTACT_SHEET,
+                CHECKSUMS,
+                CLEANUP,
+                REMOVE_UPLOADED
+            ]
+        );
+    }
+
+    #[test]
+    fn the_stages_are_swapped_by_their_name() {
+        let (start, finish) = PipelineBuilder::empty()
+            .start_stage(Named(PREFLIGHT))
+            .start_stage(Named(CAPTURE))
+            .then(Named(COMPRESS))
+            .then(Named(FINALIZE))
+            .after(PREFLIGHT, Named("approve"))
+            .after(COMPRESS, Named("watermark"))
+            .replace(Named(CAPTURE))
+            .without(FINALIZE)
+            // nothing to add it after
+            .after("missing", Named("lost"))
+            .build()
+            .stages();
+        assert_eq!(start, [PREFLIGHT, "approve", CAPTURE]);
+        assert_eq!(finish, [COMPRESS, "watermark"]);
+    }
+
+    #[tokio::test]
+    async fn a_recording_that_failed_over_is_finished_in_the_fallback() {
+        let dir = recordings::test_output_dir().join("gather");
+        let (primary, fallback) = (dir.join("primary"), dir.join("fallback"));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&fallback).unwrap();
+        // the primary can't be written any more, its probe can't be created there
+        std::fs::create_dir_all(primary.join(".record-screen-probe")).unwrap();
+        let first = primary.join("capture.mkv");
+        let second = fallback.join("capture.1.mkv");
+        std::fs::write(&first, b"first").unwrap();
+        std::fs::write(&second, b"second").unwrap();
+        let job: jobs::Compression = serde_json::from_value(serde_json::json!({
+            "input": first,
+            "output": primary.join("capture.mp4"),
+            "options": {},
+            "started_at": Local::now(),
+            "video_segments": [
+                {"file": first, "offset_ms": 0},
+                {"file": second, "offset_ms": 5000},
+            ],
+        }))
+        .unwrap();
+        let mx = Arc::new(Recorder::new().with_fallback_dir(Some(fallback.clone())));
+        let mut ctx = Context::new(mx, job.options.clone());
+        ctx.file = job.input.clone();
+        ctx.job = Some((1, job));
+        assert_eq!(Gather.run(&mut ctx).await.unwrap(), Flow::Continue);
+
+        let (_, job) = ctx.job().unwrap();
+        let files: Vec<&str> = job.video_segments.iter().map(|s| s.file.as_str()).collect();
+        let moved = fallback.join("capture.mkv");
+        assert_eq!(files, [moved.to_str().unwrap(), second.to_str().unwrap()]);
+        assert_eq!(std::fs::read(&moved).unwrap(), b"first");
+        assert_eq!(ctx.file, moved.to_string_lossy());
+        assert_eq!(job.output, fallback.join("capture.mp4").to_string_lossy());
+    }
+
+    /// remembers what was fsynced, in order
+    #[derive(Clone, Default)]
+    struct Synced(Arc<std::sync::Mutex<Vec<std::path::PathBuf>>>);
+
+    impl recordings::Fsync for Synced {
+        fn sync(&self, path: &std::path::Path) -> std::io::Result<()> {
+            self.0.lock().unwrap().push(path.to_path_buf());
+            Ok(())
+        }
+    }
+
+    /// run the end of the finish chain of a recording with `durability`, what was fsynced and
+    /// how many entries the history had when Done was reported
+    async fn finished(
+        name: &str,
+        durability: Durability,
+    ) -> (bool, Vec<std::path::PathBuf>, usize) {
+        let dir = recordings::test_output_dir().join(name);
+        std::fs::create_dir_all(&dir).unwrap();
+        let history = crate::history::History::open(&dir.join("history.jsonl")).unwrap();
+        let synced = Synced::default();
+        let mx = Arc::new(Recorder::with_history(history).with_fsync(synced.clone()));
+        let result = dir.join("result.mp4");
+        std::fs::write(&result, b"mp4").unwrap();
+        let options = RecordingOptions {
+            durability,
+            ..Default::default()
+        };
+        let job: jobs::Compression = serde_json::from_value(serde_json::json!({
+            "input": dir.join("capture.mkv"),
+            "output": result,
+            "options": options,
+            "started_at": Local::now(),
+        }))
+        .unwrap();
+        let mut ctx = Context::new(mx.clone(), options);
+        ctx.file = result.to_string_lossy().to_string();
+        ctx.job = Some((1, job));
+        let mut events = mx.events.subscribe(None, &RecordingState::Waiting);
+        let finish = tokio::spawn(async move {
+            MakeDurable.run(&mut ctx).await.unwrap();
+            Finalize.run(&mut ctx).await.unwrap();
+        });
+        let done = loop {
+            let event = events.live.recv().await.unwrap();
+            if let EventKind::State {
+                state: RecordingState::Done { durable, .. },
+            } = event.kind
+            {
+                let history = mx.history.page(&Default::default()).unwrap();
+                break (
+                    durable,
+                    synced.0.lock().unwrap().clone(),
+                    history.entries.len(),
+                );
+            }
+        };
+        finish.await.unwrap();
+        done
+    }
+
+    #[tokio::test]
+    async fn a_strict_recording_and_its_history_are_durable_before_it_is_done() {
+        let dir = recordings::test_output_dir().join("strict");
+        let (durable, synced, entries) = finished("strict", Durability::Strict).await;
+        assert!(durable);
+        // the result, then the history, each with its directory
+        assert_eq!(
+            synced,
+            [
+                dir.join("result.mp4"),
+                dir.clone(),
+                dir.join("history.jsonl"),
+                dir
+            ]
+        );
+        assert_eq!(entries, 1);
+    }
+
+    #[tokio::test]
+    async fn a_default_recording_is_not_fsynced() {
+        let (durable, synced, entries) = finished("default", Durability::Default).await;
+        assert!(!durable);
+        assert!(synced.is_empty());
+        assert_eq!(entries, 1);
+    }
+
+    /// the length of the compression of ten seconds of the test source trimmed by `trim`, with
+    /// the sidecar telling it
+    async fn trimmed(name: &str, trim: trim::Trim) -> (f64, trim::Trimmed) {
+        let dir = recordings::test_output_dir().join(name);
+        std::fs::create_dir_all(&dir).unwrap();
+        let capture = dir.join("capture.mkv");
+        let status = tokio::process::Command::new("ffmpeg")
+            .args(["-v", "error", "-y", "-f", "lavfi"])
+            .args(["-i", "testsrc=duration=10:size=160x120:rate=10"])
+            .arg(&capture)
+            .status()
+            .await
+            .unwrap();
+        assert!(status.success());
+        let result = dir.join("result.mp4");
+        let job: jobs::Compression = serde_json::from_value(serde_json::json!({
+            "input": capture,
+            "output": result,
+            "options": RecordingOptions::default(),
+            "started_at": Local::now(),
+            "trim": trim.window(10_000).unwrap(),
+        }))
+        .unwrap();
+        let mx = Arc::new(Recorder::new());
+        let mut ctx = Context::new(mx, RecordingOptions::default());
+        ctx.file = capture.to_string_lossy().to_string();
+        ctx.job = Some((1, job));
+        assert_eq!(Compress.run(&mut ctx).await.unwrap(), Flow::Continue);
+        let duration = probe::probe(&result).await.unwrap().duration.unwrap();
+        let sidecar = std::fs::read(trim::sidecar_path(&result)).unwrap();
+        (duration, serde_json::from_slice(&sidecar).unwrap())
+    }
+
+    #[tokio::test]
+    async fn the_result_is_trimmed_at_its_head_and_its_tail() {
+        if std::process::Command::new("ffmpeg")
+            .arg("-version")
+            .output()
+            .is_err()
+        {
+            eprintln!("ffmpeg is not installed, skipped");
+            return;
+        }
+        for (name, head_secs, tail_secs) in [
+            ("trim-head", 2.0, 0.0),
+            ("trim-tail", 0.0, 3.0),
+            ("trim-both", 2.0, 3.0),
+        ] {
+            let trim = trim::Trim {
+                head_secs,
+                tail_secs,
+            };
+            let (duration, sidecar) = trimmed(name, trim).await;
+            let expected = 10.0 - head_secs - tail_secs;
+            assert!((duration - expected).abs() < 0.3, "{}: {}s", name, duration);
+            assert_eq!(sidecar.full_secs, 10.0);
+            assert_eq!(sidecar.trimmed_secs, expected);
+        }
+    }
+}
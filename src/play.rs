@@ -0,0 +1,285 @@
+//! Playable copies of the recordings, for browsers that can't play what was recorded
+//!
+//! A copy is transcoded on the first request and kept next to the recording as
+//! `<recording>.play.webm` (or `.play.mp4`). The copy gets the modification time of its source,
+//! so a recording that changed afterwards is transcoded again; its access time is bumped on every
+//! hit, and the least recently played copies are evicted once all of them take more than the
+//! configured size. Requests for a copy being transcoded share the same job.
+use crate::ffmpeg::{FfmpegBuilder, File, Parameter};
+use crate::probe::probe;
+use crate::service::{ChildRole, Recorder};
+use crate::throttle::Category;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::FileTimes;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tracing::*;
+
+/// Format of the playable copy
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlayFormat {
+    /// VP9 and Opus
+    #[default]
+    Webm,
+    /// H.264 and AAC, for the players that can't use the raw recording
+    Mp4,
+}
+
+impl PlayFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            PlayFormat::Webm => "webm",
+            PlayFormat::Mp4 => "mp4",
+        }
+    }
+
+    fn options(&self) -> Vec<Parameter<'static>> {
+        match self {
+            PlayFormat::Webm => vec![
+                Parameter::codec("v", "libvpx-vp9"),
+                Parameter::StreamSpec {
+                    base: "b",
+                    specifier: "v",
+                    value: "0",
+                },
+                Parameter::KeyValue("crf", "35"),
+                Parameter::KeyValue("deadline", "realtime"),
+                Parameter::KeyValue("cpu-used", "8"),
+                Parameter::KeyValue("row-mt", "1"),
+                Parameter::codec("a", "libopus"),
+                Parameter::KeyValue("f", "webm"),
+            ],
+            PlayFormat::Mp4 => vec![
+                Parameter::codec("v", "libx264"),
+                Parameter::KeyValue("preset", "veryfast"),
+                Parameter::KeyValue("crf", "23"),
+                Parameter::KeyValue("pix_fmt", "yuv420p"),
+                Parameter::codec("a", "aac"),
+                Parameter::KeyValue("movflags", "+faststart"),
+                Parameter::KeyValue("f", "mp4"),
+            ],
+        }
+    }
+}
+
+/// the playable copies are not larger than this
+const MAX_WIDTH: u32 = 1280;
+
+/// A transcode in progress
+#[derive(Debug, Clone, Serialize)]
+pub struct PlayJob {
+    pub id: u64,
+    /// percent of the source transcoded so far
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub percent: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// What a play request gets
+pub enum Lookup {
+    /// the copy is ready
+    Ready(PathBuf),
+    /// the copy is being transcoded
+    Pending(PlayJob),
+    /// the transcode failed, the next request tries again
+    Failed(PlayJob),
+}
+
+/// The copies being transcoded, by the path of the copy
+#[derive(Default)]
+pub struct PlayCache {
+    /// all the copies together are not larger than this, no limit with 0
+    pub max_bytes: u64,
+    jobs: Mutex<HashMap<PathBuf, PlayJob>>,
+    next_id: AtomicU64,
+}
+
+/// path of the playable copy of a recording
+pub fn copy_path(source: &Path, format: PlayFormat) -> PathBuf {
+    let mut path = source.as_os_str().to_owned();
+    path.push(format!(".play.{}", format.extension()));
+    PathBuf::from(path)
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn is_copy(path: &Path) -> bool {
+    let name = path.to_string_lossy();
+    name.ends_with(".play.webm") || name.ends_with(".play.mp4")
+}
+
+impl PlayCache {
+    pub fn new(max_bytes: u64) -> Self {
+        Self {
+            max_bytes,
+            ..Default::default()
+        }
+    }
+
+    /// the copy of the source, starting its transcode when there is none yet
+    pub fn lookup(&self, mx: &Arc<Recorder>, source: &Path, format: PlayFormat) -> Lookup {
+        let copy = copy_path(source, format);
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.get(&copy) {
+            if job.error.is_some() {
+                let job = job.clone();
+                jobs.remove(&copy);
+                return Lookup::Failed(job);
+            }
+            return Lookup::Pending(job.clone());
+        }
+        if copy.is_file() && modified(&copy) == modified(source) {
+            touch(&copy);
+            return Lookup::Ready(copy);
+        }
+        let job = PlayJob {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            percent: None,
+            error: None,
+        };
+        jobs.insert(copy.clone(), job.clone());
+        drop(jobs);
+
+        let mx = mx.clone();
+        let source = source.to_path_buf();
+        tokio::spawn(async move {
+            let result = transcode(&mx, &source, &copy, format).await;
+            let mut jobs = mx.play.jobs.lock().unwrap();
+            match result {
+                Ok(()) => {
+                    jobs.remove(&copy);
+                    drop(jobs);
+                    if let Some(dir) = copy.parent() {
+                        mx.play.evict(dir, &copy);
+                    }
+                }
+                Err(e) => {
+                    warn!("cannot transcode {} for playing: {}", source.display(), e);
+                    if let Some(job) = jobs.get_mut(&copy) {
+                        job.error = Some(e.to_string());
+                    }
+                }
+            }
+        });
+        Lookup::Pending(job)
+    }
+
+    fn set_percent(&self, copy: &Path, percent: f64) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(copy) {
+            job.percent = Some(percent);
+        }
+    }
+
+    /// remove the least recently played copies until they fit, keeping `keep`
+    fn evict(&self, dir: &Path, keep: &Path) {
+        if self.max_bytes == 0 {
+            return;
+        }
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+        let mut copies: Vec<(SystemTime, u64, PathBuf)> = entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| is_copy(p))
+            .filter_map(|p| {
+                let meta = std::fs::metadata(&p).ok()?;
+                Some((meta.accessed().ok()?, meta.len(), p))
+            })
+            .collect();
+        let mut total: u64 = copies.iter().map(|(_, size, _)| size).sum();
+        copies.sort();
+        for (_, size, path) in copies {
+            if total <= self.max_bytes {
+                break;
+            }
+            if path == keep {
+                continue;
+            }
+            match std::fs::remove_file(&path) {
+                Ok(()) => {
+                    info!("evicted {} from the play cache", path.display());
+                    total -= size;
+                }
+                Err(e) => warn!("cannot evict {}: {}", path.display(), e),
+            }
+        }
+    }
+}
+
+/// mark the copy as played now
+fn touch(copy: &Path) {
+    if let Ok(file) = std::fs::File::options().write(true).open(copy) {
+        let _ = file.set_times(FileTimes::new().set_accessed(SystemTime::now()));
+    }
+}
+
+async fn transcode(
+    mx: &Arc<Recorder>,
+    source: &Path,
+    copy: &Path,
+    format: PlayFormat,
+) -> anyhow::Result<()> {
+    let duration = probe(source).await?.duration.unwrap_or_default();
+    let mut partial = copy.as_os_str().to_owned();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+
+    let input = source.to_string_lossy().to_string();
+    let output = partial.to_string_lossy().to_string();
+    let scale = format!("scale='min({},iw)':-2", MAX_WIDTH);
+    let readrate = mx.throttle.readrate(Category::Transcode, source).await;
+    let mut file = File::new(&input);
+    if let Some((speed, _)) = &readrate {
+        file = file.option(Parameter::KeyValue("readrate", speed));
+    }
+    let mut builder = FfmpegBuilder::new()
+        .stderr(Stdio::piped())
+        .option(Parameter::Single("y"))
+        .input(file)
+        .option2(Parameter::KeyValue("vf", &scale));
+    for option in format.options() {
+        builder = builder.option2(option);
+    }
+    let ffmpeg = builder.output(File::new(&output)).run().await?;
+    let pid = ffmpeg.id();
+    mx.children
+        .register(pid, ChildRole::Transcode, vec![output.clone()]);
+    mx.throttle.idle(pid);
+    let summary = ffmpeg
+        .wait_with_progress(|p| {
+            if let (Some(out_time), true) = (p.out_time, duration > 0.0) {
+                let percent = (out_time.as_secs_f64() / duration * 100.0).min(100.0);
+                mx.play.set_percent(copy, percent);
+            }
+        })
+        .await;
+    mx.children.unregister(pid);
+    let summary = summary?;
+    if !summary.success() {
+        let _ = std::fs::remove_file(&partial);
+        anyhow::bail!(
+            "ffmpeg exited with {}: {}",
+            summary.exit_status,
+            summary.stderr_tail.join("\n")
+        );
+    }
+
+    // the copy is valid for as long as the source keeps this modification time
+    let file = std::fs::File::options().write(true).open(&partial)?;
+    let mut times = FileTimes::new().set_accessed(SystemTime::now());
+    if let Some(modified) = modified(source) {
+        times = times.set_modified(modified);
+    }
+    file.set_times(times)?;
+    std::fs::rename(&partial, copy)?;
+    Ok(())
+}
@@ -0,0 +1,427 @@
+//! Time-of-day windows outside of which nothing may be recorded
+//!
+//! The windows are weekdays and wall clock times of the server's timezone (`TZ`), e.g.
+//! `mon-fri 08:00-18:00`. A window whose end is not after its start ends on the next day, so
+//! `fri 22:00-06:00` covers the night to Saturday. Everything is computed on the local wall clock,
+//! which keeps a window of `08:00-18:00` at those hours on both sides of a DST change.
+//!
+//! Without any window, recording is always allowed.
+use crate::events::EventKind;
+use crate::service::{stop_for, Recorder, RecordingState, StopReason};
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDateTime, NaiveTime, TimeZone, Weekday};
+use serde::Serialize;
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::*;
+
+/// how many minutes before the end of a window the recording is warned about
+pub const WARN_BEFORE_MINUTES: i64 = 5;
+/// how often the watcher looks at the clock
+const WATCH_EVERY: std::time::Duration = std::time::Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordingWindow {
+    pub days: Vec<Weekday>,
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl FromStr for RecordingWindow {
+    type Err = String;
+
+    /// `<days> <HH:MM>-<HH:MM>`, days being `daily`, `mon`, `mon-fri` or `sat,sun`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (days, times) = s
+            .trim()
+            .split_once(' ')
+            .ok_or("expected '<days> <HH:MM>-<HH:MM>'")?;
+        let (start, end) = times
+            .trim()
+            .split_once('-')
+            .ok_or("expected a time range like 08:00-18:00")?;
+        Ok(Self {
+            days: parse_days(days)?,
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+        })
+    }
+}
+
+pub fn parse_time(s: &str) -> Result<NaiveTime, String> {
+    if s == "24:00" {
+        return Ok(NaiveTime::MIN);
+    }
+    NaiveTime::parse_from_str(s, "%H:%M").map_err(|e| format!("{}: {}", s, e))
+}
+
+pub fn parse_days(s: &str) -> Result<Vec<Weekday>, String> {
+    let weekday = |d: &str| Weekday::from_str(d).map_err(|_| format!("unknown weekday {}", d));
+    if s == "daily" || s == "*" {
+        return Ok(vec![
+            Weekday::Mon,
+            Weekday::Tue,
+            Weekday::Wed,
+            Weekday::Thu,
+            Weekday::Fri,
+            Weekday::Sat,
+            Weekday::Sun,
+        ]);
+    }
+    let mut days = vec![];
+    for part in s.split(',') {
+        match part.split_once('-') {
+            Some((from, to)) => {
+                let (mut day, to) = (weekday(from)?, weekday(to)?);
+                days.push(day);
+                while day != to {
+                    day = day.succ();
+                    days.push(day);
+                }
+            }
+            None => days.push(weekday(part)?),
+        }
+    }
+    Ok(days)
+}
+
+/// Whether recording is allowed now, and when that changes
+#[derive(Debug, Clone, Serialize)]
+pub struct PolicyStatus {
+    pub allowed: bool,
+    /// end of the current window
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub until: Option<DateTime<Local>>,
+    /// start of the next window
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_window: Option<DateTime<Local>>,
+}
+
+/// The recording windows and how strictly their end is enforced
+#[derive(Debug, Clone)]
+pub struct Policy {
+    pub windows: Vec<RecordingWindow>,
+    /// how long a recording may run past the end of its window
+    pub grace: Duration,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            windows: vec![],
+            grace: Duration::minutes(1),
+        }
+    }
+}
+
+impl Policy {
+    /// the windows around `now` on the local wall clock, merged where they touch
+    fn intervals(&self, now: NaiveDateTime) -> Vec<(NaiveDateTime, NaiveDateTime)> {
+        let mut intervals = vec![];
+        // a window of yesterday can still be open, the next one is at most a week away
+        for offset in -1..=7 {
+            let date = now.date() + Duration::days(offset);
+            for window in &self.windows {
+                if !window.days.contains(&date.weekday()) {
+                    continue;
+                }
+                let start = date.and_time(window.start);
+                let mut end = date.and_time(window.end);
+                if end <= start {
+                    end += Duration::days(1);
+                }
+                intervals.push((start, end));
+            }
+        }
+        intervals.sort();
+        let mut merged: Vec<(NaiveDateTime, NaiveDateTime)> = vec![];
+        for (start, end) in intervals {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        merged
+    }
+
+    pub fn status(&self, now: DateTime<Local>) -> PolicyStatus {
+        if self.windows.is_empty() {
+            return PolicyStatus {
+                allowed: true,
+                until: None,
+                next_window: None,
+            };
+        }
+        let wall = now.naive_local();
+        let intervals = self.intervals(wall);
+        let current = intervals.iter().find(|(s, e)| *s <= wall && wall < *e);
+        let next = intervals.iter().find(|(s, _)| *s > wall);
+        PolicyStatus {
+            allowed: current.is_some(),
+            until: current.map(|(_, e)| to_local(*e)),
+            next_window: next.map(|(s, _)| to_local(*s)),
+        }
+    }
+}
+
+/// the instant of a wall clock time, moved past the gap when the clocks jump forward
+pub fn to_local(wall: NaiveDateTime) -> DateTime<Local> {
+    in_zone(&Local, wall)
+}
+
+/// [to_local] in the timezone `tz`, the first of the two instants when the clocks go back
+fn in_zone<Tz: TimeZone>(tz: &Tz, wall: NaiveDateTime) -> DateTime<Tz> {
+    let mut wall = wall;
+    loop {
+        if let Some(t) = tz.from_local_datetime(&wall).earliest() {
+            return t;
+        }
+        wall += Duration::minutes(15);
+    }
+}
+
+/// stop the recordings running past the end of their window, warning about it beforehand
+pub async fn watch(mx: Arc<Recorder>) {
+    if mx.policy.windows.is_empty() {
+        return;
+    }
+    let mut warned: Option<DateTime<Local>> = None;
+    // the end of the window seen last, the grace period runs from it once it's over
+    let mut window_end: Option<DateTime<Local>> = None;
+    loop {
+        tokio::time::sleep(WATCH_EVERY).await;
+        let started_at = match mx.lock().await.recording() {
+            RecordingState::Started { started_at, .. } => *started_at,
+            _ => continue,
+        };
+        let now = Local::now();
+        let status = mx.policy.status(now);
+        if status.until.is_some() {
+            window_end = status.until;
+        }
+        let end = match window_end {
+            Some(end) => end,
+            // it was started outside of any window
+            None => now - mx.policy.grace,
+        };
+        if now >= end + mx.policy.grace {
+            info!("recording window closed at {}, stopping", end);
+            let mx = mx.clone();
+            tokio::spawn(async move {
+                if let Err(e) = stop_for(mx, Some(StopReason::OutsideAllowedWindow)).await {
+                    warn!("cannot stop at the end of the window: {}", e);
+                }
+            });
+        } else if now >= end - Duration::minutes(WARN_BEFORE_MINUTES) && warned != Some(started_at)
+        {
+            warned = Some(started_at);
+            let message = format!(
+                "the recording window closes at {}, the recording stops then",
+                end + mx.policy.grace
+            );
+            warn!("{}", message);
+            mx.events.publish(EventKind::Notice { message });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{FixedOffset, LocalResult, NaiveDate};
+
+    fn wall(y: i32, m: u32, d: u32, h: u32, min: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(y, m, d)
+            .unwrap()
+            .and_hms_opt(h, min, 0)
+            .unwrap()
+    }
+
+    fn policy(windows: &[&str]) -> Policy {
+        Policy {
+            windows: windows.iter().map(|w| w.parse().unwrap()).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// the status at a wall clock time of January, far from any change of the clocks
+    fn status_at(policy: &Policy, wall: NaiveDateTime) -> PolicyStatus {
+        policy.status(to_local(wall))
+    }
+
+    #[test]
+    fn windows_are_parsed() {
+        let window: RecordingWindow = "mon-wed,sat 08:00-18:30".parse().unwrap();
+        assert_eq!(
+            window.days,
+            [Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Sat]
+        );
+        assert_eq!(window.start, NaiveTime::from_hms_opt(8, 0, 0).unwrap());
+        assert_eq!(window.end, NaiveTime::from_hms_opt(18, 30, 0).unwrap());
+        // a range of days may wrap around the week
+        assert_eq!(
+            parse_days("sat-mon").unwrap(),
+            [Weekday::Sat, Weekday::Sun, Weekday::Mon]
+        );
+        assert_eq!(parse_days("daily").unwrap().len(), 7);
+        assert_eq!(parse_time("24:00").unwrap(), NaiveTime::MIN);
+        assert!("mon 08:00".parse::<RecordingWindow>().is_err());
+        assert!("someday 08:00-18:00".parse::<RecordingWindow>().is_err());
+        assert!("mon 8h-18h".parse::<RecordingWindow>().is_err());
+    }
+
+    #[test]
+    fn without_windows_recording_is_always_allowed() {
+        let status = status_at(&policy(&[]), wall(2024, 1, 10, 3, 0));
+        assert!(status.allowed);
+        assert_eq!(status.until, None);
+        assert_eq!(status.next_window, None);
+    }
+
+    #[test]
+    fn a_window_is_open_from_its_start_to_its_end() {
+        let policy = policy(&["mon-fri 08:00-18:00"]);
+        // 2024-01-10 is a Wednesday
+        let status = status_at(&policy, wall(2024, 1, 10, 8, 0));
+        assert!(status.allowed);
+        assert_eq!(status.until, Some(to_local(wall(2024, 1, 10, 18, 0))));
+        assert_eq!(status.next_window, Some(to_local(wall(2024, 1, 11, 8, 0))));
+        let status = status_at(&policy, wall(2024, 1, 10, 18, 0));
+        assert!(!status.allowed);
+        assert_eq!(status.until, None);
+        // nothing on the weekend
+        let status = status_at(&policy, wall(2024, 1, 12, 18, 30));
+        assert_eq!(status.next_window, Some(to_local(wall(2024, 1, 15, 8, 0))));
+    }
+
+    #[test]
+    fn a_window_ending_before_its_start_crosses_midnight() {
+        let policy = policy(&["fri 22:00-06:00"]);
+        // 2024-01-12 is a Friday, the night goes on into Saturday
+        let status = status_at(&policy, wall(2024, 1, 12, 23, 59));
+        assert!(status.allowed);
+        assert_eq!(status.until, Some(to_local(wall(2024, 1, 13, 6, 0))));
+        let status = status_at(&policy, wall(2024, 1, 13, 0, 0));
+        assert!(status.allowed);
+        let status = status_at(&policy, wall(2024, 1, 13, 6, 0));
+        assert!(!status.allowed);
+        assert_eq!(status.next_window, Some(to_local(wall(2024, 1, 19, 22, 0))));
+        // only the night from Friday
+        assert!(!status_at(&policy, wall(2024, 1, 12, 3, 0)).allowed);
+    }
+
+    #[test]
+    fn a_window_of_sunday_night_is_open_on_monday() {
+        let policy = policy(&["sun 22:00-02:00"]);
+        // 2024-01-15 is a Monday
+        let status = status_at(&policy, wall(2024, 1, 15, 1, 0));
+        assert!(status.allowed);
+        assert_eq!(status.until, Some(to_local(wall(2024, 1, 15, 2, 0))));
+    }
+
+    #[test]
+    fn a_window_to_midnight_ends_at_the_next_day() {
+        let policy = policy(&["wed 20:00-24:00"]);
+        let status = status_at(&policy, wall(2024, 1, 10, 23, 30));
+        assert!(status.allowed);
+        assert_eq!(status.until, Some(to_local(wall(2024, 1, 11, 0, 0))));
+    }
+
+    #[test]
+    fn windows_that_touch_are_merged() {
+        let nights = policy(&["daily 20:00-24:00", "daily 00:00-06:00"]);
+        // the evening goes on into the morning
+        let status = status_at(&nights, wall(2024, 1, 10, 21, 0));
+        assert!(status.allowed);
+        assert_eq!(status.until, Some(to_local(wall(2024, 1, 11, 6, 0))));
+        assert_eq!(status.next_window, Some(to_local(wall(2024, 1, 11, 20, 0))));
+        let overlapping = policy(&["mon 08:00-12:00", "mon 10:00-14:00"]);
+        let status = status_at(&overlapping, wall(2024, 1, 15, 9, 0));
+        assert_eq!(status.until, Some(to_local(wall(2024, 1, 15, 14, 0))));
+    }
+
+    /// Central European time of 2024, CEST from 03-31 01:00 to 10-27 01:00 UTC
+    #[derive(Debug, Clone, Copy)]
+    struct Cet;
+
+    impl Cet {
+        fn winter() -> FixedOffset {
+            FixedOffset::east_opt(3600).unwrap()
+        }
+
+        fn summer() -> FixedOffset {
+            FixedOffset::east_opt(7200).unwrap()
+        }
+    }
+
+    impl TimeZone for Cet {
+        type Offset = FixedOffset;
+
+        fn from_offset(_: &FixedOffset) -> Self {
+            Cet
+        }
+
+        fn offset_from_local_date(&self, local: &NaiveDate) -> LocalResult<FixedOffset> {
+            self.offset_from_local_datetime(&local.and_hms_opt(12, 0, 0).unwrap())
+        }
+
+        fn offset_from_local_datetime(&self, local: &NaiveDateTime) -> LocalResult<FixedOffset> {
+            let winter = self.offset_from_utc_datetime(&(*local - Duration::hours(1)));
+            let summer = self.offset_from_utc_datetime(&(*local - Duration::hours(2)));
+            match (winter == Cet::winter(), summer == Cet::summer()) {
+                (true, true) => LocalResult::Ambiguous(Cet::summer(), Cet::winter()),
+                (true, false) => LocalResult::Single(Cet::winter()),
+                (false, true) => LocalResult::Single(Cet::summer()),
+                (false, false) => LocalResult::None,
+            }
+        }
+
+        fn offset_from_utc_date(&self, utc: &NaiveDate) -> FixedOffset {
+            self.offset_from_utc_datetime(&utc.and_hms_opt(0, 0, 0).unwrap())
+        }
+
+        fn offset_from_utc_datetime(&self, utc: &NaiveDateTime) -> FixedOffset {
+            if wall(2024, 3, 31, 1, 0) <= *utc && *utc < wall(2024, 10, 27, 1, 0) {
+                Cet::summer()
+            } else {
+                Cet::winter()
+            }
+        }
+    }
+
+    #[test]
+    fn a_time_the_clocks_skip_is_moved_past_the_gap() {
+        let t = in_zone(&Cet, wall(2024, 3, 31, 2, 30));
+        assert_eq!(t.naive_local(), wall(2024, 3, 31, 3, 0));
+        assert_eq!(t.naive_utc(), wall(2024, 3, 31, 1, 0));
+        // outside of the gap, it is the time asked for
+        let t = in_zone(&Cet, wall(2024, 3, 31, 1, 59));
+        assert_eq!(t.naive_utc(), wall(2024, 3, 31, 0, 59));
+    }
+
+    #[test]
+    fn a_time_the_clocks_repeat_is_the_first_one() {
+        let t = in_zone(&Cet, wall(2024, 10, 27, 2, 30));
+        assert_eq!(t.naive_utc(), wall(2024, 10, 27, 0, 30));
+    }
+
+    #[test]
+    fn a_window_keeps_its_wall_clock_hours_over_a_change() {
+        let policy = policy(&["daily 01:00-05:00"]);
+        let lasts = |day: NaiveDateTime| {
+            let (start, end) = policy
+                .intervals(day)
+                .into_iter()
+                .find(|(s, _)| s.date() == day.date())
+                .unwrap();
+            assert_eq!(
+                (start.time(), end.time()),
+                (day.time(), wall(2024, 1, 1, 5, 0).time())
+            );
+            in_zone(&Cet, end) - in_zone(&Cet, start)
+        };
+        // an hour less in spring, an hour more in autumn
+        assert_eq!(lasts(wall(2024, 3, 30, 1, 0)), Duration::hours(4));
+        assert_eq!(lasts(wall(2024, 3, 31, 1, 0)), Duration::hours(3));
+        assert_eq!(lasts(wall(2024, 10, 27, 1, 0)), Duration::hours(5));
+    }
+}
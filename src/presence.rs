@@ -0,0 +1,202 @@
+//! Who is using the server right now
+//!
+//! Informational only, nothing is refused on it. A client names itself with the
+//! [CLIENT_HEADER] header, or the `client` query parameter where it can't set headers, as an
+//! `EventSource` can't. [track] gives every request its [Identity], and the requests carrying a
+//! name keep that client present for [TTL]. An event stream keeps its client present until it
+//! disconnects, then the client is gone. The mutating requests leave their identity in the state
+//! and the history.
+use crate::auth::Role;
+use crate::service::Recorder;
+use axum::body::Body;
+use axum::extract::{ConnectInfo, Query};
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// header naming the client
+pub const CLIENT_HEADER: &str = "x-client-name";
+/// how long a client without a stream stays present after its last named request
+pub const TTL: Duration = Duration::from_secs(60);
+/// longer names are cut
+const MAX_NAME: usize = 64;
+
+/// Who sent a request
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Identity {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpAddr>,
+    /// of the API token of the request, which names it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<Role>,
+}
+
+impl std::fmt::Display for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.name, &self.ip) {
+            (Some(name), Some(ip)) => write!(f, "{} ({})", name, ip),
+            (Some(name), None) => write!(f, "{}", name),
+            (None, Some(ip)) => write!(f, "{}", ip),
+            (None, None) => write!(f, "unknown"),
+        }
+    }
+}
+
+/// A client that is present
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Client {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpAddr>,
+    pub watching_since: DateTime<Local>,
+}
+
+struct Entry {
+    identity: Identity,
+    since: DateTime<Local>,
+    last_seen: Instant,
+    /// open event streams
+    streams: usize,
+}
+
+/// The clients present, keyed by their name or, for the unnamed streams, their address
+#[derive(Default)]
+pub struct Presence {
+    clients: Mutex<HashMap<String, Entry>>,
+}
+
+fn key(identity: &Identity) -> String {
+    match (&identity.name, &identity.ip) {
+        (Some(name), _) => format!("name:{}", name),
+        (None, Some(ip)) => format!("ip:{}", ip),
+        (None, None) => "unknown".to_string(),
+    }
+}
+
+impl Presence {
+    /// the client sent a request: present for [TTL] more
+    pub fn seen(&self, identity: &Identity) {
+        self.entry(identity, |_| {});
+    }
+
+    /// the client opened an event stream: present until the guard is dropped
+    pub fn connect(self: &Arc<Self>, identity: &Identity) -> StreamGuard {
+        self.entry(identity, |entry| entry.streams += 1);
+        StreamGuard {
+            presence: self.clone(),
+            key: key(identity),
+        }
+    }
+
+    fn entry(&self, identity: &Identity, update: impl FnOnce(&mut Entry)) {
+        let mut clients = self.clients.lock().unwrap();
+        let entry = clients.entry(key(identity)).or_insert_with(|| Entry {
+            identity: identity.clone(),
+            since: Local::now(),
+            last_seen: Instant::now(),
+            streams: 0,
+        });
+        entry.last_seen = Instant::now();
+        // the address of a named client is its latest one
+        entry.identity.ip = identity.ip.or(entry.identity.ip);
+        update(entry);
+    }
+
+    /// the clients present, the expired ones dropped, longest present first
+    pub fn list(&self) -> Vec<Client> {
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain(|_, e| e.streams > 0 || now.duration_since(e.last_seen) < TTL);
+        let mut list: Vec<Client> = clients
+            .values()
+            .map(|e| Client {
+                name: e.identity.name.clone(),
+                ip: e.identity.ip,
+                watching_since: e.since,
+            })
+            .collect();
+        list.sort_by_key(|c| c.watching_since);
+        list
+    }
+}
+
+/// Keeps the client of an event stream present
+pub struct StreamGuard {
+    presence: Arc<Presence>,
+    key: String,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        let mut clients = self.presence.clients.lock().unwrap();
+        if let Some(entry) = clients.get_mut(&self.key) {
+            entry.streams = entry.streams.saturating_sub(1);
+            // gone with its last stream, a polling client is back with its next request
+            if entry.streams == 0 {
+                clients.remove(&self.key);
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ClientQuery {
+    client: Option<String>,
+}
+
+/// the name of the client, printable and short
+fn clean(name: &str) -> Option<String> {
+    let name: String = name
+        .trim()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_NAME)
+        .collect();
+    (!name.is_empty()).then_some(name)
+}
+
+/// who sent the request, from its header or query and its connection
+pub fn identify(req: &Request<Body>) -> Identity {
+    let name = req
+        .headers()
+        .get(CLIENT_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
+        .or_else(|| {
+            Query::<ClientQuery>::try_from_uri(req.uri())
+                .ok()
+                .and_then(|q| q.0.client)
+        })
+        .and_then(|name| clean(&name));
+    let ip = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    Identity {
+        name,
+        ip,
+        role: None,
+    }
+}
+
+/// give the request its [Identity], keeping a named client present
+pub async fn track(mut req: Request<Body>, next: Next<Body>) -> Response {
+    let identity = identify(&req);
+    if identity.name.is_some() {
+        if let Some(mx) = req.extensions().get::<Arc<Recorder>>() {
+            mx.presence.seen(&identity);
+        }
+    }
+    req.extensions_mut().insert(identity);
+    next.run(req).await
+}
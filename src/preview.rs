@@ -0,0 +1,84 @@
+//! Watching the capture from the browser while it is recorded
+//!
+//! With `live_preview`, the capture has a second output: an HLS stream of [SEGMENT_SECONDS]
+//! segments and its [PLAYLIST], written to a directory of its own in the temporary directory, see
+//! [dir]. The playlist keeps the last [LIST_SIZE] segments and ffmpeg deletes the older ones, so
+//! the preview takes a few seconds of video on the disk however long the recording is. A screen
+//! capture is encoded again for it, smaller and as fast as libx264 goes; a stream that is copied
+//! is copied to the preview as well.
+//!
+//! The preview goes on over the segments of a capture rolled over: the playlist is never ended
+//! and its segments are numbered by the time they are written, a player following it takes the
+//! segments of the next capture for the next ones. `GET /api/preview/:file` serves the files of
+//! the preview of the running recording, [URL] being the playlist for hls.js, and the directory
+//! is removed once the capture is stopped or cancelled.
+use std::path::{Path, PathBuf};
+use tracing::*;
+
+/// the length of a segment of the preview
+pub const SEGMENT_SECONDS: u32 = 2;
+/// the segments the playlist keeps
+pub const LIST_SIZE: u32 = 5;
+/// the playlist in the directory of the preview
+pub const PLAYLIST: &str = "index.m3u8";
+/// where the playlist of the running recording is served
+pub const URL: &str = "/api/preview/index.m3u8";
+/// the height the preview is scaled down to
+const HEIGHT: u32 = 720;
+
+/// the directory of the preview of a capture, named after its file
+pub fn dir(capture: &str) -> String {
+    let name = Path::new(capture).file_stem().unwrap_or_default();
+    std::env::temp_dir()
+        .join(format!("record-screen-preview.{}", name.to_string_lossy()))
+        .to_string_lossy()
+        .to_string()
+}
+
+/// the path of the playlist of the preview in `dir`
+pub fn playlist(dir: &str) -> String {
+    Path::new(dir).join(PLAYLIST).to_string_lossy().to_string()
+}
+
+/// the options of the output of the preview, of a capture at `framerate` or copied
+pub fn output_options(copy: bool, framerate: &str) -> Vec<(&'static str, String)> {
+    let mut options = vec![("map", "0:v".to_string())];
+    if copy {
+        options.extend([("map", "0:a?".to_string()), ("c", "copy".to_string())]);
+    } else {
+        // a keyframe opens every segment
+        let gop = framerate.parse::<u32>().unwrap_or(25) * SEGMENT_SECONDS;
+        options.extend([
+            ("vf", format!("scale=-2:'min({},ih)'", HEIGHT)),
+            ("c:v", "libx264".to_string()),
+            ("preset", "veryfast".to_string()),
+            ("tune", "zerolatency".to_string()),
+            ("pix_fmt", "yuv420p".to_string()),
+            ("g", gop.to_string()),
+        ]);
+    }
+    options.extend([
+        ("f", "hls".to_string()),
+        ("hls_time", SEGMENT_SECONDS.to_string()),
+        ("hls_list_size", LIST_SIZE.to_string()),
+        ("hls_flags", "delete_segments+omit_endlist".to_string()),
+        ("hls_start_number_source", "epoch".to_string()),
+    ]);
+    options
+}
+
+/// the file of the preview in `dir` a client asks for, None unless it is a playlist or a segment
+pub fn file(dir: &str, name: &str) -> Option<PathBuf> {
+    let preview = name.ends_with(".m3u8") || name.ends_with(".ts");
+    let plain = !name.starts_with('.') && !name.contains(['/', '\\']);
+    (preview && plain).then(|| Path::new(dir).join(name))
+}
+
+/// remove the directory of the preview with what is left in it
+pub async fn remove(dir: &str) {
+    match tokio::fs::remove_dir_all(dir).await {
+        Ok(()) => debug!("removed the preview {}", dir),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => warn!("cannot remove the preview {}: {}", dir, e),
+    }
+}
@@ -0,0 +1,101 @@
+//! Media information from ffprobe
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// What ffprobe knows about a media file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MediaInfo {
+    /// duration in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+    /// dimensions of the first video stream
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    /// codecs of the first video and audio streams
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub video_codec: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio_codec: Option<String>,
+    /// profile, pixel format and framerate (`25/1`) of the first video stream
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pix_fmt: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frame_rate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Output {
+    #[serde(default)]
+    format: Option<Format>,
+    #[serde(default)]
+    streams: Vec<Stream>,
+}
+
+#[derive(Deserialize)]
+struct Format {
+    duration: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Stream {
+    codec_type: Option<String>,
+    codec_name: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    profile: Option<String>,
+    pix_fmt: Option<String>,
+    r_frame_rate: Option<String>,
+}
+
+/// run ffprobe on the file
+pub async fn probe(path: &Path) -> anyhow::Result<MediaInfo> {
+    probe_args(&[path.to_string_lossy().to_string()]).await
+}
+
+/// run ffprobe with the given input arguments, e.g. the options and `-i` of a stream
+pub async fn probe_args(input: &[String]) -> anyhow::Result<MediaInfo> {
+    let output = tokio::process::Command::new("ffprobe")
+        .args(["-v", "error", "-of", "json"])
+        .args([
+            "-show_entries",
+            "format=duration:stream=codec_type,codec_name,width,height,profile,pix_fmt,r_frame_rate",
+        ])
+        .args(input)
+        .kill_on_drop(true)
+        .output()
+        .await
+        .context("cannot run ffprobe")?;
+    if !output.status.success() {
+        bail!(
+            "ffprobe failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let parsed: Output = serde_json::from_slice(&output.stdout)?;
+    let video = parsed.streams.iter().find(|s| s.width.is_some());
+    let codec = |kind: &str| {
+        parsed
+            .streams
+            .iter()
+            .find(|s| s.codec_type.as_deref() == Some(kind))
+            .and_then(|s| s.codec_name.clone())
+    };
+    Ok(MediaInfo {
+        duration: parsed
+            .format
+            .and_then(|f| f.duration)
+            .and_then(|d| d.parse().ok()),
+        width: video.and_then(|s| s.width),
+        height: video.and_then(|s| s.height),
+        video_codec: codec("video"),
+        audio_codec: codec("audio"),
+        profile: video.and_then(|s| s.profile.clone()),
+        pix_fmt: video.and_then(|s| s.pix_fmt.clone()),
+        frame_rate: video.and_then(|s| s.r_frame_rate.clone()),
+    })
+}
@@ -0,0 +1,338 @@
+//! Error responses of the HTTP API, as RFC 7807 `application/problem+json`
+//!
+//! Handlers return an [ApiError]. The responses axum and tower-http produce themselves
+//! (rejected JSON, unknown routes, wrong methods, bodies over the limit) are rewritten to the same
+//! format by [rewrite_rejections], and every problem carries the id of its request.
+use axum::body::{Body, HttpBody};
+use axum::http::{header, HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub const CONTENT_TYPE: &str = "application/problem+json";
+/// header with the id of the request, taken from the client when it sends one
+pub const REQUEST_ID: &str = "x-request-id";
+
+/// the plain text of a rejection is kept as the detail up to this size
+const MAX_DETAIL: usize = 4096;
+
+tokio::task_local! {
+    static CURRENT_REQUEST: String;
+}
+
+/// Class of an error, its `type` is stable for the clients to match on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProblemType {
+    Validation,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    Conflict,
+    PayloadTooLarge,
+    RangeNotSatisfiable,
+    Busy,
+    /// a tool the server runs failed
+    BadGateway,
+    Internal,
+}
+
+impl ProblemType {
+    pub fn slug(&self) -> &'static str {
+        match self {
+            Self::Validation => "validation",
+            Self::Unauthorized => "unauthorized",
+            Self::Forbidden => "forbidden",
+            Self::NotFound => "not-found",
+            Self::MethodNotAllowed => "method-not-allowed",
+            Self::Conflict => "conflict",
+            Self::PayloadTooLarge => "payload-too-large",
+            Self::RangeNotSatisfiable => "range-not-satisfiable",
+            Self::Busy => "busy",
+            Self::BadGateway => "bad-gateway",
+            Self::Internal => "internal",
+        }
+    }
+
+    pub fn title(&self) -> &'static str {
+        match self {
+            Self::Validation => "Invalid request",
+            Self::Unauthorized => "Authentication required",
+            Self::Forbidden => "Not allowed",
+            Self::NotFound => "Not found",
+            Self::MethodNotAllowed => "Method not allowed",
+            Self::Conflict => "Conflicting state",
+            Self::PayloadTooLarge => "Request body too large",
+            Self::RangeNotSatisfiable => "Range not satisfiable",
+            Self::Busy => "Busy",
+            Self::BadGateway => "Bad gateway",
+            Self::Internal => "Internal error",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Validation => StatusCode::BAD_REQUEST,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            Self::Conflict => StatusCode::CONFLICT,
+            Self::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::RangeNotSatisfiable => StatusCode::RANGE_NOT_SATISFIABLE,
+            Self::Busy => StatusCode::SERVICE_UNAVAILABLE,
+            Self::BadGateway => StatusCode::BAD_GATEWAY,
+            Self::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// the class of a status code the router or a layer answered with
+    fn of_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED => Self::Unauthorized,
+            StatusCode::FORBIDDEN => Self::Forbidden,
+            StatusCode::NOT_FOUND => Self::NotFound,
+            StatusCode::METHOD_NOT_ALLOWED => Self::MethodNotAllowed,
+            StatusCode::CONFLICT => Self::Conflict,
+            StatusCode::PAYLOAD_TOO_LARGE => Self::PayloadTooLarge,
+            StatusCode::RANGE_NOT_SATISFIABLE => Self::RangeNotSatisfiable,
+            StatusCode::SERVICE_UNAVAILABLE | StatusCode::TOO_MANY_REQUESTS => Self::Busy,
+            StatusCode::BAD_GATEWAY => Self::BadGateway,
+            s if s.is_client_error() => Self::Validation,
+            _ => Self::Internal,
+        }
+    }
+}
+
+/// An invalid field of the request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+/// An error of the API, answered as problem+json
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiError {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub title: &'static str,
+    #[serde(serialize_with = "serialize_status")]
+    pub status: StatusCode,
+    pub detail: String,
+    /// the FailureReason or StopReason behind the error
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+    /// members specific to the error
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+fn serialize_status<S: serde::Serializer>(status: &StatusCode, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_u16(status.as_u16())
+}
+
+impl ApiError {
+    pub fn new(kind: ProblemType, detail: impl ToString) -> Self {
+        Self {
+            kind: format!("/problems/{}", kind.slug()),
+            title: kind.title(),
+            status: kind.status(),
+            detail: detail.to_string(),
+            reason: None,
+            request_id: None,
+            errors: vec![],
+            extra: Default::default(),
+        }
+    }
+
+    pub fn validation(detail: impl ToString) -> Self {
+        Self::new(ProblemType::Validation, detail)
+    }
+
+    pub fn not_found(detail: impl ToString) -> Self {
+        Self::new(ProblemType::NotFound, detail)
+    }
+
+    pub fn conflict(detail: impl ToString) -> Self {
+        Self::new(ProblemType::Conflict, detail)
+    }
+
+    pub fn internal(detail: impl ToString) -> Self {
+        Self::new(ProblemType::Internal, detail)
+    }
+
+    pub fn with_reason(mut self, reason: impl Serialize) -> Self {
+        self.reason = serde_json::to_value(reason).ok();
+        self
+    }
+
+    pub fn with_field(mut self, field: impl ToString, message: impl ToString) -> Self {
+        self.errors.push(FieldError {
+            field: field.to_string(),
+            message: message.to_string(),
+        });
+        self
+    }
+
+    pub fn with(mut self, key: &str, value: impl Serialize) -> Self {
+        if let Ok(value) = serde_json::to_value(value) {
+            self.extra.insert(key.to_string(), value);
+        }
+        self
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.title, self.detail)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(mut self) -> Response {
+        if self.request_id.is_none() {
+            self.request_id = CURRENT_REQUEST.try_with(|id| id.clone()).ok();
+        }
+        let body = serde_json::to_vec(&self).unwrap_or_default();
+        (
+            self.status,
+            [(header::CONTENT_TYPE, HeaderValue::from_static(CONTENT_TYPE))],
+            body,
+        )
+            .into_response()
+    }
+}
+
+static NEXT_REQUEST: AtomicU64 = AtomicU64::new(1);
+
+/// give the request an id, answered in [REQUEST_ID] and put into its problems
+pub async fn request_id(req: Request<Body>, next: Next<Body>) -> Response {
+    let id = req
+        .headers()
+        .get(REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
+        .unwrap_or_else(|| format!("{:08x}", NEXT_REQUEST.fetch_add(1, Ordering::Relaxed)));
+    let mut res = CURRENT_REQUEST.scope(id.clone(), next.run(req)).await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        res.headers_mut().insert(REQUEST_ID, value);
+    }
+    res
+}
+
+/// answer the errors axum and the layers produce as problems too
+///
+/// Errors that are JSON already are left alone: they are problems, or reports such as the
+/// liveness one.
+pub async fn rewrite_rejections(res: Response) -> Response {
+    let status = res.status();
+    let is_json = res.headers().get(header::CONTENT_TYPE).is_some_and(|v| {
+        v.as_bytes().starts_with(b"application/json")
+            || v.as_bytes().starts_with(CONTENT_TYPE.as_bytes())
+    });
+    if !(status.is_client_error() || status.is_server_error()) || is_json {
+        return res;
+    }
+    let kind = ProblemType::of_status(status);
+    let (parts, mut body) = res.into_parts();
+    let mut text = vec![];
+    if body
+        .size_hint()
+        .upper()
+        .is_some_and(|size| size as usize <= MAX_DETAIL)
+    {
+        while let Some(Ok(chunk)) = body.data().await {
+            text.extend_from_slice(&chunk);
+        }
+    }
+    let text = String::from_utf8_lossy(&text).trim().to_string();
+    let detail = if text.is_empty() {
+        status
+            .canonical_reason()
+            .unwrap_or(kind.title())
+            .to_string()
+    } else {
+        text
+    };
+    let mut problem = ApiError::new(kind, detail);
+    problem.status = status;
+    let mut res = problem.into_response();
+    // Allow, Content-Range and the like still tell about the problem, the body alone is new
+    for (name, value) in &parts.headers {
+        if name != header::CONTENT_TYPE && name != header::CONTENT_LENGTH {
+            res.headers_mut().append(name, value.clone());
+        }
+    }
+    res
+}
+
+/// the fallback of the router
+pub async fn not_found() -> ApiError {
+    ApiError::not_found("no such endpoint")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::BoxBody;
+
+    async fn rewritten(res: Response) -> (Response, serde_json::Value) {
+        let res = rewrite_rejections(res).await;
+        let (parts, mut body) = res.into_parts();
+        let mut bytes = vec![];
+        while let Some(chunk) = body.data().await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+        let problem = serde_json::from_slice(&bytes).unwrap();
+        (Response::from_parts(parts, BoxBody::default()), problem)
+    }
+
+    #[tokio::test]
+    async fn a_rejection_keeps_its_headers() {
+        let res = (
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            [
+                (header::CONTENT_RANGE, "bytes */1000"),
+                (header::CONTENT_TYPE, "text/plain"),
+            ],
+            "out of range",
+        )
+            .into_response();
+        let (res, problem) = rewritten(res).await;
+        assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(res.headers()[header::CONTENT_RANGE], "bytes */1000");
+        assert_eq!(res.headers()[header::CONTENT_TYPE], CONTENT_TYPE);
+        assert_eq!(
+            res.headers().get_all(header::CONTENT_TYPE).iter().count(),
+            1
+        );
+        assert_eq!(problem["detail"], "out of range");
+        assert_eq!(problem["status"], 416);
+    }
+
+    #[tokio::test]
+    async fn a_method_not_allowed_keeps_its_allow() {
+        let res = (
+            StatusCode::METHOD_NOT_ALLOWED,
+            [(header::ALLOW, "GET,HEAD")],
+        )
+            .into_response();
+        let (res, problem) = rewritten(res).await;
+        assert_eq!(res.headers()[header::ALLOW], "GET,HEAD");
+        assert_eq!(problem["type"], "/problems/method-not-allowed");
+    }
+
+    #[tokio::test]
+    async fn a_problem_is_left_alone() {
+        let res = ApiError::conflict("busy").into_response();
+        let (res, problem) = rewritten(res).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        assert_eq!(problem["detail"], "busy");
+    }
+}
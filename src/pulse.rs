@@ -0,0 +1,252 @@
+//! The PulseAudio sources the audio of a screen recording is captured from
+//!
+//! The pulse input of ffmpeg takes the name of a source, [DEFAULT] being what the sound server
+//! takes as its default, the microphone most of the time. `audio_source` names another one, the
+//! monitor of an output sink for the sound of the desktop say; [list] tells the sources the
+//! server has, by `pactl`, which PipeWire answers as well.
+//!
+//! `audio_sources` captures several, a microphone and the monitor of a sink say, each at its own
+//! `volume`: they are pulse inputs of the capture one after another, mixed into a single stereo
+//! track by the [mix_filter], the audio of the capture being [MIXED] then. A single source at its
+//! own volume is captured as it is, without a mix.
+//!
+//! A source that is named is looked for when the recording starts, and a source the server
+//! doesn't have fails the start rather than an ffmpeg that would die at once. The names that
+//! stand for a default, the [ALIASES], are taken as they are; without `pactl` the sources are
+//! not checked either.
+use crate::service::RecordingOptions;
+use crate::source::CaptureSource;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::process::Command;
+use tracing::*;
+
+/// the source captured without an `audio_source`
+pub const DEFAULT: &str = "default";
+/// the names of the defaults of the sound server, which are not sources of the list
+pub const ALIASES: &[&str] = &[DEFAULT, "@DEFAULT_SOURCE@", "@DEFAULT_MONITOR@"];
+/// the loudest a source of a mix can be made
+pub const MAX_VOLUME: f32 = 4.0;
+/// the label of the audio the [mix_filter] makes
+pub const MIXED: &str = "[aout]";
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("pactl can't be run")]
+    NoPactl,
+    #[error("cannot list the audio sources: {0}")]
+    Unreachable(String),
+    #[error("the sound server has no audio source {name}")]
+    Unknown {
+        name: String,
+        available: Vec<String>,
+    },
+}
+
+/// A source the audio can be captured from
+#[derive(Debug, Clone, Serialize)]
+pub struct Source {
+    /// what `audio_source` takes
+    pub name: String,
+    pub description: String,
+    /// whether the source is the monitor of an output sink, what it plays
+    pub monitor: bool,
+}
+
+/// A source of the audio of a recording, given by its name alone or with its volume
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "Given")]
+pub struct AudioInput {
+    pub source: String,
+    /// the gain of the source in the mix, 1 for as it is
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volume: Option<f32>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Given {
+    Name(String),
+    Input {
+        source: String,
+        #[serde(default)]
+        volume: Option<f32>,
+    },
+}
+
+impl From<Given> for AudioInput {
+    fn from(given: Given) -> Self {
+        match given {
+            Given::Name(source) => Self {
+                source,
+                volume: None,
+            },
+            Given::Input { source, volume } => Self { source, volume },
+        }
+    }
+}
+
+/// the sources the options capture, in the order of their inputs
+pub fn inputs(opt: &RecordingOptions) -> Vec<AudioInput> {
+    if !opt.audio_sources.is_empty() {
+        return opt.audio_sources.clone();
+    }
+    vec![AudioInput {
+        source: opt.audio_source.as_deref().unwrap_or(DEFAULT).to_string(),
+        volume: None,
+    }]
+}
+
+/// the `-filter_complex` mixing the sources, the inputs from `first` on, into [MIXED]; None for
+/// a single source at its own volume
+pub fn mix_filter(inputs: &[AudioInput], first: usize) -> Option<String> {
+    match inputs {
+        [AudioInput { volume: None, .. }] => return None,
+        [AudioInput {
+            volume: Some(volume),
+            ..
+        }] => return Some(format!("[{}:a]volume={}{}", first, volume, MIXED)),
+        _ => {}
+    }
+    let mut filter = String::new();
+    let mut mixed = String::new();
+    for (i, input) in inputs.iter().enumerate() {
+        match input.volume {
+            Some(volume) => {
+                filter += &format!("[{}:a]volume={}[a{}];", first + i, volume, i);
+                mixed += &format!("[a{}]", i);
+            }
+            None => mixed += &format!("[{}:a]", first + i),
+        }
+    }
+    filter += &format!(
+        "{}amix=inputs={}:duration=longest:normalize=0{}",
+        mixed,
+        inputs.len(),
+        MIXED
+    );
+    Some(filter)
+}
+
+/// the audio stream of a screen capture with the sources of the options, the first input after
+/// the screen or the mix
+pub fn audio_stream(opt: &RecordingOptions) -> &'static str {
+    match mix_filter(&inputs(opt), 1) {
+        Some(_) => MIXED,
+        None => "1:a",
+    }
+}
+
+/// the sources of the sound server, as `pactl list sources` tells
+pub async fn list() -> Result<Vec<Source>, Error> {
+    let output = Command::new("pactl")
+        .args(["list", "sources"])
+        .env("LC_ALL", "C")
+        .output()
+        .await
+        .map_err(|_| Error::NoPactl)?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::Unreachable(stderr.trim().to_string()));
+    }
+    Ok(parse(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// the sources of the output of `pactl list sources`
+fn parse(output: &str) -> Vec<Source> {
+    let mut sources: Vec<Source> = vec![];
+    for line in output.lines() {
+        if line.starts_with("Source #") {
+            sources.push(Source {
+                name: String::new(),
+                description: String::new(),
+                monitor: false,
+            });
+            continue;
+        }
+        let (Some(source), Some((key, value))) = (sources.last_mut(), line.trim().split_once(':'))
+        else {
+            continue;
+        };
+        let value = value.trim();
+        match key {
+            "Name" => source.name = value.to_string(),
+            "Description" => source.description = value.to_string(),
+            "Monitor of Sink" => source.monitor = value != "n/a",
+            _ => {}
+        }
+    }
+    sources.retain(|source| !source.name.is_empty());
+    sources
+}
+
+/// whether the sources the options ask for can be given, the field that can't otherwise
+pub fn validate(opt: &RecordingOptions) -> Result<(), (&'static str, String)> {
+    let field = match (&opt.audio_source, opt.audio_sources.is_empty()) {
+        (None, true) => return Ok(()),
+        (Some(_), false) => {
+            return Err((
+                "audio_sources",
+                "given with audio_source, which it replaces".to_string(),
+            ))
+        }
+        (Some(_), true) => "audio_source",
+        (None, false) => "audio_sources",
+    };
+    if opt.source != CaptureSource::Screen {
+        return Err((
+            field,
+            "the audio of a stream is that of the stream".to_string(),
+        ));
+    }
+    if !opt.audio {
+        return Err((field, "given without audio".to_string()));
+    }
+    for input in inputs(opt) {
+        if input.source.trim().is_empty() {
+            return Err((field, "a source must be named".to_string()));
+        }
+        if let Some(volume) = input.volume {
+            if !(0.0..=MAX_VOLUME).contains(&volume) {
+                return Err((field, format!("a volume must be within 0..{}", MAX_VOLUME)));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// whether the sound server has the sources the options ask for
+pub async fn check(opt: &RecordingOptions) -> Result<(), Error> {
+    if !opt.audio || opt.source != CaptureSource::Screen {
+        return Ok(());
+    }
+    let named: Vec<String> = inputs(opt)
+        .into_iter()
+        .map(|input| input.source)
+        .filter(|name| !ALIASES.contains(&name.as_str()))
+        .collect();
+    if named.is_empty() {
+        return Ok(());
+    }
+    let sources = match list().await {
+        Ok(sources) => sources,
+        Err(Error::NoPactl) => {
+            warn!(
+                "pactl can't be run, capturing the audio of {} unchecked",
+                named.join(", ")
+            );
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    match named
+        .into_iter()
+        .find(|name| !sources.iter().any(|source| &source.name == name))
+    {
+        None => Ok(()),
+        Some(name) => Err(Error::Unknown {
+            name,
+            available: sources.into_iter().map(|source| source.name).collect(),
+        }),
+    }
+}
@@ -0,0 +1,363 @@
+//! Changing the quality of a running capture
+//!
+//! x264 can't be reconfigured while it encodes, so a change rolls the capture over to a new
+//! segment: [request] leaves the new settings pending in the state and interrupts the capture,
+//! which finishes its file, and the capture stage starts the next segment with them right away.
+//! Every change is a marker of the recording. At stop the segments are joined by [join], before
+//! the compression: copied when [compatible] finds their streams alike, transcoded with a warning
+//! when not. What the screen showed between the end of a segment and the first frame of the
+//! next, usually a fraction of a second, is not in the recording, and the resilient audio is
+//! placed as if it were.
+use crate::container;
+use crate::ffmpeg::{FfmpegBuilder, File, Parameter};
+use crate::probe::{probe, MediaInfo};
+use crate::service::{log_summary, ChildRole, Children, Recorder, RecordingState};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+use thiserror::Error;
+use tracing::*;
+
+/// changes closer together are refused
+pub const MIN_INTERVAL: Duration = Duration::from_secs(30);
+/// highest crf and qp of x264 at 8 bits
+pub const MAX_CRF: u8 = 51;
+pub const MAX_QP: u8 = 51;
+pub const MAX_FRAMERATE: u32 = 120;
+/// framerate of a screen capture unless changed
+pub const SCREEN_FRAMERATE: u32 = 25;
+
+/// How x264 spends its bits
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateControl {
+    Crf(u8),
+    /// constant quantizer, lossless at 0
+    Qp(u8),
+}
+
+/// What the capture encodes with
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CaptureQuality {
+    #[serde(flatten)]
+    pub rate: RateControl,
+    /// frames per second, the one of the stream when None
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub framerate: Option<u32>,
+}
+
+impl CaptureQuality {
+    /// the lossless capture a recording starts with
+    pub fn lossless(framerate: Option<u32>) -> Self {
+        Self {
+            rate: RateControl::Qp(0),
+            framerate,
+        }
+    }
+
+    /// the ffmpeg option and its value
+    pub fn rate_option(&self) -> (&'static str, String) {
+        match self.rate {
+            RateControl::Crf(crf) => ("crf", crf.to_string()),
+            RateControl::Qp(qp) => ("qp", qp.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for CaptureQuality {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (option, value) = self.rate_option();
+        write!(f, "{} {}", option, value)?;
+        if let Some(framerate) = self.framerate {
+            write!(f, ", {} fps", framerate)?;
+        }
+        Ok(())
+    }
+}
+
+/// A change asked for, either `crf` or `qp`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct QualityChange {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crf: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub qp: Option<u8>,
+    /// the current one when None
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub framerate: Option<u32>,
+}
+
+impl QualityChange {
+    /// the settings after the change
+    pub fn apply(&self, current: &CaptureQuality) -> Result<CaptureQuality, Error> {
+        let rate = match (self.crf, self.qp) {
+            (Some(_), Some(_)) => return Err(Error::Invalid("qp", "give crf or qp, not both")),
+            (None, None) => return Err(Error::Invalid("crf", "give crf or qp")),
+            (Some(crf), None) if crf <= MAX_CRF => RateControl::Crf(crf),
+            (Some(_), None) => return Err(Error::Invalid("crf", "must be within 0..51")),
+            (None, Some(qp)) if qp <= MAX_QP => RateControl::Qp(qp),
+            (None, Some(_)) => return Err(Error::Invalid("qp", "must be within 0..51")),
+        };
+        let framerate = match self.framerate {
+            Some(framerate) if (1..=MAX_FRAMERATE).contains(&framerate) => Some(framerate),
+            Some(_) => return Err(Error::Invalid("framerate", "must be within 1..120")),
+            None => current.framerate,
+        };
+        Ok(CaptureQuality { rate, framerate })
+    }
+}
+
+/// A file of the capture, recorded with the same settings throughout
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VideoSegment {
+    pub file: String,
+    /// when the segment started, relative to the start of the recording
+    pub offset_ms: u64,
+    /// None when the capture copies the stream
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quality: Option<CaptureQuality>,
+}
+
+/// The quality of a running capture
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QualityStatus {
+    #[serde(flatten)]
+    pub active: CaptureQuality,
+    /// how many times it was changed
+    #[serde(default)]
+    pub changes: u32,
+    /// asked for, the next segment is being started with it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pending: Option<CaptureQuality>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub changed_at: Option<DateTime<Local>>,
+}
+
+impl QualityStatus {
+    pub fn new(active: CaptureQuality) -> Self {
+        Self {
+            active,
+            changes: 0,
+            pending: None,
+            changed_at: None,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{1}")]
+    Invalid(&'static str, &'static str),
+    #[error("not started")]
+    NotStarted,
+    #[error("the capture has no encoder settings to change, it copies the stream")]
+    Unchangeable,
+    #[error("frame timestamps can't span a quality change")]
+    FrameTimestamps,
+    #[error("a quality change is being applied already")]
+    Pending,
+    #[error("the quality was changed less than {}s ago", MIN_INTERVAL.as_secs())]
+    TooSoon {
+        /// seconds until a change is accepted
+        retry_after: u64,
+    },
+}
+
+/// change the quality of the running capture, the segment rolls over in the background
+pub async fn request(mx: &Recorder, change: &QualityChange) -> Result<CaptureQuality, Error> {
+    let mut state = mx.lock().await;
+    let RecordingState::Started {
+        process_id,
+        options,
+        quality,
+        ..
+    } = &mut *state
+    else {
+        return Err(Error::NotStarted);
+    };
+    if options.frame_timestamps {
+        return Err(Error::FrameTimestamps);
+    }
+    let Some(status) = quality else {
+        return Err(Error::Unchangeable);
+    };
+    if status.pending.is_some() {
+        return Err(Error::Pending);
+    }
+    let next = change.apply(&status.active)?;
+    let now = Local::now();
+    if let Some(at) = status.changed_at {
+        let since = (now - at).to_std().unwrap_or_default();
+        if since < MIN_INTERVAL {
+            return Err(Error::TooSoon {
+                retry_after: (MIN_INTERVAL - since).as_secs() + 1,
+            });
+        }
+    }
+    status.pending = Some(next);
+    status.changed_at = Some(now);
+    let pid = *process_id;
+    let updated = state.clone();
+    mx.replace(&mut state, updated);
+    // the capture finishes its file, the capture stage starts the next segment
+    let pid = nix::unistd::Pid::from_raw(pid as i32);
+    if let Err(e) = nix::sys::signal::kill(pid, nix::sys::signal::Signal::SIGINT) {
+        warn!("cannot interrupt the capture {}: {}", pid, e);
+    }
+    info!("quality of the capture changes to {}", next);
+    Ok(next)
+}
+
+/// path of the segment `index` of a capture, the first one is the capture itself
+pub fn segment_path(capture: &str, index: usize) -> String {
+    match index {
+        0 => capture.to_string(),
+        index => format!(
+            "{}.part{:03}.{}",
+            container::base(capture),
+            index,
+            container::extension_of(capture)
+        ),
+    }
+}
+
+/// path of the segments of a capture joined
+pub fn joined_path(capture: &str) -> String {
+    format!(
+        "{}.joined.{}",
+        container::base(capture),
+        container::extension_of(capture)
+    )
+}
+
+/// whether the segments can be joined without decoding them, Err tells why not
+pub fn compatible(infos: &[MediaInfo]) -> Result<(), String> {
+    let Some(first) = infos.first() else {
+        return Ok(());
+    };
+    for (i, info) in infos.iter().enumerate().skip(1) {
+        let differs = |what: &str, a: &dyn std::fmt::Debug, b: &dyn std::fmt::Debug| {
+            format!("segment {} has {} {:?}, the first one {:?}", i, what, b, a)
+        };
+        if info.video_codec != first.video_codec {
+            return Err(differs("codec", &first.video_codec, &info.video_codec));
+        }
+        if info.profile != first.profile {
+            return Err(differs("profile", &first.profile, &info.profile));
+        }
+        if (info.width, info.height) != (first.width, first.height) {
+            return Err(differs(
+                "size",
+                &(first.width, first.height),
+                &(info.width, info.height),
+            ));
+        }
+        if info.pix_fmt != first.pix_fmt {
+            return Err(differs("pixel format", &first.pix_fmt, &info.pix_fmt));
+        }
+        if info.frame_rate != first.frame_rate {
+            return Err(differs("framerate", &first.frame_rate, &info.frame_rate));
+        }
+        if info.audio_codec != first.audio_codec {
+            return Err(differs("audio", &first.audio_codec, &info.audio_codec));
+        }
+    }
+    Ok(())
+}
+
+/// a line of a concat demuxer list
+fn concat_entry(file: &str) -> String {
+    format!("file '{}'\n", file.replace('\'', "'\\''"))
+}
+
+/// join the segments into [joined_path], returning it and the command that did
+pub async fn join(
+    segments: &[VideoSegment],
+    children: &Children,
+) -> anyhow::Result<(String, Vec<String>)> {
+    let Some(first) = segments.first() else {
+        anyhow::bail!("no segments to join");
+    };
+    let mut infos = vec![];
+    for segment in segments {
+        infos.push(probe(Path::new(&segment.file)).await?);
+    }
+    let output = joined_path(&first.file);
+    let list = format!("{}.segments.txt", container::base(&first.file));
+    let mut builder = FfmpegBuilder::new().stderr(Stdio::piped());
+    // outlive the builder
+    let filter;
+    let framerate = infos[0].frame_rate.clone().unwrap_or("25".to_string());
+    let pix_fmt = infos[0].pix_fmt.clone().unwrap_or("yuv444p".to_string());
+    match compatible(&infos) {
+        Ok(()) => {
+            std::fs::write(
+                &list,
+                segments
+                    .iter()
+                    .map(|s| concat_entry(&s.file))
+                    .collect::<String>(),
+            )?;
+            builder = builder
+                .option(Parameter::KeyValue("f", "concat"))
+                .option(Parameter::KeyValue("safe", "0"))
+                .input(File::new(&list))
+                .option2(Parameter::KeyValue("c", "copy"));
+        }
+        Err(why) => {
+            warn!("the segments are transcoded to be joined: {}", why);
+            let audio = infos.iter().all(|i| i.audio_codec.is_some());
+            let mut graph = String::new();
+            for (i, segment) in segments.iter().enumerate() {
+                builder = builder.input(File::new(&segment.file));
+                graph += &format!(
+                    "[{}:v]fps={},format={},setsar=1[v{}];",
+                    i, framerate, pix_fmt, i
+                );
+            }
+            for i in 0..segments.len() {
+                graph += &format!("[v{}]", i);
+                if audio {
+                    graph += &format!("[{}:a]", i);
+                }
+            }
+            graph += &format!(
+                "concat=n={}:v=1:a={}[v]{}",
+                segments.len(),
+                audio as u8,
+                if audio { "[a]" } else { "" }
+            );
+            filter = graph;
+            let maps = match audio {
+                true => vec!["[v]", "[a]"],
+                false => vec!["[v]"],
+            };
+            builder = builder
+                .option2(Parameter::KeyValue("filter_complex", &filter))
+                .option2(Parameter::Repeated("map", maps))
+                .option2(Parameter::codec("v", "libx264"))
+                .option2(Parameter::KeyValue("preset", "ultrafast"))
+                .option2(Parameter::KeyValue("qp", "0"));
+            if audio {
+                builder = builder.option2(Parameter::codec("a", "aac"));
+            }
+        }
+    }
+    builder = builder.output(File::new(&output));
+    let ffmpeg = builder.run().await?;
+    let process_id = ffmpeg.id();
+    let command = ffmpeg.argv().to_vec();
+    children.register(process_id, ChildRole::Compression, vec![output.clone()]);
+    let summary = ffmpeg.wait_with_progress(|_| {}).await;
+    children.unregister(process_id);
+    let _ = std::fs::remove_file(&list);
+    let summary = summary?;
+    log_summary("join", &summary);
+    if !summary.success() {
+        anyhow::bail!("cannot join the segments: {}", summary.exit_status);
+    }
+    info!("{} segments joined into {}", segments.len(), output);
+    Ok((output, command))
+}
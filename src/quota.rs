@@ -0,0 +1,359 @@
+//! Disk quotas of the owners of the recordings
+//!
+//! Quotas are given per owner, `teamA=50GB`, and as `default=20GB` for every owner without one of
+//! its own. Recordings without an owner count against the `default` bucket. The usage is tallied
+//! from the history at startup, counting the recordings still on disk, and follows the recordings
+//! done, imported and deleted. An imported recording has no owner.
+//!
+//! A recording in progress, captured or waiting for its compression, reserves what it has written
+//! but at least [RESERVATION], and so does the recording asked for: an owner whose usage leaves
+//! less room than that can't start another one. Without any quota nothing is refused.
+use crate::history::{History, PageQuery, MAX_LIMIT};
+use crate::service::{Recorder, RecordingState};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// the bucket of the recordings without an owner, and the quota of the owners without one
+pub const DEFAULT_BUCKET: &str = "default";
+/// least size a recording in progress is expected to reach
+pub const RESERVATION: u64 = 1 << 30;
+
+/// `1024`, `500MB`, `50GB`, `2TiB`
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number.parse().map_err(|_| format!("invalid size: {}", s))?;
+    let unit: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" | "k" => 1_000,
+        "mb" | "m" => 1_000_000,
+        "gb" | "g" => 1_000_000_000,
+        "tb" | "t" => 1_000_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        other => return Err(format!("unknown unit of size: {}", other)),
+    };
+    Ok((number * unit as f64) as u64)
+}
+
+/// The quota of an owner, or the default one
+#[derive(Debug, Clone, PartialEq)]
+pub struct OwnerQuota {
+    pub owner: String,
+    pub bytes: u64,
+}
+
+impl FromStr for OwnerQuota {
+    type Err = String;
+
+    /// `<owner>=<size>`, the owner being `default` for the default quota
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (owner, size) = s.split_once('=').ok_or("expected '<owner>=<size>'")?;
+        let owner = owner.trim();
+        if owner.is_empty() {
+            return Err("expected '<owner>=<size>'".to_string());
+        }
+        Ok(Self {
+            owner: owner.to_string(),
+            bytes: parse_size(size)?,
+        })
+    }
+}
+
+/// An owner with less room than the recording it asked for
+#[derive(Debug, Clone, Serialize, thiserror::Error)]
+#[error("{owner} uses {used} and reserves {reserved} of its {limit} bytes")]
+pub struct Exceeded {
+    pub owner: String,
+    pub used: u64,
+    /// by the recordings in progress and the one asked for
+    pub reserved: u64,
+    pub limit: u64,
+}
+
+/// The usage of an owner
+#[derive(Debug, Clone, Serialize)]
+pub struct Usage {
+    pub owner: String,
+    /// bytes of the recordings on disk
+    pub used: u64,
+    /// bytes reserved by the recordings in progress
+    pub reserved: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u64>,
+    pub over: bool,
+}
+
+/// The quotas and the usage of every owner
+#[derive(Default)]
+pub struct Quotas {
+    limits: HashMap<String, u64>,
+    usage: Mutex<HashMap<String, u64>>,
+}
+
+fn bucket(owner: Option<&str>) -> &str {
+    owner.unwrap_or(DEFAULT_BUCKET)
+}
+
+impl Quotas {
+    pub fn new(quotas: Vec<OwnerQuota>) -> Self {
+        Self {
+            limits: quotas.into_iter().map(|q| (q.owner, q.bytes)).collect(),
+            usage: Default::default(),
+        }
+    }
+
+    /// the quota of an owner, None when unlimited
+    pub fn limit(&self, owner: Option<&str>) -> Option<u64> {
+        self.limits
+            .get(bucket(owner))
+            .or_else(|| self.limits.get(DEFAULT_BUCKET))
+            .copied()
+    }
+
+    /// bytes of the recordings of an owner on disk
+    pub fn used(&self, owner: Option<&str>) -> u64 {
+        let usage = self.usage.lock().unwrap();
+        usage.get(bucket(owner)).copied().unwrap_or(0)
+    }
+
+    /// count a recording that was created
+    pub fn add(&self, owner: Option<&str>, bytes: u64) {
+        let mut usage = self.usage.lock().unwrap();
+        *usage.entry(bucket(owner).to_string()).or_default() += bytes;
+    }
+
+    /// stop counting a recording that was deleted
+    pub fn remove(&self, owner: Option<&str>, bytes: u64) {
+        let mut usage = self.usage.lock().unwrap();
+        if let Some(used) = usage.get_mut(bucket(owner)) {
+            *used = used.saturating_sub(bytes);
+        }
+    }
+
+    /// count the recordings of the history that are still on disk, replacing the tallies
+    pub fn tally(&self, history: &History) -> anyhow::Result<()> {
+        let mut usage: HashMap<String, u64> = HashMap::new();
+        let mut query = PageQuery {
+            state: Some("done".to_string()),
+            limit: Some(MAX_LIMIT),
+            ..Default::default()
+        };
+        loop {
+            let page = history.page(&query)?;
+            for entry in page.entries {
+                let Some(file) = &entry.file else {
+                    continue;
+                };
+                // deleted since
+                let Ok(metadata) = std::fs::metadata(file) else {
+                    continue;
+                };
+                *usage
+                    .entry(bucket(entry.owner.as_deref()).to_string())
+                    .or_default() += metadata.len();
+            }
+            match page.next_cursor {
+                Some(cursor) => query.cursor = Some(cursor),
+                None => break,
+            }
+        }
+        *self.usage.lock().unwrap() = usage;
+        Ok(())
+    }
+
+    /// whether the owner has room for one more recording, besides those in progress
+    pub fn check(&self, owner: Option<&str>, in_progress: &[Reservation]) -> Result<(), Exceeded> {
+        let Some(limit) = self.limit(owner) else {
+            return Ok(());
+        };
+        let used = self.used(owner);
+        let reserved = reserved_by(owner, in_progress) + RESERVATION;
+        if used + reserved > limit {
+            return Err(Exceeded {
+                owner: bucket(owner).to_string(),
+                used,
+                reserved,
+                limit,
+            });
+        }
+        Ok(())
+    }
+
+    /// the usage of the owners with a quota, a recording or a reservation
+    pub fn report(&self, in_progress: &[Reservation]) -> Vec<Usage> {
+        let mut owners: BTreeMap<String, u64> = self
+            .usage
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(owner, used)| (owner.clone(), *used))
+            .collect();
+        for owner in self.limits.keys() {
+            owners.entry(owner.clone()).or_default();
+        }
+        for reservation in in_progress {
+            owners
+                .entry(bucket(reservation.owner.as_deref()).to_string())
+                .or_default();
+        }
+        owners
+            .into_iter()
+            .map(|(owner, used)| {
+                let reserved = reserved_by(Some(&owner), in_progress);
+                let limit = self.limit(Some(&owner));
+                Usage {
+                    over: limit.is_some_and(|limit| used + reserved > limit),
+                    owner,
+                    used,
+                    reserved,
+                    limit,
+                }
+            })
+            .collect()
+    }
+}
+
+/// What a recording in progress is expected to take
+#[derive(Debug, Clone)]
+pub struct Reservation {
+    pub owner: Option<String>,
+    pub bytes: u64,
+}
+
+impl Reservation {
+    fn of(owner: Option<String>, file: &str) -> Self {
+        let written = std::fs::metadata(Path::new(file))
+            .map(|m| m.len())
+            .unwrap_or(0);
+        Self {
+            owner,
+            bytes: written.max(RESERVATION),
+        }
+    }
+}
+
+fn reserved_by(owner: Option<&str>, in_progress: &[Reservation]) -> u64 {
+    in_progress
+        .iter()
+        .filter(|r| bucket(r.owner.as_deref()) == bucket(owner))
+        .map(|r| r.bytes)
+        .sum()
+}
+
+/// the recording being captured and those waiting for their compression
+pub async fn in_progress(mx: &Recorder) -> Vec<Reservation> {
+    let mut reservations: Vec<Reservation> = mx
+        .jobs
+        .unfinished()
+        .into_iter()
+        .map(|job| Reservation::of(job.compression.options.owner, &job.compression.input))
+        .collect();
+    if let RecordingState::Started { options, file, .. } = mx.lock().await.recording() {
+        reservations.push(Reservation::of(options.owner.clone(), file));
+    }
+    reservations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reservation(owner: Option<&str>, bytes: u64) -> Reservation {
+        Reservation {
+            owner: owner.map(str::to_string),
+            bytes,
+        }
+    }
+
+    #[test]
+    fn the_sizes_are_parsed_with_their_unit() {
+        assert_eq!(parse_size("1024"), Ok(1024));
+        assert_eq!(parse_size("50GB"), Ok(50_000_000_000));
+        assert_eq!(parse_size("1.5 MB"), Ok(1_500_000));
+        assert_eq!(parse_size("2GiB"), Ok(2 << 30));
+        assert!(parse_size("5 parsecs").is_err());
+        let quota: OwnerQuota = "teamA=50GB".parse().unwrap();
+        assert_eq!(
+            (quota.owner.as_str(), quota.bytes),
+            ("teamA", 50_000_000_000)
+        );
+        assert!("=50GB".parse::<OwnerQuota>().is_err());
+    }
+
+    #[test]
+    fn an_owner_without_a_quota_has_the_default_one() {
+        let quotas = Quotas::new(vec![
+            "teamA=50GB".parse().unwrap(),
+            "default=20GB".parse().unwrap(),
+        ]);
+        assert_eq!(quotas.limit(Some("teamA")), Some(50_000_000_000));
+        assert_eq!(quotas.limit(Some("teamB")), Some(20_000_000_000));
+        assert_eq!(quotas.limit(None), Some(20_000_000_000));
+        assert_eq!(Quotas::default().limit(Some("teamA")), None);
+    }
+
+    #[test]
+    fn the_recordings_in_progress_reserve_room() {
+        let limit = 3 * RESERVATION;
+        let quotas = Quotas::new(vec![OwnerQuota {
+            owner: "teamA".to_string(),
+            bytes: limit,
+        }]);
+        quotas.add(Some("teamA"), RESERVATION / 2);
+        assert!(quotas.check(Some("teamA"), &[]).is_ok());
+        // one at least its reservation, another owner's not counted
+        let in_progress = [
+            reservation(Some("teamA"), RESERVATION),
+            reservation(None, limit),
+        ];
+        assert!(quotas.check(Some("teamA"), &in_progress).is_ok());
+        let in_progress = [
+            reservation(Some("teamA"), RESERVATION),
+            reservation(Some("teamA"), RESERVATION),
+        ];
+        let e = quotas.check(Some("teamA"), &in_progress).unwrap_err();
+        assert_eq!(e.used, RESERVATION / 2);
+        // the two in progress and the one asked for
+        assert_eq!(e.reserved, 3 * RESERVATION);
+        assert_eq!(e.limit, limit);
+        let report = quotas.report(&in_progress);
+        assert_eq!(report.len(), 1);
+        assert_eq!(report[0].reserved, 2 * RESERVATION);
+        // within its quota, without room for another one
+        assert!(!report[0].over);
+    }
+
+    #[test]
+    fn a_recording_reserves_what_it_wrote_beyond_the_minimum() {
+        let small = crate::recordings::test_output_dir().join("quota-small.mkv");
+        std::fs::write(&small, b"capture").unwrap();
+        let reservation = Reservation::of(None, &small.to_string_lossy());
+        assert_eq!(reservation.bytes, RESERVATION);
+        let large = crate::recordings::test_output_dir().join("quota-large.mkv");
+        let file = std::fs::File::create(&large).unwrap();
+        file.set_len(RESERVATION + 1).unwrap();
+        let reservation = Reservation::of(Some("teamA".to_string()), &large.to_string_lossy());
+        assert_eq!(reservation.bytes, RESERVATION + 1);
+    }
+
+    #[test]
+    fn a_deleted_recording_is_no_longer_counted() {
+        let quotas = Quotas::default();
+        quotas.add(None, 1000);
+        quotas.add(Some("teamA"), 500);
+        quotas.remove(None, 400);
+        quotas.remove(Some("teamA"), 800);
+        assert_eq!(quotas.used(None), 600);
+        assert_eq!(quotas.used(Some(DEFAULT_BUCKET)), 600);
+        assert_eq!(quotas.used(Some("teamA")), 0);
+    }
+}
@@ -0,0 +1,543 @@
+//! Recorded files in the output directory
+use crate::checksums::{self, Manifest};
+use crate::chunks;
+use crate::container;
+use crate::probe::probe;
+use crate::service::{HashLink, Recorder, RecordingState};
+use crate::storage::ObjectInfo;
+use crate::transcripts::{self, Segment};
+use anyhow::{bail, Context};
+use axum::body::Bytes;
+use chrono::{DateTime, Local};
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+/// directory of the content addressed recordings, inside the output directory
+pub const BY_HASH: &str = "by-hash";
+/// hex digits of the sha256 in a content addressed name
+const HASH_NAME_LEN: usize = 16;
+/// how often a growing file is checked for new data
+const TAIL_POLL: Duration = Duration::from_millis(500);
+const CHUNK: usize = 64 * 1024;
+/// recordings of a listing unless `?limit=` asks for another count
+pub const DEFAULT_LIMIT: usize = 50;
+/// the most recordings of a listing, each is probed
+pub const MAX_LIMIT: usize = 500;
+/// recordings of a listing probed at once
+const PROBES: usize = 4;
+
+static OUTPUT_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// write the recordings to `dir` rather than the videos directory, before the first one
+pub fn set_output_dir(dir: PathBuf) {
+    if OUTPUT_DIR.set(dir).is_err() {
+        tracing::warn!("the output directory was set already");
+    }
+}
+
+/// the output directory of the tests, the same for them all as the server has one
+#[cfg(test)]
+pub(crate) fn test_output_dir() -> PathBuf {
+    static DIR: OnceLock<PathBuf> = OnceLock::new();
+    DIR.get_or_init(|| {
+        let dir = std::env::temp_dir().join(format!("record-screen-tests-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        set_output_dir(dir.clone());
+        dir
+    })
+    .clone()
+}
+
+/// directory where the recordings are written, `--output-dir` or the videos directory
+pub fn output_dir() -> anyhow::Result<PathBuf> {
+    match OUTPUT_DIR.get() {
+        Some(dir) => Ok(dir.clone()),
+        None => dirs::video_dir().context("no videos directory, set --output-dir"),
+    }
+}
+
+/// the output directory, created when missing, once a file could be written in it
+pub async fn writable_output_dir() -> anyhow::Result<PathBuf> {
+    let dir = output_dir()?;
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .with_context(|| format!("cannot create {}", dir.display()))?;
+    let probe = dir.join(".record-screen-write-check");
+    tokio::fs::write(&probe, b"")
+        .await
+        .with_context(|| format!("cannot write to {}", dir.display()))?;
+    let _ = tokio::fs::remove_file(&probe).await;
+    Ok(dir)
+}
+
+/// path of a new recording named `name` in `dir`, refusing one that would end up elsewhere
+pub async fn new_file(dir: &Path, name: &str) -> anyhow::Result<PathBuf> {
+    let path = dir.join(name);
+    let parent = match path.parent() {
+        Some(parent) => tokio::fs::canonicalize(parent).await,
+        None => bail!("{} has no directory", path.display()),
+    };
+    let dir = tokio::fs::canonicalize(dir)
+        .await
+        .with_context(|| format!("cannot resolve {}", dir.display()))?;
+    if parent.ok().as_ref() != Some(&dir) || path.file_name().is_none() {
+        bail!("{} is outside of {}", name, dir.display());
+    }
+    Ok(path)
+}
+
+/// path of the recording with the given file name, refusing anything outside the directory
+///
+/// A content addressed name is found in the [BY_HASH] directory.
+pub fn resolve(dir: &Path, name: &str) -> anyhow::Result<PathBuf> {
+    if name.is_empty() || name.starts_with('.') || name.contains('/') || name.contains('\\') {
+        bail!("invalid recording name");
+    }
+    let path = dir.join(name);
+    let hashed = dir.join(BY_HASH).join(name);
+    if !path.exists() && hashed.is_file() {
+        return Ok(hashed);
+    }
+    Ok(path)
+}
+
+/// content addressed name of the file: the start of its sha256, with its extension
+pub fn hash_name(file: &Path, manifest: &Manifest) -> String {
+    let hash = &manifest.sha256[..HASH_NAME_LEN.min(manifest.sha256.len())];
+    match file.extension() {
+        Some(ext) => format!("{}.{}", hash, ext.to_string_lossy()),
+        None => hash.to_string(),
+    }
+}
+
+/// give a finished recording its content addressed name in the [BY_HASH] directory
+///
+/// The human readable name keeps working, as a symlink or a hard link of the hashed file. A file
+/// with the same content already there is reused, so both names lead to the same copy.
+pub fn address_by_hash(
+    file: &Path,
+    manifest: &Manifest,
+    link: HashLink,
+) -> anyhow::Result<PathBuf> {
+    let dir = file.parent().context("recording without a directory")?;
+    let name = file.file_name().context("recording without a name")?;
+    let hashed_dir = dir.join(BY_HASH);
+    std::fs::create_dir_all(&hashed_dir)?;
+    let hash_name = hash_name(file, manifest);
+    let hashed = hashed_dir.join(&hash_name);
+    if !hashed.exists() {
+        std::fs::hard_link(file, &hashed)?;
+        std::fs::write(
+            checksums::manifest_path(&hashed),
+            crate::schema::to_vec_pretty(manifest)?,
+        )?;
+    }
+    // the human readable name is replaced in one rename, it never goes missing
+    let mut temporary = std::ffi::OsString::from(".");
+    temporary.push(name);
+    temporary.push(".link");
+    let temporary = dir.join(temporary);
+    let _ = std::fs::remove_file(&temporary);
+    match link {
+        HashLink::Symlink => {
+            std::os::unix::fs::symlink(Path::new(BY_HASH).join(&hash_name), &temporary)?
+        }
+        HashLink::Hardlink => std::fs::hard_link(&hashed, &temporary)?,
+    }
+    std::fs::rename(&temporary, file)?;
+    Ok(hashed)
+}
+
+/// files the current state is still writing
+pub fn being_written(state: &RecordingState) -> Vec<&str> {
+    match state.recording() {
+        RecordingState::Started { file, .. } | RecordingState::Stopping { file, .. } => {
+            vec![file]
+        }
+        RecordingState::Compressing { output, .. } => vec![output],
+        _ => vec![],
+    }
+}
+
+/// files the current state needs: reading them is fine, removing is not
+pub fn in_use(state: &RecordingState) -> Vec<&str> {
+    match state.recording() {
+        RecordingState::Compressing { input, output, .. } => vec![input, output],
+        RecordingState::Uploading { file, .. } => vec![file],
+        RecordingState::Started {
+            file,
+            segments,
+            chunks,
+            ..
+        } => {
+            let earlier = segments.iter().map(|s| s.file.as_str());
+            let done = chunks
+                .iter()
+                .flat_map(|c| c.done.iter().map(String::as_str));
+            std::iter::once(file.as_str())
+                .chain(earlier.chain(done).filter(|f| f != file))
+                .collect()
+        }
+        _ => being_written(state),
+    }
+}
+
+/// whether the object is a recording, rather than a sidecar or a partial file
+pub fn is_recording(name: &str) -> bool {
+    let extension = name.rsplit_once('.').map(|(_, e)| e).unwrap_or_default();
+    container::EXTENSIONS.contains(&extension) && !name.contains(".part.")
+}
+
+/// The order of a listing
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortKey {
+    /// by the birth of the file, its modification where the filesystem doesn't keep it
+    #[default]
+    Created,
+    Size,
+    Name,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// What `/api/recordings` lists
+#[derive(Default, Debug, Clone, Deserialize)]
+pub struct ListQuery {
+    /// only the recordings whose transcript contains this, ignoring case
+    pub q: Option<String>,
+    #[serde(default)]
+    pub sort: SortKey,
+    #[serde(default)]
+    pub order: Order,
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub offset: usize,
+}
+
+impl ListQuery {
+    /// the field that is out of range and why
+    pub fn invalid(&self) -> Option<(&'static str, String)> {
+        match self.limit {
+            Some(limit) if !(1..=MAX_LIMIT).contains(&limit) => {
+                Some(("limit", format!("must be within 1..{}", MAX_LIMIT)))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A finished recording
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Listed {
+    /// the file name of the recording
+    pub name: String,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<DateTime<Local>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified: Option<DateTime<Local>>,
+    /// seconds, as ffprobe tells
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+    /// the file name of its transcript
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transcript: Option<String>,
+    /// the segments of the transcript containing the term searched
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub matches: Vec<Segment>,
+    /// left by a server that crashed, see [flag_for_recovery]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub recovered: bool,
+    /// the file names of every chunk of a recording in chunks, `name` is the first; the size
+    /// and the duration are those of all of them, see [crate::chunks]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<String>,
+}
+
+/// A page of a listing
+#[derive(Debug, Clone)]
+pub struct Listing {
+    /// the recordings of every page
+    pub total: usize,
+    pub recordings: Vec<Listed>,
+}
+
+/// the finished recordings of the storage, one page of them
+///
+/// The files the state is still working on, the capture and the input of a compression, are not
+/// finished. Only the recordings of the page are probed for their duration. The chunks of a
+/// recording in chunks are one recording, see [Listed::chunks].
+pub async fn list(mx: &Recorder, query: &ListQuery) -> anyhow::Result<Listing> {
+    let objects = mx.storage.list().await?;
+    let busy: Vec<String> = in_use(&*mx.lock().await)
+        .into_iter()
+        .filter_map(|file| Path::new(file).file_name())
+        .map(|name| name.to_string_lossy().to_string())
+        .collect();
+    let found = match query.q.as_deref().filter(|q| !q.is_empty()) {
+        Some(term) => {
+            let (dir, term) = (output_dir()?, term.to_string());
+            let results =
+                tokio::task::spawn_blocking(move || transcripts::search(&dir, Some(&term)))
+                    .await??;
+            Some(results)
+        }
+        None => None,
+    };
+    let names: HashSet<&str> = objects.iter().map(|o| o.name.as_str()).collect();
+    let mut listed: Vec<Listed> = vec![];
+    for object in &objects {
+        if !is_recording(&object.name) || busy.contains(&object.name) {
+            continue;
+        }
+        let transcript = transcripts::srt_path(&object.name);
+        let matches = match &found {
+            Some(found) => match found.iter().find(|r| r.name == object.name) {
+                Some(result) => result.matches.clone(),
+                None => continue,
+            },
+            None => vec![],
+        };
+        let flag = format!("{}.recover", object.name);
+        listed.push(Listed {
+            recovered: names.contains(flag.as_str()),
+            transcript: names.contains(transcript.as_str()).then_some(transcript),
+            matches,
+            ..listed_of(object)
+        });
+    }
+    let mut listed = group(listed, &sessions(mx, &objects).await);
+    sort(&mut listed, query.sort, query.order);
+    let total = listed.len();
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+    let page: Vec<Listed> = listed.into_iter().skip(query.offset).take(limit).collect();
+    let recordings = futures::stream::iter(page)
+        .map(|mut listed| async move {
+            let names = match listed.chunks.is_empty() {
+                true => vec![listed.name.clone()],
+                false => listed.chunks.clone(),
+            };
+            let mut duration = Some(0.0);
+            for name in &names {
+                let probed = match mx.storage.local_path(name).await {
+                    Ok(local) => probe(&local).await.ok().and_then(|info| info.duration),
+                    Err(_) => None,
+                };
+                duration = duration.zip(probed).map(|(total, d)| total + d);
+            }
+            listed.duration = duration;
+            listed
+        })
+        .buffered(PROBES)
+        .collect()
+        .await;
+    Ok(Listing { total, recordings })
+}
+
+fn listed_of(object: &ObjectInfo) -> Listed {
+    Listed {
+        name: object.name.clone(),
+        size: object.size,
+        created: object.created,
+        modified: object.modified,
+        duration: None,
+        transcript: None,
+        matches: vec![],
+        recovered: false,
+        chunks: vec![],
+    }
+}
+
+/// the chunks of every recording in chunks, by the sidecars of the storage
+async fn sessions(mx: &Recorder, objects: &[ObjectInfo]) -> Vec<Vec<String>> {
+    let mut sessions = vec![];
+    for object in objects.iter().filter(|o| chunks::is_sidecar(&o.name)) {
+        let Ok(path) = mx.storage.local_path(&object.name).await else {
+            continue;
+        };
+        match tokio::fs::read(&path).await.map_err(anyhow::Error::from) {
+            Ok(json) => match crate::schema::from_slice::<chunks::Sidecar>(&json) {
+                Ok((sidecar, _)) => sessions.push(sidecar.chunks),
+                Err(e) => tracing::warn!("ignoring {}: {}", object.name, e),
+            },
+            Err(e) => tracing::warn!("cannot read {}: {}", object.name, e),
+        }
+    }
+    sessions
+}
+
+/// fold the chunks of every recording in chunks listed into its first one listed
+fn group(listed: Vec<Listed>, sessions: &[Vec<String>]) -> Vec<Listed> {
+    let mut grouped = vec![];
+    let mut chunked: Vec<Vec<Listed>> = vec![vec![]; sessions.len()];
+    for entry in listed {
+        match sessions.iter().position(|s| s.contains(&entry.name)) {
+            Some(session) => chunked[session].push(entry),
+            None => grouped.push(entry),
+        }
+    }
+    for (session, mut entries) in sessions.iter().zip(chunked) {
+        entries.sort_by_key(|entry| session.iter().position(|name| *name == entry.name));
+        let mut entries = entries.into_iter();
+        let Some(mut first) = entries.next() else {
+            continue;
+        };
+        first.chunks = vec![first.name.clone()];
+        for mut entry in entries {
+            first.size += entry.size;
+            first.matches.append(&mut entry.matches);
+            first.recovered |= entry.recovered;
+            first.chunks.push(entry.name);
+        }
+        grouped.push(first);
+    }
+    grouped
+}
+
+fn sort(listed: &mut [Listed], key: SortKey, order: Order) {
+    match key {
+        SortKey::Created => listed.sort_by_key(|l| (l.created.or(l.modified), l.name.clone())),
+        SortKey::Size => listed.sort_by_key(|l| (l.size, l.name.clone())),
+        SortKey::Name => listed.sort_by(|a, b| a.name.cmp(&b.name)),
+    }
+    if order == Order::Desc {
+        listed.reverse();
+    }
+}
+
+/// leave a `<file>.recover` flag next to a partial file, for the recovery scan
+pub fn flag_for_recovery(file: &Path) -> std::io::Result<()> {
+    let mut flag = file.as_os_str().to_owned();
+    flag.push(".recover");
+    std::fs::write(flag, b"")
+}
+
+/// An fsync of a file or a directory, a test double sees what a strict recording makes durable
+pub trait Fsync: Send + Sync {
+    fn sync(&self, path: &Path) -> std::io::Result<()>;
+}
+
+/// The fsync of the disk
+pub struct Disk;
+
+impl Fsync for Disk {
+    fn sync(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::File::open(path)?.sync_all()
+    }
+}
+
+/// What the strictly durable recordings are fsynced with, the [Disk] by default
+#[derive(Clone)]
+pub struct Syncer(Arc<dyn Fsync>);
+
+impl Default for Syncer {
+    fn default() -> Self {
+        Self::new(Disk)
+    }
+}
+
+impl Syncer {
+    pub fn new(fsync: impl Fsync + 'static) -> Self {
+        Self(Arc::new(fsync))
+    }
+
+    /// fsync the file and the directory holding it, so both survive a power loss
+    pub fn sync_file_blocking(&self, path: &Path) -> std::io::Result<()> {
+        self.0.sync(path)?;
+        match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => self.0.sync(dir),
+            _ => Ok(()),
+        }
+    }
+
+    /// [Self::sync_file_blocking] off the runtime
+    pub async fn sync_file(&self, path: &Path) -> anyhow::Result<()> {
+        let syncer = self.clone();
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || syncer.sync_file_blocking(&path)).await??;
+        Ok(())
+    }
+}
+
+/// whether an mp4 can be played while it's still written: its `moov` comes before the media,
+/// as with `-movflags frag_keyframe+empty_moov`
+pub async fn is_fragmented_mp4(path: &Path) -> bool {
+    let Ok(mut file) = tokio::fs::File::open(path).await else {
+        return false;
+    };
+    let mut offset = 0u64;
+    let mut header = [0u8; 8];
+    // the first few boxes are enough: ftyp, free, then either moov or mdat
+    for _ in 0..8 {
+        if file.seek(std::io::SeekFrom::Start(offset)).await.is_err()
+            || file.read_exact(&mut header).await.is_err()
+        {
+            return false;
+        }
+        let size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64;
+        match &header[4..8] {
+            b"moov" => return true,
+            b"mdat" | b"moof" => return false,
+            _ if size < 8 => return false,
+            _ => offset += size,
+        }
+    }
+    false
+}
+
+/// whether the state is still writing to the file
+async fn is_growing(mx: &Arc<Recorder>, file: &str) -> bool {
+    being_written(&*mx.lock().await).contains(&file)
+}
+
+/// bytes of a file starting at `from`, following it while it grows
+///
+/// The stream ends once the file is no longer written by the current state
+/// and everything up to its final size was sent.
+pub fn tail(
+    mx: Arc<Recorder>,
+    path: PathBuf,
+    from: u64,
+) -> impl Stream<Item = std::io::Result<Bytes>> {
+    futures::stream::try_unfold(None, move |file: Option<tokio::fs::File>| {
+        let mx = mx.clone();
+        let path = path.clone();
+        async move {
+            let mut file = match file {
+                Some(file) => file,
+                None => {
+                    let mut file = tokio::fs::File::open(&path).await?;
+                    file.seek(std::io::SeekFrom::Start(from)).await?;
+                    file
+                }
+            };
+            let name = path.to_string_lossy().to_string();
+            let mut buf = vec![0u8; CHUNK];
+            loop {
+                // check before reading, so nothing written meanwhile is missed at the end
+                let growing = is_growing(&mx, &name).await;
+                let n = file.read(&mut buf).await?;
+                if n > 0 {
+                    buf.truncate(n);
+                    return Ok(Some((Bytes::from(buf), Some(file))));
+                }
+                if !growing {
+                    return Ok(None);
+                }
+                tokio::time::sleep(TAIL_POLL).await;
+            }
+        }
+    })
+}
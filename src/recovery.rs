@@ -0,0 +1,232 @@
+//! Picking a recording up after the server crashed
+//!
+//! Every state the recorder switches to is written to the [StateFile], `.record-screen-state.json`
+//! in the output directory, by a task of its own: the state is replaced by a write of a temporary
+//! file and a rename, so that a crash in the middle leaves the previous state, and the latest
+//! state wins when they come faster than the disk. A file that can't be read is quarantined.
+//!
+//! When the server starts and no handoff was adopted, the state the crashed server left is looked
+//! at before the jobs are restored:
+//! - a Started recording whose capture still runs is adopted as a handed over one, watched by its
+//!   pid and the growth of its file; one whose capture is gone is stopped, compressing what it
+//!   wrote. The resilient audio is not adopted, its processes and segments are not persisted:
+//!   the audio processes left writing next to the capture are interrupted and their files flagged;
+//! - a Stopping recording has its capture interrupted if it still runs, and its file is flagged;
+//! - the compression of a Compressing recording is killed if it still runs, the journal of the
+//!   jobs restarts it;
+//! - nothing is left to do of the other states.
+//!
+//! A flagged file has a `<file>.recover` next to it and is listed as recovered, to be compressed
+//! by hand.
+use crate::container;
+use crate::handoff;
+use crate::recordings;
+use crate::schema::{self, Versioned};
+use crate::service::{Recorder, RecordingState};
+use chrono::{DateTime, Local};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::watch;
+use tracing::*;
+
+const FILE_NAME: &str = ".record-screen-state.json";
+
+/// The state of the recorder, as the server last saw it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Persisted {
+    pub at: DateTime<Local>,
+    /// the server that wrote it
+    pub server_pid: u32,
+    pub state: RecordingState,
+}
+
+impl Versioned for Persisted {
+    const KIND: &'static str = "recorder states";
+    const VERSION: u32 = 1;
+    const MIGRATIONS: &'static [schema::Migration] = &[schema::unversioned];
+}
+
+/// path of the state file of the output directory
+pub fn path(dir: &Path) -> PathBuf {
+    dir.join(FILE_NAME)
+}
+
+/// Where the state of the recorder is saved on every change
+pub struct StateFile {
+    latest: watch::Sender<RecordingState>,
+}
+
+impl StateFile {
+    /// start the task writing the states to `path`
+    pub fn open(path: PathBuf) -> Self {
+        let (latest, mut changed) = watch::channel(RecordingState::Waiting);
+        tokio::spawn(async move {
+            while changed.changed().await.is_ok() {
+                let persisted = Persisted {
+                    at: Local::now(),
+                    server_pid: std::process::id(),
+                    state: changed.borrow_and_update().clone(),
+                };
+                let path = path.clone();
+                let written = tokio::task::spawn_blocking(move || write(&path, &persisted)).await;
+                if let Ok(Err(e)) = written {
+                    warn!("cannot save the recorder state: {}", e);
+                }
+            }
+        });
+        Self { latest }
+    }
+
+    /// have the state written, replacing the one not written yet
+    pub fn save(&self, state: &RecordingState) {
+        self.latest.send_replace(state.clone());
+    }
+}
+
+fn write(path: &Path, persisted: &Persisted) -> anyhow::Result<()> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+    let mut file = std::fs::File::create(&partial)?;
+    file.write_all(&schema::to_vec_pretty(persisted)?)?;
+    file.sync_all()?;
+    std::fs::rename(&partial, path)?;
+    Ok(())
+}
+
+/// the state the previous server left in `dir`, None when it left none or one that can't be read
+pub fn take(dir: &Path) -> Option<Persisted> {
+    let path = path(dir);
+    let json = match std::fs::read(&path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            warn!("cannot read {}: {}", path.display(), e);
+            return None;
+        }
+    };
+    match schema::from_slice::<Persisted>(&json) {
+        Ok((persisted, _)) => Some(persisted),
+        Err(e) => {
+            warn!("ignoring the recorder state {}: {}", path.display(), e);
+            if let Err(e) = schema::quarantine(&path) {
+                warn!("cannot quarantine {}: {}", path.display(), e);
+            }
+            None
+        }
+    }
+}
+
+/// pick up what the crashed server left running
+///
+/// To be awaited before the jobs are restored, which wait for the recorder to be idle.
+pub async fn recover(mx: Arc<Recorder>, left: Persisted) {
+    let from = format!(
+        "recovered after the crash of the server {} at {}",
+        left.server_pid,
+        left.at.to_rfc3339()
+    );
+    // the capture of a paused recording is gone, the recording is adopted to be stopped as it is
+    let left_state = match left.state {
+        RecordingState::Paused { recording, .. } => *recording,
+        state => state,
+    };
+    match left_state {
+        RecordingState::Started {
+            process_id,
+            ref file,
+            ..
+        } => {
+            for (pid, file) in orphans(file, process_id) {
+                warn!("interrupting the orphan audio {} of {}", pid, file);
+                signal(pid, Signal::SIGINT);
+                flag(Path::new(&file));
+            }
+            let mut state = left_state.clone();
+            if let RecordingState::Started {
+                audio, warnings, ..
+            } = &mut state
+            {
+                if audio.take().is_some() {
+                    warnings.push("the resilient audio was not recovered".to_string());
+                }
+            }
+            handoff::adopt_state(mx, state, from).await;
+        }
+        RecordingState::Stopping { process_id, file } => {
+            if handoff::is_capture(process_id, &file) {
+                warn!("interrupting the orphan capture {} of {}", process_id, file);
+                signal(process_id, Signal::SIGINT);
+            }
+            warn!("the recording {} was being stopped, {}", file, from);
+            flag(Path::new(&file));
+        }
+        RecordingState::Compressing {
+            process_id, output, ..
+        } => {
+            if handoff::is_capture(process_id, &output) {
+                warn!(
+                    "killing the orphan compression {} of {}",
+                    process_id, output
+                );
+                signal(process_id, Signal::SIGKILL);
+            }
+        }
+        // the job is restarted from the journal, compressing again
+        RecordingState::Uploading { .. } => {}
+        RecordingState::Waiting
+        | RecordingState::Countdown { .. }
+        | RecordingState::Paused { .. }
+        | RecordingState::Done { .. }
+        | RecordingState::Failed { .. }
+        | RecordingState::Cancelled { .. } => {}
+    }
+}
+
+fn signal(pid: u32, signal: Signal) {
+    if let Err(e) = kill(Pid::from_raw(pid as i32), signal) {
+        warn!("cannot signal {}: {}", pid, e);
+    }
+}
+
+fn flag(file: &Path) {
+    if let Err(e) = recordings::flag_for_recovery(file) {
+        warn!("cannot flag {} for recovery: {}", file.display(), e);
+    }
+}
+
+/// the processes other than the capture writing the files that go with `capture`, and the file
+fn orphans(capture: &str, process_id: u32) -> Vec<(u32, String)> {
+    let prefix = format!("{}.", container::base(capture));
+    let Ok(procs) = std::fs::read_dir("/proc") else {
+        return vec![];
+    };
+    let mut found = vec![];
+    for entry in procs.flatten() {
+        let Some(pid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|s| s.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        if pid == process_id || pid == std::process::id() {
+            continue;
+        }
+        let Ok(cmdline) = std::fs::read(entry.path().join("cmdline")) else {
+            continue;
+        };
+        let file = cmdline
+            .split(|&b| b == 0)
+            .map(String::from_utf8_lossy)
+            .find(|arg| arg.starts_with(&prefix) && arg != capture);
+        if let Some(file) = file {
+            found.push((pid, file.to_string()));
+        }
+    }
+    found
+}
@@ -0,0 +1,199 @@
+//! Streaming the capture to an RTMP server, a local nginx-rtmp or Twitch say
+//!
+//! With `rtmp_url`, the capture is encoded for a live stream rather than losslessly: libx264 at
+//! the constant `rtmp_bitrate`, [DEFAULT_BITRATE] by default, with a keyframe every
+//! [KEYFRAME_SECONDS] and AAC audio, sent as flv. The tee muxer writes that same encode to the
+//! capture file as well, which is compressed as any other once stopped; with `stream_only`
+//! nothing is written, and the recorder is Waiting again once the stream is stopped.
+//!
+//! The server has ten seconds to take the stream or a packet of it. A capture that exits while it streams, the server
+//! refusing it or dropping it later, fails the recording with the end of the stderr of ffmpeg,
+//! the capture file being flagged for recovery.
+//!
+//! The URL usually holds the credentials or the stream key: the options of a recording have it
+//! [redact]ed as soon as it started, the pipeline keeps it for the captures, and it is redacted
+//! from their commands as well.
+use crate::container::Container;
+use crate::ffmpeg;
+use crate::pulse;
+use crate::service::RecordingOptions;
+use crate::source::CaptureSource;
+use serde::{Deserialize, Serialize};
+
+/// the bitrate of the stream when none is asked for, in kbit/s
+pub const DEFAULT_BITRATE: u32 = 4500;
+/// the lowest bitrate of the stream, in kbit/s
+pub const MIN_BITRATE: u32 = 100;
+/// the highest bitrate of the stream, in kbit/s
+pub const MAX_BITRATE: u32 = 50_000;
+/// how far apart the keyframes of the stream are, what the ingests ask for
+pub const KEYFRAME_SECONDS: u32 = 2;
+/// how long the server may take to answer, in microseconds as `rw_timeout` takes it
+const TIMEOUT: &str = "10000000";
+const AUDIO_BITRATE: &str = "160k";
+/// what stands for the credentials and the stream key
+const REDACTED: &str = "***";
+
+/// Where the capture is streamed to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Streaming {
+    /// the RTMP server, without its credentials or the stream key
+    pub url: String,
+    pub bitrate_kbps: u32,
+    /// the capture file is written along with the stream
+    pub recording: bool,
+}
+
+impl Streaming {
+    /// how the options stream, None unless they do
+    pub fn new(opt: &RecordingOptions) -> Option<Self> {
+        Some(Self {
+            url: redact(opt.rtmp_url.as_deref()?),
+            bitrate_kbps: bitrate(opt),
+            recording: !opt.stream_only,
+        })
+    }
+}
+
+/// the bitrate of the stream, in kbit/s
+pub fn bitrate(opt: &RecordingOptions) -> u32 {
+    opt.rtmp_bitrate.unwrap_or(DEFAULT_BITRATE)
+}
+
+/// the URL without the user and the password, the path after the application or the query
+pub fn redact(url: &str) -> String {
+    let Ok(mut parsed) = reqwest::Url::parse(url) else {
+        return REDACTED.to_string();
+    };
+    if !parsed.username().is_empty() || parsed.password().is_some() {
+        let _ = parsed.set_username(REDACTED);
+        let _ = parsed.set_password(None);
+    }
+    let path = parsed.path().trim_start_matches('/').to_string();
+    if let Some((app, _)) = path.split_once('/') {
+        parsed.set_path(&format!("/{}/{}", app, REDACTED));
+    }
+    if parsed.query().is_some() {
+        parsed.set_query(Some(REDACTED));
+    }
+    parsed.to_string()
+}
+
+/// keep the URL of the stream out of the options, which are shown and saved: it is replaced
+/// by its redacted form there, and returned
+pub fn conceal(opt: &mut RecordingOptions) -> Option<String> {
+    let url = opt.rtmp_url.take()?;
+    opt.rtmp_url = Some(redact(&url));
+    Some(url)
+}
+
+/// whether the stream the options ask for can be sent, the field that can't otherwise
+pub fn validate(opt: &RecordingOptions) -> Result<(), (&'static str, String)> {
+    let Some(url) = &opt.rtmp_url else {
+        if opt.rtmp_bitrate.is_some() {
+            return Err(("rtmp_bitrate", "given without rtmp_url".to_string()));
+        }
+        if opt.stream_only {
+            return Err(("stream_only", "given without rtmp_url".to_string()));
+        }
+        return Ok(());
+    };
+    match reqwest::Url::parse(url) {
+        Ok(url) if matches!(url.scheme(), "rtmp" | "rtmps") && url.host_str().is_some() => {}
+        _ => return Err(("rtmp_url", "must be an rtmp:// or rtmps:// URL".to_string())),
+    }
+    if let Some(bitrate) = opt.rtmp_bitrate {
+        if !(MIN_BITRATE..=MAX_BITRATE).contains(&bitrate) {
+            return Err((
+                "rtmp_bitrate",
+                format!("must be within {}..{} kbit/s", MIN_BITRATE, MAX_BITRATE),
+            ));
+        }
+    }
+    if !opt.encoder.is_software() {
+        return Err(("encoder", "a stream is encoded with libx264".to_string()));
+    }
+    if opt.audio_resilient {
+        return Err((
+            "audio_resilient",
+            "the resilient audio is recorded apart, not in the stream".to_string(),
+        ));
+    }
+    if opt.stream_only && opt.segment_seconds.is_some() {
+        return Err((
+            "segment_seconds",
+            "nothing is written with stream_only".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// the options of the encoding of the stream, of a capture at `framerate`
+pub fn encode_options(opt: &RecordingOptions, framerate: &str) -> Vec<(&'static str, String)> {
+    let bitrate = bitrate(opt);
+    let gop = framerate.parse::<u32>().unwrap_or(25) * KEYFRAME_SECONDS;
+    let mut options = vec![
+        ("c:v", "libx264".to_string()),
+        ("preset", "veryfast".to_string()),
+        ("tune", "zerolatency".to_string()),
+        ("pix_fmt", "yuv420p".to_string()),
+        ("b:v", format!("{}k", bitrate)),
+        ("maxrate", format!("{}k", bitrate)),
+        ("bufsize", format!("{}k", bitrate * 2)),
+        ("g", gop.to_string()),
+        ("keyint_min", gop.to_string()),
+    ];
+    if has_audio(opt) {
+        options.extend([
+            ("c:a", "aac".to_string()),
+            ("b:a", AUDIO_BITRATE.to_string()),
+        ]);
+    }
+    options
+}
+
+/// whether the capture has audio to stream
+fn has_audio(opt: &RecordingOptions) -> bool {
+    match opt.source {
+        CaptureSource::Screen => opt.audio && !opt.audio_resilient,
+        _ => true,
+    }
+}
+
+/// the streams of the inputs of the capture, which the tee muxer needs mapped
+pub fn maps(opt: &RecordingOptions) -> Vec<&'static str> {
+    match (&opt.source, has_audio(opt)) {
+        (CaptureSource::Screen, true) => vec!["0:v", pulse::audio_stream(opt)],
+        (CaptureSource::Screen, false) => vec!["0:v"],
+        _ => vec!["0:v", "0:a?"],
+    }
+}
+
+/// the options of an output of flv to the server, but the URL
+pub fn flv_options() -> Vec<(&'static str, &'static str)> {
+    vec![("f", "flv"), ("rw_timeout", TIMEOUT)]
+}
+
+/// the outputs of the tee muxer: the capture `file`, the chunks of `segment_time` seconds of its
+/// pattern with one, and the stream to `url`
+pub fn tee(file: &str, format: Container, segment_time: Option<&str>, url: &str) -> String {
+    let file_options = match segment_time {
+        Some(seconds) => format!("f=segment:segment_time={}:reset_timestamps=1", seconds),
+        None => format!("f={}", muxer(format)),
+    };
+    format!(
+        "[{}]{}|[f=flv:onfail=abort:rw_timeout={}]{}",
+        file_options,
+        ffmpeg::escape_tee(file),
+        TIMEOUT,
+        ffmpeg::escape_tee(url)
+    )
+}
+
+/// the ffmpeg muxer of the capture in the container
+fn muxer(format: Container) -> &'static str {
+    match format.capture() {
+        Container::Mp4 => "mp4",
+        Container::Mkv | Container::Webm => "matroska",
+    }
+}
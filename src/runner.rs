@@ -1,27 +1,278 @@
-use serde::Serialize;
-use std::{process::Child, time::Duration};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 use futures::{
     channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
-    SinkExt,
+    SinkExt, Stream, StreamExt,
 };
 use thiserror::Error;
 use tokio::{
-    io::{AsyncBufReadExt, BufReader},
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader},
     net::TcpListener,
+    process::Child,
 };
+use tracing::info;
 
-use crate::ffmpeg::{FfmpegBuilder, Parameter};
+use crate::ffmpeg::{argv, shell_quote, FfmpegBuilder, Parameter};
+
+pub use tokio_util::sync::CancellationToken;
 
 type Result<T> = std::result::Result<T, Error>;
 
+/// How many lines of stderr a [CompletionSummary] keeps.
+pub const STDERR_TAIL: usize = 20;
+
+/// How long a cancelled ffmpeg may take to finish its output before it's killed.
+pub const CANCEL_GRACE: Duration = Duration::from_secs(5);
+
 /// A running instance of ffmpeg.
+///
+/// Lifecycle guarantees:
+/// - [Self::progress] always ends with a [Status::End] progress or with an error,
+///   even when ffmpeg dies without saying goodbye.
+/// - [Self::wait_with_progress] and [Self::wait_with_progress_or_cancel] always reap the process,
+///   and read its stderr meanwhile, so a piped stderr can never fill up and block ffmpeg.
 #[derive(Debug)]
 pub struct Ffmpeg {
     /// The stream of progress events emitted by ffmpeg.
-    pub progress: UnboundedReceiver<Result<Progress>>,
+    pub progress: ProgressStream,
     /// The actual ffmpeg process.
     pub process: Child,
+    /// tokio forgets the id once the process is reaped
+    pid: u32,
+    started: Instant,
+    argv: Vec<String>,
+    stderr_hook: Option<StderrHook>,
+}
+
+/// Sees every line of a piped stderr, see [Ffmpeg::on_stderr].
+pub struct StderrHook(Box<dyn FnMut(&str) -> bool + Send>);
+
+impl std::fmt::Debug for StderrHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("StderrHook")
+    }
+}
+
+/// The stream of progress events of a running ffmpeg.
+#[derive(Debug)]
+pub struct ProgressStream {
+    rx: UnboundedReceiver<Result<Progress>>,
+    finished: bool,
+}
+
+impl Stream for ProgressStream {
+    type Item = Result<Progress>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.finished {
+            return Poll::Ready(None);
+        }
+        let item = match self.rx.poll_next_unpin(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Some(Ok(progress))) => {
+                self.finished = matches!(progress.status, Status::End);
+                Ok(progress)
+            }
+            Poll::Ready(Some(Err(e))) => {
+                self.finished = true;
+                Err(e)
+            }
+            Poll::Ready(None) => {
+                self.finished = true;
+                Err(Error::Disconnected)
+            }
+        };
+        Poll::Ready(Some(item))
+    }
+}
+
+/// How a finished ffmpeg went.
+#[derive(Debug, Clone)]
+pub struct CompletionSummary {
+    /// The exit status of the process.
+    pub exit_status: ExitStatus,
+    /// The last progress event, if there was any.
+    pub last_progress: Option<Progress>,
+    /// How long the process ran.
+    pub wall_time: Duration,
+    /// The last [STDERR_TAIL] lines of stderr, when it was piped.
+    pub stderr_tail: Vec<String>,
+    /// Whether it was stopped through the cancellation token.
+    pub cancelled: bool,
+}
+
+impl CompletionSummary {
+    /// Whether ffmpeg exited successfully.
+    pub fn success(&self) -> bool {
+        self.exit_status.success()
+    }
+}
+
+impl Ffmpeg {
+    /// The process id of ffmpeg.
+    pub fn id(&self) -> u32 {
+        self.pid
+    }
+
+    /// The program and the arguments ffmpeg was spawned with.
+    pub fn argv(&self) -> &[String] {
+        &self.argv
+    }
+
+    /// Calls `hook` with every line of the piped stderr as it is read.
+    ///
+    /// The lines it returns true for are taken: they are left out of the
+    /// [CompletionSummary::stderr_tail]. The hook is dropped once stderr is closed.
+    pub fn on_stderr(&mut self, hook: impl FnMut(&str) -> bool + Send + 'static) {
+        self.stderr_hook = Some(StderrHook(Box::new(hook)));
+    }
+
+    /// Drives the progress stream to its end, calling `on_progress` with every event,
+    /// then waits for the process to exit.
+    pub async fn wait_with_progress(
+        self,
+        on_progress: impl FnMut(&Progress),
+    ) -> Result<CompletionSummary> {
+        self.reap(None, on_progress).await
+    }
+
+    /// Like [Self::wait_with_progress], interrupting ffmpeg once `cancel` is cancelled.
+    ///
+    /// Ffmpeg gets SIGINT so that it can still finish its output, and SIGKILL when it takes
+    /// longer than [CANCEL_GRACE].
+    pub async fn wait_with_progress_or_cancel(
+        self,
+        cancel: &CancellationToken,
+        on_progress: impl FnMut(&Progress),
+    ) -> Result<CompletionSummary> {
+        self.reap(Some(cancel), on_progress).await
+    }
+
+    /// Waits for ffmpeg to exit and reaps it, without blocking the runtime.
+    ///
+    /// The progress and stderr are left alone, see [Self::wait_with_progress] for a piped stderr.
+    pub async fn wait(&mut self) -> Result<ExitStatus> {
+        Ok(self.process.wait().await?)
+    }
+
+    /// Asks ffmpeg to finish its output and waits for it, killing it after `timeout`.
+    ///
+    /// Ffmpeg is asked with `q` on a piped stdin, otherwise with SIGINT.
+    pub async fn stop_graceful(mut self, timeout: Duration) -> Result<ExitStatus> {
+        let asked = match self.process.stdin.as_mut() {
+            Some(stdin) => stdin.write_all(b"q").await.is_ok() && stdin.flush().await.is_ok(),
+            None => false,
+        };
+        let pid = nix::unistd::Pid::from_raw(self.pid as i32);
+        if !asked {
+            let _ = nix::sys::signal::kill(pid, nix::sys::signal::Signal::SIGINT);
+        }
+        match tokio::time::timeout(timeout, self.wait()).await {
+            Ok(status) => status,
+            Err(_) => {
+                let _ = nix::sys::signal::kill(pid, nix::sys::signal::Signal::SIGKILL);
+                self.wait().await
+            }
+        }
+    }
+
+    async fn reap(
+        mut self,
+        cancel: Option<&CancellationToken>,
+        mut on_progress: impl FnMut(&Progress),
+    ) -> Result<CompletionSummary> {
+        let hook = self.stderr_hook.take();
+        let stderr = self
+            .process
+            .stderr
+            .take()
+            .map(|stderr| tokio::spawn(tail_lines(stderr, STDERR_TAIL, hook)));
+        let pid = nix::unistd::Pid::from_raw(self.pid as i32);
+        let mut last_progress = None;
+        let mut cancelled = false;
+        loop {
+            let next = match cancel {
+                Some(token) if !cancelled => tokio::select! {
+                    next = self.progress.next() => next,
+                    _ = token.cancelled() => {
+                        cancelled = true;
+                        let _ = nix::sys::signal::kill(pid, nix::sys::signal::Signal::SIGINT);
+                        continue;
+                    }
+                },
+                _ => self.progress.next().await,
+            };
+            match next {
+                Some(Ok(progress)) => {
+                    on_progress(&progress);
+                    last_progress = Some(progress);
+                }
+                // the stream ends after an error
+                Some(Err(_)) => {}
+                None => break,
+            }
+        }
+
+        let exit_status = if cancelled {
+            match tokio::time::timeout(CANCEL_GRACE, self.wait()).await {
+                Ok(status) => status,
+                Err(_) => {
+                    let _ = nix::sys::signal::kill(pid, nix::sys::signal::Signal::SIGKILL);
+                    self.wait().await
+                }
+            }
+        } else {
+            self.wait().await
+        }?;
+        let stderr_tail = match stderr {
+            Some(reader) => reader.await.unwrap_or_default(),
+            None => vec![],
+        };
+        Ok(CompletionSummary {
+            exit_status,
+            last_progress,
+            wall_time: self.started.elapsed(),
+            stderr_tail,
+            cancelled,
+        })
+    }
+}
+
+/// the last `n` lines of the output, progress lines ending with `\r` included,
+/// the lines taken by the hook left out
+async fn tail_lines(
+    output: impl AsyncRead + Unpin,
+    n: usize,
+    mut hook: Option<StderrHook>,
+) -> Vec<String> {
+    let mut reader = BufReader::new(output);
+    let mut lines = VecDeque::with_capacity(n);
+    let mut buf = vec![];
+    loop {
+        buf.clear();
+        match reader.read_until(b'\n', &mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        let text = String::from_utf8_lossy(&buf);
+        let Some(line) = text.split('\r').map(str::trim_end).rfind(|l| !l.is_empty()) else {
+            continue;
+        };
+        if hook.as_mut().is_some_and(|StderrHook(hook)| hook(line)) {
+            continue;
+        }
+        if lines.len() == n {
+            lines.pop_front();
+        }
+        lines.push_back(line.to_string());
+    }
+    lines.into()
 }
 
 /// A progress event emitted by ffmpeg.
@@ -29,8 +280,7 @@ pub struct Ffmpeg {
 /// Names of the fields directly correspond to the names in the output of ffmpeg's `-progress`.  
 /// Everything is wrapped in an option because this has no docs I can find, so I can't guarantee
 /// that they will all be in the data ffmpeg sends.
-/// Note that bitrate is ignored because I'm not sure of the exact format it's in. Blame ffmpeg.  
-#[derive(Debug, Clone, Default, Serialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Progress {
     /// What frame ffmpeg is on.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -53,14 +303,74 @@ pub struct Progress {
     /// How fast it is processing, relative to 1x playback speed.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub speed: Option<f64>,
+    /// The bitrate of the output so far, in kbit/s, None while ffmpeg says `N/A`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bitrate_kbps: Option<f64>,
     /// What ffmpeg will do now.
     pub status: Status,
+    /// The quality of every output stream, from the `stream_<file>_<stream>_q` keys.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub streams: Vec<StreamProgress>,
+    /// The size on disk of every output file, when there are several.
+    ///
+    /// Ffmpeg's [Self::total_size] is the sum of all of them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub outputs: Vec<OutputProgress>,
+}
+
+/// The progress of a single output stream.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StreamProgress {
+    /// The index of the output file.
+    pub file: usize,
+    /// The index of the stream in that file.
+    pub stream: usize,
+    /// The quantizer of the last frame, `-1` for streams that are copied.
+    pub q: f64,
+}
+
+/// The progress of a single output file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutputProgress {
+    /// The path of the output.
+    pub path: String,
+    /// What the file takes on disk now, if it exists.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size_on_disk: Option<u64>,
 }
 
 use color_eyre::owo_colors::OwoColorize;
 use num_format::{Locale, ToFormattedString};
 
 impl Progress {
+    /// The size written so far: of the given output, or of all of them.
+    ///
+    /// A single output takes all of [Self::total_size].
+    pub fn size_of(&self, output: Option<&str>) -> Option<u64> {
+        match output {
+            Some(path) if !self.outputs.is_empty() => self
+                .outputs
+                .iter()
+                .find(|o| o.path == path)
+                .and_then(|o| o.size_on_disk),
+            _ => self.total_size,
+        }
+    }
+
+    /// the progress as an event of the log, its numbers as fields
+    pub fn log(&self) {
+        info!(
+            status = ?self.status,
+            frame = self.frame,
+            fps = self.fps,
+            total_size = self.total_size,
+            speed = self.speed,
+            drop_frames = self.drop_frames,
+            bitrate_kbps = self.bitrate_kbps,
+            "progress"
+        );
+    }
+
     pub fn print_info(&self) -> String {
         let mut out = format!("{}", &self.status.yellow());
         if let Some(frame) = self.frame {
@@ -92,14 +402,29 @@ impl Progress {
                 out += &format!(" fps: {:>8}", fps.to_string().yellow());
             }
         }
+        if let Some(bitrate) = self.bitrate_kbps {
+            out += &format!(" bitrate: {:>10}", format!("{:.1}kbit/s", bitrate).yellow());
+        }
+        for output in &self.outputs {
+            let name = std::path::Path::new(&output.path)
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_else(|| output.path.clone());
+            let size = output
+                .size_on_disk
+                .map(|s| s.to_formatted_string(&Locale::en))
+                .unwrap_or_else(|| "-".to_string());
+            out += &format!(" | {} {}", name, size.yellow());
+        }
         out
     }
 }
 
 /// What ffmpeg is going to do next.
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub enum Status {
     /// Ffmpeg will continue emitting progress events.
+    #[default]
     Continue,
     /// Ffmpeg has finished processing.
     ///
@@ -107,12 +432,6 @@ pub enum Status {
     End,
 }
 
-impl Default for Status {
-    fn default() -> Self {
-        Self::Continue
-    }
-}
-
 // implement display for Status
 impl std::fmt::Display for Status {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -146,7 +465,39 @@ pub enum Error {
     /// Can only be a float or int parsing error.
     /// The String is what it was trying to parse.
     #[error("Parse Error: {0}")]
-    OtherParseError(#[source] Box<dyn std::error::Error + Send>, String),
+    OtherParseError(#[source] Box<dyn std::error::Error + Send + Sync>, String),
+    /// Ffmpeg exited before it connected to report progress.
+    ///
+    /// The last line of stderr usually tells why, like `Unknown encoder`.
+    #[error("Ffmpeg exited before reporting progress: {status}{}", last_line(.stderr_tail))]
+    Exited {
+        status: ExitStatus,
+        /// The last [STDERR_TAIL] lines of stderr, when it was piped.
+        stderr_tail: Vec<String>,
+    },
+    /// The progress connection closed without an end status, ffmpeg was probably killed.
+    #[error("Progress ended without an end status")]
+    Disconnected,
+    /// Two options of a file contradict each other, see [FfmpegBuilder::to_command].
+    #[error("Conflicting options: {0}")]
+    Conflict(String),
+}
+
+impl Error {
+    /// The end of stderr of an ffmpeg that exited at once, empty for any other error.
+    pub fn stderr_tail(&self) -> &[String] {
+        match self {
+            Self::Exited { stderr_tail, .. } => stderr_tail,
+            _ => &[],
+        }
+    }
+}
+
+fn last_line(tail: &[String]) -> String {
+    match tail.last() {
+        Some(line) => format!(", {}", line),
+        None => String::new(),
+    }
 }
 
 impl<'a> FfmpegBuilder<'a> {
@@ -154,116 +505,273 @@ impl<'a> FfmpegBuilder<'a> {
     ///
     /// This has to consume the builder for stdin, etc to work
     pub async fn run(mut self) -> Result<Ffmpeg> {
-        let listener = TcpListener::bind("127.0.0.1:0").await?;
-        let port = listener.local_addr()?.port();
-        let prog_url = format!("tcp://127.0.0.1:{}", port);
+        // the socket file goes away with the listener, whichever way this returns
+        let listener = ProgressListener::bind().await?;
+        let prog_url = listener.url();
 
+        let redactions = std::mem::take(&mut self.redactions);
+        let redact = |arg: &str| {
+            redactions
+                .iter()
+                .fold(arg.to_string(), |arg, (secret, shown)| {
+                    arg.replace(secret, shown)
+                })
+        };
+        // ffmpeg reports their total size only
+        let outputs: Vec<String> = match self.outputs.len() {
+            0 | 1 => vec![],
+            _ => self.outputs.iter().map(|f| redact(f.url)).collect(),
+        };
         self = self.option(Parameter::KeyValue("progress", &prog_url));
-        let mut command = self.to_command();
-        println!("command {:?}", command);
-        let child = command.spawn()?;
+        let mut command = self.to_command()?;
+        let argv: Vec<String> = argv(&command).iter().map(|arg| redact(arg)).collect();
+        info!(command = %shell_quote(&argv), "running ffmpeg");
+        let started = Instant::now();
+        let mut child = command.spawn()?;
+        let pid = child.id().unwrap_or_default();
 
-        let conn = listener.accept().await?.0;
+        let conn = tokio::select! {
+            conn = listener.accept() => conn?,
+            status = child.wait() => {
+                let status = status?;
+                // it is gone, so its stderr is read to the end at once
+                let stderr_tail = match child.stderr.take() {
+                    Some(stderr) => tail_lines(stderr, STDERR_TAIL, None).await,
+                    None => vec![],
+                };
+                return Err(Error::Exited { status, stderr_tail });
+            }
+        };
+        drop(listener);
 
-        let (mut tx, rx) = mpsc::unbounded();
+        let (tx, rx) = mpsc::unbounded();
+        tokio::spawn(read_progress(conn, outputs, tx));
 
-        tokio::spawn(async move {
-            let mut reader = BufReader::new(conn);
-            let mut progress: Progress = Default::default();
+        Ok(Ffmpeg {
+            progress: ProgressStream {
+                rx,
+                finished: false,
+            },
+            process: child,
+            pid,
+            started,
+            argv,
+            stderr_hook: None,
+        })
+    }
 
-            loop {
-                let mut line = String::new();
-                let read = reader.read_line(&mut line).await;
+    /// Runs ffmpeg until it exits, ignoring the progress on the way.
+    pub async fn run_to_completion(self) -> Result<CompletionSummary> {
+        self.run().await?.wait_with_progress(|_| {}).await
+    }
+}
 
-                match read {
-                    Ok(n) => {
-                        if n == 0 {
-                            tx.close_channel();
-                            break;
-                        }
-                    }
-                    Err(e) => {
-                        let _ = tx.send(Err(e.into())).await;
-                        tx.close_channel();
-                    }
-                }
+/// Applies a `key=value` line of `-progress` to the block being read, returning the block
+/// once a `progress=` line completes it.
+///
+/// Unknown keys are ignored; `N/A` values, as ffmpeg has them before the first frame, leave
+/// the field unset.
+pub fn apply_line(progress: &mut Progress, line: &str) -> Result<Option<Progress>> {
+    let Some((key, value)) = parse_line(line) else {
+        return Err(Error::KeyValueParseError(line.to_owned()));
+    };
+    match key {
+        "frame" => progress.frame = parsed(value)?,
+        "fps" => progress.fps = parsed(value)?,
+        "bitrate" => {
+            progress.bitrate_kbps = parse_bitrate(value)
+                .transpose()
+                .map_err(|e| Error::OtherParseError(Box::new(e), value.to_owned()))?
+        }
+        "total_size" => progress.total_size = parsed(value)?,
+        "out_time_us" => progress.out_time = parsed(value)?.map(Duration::from_micros),
+        "dup_frames" => progress.dup_frames = parsed(value)?,
+        "drop_frames" => progress.drop_frames = parsed(value)?,
+        "speed" => progress.speed = parsed(value.strip_suffix('x').unwrap_or(value))?,
+        key if parse_stream_key(key).is_some() => {
+            if let (Some((file, stream)), Some(q)) = (parse_stream_key(key), parsed(value)?) {
+                progress.streams.push(StreamProgress { file, stream, q });
+            }
+        }
+        "progress" => {
+            progress.status = match value {
+                "continue" => Status::Continue,
+                "end" => Status::End,
+                x => return Err(Error::UnknownStatusError(x.to_owned())),
+            };
+            return Ok(Some(std::mem::take(progress)));
+        }
+        _ => {}
+    }
+    Ok(None)
+}
 
-                if let Some((key, value)) = parse_line(&line) {
-                    match key {
-                        "frame" => match value.parse() {
-                            Ok(x) => progress.frame = Some(x),
-                            Err(e) => handle_parse_error(&mut tx, e, value).await,
-                        },
-                        "fps" => match value.parse() {
-                            Ok(x) => progress.fps = Some(x),
-                            Err(e) => handle_parse_error(&mut tx, e, value).await,
-                        },
-                        // TOOD: bitrate
-                        "total_size" => match value.parse() {
-                            Ok(x) => progress.total_size = Some(x),
-                            Err(e) => handle_parse_error(&mut tx, e, value).await,
-                        },
-                        "out_time_us" => match value.parse() {
-                            Ok(us) => progress.out_time = Some(Duration::from_micros(us)),
-                            Err(e) => handle_parse_error(&mut tx, e, value).await,
-                        },
-                        "dup_frames" => match value.parse() {
-                            Ok(x) => progress.dup_frames = Some(x),
-                            Err(e) => handle_parse_error(&mut tx, e, value).await,
-                        },
-                        "drop_frames" => match value.parse() {
-                            Ok(x) => progress.drop_frames = Some(x),
-                            Err(e) => handle_parse_error(&mut tx, e, value).await,
-                        },
-                        "speed" => {
-                            let num = &value[..(value.len() - 1)];
-                            match num.parse() {
-                                Ok(x) => progress.speed = Some(x),
-                                Err(e) => handle_parse_error(&mut tx, e, num).await,
-                            }
-                        }
-                        "progress" => {
-                            progress.status = match value {
-                                "continue" => Status::Continue,
-                                "end" => Status::End,
-                                x => {
-                                    // This causes feeding the next thing to error
-                                    // However, we don't care
-                                    // We just ignore the error
-                                    let _ = tx.feed(Err(Error::UnknownStatusError(x.to_owned())));
-                                    tx.close_channel();
-
-                                    // Just give it a status so it compiles
-                                    Status::End
-                                }
-                            };
-                            match tx.feed(Ok(progress)).await {
-                                Ok(_) => {}
-                                Err(e) => {
-                                    if e.is_disconnected() {
-                                        tx.close_channel();
-                                    }
-                                }
-                            }
-                            progress = Default::default();
-                        }
-                        _ => {}
-                    }
-                } else {
-                    let _ = tx.send(Err(Error::KeyValueParseError(line)));
-                    tx.close_channel();
-                }
+/// the value of a key, None for `N/A`
+fn parsed<T>(value: &str) -> Result<Option<T>>
+where
+    T: std::str::FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match value {
+        "N/A" => Ok(None),
+        _ => value
+            .parse()
+            .map(Some)
+            .map_err(|e| Error::OtherParseError(Box::new(e), value.to_owned())),
+    }
+}
+
+/// The blocks of a `-progress` output as they are completed, ending after the first error.
+pub fn parse_progress_stream(
+    reader: impl AsyncBufRead + Unpin,
+) -> impl Stream<Item = Result<Progress>> {
+    futures::stream::unfold(Some((reader, Progress::default())), |state| async move {
+        let (mut reader, mut progress) = state?;
+        loop {
+            let mut line = String::new();
+            match reader.read_line(&mut line).await {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(e) => return Some((Err(e.into()), None)),
+            }
+            match apply_line(&mut progress, &line) {
+                Ok(Some(block)) => return Some((Ok(block), Some((reader, progress)))),
+                Ok(None) => {}
+                Err(e) => return Some((Err(e), None)),
             }
-        });
+        }
+    })
+}
 
-        Ok(Ffmpeg {
-            progress: rx,
-            process: child,
+/// passes the progress of ffmpeg on to `tx`, with the size of every output when there are several
+async fn read_progress(
+    conn: impl AsyncRead + Unpin,
+    outputs: Vec<String>,
+    mut tx: UnboundedSender<Result<Progress>>,
+) {
+    let blocks = parse_progress_stream(BufReader::new(conn));
+    tokio::pin!(blocks);
+    while let Some(mut block) = blocks.next().await {
+        if let Ok(progress) = &mut block {
+            for path in &outputs {
+                progress.outputs.push(OutputProgress {
+                    path: path.clone(),
+                    size_on_disk: tokio::fs::metadata(path).await.ok().map(|m| m.len()),
+                });
+            }
+        }
+        if tx.send(block).await.is_err() {
+            break;
+        }
+    }
+    tx.close_channel();
+}
+
+/// Where ffmpeg connects to report progress: a Unix socket, or a port of localhost where
+/// Unix sockets can't be bound
+enum ProgressListener {
+    Unix {
+        listener: tokio::net::UnixListener,
+        path: std::path::PathBuf,
+    },
+    Tcp(TcpListener),
+}
+
+/// The connection of ffmpeg to a [ProgressListener]
+type ProgressConnection = Pin<Box<dyn AsyncRead + Send>>;
+
+impl ProgressListener {
+    async fn bind() -> std::io::Result<Self> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let dir = dirs::runtime_dir().unwrap_or_else(std::env::temp_dir);
+        let path = dir.join(format!(
+            "record-screen-progress-{}-{}.sock",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        match tokio::net::UnixListener::bind(&path) {
+            Ok(listener) => Ok(Self::Unix { listener, path }),
+            Err(e) => {
+                tracing::debug!("cannot bind {}, reporting over TCP: {}", path.display(), e);
+                Ok(Self::Tcp(TcpListener::bind("127.0.0.1:0").await?))
+            }
+        }
+    }
+
+    /// the value of `-progress`
+    fn url(&self) -> String {
+        match self {
+            Self::Unix { path, .. } => format!("unix://{}", path.display()),
+            Self::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => format!("tcp://{}", addr),
+                Err(_) => String::new(),
+            },
+        }
+    }
+
+    async fn accept(&self) -> std::io::Result<ProgressConnection> {
+        Ok(match self {
+            Self::Unix { listener, .. } => Box::pin(listener.accept().await?.0),
+            Self::Tcp(listener) => Box::pin(listener.accept().await?.0),
         })
     }
 }
 
-fn parse_line<'a>(line: &'a str) -> Option<(&'a str, &'a str)> {
+impl Drop for ProgressListener {
+    fn drop(&mut self) {
+        if let Self::Unix { path, .. } = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Like [Ffmpeg::stop_graceful] for an ffmpeg whose handle is elsewhere: SIGINT, then SIGKILL
+/// when `reaped` didn't resolve within `timeout`.
+///
+/// A process that is gone already is fine, `reaped` tells when its reaper is done with it.
+/// Returns whether it had to be killed.
+pub async fn stop_graceful(
+    pid: u32,
+    timeout: Duration,
+    reaped: impl std::future::Future<Output = ()>,
+) -> bool {
+    let pid = nix::unistd::Pid::from_raw(pid as i32);
+    let _ = nix::sys::signal::kill(pid, nix::sys::signal::Signal::SIGINT);
+    tokio::pin!(reaped);
+    if tokio::time::timeout(timeout, &mut reaped).await.is_ok() {
+        return false;
+    }
+    let _ = nix::sys::signal::kill(pid, nix::sys::signal::Signal::SIGKILL);
+    reaped.await;
+    true
+}
+
+/// the kbit/s of a `bitrate` value like `512.3kbits/s`, None for `N/A`
+fn parse_bitrate(value: &str) -> Option<std::result::Result<f64, std::num::ParseFloatError>> {
+    let value = value.trim().to_lowercase();
+    if value == "n/a" {
+        return None;
+    }
+    let (number, scale) = if let Some(number) = value.strip_suffix("kbits/s") {
+        (number, 1.0)
+    } else if let Some(number) = value.strip_suffix("mbits/s") {
+        (number, 1000.0)
+    } else if let Some(number) = value.strip_suffix("bits/s") {
+        (number, 0.001)
+    } else {
+        (value.as_str(), 0.001)
+    };
+    Some(number.trim().parse::<f64>().map(|x| x * scale))
+}
+
+/// the output file and stream of a `stream_<file>_<stream>_q` key
+fn parse_stream_key(key: &str) -> Option<(usize, usize)> {
+    let indexes = key.strip_prefix("stream_")?.strip_suffix("_q")?;
+    let (file, stream) = indexes.split_once('_')?;
+    Some((file.parse().ok()?, stream.parse().ok()?))
+}
+
+fn parse_line(line: &str) -> Option<(&str, &str)> {
     let trimmed = line.trim();
     let mut iter = trimmed.splitn(2, '=');
 
@@ -277,14 +785,352 @@ fn parse_line<'a>(line: &'a str) -> Option<(&'a str, &'a str)> {
     Some((key, value))
 }
 
-async fn handle_parse_error(
-    tx: &mut UnboundedSender<Result<Progress>>,
-    e: impl std::error::Error + Send + 'static,
-    x: &str,
-) {
-    // Ignore the error because we're closing the channel anyway
-    let _ = tx
-        .send(Err(Error::OtherParseError(Box::new(e), x.to_owned())))
-        .await;
-    tx.close_channel();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffmpeg::File;
+
+    /// the lines of a block as ffmpeg writes them with `-progress`
+    const BLOCK: &str = "frame=120\n\
+                         fps=30.00\n\
+                         stream_0_0_q=23.0\n\
+                         bitrate=1024.5kbits/s\n\
+                         total_size=1048576\n\
+                         out_time_us=4000000\n\
+                         out_time_ms=4000000\n\
+                         out_time=00:00:04.000000\n\
+                         dup_frames=0\n\
+                         drop_frames=2\n\
+                         speed=1.5x\n\
+                         progress=continue\n";
+
+    fn apply_all(lines: &str) -> Vec<Progress> {
+        let mut progress = Progress::default();
+        lines
+            .lines()
+            .filter_map(|line| apply_line(&mut progress, line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn a_block_is_returned_once_complete() {
+        let mut progress = Progress::default();
+        let mut lines = BLOCK.lines();
+        for line in lines.by_ref().take(11) {
+            assert!(
+                apply_line(&mut progress, line).unwrap().is_none(),
+                "{}",
+                line
+            );
+        }
+        let block = apply_line(&mut progress, lines.next().unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(block.frame, Some(120));
+        assert_eq!(block.fps, Some(30.0));
+        assert_eq!(block.bitrate_kbps, Some(1024.5));
+        assert_eq!(block.total_size, Some(1048576));
+        assert_eq!(block.out_time, Some(Duration::from_secs(4)));
+        assert_eq!(block.dup_frames, Some(0));
+        assert_eq!(block.drop_frames, Some(2));
+        assert_eq!(block.speed, Some(1.5));
+        assert!(matches!(block.status, Status::Continue));
+        assert_eq!(
+            block.streams,
+            vec![StreamProgress {
+                file: 0,
+                stream: 0,
+                q: 23.0
+            }]
+        );
+        // the next block starts from scratch
+        assert_eq!(progress.frame, None);
+        assert!(progress.streams.is_empty());
+    }
+
+    #[test]
+    fn the_speed_drops_its_x() {
+        let blocks = apply_all("speed=1.5x\nprogress=continue");
+        assert_eq!(blocks[0].speed, Some(1.5));
+        let blocks = apply_all("speed= 0.98x\nprogress=continue");
+        assert_eq!(blocks[0].speed, Some(0.98));
+    }
+
+    #[test]
+    fn the_out_time_is_in_microseconds() {
+        let blocks = apply_all("out_time_us=1500\nprogress=continue");
+        assert_eq!(blocks[0].out_time, Some(Duration::from_micros(1500)));
+    }
+
+    #[test]
+    fn not_available_values_leave_the_fields_unset() {
+        let blocks =
+            apply_all("fps=N/A\nbitrate=N/A\nout_time_us=N/A\nspeed=N/A\nprogress=continue");
+        assert_eq!(blocks[0].fps, None);
+        assert_eq!(blocks[0].bitrate_kbps, None);
+        assert_eq!(blocks[0].out_time, None);
+        assert_eq!(blocks[0].speed, None);
+    }
+
+    #[test]
+    fn unknown_keys_are_ignored() {
+        let blocks = apply_all("frame=1\nsomething_new=42\nout_time=00:00:00.04\nprogress=end");
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].frame, Some(1));
+        assert!(matches!(blocks[0].status, Status::End));
+    }
+
+    #[test]
+    fn a_line_without_a_value_is_refused() {
+        let mut progress = Progress::default();
+        let e = apply_line(&mut progress, "frame 120").unwrap_err();
+        assert!(matches!(e, Error::KeyValueParseError(line) if line == "frame 120"));
+    }
+
+    #[test]
+    fn an_unknown_status_is_refused() {
+        let mut progress = Progress::default();
+        let e = apply_line(&mut progress, "progress=maybe").unwrap_err();
+        assert!(matches!(e, Error::UnknownStatusError(status) if status == "maybe"));
+    }
+
+    #[test]
+    fn a_value_that_is_no_number_is_refused() {
+        let mut progress = Progress::default();
+        let e = apply_line(&mut progress, "frame=many").unwrap_err();
+        assert!(matches!(e, Error::OtherParseError(_, value) if value == "many"));
+    }
+
+    #[test]
+    fn bitrates_are_in_kbits() {
+        let kbps = |value| parse_bitrate(value).map(|parsed| parsed.unwrap());
+        assert_eq!(kbps("512.3kbits/s"), Some(512.3));
+        assert_eq!(kbps(" 2.5mbits/s"), Some(2500.0));
+        assert_eq!(kbps("1500bits/s"), Some(1.5));
+        assert_eq!(kbps("1500"), Some(1.5));
+        assert_eq!(kbps("512.3KBits/s"), Some(512.3));
+        assert_eq!(kbps("N/A"), None);
+        assert!(parse_bitrate("fast kbits/s").unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn the_stream_yields_every_block() {
+        let output = format!("{}frame=240\nprogress=end\n", BLOCK);
+        let blocks: Vec<_> = parse_progress_stream(output.as_bytes()).collect().await;
+        assert_eq!(blocks.len(), 2);
+        let first = blocks[0].as_ref().unwrap();
+        assert_eq!(first.frame, Some(120));
+        assert!(matches!(first.status, Status::Continue));
+        let last = blocks[1].as_ref().unwrap();
+        assert_eq!(last.frame, Some(240));
+        assert!(matches!(last.status, Status::End));
+    }
+
+    #[tokio::test]
+    async fn the_stream_ends_after_an_error() {
+        let output = "frame=1\nprogress=continue\ngarbage\nframe=2\nprogress=continue\n";
+        let blocks: Vec<_> = parse_progress_stream(output.as_bytes()).collect().await;
+        assert_eq!(blocks.len(), 2);
+        assert!(blocks[0].is_ok());
+        assert!(matches!(blocks[1], Err(Error::KeyValueParseError(_))));
+    }
+
+    #[tokio::test]
+    async fn the_stream_waits_for_lines_written_in_pieces() {
+        let (mut ffmpeg, conn) = tokio::io::duplex(64);
+        let blocks = parse_progress_stream(BufReader::new(conn));
+        tokio::pin!(blocks);
+        ffmpeg.write_all(b"frame=7\nout_time_us=25").await.unwrap();
+        ffmpeg.write_all(b"0000\nprogress=cont").await.unwrap();
+        ffmpeg.write_all(b"inue\n").await.unwrap();
+        let block = blocks.next().await.unwrap().unwrap();
+        assert_eq!(block.frame, Some(7));
+        assert_eq!(block.out_time, Some(Duration::from_millis(250)));
+        // ffmpeg went away without a last block
+        ffmpeg.write_all(b"frame=8\n").await.unwrap();
+        drop(ffmpeg);
+        assert!(blocks.next().await.is_none());
+    }
+
+    /// an ffmpeg that refuses a format it doesn't know, run with `command`
+    async fn refused(command: &str) -> (Result<Ffmpeg>, Duration) {
+        let mut builder = FfmpegBuilder::new()
+            .stderr(std::process::Stdio::piped())
+            .input(File::new("nothing").option(Parameter::KeyValue("f", "nosuchformat")))
+            .output(File::new("-").option(Parameter::KeyValue("f", "null")));
+        builder.ffmpeg_command = command;
+        let started = Instant::now();
+        let run = tokio::time::timeout(Duration::from_secs(30), builder.run()).await;
+        (run.expect("still waiting for ffmpeg"), started.elapsed())
+    }
+
+    fn assert_exited(run: Result<Ffmpeg>) {
+        match run {
+            Err(Error::Exited {
+                status,
+                stderr_tail,
+            }) => {
+                assert!(!status.success());
+                assert!(!stderr_tail.is_empty());
+            }
+            Err(e) => panic!("expected Exited, got {}", e),
+            Ok(_) => panic!("expected Exited, it runs"),
+        }
+    }
+
+    #[tokio::test]
+    async fn an_unknown_format_exits_with_the_stderr() {
+        if std::process::Command::new("ffmpeg")
+            .arg("-version")
+            .output()
+            .is_err()
+        {
+            eprintln!("ffmpeg is not installed, skipped");
+            return;
+        }
+        let (run, took) = refused("ffmpeg").await;
+        assert_exited(run);
+        assert!(took < Duration::from_secs(5), "took {:?}", took);
+    }
+
+    #[tokio::test]
+    async fn a_command_that_exits_at_once_is_not_waited_for() {
+        // sh refuses `-progress` like ffmpeg an unknown format, before connecting
+        let (run, took) = refused("sh").await;
+        assert_exited(run);
+        assert!(took < Duration::from_secs(5), "took {:?}", took);
+    }
+
+    /// a mock ffmpeg: it leaves its arguments in `<name>.args` next to itself, then runs `body`
+    fn mock_ffmpeg(name: &str, body: &str) -> String {
+        use std::os::unix::fs::PermissionsExt;
+        let path = crate::recordings::test_output_dir().join(name);
+        let _ = std::fs::remove_file(path.with_extension("args"));
+        let script = format!(
+            "#!/bin/sh\nprintf '%s\\n' \"$@\" > \"$0.args.part\"\nmv \"$0.args.part\" \"$0.args\"\n{}\n",
+            body
+        );
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    type Reporter = Pin<Box<dyn tokio::io::AsyncWrite + Send>>;
+
+    /// connect to where the mock at `mock` reports its progress, as ffmpeg does
+    async fn report_for(mock: &str) -> Reporter {
+        let args = format!("{}.args", mock);
+        let url = loop {
+            if let Ok(args) = std::fs::read_to_string(&args) {
+                let args: Vec<&str> = args.lines().collect();
+                let at = args.iter().position(|a| *a == "-progress").unwrap();
+                break args[at + 1].to_string();
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        match url.split_once("://").unwrap() {
+            ("unix", path) => Box::pin(tokio::net::UnixStream::connect(path).await.unwrap()),
+            (_, addr) => Box::pin(tokio::net::TcpStream::connect(addr).await.unwrap()),
+        }
+    }
+
+    /// run the mock until it connected, with what it reports through
+    async fn run_mock(mock: &str) -> (Ffmpeg, Reporter) {
+        let mut builder = FfmpegBuilder::new().output(File::new("-"));
+        builder.ffmpeg_command = mock;
+        let (ffmpeg, reporter) = tokio::join!(builder.run(), report_for(mock));
+        (ffmpeg.unwrap(), reporter)
+    }
+
+    fn is_reaped(pid: u32) -> bool {
+        let pid = nix::unistd::Pid::from_raw(pid as i32);
+        nix::sys::signal::kill(pid, None).is_err()
+    }
+
+    #[tokio::test]
+    async fn a_successful_run_ends_with_its_end_and_is_reaped() {
+        // exits once the test lets it
+        let mock = mock_ffmpeg(
+            "mock-ffmpeg-ok",
+            "while [ ! -e \"$0.exit\" ]; do sleep 0.01; done\nrm \"$0.exit\"",
+        );
+        let (ffmpeg, mut reporter) = run_mock(&mock).await;
+        let pid = ffmpeg.id();
+        reporter.write_all(BLOCK.as_bytes()).await.unwrap();
+        let end = BLOCK.replace("progress=continue", "progress=end");
+        reporter.write_all(end.as_bytes()).await.unwrap();
+        drop(reporter);
+        std::fs::write(format!("{}.exit", mock), b"").unwrap();
+
+        let mut seen = vec![];
+        let summary = ffmpeg
+            .wait_with_progress(|p| seen.push(p.status.clone()))
+            .await
+            .unwrap();
+        assert!(summary.success());
+        assert!(!summary.cancelled);
+        assert!(matches!(seen[..], [Status::Continue, Status::End]));
+        assert!(matches!(summary.last_progress.unwrap().status, Status::End));
+        assert!(is_reaped(pid));
+    }
+
+    #[tokio::test]
+    async fn a_failed_run_ends_with_an_error_and_its_exit_status() {
+        let mock = mock_ffmpeg(
+            "mock-ffmpeg-fails",
+            "while [ ! -e \"$0.exit\" ]; do sleep 0.01; done\nrm \"$0.exit\"\nexit 3",
+        );
+        let (mut ffmpeg, mut reporter) = run_mock(&mock).await;
+        let pid = ffmpeg.id();
+        reporter.write_all(BLOCK.as_bytes()).await.unwrap();
+        assert!(ffmpeg.progress.next().await.unwrap().is_ok());
+        // gone without an end
+        drop(reporter);
+        std::fs::write(format!("{}.exit", mock), b"").unwrap();
+        assert!(matches!(
+            ffmpeg.progress.next().await,
+            Some(Err(Error::Disconnected))
+        ));
+        assert!(ffmpeg.progress.next().await.is_none());
+
+        let summary = ffmpeg.wait_with_progress(|_| {}).await.unwrap();
+        assert!(!summary.success());
+        assert_eq!(summary.exit_status.code(), Some(3));
+        assert!(is_reaped(pid));
+    }
+
+    #[tokio::test]
+    async fn a_cancelled_run_is_interrupted_and_reaped() {
+        let mock = mock_ffmpeg(
+            "mock-ffmpeg-cancel",
+            "trap 'exit 255' INT\nwhile :; do sleep 0.01; done",
+        );
+        let (ffmpeg, mut reporter) = run_mock(&mock).await;
+        let pid = ffmpeg.id();
+        reporter.write_all(BLOCK.as_bytes()).await.unwrap();
+        let cancel = CancellationToken::new();
+        let waiting = {
+            let cancel = cancel.clone();
+            tokio::spawn(async move {
+                ffmpeg
+                    .wait_with_progress_or_cancel(&cancel, |_| {})
+                    .await
+                    .unwrap()
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        cancel.cancel();
+        // ffmpeg closes its progress as it exits
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(reporter);
+
+        let summary = tokio::time::timeout(CANCEL_GRACE, waiting)
+            .await
+            .expect("not interrupted")
+            .unwrap();
+        assert!(summary.cancelled);
+        assert_eq!(summary.exit_status.code(), Some(255));
+        assert!(summary.last_progress.is_some());
+        assert!(is_reaped(pid));
+    }
 }
@@ -0,0 +1,616 @@
+//! Uploading the finished recordings to an S3 compatible object storage
+//!
+//! With `--s3-bucket`, the upload stage of the pipeline sends the result to the bucket before it
+//! is reported done, the state is Uploading meanwhile. The requests are signed with AWS
+//! signature version 4 and address the bucket by path, `<endpoint>/<bucket>/<key>`, which AWS,
+//! MinIO and the other S3 compatible stores take. The payload is not signed, S3 takes the
+//! `UNSIGNED-PAYLOAD` of the signature instead of its hash.
+//!
+//! A file of up to [MULTIPART_THRESHOLD] is sent by one PUT, a larger one as a multipart upload
+//! of parts of [PART_SIZE]; a part is read in memory while it is sent, and the progress moves on
+//! after every part. A multipart upload that fails is aborted, for the parts sent not to stay in
+//! the bucket.
+use crate::template::{Context, Template, Values};
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tracing::*;
+
+/// the files larger than this are sent in parts
+pub const MULTIPART_THRESHOLD: u64 = 100 * 1024 * 1024;
+/// the size of the parts, but the last
+pub const PART_SIZE: u64 = 16 * 1024 * 1024;
+/// the most parts of an upload S3 takes
+const MAX_PARTS: u64 = 10_000;
+/// how long a request may take, a part being sent included
+const TIMEOUT: Duration = Duration::from_secs(600);
+const UNSIGNED: &str = "UNSIGNED-PAYLOAD";
+
+/// Where the recordings are uploaded
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub bucket: String,
+    /// the URL of the store, that of AWS in the region by default
+    pub endpoint: Option<String>,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// the token of temporary credentials
+    pub session_token: Option<String>,
+    /// prepended to the file names of the recordings to make their keys, `recordings/{owner}/`
+    /// say, its values percent-encoded so that a slash in one stays in its segment
+    pub prefix: Template,
+    /// remove the local result once it was uploaded
+    pub delete_after_upload: bool,
+}
+
+/// A bucket the recordings are uploaded to
+pub struct Bucket {
+    pub config: Config,
+    endpoint: String,
+    client: reqwest::Client,
+}
+
+impl Bucket {
+    pub fn new(config: Config) -> anyhow::Result<Self> {
+        let endpoint = match &config.endpoint {
+            Some(endpoint) => endpoint.trim_end_matches('/').to_string(),
+            None => format!("https://s3.{}.amazonaws.com", config.region),
+        };
+        reqwest::Url::parse(&endpoint)?;
+        let client = reqwest::Client::builder().timeout(TIMEOUT).build()?;
+        Ok(Self {
+            config,
+            endpoint,
+            client,
+        })
+    }
+
+    /// the key of a recording, with the values of the recording in its prefix
+    pub fn key(&self, file: &Path, values: &Values) -> Result<String, String> {
+        let name = file.file_name().unwrap_or_default().to_string_lossy();
+        let values = values.clone().with_file(&file.to_string_lossy());
+        let prefix = self.config.prefix.render(&values, Context::Url)?;
+        Ok(format!("{}{}", prefix, name))
+    }
+
+    /// upload the file as `key`, adding the bytes to `sent` as they are sent
+    pub async fn upload(&self, file: &Path, key: &str, sent: &AtomicU64) -> anyhow::Result<()> {
+        let size = tokio::fs::metadata(file).await?.len();
+        if size <= MULTIPART_THRESHOLD {
+            let body = tokio::fs::read(file).await?;
+            self.send(reqwest::Method::PUT, key, &[], body).await?;
+            sent.fetch_add(size, Ordering::Relaxed);
+            return Ok(());
+        }
+        let created = self
+            .send(reqwest::Method::POST, key, &[("uploads", "")], vec![])
+            .await?;
+        let upload_id = element(&created.text().await?, "UploadId")
+            .ok_or_else(|| anyhow::anyhow!("no UploadId in the answer to the multipart upload"))?;
+        match self.upload_parts(file, key, size, &upload_id, sent).await {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                let query = [("uploadId", upload_id.as_str())];
+                let aborted = self.send(reqwest::Method::DELETE, key, &query, vec![]);
+                if let Err(abort) = aborted.await {
+                    warn!("cannot abort the upload of {}: {}", key, abort);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn upload_parts(
+        &self,
+        file: &Path,
+        key: &str,
+        size: u64,
+        upload_id: &str,
+        sent: &AtomicU64,
+    ) -> anyhow::Result<()> {
+        let part_size = PART_SIZE.max(size.div_ceil(MAX_PARTS));
+        let mut input = tokio::fs::File::open(file).await?;
+        let mut etags = vec![];
+        let mut offset = 0;
+        while offset < size {
+            let len = part_size.min(size - offset);
+            let mut part = vec![0; len as usize];
+            input.seek(std::io::SeekFrom::Start(offset)).await?;
+            input.read_exact(&mut part).await?;
+            let number = (etags.len() + 1).to_string();
+            let query = [("partNumber", number.as_str()), ("uploadId", upload_id)];
+            let res = self.send(reqwest::Method::PUT, key, &query, part).await?;
+            let etag = res
+                .headers()
+                .get(reqwest::header::ETAG)
+                .and_then(|etag| etag.to_str().ok())
+                .ok_or_else(|| anyhow::anyhow!("no ETag for the part {}", number))?;
+            etags.push(etag.to_string());
+            offset += len;
+            sent.fetch_add(len, Ordering::Relaxed);
+        }
+        let mut body = String::from("<CompleteMultipartUpload>");
+        for (n, etag) in etags.iter().enumerate() {
+            body += &format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                n + 1,
+                etag
+            );
+        }
+        body += "</CompleteMultipartUpload>";
+        let query = [("uploadId", upload_id)];
+        let res = self
+            .send(reqwest::Method::POST, key, &query, body.into_bytes())
+            .await?;
+        // the completion can fail after its 200 has been sent
+        let answer = res.text().await?;
+        if let Some(message) = answer
+            .contains("<Error>")
+            .then(|| element(&answer, "Message"))
+        {
+            anyhow::bail!(
+                "the multipart upload did not complete: {}",
+                message.unwrap_or(answer)
+            );
+        }
+        Ok(())
+    }
+
+    /// a signed request about the object, failing unless it succeeded
+    async fn send(
+        &self,
+        method: reqwest::Method,
+        key: &str,
+        query: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> anyhow::Result<reqwest::Response> {
+        let path = format!(
+            "/{}/{}",
+            encode(&self.config.bucket, false),
+            encode(key, true)
+        );
+        let mut query: Vec<(String, String)> = query
+            .iter()
+            .map(|(k, v)| (encode(k, false), encode(v, false)))
+            .collect();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join("&");
+        let url = match query.is_empty() {
+            true => format!("{}{}", self.endpoint, path),
+            false => format!("{}{}?{}", self.endpoint, path, query),
+        };
+        let url = reqwest::Url::parse(&url)?;
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+        let now = Utc::now();
+        let (date, time) = (
+            now.format("%Y%m%d").to_string(),
+            now.format("%Y%m%dT%H%M%SZ").to_string(),
+        );
+
+        let mut headers = vec![
+            ("host", host),
+            ("x-amz-content-sha256", UNSIGNED.to_string()),
+            ("x-amz-date", time.clone()),
+        ];
+        if let Some(token) = &self.config.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let canonical = canonical_request(method.as_str(), &path, &query, &headers, UNSIGNED);
+        let scope = format!("{}/{}/s3/aws4_request", date, self.config.region);
+        let key = signing_key(&self.config.secret_key, &date, &self.config.region, "s3");
+        let signature = hex(&hmac(
+            &key,
+            string_to_sign(&time, &scope, &canonical).as_bytes(),
+        ));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.config.access_key,
+            scope,
+            signed_headers(&headers),
+            signature
+        );
+
+        let mut req = self
+            .client
+            .request(method, url)
+            .header(reqwest::header::AUTHORIZATION, authorization);
+        for (name, value) in headers.into_iter().filter(|(name, _)| *name != "host") {
+            req = req.header(name, value);
+        }
+        let res = req.body(body).send().await?;
+        if !res.status().is_success() {
+            let status = res.status();
+            let answer = res.text().await.unwrap_or_default();
+            anyhow::bail!(
+                "{}: {}",
+                status,
+                element(&answer, "Message").unwrap_or(answer)
+            );
+        }
+        Ok(res)
+    }
+}
+
+/// the names of the signed headers, sorted as [canonical_request] has them
+fn signed_headers(headers: &[(&str, String)]) -> String {
+    let mut names: Vec<_> = headers.iter().map(|(name, _)| *name).collect();
+    names.sort();
+    names.join(";")
+}
+
+/// the canonical request of signature version 4, of a path and a query already URI-encoded and
+/// headers of lowercase names
+fn canonical_request(
+    method: &str,
+    path: &str,
+    query: &str,
+    headers: &[(&str, String)],
+    payload: &str,
+) -> String {
+    let mut headers = headers.to_vec();
+    headers.sort();
+    format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method,
+        path,
+        query,
+        headers
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+            .collect::<String>(),
+        signed_headers(&headers),
+        payload
+    )
+}
+
+/// what is signed of a request made at `time`, `20150830T123600Z`
+fn string_to_sign(time: &str, scope: &str, canonical: &str) -> String {
+    format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{:x}",
+        time,
+        scope,
+        Sha256::digest(canonical.as_bytes())
+    )
+}
+
+/// the key signing the requests of a day, `20150830`
+fn signing_key(secret: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let mut key = hmac(format!("AWS4{}", secret).as_bytes(), date.as_bytes());
+    for part in [region, service, "aws4_request"] {
+        key = hmac(&key, part.as_bytes());
+    }
+    key
+}
+
+/// the text of the first `<name>` element of an XML answer
+fn element(xml: &str, name: &str) -> Option<String> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", name))?;
+    Some(xml[start..end].to_string())
+}
+
+/// the URI encoding of S3, keeping the slashes of a key
+fn encode(s: &str, keep_slash: bool) -> String {
+    let mut out = String::new();
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(b as char)
+            }
+            b'/' if keep_slash => out.push('/'),
+            b => out += &format!("%{:02X}", b),
+        }
+    }
+    out
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut block = [0u8; 64];
+    if key.len() > block.len() {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha256::new();
+    inner.update(block.map(|b| b ^ 0x36));
+    inner.update(data);
+    let mut outer = Sha256::new();
+    outer.update(block.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::OriginalUri;
+    use axum::http::{HeaderMap, Method};
+    use axum::response::IntoResponse;
+    use axum::{Extension, Router};
+    use chrono::{Local, TimeZone};
+    use std::sync::{Arc, Mutex};
+
+    /// A request the store got: its method, its URI and the size of its body
+    type Request = (Method, String, usize);
+
+    /// an S3 compatible store taking every upload, its endpoint and the requests it got
+    fn store() -> (String, Arc<Mutex<Vec<Request>>>) {
+        let requests = Arc::new(Mutex::new(vec![]));
+        let app = Router::new()
+            .fallback(
+                |Extension(requests): Extension<Arc<Mutex<Vec<Request>>>>,
+                 method: Method,
+                 OriginalUri(uri): OriginalUri,
+                 body: axum::body::Bytes| async move {
+                    let query = uri.query().unwrap_or_default().to_string();
+                    requests
+                        .lock()
+                        .unwrap()
+                        .push((method.clone(), uri.to_string(), body.len()));
+                    let mut headers = HeaderMap::new();
+                    if method == Method::PUT && query.contains("partNumber") {
+                        headers.insert("etag", "\"part\"".parse().unwrap());
+                    }
+                    let answer = match method {
+                        Method::POST if query == "uploads=" => {
+                            "<InitiateMultipartUploadResult><UploadId>up-1</UploadId></InitiateMultipartUploadResult>"
+                        }
+                        Method::POST => "<CompleteMultipartUploadResult/>",
+                        _ => "",
+                    };
+                    (headers, answer).into_response()
+                },
+            )
+            .layer(Extension(requests.clone()))
+            .layer(axum::extract::DefaultBodyLimit::disable());
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener).unwrap();
+        tokio::spawn(server.serve(app.into_make_service()));
+        (format!("http://{}", addr), requests)
+    }
+
+    fn bucket(endpoint: &str, prefix: &str) -> Bucket {
+        Bucket::new(Config {
+            bucket: "videos".to_string(),
+            endpoint: Some(endpoint.to_string()),
+            region: "us-east-1".to_string(),
+            access_key: "AKIDEXAMPLE".to_string(),
+            secret_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            session_token: None,
+            prefix: crate::template::key_prefix(prefix).unwrap(),
+            delete_after_upload: false,
+        })
+        .unwrap()
+    }
+
+    fn values(owner: &str) -> Values {
+        Values {
+            started_at: Local.with_ymd_and_hms(2024, 5, 1, 14, 30, 0).unwrap(),
+            hostname: "host".to_string(),
+            owner: Some(owner.to_string()),
+            filename: None,
+            profile: Some("fast".to_string()),
+        }
+    }
+
+    /// a file of `size` bytes in the temporary directory
+    fn file(name: &str, size: u64) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("record-screen-s3-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        let file = std::fs::File::create(&path).unwrap();
+        file.set_len(size).unwrap();
+        path
+    }
+
+    #[test]
+    fn a_key_holds_the_values_of_its_recording() {
+        let bucket = bucket(
+            "http://localhost:9000",
+            "recordings/{owner}/{date}/{profile}-",
+        );
+        let key = bucket.key(Path::new("/videos/2024-05-01T14-30.mp4"), &values("a b/c"));
+        assert_eq!(
+            key.unwrap(),
+            "recordings/a%20b%2Fc/2024-05-01/fast-2024-05-01T14-30.mp4"
+        );
+        let bucket = self::bucket("http://localhost:9000", "{recording_id}/{filename}/");
+        let key = bucket.key(Path::new("/videos/x.mp4"), &values("alice"));
+        assert_eq!(key.unwrap(), "20240501T143000.000/x.mp4/x.mp4");
+    }
+
+    #[tokio::test]
+    async fn an_upload_lands_under_its_prefix() {
+        let (endpoint, requests) = store();
+        let bucket = bucket(&endpoint, "recordings/{owner}/");
+        let path = file("landing.mp4", 1000);
+        let key = bucket.key(&path, &values("alice smith")).unwrap();
+        let sent = AtomicU64::new(0);
+        bucket.upload(&path, &key, &sent).await.unwrap();
+        assert_eq!(sent.load(Ordering::Relaxed), 1000);
+        let requests = requests.lock().unwrap().clone();
+        assert_eq!(
+            requests,
+            vec![(
+                Method::PUT,
+                "/videos/recordings/alice%2520smith/landing.mp4".to_string(),
+                1000
+            )]
+        );
+    }
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn hmac_sha256_gives_the_values_of_rfc_4231() {
+        let key: Vec<u8> = (1..=0x19).collect();
+        let cases: [(Vec<u8>, Vec<u8>, &str); 6] = [
+            (
+                vec![0x0b; 20],
+                b"Hi There".to_vec(),
+                "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+            ),
+            (
+                b"Jefe".to_vec(),
+                b"what do ya want for nothing?".to_vec(),
+                "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            ),
+            (
+                vec![0xaa; 20],
+                vec![0xdd; 50],
+                "773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe",
+            ),
+            (
+                key,
+                vec![0xcd; 50],
+                "82558a389a443c0ea4cc819899f2083a85f0faa3e578f8077a2e3ff46729665b",
+            ),
+            // keys larger than a block are hashed first
+            (
+                vec![0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First".to_vec(),
+                "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+            ),
+            (
+                vec![0xaa; 131],
+                b"This is a test using a larger than block-size key and a larger than block-size \
+                  data. The key needs to be hashed before being used by the HMAC algorithm."
+                    .to_vec(),
+                "9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2",
+            ),
+        ];
+        for (key, data, mac) in cases {
+            assert_eq!(hmac(&key, &data), unhex(mac), "{}", mac);
+        }
+    }
+
+    /// the empty payload, hashed
+    const EMPTY: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    #[test]
+    fn a_request_is_signed_as_the_sigv4_test_suite_has_it() {
+        // get-vanilla
+        let headers = [
+            ("x-amz-date", "20150830T123600Z".to_string()),
+            ("host", "example.amazonaws.com".to_string()),
+        ];
+        let canonical = canonical_request("GET", "/", "", &headers, EMPTY);
+        assert_eq!(
+            canonical,
+            format!(
+                "GET\n/\n\nhost:example.amazonaws.com\nx-amz-date:20150830T123600Z\n\n\
+                 host;x-amz-date\n{}",
+                EMPTY
+            )
+        );
+        let scope = "20150830/us-east-1/service/aws4_request";
+        let to_sign = string_to_sign("20150830T123600Z", scope, &canonical);
+        assert_eq!(
+            to_sign,
+            "AWS4-HMAC-SHA256\n20150830T123600Z\n20150830/us-east-1/service/aws4_request\n\
+             bb579772317eb040ac9ed261061d46c1f17a8133879d6129b6e1c25292927e63"
+        );
+        let secret = "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY";
+        let key = signing_key(secret, "20150830", "us-east-1", "service");
+        assert_eq!(
+            hex(&hmac(&key, to_sign.as_bytes())),
+            "5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+    }
+
+    #[test]
+    fn a_get_of_an_object_is_signed_as_the_s3_documentation_has_it() {
+        let headers = [
+            ("host", "examplebucket.s3.amazonaws.com".to_string()),
+            ("range", "bytes=0-9".to_string()),
+            ("x-amz-content-sha256", EMPTY.to_string()),
+            ("x-amz-date", "20130524T000000Z".to_string()),
+        ];
+        let canonical = canonical_request("GET", "/test.txt", "", &headers, EMPTY);
+        assert_eq!(
+            signed_headers(&headers),
+            "host;range;x-amz-content-sha256;x-amz-date"
+        );
+        let scope = "20130524/us-east-1/s3/aws4_request";
+        let to_sign = string_to_sign("20130524T000000Z", scope, &canonical);
+        assert!(
+            to_sign.ends_with("\n7344ae5b7ee6c3e7e6b0fe0640412a37625d1fbfff95c48bbb2dc43964946972")
+        );
+        let secret = "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY";
+        let key = signing_key(secret, "20130524", "us-east-1", "s3");
+        assert_eq!(
+            hex(&hmac(&key, to_sign.as_bytes())),
+            "f0e8bdb87c964420e857bd35b5d6ed310bd44f0170aba48dd91039c6036bdb41"
+        );
+    }
+
+    #[tokio::test]
+    async fn a_file_of_the_threshold_is_sent_at_once() {
+        let (endpoint, requests) = store();
+        let bucket = bucket(&endpoint, "");
+        let path = file("threshold.mp4", MULTIPART_THRESHOLD);
+        let sent = AtomicU64::new(0);
+        bucket.upload(&path, "threshold.mp4", &sent).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(sent.load(Ordering::Relaxed), MULTIPART_THRESHOLD);
+        let requests = requests.lock().unwrap().clone();
+        let expected = (
+            Method::PUT,
+            "/videos/threshold.mp4".to_string(),
+            MULTIPART_THRESHOLD as usize,
+        );
+        assert_eq!(requests, vec![expected]);
+    }
+
+    #[tokio::test]
+    async fn a_file_past_the_threshold_is_sent_in_parts() {
+        let (endpoint, requests) = store();
+        let bucket = bucket(&endpoint, "");
+        let size = MULTIPART_THRESHOLD + 1;
+        let path = file("parts.mp4", size);
+        let sent = AtomicU64::new(0);
+        bucket.upload(&path, "parts.mp4", &sent).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(sent.load(Ordering::Relaxed), size);
+
+        let requests = requests.lock().unwrap().clone();
+        let parts = size.div_ceil(PART_SIZE) as usize;
+        assert_eq!(requests.len(), parts + 2, "{:?}", requests);
+        assert_eq!(
+            requests[0],
+            (Method::POST, "/videos/parts.mp4?uploads=".to_string(), 0)
+        );
+        for (n, request) in requests[1..=parts].iter().enumerate() {
+            let uri = format!("/videos/parts.mp4?partNumber={}&uploadId=up-1", n + 1);
+            let len = match n + 1 == parts {
+                true => size - PART_SIZE * (parts as u64 - 1),
+                false => PART_SIZE,
+            };
+            assert_eq!(request, &(Method::PUT, uri, len as usize));
+        }
+        let (method, uri, _) = &requests[parts + 1];
+        assert_eq!(method, Method::POST);
+        assert_eq!(uri, "/videos/parts.mp4?uploadId=up-1");
+    }
+}
@@ -0,0 +1,114 @@
+//! Downscaling the compressed result
+//!
+//! With `scale`, the compression runs the frames through the `scale` filter, a 4K capture being
+//! archived at 1080p say, while the raw capture stays at the resolution of the screen. The scale
+//! is `<width>:<height>`, one of them [KEEP_RATIO] for the aspect ratio to be kept at an even
+//! size, as libx264 needs it, or the shorthand of a height in [SHORTHANDS], `1080p`. It is checked
+//! when the recording is started, see [validate], rather than failing the compression once it is
+//! stopped. The Done state has the [Resolution] of the result, as ffprobe reads it.
+use crate::geometry::MAX_CAPTURE_SIZE;
+use crate::probe;
+use crate::service::RecordingOptions;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tracing::*;
+
+/// the heights a scale can be given by, the width following the aspect ratio
+pub const SHORTHANDS: &[(&str, u32)] = &[
+    ("2160p", 2160),
+    ("1440p", 1440),
+    ("1080p", 1080),
+    ("720p", 720),
+    ("540p", 540),
+    ("480p", 480),
+    ("360p", 360),
+];
+/// the side of a scale computed from the other one, keeping the aspect ratio at an even size
+pub const KEEP_RATIO: i32 = -2;
+
+/// The size of the frames of a result
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Resolution {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl std::fmt::Display for Resolution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)
+    }
+}
+
+/// the `scale` filter of a scale, or what is wrong with it
+pub fn filter(scale: &str) -> Result<String, String> {
+    let scale = scale.trim();
+    let shorthand = SHORTHANDS
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(scale));
+    if let Some((_, height)) = shorthand {
+        return Ok(format!("scale={}:{}", KEEP_RATIO, height));
+    }
+    let Some((width, height)) = scale.split_once([':', 'x']) else {
+        let names: Vec<&str> = SHORTHANDS.iter().map(|(name, _)| *name).collect();
+        return Err(format!(
+            "{} is neither <width>:<height> nor one of {}",
+            scale,
+            names.join(", ")
+        ));
+    };
+    let (width, height) = (side("width", width)?, side("height", height)?);
+    if width == KEEP_RATIO && height == KEEP_RATIO {
+        return Err(format!("only one side can be {}", KEEP_RATIO));
+    }
+    Ok(format!("scale={}:{}", width, height))
+}
+
+fn side(name: &str, value: &str) -> Result<i32, String> {
+    let size: i32 = value
+        .trim()
+        .parse()
+        .map_err(|_| format!("the {} {} is not a number", name, value.trim()))?;
+    if size == KEEP_RATIO {
+        return Ok(size);
+    }
+    if !(2..=MAX_CAPTURE_SIZE as i32).contains(&size) {
+        return Err(format!(
+            "the {} must be {} or within 2..={}",
+            name, KEEP_RATIO, MAX_CAPTURE_SIZE
+        ));
+    }
+    if size % 2 != 0 {
+        return Err(format!("the {} must be even, libx264 needs it so", name));
+    }
+    Ok(size)
+}
+
+/// whether the scale the options ask for can be applied, the field that can't otherwise
+pub fn validate(opt: &RecordingOptions) -> Result<(), (&'static str, String)> {
+    let Some(scale) = &opt.scale else {
+        return Ok(());
+    };
+    filter(scale).map_err(|e| ("scale", e))?;
+    if opt.compress == Some(false) {
+        return Err(("scale", "given without a compression".to_string()));
+    }
+    Ok(())
+}
+
+/// the resolution of a result, None when it can't be probed
+pub async fn resolution(result: &Path) -> Option<Resolution> {
+    match probe::probe(result).await {
+        Ok(info) => {
+            let (width, height) = info.width.zip(info.height)?;
+            Some(Resolution { width, height })
+        }
+        Err(e) => {
+            warn!(
+                "cannot probe the resolution of {}: {:#}",
+                result.display(),
+                e
+            );
+            None
+        }
+    }
+}
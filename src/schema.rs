@@ -0,0 +1,299 @@
+//! Versions of the documents kept on disk, and their migration on load
+//!
+//! Every persisted document carries a `schema_version`: a line of the history or of the job
+//! journal, the capture path analysis, a checksum manifest, a start sidecar. The documents written
+//! before there were versions have none, they are version 0.
+//!
+//! How a persisted struct changes:
+//! - a new field gets `#[serde(default)]`, so that the documents without it still load, and needs
+//!   no new version;
+//! - renaming or removing a field, or changing what it means, bumps [Versioned::VERSION] and adds
+//!   the migration from the previous version to [Versioned::MIGRATIONS]; it rewrites the JSON of
+//!   an older document into the newer shape before it is parsed;
+//! - a document of a newer version, left by a newer server before a rollback, is parsed as it
+//!   is, its unknown fields ignored.
+//!
+//! A line or a file that can't be read is moved into the [QUARANTINE] directory next to it rather
+//! than keeping the server from starting, and counted in the [Report] logged when it's loaded.
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use tracing::*;
+
+/// the field with the version of a document
+pub const FIELD: &str = "schema_version";
+/// the directory next to a store the documents that can't be read are moved to
+pub const QUARANTINE: &str = ".record-screen-quarantine";
+
+/// Rewrites a document of one version into the shape of the next one
+pub type Migration = fn(&mut Map<String, Value>) -> Result<(), String>;
+
+/// A struct that is persisted
+pub trait Versioned: Serialize + DeserializeOwned {
+    /// what the documents are, in the reports
+    const KIND: &'static str;
+    /// the version written now
+    const VERSION: u32;
+    /// the migration from version `n` to `n + 1` at index `n`, one for every version before
+    /// [Self::VERSION]
+    const MIGRATIONS: &'static [Migration];
+}
+
+/// from the documents written before there were versions, which are the first version already
+pub fn unversioned(_: &mut Map<String, Value>) -> Result<(), String> {
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("not a JSON object")]
+    NotAnObject,
+    #[error("no migration from version {0}")]
+    NoMigration(u32),
+    #[error("migration from version {from}: {message}")]
+    Migration { from: u32, message: String },
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+/// the version of a document, 0 when it has none
+pub fn version(document: &Map<String, Value>) -> u32 {
+    document
+        .get(FIELD)
+        .and_then(Value::as_u64)
+        .unwrap_or_default() as u32
+}
+
+/// parse a document of any version, returning whether it had to be migrated
+pub fn upgrade<T: Versioned>(value: Value) -> Result<(T, bool), Error> {
+    let Value::Object(mut document) = value else {
+        return Err(Error::NotAnObject);
+    };
+    let from = version(&document);
+    for v in from..T::VERSION {
+        let migrate = T::MIGRATIONS.get(v as usize).ok_or(Error::NoMigration(v))?;
+        migrate(&mut document).map_err(|message| Error::Migration { from: v, message })?;
+    }
+    if from > T::VERSION {
+        debug!("{} of version {}, newer than {}", T::KIND, from, T::VERSION);
+    }
+    document.remove(FIELD);
+    let parsed = serde_json::from_value(Value::Object(document))?;
+    Ok((parsed, from < T::VERSION))
+}
+
+pub fn from_str<T: Versioned>(json: &str) -> Result<(T, bool), Error> {
+    upgrade(serde_json::from_str(json)?)
+}
+
+pub fn from_slice<T: Versioned>(json: &[u8]) -> Result<(T, bool), Error> {
+    upgrade(serde_json::from_slice(json)?)
+}
+
+/// the JSON of a document, with its version
+pub fn to_value<T: Versioned>(document: &T) -> serde_json::Result<Value> {
+    let mut value = serde_json::to_value(document)?;
+    if let Value::Object(fields) = &mut value {
+        fields.insert(FIELD.to_string(), T::VERSION.into());
+    }
+    Ok(value)
+}
+
+/// a line of a JSON lines store
+pub fn to_string<T: Versioned>(document: &T) -> serde_json::Result<String> {
+    serde_json::to_string(&to_value(document)?)
+}
+
+/// a document of a file of its own
+pub fn to_vec_pretty<T: Versioned>(document: &T) -> serde_json::Result<Vec<u8>> {
+    serde_json::to_vec_pretty(&to_value(document)?)
+}
+
+/// How the documents of a store loaded
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub kind: &'static str,
+    pub path: PathBuf,
+    /// of the current version, or newer
+    pub current: usize,
+    pub migrated: usize,
+    /// moved to the quarantine
+    pub failed: usize,
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} {} loaded, {} of them migrated, {} quarantined",
+            self.path.display(),
+            self.current + self.migrated,
+            self.kind,
+            self.migrated,
+            self.failed
+        )
+    }
+}
+
+/// where `path` goes when it can't be read
+pub fn quarantine_path(path: &Path) -> PathBuf {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    dir.join(QUARANTINE)
+        .join(path.file_name().unwrap_or_default())
+}
+
+/// move a file that can't be read into the quarantine, returning where it is now
+pub fn quarantine(path: &Path) -> std::io::Result<PathBuf> {
+    let to = quarantine_path(path);
+    std::fs::create_dir_all(to.parent().unwrap_or(Path::new(".")))?;
+    std::fs::rename(path, &to)?;
+    warn!(
+        "{} can't be read, moved to {}",
+        path.display(),
+        to.display()
+    );
+    Ok(to)
+}
+
+/// load a JSON lines store, moving the lines that can't be read into the quarantine
+pub fn read_lines<T: Versioned>(path: &Path) -> anyhow::Result<(Vec<T>, Report)> {
+    let mut report = Report {
+        kind: T::KIND,
+        path: path.to_path_buf(),
+        ..Default::default()
+    };
+    if !path.exists() {
+        return Ok((vec![], report));
+    }
+    let file = std::io::BufReader::new(std::fs::File::open(path)?);
+    let mut documents = vec![];
+    let mut kept = vec![];
+    let mut failed = vec![];
+    for (n, line) in file.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match from_str::<T>(&line) {
+            Ok((document, migrated)) => {
+                match migrated {
+                    true => report.migrated += 1,
+                    false => report.current += 1,
+                }
+                documents.push(document);
+                kept.push(line);
+            }
+            Err(e) => {
+                warn!("{}:{}: quarantined: {}", path.display(), n + 1, e);
+                failed.push(line);
+            }
+        }
+    }
+    report.failed = failed.len();
+    if !failed.is_empty() {
+        if let Err(e) = quarantine_lines(path, &kept, &failed) {
+            warn!("cannot quarantine the lines of {}: {}", path.display(), e);
+        }
+    }
+    Ok((documents, report))
+}
+
+/// append the `failed` lines to the quarantine, then rewrite the store with the `kept` ones
+fn quarantine_lines(path: &Path, kept: &[String], failed: &[String]) -> std::io::Result<()> {
+    let to = quarantine_path(path);
+    std::fs::create_dir_all(to.parent().unwrap_or(Path::new(".")))?;
+    let mut quarantined = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&to)?;
+    for line in failed {
+        writeln!(quarantined, "{}", line)?;
+    }
+    quarantined.sync_all()?;
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".quarantine");
+    let temporary = PathBuf::from(temporary);
+    let mut file = std::io::BufWriter::new(std::fs::File::create(&temporary)?);
+    for line in kept {
+        writeln!(file, "{}", line)?;
+    }
+    file.into_inner()?.sync_all()?;
+    std::fs::rename(&temporary, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use serde_json::json;
+
+    /// a document whose `name` was `title` in version 1, and had no version before
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Document {
+        name: String,
+    }
+
+    fn rename_title(document: &mut Map<String, Value>) -> Result<(), String> {
+        let title = document.remove("title").ok_or("no title")?;
+        document.insert("name".to_string(), title);
+        Ok(())
+    }
+
+    impl Versioned for Document {
+        const KIND: &'static str = "documents";
+        const VERSION: u32 = 2;
+        const MIGRATIONS: &'static [Migration] = &[unversioned, rename_title];
+    }
+
+    #[test]
+    fn an_older_document_goes_through_every_migration() {
+        let (document, migrated) = from_str::<Document>(r#"{"title":"a"}"#).unwrap();
+        assert_eq!(document.name, "a");
+        assert!(migrated);
+        let (document, migrated) =
+            from_str::<Document>(r#"{"schema_version":1,"title":"b"}"#).unwrap();
+        assert_eq!(document.name, "b");
+        assert!(migrated);
+    }
+
+    #[test]
+    fn a_current_or_newer_document_is_parsed_as_it_is() {
+        let (document, migrated) =
+            from_str::<Document>(r#"{"schema_version":2,"name":"c"}"#).unwrap();
+        assert_eq!(document.name, "c");
+        assert!(!migrated);
+        let (document, migrated) =
+            from_str::<Document>(r#"{"schema_version":9,"name":"d","added":true}"#).unwrap();
+        assert_eq!(document.name, "d");
+        assert!(!migrated);
+    }
+
+    #[test]
+    fn a_document_is_written_with_its_version() {
+        let value = to_value(&Document {
+            name: "e".to_string(),
+        })
+        .unwrap();
+        assert_eq!(value, json!({ "name": "e", "schema_version": 2 }));
+        let (read, _) = upgrade::<Document>(value).unwrap();
+        assert_eq!(read.name, "e");
+    }
+
+    #[test]
+    fn a_document_that_cannot_be_migrated_is_an_error() {
+        let e = from_str::<Document>(r#"{"schema_version":1,"name":"f"}"#).unwrap_err();
+        assert!(matches!(e, Error::Migration { from: 1, ref message } if message == "no title"));
+        assert!(matches!(
+            from_str::<Document>("[]"),
+            Err(Error::NotAnObject)
+        ));
+        assert!(matches!(from_str::<Document>("{"), Err(Error::Json(_))));
+        assert!(matches!(
+            from_str::<Document>(r#"{"schema_version":2}"#),
+            Err(Error::Json(_))
+        ));
+    }
+}
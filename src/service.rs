@@ -1,21 +1,144 @@
+use crate::audio::{self, AudioStatus};
+use crate::auth::Tokens;
+use crate::canary::Canaries;
+use crate::chunks::Chunks;
+use crate::contact_sheet::ContactSheets;
+use crate::container::{self, Container};
+use crate::content_check::{self, ContentWarning};
+use crate::cursor;
+use crate::encoder_profiles::{EncoderProfile, ProfileParameter, Profiles};
+use crate::events::{EventKind, Fanout, Subscription};
+use crate::failover::FailoverStatus;
 use crate::ffmpeg::*;
+use crate::frames;
+use crate::geometry::{GeometryPolicy, Rect};
+use crate::gif::Gifs;
+use crate::gpu::GpuUsage;
+use crate::health::{self, Health};
+use crate::history::{History, HistoryEntry};
+use crate::hwaccel::VideoEncoder;
+use crate::jobs::{self, JobState, Journal};
+use crate::latency::{self, FirstFrame};
+use crate::liveness::Liveness;
+use crate::loudness::Loudness;
+use crate::overlays;
+use crate::pipeline::{Flow, Pipeline};
+use crate::play::PlayCache;
+use crate::policy::Policy;
+use crate::presence::{Identity, Presence};
+use crate::preview;
+use crate::pulse::AudioInput;
+use crate::quality::{QualityStatus, VideoSegment};
+use crate::quota::Quotas;
+use crate::recordings;
+use crate::recovery::StateFile;
+use crate::rtmp::Streaming;
+use crate::s3::Bucket;
+use crate::scale::Resolution;
+use crate::source::CaptureSource;
+use crate::storage::Storage;
+use crate::sync_start::{self, StartSync};
+use crate::template::Template;
+use crate::throttle::{self, Throttle};
+use crate::thumbnail::Thumbnails;
+use crate::transcripts::{TranscribeRequest, Transcribers};
+use crate::trim::{StopRequest, Trim};
+use crate::webhook::Webhook;
+use crate::webrtc_preview;
 use anyhow::bail;
-use color_eyre::owo_colors::OwoColorize;
-use futures::{future::ready, StreamExt};
-use serde::Serialize;
-use std::process::Stdio;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::{Mutex, MutexGuard};
+use tracing::*;
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(tag = "type")]
+#[allow(clippy::large_enum_variant)]
 pub enum RecordingState {
+    #[default]
     Waiting,
+    /// a synchronized start, waiting for its time
+    Countdown {
+        start_at: DateTime<Local>,
+        #[serde(default)]
+        options: RecordingOptions,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        started_by: Option<Identity>,
+    },
     Started {
-        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
         progress: Option<Progress>,
         process_id: u32,
         file: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        audio: Option<AudioStatus>,
+        #[serde(default)]
+        options: RecordingOptions,
+        started_at: DateTime<Local>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        markers: Vec<Marker>,
+        /// the spans of the capture
+        #[serde(default, skip_serializing_if = "RecordingTimeline::is_empty")]
+        timeline: RecordingTimeline,
+        /// what went wrong without ending the recording
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        warnings: Vec<String>,
+        /// program and arguments of the capture
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        command: Vec<String>,
+        /// sidecar with the capture time of every frame
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        frame_timestamps: Option<String>,
+        /// the client that asked for the recording
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        started_by: Option<Identity>,
+        /// encoder settings of the capture, None when it copies the stream
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        quality: Option<QualityStatus>,
+        /// every file of the capture once it rolled over, the current one last
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        segments: Vec<VideoSegment>,
+        /// the chunks of a capture of `segment_seconds`, `file` is the one being written
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        chunks: Option<Chunks>,
+        /// the directory written to, when there is a fallback to fail over to
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        failover: Option<FailoverStatus>,
+        /// when a synchronized start was asked for and when it was
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        start_sync: Option<StartSync>,
+        /// when the first frame came rather than the spawn, see [crate::latency]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        first_frame: Option<FirstFrame>,
+        /// how well the capture goes, see [crate::health]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        health: Option<Health>,
+        /// the directory of the live preview, see [crate::preview]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        preview: Option<String>,
+        /// where the capture is streamed to, see [crate::rtmp]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        streaming: Option<Streaming>,
+    },
+    /// a started recording whose capture is paused, see [crate::pause]
+    Paused {
+        /// the capture that was paused, finishing its file
+        process_id: u32,
+        file: String,
+        /// when it was paused
+        at: DateTime<Local>,
+        /// the milliseconds recorded until then
+        elapsed_ms: u64,
+        /// the resume was asked for, the capture starts again once the paused one finished its file
+        #[serde(default)]
+        resumed: bool,
+        /// the Started state of the recording, which it is again on the resume
+        recording: Box<RecordingState>,
     },
     Stopping {
         process_id: u32,
@@ -25,153 +148,1771 @@ pub enum RecordingState {
         process_id: u32,
         input: String,
         output: String,
+        encoder: EncoderParams,
+        /// utilization of the hardware encoder
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        gpu: Option<GpuUsage>,
+        /// program and arguments of the compression
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        command: Vec<String>,
+        /// how well the compression goes, see [crate::health]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        health: Option<Health>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        progress: Option<Progress>,
+        /// what the result will last, when the capture tells
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        duration_ms: Option<u64>,
+        /// how far the compression is, out of 100
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        percent: Option<f64>,
+    },
+    /// the result is being sent to the bucket, see [crate::s3]
+    Uploading {
+        /// the local result
+        file: String,
+        /// its key in the bucket
+        key: String,
+        bytes_sent: u64,
+        total: u64,
     },
     Done {
         file: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        audio: Option<AudioStatus>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        markers: Vec<Marker>,
+        /// the file was fsynced before reporting Done
+        #[serde(default)]
+        durable: bool,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        frames: Vec<frames::Frame>,
+        /// why it was stopped, when it was not asked to
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stopped_reason: Option<StopReason>,
+        /// sidecar with the capture time of every frame
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        frame_timestamps: Option<String>,
+        /// what the content suggests went wrong, see [crate::content_check]
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        content_warnings: Vec<ContentWarning>,
+        /// every result of a recording in chunks, `file` is the first
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        chunks: Vec<String>,
+        /// the loudness of the audio normalized, see [crate::loudness]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        loudness: Option<Loudness>,
+        /// the size of the frames of the result, see [crate::scale]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        resolution: Option<Resolution>,
+    },
+    Failed {
+        reason: FailureReason,
+        message: String,
+        /// when the recording failed
+        at: DateTime<Local>,
+        /// the end of the stderr of an ffmpeg that exited at once
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        stderr_tail: Vec<String>,
     },
+    Cancelled {
+        reason: CancelReason,
+        /// partial files left on disk for the recovery scan
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        preserved: Vec<String>,
+    },
+}
+
+/// Why a recording was cancelled
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CancelReason {
+    /// every child process was killed at once
+    EmergencyStop,
+    /// stopped before the time of its synchronized start
+    StoppedInCountdown,
+}
+
+/// Why a recording failed
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureReason {
+    /// the stream to record did not answer within the startup timeout
+    SourceUnreachable,
+    /// recording is not allowed at this time of the day
+    OutsideAllowedWindow,
+    /// the owner has no room left for another recording
+    QuotaExceeded,
+    /// the capture could not be spawned or exited at once, at its time for a synchronized start
+    CaptureFailed,
+    /// the output directory can't be created or written to
+    OutputUnwritable,
+    /// the X display of a screen recording can't be reached
+    DisplayUnavailable,
+    /// the recording could not be started, the message tells why
+    StartFailed,
+    /// the result could not be sent to the bucket, it is kept locally
+    UploadFailed,
+    /// the RTMP server refused the stream or dropped it, see [crate::rtmp]
+    StreamFailed,
+}
+
+/// Why a recording was stopped by the server itself
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StopReason {
+    /// its recording window closed
+    OutsideAllowedWindow,
+    /// the captured monitor or the screen resolution changed
+    GeometryChanged,
+    /// the X server went away
+    DisplayLost,
+    /// the server was shut down, see [crate::shutdown]
+    Shutdown,
+    /// the capture exited cleanly by itself, its input came to an end
+    CaptureEnded,
+}
+
+/// A point of interest in the recording
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Marker {
+    /// milliseconds since the start of the recording
+    pub at_ms: u64,
+    pub label: String,
+    /// milliseconds into what was recorded, the position in the result
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_ms: Option<u64>,
+}
+
+/// milliseconds of the wall clock since `started_at`
+pub fn since(started_at: DateTime<Local>) -> u64 {
+    (Local::now() - started_at).num_milliseconds().max(0) as u64
+}
+
+/// A run of the capture, one segment of it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Span {
+    /// when it started, milliseconds of the wall clock since the start of the recording
+    pub wall_ms: u64,
+    /// how much was recorded before it
+    pub content_ms: u64,
+    /// when it ended, None while it is recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ended_ms: Option<u64>,
+}
+
+/// What was recorded of a recording against the wall clock
+///
+/// The capture records in spans, a new one at every rollover of its segment, and nothing between
+/// the end of one and the start of the next. The wall clock goes on meanwhile, so that a position
+/// of the wall clock is not a position of the result after the first gap: a marker keeps both,
+/// one converted from the other. A wall clock position in a gap is the start of the next span.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RecordingTimeline {
+    pub spans: Vec<Span>,
+}
+
+impl RecordingTimeline {
+    /// the timeline of a capture that just started
+    pub fn started() -> Self {
+        Self {
+            spans: vec![Span {
+                wall_ms: 0,
+                content_ms: 0,
+                ended_ms: None,
+            }],
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spans.is_empty()
+    }
+
+    /// the span being recorded ended at `wall_ms`
+    pub fn end(&mut self, wall_ms: u64) {
+        if let Some(span) = self.spans.last_mut().filter(|s| s.ended_ms.is_none()) {
+            span.ended_ms = Some(wall_ms.max(span.wall_ms));
+        }
+    }
+
+    /// a span starts at `wall_ms`, ending the one before when it was not yet
+    pub fn resume(&mut self, wall_ms: u64) {
+        self.end(wall_ms);
+        let content_ms = self.content_at(wall_ms);
+        let wall_ms = wall_ms.max(self.spans.last().map_or(0, |s| s.wall_ms));
+        self.spans.push(Span {
+            wall_ms,
+            content_ms,
+            ended_ms: None,
+        });
+    }
+
+    /// the position in what was recorded of the wall clock position
+    pub fn content_at(&self, wall_ms: u64) -> u64 {
+        let Some(span) = self.spans.iter().rev().find(|s| s.wall_ms <= wall_ms) else {
+            return 0;
+        };
+        let until = span.ended_ms.map_or(wall_ms, |ended| wall_ms.min(ended));
+        span.content_ms + (until - span.wall_ms)
+    }
+
+    /// the wall clock position of the position in what was recorded
+    pub fn wall_at(&self, content_ms: u64) -> u64 {
+        let Some(span) = self.spans.iter().rev().find(|s| s.content_ms <= content_ms) else {
+            return content_ms;
+        };
+        let wall_ms = span.wall_ms + (content_ms - span.content_ms);
+        span.ended_ms.map_or(wall_ms, |ended| wall_ms.min(ended))
+    }
+
+    /// all that was recorded, None while it is recorded
+    pub fn recorded_ms(&self) -> Option<u64> {
+        let last = self.spans.last()?;
+        last.ended_ms
+            .map(|ended| last.content_ms + (ended - last.wall_ms))
+    }
+
+    /// the time not recorded between the spans, up to `wall_ms`
+    pub fn gaps_ms(&self, wall_ms: u64) -> u64 {
+        wall_ms.saturating_sub(self.content_at(wall_ms))
+    }
+
+    /// a marker at the wall clock position
+    pub fn marker(&self, at_ms: u64, label: &str) -> Marker {
+        Marker {
+            at_ms,
+            label: label.to_string(),
+            content_ms: Some(self.content_at(at_ms)),
+        }
+    }
 }
 
 impl RecordingState {
+    /// name of the state, as in the `type` tag of its JSON
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Waiting => "Waiting",
+            Self::Countdown { .. } => "Countdown",
+            Self::Started { .. } => "Started",
+            Self::Paused { .. } => "Paused",
+            Self::Stopping { .. } => "Stopping",
+            Self::Compressing { .. } => "Compressing",
+            Self::Uploading { .. } => "Uploading",
+            Self::Done { .. } => "Done",
+            Self::Failed { .. } => "Failed",
+            Self::Cancelled { .. } => "Cancelled",
+        }
+    }
+
+    /// the state without the commands of its processes, which are only shown on request
+    pub fn without_command(mut self) -> Self {
+        match &mut self {
+            Self::Started { command, .. } | Self::Compressing { command, .. } => command.clear(),
+            Self::Paused { recording, .. } => {
+                **recording = std::mem::take(&mut **recording).without_command()
+            }
+            _ => {}
+        }
+        self
+    }
+
+    /// the state of the recording, the Started one kept by a pause
+    pub fn recording(&self) -> &Self {
+        match self {
+            Self::Paused { recording, .. } => recording,
+            state => state,
+        }
+    }
+
+    pub fn recording_mut(&mut self) -> &mut Self {
+        match self {
+            Self::Paused { recording, .. } => recording,
+            state => state,
+        }
+    }
+
+    pub fn set_gpu(&mut self, usage: Option<GpuUsage>) {
+        if let Self::Compressing { gpu, .. } = self {
+            *gpu = usage;
+        }
+    }
+
     pub fn set_progress(&mut self, p: Progress) {
-        if let Self::Started {
-            progress,
-            process_id: _,
-            file: _,
-        } = self
-        {
-            *progress = Some(p.clone());
-        };
+        match self {
+            Self::Started { progress, .. } => *progress = Some(p),
+            Self::Compressing {
+                progress,
+                duration_ms,
+                percent,
+                ..
+            } => {
+                if let (Some(out_time), Some(duration_ms)) = (p.out_time, *duration_ms) {
+                    let done = out_time.as_millis() as f64 / duration_ms.max(1) as f64;
+                    *percent = Some((done * 100.0).min(100.0));
+                }
+                *progress = Some(p);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// What a child process is for
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChildRole {
+    Capture,
+    Audio,
+    Compression,
+    Frames,
+    /// a playable copy for the browser
+    Transcode,
+    /// sampling the resources
+    Monitor,
+    /// extracting the audio for a transcript, transcribing it or adding the subtitles
+    Transcript,
+    /// looking at the content of a recording
+    Check,
+    /// a recording of the canary, see [crate::canary]
+    Canary,
+}
+
+/// A child process that was spawned and not reaped yet
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChildProcess {
+    pub pid: u32,
+    pub role: ChildRole,
+    /// files the process writes
+    pub files: Vec<String>,
+}
+
+/// The table of the child processes
+///
+/// Every spawn site registers its child, and whoever reaps it unregisters it,
+/// so the table is what is running right now.
+#[derive(Default)]
+pub struct Children {
+    table: std::sync::Mutex<HashMap<u32, ChildProcess>>,
+    /// woken whenever a child is unregistered
+    reaped: tokio::sync::Notify,
+}
+
+impl Children {
+    pub fn register(&self, pid: u32, role: ChildRole, files: Vec<String>) {
+        self.table
+            .lock()
+            .unwrap()
+            .insert(pid, ChildProcess { pid, role, files });
+    }
+
+    /// the child was reaped
+    pub fn unregister(&self, pid: u32) {
+        self.table.lock().unwrap().remove(&pid);
+        self.reaped.notify_waiters();
+    }
+
+    /// whether the child was not reaped yet
+    pub fn running(&self, pid: u32) -> bool {
+        self.table.lock().unwrap().contains_key(&pid)
+    }
+
+    pub fn list(&self) -> Vec<ChildProcess> {
+        let mut list: Vec<_> = self.table.lock().unwrap().values().cloned().collect();
+        list.sort_by_key(|c| c.pid);
+        list
+    }
+
+    /// wait until the child was reaped
+    pub async fn exited(&self, pid: u32) {
+        loop {
+            // registered before the look, an unregister in between is not missed
+            let reaped = self.reaped.notified();
+            tokio::pin!(reaped);
+            reaped.as_mut().enable();
+            if !self.running(pid) {
+                return;
+            }
+            reaped.await;
+        }
+    }
+
+    /// SIGKILL every child, the reapers unregister them
+    pub fn kill_all(&self) -> Vec<ChildProcess> {
+        Self::kill_each(self.list())
+    }
+
+    /// SIGKILL the children of these roles, the reapers unregister them
+    pub fn kill(&self, roles: &[ChildRole]) -> Vec<ChildProcess> {
+        let mut list = self.list();
+        list.retain(|child| roles.contains(&child.role));
+        Self::kill_each(list)
+    }
+
+    fn kill_each(list: Vec<ChildProcess>) -> Vec<ChildProcess> {
+        for child in &list {
+            let pid = nix::unistd::Pid::from_raw(child.pid as i32);
+            if let Err(e) = nix::sys::signal::kill(pid, nix::sys::signal::Signal::SIGKILL) {
+                warn!("cannot kill {:?} {}: {}", child.role, child.pid, e);
+            }
+        }
+        list
+    }
+}
+
+/// The recording state shared by the server, its changes are published to the streams
+#[derive(Default)]
+pub struct Recorder {
+    state: Mutex<RecordingState>,
+    pub events: Fanout,
+    pub history: History,
+    pub liveness: Liveness,
+    pub children: Children,
+    /// the API tokens and their roles
+    pub tokens: Tokens,
+    /// the URL the clients reach the server at, for the absolute links
+    pub external_url: Option<String>,
+    /// signs the feed tokens, the feeds are open without one
+    pub feed_secret: Option<String>,
+    /// when recording is allowed
+    pub policy: Policy,
+    /// the disk quotas of the owners
+    pub quotas: Quotas,
+    /// the speech-to-text programs the recordings may ask for
+    pub transcribers: Transcribers,
+    /// where a capture goes on when the output directory can't be written any more
+    pub fallback_dir: Option<PathBuf>,
+    /// the playable copies of the recordings
+    pub play: PlayCache,
+    /// the contact sheets being made
+    pub sheets: ContactSheets,
+    /// the pointers being drawn over the recordings
+    pub cursor_renders: cursor::Renders,
+    /// the GIFs being made of the recordings
+    pub gifs: Gifs,
+    /// the preview images of the recordings
+    pub thumbnails: Thumbnails,
+    /// how the content of the recordings is looked at
+    pub content_check: content_check::Config,
+    /// where the finished recordings are kept
+    pub storage: Storage,
+    /// what the strictly durable recordings are fsynced with
+    pub fsync: recordings::Syncer,
+    /// the longest recording of `/api/record`, [crate::timed::DEFAULT_MAX] when None
+    pub max_record: Option<Duration>,
+    /// the name of the recordings, see [crate::template]
+    pub file_name: Template,
+    /// how fast the background jobs may read
+    pub throttle: Arc<Throttle>,
+    /// how the recordings are scored
+    pub health: health::Monitor,
+    /// the encoders the recordings may ask for
+    pub encoder_profiles: Profiles,
+    /// what is cut off the results when the stop tells nothing, see [crate::trim]
+    pub trim: Trim,
+    /// the recordings proving the recorder works, see [crate::canary]
+    pub canaries: Canaries,
+    /// the last sample of the GPU, while a hardware encoder runs
+    pub gpu: std::sync::Mutex<Option<GpuUsage>>,
+    /// the traceparent of the recording started last, its compression joins its trace, see
+    /// [crate::otel]
+    pub trace: std::sync::Mutex<Option<String>>,
+    /// a recording is being started, see [StartClaim]
+    starting: AtomicBool,
+    /// the compressions, queued, running and finished
+    pub jobs: Journal,
+    /// the clients using the server
+    pub presence: Arc<Presence>,
+    /// the responses to the requests with an idempotency key, see [crate::idempotency]
+    pub idempotency: crate::idempotency::Responses,
+    /// the viewer of the WebRTC preview of the capture, see [crate::webrtc_preview]
+    pub webrtc_preview: webrtc_preview::Preview,
+    /// the stages of the recordings
+    pub pipeline: Pipeline,
+    /// where the state is saved for the next server after a crash, see [crate::recovery]
+    pub state_file: Option<StateFile>,
+    /// where the changes of state are posted, see [crate::webhook]
+    pub webhook: Option<Webhook>,
+    /// where the results are uploaded, see [crate::s3]
+    pub bucket: Option<Bucket>,
+}
+
+/// Held from the request to start a recording until it is Started or its start failed,
+/// so that a second request can't start another one meanwhile
+pub struct StartClaim(Arc<Recorder>);
+
+impl StartClaim {
+    pub fn recorder(&self) -> Arc<Recorder> {
+        self.0.clone()
+    }
+}
+
+impl Drop for StartClaim {
+    fn drop(&mut self) {
+        self.0.starting.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_history(history: History) -> Self {
+        Self {
+            history,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_liveness(mut self, liveness: Liveness) -> Self {
+        self.liveness = liveness;
+        self
+    }
+
+    pub fn with_tokens(mut self, tokens: Tokens) -> Self {
+        self.tokens = tokens;
+        self
+    }
+
+    pub fn with_external_url(mut self, url: Option<String>) -> Self {
+        self.external_url = url;
+        self
+    }
+
+    pub fn with_feed_secret(mut self, secret: Option<String>) -> Self {
+        self.feed_secret = secret;
+        self
+    }
+
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn with_quotas(mut self, quotas: Quotas) -> Self {
+        self.quotas = quotas;
+        self
+    }
+
+    pub fn with_transcribers(mut self, transcribers: Transcribers) -> Self {
+        self.transcribers = transcribers;
+        self
+    }
+
+    pub fn with_throttle(mut self, config: throttle::Config) -> Self {
+        self.throttle = Arc::new(Throttle::new(config));
+        self
+    }
+
+    pub fn with_encoder_profiles(mut self, profiles: Profiles) -> Self {
+        self.encoder_profiles = profiles;
+        self
+    }
+
+    pub fn with_canaries(mut self, canaries: Canaries) -> Self {
+        self.canaries = canaries;
+        self
+    }
+
+    pub fn with_fsync(mut self, fsync: impl recordings::Fsync + 'static) -> Self {
+        self.fsync = recordings::Syncer::new(fsync);
+        self
+    }
+
+    pub fn with_trim(mut self, trim: Trim) -> Self {
+        self.trim = trim;
+        self
+    }
+
+    pub fn with_health(mut self, config: health::Config) -> Self {
+        self.health = health::Monitor::new(config);
+        self
+    }
+
+    pub fn with_file_name(mut self, file_name: Template) -> Self {
+        self.file_name = file_name;
+        self
+    }
+
+    pub fn with_fallback_dir(mut self, dir: Option<PathBuf>) -> Self {
+        self.fallback_dir = dir;
+        self
+    }
+
+    pub fn with_jobs(mut self, jobs: Journal) -> Self {
+        self.jobs = jobs;
+        self
+    }
+
+    pub fn with_pipeline(mut self, pipeline: Pipeline) -> Self {
+        self.pipeline = pipeline;
+        self
+    }
+
+    pub fn with_play_cache(mut self, play: PlayCache) -> Self {
+        self.play = play;
+        self
+    }
+
+    pub fn with_content_check(mut self, config: content_check::Config) -> Self {
+        self.content_check = config;
+        self
+    }
+
+    pub fn with_max_record(mut self, max: Option<Duration>) -> Self {
+        self.max_record = max;
+        self
+    }
+
+    pub fn with_storage(mut self, storage: Storage) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    pub fn with_state_file(mut self, state_file: Option<StateFile>) -> Self {
+        self.state_file = state_file;
+        self
+    }
+
+    pub fn with_webhook(mut self, webhook: Option<Webhook>) -> Self {
+        self.webhook = webhook;
+        self
+    }
+
+    pub fn with_bucket(mut self, bucket: Option<Bucket>) -> Self {
+        self.bucket = bucket;
+        self
+    }
+
+    /// claim the start of a recording, None while another one is being started
+    pub fn claim_start(self: &Arc<Self>) -> Option<StartClaim> {
+        (!self.starting.swap(true, Ordering::SeqCst)).then(|| StartClaim(self.clone()))
+    }
+
+    pub async fn lock(&self) -> MutexGuard<'_, RecordingState> {
+        self.state.lock().await
+    }
+
+    /// switch to a new state
+    pub async fn set(&self, state: RecordingState) {
+        let mut guard = self.lock().await;
+        self.replace(&mut guard, state);
+    }
+
+    /// switch to a new state while already holding the lock
+    pub fn replace(&self, guard: &mut RecordingState, state: RecordingState) {
+        *guard = state;
+        if let Some(state_file) = &self.state_file {
+            state_file.save(guard);
+        }
+        if let Some(webhook) = &self.webhook {
+            webhook.notify(guard);
+        }
+        self.events.publish(EventKind::State {
+            state: guard.clone(),
+        });
+    }
+
+    /// the progress of the capture or the compression, kept in the state for the status
+    pub fn progress(&self, progress: &Progress) {
+        // the next report comes in a moment when the state is busy
+        if let Ok(mut state) = self.state.try_lock() {
+            state.set_progress(progress.clone());
+        }
+        self.events.publish(EventKind::Progress {
+            progress: progress.clone(),
+            gpu: self.gpu.lock().unwrap().clone(),
+        });
+    }
+
+    /// subscribe to the events after `since`
+    pub async fn subscribe(&self, since: Option<u64>) -> Subscription {
+        let state = self.lock().await;
+        self.events.subscribe(since, &state)
     }
 }
 
-#[derive(Default, Debug, Clone, serde::Deserialize)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct RecordingOptions {
+    /// what to record, the local screen by default
+    #[serde(default)]
+    pub source: CaptureSource,
+    /// record the sound server along with the screen
     #[serde(default)]
     pub audio: bool,
+    /// record audio with a separate supervised process, so that a restart
+    /// of the sound server does not end the recording
+    #[serde(default)]
+    pub audio_resilient: bool,
+    /// the PulseAudio source the audio is captured from, [crate::pulse::DEFAULT] by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio_source: Option<String>,
+    /// the PulseAudio sources mixed into the audio instead, each at its volume
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub audio_sources: Vec<AudioInput>,
+    /// what is being recorded, to tune the compression for it
+    #[serde(default)]
+    pub content: ContentKind,
+    /// whether the result must be on disk before the recording is reported done
+    #[serde(default)]
+    pub durability: Durability,
+    /// who the recording is for, kept in the history
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    /// start the video with a 3-2-1 countdown and a red border, drawn by ffmpeg
+    #[serde(default)]
+    pub intro_countdown: bool,
+    /// a text drawn over all of the capture, `%{localtime}` expanded, see [crate::overlays]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub overlay_text: Option<String>,
+    /// where the overlay text is drawn
+    #[serde(default)]
+    pub overlay_position: overlays::Position,
+    /// the size of the overlay text in pixels, [crate::overlays::FONT_SIZE] by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub overlay_font_size: Option<u32>,
+    /// positions of still frames to extract once the recording is done
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extract_frames: Vec<String>,
+    /// transcribe the recording once it is done
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transcribe: Option<TranscribeRequest>,
+    /// also name the result by its hash, in the `by-hash` directory
+    #[serde(default)]
+    pub content_addressed: bool,
+    /// what the human readable name of a content addressed result becomes
+    #[serde(default)]
+    pub hash_link: HashLink,
+    /// what to do when the captured monitor or the resolution changes
+    #[serde(default)]
+    pub on_geometry_change: GeometryPolicy,
+    /// write the capture time of every frame to a `<name>.frames.csv.gz` sidecar
+    #[serde(default)]
+    pub frame_timestamps: bool,
+    /// the region of the display to capture, all of the screen by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<Rect>,
+    /// the width captured from the top left corner without a region, that of the screen by
+    /// default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    /// the height captured from the top left corner without a region
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    /// frames per second of a screen capture, [crate::quality::SCREEN_FRAMERATE] by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub framerate: Option<u32>,
+    /// whether x11grab reads the screen from shared memory, as the capture path analysis
+    /// recommends by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub use_shm: Option<bool>,
+    /// capture the screen without the pointer and track it apart, to be drawn over the recording
+    /// later, see [crate::cursor]
+    #[serde(default)]
+    pub cursor_track: bool,
+    /// whether x11grab draws the pointer, unless false; not with cursor_track, see [crate::cursor]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub draw_mouse: Option<bool>,
+    /// draw a square around the pointer for every click while the capture is compressed
+    #[serde(default)]
+    pub show_clicks: bool,
+    /// write an HLS stream of the capture to watch while it is recorded, see [crate::preview]
+    #[serde(default)]
+    pub live_preview: bool,
+    /// how the capture is previewed, `hls` being `live_preview`, see [crate::webrtc_preview]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preview: Option<webrtc_preview::Transport>,
+    /// stream the capture to this RTMP server as well, see [crate::rtmp]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rtmp_url: Option<String>,
+    /// the bitrate of the stream in kbit/s, [crate::rtmp::DEFAULT_BITRATE] by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rtmp_bitrate: Option<u32>,
+    /// stream the capture without writing it
+    #[serde(default)]
+    pub stream_only: bool,
+    /// open the compressed recording with a slate naming it, see [crate::slate]
+    #[serde(default)]
+    pub slate: bool,
+    /// start the capture at this time, to start on several machines at once, see
+    /// [crate::sync_start]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_at: Option<DateTime<Local>>,
+    /// make the contact sheet of the recording once it is done, see [crate::contact_sheet]
+    #[serde(default)]
+    pub auto_contact_sheet: bool,
+    /// look for a black or silent result once it is compressed, see [crate::content_check];
+    /// the server decides when None
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verify_content: Option<bool>,
+    /// compress with an encoder profile of the server rather than for the content, see
+    /// [crate::encoder_profiles]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoder_profile: Option<String>,
+    /// options of ffmpeg over those of the encoder profile, `{"crf": "18"}`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub encoder_options: BTreeMap<String, String>,
+    /// write the capture in chunks of this many seconds, see [crate::chunks]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub segment_seconds: Option<u64>,
+    /// join the chunks into one result rather than compress each of them
+    #[serde(default)]
+    pub concat_segments: bool,
+    /// compress the capture once it is stopped, unless false: the raw capture is the result then
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compress: Option<bool>,
+    /// the constant rate factor of the compression, 0 to [MAX_CRF], over that for the content
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crf: Option<u8>,
+    /// the libx264 preset of the compression, one of [PRESETS]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+    /// keep the raw capture next to the compressed result
+    #[serde(default)]
+    pub keep_original: bool,
+    /// bring the audio to [crate::loudness::TARGET_LUFS] while it is compressed
+    #[serde(default)]
+    pub normalize_audio: bool,
+    /// the size the compression scales the frames to, `1920:-2` or `1080p`, see [crate::scale]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scale: Option<String>,
+    /// what encodes the capture and its compression, see [crate::hwaccel]
+    #[serde(default, skip_serializing_if = "VideoEncoder::is_software")]
+    pub encoder: VideoEncoder,
+    /// the container of the result, see [crate::container]
+    #[serde(default, skip_serializing_if = "Container::is_mp4")]
+    pub format: Container,
+}
+
+/// How hard to make sure the recording survives a power loss
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum Durability {
+    /// leave it to the page cache
+    #[default]
+    Default,
+    /// flush packets while capturing, fsync the result before reporting Done
+    Strict,
+}
+
+/// How the human readable name of a content addressed recording keeps working
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum HashLink {
+    /// the file moves to its hash name, the human readable name links to it
+    #[default]
+    Symlink,
+    /// both names are hard links of the same file
+    Hardlink,
+}
+
+/// Kind of the recorded content, the compression encoder is tuned for it
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum ContentKind {
+    /// generic settings
+    #[default]
+    Auto,
+    /// mostly static text: terminals, IDEs, documents
+    Screen,
+    /// video, games, animations
+    Motion,
+}
+
+/// Encoder and its options used for the compression
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncoderParams {
+    pub content: ContentKind,
+    pub codec: String,
+    pub options: Vec<(String, String)>,
+    /// the encoder profile the options come from, see [crate::encoder_profiles]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    /// the options of the profile merged with those of the recording, in place of `options`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parameters: Vec<ProfileParameter>,
+    /// the hardware `codec` is of, see [crate::hwaccel]
+    #[serde(default, skip_serializing_if = "VideoEncoder::is_software")]
+    pub video_encoder: VideoEncoder,
 }
 
+impl EncoderParams {
+    /// compression settings for the given content
+    pub fn resolve(content: ContentKind) -> Self {
+        let options: &[(&str, &str)] = match content {
+            ContentKind::Auto => &[("crf", "20")],
+            // static frames compress best with long GOPs, and the raw capture is
+            // yuv444p already: keeping it leaves small text sharp
+            ContentKind::Screen => &[
+                ("crf", "20"),
+                ("tune", "stillimage"),
+                ("g", "250"),
+                ("pix_fmt", "yuv444p"),
+            ],
+            ContentKind::Motion => &[
+                ("crf", "20"),
+                ("tune", "film"),
+                ("g", "50"),
+                ("pix_fmt", "yuv420p"),
+            ],
+        };
+        Self {
+            content,
+            codec: "libx264".to_string(),
+            options: options
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            profile: None,
+            parameters: vec![],
+            video_encoder: VideoEncoder::Software,
+        }
+    }
+
+    /// the settings for the content on a hardware encoder, at the quality of the software one
+    pub fn on(mut self, encoder: VideoEncoder) -> Self {
+        if encoder.is_software() {
+            return self;
+        }
+        // the tune and the pixel format are those of x264, the GOP applies to any encoder
+        let quality = self.options.iter().find(|(k, _)| k == "crf").cloned();
+        self.options.retain(|(k, _)| k == "g");
+        if encoder == VideoEncoder::Nvenc {
+            self.options.push(("preset".to_string(), "p5".to_string()));
+            self.options.push(("rc".to_string(), "vbr".to_string()));
+        }
+        if let Some((_, value)) = quality {
+            let option = encoder.rate_option("crf");
+            self.options.push((option.to_string(), value));
+        }
+        self.codec = encoder.codec().to_string();
+        self.video_encoder = encoder;
+        self
+    }
+
+    /// the settings for the content in a webm, VP9 rather than x264
+    pub fn in_container(mut self, format: Container) -> Self {
+        if format != Container::Webm {
+            return self;
+        }
+        // a constant quality of VP9 needs a bitrate of 0, its crf goes to 63
+        let gop = self.options.iter().find(|(k, _)| k == "g").cloned();
+        self.options = vec![
+            ("crf".to_string(), "32".to_string()),
+            ("b:v".to_string(), "0".to_string()),
+            ("row-mt".to_string(), "1".to_string()),
+            ("pix_fmt".to_string(), "yuv420p".to_string()),
+        ];
+        self.options.extend(gop);
+        self.codec = "libvpx-vp9".to_string();
+        self
+    }
+
+    /// the settings with the rate factor and the preset the options ask for over them
+    pub fn tuned(mut self, opt: &RecordingOptions) -> Self {
+        let rate = self.video_encoder.rate_option("crf");
+        let crf = opt.crf.map(|crf| (rate, crf.to_string()));
+        let preset = opt.preset.as_ref().map(|preset| ("preset", preset.clone()));
+        for (key, value) in crf.into_iter().chain(preset) {
+            self.options.retain(|(k, _)| k != key);
+            self.options.push((key.to_string(), value));
+        }
+        self
+    }
+
+    /// compression settings of an encoder profile, with the options of the recording over them
+    pub fn of_profile(
+        profile: &EncoderProfile,
+        content: ContentKind,
+        overrides: &BTreeMap<String, String>,
+    ) -> Result<Self, String> {
+        Ok(Self {
+            content,
+            codec: profile.video_codec().unwrap_or("default").to_string(),
+            options: vec![],
+            profile: Some(profile.name.clone()),
+            parameters: profile.merged(overrides)?,
+            video_encoder: VideoEncoder::Software,
+        })
+    }
+}
+
+/// the highest constant rate factor of libx264, the worst quality
+pub const MAX_CRF: u8 = 51;
+
+/// the presets of libx264, from the fastest to the smallest result
+pub const PRESETS: &[&str] = &[
+    "ultrafast",
+    "superfast",
+    "veryfast",
+    "faster",
+    "fast",
+    "medium",
+    "slow",
+    "slower",
+    "veryslow",
+    "placebo",
+];
+
+/// whether the compression the options ask for can be done, the field that can't otherwise
+pub fn validate_compression(opt: &RecordingOptions) -> Result<(), (&'static str, String)> {
+    if let Some(crf) = opt.crf.filter(|crf| *crf > MAX_CRF) {
+        return Err(("crf", format!("{} is over {}", crf, MAX_CRF)));
+    }
+    if let Some(preset) = opt.preset.as_deref().filter(|p| !PRESETS.contains(p)) {
+        return Err(("preset", format!("{} is not a preset of libx264", preset)));
+    }
+    if opt.preset.is_some() && !opt.encoder.is_software() {
+        return Err(("preset", format!("not for the {} encoder", opt.encoder)));
+    }
+    if opt.encoder_profile.is_some() && !opt.format.is_mp4() {
+        return Err((
+            "format",
+            "the encoder profile tells the container".to_string(),
+        ));
+    }
+    if opt.format == Container::Webm {
+        if !opt.encoder.is_software() {
+            return Err(("format", format!("not with the {} encoder", opt.encoder)));
+        }
+        if opt.preset.is_some() {
+            return Err(("preset", "a preset of libx264, not of VP9".to_string()));
+        }
+    }
+    if !opt.encoder.is_software() {
+        if opt.encoder_profile.is_some() {
+            return Err((
+                "encoder",
+                "the encoder profile tells the encoder".to_string(),
+            ));
+        }
+        // the slate is drawn in a filter graph the frames are not uploaded from
+        if opt.slate && opt.encoder == VideoEncoder::Vaapi {
+            return Err(("slate", "not with the vaapi encoder".to_string()));
+        }
+    }
+    let tuned = [
+        ("crf", opt.crf.is_some()),
+        ("preset", opt.preset.is_some()),
+        ("normalize_audio", opt.normalize_audio),
+    ];
+    for (field, _) in tuned.into_iter().filter(|(_, given)| *given) {
+        if opt.encoder_profile.is_some() {
+            return Err((
+                field,
+                "given in the encoder_options of a profile".to_string(),
+            ));
+        }
+        if opt.compress == Some(false) {
+            return Err((field, "given without a compression".to_string()));
+        }
+    }
+    if opt.compress == Some(false) {
+        // both are done by the compression
+        if opt.audio_resilient {
+            return Err((
+                "compress",
+                "the resilient audio is mixed in by it".to_string(),
+            ));
+        }
+        if opt.slate {
+            return Err(("compress", "the slate is added by it".to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// region of the display that is captured when its size can't be asked, see [crate::display]
+pub const VIDEO_SIZE: &str = "1920x1080";
+
 /// start process of recording
-pub async fn start(mx: Arc<Mutex<RecordingState>>, opt: RecordingOptions) -> anyhow::Result<()> {
-    let current = mx.clone().lock().await.clone();
-    match current {
-        RecordingState::Done { .. } => {}
-        RecordingState::Waiting => {}
-        _ => anyhow::bail!("not ready to start"),
+pub async fn start(mx: Arc<Recorder>, opt: RecordingOptions) -> anyhow::Result<()> {
+    let Some(claim) = mx.claim_start() else {
+        bail!("a recording is being started already");
+    };
+    start_claimed(claim, opt, None).await
+}
+
+/// start process of recording, once its start was claimed, for the client `by`
+pub async fn start_claimed(
+    claim: StartClaim,
+    opt: RecordingOptions,
+    by: Option<Identity>,
+) -> anyhow::Result<()> {
+    let mx = claim.recorder();
+    mx.pipeline.start(claim, opt, by).await.map(|_| ())
+}
+
+pub(crate) fn log_summary(what: &str, summary: &CompletionSummary) {
+    if summary.success() {
+        info!("{} finished in {:?}", what, summary.wall_time);
+    } else {
+        warn!(
+            "{} exited with {} after {:?}:\n{}",
+            what,
+            summary.exit_status,
+            summary.wall_time,
+            summary.stderr_tail.join("\n")
+        );
+    }
+}
+
+/// switch to Failed and keep the failure in the history
+pub(crate) async fn fail(
+    mx: &Recorder,
+    opt: &RecordingOptions,
+    reason: FailureReason,
+    message: String,
+    stderr_tail: Vec<String>,
+) {
+    warn!("recording failed, {:?}: {}", reason, message);
+    let at = Local::now();
+    mx.set(RecordingState::Failed {
+        reason,
+        message,
+        at,
+        stderr_tail,
+    })
+    .await;
+    let entry = HistoryEntry {
+        min_health: mx.health.take_low(),
+        ..HistoryEntry::new("failed", at, opt.owner.clone())
     };
-    let pictures = dirs::video_dir().unwrap();
-    let out = format!(
-        "{}/{}.mp4",
-        pictures.to_str().unwrap(),
-        chrono::Local::now().format("%Y-%m-%dT%H-%M")
+    if let Err(e) = mx.history.append(entry) {
+        warn!("cannot write history: {}", e);
+    }
+}
+
+/// What an emergency stop killed and left behind
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmergencyStop {
+    pub killed: Vec<ChildProcess>,
+    pub preserved: Vec<String>,
+}
+
+/// SIGKILL every child process now, skipping all post-processing
+///
+/// The partial files are kept and flagged for the recovery scan.
+pub async fn emergency_stop(mx: Arc<Recorder>, by: Option<Identity>) -> EmergencyStop {
+    // under the lock, so that no supervisor spawns anything meanwhile
+    let mut state = mx.lock().await;
+    let killed = mx.children.kill_all();
+    let mut preserved: Vec<String> = killed
+        .iter()
+        .flat_map(|c| c.files.iter().cloned())
+        .chain(recordings::in_use(&state).into_iter().map(String::from))
+        .collect();
+    if let RecordingState::Started {
+        audio: Some(audio), ..
+    } = state.recording()
+    {
+        preserved.extend(audio.segments.iter().map(|s| s.file.clone()));
+    }
+    if let RecordingState::Started { segments, .. } = state.recording() {
+        preserved.extend(segments.iter().map(|s| s.file.clone()));
+    }
+    preserved.sort();
+    preserved.dedup();
+    preserved.retain(|f| std::path::Path::new(f).exists());
+    for file in &preserved {
+        if let Err(e) = recordings::flag_for_recovery(std::path::Path::new(file)) {
+            warn!("cannot flag {} for recovery: {}", file, e);
+        }
+    }
+    warn!(
+        "emergency stop: killed {:?}, preserved {:?}",
+        killed.iter().map(|c| c.pid).collect::<Vec<_>>(),
+        preserved
+    );
+
+    let active = matches!(
+        *state,
+        RecordingState::Countdown { .. }
+            | RecordingState::Started { .. }
+            | RecordingState::Paused { .. }
+            | RecordingState::Stopping { .. }
+            | RecordingState::Compressing { .. }
     );
-    println!("{} {:?} -> {}", "on air".green(), opt, out.yellow());
-    let mut builder = FfmpegBuilder::new().stderr(Stdio::piped());
-    builder = builder
-        .option(Parameter::KeyValue("f", "x11grab"))
-        .option(Parameter::KeyValue("video_size", "1920x1080"))
-        .option(Parameter::KeyValue("framerate", "25"))
-        .option(Parameter::KeyValue("i", ":1.0"));
-    if opt.audio {
-        builder = builder
-            .option(Parameter::KeyValue("f", "pulse"))
-            .option(Parameter::KeyValue("ac", "2"))
-            .option(Parameter::KeyValue("i", "default"));
-    }
-
-    builder = builder
-        .option(Parameter::KeyValue("preset", "ultrafast"))
-        .option(Parameter::KeyValue("qp", "0"))
-        .option(Parameter::KeyValue("pix_fmt", "yuv444p"))
-        .output(File::new(&out));
-
-    let ffmpeg = builder.run().await.unwrap();
-    let process_id = ffmpeg.process.id();
-    if process_id > 0 {
-        *mx.clone().lock().await = RecordingState::Started {
-            progress: None,
-            process_id,
-            file: out.clone(),
+    if active {
+        let (started_at, owner, commands, started_by) = match state.recording() {
+            RecordingState::Started {
+                started_at,
+                options,
+                command,
+                started_by,
+                ..
+            } => (
+                Some(*started_at),
+                options.owner.clone(),
+                vec![command.clone()],
+                started_by.clone(),
+            ),
+            RecordingState::Compressing { command, .. } => {
+                (None, None, vec![command.clone()], None)
+            }
+            _ => (None, None, vec![], None),
+        };
+        mx.replace(
+            &mut state,
+            RecordingState::Cancelled {
+                reason: CancelReason::EmergencyStop,
+                preserved: preserved.clone(),
+            },
+        );
+        let entry = HistoryEntry {
+            started_at,
+            commands,
+            started_by,
+            stopped_by: by,
+            min_health: mx.health.take_low(),
+            ..HistoryEntry::new("cancelled", Local::now(), owner)
         };
+        if let Err(e) = mx.history.append(entry) {
+            warn!("cannot write history: {}", e);
+        }
     }
-    ffmpeg
-        .progress
-        .for_each(|x| {
-            if let Ok(p) = x {
-                println!("{}", p.print_info());
-                //  mx.lock().await.set_progress(p);
-                //futures::executor::block_on(async move || {
-                //   *(mx.clone().lock().await).set_progress(&p);
-                //});
+    EmergencyStop { killed, preserved }
+}
+
+/// throw the running recording away, for one started by accident
+///
+/// A capture is killed and its files are removed, the recorder is Waiting again. A compression
+/// is killed and its partial output removed, the raw capture it was compressing is the result.
+pub async fn cancel(mx: Arc<Recorder>, by: Option<Identity>) -> anyhow::Result<RecordingState> {
+    let mut state = mx.lock().await;
+    let (next, files, killed, entry, preview) = match state.recording().clone() {
+        RecordingState::Countdown { .. } => (RecordingState::Waiting, vec![], vec![], None, None),
+        RecordingState::Started {
+            file,
+            audio,
+            options,
+            started_at,
+            command,
+            frame_timestamps,
+            started_by,
+            segments,
+            chunks,
+            preview,
+            ..
+        } => {
+            // under the lock, so that no supervisor spawns another segment meanwhile
+            let killed = mx.children.kill(&[ChildRole::Capture, ChildRole::Audio]);
+            let mut captures: Vec<String> = segments.into_iter().map(|s| s.file).collect();
+            if let Some(chunks) = chunks {
+                // the sidecars are named after the capture
+                captures.extend(chunks.all());
+                captures.push(chunks.capture);
             }
-            ready(())
-        })
-        .await;
+            captures.push(file);
+            captures.sort();
+            captures.dedup();
+            let mut files: Vec<PathBuf> = captures
+                .iter()
+                .flat_map(|capture| {
+                    [
+                        PathBuf::from(capture),
+                        latency::sidecar_path(capture),
+                        cursor::track_path(capture),
+                        cursor::clicks_path(capture),
+                        sync_start::sidecar_path(capture),
+                    ]
+                })
+                .collect();
+            let audio = audio.into_iter().flat_map(|audio| audio.segments);
+            files.extend(audio.map(|segment| PathBuf::from(segment.file)));
+            files.extend(frame_timestamps.map(PathBuf::from));
+            files.extend(
+                killed
+                    .iter()
+                    .flat_map(|c| c.files.iter().map(PathBuf::from)),
+            );
+            let entry = (Some(started_at), options.owner, command, started_by, None);
+            (RecordingState::Waiting, files, killed, Some(entry), preview)
+        }
+        RecordingState::Compressing {
+            input,
+            output,
+            command,
+            ..
+        } => {
+            let killed = mx.children.kill(&[ChildRole::Compression]);
+            let raw = RecordingState::Done {
+                file: input.clone(),
+                audio: None,
+                markers: vec![],
+                durable: false,
+                frames: vec![],
+                stopped_reason: None,
+                frame_timestamps: None,
+                content_warnings: vec![],
+                chunks: vec![],
+                loudness: None,
+                resolution: None,
+            };
+            let entry = (None, None, command, None, Some(input));
+            (raw, vec![PathBuf::from(output)], killed, Some(entry), None)
+        }
+        state => bail!("cannot cancel while {}", state.name()),
+    };
+    mx.replace(&mut state, next.clone());
+    drop(state);
+    info!("cancelled, {}", next.name());
+    // the files are removed once nothing writes them any more
+    for child in &killed {
+        if tokio::time::timeout(CANCEL_GRACE, mx.children.exited(child.pid))
+            .await
+            .is_err()
+        {
+            warn!("the {:?} {} was not reaped", child.role, child.pid);
+        }
+    }
+    for file in &files {
+        match std::fs::remove_file(file) {
+            Ok(()) => debug!("removed {}", file.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warn!("cannot remove {}: {}", file.display(), e),
+        }
+    }
+    if let Some(preview) = &preview {
+        preview::remove(preview).await;
+    }
+    if let Some((started_at, owner, command, started_by, file)) = entry {
+        let entry = HistoryEntry {
+            started_at,
+            file,
+            commands: vec![command],
+            started_by,
+            stopped_by: by,
+            min_health: mx.health.take_low(),
+            ..HistoryEntry::new("cancelled", Local::now(), owner)
+        };
+        if let Err(e) = mx.history.append(entry) {
+            warn!("cannot write history: {}", e);
+        }
+    }
+    Ok(next)
+}
 
-    Ok(())
+/// drop a marker at the current position of the recording
+pub async fn add_marker(mx: Arc<Recorder>, label: &str) -> anyhow::Result<Marker> {
+    let mut state = mx.lock().await;
+    let RecordingState::Started {
+        started_at,
+        markers,
+        timeline,
+        ..
+    } = state.recording_mut()
+    else {
+        bail!("not started")
+    };
+    let marker = timeline.marker(since(*started_at), label);
+    markers.push(marker.clone());
+    Ok(marker)
 }
 
 /// stop process of recording
-pub async fn stop(mx: Arc<Mutex<RecordingState>>) -> anyhow::Result<()> {
-    let current = mx.clone().lock().await.clone();
-    let (pid, input) = if let RecordingState::Started {
-        process_id, file, ..
-    } = current
-    {
-        (process_id, file.to_string())
-    } else {
+pub async fn stop(mx: Arc<Recorder>) -> anyhow::Result<()> {
+    stop_with(mx, None, None, StopRequest::default()).await
+}
+
+/// stop the recording, telling why when the server decided to
+pub async fn stop_for(mx: Arc<Recorder>, reason: Option<StopReason>) -> anyhow::Result<()> {
+    stop_with(mx, reason, None, StopRequest::by_server()).await
+}
+
+/// stop the recording for the client `by`, trimmed as it asks
+pub async fn stop_by(
+    mx: Arc<Recorder>,
+    by: Option<Identity>,
+    request: StopRequest,
+) -> anyhow::Result<()> {
+    stop_with(mx, None, by, request).await
+}
+
+async fn stop_with(
+    mx: Arc<Recorder>,
+    reason: Option<StopReason>,
+    stopped_by: Option<Identity>,
+    request: StopRequest,
+) -> anyhow::Result<()> {
+    // switching to Stopping under the same lock keeps the audio supervisor
+    // from adding a segment we would not know about
+    let mut state = mx.lock().await;
+    if let RecordingState::Countdown { start_at, .. } = &*state {
+        info!("stopped before the start at {}", start_at.to_rfc3339());
+        mx.replace(
+            &mut state,
+            RecordingState::Cancelled {
+                reason: CancelReason::StoppedInCountdown,
+                preserved: vec![],
+            },
+        );
+        return Ok(());
+    }
+    let RecordingState::Started {
+        process_id: pid,
+        file: input,
+        audio,
+        options,
+        markers,
+        started_at,
+        command: capture_command,
+        frame_timestamps,
+        started_by,
+        segments: mut video_segments,
+        chunks,
+        mut timeline,
+        first_frame,
+        preview,
+        ..
+    } = state.recording().clone()
+    else {
         bail!("not started")
     };
-    let output = input.clone().replace(".mp4", ".compressed.mp4");
+    timeline.end(since(started_at));
+    // the content only grew since the request was checked, a default may not fit a short one
+    let trim = match request
+        .trim(mx.trim)
+        .and_then(|trim| trim.window_at(&timeline, since(started_at)))
+    {
+        Ok(window) => window,
+        Err(e) => {
+            warn!("not trimming {}: {}", input, e);
+            None
+        }
+    };
+    // the result is named after the first segment
+    let first = video_segments.first().map(|s| s.file.clone());
+    let extension = options
+        .encoder_profile
+        .as_deref()
+        .and_then(|name| mx.encoder_profiles.get(name))
+        .map_or(options.format.extension(), |profile| profile.extension());
+    // next to the capture, in the output directory
+    let capture = first
+        .as_ref()
+        .or(chunks.as_ref().map(|chunks| &chunks.capture))
+        .unwrap_or(&input);
+    let output = format!("{}.compressed.{}", container::base(capture), extension);
 
-    println!("{} {}", "stopping".green(), pid);
-    *mx.clone().lock().await = RecordingState::Stopping {
-        process_id: pid,
-        file: input.clone(),
+    info!("stopping {}", pid);
+    mx.replace(
+        &mut state,
+        RecordingState::Stopping {
+            process_id: pid,
+            file: input.clone(),
+        },
+    );
+    drop(state);
+
+    // the capture may be gone already between two segments or when it ended by itself, its
+    // reaper unregisters it, and its pid is not signalled once it may be another process's
+    if mx.children.running(pid)
+        && crate::runner::stop_graceful(pid, CANCEL_GRACE, mx.children.exited(pid)).await
+    {
+        warn!(
+            "the capture {} didn't finish within {:?}, it was killed",
+            pid, CANCEL_GRACE
+        );
+    }
+    if let Some(preview) = &preview {
+        preview::remove(preview).await;
+    }
+    if !matches!(*mx.lock().await, RecordingState::Stopping { .. }) {
+        info!("recording was cancelled while stopping");
+        return Ok(());
+    }
+    if options.stream_only {
+        // nothing was written to be compressed
+        info!("the stream ended");
+        let entry = HistoryEntry {
+            started_at: Some(started_at),
+            commands: vec![capture_command],
+            frame_timestamps,
+            started_by,
+            stopped_by,
+            first_frame,
+            min_health: mx.health.take_low(),
+            ..HistoryEntry::new("streamed", Local::now(), options.owner)
+        };
+        if let Err(e) = mx.history.append(entry) {
+            warn!("cannot write history: {}", e);
+        }
+        mx.set(RecordingState::Waiting).await;
+        return Ok(());
+    }
+    let segments = match &audio {
+        Some(audio) => {
+            audio::stop(audio).await;
+            audio::recorded_segments(audio)
+        }
+        None => vec![],
     };
+    // the last chunk is complete once the capture exited
+    let mut chunks = chunks.map(|chunks| chunks.all()).unwrap_or_default();
+    let input = chunks.first().cloned().or(first).unwrap_or(input);
+    if options.concat_segments {
+        // joined as the segments of a capture rolled over
+        let seconds = options.segment_seconds.unwrap_or_default();
+        video_segments = std::mem::take(&mut chunks)
+            .into_iter()
+            .enumerate()
+            .map(|(i, file)| VideoSegment {
+                file,
+                offset_ms: i as u64 * seconds * 1000,
+                quality: None,
+            })
+            .collect();
+    }
 
-    // sending kill signal for a process
-    nix::sys::signal::kill(
-        nix::unistd::Pid::from_raw(pid as i32),
-        nix::sys::signal::Signal::SIGINT,
-    )
-    .expect("cannot send ctrl-c");
-
-    // wait for process to be finished if the process is finished
-    nix::sys::wait::waitpid(nix::unistd::Pid::from_raw(pid as i32), None).expect("waitpid failed");
-
-    // start compression and watch its progress
-    // ffmpeg -i input.mp4 -vcodec libx264 -crf 20 output.mp4
-    let mut builder = FfmpegBuilder::new().stderr(Stdio::piped());
-    builder = builder
-        .input(File::new(&input))
-        .option2(Parameter::KeyValue("vcodec", "libx264"))
-        .option2(Parameter::KeyValue("crf", "20"))
-        .output(File::new(&output));
-    let ffmpeg = builder.run().await.unwrap();
-    let process_id = ffmpeg.process.id();
-    *mx.clone().lock().await = RecordingState::Compressing {
-        process_id,
-        input: input.clone(),
-        output: output.clone(),
+    let compression = jobs::Compression {
+        input,
+        output,
+        options,
+        segments,
+        audio,
+        markers,
+        started_at,
+        capture_command,
+        reason,
+        frame_timestamps,
+        started_by,
+        stopped_by,
+        video_segments,
+        chunks,
+        timeline,
+        first_frame,
+        trim,
+        trace: mx.trace.lock().unwrap().take(),
     };
+    let id = mx.jobs.enqueue(compression.clone());
+    compress(mx, id, compression, None).await
+}
 
-    println!("{} {}", "compressing".green(), process_id);
-    ffmpeg
-        .progress
-        .for_each(|x| {
-            if let Ok(p) = x {
-                println!("{}", p.print_info());
-            }
-            ready(())
+/// compress a stopped recording, keeping the job journal up to date
+///
+/// A `claim` of the start is released once the state is Compressing.
+pub async fn compress(
+    mx: Arc<Recorder>,
+    id: u64,
+    job: jobs::Compression,
+    claim: Option<StartClaim>,
+) -> anyhow::Result<()> {
+    let result = mx.pipeline.finish(mx.clone(), id, job, claim).await;
+    match &result {
+        Ok(Flow::Continue) => mx.jobs.update(id, JobState::Done, None),
+        Ok(Flow::Cancelled) => mx.jobs.update(id, JobState::Cancelled, None),
+        Err(e) => mx.jobs.update(id, JobState::Failed, Some(e.to_string())),
+    }
+    result.map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::PageQuery;
+    use serde_json::json;
+    use std::path::Path;
+
+    fn state(value: serde_json::Value) -> RecordingState {
+        serde_json::from_value(value).unwrap()
+    }
+
+    /// a directory of its own for the files of a test
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "record-screen-cancel-{}-{}",
+            std::process::id(),
+            name
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn touch(path: &Path) -> String {
+        std::fs::write(path, b"").unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    fn cancelled_entries(mx: &Recorder) -> Vec<HistoryEntry> {
+        let query = PageQuery {
+            state: Some("cancelled".to_string()),
+            ..Default::default()
+        };
+        mx.history.page(&query).unwrap().entries
+    }
+
+    fn started(file: &str) -> RecordingState {
+        state(json!({
+            "type": "Started",
+            "process_id": 4242,
+            "file": file,
+            "started_at": Local::now(),
+            "command": ["ffmpeg", "-i", ":0"],
+            "options": { "owner": "alice" },
+        }))
+    }
+
+    #[tokio::test]
+    async fn a_countdown_goes_back_to_waiting() {
+        let mx = Arc::new(Recorder::new());
+        mx.set(state(
+            json!({ "type": "Countdown", "start_at": Local::now() }),
+        ))
+        .await;
+        let next = cancel(mx.clone(), None).await.unwrap();
+        assert!(matches!(next, RecordingState::Waiting));
+        assert!(matches!(*mx.lock().await, RecordingState::Waiting));
+        assert!(cancelled_entries(&mx).is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_capture_is_removed_with_its_sidecars() {
+        let dir = dir("started");
+        let file = touch(&dir.join("capture.mkv"));
+        let sidecar = touch(&latency::sidecar_path(&file));
+        let kept = touch(&dir.join("other.mkv"));
+        let mx = Arc::new(Recorder::new());
+        mx.set(started(&file)).await;
+        let next = cancel(mx.clone(), None).await.unwrap();
+        assert!(matches!(next, RecordingState::Waiting));
+        assert!(!Path::new(&file).exists());
+        assert!(!Path::new(&sidecar).exists());
+        assert!(Path::new(&kept).exists());
+        let entries = cancelled_entries(&mx);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].owner.as_deref(), Some("alice"));
+        assert_eq!(entries[0].file, None);
+        assert_eq!(entries[0].commands, vec![vec!["ffmpeg", "-i", ":0"]]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_paused_capture_is_cancelled_as_a_started_one() {
+        let dir = dir("paused");
+        let file = touch(&dir.join("capture.mkv"));
+        let mx = Arc::new(Recorder::new());
+        mx.set(RecordingState::Paused {
+            process_id: 4242,
+            file: file.clone(),
+            at: Local::now(),
+            elapsed_ms: 1000,
+            resumed: false,
+            recording: Box::new(started(&file)),
         })
         .await;
+        let next = cancel(mx.clone(), None).await.unwrap();
+        assert!(matches!(next, RecordingState::Waiting));
+        assert!(!Path::new(&file).exists());
+        assert_eq!(cancelled_entries(&mx).len(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 
-    *mx.clone().lock().await = RecordingState::Done {
-        file: output.clone(),
-    };
-    // remove local "input" file, ignore error
-    let _ = std::fs::remove_file(input);
+    #[tokio::test]
+    async fn a_compression_leaves_the_raw_capture() {
+        let dir = dir("compressing");
+        let input = touch(&dir.join("capture.mkv"));
+        let output = touch(&dir.join("capture.compressed.mp4"));
+        let mx = Arc::new(Recorder::new());
+        mx.set(state(json!({
+            "type": "Compressing",
+            "process_id": 4243,
+            "input": input,
+            "output": output,
+            "encoder": { "content": "Screen", "codec": "libx264", "options": [] },
+        })))
+        .await;
+        let next = cancel(mx.clone(), None).await.unwrap();
+        match next {
+            RecordingState::Done { file, durable, .. } => {
+                assert_eq!(file, input);
+                assert!(!durable);
+            }
+            next => panic!("cancelled into {}", next.name()),
+        }
+        assert!(Path::new(&input).exists());
+        assert!(!Path::new(&output).exists());
+        assert_eq!(
+            cancelled_entries(&mx)[0].file.as_deref(),
+            Some(input.as_str())
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 
-    println!("{} {}", "done".green(), output.yellow());
-    Ok(())
+    #[tokio::test]
+    async fn nothing_is_cancelled_while_idle() {
+        let mx = Arc::new(Recorder::new());
+        let e = cancel(mx.clone(), None).await.unwrap_err();
+        assert_eq!(e.to_string(), "cannot cancel while Waiting");
+        mx.set(state(
+            json!({ "type": "Stopping", "process_id": 1, "file": "a.mkv" }),
+        ))
+        .await;
+        assert!(cancel(mx.clone(), None).await.is_err());
+        assert!(matches!(*mx.lock().await, RecordingState::Stopping { .. }));
+        assert!(cancelled_entries(&mx).is_empty());
+    }
+
+    #[test]
+    fn a_timeline_without_gaps_is_the_wall_clock() {
+        let mut timeline = RecordingTimeline::started();
+        assert_eq!(timeline.content_at(1_500), 1_500);
+        assert_eq!(timeline.wall_at(1_500), 1_500);
+        assert_eq!(timeline.gaps_ms(1_500), 0);
+        assert_eq!(timeline.recorded_ms(), None);
+        // a rollover starts a span right where the last one ended
+        timeline.resume(5_000);
+        assert_eq!(timeline.content_at(6_000), 6_000);
+        assert_eq!(timeline.gaps_ms(6_000), 0);
+        timeline.end(8_000);
+        assert_eq!(timeline.recorded_ms(), Some(8_000));
+    }
+
+    #[test]
+    fn a_pause_is_a_gap_of_the_timeline() {
+        let mut timeline = RecordingTimeline::started();
+        timeline.end(10_000);
+        assert_eq!(timeline.recorded_ms(), Some(10_000));
+        // the wall clock goes on while paused, the content doesn't
+        assert_eq!(timeline.content_at(12_000), 10_000);
+        timeline.resume(15_000);
+        assert_eq!(timeline.recorded_ms(), None);
+        assert_eq!(timeline.content_at(9_000), 9_000);
+        assert_eq!(timeline.content_at(12_000), 10_000);
+        assert_eq!(timeline.content_at(16_000), 11_000);
+        assert_eq!(timeline.gaps_ms(16_000), 5_000);
+        // a position of the content at the gap is the start of the next span
+        assert_eq!(timeline.wall_at(9_000), 9_000);
+        assert_eq!(timeline.wall_at(10_000), 15_000);
+        assert_eq!(timeline.wall_at(11_000), 16_000);
+        timeline.end(20_000);
+        assert_eq!(timeline.recorded_ms(), Some(15_000));
+        assert_eq!(timeline.content_at(25_000), 15_000);
+    }
+
+    #[test]
+    fn the_markers_are_placed_in_the_content() {
+        let mut timeline = RecordingTimeline::started();
+        let before = timeline.marker(4_000, "before");
+        timeline.end(10_000);
+        let paused = timeline.marker(12_000, "while paused");
+        timeline.resume(15_000);
+        let after = timeline.marker(20_000, "after");
+        assert_eq!(before.content_ms, Some(4_000));
+        assert_eq!(paused.content_ms, Some(10_000));
+        assert_eq!((after.at_ms, after.content_ms), (20_000, Some(15_000)));
+        assert_eq!(after.label, "after");
+    }
+
+    #[test]
+    fn a_timeline_tolerates_the_clock_going_back() {
+        let mut timeline = RecordingTimeline::started();
+        timeline.end(10_000);
+        // ended once only
+        timeline.end(12_000);
+        assert_eq!(timeline.recorded_ms(), Some(10_000));
+        timeline.resume(20_000);
+        timeline.end(19_000);
+        assert_eq!(timeline.spans.last().unwrap().ended_ms, Some(20_000));
+        timeline.resume(18_000);
+        assert_eq!(timeline.spans.last().unwrap().wall_ms, 20_000);
+        assert_eq!(timeline.spans.len(), 3);
+    }
 }
@@ -0,0 +1,98 @@
+//! Shutting the server down without losing its recording
+//!
+//! SIGTERM, as `systemctl stop` sends it, or SIGINT stop the running recording before the server
+//! exits: the capture is interrupted and finishes its file, which plays, and the pipeline runs as
+//! for any other stop. The compression is skipped unless the server is told to compress on the
+//! shutdown, to keep it quick; the raw capture is the result then. A stop or a compression that
+//! was running already is waited for. [Config::grace] bounds all of it: the children still
+//! running past it are killed, and a compression killed that way is restarted from the journal
+//! of the jobs by the next server. The server then stops taking requests and exits.
+use crate::service::{stop_for, Recorder, RecordingState, StopReason};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::*;
+
+/// how often the state is looked at while a stop or a compression finishes
+const POLL: Duration = Duration::from_millis(200);
+
+/// How the server shuts down
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// how long the recording may take to finish
+    pub grace: Duration,
+    /// compress the recording stopped by the shutdown rather than keep the raw capture
+    pub compress: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            grace: Duration::from_secs(30),
+            compress: false,
+        }
+    }
+}
+
+/// wait for SIGTERM or SIGINT
+pub async fn requested() -> std::io::Result<()> {
+    let mut term = signal(SignalKind::terminate())?;
+    let mut int = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = term.recv() => info!("SIGTERM: shutting down"),
+        _ = int.recv() => info!("SIGINT: shutting down"),
+    }
+    Ok(())
+}
+
+/// finish the recording within the grace, then kill the children left
+pub async fn finish(mx: Arc<Recorder>, config: &Config) {
+    if !config.compress {
+        keep_uncompressed(&mx).await;
+    }
+    if tokio::time::timeout(config.grace, finish_recording(mx.clone()))
+        .await
+        .is_err()
+    {
+        warn!("the recording didn't finish within {:?}", config.grace);
+    }
+    let killed = mx.children.kill_all();
+    if !killed.is_empty() {
+        let roles: Vec<_> = killed.iter().map(|child| child.role).collect();
+        warn!("killed {:?} at the shutdown", roles);
+    }
+}
+
+/// have the running recording skip its compression when it stops
+async fn keep_uncompressed(mx: &Recorder) {
+    let mut state = mx.lock().await;
+    let RecordingState::Started { options, .. } = state.recording_mut() else {
+        return;
+    };
+    options.compress = Some(false);
+    let updated = state.clone();
+    mx.replace(&mut state, updated);
+}
+
+async fn finish_recording(mx: Arc<Recorder>) {
+    let running = matches!(
+        *mx.lock().await,
+        RecordingState::Countdown { .. }
+            | RecordingState::Started { .. }
+            | RecordingState::Paused { .. }
+    );
+    if running {
+        if let Err(e) = stop_for(mx.clone(), Some(StopReason::Shutdown)).await {
+            warn!("cannot stop the recording at the shutdown: {}", e);
+        }
+    }
+    // a stop or a compression that was running already
+    while matches!(
+        *mx.lock().await,
+        RecordingState::Stopping { .. }
+            | RecordingState::Compressing { .. }
+            | RecordingState::Uploading { .. }
+    ) {
+        tokio::time::sleep(POLL).await;
+    }
+}
@@ -0,0 +1,130 @@
+//! Control of the server with unix signals, for integrations that can only run a command
+//!
+//! - `SIGUSR1` toggles the recording: starts it with the default options of the server,
+//!   or stops the one that is running
+//! - `SIGUSR2` drops a marker into the running recording
+//! - `SIGHUP` reopens the log file, after logrotate moved it
+//!
+//! `SIGTERM` and `SIGINT` shut the server down, see [crate::shutdown].
+//!
+//! Both go through the same service functions as the HTTP endpoints. Signals that make
+//! no sense in the current state are logged and ignored.
+use crate::service::*;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::*;
+
+/// handle the signals for as long as the server runs
+pub async fn listen(mx: Arc<Recorder>, defaults: RecordingOptions) -> anyhow::Result<()> {
+    let mut usr1 = signal(SignalKind::user_defined1())?;
+    let mut usr2 = signal(SignalKind::user_defined2())?;
+    let mut hup = signal(SignalKind::hangup())?;
+    loop {
+        tokio::select! {
+            _ = usr1.recv() => toggle(mx.clone(), &defaults).await,
+            _ = usr2.recv() => match add_marker(mx.clone(), "SIGUSR2").await {
+                Ok(marker) => info!("SIGUSR2: marker at {}ms", marker.at_ms),
+                Err(e) => info!("SIGUSR2 ignored: {}", e),
+            },
+            _ = hup.recv() => {
+                crate::logging::reopen();
+                info!("SIGHUP: reopening the log file");
+            }
+        }
+    }
+}
+
+async fn toggle(mx: Arc<Recorder>, defaults: &RecordingOptions) {
+    let current = mx.lock().await.clone();
+    match current {
+        RecordingState::Waiting
+        | RecordingState::Done { .. }
+        | RecordingState::Failed { .. }
+        | RecordingState::Cancelled { .. } => {
+            info!("SIGUSR1: starting recording");
+            let opt = defaults.clone();
+            tokio::spawn(async move {
+                if let Err(e) = start(mx, opt).await {
+                    warn!("SIGUSR1 start failed: {}", e);
+                }
+            });
+        }
+        RecordingState::Countdown { .. }
+        | RecordingState::Started { .. }
+        | RecordingState::Paused { .. } => {
+            info!("SIGUSR1: stopping recording");
+            tokio::spawn(async move {
+                if let Err(e) = stop(mx).await {
+                    warn!("SIGUSR1 stop failed: {}", e);
+                }
+            });
+        }
+        _ => info!("SIGUSR1 ignored while {}", current.name()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::signal::{raise, Signal};
+    use std::time::Duration;
+
+    /// wait for the state to be as `until` wants it
+    async fn until(mx: &Recorder, until: impl Fn(&RecordingState) -> bool) -> RecordingState {
+        for _ in 0..100 {
+            let state = mx.lock().await.clone();
+            if until(&state) {
+                return state;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("still {}", mx.lock().await.name());
+    }
+
+    fn markers(state: &RecordingState) -> Vec<&str> {
+        match state {
+            RecordingState::Started { markers, .. } => {
+                markers.iter().map(|m| m.label.as_str()).collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    // the only test raising signals: the listeners are those of the whole process
+    #[tokio::test]
+    async fn the_signals_of_the_process_drive_the_recording() {
+        let mx = Arc::new(Recorder::new());
+        tokio::spawn(listen(mx.clone(), RecordingOptions::default()));
+        // the listeners are installed by the task
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // nothing to mark while waiting
+        raise(Signal::SIGUSR2).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(matches!(*mx.lock().await, RecordingState::Waiting));
+
+        let mut capture = tokio::process::Command::new("sleep")
+            .arg("30")
+            .spawn()
+            .unwrap();
+        let started = serde_json::from_value(serde_json::json!({
+            "type": "Started",
+            "process_id": capture.id().unwrap(),
+            "file": "signalled.mkv",
+            "started_at": chrono::Local::now(),
+            // stopped without a compression
+            "options": { "stream_only": true },
+        }))
+        .unwrap();
+        mx.set(started).await;
+        raise(Signal::SIGUSR2).unwrap();
+        let marked = until(&mx, |state| !markers(state).is_empty()).await;
+        assert_eq!(markers(&marked), ["SIGUSR2"]);
+
+        raise(Signal::SIGUSR1).unwrap();
+        until(&mx, |state| matches!(state, RecordingState::Waiting)).await;
+        let history = mx.history.page(&Default::default()).unwrap();
+        assert_eq!(history.entries[0].state, "streamed");
+        capture.kill().await.unwrap();
+    }
+}
@@ -0,0 +1,90 @@
+//! A slate opening the compressed recording, naming it
+//!
+//! With `slate`, the compression puts [SECONDS] of a black frame ahead of the capture, with the
+//! id of the recording, when it started, the host and the resolution drawn on it. The slate is a
+//! lavfi `color` source of the filtergraph of the compression, joined to the capture by the
+//! `concat` filter, so both are encoded once with the same settings and the capture itself stays
+//! as it was. The audio is delayed by the slate, and so are the markers of the result.
+use crate::ffmpeg::escape_drawtext;
+use crate::probe;
+use anyhow::Context;
+use chrono::{DateTime, Local};
+use std::path::Path;
+
+/// how long the slate is shown
+pub const SECONDS: u64 = 2;
+/// how much the slate delays everything of the capture
+pub const MS: u64 = SECONDS * 1000;
+
+/// What the slate of a recording shows, and what it has to match
+#[derive(Debug, Clone)]
+pub struct Slate {
+    /// the id of its compression job, growing with every recording
+    pub id: u64,
+    pub started_at: DateTime<Local>,
+    pub hostname: String,
+    pub width: u32,
+    pub height: u32,
+    /// of the capture, `25/1`
+    pub frame_rate: String,
+    /// the capture has an audio stream of its own
+    pub audio: bool,
+}
+
+/// the name of this machine
+pub fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_string())
+        .unwrap_or_else(|_| "unknown host".to_string())
+}
+
+impl Slate {
+    /// the slate of the recording `id`, matching its capture
+    pub async fn of(id: u64, started_at: DateTime<Local>, capture: &Path) -> anyhow::Result<Self> {
+        let info = probe::probe(capture).await?;
+        let (width, height) = info
+            .width
+            .zip(info.height)
+            .context("the capture has no video stream")?;
+        Ok(Self {
+            id,
+            started_at,
+            hostname: hostname(),
+            width,
+            height,
+            frame_rate: info.frame_rate.unwrap_or_else(|| "25/1".to_string()),
+            audio: info.audio_codec.is_some(),
+        })
+    }
+
+    /// the lines drawn, one below the other
+    pub fn lines(&self) -> Vec<String> {
+        vec![
+            format!("recording {}", self.id),
+            self.started_at.format("%Y-%m-%d %H:%M:%S %:z").to_string(),
+            self.hostname.clone(),
+            format!("{}x{}", self.width, self.height),
+        ]
+    }
+
+    /// the filtergraph putting the slate ahead of input 0, with its video run through `filters`
+    /// in `[v]`; the audio given as `[0:a]` or the label of a mix comes delayed in `[a]`
+    pub fn graph(&self, audio: Option<&str>, filters: Option<&str>) -> String {
+        let mut graph = format!(
+            "color=c=black:s={}x{}:r={}:d={},\
+             drawtext=text={}:fontsize=h/16:fontcolor=white:line_spacing=h/32\
+             :x=(w-text_w)/2:y=(h-text_h)/2,setsar=1[slate];\
+             [0:v]setsar=1[capture];[slate][capture]concat=n=2:v=1:a=0{}[v]",
+            self.width,
+            self.height,
+            self.frame_rate,
+            SECONDS,
+            escape_drawtext(&self.lines().join("\n")),
+            filters.map_or(String::new(), |filters| format!(",{}", filters)),
+        );
+        if let Some(audio) = audio {
+            graph += &format!(";{}adelay={}:all=1[a]", audio, MS);
+        }
+        graph
+    }
+}
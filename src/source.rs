@@ -0,0 +1,142 @@
+//! What is being captured: the local screen or a network stream
+//!
+//! A stream keeps the lifecycle of a screen recording: it's captured into the raw master,
+//! then compressed when stopped. The stream is probed first, so that an unreachable source
+//! fails the start within [STARTUP_TIMEOUT], and so that h264/aac streams can be copied
+//! into the master as they are.
+use crate::probe::{self, MediaInfo};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use thiserror::Error;
+
+/// schemes of the stream URLs that can be recorded
+pub const SCHEMES: &[&str] = &["rtsp", "rtsps", "http", "https", "srt"];
+
+/// how long a stream may take to answer the probe
+pub const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Transport of the RTSP streams
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum RtspTransport {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+impl RtspTransport {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RtspTransport::Tcp => "tcp",
+            RtspTransport::Udp => "udp",
+        }
+    }
+}
+
+/// Where the recording comes from
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum CaptureSource {
+    /// the local X11 display
+    #[default]
+    Screen,
+    /// any input ffmpeg can open by URL
+    Url {
+        url: String,
+        /// format of the input, when ffmpeg can't guess it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        input_format: Option<String>,
+        /// defaults to tcp, udp tends to drop packets
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        rtsp_transport: Option<RtspTransport>,
+    },
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("invalid source url: {0}")]
+    InvalidUrl(String),
+    #[error("source is unreachable: {0}")]
+    Unreachable(String),
+}
+
+impl CaptureSource {
+    /// check the source can be recorded at all
+    pub fn validate(&self) -> Result<(), Error> {
+        let CaptureSource::Url { url, .. } = self else {
+            return Ok(());
+        };
+        let Some((scheme, rest)) = url.split_once("://") else {
+            return Err(Error::InvalidUrl("no scheme".to_string()));
+        };
+        if !SCHEMES.contains(&scheme.to_ascii_lowercase().as_str()) {
+            return Err(Error::InvalidUrl(format!(
+                "scheme {} is not one of {}",
+                scheme,
+                SCHEMES.join(", ")
+            )));
+        }
+        if rest.is_empty() {
+            return Err(Error::InvalidUrl("no host".to_string()));
+        }
+        Ok(())
+    }
+
+    /// ffmpeg options of the stream input, the `-i` included
+    pub fn input_options(&self) -> Vec<(String, String)> {
+        let CaptureSource::Url {
+            url,
+            input_format,
+            rtsp_transport,
+        } = self
+        else {
+            return vec![];
+        };
+        let scheme = url
+            .split_once("://")
+            .map(|(s, _)| s.to_ascii_lowercase())
+            .unwrap_or_default();
+        let mut options = vec![];
+        match scheme.as_str() {
+            "rtsp" | "rtsps" => options.push((
+                "rtsp_transport".to_string(),
+                rtsp_transport.unwrap_or_default().as_str().to_string(),
+            )),
+            // live HTTP streams drop their connection now and then
+            "http" | "https" => {
+                options.push(("reconnect".to_string(), "1".to_string()));
+                options.push(("reconnect_streamed".to_string(), "1".to_string()));
+            }
+            _ => {}
+        }
+        if let Some(format) = input_format {
+            options.push(("f".to_string(), format.clone()));
+        }
+        options.push(("i".to_string(), url.clone()));
+        options
+    }
+
+    /// probe the stream, failing when it doesn't answer in time
+    pub async fn probe(&self) -> Result<MediaInfo, Error> {
+        let args: Vec<String> = self
+            .input_options()
+            .into_iter()
+            .filter(|(key, _)| key != "reconnect" && key != "reconnect_streamed")
+            .flat_map(|(key, value)| [format!("-{}", key), value])
+            .collect();
+        match tokio::time::timeout(STARTUP_TIMEOUT, probe::probe_args(&args)).await {
+            Ok(Ok(info)) => Ok(info),
+            Ok(Err(e)) => Err(Error::Unreachable(e.to_string())),
+            Err(_) => Err(Error::Unreachable(format!(
+                "no answer in {}s",
+                STARTUP_TIMEOUT.as_secs()
+            ))),
+        }
+    }
+}
+
+/// whether the stream can go into the mp4 master without transcoding
+pub fn can_copy(info: &MediaInfo) -> bool {
+    info.video_codec.as_deref() == Some("h264")
+        && info.audio_codec.as_deref().is_none_or(|c| c == "aac")
+}
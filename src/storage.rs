@@ -0,0 +1,470 @@
+//! Where the finished recordings are kept
+//!
+//! The library of the finished recordings, their download and their feeds, goes through a
+//! [StorageBackend] rather than the filesystem. The server keeps them in [LocalStorage], the
+//! output directory; a library user gives another backend to [Recorder::with_storage]. The
+//! captures, their segments and the compression always work in the local output directory, as
+//! scratch space. An object is named by the file name of the recording; its manifest and its
+//! sidecars are the objects named after it, `<name>.sha256.json` and so on, as the files next to
+//! it are locally.
+//!
+//! What needs a recording as a local file, probing it or making a still of it, asks
+//! [StorageBackend::local_path]: a backend that is not local answers it by fetching the object
+//! into a local cache first.
+//!
+//! [Recorder::with_storage]: crate::service::Recorder::with_storage
+use crate::recordings;
+use axum::body::Bytes;
+use chrono::{DateTime, Local};
+use futures::future::BoxFuture;
+use futures::Stream;
+use serde::Serialize;
+use std::io::{Error, ErrorKind, Result, SeekFrom};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
+
+/// bytes read at once from an object
+const CHUNK: usize = 64 * 1024;
+
+/// The bytes of an object, read as they are needed
+pub type Reader = Pin<Box<dyn AsyncRead + Send>>;
+
+/// An object of a backend
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ObjectInfo {
+    pub name: String,
+    pub size: u64,
+    /// when the backend knows
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created: Option<DateTime<Local>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modified: Option<DateTime<Local>>,
+}
+
+/// Keeps the finished recordings and their sidecars, by name
+///
+/// A name that can't be an object, with a `/` or starting with a `.`, is refused with
+/// [ErrorKind::InvalidInput].
+pub trait StorageBackend: Send + Sync {
+    /// write the object from `body`, returning its size
+    fn put<'a>(&'a self, name: &'a str, body: Reader) -> BoxFuture<'a, Result<u64>>;
+
+    /// read the object, or the `range` of its bytes
+    fn get<'a>(&'a self, name: &'a str, range: Option<Range<u64>>)
+        -> BoxFuture<'a, Result<Reader>>;
+
+    /// the object, None when there is none of the name
+    fn stat<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Option<ObjectInfo>>>;
+
+    /// every object, sidecars included
+    fn list(&self) -> BoxFuture<'_, Result<Vec<ObjectInfo>>>;
+
+    fn delete<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<()>>;
+
+    fn rename<'a>(&'a self, from: &'a str, to: &'a str) -> BoxFuture<'a, Result<()>>;
+
+    /// a local file with the bytes of the object, for what can only read files
+    fn local_path<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<PathBuf>>;
+}
+
+/// The backend of a recorder, [LocalStorage] by default
+#[derive(Clone)]
+pub struct Storage(Arc<dyn StorageBackend>);
+
+impl Storage {
+    pub fn new(backend: impl StorageBackend + 'static) -> Self {
+        Self(Arc::new(backend))
+    }
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self::new(LocalStorage::default())
+    }
+}
+
+impl std::ops::Deref for Storage {
+    type Target = dyn StorageBackend;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+/// The recordings as files of a directory, the output directory when none is given
+#[derive(Debug, Clone, Default)]
+pub struct LocalStorage {
+    dir: Option<PathBuf>,
+}
+
+impl LocalStorage {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Self { dir }
+    }
+
+    fn dir(&self) -> Result<PathBuf> {
+        match &self.dir {
+            Some(dir) => Ok(dir.clone()),
+            None => recordings::output_dir().map_err(|e| Error::new(ErrorKind::NotFound, e)),
+        }
+    }
+
+    /// the file of the object, a content addressed one in its own directory
+    fn path(&self, name: &str) -> Result<PathBuf> {
+        recordings::resolve(&self.dir()?, name).map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+    }
+}
+
+fn modified(meta: &std::fs::Metadata) -> Option<DateTime<Local>> {
+    meta.modified().ok().map(DateTime::from)
+}
+
+/// the birth of the file, on the filesystems that keep it
+fn created(meta: &std::fs::Metadata) -> Option<DateTime<Local>> {
+    meta.created().ok().map(DateTime::from)
+}
+
+impl StorageBackend for LocalStorage {
+    fn put<'a>(&'a self, name: &'a str, mut body: Reader) -> BoxFuture<'a, Result<u64>> {
+        Box::pin(async move {
+            let path = self.path(name)?;
+            let mut partial = path.as_os_str().to_owned();
+            partial.push(".part");
+            let partial = PathBuf::from(partial);
+            let mut file = tokio::fs::File::create(&partial).await?;
+            let size = tokio::io::copy(&mut body, &mut file).await?;
+            file.sync_all().await?;
+            tokio::fs::rename(&partial, &path).await?;
+            Ok(size)
+        })
+    }
+
+    fn get<'a>(
+        &'a self,
+        name: &'a str,
+        range: Option<Range<u64>>,
+    ) -> BoxFuture<'a, Result<Reader>> {
+        Box::pin(async move {
+            let mut file = tokio::fs::File::open(self.path(name)?).await?;
+            let reader: Reader = match range {
+                None => Box::pin(file),
+                Some(range) => {
+                    file.seek(SeekFrom::Start(range.start)).await?;
+                    Box::pin(file.take(range.end.saturating_sub(range.start)))
+                }
+            };
+            Ok(reader)
+        })
+    }
+
+    fn stat<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Option<ObjectInfo>>> {
+        Box::pin(async move {
+            match tokio::fs::metadata(self.path(name)?).await {
+                Ok(meta) if meta.is_file() => Ok(Some(ObjectInfo {
+                    name: name.to_string(),
+                    size: meta.len(),
+                    created: created(&meta),
+                    modified: modified(&meta),
+                })),
+                Ok(_) => Ok(None),
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e),
+            }
+        })
+    }
+
+    fn list(&self) -> BoxFuture<'_, Result<Vec<ObjectInfo>>> {
+        Box::pin(async move {
+            let mut objects = vec![];
+            let mut entries = tokio::fs::read_dir(self.dir()?).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let Ok(meta) = entry.metadata().await else {
+                    continue;
+                };
+                // the history, the journal and the partial files are not objects
+                if name.starts_with('.') || !meta.is_file() || name.ends_with(".part") {
+                    continue;
+                }
+                objects.push(ObjectInfo {
+                    name,
+                    size: meta.len(),
+                    created: created(&meta),
+                    modified: modified(&meta),
+                });
+            }
+            objects.sort_by(|a, b| a.name.cmp(&b.name));
+            Ok(objects)
+        })
+    }
+
+    fn delete<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move { tokio::fs::remove_file(self.path(name)?).await })
+    }
+
+    fn rename<'a>(&'a self, from: &'a str, to: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move { tokio::fs::rename(self.path(from)?, self.path(to)?).await })
+    }
+
+    fn local_path<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<PathBuf>> {
+        Box::pin(async move { self.path(name) })
+    }
+}
+
+/// The objects in memory, a backend that is not local for the tests
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct MemoryStorage {
+    objects: std::sync::Mutex<std::collections::BTreeMap<String, Vec<u8>>>,
+}
+
+#[cfg(test)]
+impl MemoryStorage {
+    fn valid(name: &str) -> Result<()> {
+        if name.is_empty() || name.starts_with('.') || name.contains('/') || name.contains('\\') {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "invalid recording name",
+            ));
+        }
+        Ok(())
+    }
+
+    fn bytes(&self, name: &str) -> Result<Vec<u8>> {
+        Self::valid(name)?;
+        let objects = self.objects.lock().unwrap();
+        let bytes = objects.get(name).ok_or(ErrorKind::NotFound)?;
+        Ok(bytes.clone())
+    }
+
+    fn info(name: &str, bytes: &[u8]) -> ObjectInfo {
+        ObjectInfo {
+            name: name.to_string(),
+            size: bytes.len() as u64,
+            created: None,
+            modified: None,
+        }
+    }
+}
+
+#[cfg(test)]
+impl StorageBackend for MemoryStorage {
+    fn put<'a>(&'a self, name: &'a str, mut body: Reader) -> BoxFuture<'a, Result<u64>> {
+        Box::pin(async move {
+            Self::valid(name)?;
+            let mut bytes = vec![];
+            body.read_to_end(&mut bytes).await?;
+            let size = bytes.len() as u64;
+            self.objects.lock().unwrap().insert(name.to_string(), bytes);
+            Ok(size)
+        })
+    }
+
+    fn get<'a>(
+        &'a self,
+        name: &'a str,
+        range: Option<Range<u64>>,
+    ) -> BoxFuture<'a, Result<Reader>> {
+        Box::pin(async move {
+            let bytes = self.bytes(name)?;
+            let bytes = match range {
+                None => bytes,
+                Some(range) => {
+                    let end = (range.end as usize).min(bytes.len());
+                    bytes[(range.start as usize).min(end)..end].to_vec()
+                }
+            };
+            let reader: Reader = Box::pin(std::io::Cursor::new(bytes));
+            Ok(reader)
+        })
+    }
+
+    fn stat<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Option<ObjectInfo>>> {
+        Box::pin(async move {
+            Self::valid(name)?;
+            let objects = self.objects.lock().unwrap();
+            Ok(objects.get(name).map(|bytes| Self::info(name, bytes)))
+        })
+    }
+
+    fn list(&self) -> BoxFuture<'_, Result<Vec<ObjectInfo>>> {
+        Box::pin(async move {
+            let objects = self.objects.lock().unwrap();
+            Ok(objects
+                .iter()
+                .map(|(name, bytes)| Self::info(name, bytes))
+                .collect())
+        })
+    }
+
+    fn delete<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            Self::valid(name)?;
+            let removed = self.objects.lock().unwrap().remove(name);
+            removed.map(drop).ok_or_else(|| ErrorKind::NotFound.into())
+        })
+    }
+
+    fn rename<'a>(&'a self, from: &'a str, to: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            Self::valid(from)?;
+            Self::valid(to)?;
+            let mut objects = self.objects.lock().unwrap();
+            let bytes = objects.remove(from).ok_or(ErrorKind::NotFound)?;
+            objects.insert(to.to_string(), bytes);
+            Ok(())
+        })
+    }
+
+    /// fetched into a cache of its own
+    fn local_path<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<PathBuf>> {
+        Box::pin(async move {
+            let bytes = self.bytes(name)?;
+            let cache = recordings::test_output_dir().join(format!("memory-{:p}", self));
+            tokio::fs::create_dir_all(&cache).await?;
+            let path = cache.join(name);
+            tokio::fs::write(&path, bytes).await?;
+            Ok(path)
+        })
+    }
+}
+
+/// the bytes of `reader` as a body, a chunk at a time
+pub fn stream(reader: Reader) -> impl Stream<Item = Result<Bytes>> {
+    futures::stream::try_unfold(reader, |mut reader| async move {
+        let mut buf = vec![0u8; CHUNK];
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.truncate(n);
+        Ok(Some((Bytes::from(buf), reader)))
+    })
+}
+
+/// the bytes a `Range` header asks for out of `size`
+///
+/// None for a header that is not a single range of bytes, which gets the whole object; an error
+/// for a range past the end.
+pub fn parse_range(header: &str, size: u64) -> Option<std::result::Result<Range<u64>, ()>> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    let range = match (start, end) {
+        ("", "") => return None,
+        // the last bytes
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            size.saturating_sub(suffix)..size
+        }
+        (start, "") => start.parse().ok()?..size,
+        (start, end) => {
+            let end: u64 = end.parse().ok()?;
+            start.parse().ok()?..(end + 1).min(size)
+        }
+    };
+    if range.start >= size || range.start >= range.end {
+        return Some(Err(()));
+    }
+    Some(Ok(range))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(bytes: &'static [u8]) -> Reader {
+        Box::pin(bytes)
+    }
+
+    async fn read(reader: Result<Reader>) -> Result<Vec<u8>> {
+        let mut bytes = vec![];
+        reader?.read_to_end(&mut bytes).await?;
+        Ok(bytes)
+    }
+
+    /// what can be seen of the backend through its operations, in order
+    async fn observe(backend: &dyn StorageBackend) -> Vec<String> {
+        let mut seen = vec![];
+        let size =
+            |info: Result<Option<ObjectInfo>>| format!("{:?}", info.map(|i| i.map(|i| i.size)));
+        let names = |list: Result<Vec<ObjectInfo>>| {
+            let list = list.unwrap();
+            format!(
+                "{:?}",
+                list.iter().map(|o| (&o.name, o.size)).collect::<Vec<_>>()
+            )
+        };
+        seen.push(format!(
+            "{:?}",
+            backend.put("a.mp4", body(b"hello world")).await
+        ));
+        seen.push(format!(
+            "{:?}",
+            backend.put("a.mp4.sha256.json", body(b"{}")).await
+        ));
+        seen.push(size(backend.stat("a.mp4").await));
+        seen.push(size(backend.stat("missing.mp4").await));
+        seen.push(format!(
+            "{:?}",
+            read(backend.get("a.mp4", None).await).await
+        ));
+        seen.push(format!(
+            "{:?}",
+            read(backend.get("a.mp4", Some(6..11)).await).await
+        ));
+        let missing = backend
+            .get("missing.mp4", None)
+            .await
+            .err()
+            .map(|e| e.kind());
+        seen.push(format!("{:?}", missing));
+        seen.push(names(backend.list().await));
+        seen.push(format!("{:?}", backend.rename("a.mp4", "b.mp4").await));
+        seen.push(size(backend.stat("a.mp4").await));
+        let local = backend.local_path("b.mp4").await.unwrap();
+        seen.push(format!("{:?}", std::fs::read(local)));
+        seen.push(format!("{:?}", backend.delete("b.mp4").await));
+        let deleted = backend.delete("b.mp4").await.err().map(|e| e.kind());
+        seen.push(format!("{:?}", deleted));
+        seen.push(names(backend.list().await));
+        for name in ["../escape.mp4", ".history.jsonl", ""] {
+            let refused = backend.put(name, body(b"")).await.err().map(|e| e.kind());
+            seen.push(format!("{:?}", refused));
+        }
+        seen
+    }
+
+    #[tokio::test]
+    async fn the_backends_behave_alike() {
+        let dir = recordings::test_output_dir().join("storage");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let local = observe(&LocalStorage::new(Some(dir))).await;
+        let memory = observe(&MemoryStorage::default()).await;
+        assert_eq!(local, memory);
+        assert_eq!(
+            local[4],
+            "Ok([104, 101, 108, 108, 111, 32, 119, 111, 114, 108, 100])"
+        );
+        assert_eq!(local[5], "Ok([119, 111, 114, 108, 100])");
+        assert_eq!(local[13], "[(\"a.mp4.sha256.json\", 2)]");
+    }
+
+    #[test]
+    fn the_ranges_are_parsed_against_the_size() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some(Ok(0..100)));
+        assert_eq!(parse_range("bytes=400-", 1000), Some(Ok(400..1000)));
+        assert_eq!(parse_range("bytes=-100", 1000), Some(Ok(900..1000)));
+        assert_eq!(parse_range("bytes=900-2000", 1000), Some(Ok(900..1000)));
+        assert_eq!(parse_range("bytes=1000-", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=0-1,5-9", 1000), None);
+        assert_eq!(parse_range("lines=0-1", 1000), None);
+    }
+}
@@ -0,0 +1,125 @@
+//! Starting the recordings of several machines at the same instant
+//!
+//! A start with `start_at` is resolved and checked at once, then held in
+//! [RecordingState::Countdown] until that wall-clock time. The wait is a tokio sleep until a
+//! monotonic deadline: the time left is read off the realtime clock once, when the countdown
+//! begins, so that a step of the realtime clock meanwhile doesn't move the start. The capture is
+//! spawned at the deadline; the instant it was, next to the one asked for, is kept in the state
+//! and in the `<name>.start.json` sidecar, to align the recordings of the machines afterwards.
+//!
+//! The clocks of the machines are not compared: every server only tells its own time and whether
+//! NTP synchronizes it.
+use crate::container;
+use crate::schema::{self, Versioned};
+use crate::service::{Recorder, RecordingState};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::process::Command;
+use tracing::*;
+
+/// how far ahead a start may be scheduled
+pub const MAX_AHEAD: Duration = Duration::from_secs(300);
+/// how often the countdown looks whether it was stopped
+const CHECK_EVERY: Duration = Duration::from_millis(250);
+
+/// The reply to a start with `start_at`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduledStart {
+    pub start_at: DateTime<Local>,
+    /// the wall clock of the server when it accepted the start
+    pub server_time: DateTime<Local>,
+    /// whether NTP synchronizes that clock, None when it can't be told
+    pub clock_synchronized: Option<bool>,
+}
+
+/// When a synchronized start was asked for and when the capture was spawned
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StartSync {
+    pub intended: DateTime<Local>,
+    pub triggered: DateTime<Local>,
+    /// `triggered` minus `intended`
+    pub error_ms: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clock_synchronized: Option<bool>,
+}
+
+impl StartSync {
+    pub fn new(
+        intended: DateTime<Local>,
+        triggered: DateTime<Local>,
+        clock_synchronized: Option<bool>,
+    ) -> Self {
+        let error = triggered - intended;
+        Self {
+            intended,
+            triggered,
+            error_ms: error.num_microseconds().unwrap_or(i64::MAX) as f64 / 1000.0,
+            clock_synchronized,
+        }
+    }
+}
+
+impl Versioned for StartSync {
+    const KIND: &'static str = "start sidecars";
+    const VERSION: u32 = 1;
+    const MIGRATIONS: &'static [schema::Migration] = &[schema::unversioned];
+}
+
+/// why `start_at` can't be waited for, None when it can
+pub fn invalid(start_at: DateTime<Local>, now: DateTime<Local>) -> Option<String> {
+    match (start_at - now).to_std() {
+        Err(_) => Some("in the past".to_string()),
+        Ok(ahead) if ahead > MAX_AHEAD => Some(format!("more than {}s ahead", MAX_AHEAD.as_secs())),
+        Ok(_) => None,
+    }
+}
+
+/// whether NTP synchronizes the clock, as systemd tells
+pub async fn clock_synchronized() -> Option<bool> {
+    let output = Command::new("timedatectl")
+        .args(["show", "--property=NTPSynchronized", "--value"])
+        .output()
+        .await
+        .ok()?;
+    match String::from_utf8_lossy(&output.stdout).trim() {
+        "yes" => Some(true),
+        "no" => Some(false),
+        _ => None,
+    }
+}
+
+/// the monotonic instant `start_at` is at, by the realtime clock now
+pub fn deadline(start_at: DateTime<Local>) -> tokio::time::Instant {
+    let left = (start_at - Local::now()).to_std().unwrap_or_default();
+    tokio::time::Instant::now() + left
+}
+
+/// hold the recording in the countdown until `start_at`, or until it is stopped
+pub async fn countdown(mx: &Recorder, state: RecordingState, start_at: DateTime<Local>) {
+    let deadline = deadline(start_at);
+    mx.set(state).await;
+    info!("capture starts at {}", start_at.to_rfc3339());
+    loop {
+        let next = deadline.min(tokio::time::Instant::now() + CHECK_EVERY);
+        tokio::time::sleep_until(next).await;
+        if next == deadline || !matches!(*mx.lock().await, RecordingState::Countdown { .. }) {
+            return;
+        }
+    }
+}
+
+/// path of the sidecar of a synchronized capture
+pub fn sidecar_path(capture: &str) -> PathBuf {
+    PathBuf::from(format!("{}.start.json", container::base(capture)))
+}
+
+/// write the sidecar next to the capture
+pub async fn write_sidecar(capture: &str, sync: &StartSync) {
+    let path = sidecar_path(capture);
+    let json = schema::to_vec_pretty(sync).expect("start sync json");
+    if let Err(e) = tokio::fs::write(&path, json).await {
+        warn!("cannot write {}: {}", path.display(), e);
+    }
+}
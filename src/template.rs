@@ -0,0 +1,420 @@
+//! Templates of the names and the arguments made from a recording, `{date}T{time}-{owner}`
+//!
+//! A template is text with [Token]s in braces, `{{` and `}}` standing for the braces themselves.
+//! It is parsed when the server reads its flags, so that a token it doesn't know is an error then
+//! rather than an empty value later. What a value may hold depends on where it goes, the
+//! [Context] of the rendering: in a file name a few characters are safe, a slash would go to
+//! another directory and a leading dash be taken for an option, in a URL it must be
+//! percent-encoded, while a program argument is passed as an argv element of its own and
+//! never through a shell, so it goes as it is.
+use chrono::{DateTime, Local};
+use std::fmt;
+use std::str::FromStr;
+
+/// A value a template may hold
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Token {
+    /// the day the recording started, `2024-05-01`
+    Date,
+    /// the minute it started, `14-30`
+    Time,
+    Hostname,
+    /// who the recording is for, empty without one
+    Owner,
+    /// the name of the recorded file, without its directory
+    Filename,
+    /// the id of the recording in the log, `20240501T143000.000`, see
+    /// [crate::pipeline::recording_id]
+    RecordingId,
+    /// the encoder profile of the recording, empty without one
+    Profile,
+}
+
+impl Token {
+    pub const ALL: &'static [Token] = &[
+        Token::Date,
+        Token::Time,
+        Token::Hostname,
+        Token::Owner,
+        Token::Filename,
+        Token::RecordingId,
+        Token::Profile,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Date => "date",
+            Self::Time => "time",
+            Self::Hostname => "hostname",
+            Self::Owner => "owner",
+            Self::Filename => "filename",
+            Self::RecordingId => "recording_id",
+            Self::Profile => "profile",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Token(Token),
+}
+
+/// A parsed template
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    source: String,
+    parts: Vec<Part>,
+}
+
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = vec![];
+        let mut text = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(format!("the token {{{} is not closed", name)),
+                        }
+                    }
+                    let token = Token::ALL
+                        .iter()
+                        .find(|t| t.name() == name)
+                        .ok_or_else(|| {
+                            let known: Vec<_> = Token::ALL.iter().map(Token::name).collect();
+                            format!(
+                                "unknown token {{{}}}, expected one of {}",
+                                name,
+                                known.join(", ")
+                            )
+                        })?;
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(Part::Token(*token));
+                }
+                '}' => return Err("a '}' that closes nothing, '}}' stands for one".to_string()),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(Self {
+            source: s.to_string(),
+            parts,
+        })
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+/// Where a rendered template goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Context {
+    /// the name of a file, the values only hold letters, digits, `.`, `_` and `-` of ASCII, the
+    /// rest is a `_`
+    FileName,
+    /// an argument of a program, as it is
+    Argument,
+    /// a part of a URL, the values percent-encoded
+    Url,
+}
+
+/// The values of a recording
+#[derive(Debug, Clone)]
+pub struct Values {
+    pub started_at: DateTime<Local>,
+    pub hostname: String,
+    pub owner: Option<String>,
+    pub filename: Option<String>,
+    pub profile: Option<String>,
+}
+
+impl Values {
+    pub fn new(started_at: DateTime<Local>, owner: Option<&str>) -> Self {
+        Self {
+            started_at,
+            hostname: crate::slate::hostname(),
+            owner: owner.map(str::to_string),
+            filename: None,
+            profile: None,
+        }
+    }
+
+    /// the values of a recording with the encoder profile
+    pub fn with_profile(mut self, profile: Option<&str>) -> Self {
+        self.profile = profile.map(str::to_string);
+        self
+    }
+
+    /// the values once the file is known, its name taken from its path
+    pub fn with_file(mut self, path: &str) -> Self {
+        self.filename = std::path::Path::new(path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string());
+        self
+    }
+
+    fn get(&self, token: Token) -> Option<String> {
+        match token {
+            Token::Date => Some(self.started_at.format("%Y-%m-%d").to_string()),
+            Token::Time => Some(self.started_at.format("%H-%M").to_string()),
+            Token::Hostname => Some(self.hostname.clone()),
+            Token::Owner => Some(self.owner.clone().unwrap_or_default()),
+            Token::Filename => self.filename.clone(),
+            Token::RecordingId => Some(crate::pipeline::recording_id(self.started_at)),
+            Token::Profile => Some(self.profile.clone().unwrap_or_default()),
+        }
+    }
+}
+
+/// `value` fit for `context`
+fn encode(value: &str, context: Context) -> String {
+    match context {
+        Context::FileName => value
+            .chars()
+            .map(|c| match c {
+                'A'..='Z' | 'a'..='z' | '0'..='9' | '.' | '_' | '-' => c,
+                _ => '_',
+            })
+            .collect(),
+        Context::Argument => value.to_string(),
+        Context::Url => value
+            .bytes()
+            .map(|b| match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                    (b as char).to_string()
+                }
+                b => format!("%{:02X}", b),
+            })
+            .collect(),
+    }
+}
+
+impl Template {
+    /// the tokens of the template, in order
+    pub fn tokens(&self) -> impl Iterator<Item = Token> + '_ {
+        self.parts.iter().filter_map(|part| match part {
+            Part::Token(token) => Some(*token),
+            Part::Text(_) => None,
+        })
+    }
+
+    /// an error naming the first token that is not among `allowed`
+    pub fn only(self, allowed: &[Token]) -> Result<Self, String> {
+        let outside = self.tokens().find(|t| !allowed.contains(t));
+        match outside {
+            Some(token) => Err(format!("{{{}}} can't be used here", token.name())),
+            None => Ok(self),
+        }
+    }
+
+    /// the text of the template with the values of `values` encoded for `context`
+    pub fn render(&self, values: &Values, context: Context) -> Result<String, String> {
+        let mut rendered = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => rendered += text,
+                Part::Token(token) => {
+                    let value = values
+                        .get(*token)
+                        .ok_or_else(|| format!("no {{{}}} yet", token.name()))?;
+                    rendered += &encode(&value, context);
+                }
+            }
+        }
+        if context == Context::FileName {
+            // not hidden with the files of the state directory, nor the directory itself, nor
+            // taken for an option
+            if rendered.starts_with(['.', '-']) || rendered.is_empty() {
+                rendered.insert(0, '_');
+            }
+        }
+        Ok(rendered)
+    }
+}
+
+/// the name of the recordings when the server is not told
+pub const DEFAULT_FILE_NAME: &str = "{date}T{time}";
+
+/// the tokens a file name may hold, it is named before there is a file
+pub const FILE_NAME_TOKENS: &[Token] = &[
+    Token::Date,
+    Token::Time,
+    Token::Hostname,
+    Token::Owner,
+    Token::Profile,
+];
+
+/// the tokens a webhook URL may hold, it is rendered for states that have no file
+pub const WEBHOOK_TOKENS: &[Token] = &[
+    Token::RecordingId,
+    Token::Date,
+    Token::Time,
+    Token::Hostname,
+    Token::Owner,
+    Token::Profile,
+];
+
+/// a template of the name of the recordings, without its extension
+pub fn file_name(s: &str) -> Result<Template, String> {
+    if s.contains('/') {
+        return Err("a file name can't hold a '/'".to_string());
+    }
+    s.parse::<Template>()?.only(FILE_NAME_TOKENS)
+}
+
+/// a template of the URL of the webhook, a URL once its values are in
+pub fn webhook_url(s: &str) -> Result<Template, String> {
+    let template = s.parse::<Template>()?.only(WEBHOOK_TOKENS)?;
+    let example = Values::new(Local::now(), Some("owner")).with_profile(Some("profile"));
+    let url = template.render(&example, Context::Url)?;
+    reqwest::Url::parse(&url).map_err(|e| format!("{}: {}", url, e))?;
+    Ok(template)
+}
+
+/// a template of the prefix of the keys of the uploads, every token is known by then
+pub fn key_prefix(s: &str) -> Result<Template, String> {
+    s.parse()
+}
+
+impl Default for Template {
+    fn default() -> Self {
+        file_name(DEFAULT_FILE_NAME).expect("default file name")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn values(owner: &str) -> Values {
+        Values {
+            started_at: Local.with_ymd_and_hms(2024, 5, 1, 14, 30, 0).unwrap(),
+            hostname: "host".to_string(),
+            owner: Some(owner.to_string()),
+            filename: None,
+            profile: Some("fast".to_string()),
+        }
+    }
+
+    fn file_name_of(template: &str, owner: &str) -> String {
+        file_name(template)
+            .unwrap()
+            .render(&values(owner), Context::FileName)
+            .unwrap()
+    }
+
+    #[test]
+    fn a_file_name_holds_the_values() {
+        assert_eq!(
+            file_name_of("{date}T{time}-{hostname}-{owner}", "alice"),
+            "2024-05-01T14-30-host-alice"
+        );
+        assert_eq!(file_name_of("{{{owner}}}", "alice"), "{alice}");
+    }
+
+    #[test]
+    fn a_value_of_a_file_name_holds_safe_characters_only() {
+        assert_eq!(file_name_of("{owner}", "a/b\\c d"), "a_b_c_d");
+        assert_eq!(
+            file_name_of("{owner}", "ren\u{e9}e:*?\"<>|\0\n"),
+            "ren_e_________"
+        );
+        assert_eq!(file_name_of("{owner}", "a.b_c-d"), "a.b_c-d");
+        assert_eq!(
+            file_name_of("x-{owner}", "../../etc/passwd"),
+            "x-.._.._etc_passwd"
+        );
+    }
+
+    #[test]
+    fn a_file_name_is_neither_hidden_nor_an_option() {
+        assert_eq!(file_name_of("{owner}", ".."), "_..");
+        assert_eq!(file_name_of("{owner}", ".hidden"), "_.hidden");
+        assert_eq!(file_name_of("{owner}", "-y"), "_-y");
+        assert_eq!(file_name_of("{owner}", ""), "_");
+    }
+
+    #[test]
+    fn a_file_name_template_is_checked() {
+        assert!(file_name("a/{date}").is_err());
+        assert!(file_name("{filename}").is_err());
+        assert!(file_name("{nope}").is_err());
+        assert!(file_name("{date").is_err());
+        assert!(file_name("date}").is_err());
+    }
+
+    #[test]
+    fn other_contexts_encode_their_way() {
+        let template: Template = "{owner}".parse().unwrap();
+        let owner = "a b/c";
+        let render = |context| template.render(&values(owner), context).unwrap();
+        assert_eq!(render(Context::Argument), "a b/c");
+        assert_eq!(render(Context::Url), "a%20b%2Fc");
+    }
+
+    #[test]
+    fn the_recording_id_and_the_profile_are_values() {
+        let template: Template = "{recording_id}-{profile}".parse().unwrap();
+        let rendered = template.render(&values("alice"), Context::Argument);
+        assert_eq!(rendered.unwrap(), "20240501T143000.000-fast");
+        let without = Values {
+            profile: None,
+            ..values("alice")
+        };
+        assert_eq!(file_name_of("{owner}-{profile}", "alice"), "alice-fast");
+        let template = file_name("{owner}-{profile}").unwrap();
+        let rendered = template.render(&without, Context::FileName);
+        assert_eq!(rendered.unwrap(), "alice-");
+        assert!(file_name("{recording_id}").is_err());
+    }
+
+    #[test]
+    fn a_url_holds_its_values_percent_encoded() {
+        let template = webhook_url("https://hooks.example.com/{owner}/{recording_id}?p={profile}");
+        let rendered = template
+            .unwrap()
+            .render(&values("a b/c%d?e&f"), Context::Url)
+            .unwrap();
+        assert_eq!(
+            rendered,
+            "https://hooks.example.com/a%20b%2Fc%25d%3Fe%26f/20240501T143000.000?p=fast"
+        );
+        let prefix = key_prefix("recordings/{owner}/{date}/").unwrap();
+        let rendered = prefix.render(&values("../x y"), Context::Url).unwrap();
+        assert_eq!(rendered, "recordings/..%2Fx%20y/2024-05-01/");
+    }
+
+    #[test]
+    fn a_webhook_url_is_checked() {
+        assert!(webhook_url("https://hooks.example.com/{owner}").is_ok());
+        assert!(webhook_url("https://hooks.example.com/{filename}").is_err());
+        assert!(webhook_url("https://hooks.example.com/{nope}").is_err());
+        assert!(webhook_url("{owner}/hooks").is_err());
+        assert!(key_prefix("{nope}/").is_err());
+        assert!(key_prefix("{filename}/").is_ok());
+    }
+}
@@ -0,0 +1,355 @@
+//! Bounds on how fast the background jobs read the disk, leaving it to an active capture
+//!
+//! A capture on a spinning disk drops frames when a compression, a checksum or a contact sheet
+//! reads the same disk at full speed. Each [Category] of job may have a limit in bytes per second,
+//! `--io-limit checksum=64M`. While a recording is Started every limit is cut to
+//! `--capture-io-fraction` of itself, and it is restored once the capture ends, as [watch]
+//! follows the states of the recorder.
+//!
+//! The checksums read through a [ThrottledReader], which picks a changed rate up from one read to
+//! the next. An ffmpeg job is given its rate when it is spawned, as a `-readrate` relative to the
+//! bitrate of its input, and keeps it until it ends. With `--idle-io` the ffmpeg jobs are also
+//! moved to the idle I/O class with `ionice`.
+use crate::events::EventKind;
+use crate::probe::probe;
+use crate::service::{Recorder, RecordingState};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::Path;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::*;
+
+/// the limit of the checksums when the server is not told, as they always had
+pub const CHECKSUM_RATE: u64 = 64 * 1024 * 1024;
+/// of its limit a job keeps while a capture runs, when the server is not told
+pub const CAPTURE_FRACTION: f64 = 0.25;
+
+/// What a background job is, for its limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    Compression,
+    Checksum,
+    ContactSheet,
+    Transcode,
+}
+
+impl Category {
+    pub const ALL: &'static [Category] = &[
+        Category::Compression,
+        Category::Checksum,
+        Category::ContactSheet,
+        Category::Transcode,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Compression => "compression",
+            Self::Checksum => "checksum",
+            Self::ContactSheet => "contact_sheet",
+            Self::Transcode => "transcode",
+        }
+    }
+}
+
+impl FromStr for Category {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .find(|c| c.name() == s.trim())
+            .copied()
+            .ok_or_else(|| {
+                let known: Vec<_> = Self::ALL.iter().map(Category::name).collect();
+                format!("expected one of {}", known.join(", "))
+            })
+    }
+}
+
+/// The limit of a category, in bytes per second
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IoLimit {
+    pub category: Category,
+    /// unlimited with 0
+    pub rate: u64,
+}
+
+impl FromStr for IoLimit {
+    type Err = String;
+
+    /// `<category>=<bytes per second>`, with a `K`, `M` or `G` suffix
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expected = "expected '<category>=<bytes per second>', e.g. checksum=64M";
+        let (category, rate) = s.split_once('=').ok_or(expected)?;
+        let rate = rate.trim();
+        let (digits, unit) = match rate.char_indices().last() {
+            Some((i, 'K' | 'k')) => (&rate[..i], 1024),
+            Some((i, 'M' | 'm')) => (&rate[..i], 1024 * 1024),
+            Some((i, 'G' | 'g')) => (&rate[..i], 1024 * 1024 * 1024),
+            _ => (rate, 1),
+        };
+        let rate: u64 = digits.parse().map_err(|_| expected.to_string())?;
+        Ok(Self {
+            category: category.parse()?,
+            rate: rate.checked_mul(unit).ok_or(expected)?,
+        })
+    }
+}
+
+/// How the background jobs are bounded
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// bytes per second by category, the ones absent are unlimited
+    pub limits: BTreeMap<Category, u64>,
+    /// of its limit a job keeps while a capture runs
+    pub capture_fraction: f64,
+    /// move the ffmpeg jobs to the idle I/O class
+    pub idle_io: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            limits: BTreeMap::from([(Category::Checksum, CHECKSUM_RATE)]),
+            capture_fraction: CAPTURE_FRACTION,
+            idle_io: false,
+        }
+    }
+}
+
+impl Config {
+    /// the defaults with `limits` over them
+    pub fn new(limits: Vec<IoLimit>, capture_fraction: f64, idle_io: bool) -> Self {
+        let mut config = Self {
+            capture_fraction: capture_fraction.clamp(0.0, 1.0),
+            idle_io,
+            ..Default::default()
+        };
+        for limit in limits {
+            match limit.rate {
+                0 => config.limits.remove(&limit.category),
+                rate => config.limits.insert(limit.category, rate),
+            };
+        }
+        config
+    }
+}
+
+/// The limits of the server and whether a capture runs now
+#[derive(Debug, Default)]
+pub struct Throttle {
+    pub config: Config,
+    capturing: AtomicBool,
+}
+
+impl Throttle {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            capturing: AtomicBool::new(false),
+        }
+    }
+
+    pub fn capturing(&self) -> bool {
+        self.capturing.load(Ordering::Relaxed)
+    }
+
+    pub fn set_capturing(&self, capturing: bool) {
+        if self.capturing.swap(capturing, Ordering::Relaxed) != capturing {
+            info!(
+                "background I/O at {} of its limits",
+                match capturing {
+                    true => format!("{:.0}%", self.config.capture_fraction * 100.0),
+                    false => "100%".to_string(),
+                }
+            );
+        }
+    }
+
+    /// the rate of the category now, None when it is unlimited
+    pub fn rate(&self, category: Category) -> Option<u64> {
+        let limit = *self.config.limits.get(&category)?;
+        Some(match self.capturing() {
+            true => ((limit as f64 * self.config.capture_fraction) as u64).max(1),
+            false => limit,
+        })
+    }
+
+    /// the rates of the categories now, in the text format of Prometheus
+    pub fn metrics(&self) -> String {
+        let name = "record_screen_io_rate_bytes";
+        let mut metrics = format!(
+            "# HELP {} Read rate the background jobs are held to, by category.\n# TYPE {} gauge\n",
+            name, name
+        );
+        for category in Category::ALL {
+            if let Some(rate) = self.rate(*category) {
+                metrics += &format!("{}{{category=\"{}\"}} {}\n", name, category.name(), rate);
+            }
+        }
+        let name = "record_screen_io_capture_priority";
+        metrics += &format!(
+            "# HELP {} Whether the background jobs are cut down for a capture.\n# TYPE {} gauge\n{} {}\n",
+            name,
+            name,
+            name,
+            self.capturing() as u8
+        );
+        metrics
+    }
+
+    /// the `-readrate` of an ffmpeg job reading `input`, with the rate it stands for
+    ///
+    /// `-readrate` is a speed relative to the realtime of the input, the rate is turned into one
+    /// with the bitrate of the input. None when the category is unlimited or the input has no
+    /// duration.
+    pub async fn readrate(&self, category: Category, input: &Path) -> Option<(String, u64)> {
+        let rate = self.rate(category)?;
+        let size = tokio::fs::metadata(input).await.ok()?.len();
+        let duration = probe(input).await.ok()?.duration?;
+        if size == 0 || duration <= 0.0 {
+            return None;
+        }
+        let speed = rate as f64 / (size as f64 / duration);
+        Some((format!("{:.3}", speed.max(0.001)), rate))
+    }
+
+    /// move a spawned job to the idle I/O class, when the server is told to
+    pub fn idle(&self, pid: u32) {
+        if !self.config.idle_io {
+            return;
+        }
+        tokio::spawn(async move {
+            let status = tokio::process::Command::new("ionice")
+                .args(["-c", "3", "-p", &pid.to_string()])
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .kill_on_drop(true)
+                .status()
+                .await;
+            match status {
+                Ok(status) if status.success() => {}
+                Ok(status) => warn!("cannot set the I/O class of {}: ionice {}", pid, status),
+                Err(e) => warn!("cannot set the I/O class of {}: {}", pid, e),
+            }
+        });
+    }
+}
+
+/// follow the states of the recorder, cutting the limits while a capture runs
+pub async fn watch(mx: Arc<Recorder>) {
+    let mut live = mx.subscribe(None).await.live;
+    let started = |state: &RecordingState| matches!(state, RecordingState::Started { .. });
+    mx.throttle.set_capturing(started(&*mx.lock().await));
+    loop {
+        match live.recv().await {
+            Ok(event) => {
+                if let EventKind::State { state } = event.kind {
+                    mx.throttle.set_capturing(started(&state));
+                }
+            }
+            Err(RecvError::Lagged(_)) => mx.throttle.set_capturing(started(&*mx.lock().await)),
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+/// A token bucket of bytes, holding at most a second of its rate
+///
+/// The time is given to every call, not read, so that the bucket can be driven by any clock.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: u64,
+    /// may go below zero, a read is never split: the debt is waited for
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// a full bucket
+    pub fn new(rate: u64, now: Instant) -> Self {
+        Self {
+            rate: rate.max(1),
+            tokens: rate.max(1) as f64,
+            last: now,
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// the most taken at once
+    pub fn capacity(&self) -> u64 {
+        self.rate
+    }
+
+    /// change the rate, keeping what was saved up to the new capacity
+    pub fn set_rate(&mut self, rate: u64) {
+        self.rate = rate.max(1);
+        self.tokens = self.tokens.min(self.rate as f64);
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.last = now;
+    }
+
+    /// take `n` bytes at `now`, returning how long to wait before going on
+    pub fn take(&mut self, n: u64, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= n as f64;
+        match self.tokens < 0.0 {
+            true => Duration::from_secs_f64(-self.tokens / self.rate as f64),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+/// A reader held to the rate of its category, which it looks up before every read
+pub struct ThrottledReader<R> {
+    inner: R,
+    throttle: Arc<Throttle>,
+    category: Category,
+    bucket: Option<TokenBucket>,
+}
+
+impl<R: Read> ThrottledReader<R> {
+    pub fn new(inner: R, throttle: Arc<Throttle>, category: Category) -> Self {
+        Self {
+            inner,
+            throttle,
+            category,
+            bucket: None,
+        }
+    }
+}
+
+impl<R: Read> Read for ThrottledReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let Some(rate) = self.throttle.rate(self.category) else {
+            self.bucket = None;
+            return self.inner.read(buf);
+        };
+        let now = Instant::now();
+        let bucket = self
+            .bucket
+            .get_or_insert_with(|| TokenBucket::new(rate, now));
+        bucket.set_rate(rate);
+        let want = buf.len().min(bucket.capacity() as usize).max(1);
+        let n = self.inner.read(&mut buf[..want])?;
+        let wait = bucket.take(n as u64, now);
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+        Ok(n)
+    }
+}
@@ -0,0 +1,148 @@
+//! A preview image of a recording, for the web UI
+//!
+//! The thumbnail is the frame at a tenth of the recording, no wider than [MAX_WIDTH], made when
+//! it is first asked for and kept next to the recording as `<recording>.jpg`; a recording
+//! newer than its thumbnail gets another one. The requests for a thumbnail being made wait for
+//! the one ffmpeg making it, which runs in its own task: a client leaving doesn't stop it.
+use crate::ffmpeg::{FfmpegBuilder, File, Parameter};
+use crate::probe::probe;
+use crate::runner;
+use crate::service::{ChildRole, Recorder};
+use futures::future::{BoxFuture, FutureExt, Shared};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tracing::*;
+
+/// the widest thumbnail
+pub const MAX_WIDTH: u32 = 320;
+/// where in the recording the frame is, of its duration
+const AT: f64 = 0.1;
+
+#[derive(Debug, Clone, Error)]
+pub enum Error {
+    /// ffprobe or ffmpeg could not read the recording
+    #[error("cannot read the recording: {0}")]
+    Unreadable(String),
+    #[error("{0}")]
+    Other(String),
+}
+
+type Making = Shared<BoxFuture<'static, Result<PathBuf, Error>>>;
+
+/// The thumbnails being made, by the path of the thumbnail
+#[derive(Default)]
+pub struct Thumbnails {
+    making: Mutex<HashMap<PathBuf, Making>>,
+}
+
+/// path of the thumbnail of a recording
+pub fn thumbnail_path(recording: &Path) -> PathBuf {
+    let mut path = recording.as_os_str().to_owned();
+    path.push(".jpg");
+    PathBuf::from(path)
+}
+
+/// whether the thumbnail is there and not older than its recording
+fn is_fresh(source: &Path, thumbnail: &Path) -> bool {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    match (modified(thumbnail), modified(source)) {
+        (Some(thumbnail), Some(source)) => thumbnail >= source,
+        (Some(_), None) => true,
+        _ => false,
+    }
+}
+
+impl Thumbnails {
+    /// the thumbnail of the recording, made unless it is there already
+    pub async fn get(&self, mx: &Arc<Recorder>, source: &Path) -> Result<PathBuf, Error> {
+        let thumbnail = thumbnail_path(source);
+        let making = {
+            let mut making = self.making.lock().unwrap();
+            match making.get(&thumbnail) {
+                Some(job) => job.clone(),
+                None if is_fresh(source, &thumbnail) => return Ok(thumbnail),
+                None => {
+                    let (mx, source) = (mx.clone(), source.to_path_buf());
+                    let task = tokio::spawn({
+                        let thumbnail = thumbnail.clone();
+                        async move {
+                            let result = make(&mx, &source, &thumbnail).await;
+                            if let Err(e) = &result {
+                                warn!("cannot make the thumbnail of {}: {}", source.display(), e);
+                            }
+                            mx.thumbnails.making.lock().unwrap().remove(&thumbnail);
+                            result.map(|()| thumbnail)
+                        }
+                    });
+                    let job = async move {
+                        task.await
+                            .unwrap_or_else(|e| Err(Error::Other(e.to_string())))
+                    }
+                    .boxed()
+                    .shared();
+                    making.insert(thumbnail.clone(), job.clone());
+                    job
+                }
+            }
+        };
+        making.await
+    }
+}
+
+async fn make(mx: &Recorder, source: &Path, thumbnail: &Path) -> Result<(), Error> {
+    let duration = probe(source)
+        .await
+        .map_err(|e| Error::Unreadable(e.to_string()))?
+        .duration
+        .unwrap_or_default();
+    let mut partial = thumbnail.as_os_str().to_owned();
+    partial.push(".part.jpg");
+    let partial = PathBuf::from(partial);
+
+    let input = source.to_string_lossy().to_string();
+    let output = partial.to_string_lossy().to_string();
+    let seek = format!("{:.3}", duration * AT);
+    let scale = format!("scale='min({},iw)':-2", MAX_WIDTH);
+    let ffmpeg = FfmpegBuilder::new()
+        .stderr(Stdio::piped())
+        .option(Parameter::Single("y"))
+        .input(File::new(&input).option(Parameter::KeyValue("ss", &seek)))
+        .option2(Parameter::KeyValue("vf", &scale))
+        .option2(Parameter::StreamSpec {
+            base: "frames",
+            specifier: "v",
+            value: "1",
+        })
+        .option2(Parameter::StreamSpec {
+            base: "q",
+            specifier: "v",
+            value: "4",
+        })
+        .option2(Parameter::Single("an"))
+        .output(File::new(&output))
+        .run()
+        .await
+        .map_err(failed)?;
+    let pid = ffmpeg.id();
+    mx.children
+        .register(pid, ChildRole::Frames, vec![output.clone()]);
+    let summary = ffmpeg.wait_with_progress(|_| {}).await;
+    mx.children.unregister(pid);
+    let summary = summary.map_err(failed)?;
+    if !summary.success() || !partial.is_file() {
+        let _ = std::fs::remove_file(&partial);
+        return Err(Error::Unreadable(summary.stderr_tail.join("\n")));
+    }
+    std::fs::rename(&partial, thumbnail).map_err(|e| Error::Other(e.to_string()))
+}
+
+/// a file ffmpeg can't open ends it before its first progress
+fn failed(e: runner::Error) -> Error {
+    match e {
+        runner::Error::Exited { stderr_tail, .. } => Error::Unreadable(stderr_tail.join("\n")),
+        e => Error::Other(e.to_string()),
+    }
+}
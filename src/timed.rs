@@ -0,0 +1,200 @@
+//! A recording of a given duration in one request, for the scripts and the test pipelines
+//!
+//! `POST /api/record?duration_secs=` starts a recording as `/api/start` does, stops it once it
+//! was started for the duration, and answers with how it finished. The recording runs in a task
+//! of its own, followed through the events of the recorder: a client that goes away leaves it to
+//! finish and land in the history, unless it asked for `cancel_on_disconnect`, then the recording
+//! is cancelled as an emergency stop does.
+use crate::events::EventKind;
+use crate::presence::Identity;
+use crate::runner::Progress;
+use crate::service::{
+    emergency_stop, start_claimed, stop_by, Recorder, RecordingOptions, RecordingState, StartClaim,
+};
+use crate::trim::StopRequest;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use tracing::*;
+
+/// the longest recording of a request, when the server is not told
+pub const DEFAULT_MAX: Duration = Duration::from_secs(300);
+
+/// What a timed recording tells while it runs
+#[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
+pub enum Update {
+    Progress(Progress),
+    /// the last state of the recording, Done, Failed or Cancelled
+    Finished(RecordingState),
+}
+
+/// whether the recording is over
+pub fn finished(state: &RecordingState) -> bool {
+    matches!(
+        state,
+        RecordingState::Done { .. }
+            | RecordingState::Failed { .. }
+            | RecordingState::Cancelled { .. }
+    )
+}
+
+/// the next state of the recorder, passing the progress on to `tx`
+async fn next_state(
+    live: &mut broadcast::Receiver<crate::events::Event>,
+    mx: &Recorder,
+    tx: &mpsc::Sender<Update>,
+) -> RecordingState {
+    use broadcast::error::RecvError;
+    loop {
+        match live.recv().await {
+            Ok(event) => match event.kind {
+                EventKind::State { state } => return state,
+                // a slow client misses some, it only shows that the recording goes on
+                EventKind::Progress { progress, .. } => {
+                    let _ = tx.try_send(Update::Progress(progress));
+                }
+                _ => {}
+            },
+            // a state may be among the events missed
+            Err(RecvError::Lagged(_)) => return mx.lock().await.clone(),
+            Err(RecvError::Closed) => return mx.lock().await.clone(),
+        }
+    }
+}
+
+/// start the claimed recording and stop it after `duration`
+///
+/// The receiver gets the progress, then the last state. The recording goes on when it is dropped.
+pub async fn spawn(
+    claim: StartClaim,
+    opt: RecordingOptions,
+    by: Option<Identity>,
+    duration: Duration,
+) -> mpsc::Receiver<Update> {
+    let mx = claim.recorder();
+    let (tx, rx) = mpsc::channel(16);
+    // before the start, so that none of its states is missed
+    let mut live = mx.subscribe(None).await.live;
+    tokio::spawn(start_claimed(claim, opt, by.clone()));
+    tokio::spawn(async move {
+        let state = loop {
+            let state = next_state(&mut live, &mx, &tx).await;
+            if finished(&state) || matches!(state, RecordingState::Started { .. }) {
+                break state;
+            }
+        };
+        if matches!(state, RecordingState::Started { .. }) {
+            let sleep = tokio::time::sleep(duration);
+            tokio::pin!(sleep);
+            // stopped or cancelled by someone else meanwhile
+            let state = loop {
+                tokio::select! {
+                    _ = &mut sleep => break None,
+                    state = next_state(&mut live, &mx, &tx) => if finished(&state) {
+                        break Some(state);
+                    },
+                }
+            };
+            if let Some(state) = state {
+                let _ = tx.send(Update::Finished(state)).await;
+                return;
+            }
+            info!("stopping the recording of {:?}", duration);
+            let stopping = mx.clone();
+            tokio::spawn(async move {
+                if let Err(e) = stop_by(stopping, by, StopRequest::by_server()).await {
+                    warn!("cannot stop the timed recording: {}", e);
+                }
+            });
+        }
+        let mut state = state;
+        while !finished(&state) {
+            state = next_state(&mut live, &mx, &tx).await;
+        }
+        let _ = tx.send(Update::Finished(state)).await;
+    });
+    rx
+}
+
+/// Cancels the recording when dropped before it is disarmed, for a client that went away
+pub struct CancelOnDrop(Option<(Arc<Recorder>, Identity)>);
+
+impl CancelOnDrop {
+    /// a guard that cancels only when `cancel` is
+    pub fn new(cancel: bool, mx: &Arc<Recorder>, by: &Identity) -> Self {
+        Self(cancel.then(|| (mx.clone(), by.clone())))
+    }
+
+    /// the recording finished, there is nothing to cancel
+    pub fn disarm(&mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if let Some((mx, by)) = self.0.take() {
+            warn!("{} went away, cancelling its recording", by);
+            tokio::spawn(async move {
+                emergency_stop(mx, Some(by)).await;
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::ChildRole;
+
+    /// a recording started by a child that sleeps
+    async fn started(mx: &Recorder) -> tokio::process::Child {
+        let child = tokio::process::Command::new("sleep")
+            .arg("30")
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap();
+        let pid = child.id().unwrap();
+        mx.children.register(pid, ChildRole::Capture, vec![]);
+        let state = serde_json::from_value(serde_json::json!({
+            "type": "Started",
+            "process_id": pid,
+            "file": "timed.mkv",
+            "started_at": chrono::Local::now(),
+        }));
+        mx.set(state.unwrap()).await;
+        child
+    }
+
+    #[tokio::test]
+    async fn a_client_going_away_cancels_only_when_asked() {
+        let mx = Arc::new(Recorder::new());
+        let by = Identity::default();
+        let mut child = started(&mx).await;
+        drop(CancelOnDrop::new(false, &mx, &by));
+        let mut disarmed = CancelOnDrop::new(true, &mx, &by);
+        disarmed.disarm();
+        drop(disarmed);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(matches!(*mx.lock().await, RecordingState::Started { .. }));
+
+        drop(CancelOnDrop::new(true, &mx, &by));
+        tokio::time::timeout(Duration::from_secs(5), child.wait())
+            .await
+            .unwrap()
+            .unwrap();
+        let state = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let state = mx.lock().await.clone();
+                if finished(&state) {
+                    break state;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(matches!(state, RecordingState::Cancelled { .. }));
+    }
+}
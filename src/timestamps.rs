@@ -0,0 +1,208 @@
+//! Capture time of every frame, for the measurements the nominal framerate can't support
+//!
+//! With `frame_timestamps` the capture runs through ffmpeg's `showinfo` filter, which logs every
+//! frame to stderr. The reader of stderr parses those lines and streams them, as they arrive,
+//! through `gzip` into the `<name>.frames.csv.gz` sidecar, so an hour of frames never sits in
+//! memory. The first line of the sidecar is the anchor: the realtime and monotonic clocks read
+//! when the first frame was seen, together with its `pts_time`. The wall-clock time of a frame is
+//! the anchor's realtime plus its `pts_time` minus the anchor's.
+//!
+//! The accuracy has limits that no parsing removes:
+//! - x11grab stamps a frame when it grabs it, not when it was shown: compositor and vsync delays
+//!   are not in the timestamps;
+//! - the anchor is read when the first line arrives through the stderr pipe, a few milliseconds
+//!   after the grab, and that offset is carried by every frame;
+//! - `pts_time` follows the capture clock; a realtime clock stepped by NTP meanwhile is not seen;
+//! - the frames counted are the grabbed ones, before the encoder: frames the encoder duplicates or
+//!   drops to keep the output framerate are not rows of the sidecar.
+use crate::container;
+use chrono::{DateTime, Local};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use tracing::*;
+
+/// the filter logging the frames
+pub const FILTER: &str = "showinfo";
+/// columns of the rows, after the anchor line
+pub const COLUMNS: &str = "n,pts,pts_time,wallclock";
+
+/// A frame as `showinfo` logs it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShowInfo {
+    pub n: u64,
+    pub pts: i64,
+    pub pts_time: f64,
+}
+
+/// whether the stderr line was logged by `showinfo`
+pub fn is_showinfo(line: &str) -> bool {
+    line.starts_with("[Parsed_showinfo_")
+}
+
+/// parse a frame line of `showinfo`, None for its other lines
+///
+/// `[Parsed_showinfo_1 @ 0x5581c0e0] n:  12 pts: 480000 pts_time:0.48 duration: 40000 ...`,
+/// a value is either glued to its key or after the spaces aligning it.
+pub fn parse_showinfo(line: &str) -> Option<ShowInfo> {
+    if !is_showinfo(line) {
+        return None;
+    }
+    let (_, fields) = line.split_once("] ")?;
+    let (mut n, mut pts, mut pts_time) = (None, None, None);
+    let mut words = fields.split_whitespace();
+    while let Some(word) = words.next() {
+        let Some((key, value)) = word.split_once(':') else {
+            continue;
+        };
+        let value = match value {
+            "" => words.next().unwrap_or_default(),
+            value => value,
+        };
+        match key {
+            "n" => n = value.parse().ok(),
+            "pts" => pts = value.parse().ok(),
+            "pts_time" => pts_time = value.parse().ok(),
+            _ => {}
+        }
+        if let (Some(n), Some(pts), Some(pts_time)) = (n, pts, pts_time) {
+            return Some(ShowInfo { n, pts, pts_time });
+        }
+    }
+    None
+}
+
+/// The clocks when the first frame was seen
+#[derive(Debug, Clone, Copy)]
+pub struct Anchor {
+    pub realtime: DateTime<Local>,
+    /// CLOCK_MONOTONIC, in nanoseconds
+    pub monotonic_ns: u128,
+    pub pts_time: f64,
+}
+
+impl Anchor {
+    fn now(pts_time: f64) -> Self {
+        let monotonic = nix::time::clock_gettime(nix::time::ClockId::CLOCK_MONOTONIC)
+            .map(|t| std::time::Duration::from(t).as_nanos())
+            .unwrap_or_default();
+        Self {
+            realtime: Local::now(),
+            monotonic_ns: monotonic,
+            pts_time,
+        }
+    }
+
+    /// the comment line heading the sidecar
+    pub fn header(&self) -> String {
+        format!(
+            "# anchor realtime={} monotonic_ns={} pts_time={}",
+            self.realtime
+                .to_rfc3339_opts(chrono::SecondsFormat::Micros, false),
+            self.monotonic_ns,
+            self.pts_time
+        )
+    }
+
+    /// the wall-clock time of a frame, in seconds since the epoch
+    pub fn wallclock(&self, frame: &ShowInfo) -> f64 {
+        self.realtime.timestamp_micros() as f64 / 1e6 + (frame.pts_time - self.pts_time)
+    }
+}
+
+/// path of the sidecar of a capture
+pub fn sidecar_path(capture: &str) -> PathBuf {
+    PathBuf::from(format!("{}.frames.csv.gz", container::base(capture)))
+}
+
+/// The sidecar being written, finished when dropped
+pub struct Sidecar {
+    path: PathBuf,
+    gzip: Child,
+    input: Option<std::io::BufWriter<ChildStdin>>,
+    anchor: Option<Anchor>,
+    rows: u64,
+}
+
+impl Sidecar {
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        let file = std::fs::File::create(path)?;
+        let mut gzip = Command::new("gzip")
+            .arg("-c")
+            .stdin(Stdio::piped())
+            .stdout(file)
+            .stderr(Stdio::null())
+            .spawn()?;
+        let input = gzip.stdin.take().map(std::io::BufWriter::new);
+        Ok(Self {
+            path: path.to_path_buf(),
+            gzip,
+            input,
+            anchor: None,
+            rows: 0,
+        })
+    }
+
+    /// the clocks when the first frame was seen, None before
+    pub fn anchor(&self) -> Option<&Anchor> {
+        self.anchor.as_ref()
+    }
+
+    /// take a stderr line of the capture, true when it was one of `showinfo`
+    pub fn line(&mut self, line: &str) -> bool {
+        if !is_showinfo(line) {
+            return false;
+        }
+        if let Some(frame) = parse_showinfo(line) {
+            if let Err(e) = self.write(&frame) {
+                warn!("cannot write {}: {}", self.path.display(), e);
+                // the capture goes on without its timestamps
+                self.input = None;
+            }
+        }
+        true
+    }
+
+    fn write(&mut self, frame: &ShowInfo) -> std::io::Result<()> {
+        let Some(input) = &mut self.input else {
+            return Ok(());
+        };
+        let anchor = match self.anchor {
+            Some(anchor) => anchor,
+            None => {
+                let anchor = Anchor::now(frame.pts_time);
+                writeln!(input, "{}\n{}", anchor.header(), COLUMNS)?;
+                *self.anchor.insert(anchor)
+            }
+        };
+        writeln!(
+            input,
+            "{},{},{},{:.6}",
+            frame.n,
+            frame.pts,
+            frame.pts_time,
+            anchor.wallclock(frame)
+        )?;
+        self.rows += 1;
+        Ok(())
+    }
+}
+
+impl Drop for Sidecar {
+    fn drop(&mut self) {
+        if let Some(mut input) = self.input.take() {
+            if let Err(e) = input.flush() {
+                warn!("cannot write {}: {}", self.path.display(), e);
+            }
+        }
+        match self.gzip.wait() {
+            Ok(status) if status.success() => info!(
+                "{} frames timestamped in {}",
+                self.rows,
+                self.path.display()
+            ),
+            Ok(status) => warn!("gzip of {} exited with {}", self.path.display(), status),
+            Err(e) => warn!("cannot wait for the gzip of {}: {}", self.path.display(), e),
+        }
+    }
+}
@@ -0,0 +1,680 @@
+//! Transcripts of the recordings, made by a speech-to-text program the server allows
+//!
+//! The programs are given to the server by name, `--transcriber whisper="/opt/whisper/run -m
+//! base"`, and a recording asks for one with `transcribe: {command: "whisper"}`. Once it is done,
+//! its audio is extracted to a 16 kHz mono WAV and the program runs as
+//! `<program> <args>... <wav> <output> [<language>]`, its stderr going to
+//! `<recording>.transcript.log`. It writes SRT or JSON segments, `[{"start": 0.0, "end": 2.5,
+//! "text": "..."}]` or `{"segments": [...]}`, which are checked and kept as
+//! `<recording>.transcript.srt` and the plain text `<recording>.transcript.txt`. With `mux` the
+//! subtitles are also added to the recording as a track.
+//!
+//! The arguments are [templates](crate::template), `--title={filename}` gets the name of the
+//! recording as an argument of its own whatever it holds.
+use crate::ffmpeg::{FfmpegBuilder, File, Parameter};
+use crate::service::{ChildRole, Children};
+use crate::template::{self, Template, Values};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::time::Duration;
+use thiserror::Error;
+use tracing::*;
+
+/// how long a transcriber may run unless the server says otherwise
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3600);
+const SAMPLE_RATE: &str = "16000";
+
+/// A speech-to-text program the recordings may ask for
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transcriber {
+    pub name: String,
+    /// the program and its first arguments, see [crate::template]
+    pub argv: Vec<Template>,
+}
+
+impl FromStr for Transcriber {
+    type Err = String;
+
+    /// `<name>=<program> [<args>...]`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, command) = s
+            .split_once('=')
+            .ok_or("expected '<name>=<program> [<args>...]'")?;
+        let argv = command
+            .split_whitespace()
+            .map(str::parse)
+            .collect::<Result<Vec<Template>, _>>()?;
+        if name.trim().is_empty() || argv.is_empty() {
+            return Err("expected '<name>=<program> [<args>...]'".to_string());
+        }
+        Ok(Self {
+            name: name.trim().to_string(),
+            argv,
+        })
+    }
+}
+
+/// The transcribers the server allows
+#[derive(Debug, Clone)]
+pub struct Transcribers {
+    programs: HashMap<String, Vec<Template>>,
+    pub timeout: Duration,
+}
+
+impl Default for Transcribers {
+    fn default() -> Self {
+        Self::new(vec![], DEFAULT_TIMEOUT)
+    }
+}
+
+impl Transcribers {
+    pub fn new(transcribers: Vec<Transcriber>, timeout: Duration) -> Self {
+        Self {
+            programs: transcribers.into_iter().map(|t| (t.name, t.argv)).collect(),
+            timeout,
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&[Template]> {
+        self.programs.get(name).map(Vec::as_slice)
+    }
+
+    /// the names of the transcribers, sorted
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.programs.keys().cloned().collect();
+        names.sort();
+        names
+    }
+}
+
+/// The transcript a recording asks for
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TranscribeRequest {
+    /// the name of a transcriber of the server
+    pub command: String,
+    /// passed on to the transcriber, which guesses without it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    /// also add the subtitles to the recording
+    #[serde(default)]
+    pub mux: bool,
+}
+
+/// A line of the transcript, in seconds
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Segment {
+    pub start: f64,
+    pub end: f64,
+    pub text: String,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("no transcriber {0}")]
+    Unknown(String),
+    #[error("cannot extract the audio: {0}")]
+    Audio(String),
+    #[error("the transcriber failed: {0}")]
+    Failed(String),
+    #[error("the transcriber took longer than {0:?}")]
+    Timeout(Duration),
+    #[error("malformed transcript: {0}")]
+    Malformed(String),
+    #[error("cannot add the subtitles: {0}")]
+    Mux(String),
+    #[error("cannot make the arguments of the transcriber: {0}")]
+    Arguments(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+pub fn srt_path(recording: &str) -> String {
+    format!("{}.transcript.srt", recording)
+}
+
+pub fn text_path(recording: &str) -> String {
+    format!("{}.transcript.txt", recording)
+}
+
+pub fn log_path(recording: &str) -> String {
+    format!("{}.transcript.log", recording)
+}
+
+/// `00:01:02,345`, a dot being accepted for the comma
+fn parse_srt_time(s: &str) -> Option<f64> {
+    let (hms, millis) = s.trim().split_once([',', '.'])?;
+    let mut parts = hms.split(':');
+    let (h, m, s) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some() {
+        return None;
+    }
+    let seconds =
+        h.parse::<u64>().ok()? * 3600 + m.parse::<u64>().ok()? * 60 + s.parse::<u64>().ok()?;
+    Some(seconds as f64 + millis.parse::<u64>().ok()? as f64 / 10f64.powi(millis.len() as i32))
+}
+
+fn format_srt_time(seconds: f64) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02},{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+fn parse_srt(srt: &str) -> Result<Vec<Segment>, Error> {
+    let srt = srt.replace("\r\n", "\n");
+    let mut segments = vec![];
+    for block in srt.split("\n\n").map(str::trim).filter(|b| !b.is_empty()) {
+        let mut lines = block.lines();
+        let mut timing = lines.next().unwrap_or_default();
+        // the counter is optional
+        if !timing.contains("-->") {
+            timing = lines.next().unwrap_or_default();
+        }
+        let (start, end) = timing
+            .split_once("-->")
+            .ok_or_else(|| Error::Malformed(format!("no timing in {:?}", block)))?;
+        let time = |t: &str| {
+            parse_srt_time(t).ok_or_else(|| Error::Malformed(format!("invalid time {:?}", t)))
+        };
+        segments.push(Segment {
+            start: time(start)?,
+            // position settings may follow the end
+            end: time(end.split_whitespace().next().unwrap_or_default())?,
+            text: lines.collect::<Vec<_>>().join("\n"),
+        });
+    }
+    Ok(segments)
+}
+
+/// the segments of SRT or JSON output
+pub fn parse(output: &str) -> Result<Vec<Segment>, Error> {
+    let trimmed = output.trim_start_matches('\u{feff}').trim();
+    if trimmed.starts_with('[') || trimmed.starts_with('{') {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Json {
+            Segments(Vec<Segment>),
+            Object { segments: Vec<Segment> },
+        }
+        let json: Json =
+            serde_json::from_str(trimmed).map_err(|e| Error::Malformed(e.to_string()))?;
+        return Ok(match json {
+            Json::Segments(segments) | Json::Object { segments } => segments,
+        });
+    }
+    parse_srt(trimmed)
+}
+
+/// in order, trimmed, without the empty ones; times that go backwards are refused
+pub fn normalize(segments: Vec<Segment>) -> Result<Vec<Segment>, Error> {
+    let mut normalized = vec![];
+    for segment in segments {
+        let text = segment.text.trim().to_string();
+        if text.is_empty() {
+            continue;
+        }
+        if !(segment.start >= 0.0 && segment.end >= segment.start) {
+            return Err(Error::Malformed(format!(
+                "segment from {} to {}",
+                segment.start, segment.end
+            )));
+        }
+        normalized.push(Segment { text, ..segment });
+    }
+    normalized.sort_by(|a, b| a.start.total_cmp(&b.start));
+    Ok(normalized)
+}
+
+pub fn to_srt(segments: &[Segment]) -> String {
+    segments
+        .iter()
+        .enumerate()
+        .map(|(n, s)| {
+            format!(
+                "{}\n{} --> {}\n{}\n\n",
+                n + 1,
+                format_srt_time(s.start),
+                format_srt_time(s.end),
+                s.text
+            )
+        })
+        .collect()
+}
+
+pub fn to_text(segments: &[Segment]) -> String {
+    segments.iter().map(|s| format!("{}\n", s.text)).collect()
+}
+
+async fn extract_audio(recording: &str, wav: &str, children: &Children) -> Result<(), Error> {
+    let summary = run_ffmpeg(
+        FfmpegBuilder::new()
+            .stderr(Stdio::piped())
+            .option(Parameter::Single("y"))
+            .input(File::new(recording))
+            .option2(Parameter::Single("vn"))
+            .option2(Parameter::KeyValue("ac", "1"))
+            .option2(Parameter::KeyValue("ar", SAMPLE_RATE))
+            .option2(Parameter::codec("a", "pcm_s16le"))
+            .output(File::new(wav)),
+        wav,
+        children,
+    )
+    .await;
+    summary.map_err(Error::Audio)
+}
+
+async fn run_ffmpeg(
+    builder: FfmpegBuilder<'_>,
+    output: &str,
+    children: &Children,
+) -> Result<(), String> {
+    let ffmpeg = builder.run().await.map_err(|e| e.to_string())?;
+    let pid = ffmpeg.id();
+    children.register(pid, ChildRole::Transcript, vec![output.to_string()]);
+    let summary = ffmpeg.wait_with_progress(|_| {}).await;
+    children.unregister(pid);
+    let summary = summary.map_err(|e| e.to_string())?;
+    if !summary.success() {
+        return Err(summary.stderr_tail.join("\n"));
+    }
+    Ok(())
+}
+
+/// runs the transcriber, returning what it wrote
+async fn run_transcriber(
+    argv: &[String],
+    wav: &str,
+    output: &str,
+    language: Option<&str>,
+    log: &str,
+    timeout: Duration,
+    children: &Children,
+) -> Result<String, Error> {
+    let mut command = tokio::process::Command::new(&argv[0]);
+    command
+        .args(&argv[1..])
+        .args([wav, output])
+        .args(language)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(std::fs::File::create(log)?)
+        .kill_on_drop(true);
+    let mut child = command.spawn()?;
+    let pid = child.id().unwrap_or_default();
+    children.register(pid, ChildRole::Transcript, vec![output.to_string()]);
+    let status = tokio::time::timeout(timeout, child.wait()).await;
+    children.unregister(pid);
+    let status = match status {
+        Ok(status) => status?,
+        Err(_) => {
+            _ = child.kill().await;
+            return Err(Error::Timeout(timeout));
+        }
+    };
+    if !status.success() {
+        return Err(Error::Failed(format!("{}, see {}", status, log)));
+    }
+    std::fs::read_to_string(output)
+        .map_err(|e| Error::Failed(format!("no output at {}: {}", output, e)))
+}
+
+/// the subtitle codec the container takes
+fn subtitle_codec(recording: &str) -> &'static str {
+    match Path::new(recording).extension().and_then(|e| e.to_str()) {
+        Some("mkv") => "srt",
+        Some("webm") => "webvtt",
+        _ => "mov_text",
+    }
+}
+
+/// remux the recording with the subtitles as a track, replacing it
+async fn mux(
+    recording: &str,
+    srt: &str,
+    language: Option<&str>,
+    children: &Children,
+) -> Result<(), Error> {
+    let extension = Path::new(recording)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("mp4");
+    let remuxed = format!("{}.subtitled.{}", recording, extension);
+    let language = language.map(|l| format!("language={}", l));
+    let mut builder = FfmpegBuilder::new()
+        .stderr(Stdio::piped())
+        .option(Parameter::Single("y"))
+        .input(File::new(recording))
+        .input(File::new(srt))
+        .option2(Parameter::Repeated("map", vec!["0", "1"]))
+        .option2(Parameter::KeyValue("c", "copy"))
+        .option2(Parameter::codec("s", subtitle_codec(recording)));
+    if let Some(language) = &language {
+        builder = builder.option2(Parameter::StreamSpec {
+            base: "metadata",
+            specifier: "s:s:0",
+            value: language,
+        });
+    }
+    let muxed = run_ffmpeg(builder.output(File::new(&remuxed)), &remuxed, children).await;
+    if let Err(e) = muxed {
+        let _ = std::fs::remove_file(&remuxed);
+        return Err(Error::Mux(e));
+    }
+    std::fs::rename(&remuxed, recording)?;
+    Ok(())
+}
+
+/// transcribe a finished recording, keeping the transcript next to it
+pub async fn transcribe(
+    recording: &str,
+    req: &TranscribeRequest,
+    values: &Values,
+    transcribers: &Transcribers,
+    children: &Children,
+) -> Result<Vec<Segment>, Error> {
+    let argv = transcribers
+        .get(&req.command)
+        .ok_or_else(|| Error::Unknown(req.command.clone()))?
+        .iter()
+        .map(|arg| arg.render(values, template::Context::Argument))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(Error::Arguments)?;
+    let wav = format!("{}.transcript.wav", recording);
+    let output = format!("{}.transcript.out", recording);
+    let transcribed = async {
+        extract_audio(recording, &wav, children).await?;
+        let written = run_transcriber(
+            &argv,
+            &wav,
+            &output,
+            req.language.as_deref(),
+            &log_path(recording),
+            transcribers.timeout,
+            children,
+        )
+        .await?;
+        normalize(parse(&written)?)
+    }
+    .await;
+    let _ = std::fs::remove_file(&wav);
+    let _ = std::fs::remove_file(&output);
+    let segments = transcribed?;
+    let srt = srt_path(recording);
+    std::fs::write(&srt, to_srt(&segments))?;
+    std::fs::write(text_path(recording), to_text(&segments))?;
+    if req.mux && !segments.is_empty() {
+        mux(recording, &srt, req.language.as_deref(), children).await?;
+    }
+    info!("{} segments transcribed from {}", segments.len(), recording);
+    Ok(segments)
+}
+
+/// A recording whose transcript matched
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchResult {
+    /// the file name of the recording
+    pub name: String,
+    pub transcript: String,
+    /// the segments containing the term
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub matches: Vec<Segment>,
+}
+
+/// the transcripts of the directory containing `term`, ignoring case; all of them without one
+pub fn search(dir: &Path, term: Option<&str>) -> std::io::Result<Vec<SearchResult>> {
+    let term = term.map(str::to_lowercase).filter(|t| !t.is_empty());
+    let mut results = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let file_name = entry?.file_name().to_string_lossy().to_string();
+        let Some(name) = file_name.strip_suffix(".transcript.srt") else {
+            continue;
+        };
+        let Ok(srt) = std::fs::read_to_string(dir.join(&file_name)) else {
+            continue;
+        };
+        let Ok(segments) = parse_srt(&srt) else {
+            warn!("cannot read the transcript {}", file_name);
+            continue;
+        };
+        let matches: Vec<Segment> = match &term {
+            Some(term) => segments
+                .into_iter()
+                .filter(|s| s.text.to_lowercase().contains(term))
+                .collect(),
+            None => vec![],
+        };
+        if term.is_some() && matches.is_empty() {
+            continue;
+        }
+        results.push(SearchResult {
+            name: name.to_string(),
+            transcript: file_name.clone(),
+            matches,
+        });
+    }
+    results.sort_by(|a, b| b.name.cmp(&a.name));
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    const SRT: &str = "1\n00:00:00,000 --> 00:00:01,500\nHello there\n\n\
+                       2\n00:00:01,500 --> 00:00:03,000\nGeneral Kenobi\n";
+
+    fn dir(name: &str) -> std::path::PathBuf {
+        let dir = crate::recordings::test_output_dir().join(name);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// a stub transcriber in `dir` running `body`: `$1` is the WAV, `$2` the output, `$3` the
+    /// language
+    fn stub(dir: &Path, body: &str) -> String {
+        let path = dir.join("transcriber");
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    /// what the stub at `program` wrote, run over a WAV of `dir`
+    async fn run(dir: &Path, program: &str, timeout: Duration) -> Result<String, Error> {
+        let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+        run_transcriber(
+            &[program.to_string()],
+            &path("audio.wav"),
+            &path("transcript.out"),
+            Some("en"),
+            &path("transcript.log"),
+            timeout,
+            &Children::default(),
+        )
+        .await
+    }
+
+    #[test]
+    fn the_srt_and_the_json_segments_are_read() {
+        let hello = Segment {
+            start: 0.0,
+            end: 1.5,
+            text: "Hello there".to_string(),
+        };
+        assert_eq!(parse(SRT).unwrap()[0], hello);
+        let json = r#"[{"start": 0.0, "end": 1.5, "text": "Hello there"}]"#;
+        assert_eq!(parse(json).unwrap(), std::slice::from_ref(&hello));
+        let json = r#"{"segments": [{"start": 0.0, "end": 1.5, "text": "Hello there"}]}"#;
+        assert_eq!(parse(json).unwrap(), [hello]);
+        // back as it came
+        assert_eq!(
+            parse(&to_srt(&parse(SRT).unwrap())).unwrap(),
+            parse(SRT).unwrap()
+        );
+        assert_eq!(
+            to_text(&parse(SRT).unwrap()),
+            "Hello there\nGeneral Kenobi\n"
+        );
+    }
+
+    #[test]
+    fn the_segments_are_sorted_and_trimmed() {
+        let segment = |start, end, text: &str| Segment {
+            start,
+            end,
+            text: text.to_string(),
+        };
+        let normalized = normalize(vec![
+            segment(2.0, 3.0, " second "),
+            segment(1.0, 1.5, "   "),
+            segment(0.0, 1.0, "first"),
+        ])
+        .unwrap();
+        assert_eq!(
+            normalized,
+            [segment(0.0, 1.0, "first"), segment(2.0, 3.0, "second")]
+        );
+        assert!(matches!(
+            normalize(vec![segment(2.0, 1.0, "backwards")]),
+            Err(Error::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn a_malformed_output_is_refused() {
+        for output in [
+            "nothing to see",
+            "1\n00:00:00,000 --> soon\nHello",
+            r#"[{"start": 0.0}]"#,
+        ] {
+            assert!(
+                matches!(parse(output), Err(Error::Malformed(_))),
+                "{}",
+                output
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn the_stub_transcriber_is_read_and_its_stderr_logged() {
+        let dir = dir("transcript-stub");
+        let program = stub(
+            &dir,
+            &format!(
+                "echo \"transcribing $1 in $3\" >&2\nprintf '{}' > \"$2\"",
+                SRT.replace('\n', "\\n")
+            ),
+        );
+        let written = run(&dir, &program, DEFAULT_TIMEOUT).await.unwrap();
+        let segments = normalize(parse(&written).unwrap()).unwrap();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[1].text, "General Kenobi");
+        let log = std::fs::read_to_string(dir.join("transcript.log")).unwrap();
+        assert!(log.contains("audio.wav in en"), "{}", log);
+    }
+
+    #[tokio::test]
+    async fn a_failed_or_slow_transcriber_is_an_error() {
+        let dir = dir("transcript-failed");
+        let program = stub(&dir, "exit 1");
+        assert!(matches!(
+            run(&dir, &program, DEFAULT_TIMEOUT).await,
+            Err(Error::Failed(_))
+        ));
+        let dir = self::dir("transcript-silent");
+        let program = stub(&dir, "exit 0");
+        assert!(matches!(
+            run(&dir, &program, DEFAULT_TIMEOUT).await,
+            Err(Error::Failed(_))
+        ));
+        let dir = self::dir("transcript-slow");
+        let program = stub(&dir, "exec sleep 30");
+        let timeout = Duration::from_millis(100);
+        assert!(matches!(
+            run(&dir, &program, timeout).await,
+            Err(Error::Timeout(_))
+        ));
+    }
+
+    #[test]
+    fn the_transcripts_are_searched_ignoring_case() {
+        let dir = dir("transcript-search");
+        std::fs::write(dir.join("a.mp4.transcript.srt"), SRT).unwrap();
+        let other = "1\n00:00:00,000 --> 00:00:01,000\nSomething else\n";
+        std::fs::write(dir.join("b.mp4.transcript.srt"), other).unwrap();
+        std::fs::write(dir.join("c.mp4.transcript.srt"), "garbage").unwrap();
+        let all = search(&dir, None).unwrap();
+        let names: Vec<&str> = all.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["b.mp4", "a.mp4"]);
+        let found = search(&dir, Some("KENOBI")).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].name, "a.mp4");
+        assert_eq!(found[0].matches[0].text, "General Kenobi");
+        assert!(search(&dir, Some("absent")).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_recording_is_transcribed_with_its_subtitles_muxed() {
+        if std::process::Command::new("ffmpeg")
+            .arg("-version")
+            .output()
+            .is_err()
+        {
+            eprintln!("ffmpeg is not installed, skipped");
+            return;
+        }
+        let dir = dir("transcript-mux");
+        let recording = dir.join("meeting.mkv").to_string_lossy().to_string();
+        let status = tokio::process::Command::new("ffmpeg")
+            .args(["-v", "error", "-y", "-f", "lavfi"])
+            .args(["-i", "testsrc=duration=3:size=160x120:rate=10"])
+            .args(["-f", "lavfi", "-i", "sine=duration=3"])
+            .arg(&recording)
+            .status()
+            .await
+            .unwrap();
+        assert!(status.success());
+        let program = stub(
+            &dir,
+            &format!("printf '{}' > \"$2\"", SRT.replace('\n', "\\n")),
+        );
+        let transcribers = Transcribers::new(
+            vec![format!("stub={}", program).parse().unwrap()],
+            DEFAULT_TIMEOUT,
+        );
+        let req = TranscribeRequest {
+            command: "stub".to_string(),
+            language: Some("eng".to_string()),
+            mux: true,
+        };
+        let values = Values::new(chrono::Local::now(), None);
+        let segments = transcribe(
+            &recording,
+            &req,
+            &values,
+            &transcribers,
+            &Children::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(segments.len(), 2);
+        assert!(std::fs::read_to_string(srt_path(&recording))
+            .unwrap()
+            .contains("General Kenobi"));
+        let streams = tokio::process::Command::new("ffprobe")
+            .args(["-v", "error", "-show_entries", "stream=codec_type"])
+            .args(["-of", "csv=p=0", &recording])
+            .output()
+            .await
+            .unwrap();
+        let streams = String::from_utf8_lossy(&streams.stdout);
+        assert!(streams.lines().any(|t| t == "subtitle"), "{}", streams);
+    }
+}
@@ -0,0 +1,332 @@
+//! Cutting the fumbled start and end off the result of a recording
+//!
+//! The last seconds of a recording are often the way to its stop button, and the first ones the
+//! way back from its start. A stop may ask for `trim_tail_secs`, the result then ends that many
+//! seconds before the stop, and for `trim_head_secs`, the result then starts that much later. The
+//! server has defaults for both with `--trim-head` and `--trim-tail`, the stops the server decides
+//! on itself only take the one of the head.
+//!
+//! The seconds are of the content, what was recorded without the gaps between the spans of the
+//! capture, see [RecordingTimeline]. Only the result is trimmed, with `-ss` and `-t` on the
+//! inputs of the compression: the capture keeps all of it. The markers out of the [Window] kept
+//! are dropped, the others moved to the new start, and a `<name>.trim.json` sidecar next to the
+//! result tells its length before and after.
+use crate::audio::AudioSegment;
+use crate::schema::{self, Versioned};
+use crate::service::{Marker, RecordingTimeline};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use tracing::*;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum Error {
+    #[error("must be a number of seconds, 0 or more")]
+    Invalid(&'static str),
+    #[error("trims {trim_secs:.1}s of a recording of {content_secs:.1}s, leaving nothing")]
+    TooLong { trim_secs: f64, content_secs: f64 },
+}
+
+impl Error {
+    /// the field of the stop request the error is about
+    pub fn field(&self) -> &'static str {
+        match self {
+            Self::Invalid(field) => field,
+            Self::TooLong { .. } => "trim_tail_secs",
+        }
+    }
+}
+
+/// What a stop asks to trim, the defaults of the server where it asks for nothing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct StopRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trim_head_secs: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trim_tail_secs: Option<f64>,
+}
+
+impl StopRequest {
+    /// a stop the server decided on, nobody had to reach for a button at its end
+    pub fn by_server() -> Self {
+        Self {
+            trim_head_secs: None,
+            trim_tail_secs: Some(0.0),
+        }
+    }
+
+    pub fn asks(&self) -> bool {
+        self.trim_head_secs.is_some() || self.trim_tail_secs.is_some()
+    }
+
+    /// the trim with `defaults` where the request tells nothing
+    pub fn trim(&self, defaults: Trim) -> Result<Trim, Error> {
+        let secs = |value: Option<f64>, default: f64, field| match value.unwrap_or(default) {
+            secs if secs.is_finite() && secs >= 0.0 => Ok(secs),
+            _ => Err(Error::Invalid(field)),
+        };
+        Ok(Trim {
+            head_secs: secs(self.trim_head_secs, defaults.head_secs, "trim_head_secs")?,
+            tail_secs: secs(self.trim_tail_secs, defaults.tail_secs, "trim_tail_secs")?,
+        })
+    }
+}
+
+/// How much to cut off the start and the end of the results
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Trim {
+    pub head_secs: f64,
+    pub tail_secs: f64,
+}
+
+impl Trim {
+    pub fn is_none(&self) -> bool {
+        self.head_secs == 0.0 && self.tail_secs == 0.0
+    }
+
+    /// what is kept of `content_ms`, None when nothing is trimmed
+    pub fn window(&self, content_ms: u64) -> Result<Option<Window>, Error> {
+        if self.is_none() {
+            return Ok(None);
+        }
+        let (head_ms, tail_ms) = (ms(self.head_secs), ms(self.tail_secs));
+        if head_ms + tail_ms >= content_ms {
+            return Err(Error::TooLong {
+                trim_secs: self.head_secs + self.tail_secs,
+                content_secs: content_ms as f64 / 1000.0,
+            });
+        }
+        Ok(Some(Window {
+            start_ms: head_ms,
+            end_ms: content_ms - tail_ms,
+            content_ms,
+        }))
+    }
+
+    /// what is kept of a recording stopped at `wall_ms`
+    pub fn window_at(
+        &self,
+        timeline: &RecordingTimeline,
+        wall_ms: u64,
+    ) -> Result<Option<Window>, Error> {
+        self.window(timeline.content_at(wall_ms))
+    }
+}
+
+fn ms(secs: f64) -> u64 {
+    (secs * 1000.0).round() as u64
+}
+
+fn secs(ms: u64) -> String {
+    format!("{:.3}", ms as f64 / 1000.0)
+}
+
+/// What is kept of the content of a recording, in milliseconds into it
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Window {
+    pub start_ms: u64,
+    pub end_ms: u64,
+    /// all of the content, as it was recorded
+    pub content_ms: u64,
+}
+
+impl Window {
+    pub fn kept_ms(&self) -> u64 {
+        self.end_ms - self.start_ms
+    }
+
+    /// the `-ss` of the capture, None when it starts at its start
+    pub fn seek(&self) -> Option<String> {
+        (self.start_ms > 0).then(|| secs(self.start_ms))
+    }
+
+    /// the `-t` of the capture
+    pub fn duration(&self) -> String {
+        secs(self.kept_ms())
+    }
+
+    /// the audio segments placed on the trimmed capture, with the `-ss` of those starting before it
+    pub fn segments(&self, segments: &[AudioSegment]) -> Vec<(AudioSegment, Option<String>)> {
+        segments
+            .iter()
+            .map(
+                |segment| match segment.offset_ms.checked_sub(self.start_ms) {
+                    Some(offset_ms) => (
+                        AudioSegment {
+                            offset_ms,
+                            ..segment.clone()
+                        },
+                        None,
+                    ),
+                    None => (
+                        AudioSegment {
+                            offset_ms: 0,
+                            ..segment.clone()
+                        },
+                        Some(secs(self.start_ms - segment.offset_ms)),
+                    ),
+                },
+            )
+            .collect()
+    }
+
+    /// drop the markers out of the window and move the others to its start
+    pub fn markers(&self, markers: &mut Vec<Marker>) {
+        markers
+            .retain(|m| (self.start_ms..=self.end_ms).contains(&m.content_ms.unwrap_or(m.at_ms)));
+        for marker in markers {
+            marker.at_ms = marker.at_ms.saturating_sub(self.start_ms);
+            if let Some(content_ms) = marker.content_ms.as_mut() {
+                *content_ms -= self.start_ms;
+            }
+        }
+    }
+}
+
+/// The lengths of a trimmed result, in its sidecar
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Trimmed {
+    /// seconds recorded
+    pub full_secs: f64,
+    /// seconds kept in the result, without its slate
+    pub trimmed_secs: f64,
+    pub head_secs: f64,
+    pub tail_secs: f64,
+}
+
+impl Versioned for Trimmed {
+    const KIND: &'static str = "trim sidecars";
+    const VERSION: u32 = 1;
+    const MIGRATIONS: &'static [schema::Migration] = &[schema::unversioned];
+}
+
+impl From<&Window> for Trimmed {
+    fn from(window: &Window) -> Self {
+        Self {
+            full_secs: window.content_ms as f64 / 1000.0,
+            trimmed_secs: window.kept_ms() as f64 / 1000.0,
+            head_secs: window.start_ms as f64 / 1000.0,
+            tail_secs: (window.content_ms - window.end_ms) as f64 / 1000.0,
+        }
+    }
+}
+
+/// path of the trim sidecar of a result
+pub fn sidecar_path(result: &Path) -> PathBuf {
+    result.with_extension("trim.json")
+}
+
+/// write the sidecar next to the result
+pub async fn write_sidecar(result: &Path, window: &Window) {
+    let path = sidecar_path(result);
+    let json = schema::to_vec_pretty(&Trimmed::from(window)).expect("trim json");
+    if let Err(e) = tokio::fs::write(&path, json).await {
+        warn!("cannot write {}: {}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::Span;
+
+    fn trim(head_secs: f64, tail_secs: f64) -> Trim {
+        Trim {
+            head_secs,
+            tail_secs,
+        }
+    }
+
+    #[test]
+    fn a_stop_takes_the_defaults_it_does_not_tell() {
+        let defaults = trim(1.0, 5.0);
+        assert_eq!(StopRequest::default().trim(defaults), Ok(defaults));
+        let request = StopRequest {
+            trim_head_secs: None,
+            trim_tail_secs: Some(2.5),
+        };
+        assert_eq!(request.trim(defaults), Ok(trim(1.0, 2.5)));
+        // the server stopping has no button to reach for
+        assert_eq!(StopRequest::by_server().trim(defaults), Ok(trim(1.0, 0.0)));
+        for invalid in [-1.0, f64::NAN, f64::INFINITY] {
+            let request = StopRequest {
+                trim_head_secs: Some(invalid),
+                trim_tail_secs: None,
+            };
+            let e = request.trim(defaults).unwrap_err();
+            assert_eq!(e.field(), "trim_head_secs");
+        }
+    }
+
+    #[test]
+    fn a_trim_leaving_nothing_is_refused() {
+        assert_eq!(Trim::default().window(10_000), Ok(None));
+        let window = trim(2.0, 3.0).window(10_000).unwrap().unwrap();
+        assert_eq!((window.start_ms, window.end_ms), (2000, 7000));
+        assert_eq!(window.seek().as_deref(), Some("2.000"));
+        assert_eq!(window.duration(), "5.000");
+        assert_eq!(trim(0.0, 3.0).window(10_000).unwrap().unwrap().seek(), None);
+        for too_long in [trim(10.0, 0.0), trim(0.0, 12.0), trim(6.0, 4.0)] {
+            let e = too_long.window(10_000).unwrap_err();
+            assert!(matches!(e, Error::TooLong { .. }), "{:?}", too_long);
+            assert_eq!(e.field(), "trim_tail_secs");
+        }
+    }
+
+    #[test]
+    fn the_trim_is_of_the_content_without_the_pauses() {
+        // 4s recorded, paused for 6s, then 3s more
+        let timeline = RecordingTimeline {
+            spans: vec![
+                Span {
+                    wall_ms: 0,
+                    content_ms: 0,
+                    ended_ms: Some(4000),
+                },
+                Span {
+                    wall_ms: 10_000,
+                    content_ms: 4000,
+                    ended_ms: None,
+                },
+            ],
+        };
+        let window = trim(1.0, 2.0)
+            .window_at(&timeline, 13_000)
+            .unwrap()
+            .unwrap();
+        assert_eq!(window.content_ms, 7000);
+        assert_eq!(window.kept_ms(), 4000);
+        assert!(trim(0.0, 8.0).window_at(&timeline, 13_000).is_err());
+    }
+
+    #[test]
+    fn the_audio_and_the_markers_follow_the_head() {
+        let window = trim(2.0, 1.0).window(10_000).unwrap().unwrap();
+        let segment = |file: &str, offset_ms| AudioSegment {
+            file: file.to_string(),
+            offset_ms,
+        };
+        let placed = window.segments(&[segment("early.wav", 500), segment("late.wav", 5000)]);
+        assert_eq!(placed[0].0.offset_ms, 0);
+        assert_eq!(placed[0].1.as_deref(), Some("1.500"));
+        assert_eq!(placed[1].0.offset_ms, 3000);
+        assert_eq!(placed[1].1, None);
+
+        let marker = |at_ms| Marker {
+            at_ms,
+            label: String::new(),
+            content_ms: Some(at_ms),
+        };
+        let mut markers = vec![marker(1000), marker(4000), marker(9500)];
+        window.markers(&mut markers);
+        assert_eq!(markers.len(), 1);
+        assert_eq!(
+            (markers[0].at_ms, markers[0].content_ms),
+            (2000, Some(2000))
+        );
+
+        let trimmed = Trimmed::from(&window);
+        assert_eq!((trimmed.full_secs, trimmed.trimmed_secs), (10.0, 7.0));
+        assert_eq!((trimmed.head_secs, trimmed.tail_secs), (2.0, 1.0));
+    }
+}
@@ -0,0 +1,386 @@
+//! Telling another service what the recorder does, by an HTTP POST on every change of state
+//!
+//! With `--webhook-url`, every state the recorder switches to that is of another kind than the
+//! previous one, Started after Countdown or Done after Compressing, is posted as a
+//! [Notification]: the kind, the file, when, the size of the file once it is Done, and the whole
+//! state. The notifications are queued to a task of their own that posts them in order, making
+//! [ATTEMPTS] attempts with a backoff; the recording never waits for them. One that can't be
+//! delivered, or that finds the queue full, is logged and dropped.
+//!
+//! The URL is a [Template] of the values of the recording, `https://hooks.example.com/{owner}`:
+//! those of the last recording started, percent-encoded, see [template::WEBHOOK_TOKENS].
+use crate::service::RecordingState;
+use crate::template::{self, Template, Values};
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::*;
+
+/// attempts to deliver a notification
+pub const ATTEMPTS: u32 = 3;
+/// the wait after the first failed attempt, doubled after each one
+const BACKOFF: Duration = Duration::from_secs(1);
+/// how long the receiver may take to answer
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// notifications waiting for the delivery before new ones are dropped
+const QUEUED: usize = 64;
+
+/// What is posted to the webhook
+#[derive(Debug, Clone, Serialize)]
+pub struct Notification {
+    /// `state`, or `test` for the one of `POST /api/webhook/test`
+    pub event: &'static str,
+    /// the kind of the state: Started, Stopping, Compressing, Done...
+    pub kind: &'static str,
+    /// the file the state is about
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    pub at: DateTime<Local>,
+    /// the size of the finished file, in bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    pub state: RecordingState,
+}
+
+impl Notification {
+    fn new(event: &'static str, state: &RecordingState) -> Self {
+        Self {
+            event,
+            kind: state.name(),
+            file: file_of(state).map(str::to_string),
+            at: Local::now(),
+            size: None,
+            state: state.clone().without_command(),
+        }
+    }
+}
+
+fn file_of(state: &RecordingState) -> Option<&str> {
+    match state {
+        RecordingState::Started { file, .. }
+        | RecordingState::Paused { file, .. }
+        | RecordingState::Stopping { file, .. }
+        | RecordingState::Uploading { file, .. }
+        | RecordingState::Done { file, .. } => Some(file),
+        RecordingState::Compressing { output, .. } => Some(output),
+        RecordingState::Waiting
+        | RecordingState::Countdown { .. }
+        | RecordingState::Failed { .. }
+        | RecordingState::Cancelled { .. } => None,
+    }
+}
+
+/// the values of the recording a state starts
+fn values_of(state: &RecordingState) -> Option<Values> {
+    let (started_at, options) = match state {
+        RecordingState::Countdown {
+            start_at, options, ..
+        } => (*start_at, options),
+        RecordingState::Started {
+            started_at,
+            options,
+            ..
+        } => (*started_at, options),
+        _ => return None,
+    };
+    let values = Values::new(started_at, options.owner.as_deref())
+        .with_profile(options.encoder_profile.as_deref());
+    Some(values)
+}
+
+/// Where the changes of state are posted
+pub struct Webhook {
+    url: Template,
+    client: reqwest::Client,
+    queue: mpsc::Sender<(reqwest::Url, Notification)>,
+    /// the kind of the last state queued
+    last: Mutex<&'static str>,
+    /// the values of the last recording started
+    recording: Mutex<Option<Values>>,
+}
+
+impl Webhook {
+    /// start the task delivering the notifications to `url`
+    pub fn open(url: Template) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder().timeout(TIMEOUT).build()?;
+        let (queue, mut queued) = mpsc::channel::<(reqwest::Url, Notification)>(QUEUED);
+        tokio::spawn({
+            let client = client.clone();
+            async move {
+                while let Some((url, mut notification)) = queued.recv().await {
+                    if let (RecordingState::Done { .. }, Some(file)) =
+                        (&notification.state, &notification.file)
+                    {
+                        notification.size = tokio::fs::metadata(file).await.ok().map(|m| m.len());
+                    }
+                    if let Err(e) = deliver(&client, &url, &notification).await {
+                        warn!(
+                            "the webhook did not get the {} of {}: {}",
+                            notification.kind,
+                            notification.at.to_rfc3339(),
+                            e
+                        );
+                    }
+                }
+            }
+        });
+        Ok(Self {
+            url,
+            client,
+            queue,
+            last: Mutex::new(RecordingState::Waiting.name()),
+            recording: Mutex::new(None),
+        })
+    }
+
+    /// the URL of the notifications of the state, with the values of its recording
+    fn url(&self, state: &RecordingState) -> anyhow::Result<reqwest::Url> {
+        let values = {
+            let mut recording = self.recording.lock().unwrap();
+            if let Some(values) = values_of(state) {
+                *recording = Some(values);
+            }
+            recording
+                .clone()
+                .unwrap_or_else(|| Values::new(Local::now(), None))
+        };
+        let url = self
+            .url
+            .render(&values, template::Context::Url)
+            .map_err(anyhow::Error::msg)?;
+        Ok(reqwest::Url::parse(&url)?)
+    }
+
+    /// queue the notification of the state, unless it is of the kind of the previous one
+    pub fn notify(&self, state: &RecordingState) {
+        {
+            let mut last = self.last.lock().unwrap();
+            if *last == state.name() {
+                return;
+            }
+            *last = state.name();
+        }
+        let url = match self.url(state) {
+            Ok(url) => url,
+            Err(e) => {
+                warn!("no URL for the webhook to get the {}: {}", state.name(), e);
+                return;
+            }
+        };
+        match self
+            .queue
+            .try_send((url, Notification::new("state", state)))
+        {
+            Ok(()) => {}
+            Err(TrySendError::Full((_, n))) | Err(TrySendError::Closed((_, n))) => {
+                warn!("the webhook is behind, dropped the {}", n.kind);
+            }
+        }
+    }
+
+    /// post a notification of the current state right away, once, for the status it gets
+    pub async fn test(&self, state: &RecordingState) -> anyhow::Result<u16> {
+        let notification = Notification::new("test", state);
+        let res = self
+            .client
+            .post(self.url(state)?)
+            .json(&notification)
+            .send();
+        Ok(res.await?.error_for_status()?.status().as_u16())
+    }
+}
+
+async fn deliver(
+    client: &reqwest::Client,
+    url: &reqwest::Url,
+    notification: &Notification,
+) -> anyhow::Result<()> {
+    let mut backoff = BACKOFF;
+    for attempt in 1..=ATTEMPTS {
+        let sent = client.post(url.clone()).json(notification).send().await;
+        match sent.and_then(|res| res.error_for_status()) {
+            Ok(_) => return Ok(()),
+            Err(e) if attempt == ATTEMPTS => return Err(e.into()),
+            Err(e) => {
+                debug!("webhook attempt {} failed: {}", attempt, e);
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::RecordingOptions;
+    use axum::extract::OriginalUri;
+    use axum::routing::post;
+    use axum::{Extension, Router};
+    use std::sync::Arc;
+
+    /// a receiver of the notifications, the URIs they were posted to come out of the channel
+    fn receiver() -> (std::net::SocketAddr, mpsc::UnboundedReceiver<String>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let app = Router::new()
+            .route(
+                "/*path",
+                post(
+                    |Extension(tx): Extension<Arc<mpsc::UnboundedSender<String>>>,
+                     OriginalUri(uri): OriginalUri| async move {
+                        _ = tx.send(uri.to_string());
+                    },
+                ),
+            )
+            .layer(Extension(Arc::new(tx)));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener).unwrap();
+        tokio::spawn(server.serve(app.into_make_service()));
+        (addr, rx)
+    }
+
+    fn started(owner: &str, profile: &str, started_at: DateTime<Local>) -> RecordingState {
+        let options = RecordingOptions {
+            owner: Some(owner.to_string()),
+            encoder_profile: Some(profile.to_string()),
+            ..Default::default()
+        };
+        serde_json::from_value(serde_json::json!({
+            "type": "Started",
+            "process_id": 1,
+            "file": "recording.mp4",
+            "options": options,
+            "started_at": started_at,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_notification_is_delivered_to_the_url_of_its_recording() {
+        let (addr, mut posted) = receiver();
+        let url = format!(
+            "http://{}/hooks/{{owner}}/{{recording_id}}?profile={{profile}}",
+            addr
+        );
+        let webhook = Webhook::open(template::webhook_url(&url).unwrap()).unwrap();
+        let started_at = Local::now();
+        let id = crate::pipeline::recording_id(started_at);
+        let expected = format!("/hooks/a%20b%2Fc%25/{}?profile=fast", id);
+
+        webhook.notify(&started("a b/c%", "fast", started_at));
+        assert_eq!(posted.recv().await.unwrap(), expected);
+        // the states after the start are those of the same recording
+        webhook.notify(&RecordingState::Stopping {
+            process_id: 1,
+            file: "recording.mp4".to_string(),
+        });
+        assert_eq!(posted.recv().await.unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn a_notification_before_any_recording_has_empty_values() {
+        let (addr, mut posted) = receiver();
+        let url = format!("http://{}/hooks/{{owner}}/{{date}}", addr);
+        let webhook = Webhook::open(template::webhook_url(&url).unwrap()).unwrap();
+        let status = webhook.test(&RecordingState::Waiting).await.unwrap();
+        assert_eq!(status, 200);
+        let date = Local::now().format("%Y-%m-%d").to_string();
+        assert_eq!(posted.recv().await.unwrap(), format!("/hooks//{}", date));
+    }
+
+    /// a receiver answering 500 to its first `failures` posts, the bodies and the status they
+    /// were answered with come out of the channel
+    fn flaky(failures: usize) -> (String, mpsc::UnboundedReceiver<(u16, serde_json::Value)>) {
+        use axum::http::StatusCode;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let (tx, rx) = mpsc::unbounded_channel();
+        let posts = Arc::new(AtomicUsize::new(0));
+        let app = Router::new().route(
+            "/hook",
+            post(
+                move |axum::Json(body): axum::Json<serde_json::Value>| async move {
+                    let status = match posts.fetch_add(1, Ordering::SeqCst) < failures {
+                        true => StatusCode::INTERNAL_SERVER_ERROR,
+                        false => StatusCode::OK,
+                    };
+                    _ = tx.send((status.as_u16(), body));
+                    status
+                },
+            ),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener).unwrap();
+        tokio::spawn(server.serve(app.into_make_service()));
+        (format!("http://{}/hook", addr), rx)
+    }
+
+    fn stopping() -> RecordingState {
+        RecordingState::Stopping {
+            process_id: 1,
+            file: "recording.mp4".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn every_change_of_kind_is_posted_with_the_size_once_done() {
+        let (url, mut posted) = flaky(0);
+        let webhook = Webhook::open(template::webhook_url(&url).unwrap()).unwrap();
+        let file = crate::recordings::test_output_dir().join("notified.mp4");
+        std::fs::write(&file, [0; 42]).unwrap();
+        let done: RecordingState = serde_json::from_value(serde_json::json!({
+            "type": "Done",
+            "file": file,
+        }))
+        .unwrap();
+        webhook.notify(&started("owner", "fast", Local::now()));
+        // of the same kind, it is no change
+        webhook.notify(&started("owner", "fast", Local::now()));
+        webhook.notify(&stopping());
+        webhook.notify(&done);
+
+        let mut notifications = vec![];
+        for _ in 0..3 {
+            let (status, body) = posted.recv().await.unwrap();
+            assert_eq!(status, 200);
+            notifications.push(body);
+        }
+        let kinds: Vec<_> = notifications.iter().map(|n| n["kind"].clone()).collect();
+        assert_eq!(kinds, ["Started", "Stopping", "Done"]);
+        assert!(notifications.iter().all(|n| n["event"] == "state"));
+        assert_eq!(notifications[0]["file"], "recording.mp4");
+        assert_eq!(notifications[0]["state"]["type"], "Started");
+        assert_eq!(notifications[0].get("size"), None);
+        assert_eq!(notifications[2]["file"], file.to_string_lossy().as_ref());
+        assert_eq!(notifications[2]["size"], 42);
+        let more = tokio::time::timeout(Duration::from_millis(200), posted.recv()).await;
+        assert!(more.is_err(), "{:?}", more);
+    }
+
+    #[tokio::test]
+    async fn a_failed_notification_is_retried_then_dropped() {
+        let (url, mut posted) = flaky(ATTEMPTS as usize);
+        let webhook = Webhook::open(template::webhook_url(&url).unwrap()).unwrap();
+        webhook.notify(&started("owner", "fast", Local::now()));
+        webhook.notify(&stopping());
+        let mut attempts = vec![];
+        for _ in 0..=ATTEMPTS {
+            let (status, body) = posted.recv().await.unwrap();
+            attempts.push((status, body["kind"].as_str().unwrap().to_string()));
+        }
+        let failed = (500, "Started".to_string());
+        assert_eq!(
+            attempts,
+            [
+                failed.clone(),
+                failed.clone(),
+                failed,
+                (200, "Stopping".to_string())
+            ]
+        );
+    }
+}
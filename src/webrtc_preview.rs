@@ -0,0 +1,497 @@
+//! An experimental preview of the capture over WebRTC, with less latency than the HLS one
+//!
+//! With `preview: "webrtc"`, the capture has another output: its video, small and encoded for no
+//! latency, sent as RTP to a UDP port the server binds on the loopback, see [Preview::open]. A
+//! browser posts its SDP offer to `POST /api/preview/webrtc` and gets the answer of a peer
+//! connection the packets are forwarded to, see [Preview::answer]. The preview is for the LAN: the
+//! ICE candidates are those of the host, without a STUN or a TURN server. One viewer watches at a
+//! time, the offer of another one is refused until the first disconnects, and the peer connection
+//! is closed with the capture, see [Preview::close].
+//!
+//! Nothing of it touches the recording: a port that can't be bound leaves the capture without the
+//! output, and an offer that can't be answered is refused with the preview unavailable. It needs
+//! the `webrtc` feature, without it the option is refused and the capabilities tell so, see
+//! [AVAILABLE]. `GET /api/preview/webrtc` serves a page to try it in a browser.
+use crate::service::RecordingOptions;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// whether the server is built with the preview
+pub const AVAILABLE: bool = cfg!(feature = "webrtc");
+/// the height the preview is scaled down to
+const HEIGHT: u32 = 480;
+/// the size of the RTP packets, within the MTU of the peer connection
+const PACKET_SIZE: usize = 1200;
+/// the page trying the preview in a browser
+pub const PAGE: &str = include_str!("webrtc_preview.html");
+
+/// How the preview of the capture is watched
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    /// segments and a playlist, as `live_preview`, see [crate::preview]
+    Hls,
+    /// RTP forwarded to one browser, experimental
+    Webrtc,
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("the server is built without the WebRTC preview")]
+    Unavailable,
+    #[error("no WebRTC preview is running")]
+    NotRunning,
+    #[error("the preview is watched already")]
+    Watched,
+    #[error("preview unavailable: {0}")]
+    Failed(String),
+}
+
+/// whether the preview the options ask for can be given, the field that can't otherwise
+pub fn validate(opt: &RecordingOptions) -> Result<(), (&'static str, String)> {
+    if opt.preview == Some(Transport::Webrtc) && !AVAILABLE {
+        return Err(("preview", Error::Unavailable.to_string()));
+    }
+    Ok(())
+}
+
+/// the URL of the output of the capture to `port`
+pub fn url(port: u16) -> String {
+    format!("rtp://127.0.0.1:{}?pkt_size={}", port, PACKET_SIZE)
+}
+
+/// the options of the output of the preview, of a capture at `framerate`
+pub fn output_options(framerate: &str) -> Vec<(&'static str, String)> {
+    vec![
+        ("map", "0:v".to_string()),
+        ("vf", format!("scale=-2:'min({},ih)'", HEIGHT)),
+        ("c:v", "libx264".to_string()),
+        ("preset", "ultrafast".to_string()),
+        ("tune", "zerolatency".to_string()),
+        ("profile:v", "baseline".to_string()),
+        ("pix_fmt", "yuv420p".to_string()),
+        // a viewer that joins sees a picture within a second
+        ("g", framerate.to_string()),
+        ("payload_type", "96".to_string()),
+        ("f", "rtp".to_string()),
+    ]
+}
+
+/// The preview of the running capture and its viewer
+#[derive(Default)]
+pub struct Preview {
+    #[cfg(feature = "webrtc")]
+    state: tokio::sync::Mutex<session::State>,
+}
+
+#[cfg(not(feature = "webrtc"))]
+impl Preview {
+    pub async fn open(&self) -> Option<u16> {
+        None
+    }
+
+    pub async fn close(&self) {}
+
+    pub async fn answer(&self, _offer: String) -> Result<String, Error> {
+        Err(Error::Unavailable)
+    }
+}
+
+#[cfg(feature = "webrtc")]
+impl Preview {
+    /// the port the capture sends its RTP to, bound when the preview was not open yet
+    ///
+    /// None when it can't be bound, the capture goes on without the preview.
+    pub async fn open(&self) -> Option<u16> {
+        let mut state = self.state.lock().await;
+        if let Some(feed) = &state.feed {
+            return Some(feed.port);
+        }
+        match session::Feed::bind().await {
+            Ok(feed) => {
+                let port = feed.port;
+                state.feed = Some(feed);
+                Some(port)
+            }
+            Err(e) => {
+                tracing::warn!("the capture goes on without its WebRTC preview: {}", e);
+                None
+            }
+        }
+    }
+
+    /// close the peer connection and the port, the capture is over
+    pub async fn close(&self) {
+        let mut state = self.state.lock().await;
+        state.feed = None;
+        if let Some(viewer) = state.viewer.take() {
+            viewer.close().await;
+        }
+    }
+
+    /// the SDP answer to the `offer` of a viewer
+    pub async fn answer(&self, offer: String) -> Result<String, Error> {
+        let mut state = self.state.lock().await;
+        let Some(feed) = &state.feed else {
+            return Err(Error::NotRunning);
+        };
+        if state
+            .viewer
+            .as_ref()
+            .is_some_and(|viewer| viewer.watching())
+        {
+            return Err(Error::Watched);
+        }
+        let packets = feed.packets.subscribe();
+        if let Some(viewer) = state.viewer.take() {
+            viewer.close().await;
+        }
+        let (viewer, answer) = session::Viewer::connect(offer, packets)
+            .await
+            .map_err(|e| Error::Failed(e.to_string()))?;
+        state.viewer = Some(viewer);
+        Ok(answer)
+    }
+}
+
+#[cfg(feature = "webrtc")]
+mod session {
+    use std::sync::Arc;
+    use tokio::net::UdpSocket;
+    use tokio::sync::{broadcast, Notify};
+    use tokio::task::JoinHandle;
+    use tracing::*;
+    use webrtc::api::interceptor_registry::register_default_interceptors;
+    use webrtc::api::media_engine::{MediaEngine, MIME_TYPE_H264};
+    use webrtc::api::setting_engine::SettingEngine;
+    use webrtc::api::APIBuilder;
+    use webrtc::ice::mdns::MulticastDnsMode;
+    use webrtc::interceptor::registry::Registry;
+    use webrtc::peer_connection::configuration::RTCConfiguration;
+    use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+    use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+    use webrtc::peer_connection::RTCPeerConnection;
+    use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+    use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
+    use webrtc::track::track_local::{TrackLocal, TrackLocalWriter};
+
+    /// the packets kept for a viewer that is behind, the older ones are dropped
+    const BACKLOG: usize = 256;
+
+    #[derive(Default)]
+    pub(super) struct State {
+        pub feed: Option<Feed>,
+        pub viewer: Option<Viewer>,
+    }
+
+    /// The port the capture sends its RTP to, and the packets read from it
+    pub(super) struct Feed {
+        pub port: u16,
+        pub packets: broadcast::Sender<Vec<u8>>,
+        reader: JoinHandle<()>,
+    }
+
+    impl Feed {
+        pub async fn bind() -> std::io::Result<Self> {
+            let socket = UdpSocket::bind("127.0.0.1:0").await?;
+            let port = socket.local_addr()?.port();
+            let (packets, _) = broadcast::channel(BACKLOG);
+            let sender = packets.clone();
+            let reader = tokio::spawn(async move {
+                let mut buf = vec![0; 1500];
+                while let Ok(n) = socket.recv(&mut buf).await {
+                    // nobody watches
+                    let _ = sender.send(buf[..n].to_vec());
+                }
+            });
+            debug!("the WebRTC preview reads its RTP from port {}", port);
+            Ok(Self {
+                port,
+                packets,
+                reader,
+            })
+        }
+    }
+
+    impl Drop for Feed {
+        fn drop(&mut self) {
+            self.reader.abort();
+        }
+    }
+
+    /// The peer connection of a viewer and the task forwarding the packets to it
+    pub(super) struct Viewer {
+        pub peer: Arc<RTCPeerConnection>,
+        forward: JoinHandle<()>,
+    }
+
+    impl Viewer {
+        /// the viewer of `offer` with its answer, watching `packets`
+        pub async fn connect(
+            offer: String,
+            mut packets: broadcast::Receiver<Vec<u8>>,
+        ) -> webrtc::error::Result<(Self, String)> {
+            let peer = Arc::new(peer_connection().await?);
+            let track = Arc::new(TrackLocalStaticRTP::new(
+                RTCRtpCodecCapability {
+                    mime_type: MIME_TYPE_H264.to_string(),
+                    clock_rate: 90000,
+                    ..Default::default()
+                },
+                "video".to_string(),
+                "record-screen".to_string(),
+            ));
+            let sender = peer
+                .add_track(track.clone() as Arc<dyn TrackLocal + Send + Sync>)
+                .await?;
+            // the RTCP of the viewer is read for the interceptors to see it
+            tokio::spawn(async move {
+                let mut buf = vec![0; 1500];
+                while sender.read(&mut buf).await.is_ok() {}
+            });
+            let gone = Arc::new(Notify::new());
+            let notify = gone.clone();
+            peer.on_peer_connection_state_change(Box::new(move |state| {
+                debug!("the viewer of the WebRTC preview is {}", state);
+                if matches!(
+                    state,
+                    RTCPeerConnectionState::Disconnected
+                        | RTCPeerConnectionState::Failed
+                        | RTCPeerConnectionState::Closed
+                ) {
+                    notify.notify_one();
+                }
+                Box::pin(async {})
+            }));
+
+            peer.set_remote_description(RTCSessionDescription::offer(offer)?)
+                .await?;
+            let answer = peer.create_answer(None).await?;
+            let mut gathered = peer.gathering_complete_promise().await;
+            peer.set_local_description(answer).await?;
+            // the answer has every candidate, the viewer does not trickle them
+            let _ = gathered.recv().await;
+            let Some(answer) = peer.local_description().await else {
+                peer.close().await?;
+                return Err(webrtc::Error::new("no local description".to_string()));
+            };
+
+            let forwarded = peer.clone();
+            let forward = tokio::spawn(async move {
+                loop {
+                    tokio::select! {
+                        packet = packets.recv() => match packet {
+                            Ok(packet) => {
+                                if let Err(e) = track.write(&packet).await {
+                                    debug!("a packet of the WebRTC preview is lost: {}", e);
+                                }
+                            }
+                            // a keyframe comes back soon enough
+                            Err(broadcast::error::RecvError::Lagged(_)) => {}
+                            Err(broadcast::error::RecvError::Closed) => break,
+                        },
+                        _ = gone.notified() => break,
+                    }
+                }
+                let _ = forwarded.close().await;
+            });
+            Ok((Self { peer, forward }, answer.sdp))
+        }
+
+        /// whether the viewer is still connected, or connecting
+        pub fn watching(&self) -> bool {
+            !self.forward.is_finished()
+        }
+
+        pub async fn close(self) {
+            self.forward.abort();
+            if let Err(e) = self.peer.close().await {
+                debug!("cannot close the viewer of the WebRTC preview: {}", e);
+            }
+        }
+    }
+
+    /// a peer connection with the host candidates only
+    pub(super) async fn peer_connection() -> webrtc::error::Result<RTCPeerConnection> {
+        let mut media = MediaEngine::default();
+        media.register_default_codecs()?;
+        let registry = register_default_interceptors(Registry::new(), &mut media)?;
+        let mut settings = SettingEngine::default();
+        // the addresses themselves, not mDNS names a viewer may not resolve
+        settings.set_ice_multicast_dns_mode(MulticastDnsMode::Disabled);
+        let api = APIBuilder::new()
+            .with_media_engine(media)
+            .with_interceptor_registry(registry)
+            .with_setting_engine(settings)
+            .build();
+        api.new_peer_connection(RTCConfiguration::default()).await
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::super::*;
+        use super::*;
+        use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
+        use webrtc::rtp_transceiver::rtp_transceiver_direction::RTCRtpTransceiverDirection;
+        use webrtc::rtp_transceiver::RTCRtpTransceiverInit;
+
+        /// a viewer and its offer, as a browser makes them
+        async fn viewer() -> (RTCPeerConnection, String) {
+            let peer = peer_connection().await.unwrap();
+            let init = RTCRtpTransceiverInit {
+                direction: RTCRtpTransceiverDirection::Recvonly,
+                send_encodings: vec![],
+            };
+            peer.add_transceiver_from_kind(RTPCodecType::Video, Some(init))
+                .await
+                .unwrap();
+            let offer = peer.create_offer(None).await.unwrap();
+            let mut gathered = peer.gathering_complete_promise().await;
+            peer.set_local_description(offer).await.unwrap();
+            let _ = gathered.recv().await;
+            let offer = peer.local_description().await.unwrap().sdp;
+            (peer, offer)
+        }
+
+        #[tokio::test]
+        async fn an_offer_is_refused_without_a_capture() {
+            let preview = Preview::default();
+            let (_peer, offer) = viewer().await;
+            assert!(matches!(
+                preview.answer(offer).await,
+                Err(Error::NotRunning)
+            ));
+        }
+
+        #[tokio::test]
+        async fn the_packets_of_the_capture_are_read() {
+            let preview = Preview::default();
+            let port = preview.open().await.unwrap();
+            // the port stays over the segments of the capture
+            assert_eq!(preview.open().await, Some(port));
+            let mut packets = preview
+                .state
+                .lock()
+                .await
+                .feed
+                .as_ref()
+                .unwrap()
+                .packets
+                .subscribe();
+            let capture = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let packet = [0x80, 96, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1, 0x65, 0x88];
+            capture.send_to(&packet, ("127.0.0.1", port)).await.unwrap();
+            let read = tokio::time::timeout(std::time::Duration::from_secs(5), packets.recv());
+            assert_eq!(read.await.unwrap().unwrap(), packet);
+            preview.close().await;
+            assert!(preview.state.lock().await.feed.is_none());
+        }
+
+        #[tokio::test]
+        async fn an_offer_is_answered_with_the_video() {
+            let preview = Preview::default();
+            preview.open().await.unwrap();
+            let (peer, offer) = viewer().await;
+            let answer = preview.answer(offer).await.unwrap();
+            assert!(answer.contains("m=video"));
+            assert!(answer.contains("H264/90000"));
+            assert!(answer.contains("a=sendonly"));
+            for other in ["typ srflx", "typ relay", ".local"] {
+                assert!(!answer.contains(other), "{}", other);
+            }
+            peer.set_remote_description(RTCSessionDescription::answer(answer).unwrap())
+                .await
+                .unwrap();
+            preview.close().await;
+        }
+
+        #[tokio::test]
+        async fn one_viewer_watches_at_a_time() {
+            let preview = Preview::default();
+            preview.open().await.unwrap();
+            let (_first, offer) = viewer().await;
+            preview.answer(offer).await.unwrap();
+            let (_second, offer) = viewer().await;
+            assert!(matches!(
+                preview.answer(offer.clone()).await,
+                Err(Error::Watched)
+            ));
+            // the first one left
+            let state = preview.state.lock().await;
+            state.viewer.as_ref().unwrap().peer.close().await.unwrap();
+            drop(state);
+            let left = async {
+                while preview
+                    .state
+                    .lock()
+                    .await
+                    .viewer
+                    .as_ref()
+                    .unwrap()
+                    .watching()
+                {
+                    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                }
+            };
+            tokio::time::timeout(std::time::Duration::from_secs(5), left)
+                .await
+                .unwrap();
+            assert!(preview.answer(offer).await.is_ok());
+            preview.close().await;
+            assert!(preview.state.lock().await.viewer.is_none());
+        }
+
+        #[tokio::test]
+        async fn a_wrong_offer_leaves_the_preview_as_it_was() {
+            let preview = Preview::default();
+            preview.open().await.unwrap();
+            assert!(matches!(
+                preview.answer("nonsense".to_string()).await,
+                Err(Error::Failed(_))
+            ));
+            assert!(preview.state.lock().await.viewer.is_none());
+            let (_peer, offer) = viewer().await;
+            assert!(preview.answer(offer).await.is_ok());
+            preview.close().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_preview_is_sent_to_the_port_of_the_server() {
+        assert_eq!(url(5004), "rtp://127.0.0.1:5004?pkt_size=1200");
+        let options = output_options("30");
+        assert!(options.contains(&("g", "30".to_string())));
+        assert!(options.contains(&("map", "0:v".to_string())));
+        assert_eq!(options.last(), Some(&("f", "rtp".to_string())));
+    }
+
+    #[test]
+    fn a_webrtc_preview_needs_the_feature() {
+        let webrtc = RecordingOptions {
+            preview: Some(Transport::Webrtc),
+            ..Default::default()
+        };
+        assert_eq!(validate(&webrtc).is_ok(), AVAILABLE);
+        let hls = RecordingOptions {
+            preview: Some(Transport::Hls),
+            ..Default::default()
+        };
+        assert!(validate(&hls).is_ok());
+    }
+
+    #[cfg(not(feature = "webrtc"))]
+    #[tokio::test]
+    async fn nothing_is_opened_without_the_feature() {
+        let preview = Preview::default();
+        assert_eq!(preview.open().await, None);
+        assert!(matches!(
+            preview.answer(String::new()).await,
+            Err(Error::Unavailable)
+        ));
+    }
+}
@@ -0,0 +1,116 @@
+//! A pipeline of stages of its own, as a library user puts it together
+use futures::future::BoxFuture;
+use record_screen::jobs::Compression;
+use record_screen::pipeline::{self, Context, Flow, PipelineBuilder, Stage};
+use record_screen::service::{Recorder, RecordingState};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// copies the result where it is published
+struct Publish {
+    to: PathBuf,
+}
+
+impl Stage for Publish {
+    fn name(&self) -> &'static str {
+        "publish"
+    }
+
+    fn run<'a>(&'a self, ctx: &'a mut Context) -> BoxFuture<'a, anyhow::Result<Flow>> {
+        Box::pin(async move {
+            tokio::fs::copy(&ctx.file, &self.to).await?;
+            Ok(Flow::Continue)
+        })
+    }
+}
+
+/// gives up on every recording
+struct Refuse;
+
+impl Stage for Refuse {
+    fn name(&self) -> &'static str {
+        "refuse"
+    }
+
+    fn run<'a>(&'a self, _: &'a mut Context) -> BoxFuture<'a, anyhow::Result<Flow>> {
+        Box::pin(async { anyhow::bail!("refused") })
+    }
+}
+
+fn dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "record-screen-custom-stage-{}-{}",
+        std::process::id(),
+        name
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// a stopped capture in `dir`, to be finished
+fn stopped(dir: &std::path::Path) -> Compression {
+    let capture = dir.join("capture.mkv");
+    std::fs::write(&capture, b"capture").unwrap();
+    serde_json::from_value(serde_json::json!({
+        "input": capture,
+        "output": dir.join("capture.mp4"),
+        "options": {},
+        "started_at": chrono::Local::now(),
+    }))
+    .unwrap()
+}
+
+#[tokio::test]
+async fn the_raw_capture_is_published_and_reported_done() {
+    let dir = dir("publish");
+    let published = dir.join("published.mkv");
+    let pipeline = PipelineBuilder::empty()
+        .then(pipeline::Finalize)
+        .after(
+            pipeline::FINALIZE,
+            Publish {
+                to: published.clone(),
+            },
+        )
+        .build();
+    let (_, finish) = pipeline.stages();
+    assert_eq!(finish, [pipeline::FINALIZE, "publish"]);
+
+    let mx = Arc::new(Recorder::new());
+    let job = stopped(&dir);
+    let capture = job.input.clone();
+    let flow = pipeline.finish(mx.clone(), 1, job, None).await.unwrap();
+    assert_eq!(flow, Flow::Continue);
+    // without compression, the raw capture is the result
+    assert_eq!(std::fs::read(&published).unwrap(), b"capture");
+    match &*mx.lock().await {
+        RecordingState::Done { file, .. } => assert_eq!(file, &capture),
+        other => panic!("{:?}", other),
+    }
+    assert_eq!(
+        mx.history.page(&Default::default()).unwrap().entries.len(),
+        1
+    );
+}
+
+#[tokio::test]
+async fn an_error_ends_the_chain() {
+    let dir = dir("refuse");
+    let pipeline = PipelineBuilder::empty()
+        .then(Refuse)
+        .then(pipeline::Finalize)
+        .build();
+    let mx = Arc::new(Recorder::new());
+    let e = pipeline
+        .finish(mx.clone(), 1, stopped(&dir), None)
+        .await
+        .unwrap_err();
+    assert_eq!(e.to_string(), "refused");
+    assert!(!matches!(&*mx.lock().await, RecordingState::Done { .. }));
+    assert!(mx
+        .history
+        .page(&Default::default())
+        .unwrap()
+        .entries
+        .is_empty());
+}